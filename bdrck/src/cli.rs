@@ -13,12 +13,25 @@
 // limitations under the License.
 
 use crate::error::*;
+#[cfg(feature = "fs")]
+use crate::fs::TempFile;
+use crate::width::{char_width, take_width};
 use errno;
 use libc::{self, c_int};
+use once_cell::sync::Lazy;
 use std::fmt;
 use std::io::{self, Read, Write};
 use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+#[cfg(feature = "fs")]
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
 use tracing::debug;
+use tracing::level_filters::LevelFilter;
 
 /// An alias for std::io::Result.
 pub type IoResult<T> = io::Result<T>;
@@ -315,10 +328,41 @@ pub trait AbstractStream {
 
     /// Return a `Write` for this stream, if writing is supported.
     fn as_writer(&self) -> Option<Box<dyn Write>>;
+
+    /// Return the raw file descriptor backing this stream, if there is one.
+    fn as_raw_fd(&self) -> Option<c_int> {
+        None
+    }
+
+    /// Return a function which restores the given attributes to this stream,
+    /// and which can be called from any thread. This is used to restore the
+    /// terminal out-of-band (see `restore_terminal_attributes`), e.g. if the
+    /// process is being interrupted while echo is disabled. By default, this
+    /// isn't supported.
+    fn attribute_restorer(&self, _attributes: &Self::Attributes) -> Option<AttributeRestorer> {
+        None
+    }
+
+    /// Return the size of the terminal this stream refers to, as a
+    /// `(columns, rows)` tuple. By default, this queries the terminal behind
+    /// `as_raw_fd`.
+    fn window_size(&self) -> IoResult<(u16, u16)> {
+        match self.as_raw_fd() {
+            None => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "stream has no file descriptor",
+            )),
+            Some(fd) => {
+                let mut size: libc::winsize = unsafe { MaybeUninit::zeroed().assume_init() };
+                to_io_result(unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut size) })?;
+                Ok((size.ws_col, size.ws_row))
+            }
+        }
+    }
 }
 
 /// Standard input / output streams.
-#[derive(Debug)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Stream {
     /// Standard output.
     Stdout,
@@ -391,298 +435,3014 @@ impl AbstractStream for Stream {
             _ => None,
         }
     }
-}
 
-/// This structure handles a) disabling the echoing of characters typed to
-/// `Stdin`, and b) remembering to reset the terminal attributes afterwards
-/// (via `Drop`).
-struct DisableEcho<'s, S: AbstractStream> {
-    stream: &'s mut S,
-    initial_attributes: S::Attributes,
+    fn as_raw_fd(&self) -> Option<c_int> {
+        Some(self.to_fd())
+    }
+
+    fn attribute_restorer(&self, attributes: &Self::Attributes) -> Option<AttributeRestorer> {
+        let fd = self.to_fd();
+        let attributes = TerminalAttributes {
+            inner: attributes.inner,
+        };
+        Some(Box::new(move || attributes.apply(fd)))
+    }
 }
 
-impl<'s, S: AbstractStream> DisableEcho<'s, S> {
-    fn new(stream: &'s mut S) -> Result<Self> {
-        let initial_attributes = stream.get_attributes()?;
-        debug!("Initial stream attributes: {:#?}", initial_attributes);
+/// The terminal width (in columns) we assume, if the real width can't be
+/// determined (e.g. because the stream isn't a TTY).
+pub const DEFAULT_TERMINAL_WIDTH: usize = 80;
 
-        let mut attributes = stream.get_attributes()?;
-        // Don't echo characters typed to stdin.
-        attributes.disable(TerminalFlag::Echo);
-        // But, *do* echo the newline when the user hits ENTER.
-        attributes.enable(TerminalFlag::EchoNewlines);
-        debug!("Setting attributes to: {:#?}", attributes);
-        stream.set_attributes(&attributes)?;
+/// Return the width (in columns) of the terminal the given stream refers to.
+/// If the stream isn't a TTY, or if its width can't be determined for some
+/// other reason, `DEFAULT_TERMINAL_WIDTH` is returned instead.
+pub fn terminal_width(stream: &Stream) -> usize {
+    if !stream.isatty() {
+        return DEFAULT_TERMINAL_WIDTH;
+    }
 
-        Ok(DisableEcho {
-            stream: stream,
-            initial_attributes: initial_attributes,
-        })
+    match stream.window_size() {
+        Err(e) => {
+            debug!("Failed to get terminal size for {:?}: {}", stream, e);
+            DEFAULT_TERMINAL_WIDTH
+        }
+        Ok((0, _)) => DEFAULT_TERMINAL_WIDTH,
+        Ok((cols, _)) => cols as usize,
     }
 }
 
-impl<'s, S: AbstractStream> Drop for DisableEcho<'s, S> {
-    fn drop(&mut self) {
-        self.stream
-            .set_attributes(&self.initial_attributes)
-            .unwrap();
+/// Return the size of the terminal the given stream refers to, as a
+/// `(columns, rows)` tuple. Unlike `terminal_width`, this returns an error
+/// (instead of a default) if the stream isn't a TTY, or if the terminal
+/// doesn't report a size.
+pub fn terminal_size<S: AbstractStream>(stream: &S) -> Result<(u16, u16)> {
+    if !stream.isatty() {
+        return Err(Error::Precondition(
+            "cannot get the size of a non-TTY stream".to_string(),
+        ));
+    }
+    let (cols, rows) = stream.window_size()?;
+    if cols == 0 || rows == 0 {
+        return Err(Error::Precondition(
+            "terminal did not report a window size".to_string(),
+        ));
     }
+    Ok((cols, rows))
 }
 
-fn require_isatty<S: AbstractStream>(s: &mut S) -> Result<()> {
-    if !s.isatty() {
-        Err(Error::Precondition(format!(
-            "cannot prompt interactively when the I/O streams are not TTYs"
-        )))
-    } else {
-        Ok(())
+/// How often a `ResizeSubscription`'s background thread checks for resizes.
+const RESIZE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Incremented every time SIGWINCH is delivered. Each `ResizeWatcher`
+/// remembers the last value it saw, so several watchers can coexist without
+/// consuming each other's notifications.
+static SIGWINCH_GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+struct SigwinchHandlerState {
+    installs: usize,
+    previous: Option<libc::sigaction>,
+}
+
+static SIGWINCH_HANDLER: Mutex<SigwinchHandlerState> = Mutex::new(SigwinchHandlerState {
+    installs: 0,
+    previous: None,
+});
+
+/// The SIGWINCH handler installed by `ResizeWatcher`. This only touches an
+/// atomic, so it is async-signal-safe.
+pub(crate) extern "C" fn handle_sigwinch(_signal: c_int) {
+    SIGWINCH_GENERATION.fetch_add(1, Ordering::SeqCst);
+}
+
+fn install_sigwinch_handler() -> Result<()> {
+    let mut state = SIGWINCH_HANDLER.lock().unwrap();
+    if state.installs == 0 {
+        let mut action: libc::sigaction = unsafe { MaybeUninit::zeroed().assume_init() };
+        let handler: extern "C" fn(c_int) = handle_sigwinch;
+        action.sa_sigaction = handler as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        to_io_result(unsafe { libc::sigemptyset(&mut action.sa_mask) })?;
+
+        let mut previous: libc::sigaction = unsafe { MaybeUninit::zeroed().assume_init() };
+        to_io_result(unsafe { libc::sigaction(libc::SIGWINCH, &action, &mut previous) })?;
+        state.previous = Some(previous);
     }
+    state.installs += 1;
+    Ok(())
 }
 
-fn build_input_reader<IS: AbstractStream>(
-    input_stream: &mut IS,
-) -> Result<io::BufReader<Box<dyn Read>>> {
-    require_isatty(input_stream)?;
-    Ok(io::BufReader::new(match input_stream.as_reader() {
-        None => {
-            return Err(Error::Precondition(format!(
-                "the given input stream must support `Read`"
-            )))
+fn uninstall_sigwinch_handler() {
+    let mut state = SIGWINCH_HANDLER.lock().unwrap();
+    state.installs -= 1;
+    if state.installs == 0 {
+        if let Some(previous) = state.previous.take() {
+            if let Err(e) = to_io_result(unsafe {
+                libc::sigaction(libc::SIGWINCH, &previous, std::ptr::null_mut())
+            }) {
+                debug!("Failed to restore previous SIGWINCH handler: {}", e);
+            }
         }
-        Some(r) => r,
-    }))
+    }
 }
 
-fn remove_newline(mut s: String) -> Result<String> {
-    // Remove the trailing newline (if any - not finding one is an error).
-    if !s.ends_with('\n') {
-        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "unexpected end of input").into());
+/// ResizeWatcher notices when the terminal a stream refers to is resized.
+///
+/// Creating a watcher installs a SIGWINCH handler (only once, no matter how
+/// many watchers exist); the previous handler is restored when the last
+/// watcher is dropped. Callers can either poll `check` (e.g. once per
+/// iteration of a render loop), or use `subscribe` to be notified from a
+/// background thread instead.
+pub struct ResizeWatcher<S: AbstractStream> {
+    stream: S,
+    generation: AtomicUsize,
+}
+
+impl<S: AbstractStream> ResizeWatcher<S> {
+    /// Start watching for resizes of the terminal `stream` refers to.
+    pub fn new(stream: S) -> Result<Self> {
+        install_sigwinch_handler()?;
+        Ok(ResizeWatcher {
+            stream,
+            generation: AtomicUsize::new(SIGWINCH_GENERATION.load(Ordering::SeqCst)),
+        })
     }
-    s.pop();
 
-    // If this is windows and so there's also a \r, remove that too.
-    if s.ends_with('\r') {
-        s.pop();
+    /// Return the current size of the watched terminal. See `terminal_size`.
+    pub fn size(&self) -> Result<(u16, u16)> {
+        terminal_size(&self.stream)
     }
 
-    Ok(s)
+    /// If the terminal has been resized since the last call to `check` (or
+    /// since this watcher was created), return its new `(columns, rows)`
+    /// size. Otherwise, return None.
+    pub fn check(&self) -> Option<(u16, u16)> {
+        let current = SIGWINCH_GENERATION.load(Ordering::SeqCst);
+        if self.generation.swap(current, Ordering::SeqCst) == current {
+            return None;
+        }
+        match self.size() {
+            Ok(size) => Some(size),
+            Err(e) => {
+                debug!("Failed to get terminal size after resize: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Move this watcher to a background thread, which calls `callback` with
+    /// the new `(columns, rows)` size each time the terminal is resized. The
+    /// thread is stopped when the returned `ResizeSubscription` is dropped.
+    pub fn subscribe<F>(self, mut callback: F) -> ResizeSubscription
+    where
+        S: Send + 'static,
+        F: FnMut((u16, u16)) + Send + 'static,
+    {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let thread = thread::spawn(move || {
+            while !thread_stop.load(Ordering::SeqCst) {
+                if let Some(size) = self.check() {
+                    callback(size);
+                }
+                thread::sleep(RESIZE_POLL_INTERVAL);
+            }
+        });
+        ResizeSubscription {
+            stop,
+            thread: Some(thread),
+        }
+    }
 }
 
-fn prompt_for_string_impl<IS: AbstractStream, OS: AbstractStream>(
-    input_stream: &mut IS,
-    // We have to take the reader as a parameter, since it must be "global",
-    // even if this function is e.g. called in a loop. Otherwise, because it's
-    // buffered, we might buffer some input and then discard it.
-    input_reader: &mut io::BufReader<Box<dyn Read>>,
-    output_stream: &mut OS,
-    prompt: &str,
-    is_sensitive: bool,
-) -> Result<String> {
-    use io::BufRead;
+impl<S: AbstractStream> Drop for ResizeWatcher<S> {
+    fn drop(&mut self) {
+        uninstall_sigwinch_handler();
+    }
+}
 
-    require_isatty(output_stream)?;
-    // It's fine to construct a separate writer, potentially on each loop
-    // iteration or whatever, because we flush immediately, and don't do any
-    // buffering.
-    let mut writer = match output_stream.as_writer() {
-        None => {
-            return Err(Error::Precondition(format!(
-                "the given output stream must support `Write`"
-            )))
+/// A handle to a background thread started by `ResizeWatcher::subscribe`.
+/// Dropping this stops the thread (and drops the underlying watcher).
+pub struct ResizeSubscription {
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Drop for ResizeSubscription {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                debug!("Terminal resize callback thread panicked");
+            }
         }
-        Some(w) => w,
-    };
+    }
+}
 
-    write!(writer, "{}", prompt)?;
-    // We have to flush so the user sees the prompt immediately.
-    writer.flush()?;
+/// How often `run_with_signals` checks whether a signal has arrived.
+const SIGNAL_POLL_INTERVAL: Duration = Duration::from_millis(10);
 
-    Ok({
-        let _disable_echo = match is_sensitive {
-            false => None,
-            true => Some(DisableEcho::new(input_stream)?),
-        };
-        let mut ret = String::new();
-        input_reader.read_line(&mut ret)?;
-        remove_newline(ret)?
-    })
+/// The default `SignalOptions::grace_period`.
+pub const DEFAULT_SIGNAL_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// The signals `SignalGuard` handles.
+const TERMINATION_SIGNALS: [c_int; 2] = [libc::SIGINT, libc::SIGTERM];
+
+/// The first termination signal delivered since the handlers were installed
+/// (or 0 if there hasn't been one), and how many have been delivered.
+static TERMINATION_SIGNAL: AtomicI32 = AtomicI32::new(0);
+static TERMINATION_SIGNAL_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+struct TerminationHandlerState {
+    installs: usize,
+    previous: Vec<(c_int, libc::sigaction)>,
 }
 
-/// Prompt the user for a string (read from the given input stream) using the
-/// given output stream (typically standard output or standard error) to display
-/// the given prompt message.
-///
-/// If `is_sensitive` is true, then the users characters will not be echoed back
-/// (e.g. this will behave like a password prompt).
-///
-/// Note that there are various requirements for the given streams, and this
-/// function will return an error if any of them are not met:
-///
-/// - Both `input_stream` and `output_stream` must be TTYs.
-/// - `input_stream` must return a valid `Read` instance.
-/// - `output_stream` must return a valid `Write` instance.
-pub fn prompt_for_string<IS: AbstractStream, OS: AbstractStream>(
-    mut input_stream: IS,
-    mut output_stream: OS,
-    prompt: &str,
-    is_sensitive: bool,
-) -> Result<String> {
-    let mut input_reader = build_input_reader(&mut input_stream)?;
-    prompt_for_string_impl(
-        &mut input_stream,
-        &mut input_reader,
-        &mut output_stream,
-        prompt,
-        is_sensitive,
-    )
+static TERMINATION_HANDLER: Mutex<TerminationHandlerState> = Mutex::new(TerminationHandlerState {
+    installs: 0,
+    previous: Vec::new(),
+});
+
+/// The SIGINT / SIGTERM handler installed by `SignalGuard`. This only touches
+/// atomics, so it is async-signal-safe.
+extern "C" fn handle_termination_signal(signal: c_int) {
+    let _ = TERMINATION_SIGNAL.compare_exchange(0, signal, Ordering::SeqCst, Ordering::SeqCst);
+    TERMINATION_SIGNAL_COUNT.fetch_add(1, Ordering::SeqCst);
 }
 
-fn prompt_for_string_confirm_impl<IS: AbstractStream, OS: AbstractStream>(
-    input_stream: &mut IS,
-    input_reader: &mut io::BufReader<Box<dyn Read>>,
-    output_stream: &mut OS,
-    prompt: &str,
-    is_sensitive: bool,
-) -> Result<String> {
-    loop {
-        let string = prompt_for_string_impl(
-            input_stream,
-            input_reader,
-            output_stream,
-            prompt,
-            is_sensitive,
-        )?;
-        if string
-            == prompt_for_string_impl(
-                input_stream,
-                input_reader,
-                output_stream,
-                "Confirm: ",
-                is_sensitive,
-            )?
+fn restore_termination_handlers(previous: Vec<(c_int, libc::sigaction)>) {
+    for (signal, previous) in previous.into_iter().rev() {
+        if let Err(e) =
+            to_io_result(unsafe { libc::sigaction(signal, &previous, std::ptr::null_mut()) })
         {
-            return Ok(string);
+            debug!(
+                "Failed to restore previous handler for signal {}: {}",
+                signal, e
+            );
         }
     }
 }
 
-/// Prompt for a string as per `prompt_for_string`, but additionally have the
-/// user enter the value again to confirm we get the same answer twice. This is
-/// useful for e.g. password entry.
-pub fn prompt_for_string_confirm<IS: AbstractStream, OS: AbstractStream>(
-    mut input_stream: IS,
-    mut output_stream: OS,
-    prompt: &str,
-    is_sensitive: bool,
-) -> Result<String> {
-    let mut input_reader = build_input_reader(&mut input_stream)?;
-    prompt_for_string_confirm_impl(
-        &mut input_stream,
-        &mut input_reader,
-        &mut output_stream,
-        prompt,
-        is_sensitive,
-    )
+fn install_termination_handlers() -> Result<()> {
+    let mut state = TERMINATION_HANDLER.lock().unwrap();
+    if state.installs == 0 {
+        TERMINATION_SIGNAL.store(0, Ordering::SeqCst);
+        TERMINATION_SIGNAL_COUNT.store(0, Ordering::SeqCst);
+
+        let mut action: libc::sigaction = unsafe { MaybeUninit::zeroed().assume_init() };
+        let handler: extern "C" fn(c_int) = handle_termination_signal;
+        action.sa_sigaction = handler as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        to_io_result(unsafe { libc::sigemptyset(&mut action.sa_mask) })?;
+
+        let mut installed = Vec::with_capacity(TERMINATION_SIGNALS.len());
+        for &signal in TERMINATION_SIGNALS.iter() {
+            let mut previous: libc::sigaction = unsafe { MaybeUninit::zeroed().assume_init() };
+            if let Err(e) = to_io_result(unsafe { libc::sigaction(signal, &action, &mut previous) })
+            {
+                restore_termination_handlers(installed);
+                return Err(e.into());
+            }
+            installed.push((signal, previous));
+        }
+        state.previous = installed;
+    }
+    state.installs += 1;
+    Ok(())
 }
 
-/// MaybePromptedString is a wrapper for getting user input interactively, while
-/// also allowing the value to be specified at call time. This is useful e.g.
-/// when we want to prompt users interactively, but want to predefine the values
-/// in unit tests, or when users can specify a value either interactively or via
-/// flags.
-pub struct MaybePromptedString {
-    value: String,
-    was_provided: bool,
+fn uninstall_termination_handlers() {
+    let mut state = TERMINATION_HANDLER.lock().unwrap();
+    state.installs -= 1;
+    if state.installs == 0 {
+        restore_termination_handlers(std::mem::take(&mut state.previous));
+    }
 }
 
-impl MaybePromptedString {
-    /// Construct a new MaybePromptedString, either using the given value or
-    /// prompting the user interactively with the given options.
-    pub fn new<IS: AbstractStream, OS: AbstractStream>(
-        provided: Option<&str>,
-        mut input_stream: IS,
-        mut output_stream: OS,
-        prompt: &str,
-        is_sensitive: bool,
-        confirm: bool,
-    ) -> Result<Self> {
-        let mut input_reader = build_input_reader(&mut input_stream)?;
-        let prompted: Option<String> = match provided {
-            None => Some(match confirm {
-                false => prompt_for_string_impl(
-                    &mut input_stream,
-                    &mut input_reader,
-                    &mut output_stream,
-                    prompt,
-                    is_sensitive,
-                )?,
-                true => prompt_for_string_confirm_impl(
-                    &mut input_stream,
-                    &mut input_reader,
-                    &mut output_stream,
-                    prompt,
-                    is_sensitive,
-                )?,
-            }),
-            Some(_) => None,
-        };
+/// Return the exit code a process conventionally exits with when it is
+/// terminated by the given signal: 128 plus the signal number, so e.g. 130
+/// for SIGINT or 143 for SIGTERM.
+pub fn signal_exit_code(signal: c_int) -> i32 {
+    128 + signal
+}
 
-        let was_provided = provided.is_some();
-        let value = provided.map_or_else(|| prompted.unwrap(), |s| s.to_owned());
+/// CancellationToken is a flag which asks some long-running operation to stop
+/// early (e.g., set by `run_with_signals` when SIGINT or SIGTERM arrives).
+/// Clones share the same flag.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
 
-        Ok(MaybePromptedString {
-            value: value,
-            was_provided: was_provided,
-        })
+impl CancellationToken {
+    /// Construct a new token, which hasn't been cancelled.
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    /// Returns true if this string was provided, or false if it is the result
-    /// of an interactive prompt.
-    pub fn was_provided(&self) -> bool {
-        self.was_provided
+    /// Cancel this token (and all of its clones).
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
     }
 
-    /// "Unwraps" this structure into its underlying string.
-    pub fn into_inner(self) -> String {
-        self.value
+    /// Return whether or not this token has been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Return the underlying flag, e.g. to pass as `io::CopyOptions::cancel`.
+    pub fn as_atomic(&self) -> &AtomicBool {
+        &self.cancelled
+    }
+}
+
+/// SignalGuard installs handlers for SIGINT and SIGTERM which, instead of
+/// terminating the process, just record that the signal arrived (see
+/// `received`). The handlers are only installed once, no matter how many
+/// guards exist; the previous handlers are restored when the last guard is
+/// dropped.
+pub struct SignalGuard {
+    _private: (),
+}
+
+impl SignalGuard {
+    /// Install the handlers. Signals which arrived while no guard existed are
+    /// forgotten.
+    pub fn install() -> Result<Self> {
+        install_termination_handlers()?;
+        Ok(SignalGuard { _private: () })
+    }
+
+    /// Return the first signal which arrived since the handlers were
+    /// installed, if any.
+    pub fn received(&self) -> Option<c_int> {
+        match TERMINATION_SIGNAL.load(Ordering::SeqCst) {
+            0 => None,
+            signal => Some(signal),
+        }
+    }
+
+    /// Return how many signals have arrived since the handlers were
+    /// installed.
+    pub fn count(&self) -> usize {
+        TERMINATION_SIGNAL_COUNT.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for SignalGuard {
+    fn drop(&mut self) {
+        uninstall_termination_handlers();
+    }
+}
+
+/// Options which control how `run_with_signals` reacts to signals.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SignalOptions {
+    /// How long to wait, after the first signal arrives, for the callback to
+    /// return on its own before exiting anyway.
+    pub grace_period: Duration,
+}
+
+impl Default for SignalOptions {
+    fn default() -> Self {
+        SignalOptions {
+            grace_period: DEFAULT_SIGNAL_GRACE_PERIOD,
+        }
+    }
+}
+
+/// The result of a callback run by `run_with_signals`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SignalOutcome<R> {
+    /// The callback returned without any signal arriving.
+    Completed(R),
+    /// The callback returned after it was cancelled by the given signal.
+    Interrupted {
+        /// The (first) signal which arrived.
+        signal: c_int,
+        /// The value the callback returned.
+        result: R,
+    },
+}
+
+impl<R> SignalOutcome<R> {
+    /// Return the code the process should exit with because it was
+    /// interrupted (see `signal_exit_code`), or None if it wasn't.
+    pub fn exit_code(&self) -> Option<i32> {
+        match self {
+            SignalOutcome::Completed(_) => None,
+            SignalOutcome::Interrupted { signal, .. } => Some(signal_exit_code(*signal)),
+        }
+    }
+
+    /// Return the value the callback returned, whether or not it was
+    /// interrupted.
+    pub fn into_result(self) -> R {
+        match self {
+            SignalOutcome::Completed(result) => result,
+            SignalOutcome::Interrupted { result, .. } => result,
+        }
+    }
+}
+
+/// Clean up and exit after the grace period for a signal expired: restore any
+/// terminal attributes we modified (e.g. a prompt which disabled echo), flush
+/// any buffered output (including log messages), and exit.
+fn exit_after_signal(signal: c_int) {
+    restore_terminal_attributes();
+    let _ = io::stdout().flush();
+    let _ = io::stderr().flush();
+    std::process::exit(signal_exit_code(signal));
+}
+
+/// Run `f` with SIGINT and SIGTERM handled cooperatively. When either signal
+/// arrives, the token passed to `f` is cancelled, giving it a chance to clean
+/// up (e.g. persist state, or remove temporary files) and return early; in
+/// that case, the returned `SignalOutcome` says which signal arrived, and the
+/// caller should exit with `SignalOutcome::exit_code`.
+///
+/// If `f` doesn't return within the grace period, or if a second signal
+/// arrives, the process exits immediately with the appropriate code (130 for
+/// SIGINT, 143 for SIGTERM), after restoring any terminal attributes modified
+/// by this module (see `restore_terminal_attributes`) and flushing stdout and
+/// stderr. If `f` panics, the panic is resumed once the handlers have been
+/// removed.
+pub fn run_with_signals<R, F: FnOnce(&CancellationToken) -> R>(
+    options: SignalOptions,
+    f: F,
+) -> Result<SignalOutcome<R>> {
+    run_with_signals_impl(options, f, exit_after_signal)
+}
+
+/// This is identical to `run_with_signals`, except `exit` is called (on a
+/// background thread) instead of exiting the process.
+pub(crate) fn run_with_signals_impl<R, F: FnOnce(&CancellationToken) -> R>(
+    options: SignalOptions,
+    f: F,
+    exit: fn(c_int),
+) -> Result<SignalOutcome<R>> {
+    let guard = SignalGuard::install()?;
+    let token = CancellationToken::new();
+    let done = Arc::new(AtomicBool::new(false));
+
+    let monitor = {
+        let token = token.clone();
+        let done = done.clone();
+        thread::spawn(move || {
+            let mut deadline = None;
+            while !done.load(Ordering::SeqCst) {
+                let signal = TERMINATION_SIGNAL.load(Ordering::SeqCst);
+                if signal != 0 {
+                    token.cancel();
+                    let deadline =
+                        *deadline.get_or_insert_with(|| Instant::now() + options.grace_period);
+                    if TERMINATION_SIGNAL_COUNT.load(Ordering::SeqCst) > 1
+                        || Instant::now() >= deadline
+                    {
+                        exit(signal);
+                        return;
+                    }
+                }
+                thread::sleep(SIGNAL_POLL_INTERVAL);
+            }
+        })
+    };
+
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| f(&token)));
+    done.store(true, Ordering::SeqCst);
+    if monitor.join().is_err() {
+        debug!("Signal monitoring thread panicked");
+    }
+    let signal = guard.received();
+    drop(guard);
+
+    let result = match result {
+        Err(payload) => std::panic::resume_unwind(payload),
+        Ok(result) => result,
+    };
+    Ok(match signal {
+        None => SignalOutcome::Completed(result),
+        Some(signal) => SignalOutcome::Interrupted { signal, result },
+    })
+}
+
+/// Word-wrap the given text, such that no line is wider than `width` terminal
+/// columns (as measured by `display_width`). Paragraphs (separated by blank
+/// lines) are preserved, with a single empty line between them in the output.
+/// Any other whitespace is collapsed. Words which are wider than `width` on
+/// their own are broken up across several lines, but ANSI escape sequences
+/// (e.g. colors, or hyperlinks) are never split.
+pub fn wrap_text(text: &str, width: usize) -> Vec<String> {
+    // Guard against a zero width, which would otherwise never make progress.
+    let width = std::cmp::max(width, 1);
+
+    let mut lines = Vec::new();
+    for (i, paragraph) in text
+        .split("\n\n")
+        .filter(|p| !p.trim().is_empty())
+        .enumerate()
+    {
+        if i > 0 {
+            lines.push(String::new());
+        }
+
+        let mut line = String::new();
+        let mut line_len = 0;
+        for word in ansi_words(paragraph) {
+            let mut word = word.as_slice();
+            let mut word_len: usize = word.iter().map(AnsiSegment::width).sum();
+
+            if line_len > 0 && line_len + 1 + word_len > width {
+                lines.push(std::mem::take(&mut line));
+                line_len = 0;
+            }
+            // Hard-break words which will never fit on a line by themselves.
+            while word_len > width {
+                if !line.is_empty() {
+                    lines.push(std::mem::take(&mut line));
+                    line_len = 0;
+                }
+                let mut piece_len = 0;
+                let mut taken = 0;
+                for segment in word {
+                    // Always take at least one character, even if it's wider
+                    // than the whole line.
+                    if piece_len > 0 && piece_len + segment.width() > width {
+                        break;
+                    }
+                    segment.push_to(&mut line);
+                    piece_len += segment.width();
+                    taken += 1;
+                }
+                lines.push(std::mem::take(&mut line));
+                word = &word[taken..];
+                word_len -= piece_len;
+            }
+            if word.is_empty() {
+                continue;
+            }
+
+            if !line.is_empty() {
+                line.push(' ');
+                line_len += 1;
+            }
+            line_len += word_len;
+            for segment in word {
+                segment.push_to(&mut line);
+            }
+        }
+        if !line.is_empty() {
+            lines.push(line);
+        }
+    }
+    lines
+}
+
+/// Returns the number of terminal columns the given string occupies, taking
+/// wide (e.g. CJK) characters into account. ANSI escape sequences (e.g.
+/// colors, or hyperlinks) take up no space.
+pub fn display_width(s: &str) -> usize {
+    ansi_segments(s).iter().map(AnsiSegment::width).sum()
+}
+
+/// Alignment describes how a `Table` cell is padded to its column's width.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Alignment {
+    /// Pad on the right.
+    #[default]
+    Left,
+    /// Pad on the left.
+    Right,
+    /// Pad evenly on both sides (with any odd column on the right).
+    Center,
+}
+
+/// Truncation describes how a `Table` cell which is too wide for its column is
+/// shortened.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Truncation {
+    /// Keep the beginning of the cell, replacing the end with an ellipsis.
+    #[default]
+    End,
+    /// Keep the beginning and end of the cell, replacing the middle with an
+    /// ellipsis. This is useful e.g. for paths or identifiers.
+    Middle,
+}
+
+const ELLIPSIS: char = '…';
+
+fn truncate_cell(cell: &str, width: usize, truncation: Truncation) -> String {
+    if display_width(cell) <= width {
+        return cell.to_string();
+    }
+    if width == 0 {
+        return String::new();
+    }
+
+    let segments = ansi_segments(cell);
+    let chars: Vec<char> = segments
+        .iter()
+        .filter_map(|segment| match *segment {
+            AnsiSegment::Char(c) => Some(c),
+            AnsiSegment::Escape(_) => None,
+        })
+        .collect();
+
+    // Figure out how many characters to keep from either end of the cell.
+    let available = width - 1;
+    let (head, tail) = match truncation {
+        Truncation::End => (take_width(chars.iter().copied(), available).len(), 0),
+        Truncation::Middle => {
+            let tail_width = available / 2;
+            (
+                take_width(chars.iter().copied(), available - tail_width).len(),
+                take_width(chars.iter().rev().copied(), tail_width).len(),
+            )
+        }
+    };
+
+    // Escape sequences are always kept, even if the characters around them
+    // are dropped, so e.g. a hyperlink is always closed properly.
+    let mut truncated = String::with_capacity(cell.len());
+    let mut index = 0;
+    for segment in segments {
+        match segment {
+            AnsiSegment::Escape(escape) => truncated.push_str(escape),
+            AnsiSegment::Char(c) => {
+                if index == head {
+                    truncated.push(ELLIPSIS);
+                }
+                if index < head || index >= chars.len() - tail {
+                    truncated.push(c);
+                }
+                index += 1;
+            }
+        }
+    }
+    truncated
+}
+
+fn pad_cell(cell: &str, width: usize, alignment: Alignment) -> String {
+    let padding = width.saturating_sub(display_width(cell));
+    let (left, right) = match alignment {
+        Alignment::Left => (0, padding),
+        Alignment::Right => (padding, 0),
+        Alignment::Center => (padding / 2, padding - padding / 2),
+    };
+    format!("{}{}{}", " ".repeat(left), cell, " ".repeat(right))
+}
+
+/// Cells are rendered on a single line, so replace any embedded line breaks or
+/// tabs (which would break alignment, or the plain format) with spaces.
+fn sanitize_cell(cell: &str) -> String {
+    cell.replace(['\t', '\r', '\n'], " ")
+}
+
+#[derive(Clone, Debug, Default)]
+struct ColumnOptions {
+    alignment: Alignment,
+    max_width: Option<usize>,
+    truncation: Truncation,
+}
+
+/// The separator between columns, when rendering for a terminal.
+const TABLE_COLUMN_SEPARATOR: &str = "  ";
+
+/// Table renders rows of cells as aligned columns.
+///
+/// When rendered to a TTY (see `render_to`), columns are sized to fit their
+/// contents, bounded by the width of the terminal; cells which don't fit are
+/// truncated. When rendered to anything else (e.g. a pipe), cells are instead
+/// separated by a single tab (by default) with no padding or truncation, so
+/// the output is easy to process with other tools.
+#[derive(Clone, Debug)]
+pub struct Table {
+    header: Option<Vec<String>>,
+    underline_header: bool,
+    plain_separator: String,
+    columns: Vec<ColumnOptions>,
+    rows: Vec<Vec<String>>,
+}
+
+impl Default for Table {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Table {
+    /// Construct a new empty table, with no header.
+    pub fn new() -> Self {
+        Table {
+            header: None,
+            underline_header: true,
+            plain_separator: "\t".to_string(),
+            columns: Vec::new(),
+            rows: Vec::new(),
+        }
+    }
+
+    /// Set this table's header row.
+    pub fn header<D: fmt::Display, I: IntoIterator<Item = D>>(mut self, cells: I) -> Self {
+        self.header = Some(
+            cells
+                .into_iter()
+                .map(|c| sanitize_cell(&c.to_string()))
+                .collect(),
+        );
+        self
+    }
+
+    /// Set whether or not the header row (if any) is followed by a line of
+    /// dashes when rendering for a terminal. Defaults to true.
+    pub fn underline_header(mut self, underline: bool) -> Self {
+        self.underline_header = underline;
+        self
+    }
+
+    /// Set the separator used between cells when rendering for something
+    /// other than a terminal. Defaults to a single tab.
+    pub fn plain_separator(mut self, separator: &str) -> Self {
+        self.plain_separator = separator.to_string();
+        self
+    }
+
+    fn column_mut(&mut self, column: usize) -> &mut ColumnOptions {
+        if self.columns.len() <= column {
+            self.columns.resize(column + 1, ColumnOptions::default());
+        }
+        &mut self.columns[column]
+    }
+
+    fn column(&self, column: usize) -> ColumnOptions {
+        self.columns.get(column).cloned().unwrap_or_default()
+    }
+
+    /// Set the alignment of the given (zero-indexed) column.
+    pub fn align(mut self, column: usize, alignment: Alignment) -> Self {
+        self.column_mut(column).alignment = alignment;
+        self
+    }
+
+    /// Set the maximum width of the given (zero-indexed) column. Cells which
+    /// are wider than this are truncated, even if the terminal is wide enough.
+    pub fn max_width(mut self, column: usize, max_width: usize) -> Self {
+        self.column_mut(column).max_width = Some(max_width);
+        self
+    }
+
+    /// Set how cells in the given (zero-indexed) column are truncated, if
+    /// they're too wide.
+    pub fn truncation(mut self, column: usize, truncation: Truncation) -> Self {
+        self.column_mut(column).truncation = truncation;
+        self
+    }
+
+    /// Add a row of cells to this table. Rows may have different numbers of
+    /// cells; missing cells are rendered as empty.
+    pub fn add_row<D: fmt::Display, I: IntoIterator<Item = D>>(&mut self, cells: I) {
+        self.rows.push(
+            cells
+                .into_iter()
+                .map(|c| sanitize_cell(&c.to_string()))
+                .collect(),
+        );
+    }
+
+    fn all_rows(&self) -> impl Iterator<Item = &Vec<String>> {
+        self.header.iter().chain(self.rows.iter())
+    }
+
+    fn column_count(&self) -> usize {
+        self.all_rows().map(|row| row.len()).max().unwrap_or(0)
+    }
+
+    /// Compute the width of each column, such that the whole table fits in
+    /// the given width if possible.
+    fn column_widths(&self, width: usize) -> Vec<usize> {
+        let count = self.column_count();
+        let mut widths: Vec<usize> = (0..count)
+            .map(|i| {
+                let natural = self
+                    .all_rows()
+                    .filter_map(|row| row.get(i))
+                    .map(|cell| display_width(cell))
+                    .max()
+                    .unwrap_or(0);
+                match self.column(i).max_width {
+                    Some(max) => std::cmp::min(natural, max),
+                    None => natural,
+                }
+            })
+            .collect();
+
+        // Shrink the widest column one step at a time, until everything fits
+        // (or until every column is as narrow as we're willing to go).
+        let separators = count.saturating_sub(1) * TABLE_COLUMN_SEPARATOR.len();
+        let available = width.saturating_sub(separators);
+        while widths.iter().sum::<usize>() > available {
+            let (widest, &w) = widths
+                .iter()
+                .enumerate()
+                .max_by_key(|&(i, &w)| (w, std::cmp::Reverse(i)))
+                .unwrap();
+            if w <= 1 {
+                break;
+            }
+            widths[widest] -= 1;
+        }
+        widths
+    }
+
+    /// Render this table with aligned columns, fitting it into the given
+    /// width (in columns) if possible. Each line is terminated by a newline.
+    pub fn render(&self, width: usize) -> String {
+        let widths = self.column_widths(width);
+        let render_row = |row: &Vec<String>| -> String {
+            let line: Vec<String> = widths
+                .iter()
+                .enumerate()
+                .map(|(i, &w)| {
+                    let options = self.column(i);
+                    let cell = row.get(i).map(String::as_str).unwrap_or("");
+                    pad_cell(
+                        &truncate_cell(cell, w, options.truncation),
+                        w,
+                        options.alignment,
+                    )
+                })
+                .collect();
+            let mut line = line.join(TABLE_COLUMN_SEPARATOR).trim_end().to_string();
+            line.push('\n');
+            line
+        };
+
+        let mut out = String::new();
+        if let Some(header) = self.header.as_ref() {
+            out.push_str(&render_row(header));
+            if self.underline_header {
+                let underline: Vec<String> = widths.iter().map(|&w| "-".repeat(w)).collect();
+                out.push_str(&underline.join(TABLE_COLUMN_SEPARATOR));
+                out.push('\n');
+            }
+        }
+        for row in &self.rows {
+            out.push_str(&render_row(row));
+        }
+        out
+    }
+
+    /// Render this table in a machine-friendly format: cells are separated by
+    /// the plain separator, with no padding or truncation. Each line is
+    /// terminated by a newline.
+    pub fn render_plain(&self) -> String {
+        let mut out = String::new();
+        for row in self.all_rows() {
+            out.push_str(&row.join(&self.plain_separator));
+            out.push('\n');
+        }
+        out
+    }
+
+    /// Write this table to the given stream. If the stream is a TTY, this
+    /// uses `render` with the terminal's width (or `DEFAULT_TERMINAL_WIDTH`
+    /// if it can't be determined). Otherwise, this uses `render_plain`.
+    pub fn render_to<S: AbstractStream>(&self, stream: &S) -> Result<()> {
+        let rendered = if stream.isatty() {
+            let width = terminal_size(stream)
+                .map(|(cols, _)| cols as usize)
+                .unwrap_or(DEFAULT_TERMINAL_WIDTH);
+            self.render(width)
+        } else {
+            self.render_plain()
+        };
+
+        let mut writer = match stream.as_writer() {
+            None => {
+                return Err(Error::Precondition(
+                    "the given output stream must support `Write`".to_string(),
+                ))
+            }
+            Some(w) => w,
+        };
+        writer.write_all(rendered.as_bytes())?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Messages is a catalog of the user-facing strings this module displays, so
+/// applications can localize them. Every method has a default implementation
+/// returning the standard English text, so implementations only need to
+/// override the strings they want to change.
+pub trait Messages: Send + Sync {
+    /// The prompt used to ask the user to re-enter a value, in
+    /// `prompt_for_string_confirm`.
+    fn confirm_prompt(&self) -> String {
+        "Confirm: ".to_string()
+    }
+
+    /// The message a `Wizard` displays when the value entered at a step's
+    /// confirm prompt doesn't match the original, before prompting for both
+    /// again.
+    fn confirm_mismatch(&self) -> String {
+        "The values did not match. Please try again.".to_string()
+    }
+
+    /// The prompt displayed by `continue_confirmation`, given the caller's
+    /// description of what is about to happen.
+    fn continue_prompt(&self, description: &str) -> String {
+        format!("{}Continue? [Yes/No] ", description)
+    }
+
+    /// The message displayed when the user's response to
+    /// `continue_confirmation` is neither affirmative nor negative.
+    fn invalid_response(&self, response: &str) -> String {
+        format!("Invalid response '{}'.", response)
+    }
+
+    /// The (lowercase) responses `continue_confirmation` accepts as "yes".
+    fn affirmative_answers(&self) -> Vec<String> {
+        vec!["y".to_string(), "yes".to_string()]
+    }
+
+    /// The (lowercase) responses `continue_confirmation` accepts as "no".
+    fn negative_answers(&self) -> Vec<String> {
+        vec!["n".to_string(), "no".to_string()]
+    }
+
+    /// The message displayed by `prompt_for_new_password` when the entered
+    /// password doesn't satisfy the policy, before any suggestions.
+    fn password_too_weak(&self) -> String {
+        "This password is too weak.".to_string()
+    }
+
+    /// The suggestion displayed by `prompt_for_new_password` when the entered
+    /// password is shorter than the policy's minimum length.
+    fn password_too_short(&self, min_length: usize) -> String {
+        format!("Use at least {} characters.", min_length)
+    }
+
+    /// The (lowercase) response which returns to the previous `Wizard` step.
+    fn wizard_back(&self) -> String {
+        "back".to_string()
+    }
+
+    /// The (lowercase) response which cancels a `Wizard`.
+    fn wizard_cancel(&self) -> String {
+        "cancel".to_string()
+    }
+
+    /// The prompt displayed by a `Wizard`'s summary step, after listing the
+    /// answers.
+    fn wizard_summary_prompt(&self) -> String {
+        "Proceed with these settings? [Yes/No/back] ".to_string()
+    }
+}
+
+/// DefaultMessages is the standard (English) `Messages` catalog.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultMessages;
+
+impl Messages for DefaultMessages {}
+
+static MESSAGES: Lazy<RwLock<Arc<dyn Messages>>> =
+    Lazy::new(|| RwLock::new(Arc::new(DefaultMessages)));
+
+/// Replace the global `Messages` catalog, which is used by every function in
+/// this module which isn't given a catalog explicitly.
+pub fn set_messages(messages: Box<dyn Messages>) {
+    *MESSAGES.write().unwrap() = Arc::from(messages);
+}
+
+/// Return the current global `Messages` catalog.
+pub fn messages() -> Arc<dyn Messages> {
+    MESSAGES.read().unwrap().clone()
+}
+
+/// A function which restores some stream's terminal attributes (see
+/// `AbstractStream::attribute_restorer`).
+pub type AttributeRestorer = Box<dyn FnMut() -> IoResult<()> + Send>;
+
+/// Every guard (e.g. `DisableEcho`) which has modified some stream's terminal
+/// attributes and not yet restored them, keyed by a unique ID.
+///
+/// Guards only hold this lock briefly, to register or unregister themselves,
+/// and never while calling into other code, so it's safe to recover the data
+/// if a panic poisoned it.
+static ACTIVE_ATTRIBUTE_GUARDS: Mutex<Vec<(usize, AttributeRestorer)>> = Mutex::new(Vec::new());
+static NEXT_ATTRIBUTE_GUARD_ID: AtomicUsize = AtomicUsize::new(0);
+
+fn active_attribute_guards() -> MutexGuard<'static, Vec<(usize, AttributeRestorer)>> {
+    ACTIVE_ATTRIBUTE_GUARDS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+}
+
+fn register_attribute_guard(restorer: AttributeRestorer) -> usize {
+    let id = NEXT_ATTRIBUTE_GUARD_ID.fetch_add(1, Ordering::SeqCst);
+    active_attribute_guards().push((id, restorer));
+    id
+}
+
+fn unregister_attribute_guard(id: usize) {
+    active_attribute_guards().retain(|(guard_id, _)| *guard_id != id);
+}
+
+/// Restore the terminal attributes of every stream this module has modified
+/// and not yet restored (e.g. because a password prompt disabled echo), in
+/// the reverse order they were modified. Returns how many streams were
+/// restored.
+///
+/// This is intended for cleaning up when the process is about to exit
+/// abnormally, e.g. after observing a SIGINT, so the user isn't left with a
+/// terminal which doesn't echo. Note that it isn't async-signal-safe, so it
+/// must not be called from inside a signal handler itself.
+pub fn restore_terminal_attributes() -> usize {
+    let guards = std::mem::take(&mut *active_attribute_guards());
+    let restored = guards.len();
+    for (_, mut restorer) in guards.into_iter().rev() {
+        if let Err(e) = restorer() {
+            debug!("Failed to restore terminal attributes: {}", e);
+        }
+    }
+    restored
+}
+
+/// TerminalStateGuard captures a stream's terminal attributes, and restores
+/// them when it is dropped (or when `restore` is called), no matter how they
+/// were changed in the meantime (e.g. by a subprocess which disabled echo, and
+/// then crashed). While it is alive, it is registered so the attributes can
+/// also be restored out-of-band, via `restore_terminal_attributes`.
+///
+/// Guards for the same stream can be nested; as long as they're dropped in the
+/// reverse order they were created, the outermost guard's state is the one
+/// which is left in place at the end.
+pub struct TerminalStateGuard<'s, S: AbstractStream> {
+    stream: &'s mut S,
+    attributes: S::Attributes,
+    guard_id: Option<usize>,
+    restored: bool,
+}
+
+impl<'s, S: AbstractStream> TerminalStateGuard<'s, S> {
+    /// Capture the given stream's current terminal attributes.
+    pub fn capture(stream: &'s mut S) -> Result<Self> {
+        let attributes = stream.get_attributes()?;
+        debug!("Captured stream attributes: {:#?}", attributes);
+        // Register before anything has a chance to change, so there's no
+        // window where the attributes are modified but can't be restored.
+        let guard_id = stream
+            .attribute_restorer(&attributes)
+            .map(register_attribute_guard);
+        Ok(TerminalStateGuard {
+            stream,
+            attributes,
+            guard_id,
+            restored: false,
+        })
+    }
+
+    /// Return the attributes this guard captured (and will restore).
+    pub fn attributes(&self) -> &S::Attributes {
+        &self.attributes
+    }
+
+    /// Return the guarded stream, e.g. to modify its attributes.
+    pub fn stream(&mut self) -> &mut S {
+        self.stream
+    }
+
+    /// Restore the captured attributes now, returning any error instead of
+    /// just logging it (as dropping the guard does).
+    pub fn restore(mut self) -> Result<()> {
+        self.restore_impl()
+    }
+
+    fn restore_impl(&mut self) -> Result<()> {
+        // Restoring is only attempted once, whether or not it works.
+        if self.restored {
+            return Ok(());
+        }
+        self.restored = true;
+        if let Some(id) = self.guard_id.take() {
+            unregister_attribute_guard(id);
+        }
+        Ok(self.stream.set_attributes(&self.attributes)?)
+    }
+}
+
+impl<'s, S: AbstractStream> Drop for TerminalStateGuard<'s, S> {
+    fn drop(&mut self) {
+        if let Err(e) = self.restore_impl() {
+            debug!("Failed to restore terminal attributes: {}", e);
+        }
+    }
+}
+
+/// Capture the terminal attributes of each of the given streams, run `f`, and
+/// then restore the attributes (in the reverse order they were captured), even
+/// if `f` panics. If it does, the panic is resumed once the attributes have
+/// been restored. See `run_with_terminal` for the common case.
+pub fn run_with_terminal_streams<S: AbstractStream, R, F: FnOnce() -> R>(
+    streams: &mut [S],
+    f: F,
+) -> Result<R> {
+    let mut guards = Vec::with_capacity(streams.len());
+    for stream in streams.iter_mut() {
+        guards.push(TerminalStateGuard::capture(stream)?);
+    }
+
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f));
+
+    let mut restored = Ok(());
+    while let Some(guard) = guards.pop() {
+        let ret = guard.restore();
+        if restored.is_ok() {
+            restored = ret;
+        }
+    }
+    match result {
+        Err(payload) => std::panic::resume_unwind(payload),
+        Ok(r) => restored.map(|_| r),
+    }
+}
+
+/// Run `f` (typically, spawning an interactive subprocess like a pager or an
+/// editor, and waiting for it), and afterwards restore the terminal attributes
+/// of the given streams to what they were beforehand, even if `f` panics.
+/// Streams which aren't TTYs are ignored. This way, a subprocess which leaves
+/// the terminal in a broken state (e.g. in raw mode, or without echo) doesn't
+/// affect us once it has exited.
+///
+/// This module doesn't handle SIGCONT itself. If the process is stopped and
+/// continued while `f` is running, the attributes are still restored once it
+/// returns; applications which want to restore them immediately on SIGCONT can
+/// call `restore_terminal_attributes` (outside of the signal handler).
+pub fn run_with_terminal<R, F: FnOnce() -> R>(guard_streams: &[Stream], f: F) -> Result<R> {
+    let mut streams: Vec<Stream> = guard_streams
+        .iter()
+        .filter(|s| s.isatty())
+        .cloned()
+        .collect();
+    run_with_terminal_streams(streams.as_mut_slice(), f)
+}
+
+/// This structure handles a) disabling the echoing of characters typed to
+/// `Stdin`, and b) remembering to reset the terminal attributes afterwards
+/// (via `Drop`). It is built on a `TerminalStateGuard`, so it can also be
+/// reset out-of-band, via `restore_terminal_attributes`.
+struct DisableEcho<'s, S: AbstractStream> {
+    _guard: TerminalStateGuard<'s, S>,
+}
+
+impl<'s, S: AbstractStream> DisableEcho<'s, S> {
+    fn new(stream: &'s mut S) -> Result<Self> {
+        let mut guard = TerminalStateGuard::capture(stream)?;
+
+        let mut attributes = guard.stream().get_attributes()?;
+        // Don't echo characters typed to stdin.
+        attributes.disable(TerminalFlag::Echo);
+        // But, *do* echo the newline when the user hits ENTER.
+        attributes.enable(TerminalFlag::EchoNewlines);
+        debug!("Setting attributes to: {:#?}", attributes);
+        guard.stream().set_attributes(&attributes)?;
+
+        Ok(DisableEcho { _guard: guard })
+    }
+}
+
+fn require_isatty<S: AbstractStream>(s: &mut S) -> Result<()> {
+    if !s.isatty() {
+        Err(Error::Precondition(format!(
+            "cannot prompt interactively when the I/O streams are not TTYs"
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+fn build_input_reader<IS: AbstractStream>(
+    input_stream: &mut IS,
+) -> Result<io::BufReader<Box<dyn Read>>> {
+    require_isatty(input_stream)?;
+    Ok(io::BufReader::new(match input_stream.as_reader() {
+        None => {
+            return Err(Error::Precondition(format!(
+                "the given input stream must support `Read`"
+            )))
+        }
+        Some(r) => r,
+    }))
+}
+
+fn remove_newline(mut s: String) -> Result<String> {
+    // Remove the trailing newline (if any - not finding one is an error).
+    if !s.ends_with('\n') {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "unexpected end of input").into());
+    }
+    s.pop();
+
+    // If this is windows and so there's also a \r, remove that too.
+    if s.ends_with('\r') {
+        s.pop();
+    }
+
+    Ok(s)
+}
+
+fn prompt_for_string_impl<IS: AbstractStream, OS: AbstractStream>(
+    input_stream: &mut IS,
+    // We have to take the reader as a parameter, since it must be "global",
+    // even if this function is e.g. called in a loop. Otherwise, because it's
+    // buffered, we might buffer some input and then discard it.
+    input_reader: &mut io::BufReader<Box<dyn Read>>,
+    output_stream: &mut OS,
+    prompt: &str,
+    is_sensitive: bool,
+) -> Result<String> {
+    use io::BufRead;
+
+    require_isatty(output_stream)?;
+    // It's fine to construct a separate writer, potentially on each loop
+    // iteration or whatever, because we flush immediately, and don't do any
+    // buffering.
+    let mut writer = match output_stream.as_writer() {
+        None => {
+            return Err(Error::Precondition(format!(
+                "the given output stream must support `Write`"
+            )))
+        }
+        Some(w) => w,
+    };
+
+    write!(writer, "{}", prompt)?;
+    // We have to flush so the user sees the prompt immediately.
+    writer.flush()?;
+
+    Ok({
+        let _disable_echo = match is_sensitive {
+            false => None,
+            true => Some(DisableEcho::new(input_stream)?),
+        };
+        let mut ret = String::new();
+        input_reader.read_line(&mut ret)?;
+        remove_newline(ret)?
+    })
+}
+
+/// Prompt the user for a string (read from the given input stream) using the
+/// given output stream (typically standard output or standard error) to display
+/// the given prompt message.
+///
+/// If `is_sensitive` is true, then the users characters will not be echoed back
+/// (e.g. this will behave like a password prompt).
+///
+/// Note that there are various requirements for the given streams, and this
+/// function will return an error if any of them are not met:
+///
+/// - Both `input_stream` and `output_stream` must be TTYs.
+/// - `input_stream` must return a valid `Read` instance.
+/// - `output_stream` must return a valid `Write` instance.
+pub fn prompt_for_string<IS: AbstractStream, OS: AbstractStream>(
+    mut input_stream: IS,
+    mut output_stream: OS,
+    prompt: &str,
+    is_sensitive: bool,
+) -> Result<String> {
+    let mut input_reader = build_input_reader(&mut input_stream)?;
+    prompt_for_string_impl(
+        &mut input_stream,
+        &mut input_reader,
+        &mut output_stream,
+        prompt,
+        is_sensitive,
+    )
+}
+
+fn prompt_for_string_confirm_impl<IS: AbstractStream, OS: AbstractStream>(
+    input_stream: &mut IS,
+    input_reader: &mut io::BufReader<Box<dyn Read>>,
+    output_stream: &mut OS,
+    prompt: &str,
+    is_sensitive: bool,
+) -> Result<String> {
+    let confirm_prompt = messages().confirm_prompt();
+    loop {
+        let string = prompt_for_string_impl(
+            input_stream,
+            input_reader,
+            output_stream,
+            prompt,
+            is_sensitive,
+        )?;
+        if string
+            == prompt_for_string_impl(
+                input_stream,
+                input_reader,
+                output_stream,
+                confirm_prompt.as_str(),
+                is_sensitive,
+            )?
+        {
+            return Ok(string);
+        }
+    }
+}
+
+/// Prompt for a string as per `prompt_for_string`, but additionally have the
+/// user enter the value again to confirm we get the same answer twice. This is
+/// useful for e.g. password entry.
+pub fn prompt_for_string_confirm<IS: AbstractStream, OS: AbstractStream>(
+    mut input_stream: IS,
+    mut output_stream: OS,
+    prompt: &str,
+    is_sensitive: bool,
+) -> Result<String> {
+    let mut input_reader = build_input_reader(&mut input_stream)?;
+    prompt_for_string_confirm_impl(
+        &mut input_stream,
+        &mut input_reader,
+        &mut output_stream,
+        prompt,
+        is_sensitive,
+    )
+}
+
+/// MaybePromptedString is a wrapper for getting user input interactively, while
+/// also allowing the value to be specified at call time. This is useful e.g.
+/// when we want to prompt users interactively, but want to predefine the values
+/// in unit tests, or when users can specify a value either interactively or via
+/// flags.
+pub struct MaybePromptedString {
+    value: String,
+    was_provided: bool,
+}
+
+impl MaybePromptedString {
+    /// Construct a new MaybePromptedString, either using the given value or
+    /// prompting the user interactively with the given options.
+    pub fn new<IS: AbstractStream, OS: AbstractStream>(
+        provided: Option<&str>,
+        mut input_stream: IS,
+        mut output_stream: OS,
+        prompt: &str,
+        is_sensitive: bool,
+        confirm: bool,
+    ) -> Result<Self> {
+        let mut input_reader = build_input_reader(&mut input_stream)?;
+        let prompted: Option<String> = match provided {
+            None => Some(match confirm {
+                false => prompt_for_string_impl(
+                    &mut input_stream,
+                    &mut input_reader,
+                    &mut output_stream,
+                    prompt,
+                    is_sensitive,
+                )?,
+                true => prompt_for_string_confirm_impl(
+                    &mut input_stream,
+                    &mut input_reader,
+                    &mut output_stream,
+                    prompt,
+                    is_sensitive,
+                )?,
+            }),
+            Some(_) => None,
+        };
+
+        let was_provided = provided.is_some();
+        let value = provided.map_or_else(|| prompted.unwrap(), |s| s.to_owned());
+
+        Ok(MaybePromptedString {
+            value: value,
+            was_provided: was_provided,
+        })
+    }
+
+    /// Returns true if this string was provided, or false if it is the result
+    /// of an interactive prompt.
+    pub fn was_provided(&self) -> bool {
+        self.was_provided
+    }
+
+    /// "Unwraps" this structure into its underlying string.
+    pub fn into_inner(self) -> String {
+        self.value
     }
 }
 
 /// Display a "<description> Continue?" confirmation. Returns true if the user
 /// replies "yes" (or similar), or false otherwise.
 pub fn continue_confirmation<IS: AbstractStream, OS: AbstractStream>(
+    input_stream: IS,
+    output_stream: OS,
+    description: &str,
+) -> Result<bool> {
+    continue_confirmation_with_messages(input_stream, output_stream, description, &*messages())
+}
+
+/// This is identical to `continue_confirmation`, except the given `Messages`
+/// catalog is used instead of the global one.
+pub fn continue_confirmation_with_messages<IS: AbstractStream, OS: AbstractStream>(
+    mut input_stream: IS,
+    mut output_stream: OS,
+    description: &str,
+    messages: &dyn Messages,
+) -> Result<bool> {
+    let mut input_reader = build_input_reader(&mut input_stream)?;
+    let prompt = messages.continue_prompt(description);
+    let affirmative = messages.affirmative_answers();
+    let negative = messages.negative_answers();
+
+    loop {
+        let original_response = prompt_for_string_impl(
+            &mut input_stream,
+            &mut input_reader,
+            &mut output_stream,
+            prompt.as_str(),
+            /*is_sensitive=*/ false,
+        )?;
+        let response = original_response.trim().to_lowercase();
+        if affirmative.contains(&response) {
+            return Ok(true);
+        } else if negative.contains(&response) {
+            return Ok(false);
+        } else {
+            let mut writer = match output_stream.as_writer() {
+                None => {
+                    return Err(Error::Precondition(format!(
+                        "the given output stream must support `Write`"
+                    )))
+                }
+                Some(w) => w,
+            };
+            write!(
+                writer,
+                "{}\n",
+                messages.invalid_response(original_response.as_str())
+            )?;
+            // We have to flush so the user sees the prompt immediately.
+            writer.flush()?;
+        }
+    }
+}
+
+/// A function which checks a wizard step's answer, returning a message to show
+/// the user (before asking again) if the answer is unacceptable.
+pub type WizardValidator = Box<dyn Fn(&str) -> std::result::Result<(), String> + Send + Sync>;
+
+/// WizardStep describes a single question asked by a `Wizard`. Its answer is
+/// stored in the resulting `WizardAnswers` under the step's key.
+pub struct WizardStep {
+    key: String,
+    prompt: String,
+    is_sensitive: bool,
+    confirm: bool,
+    default: Option<String>,
+    choices: Option<Vec<String>>,
+    validator: Option<WizardValidator>,
+}
+
+impl WizardStep {
+    /// Construct a new step which stores its answer under the given key, and
+    /// which prompts the user with the given prompt (e.g. "Data directory: ").
+    pub fn new(key: &str, prompt: &str) -> Self {
+        WizardStep {
+            key: key.to_owned(),
+            prompt: prompt.to_owned(),
+            is_sensitive: false,
+            confirm: false,
+            default: None,
+            choices: None,
+            validator: None,
+        }
+    }
+
+    /// Don't echo the user's answer (e.g. for passwords). Sensitive answers
+    /// are also never pre-filled when the user goes back to this step.
+    pub fn sensitive(mut self) -> Self {
+        self.is_sensitive = true;
+        self
+    }
+
+    /// Have the user enter the answer twice, as per
+    /// `prompt_for_string_confirm`.
+    pub fn confirm(mut self) -> Self {
+        self.confirm = true;
+        self
+    }
+
+    /// Use the given value if the user enters an empty answer.
+    pub fn default_value(mut self, default: &str) -> Self {
+        self.default = Some(default.to_owned());
+        self
+    }
+
+    /// Have the user pick one of the given choices, either by entering it
+    /// exactly or by entering its (1-based) number in the displayed list.
+    pub fn select<S: AsRef<str>>(mut self, choices: &[S]) -> Self {
+        self.choices = Some(choices.iter().map(|c| c.as_ref().to_owned()).collect());
+        self
+    }
+
+    /// Check each answer with the given validator, asking again if it fails.
+    pub fn validator<F: Fn(&str) -> std::result::Result<(), String> + Send + Sync + 'static>(
+        mut self,
+        validator: F,
+    ) -> Self {
+        self.validator = Some(Box::new(validator));
+        self
+    }
+
+    /// Returns the key this step's answer is stored under.
+    pub fn key(&self) -> &str {
+        self.key.as_str()
+    }
+
+    fn render_prompt(&self, default: Option<&str>) -> String {
+        match default {
+            Some(default) if !self.is_sensitive => format!("{}[{}] ", self.prompt, default),
+            _ => self.prompt.clone(),
+        }
+    }
+
+    fn render_choices(&self) -> String {
+        let mut rendered = String::new();
+        for (i, choice) in self.choices.iter().flatten().enumerate() {
+            rendered.push_str(format!("  {}) {}\n", i + 1, choice).as_str());
+        }
+        rendered
+    }
+
+    // Turn the user's (non-reserved) response into this step's answer, or
+    // return a message explaining why it isn't acceptable.
+    fn accept(
+        &self,
+        response: String,
+        default: Option<&str>,
+        messages: &dyn Messages,
+    ) -> std::result::Result<String, String> {
+        let response = match (response.is_empty(), default) {
+            (true, Some(default)) => default.to_owned(),
+            _ => response,
+        };
+        let answer = match self.choices.as_ref() {
+            None => response,
+            Some(choices) => {
+                if choices.contains(&response) {
+                    response
+                } else {
+                    match response.trim().parse::<usize>() {
+                        Ok(i) if i >= 1 && i <= choices.len() => choices[i - 1].clone(),
+                        _ => return Err(messages.invalid_response(response.as_str())),
+                    }
+                }
+            }
+        };
+        if let Some(validator) = self.validator.as_ref() {
+            validator(answer.as_str())?;
+        }
+        Ok(answer)
+    }
+}
+
+impl fmt::Debug for WizardStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WizardStep")
+            .field("key", &self.key)
+            .field("prompt", &self.prompt)
+            .field("is_sensitive", &self.is_sensitive)
+            .field("confirm", &self.confirm)
+            .field("default", &self.default)
+            .field("choices", &self.choices)
+            .field("has_validator", &self.validator.is_some())
+            .finish()
+    }
+}
+
+/// WizardAnswers holds the answers collected by a completed `Wizard`, keyed by
+/// each step's key.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct WizardAnswers {
+    answers: Vec<(String, String)>,
+}
+
+impl WizardAnswers {
+    /// Returns the raw answer for the given key, if there is such a step.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.answers
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Returns the answer for the given key parsed as a `T`. It is an error if
+    /// there is no such key, or if the answer can't be parsed.
+    pub fn get_as<T: std::str::FromStr>(&self, key: &str) -> Result<T> {
+        let answer = self
+            .get(key)
+            .ok_or_else(|| Error::NotFound(format!("no wizard answer for '{}'", key)))?;
+        answer.parse().map_err(|_| {
+            Error::InvalidArgument(format!("invalid wizard answer '{}' for '{}'", answer, key))
+        })
+    }
+
+    /// Returns the answer for the given key interpreted as a yes / no answer,
+    /// according to the `Messages` catalog's affirmative and negative answers.
+    pub fn get_bool(&self, key: &str) -> Result<bool> {
+        let answer = self
+            .get(key)
+            .ok_or_else(|| Error::NotFound(format!("no wizard answer for '{}'", key)))?;
+        let messages = messages();
+        let normalized = answer.trim().to_lowercase();
+        if messages.affirmative_answers().contains(&normalized) {
+            Ok(true)
+        } else if messages.negative_answers().contains(&normalized) {
+            Ok(false)
+        } else {
+            Err(Error::InvalidArgument(format!(
+                "invalid wizard answer '{}' for '{}'",
+                answer, key
+            )))
+        }
+    }
+
+    /// Iterate over the (key, answer) pairs, in step order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.answers.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+}
+
+/// Wizard asks the user a sequence of questions (`WizardStep`s) in order. At
+/// any step, the user can enter the `Messages::wizard_back` response to return
+/// to the previous step (with their previous answer as the default), or the
+/// `Messages::wizard_cancel` response to abort the whole wizard.
+#[derive(Debug, Default)]
+pub struct Wizard {
+    steps: Vec<WizardStep>,
+    summary: bool,
+}
+
+impl Wizard {
+    /// Construct a new wizard with no steps.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the given step, after any existing steps.
+    pub fn step(mut self, step: WizardStep) -> Self {
+        self.steps.push(step);
+        self
+    }
+
+    /// After the last step, display all of the answers (sensitive ones are
+    /// masked) and ask the user whether to proceed, go back, or cancel.
+    pub fn with_summary(mut self) -> Self {
+        self.summary = true;
+        self
+    }
+
+    /// Run the wizard using the given streams. Returns `None` if the user
+    /// cancelled, or the collected answers otherwise. The same requirements as
+    /// `prompt_for_string` apply to the streams.
+    pub fn run<IS: AbstractStream, OS: AbstractStream>(
+        &self,
+        input_stream: IS,
+        output_stream: OS,
+    ) -> Result<Option<WizardAnswers>> {
+        self.run_with_messages(input_stream, output_stream, &*messages())
+    }
+
+    /// This is identical to `run`, except the given `Messages` catalog is used
+    /// instead of the global one.
+    pub fn run_with_messages<IS: AbstractStream, OS: AbstractStream>(
+        &self,
+        mut input_stream: IS,
+        mut output_stream: OS,
+        messages: &dyn Messages,
+    ) -> Result<Option<WizardAnswers>> {
+        let mut input_reader = build_input_reader(&mut input_stream)?;
+        let back = messages.wizard_back();
+        let cancel = messages.wizard_cancel();
+        let mut answers: Vec<Option<String>> = vec![None; self.steps.len()];
+
+        let mut i = 0;
+        loop {
+            if i == self.steps.len() {
+                if !self.summary {
+                    break;
+                }
+                let mut summary = String::new();
+                for (step, answer) in self.steps.iter().zip(answers.iter()) {
+                    let answer = answer.as_deref().unwrap_or_default();
+                    summary.push_str(
+                        format!(
+                            "  {}: {}\n",
+                            step.key,
+                            match step.is_sensitive {
+                                false => answer,
+                                true => "********",
+                            }
+                        )
+                        .as_str(),
+                    );
+                }
+                write_wizard_message(&mut output_stream, summary.as_str())?;
+                let response = prompt_for_string_impl(
+                    &mut input_stream,
+                    &mut input_reader,
+                    &mut output_stream,
+                    messages.wizard_summary_prompt().as_str(),
+                    /*is_sensitive=*/ false,
+                )?;
+                let normalized = response.trim().to_lowercase();
+                if normalized == back && !self.steps.is_empty() {
+                    i -= 1;
+                } else if normalized == cancel || messages.negative_answers().contains(&normalized)
+                {
+                    return Ok(None);
+                } else if messages.affirmative_answers().contains(&normalized) {
+                    break;
+                } else {
+                    write_wizard_message(
+                        &mut output_stream,
+                        format!("{}\n", messages.invalid_response(response.as_str())).as_str(),
+                    )?;
+                }
+                continue;
+            }
+
+            let step = &self.steps[i];
+            // Prefer the user's previous answer as the default, unless it's
+            // sensitive, in which case we don't want to reveal it.
+            let default = match step.is_sensitive {
+                false => answers[i].as_deref().or(step.default.as_deref()),
+                true => step.default.as_deref(),
+            };
+            if step.choices.is_some() {
+                write_wizard_message(&mut output_stream, step.render_choices().as_str())?;
+            }
+            let response = prompt_for_string_impl(
+                &mut input_stream,
+                &mut input_reader,
+                &mut output_stream,
+                step.render_prompt(default).as_str(),
+                step.is_sensitive,
+            )?;
+
+            let normalized = response.trim().to_lowercase();
+            if normalized == back {
+                i = i.saturating_sub(1);
+                continue;
+            } else if normalized == cancel {
+                return Ok(None);
+            }
+
+            if step.confirm
+                && response
+                    != prompt_for_string_impl(
+                        &mut input_stream,
+                        &mut input_reader,
+                        &mut output_stream,
+                        messages.confirm_prompt().as_str(),
+                        step.is_sensitive,
+                    )?
+            {
+                write_wizard_message(
+                    &mut output_stream,
+                    format!("{}\n", messages.confirm_mismatch()).as_str(),
+                )?;
+                continue;
+            }
+
+            match step.accept(response, default, messages) {
+                Ok(answer) => {
+                    answers[i] = Some(answer);
+                    i += 1;
+                }
+                Err(message) => {
+                    write_wizard_message(&mut output_stream, format!("{}\n", message).as_str())?
+                }
+            }
+        }
+
+        Ok(Some(WizardAnswers {
+            answers: self
+                .steps
+                .iter()
+                .zip(answers)
+                .map(|(step, answer)| (step.key.clone(), answer.unwrap_or_default()))
+                .collect(),
+        }))
+    }
+}
+
+fn write_wizard_message<OS: AbstractStream>(output_stream: &mut OS, message: &str) -> Result<()> {
+    let mut writer = match output_stream.as_writer() {
+        None => {
+            return Err(Error::Precondition(
+                "the given output stream must support `Write`".to_string(),
+            ))
+        }
+        Some(w) => w,
+    };
+    write!(writer, "{}", message)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// PasswordScore is a coarse bucket describing how hard a password would be to
+/// guess. Scores are ordered, so e.g. `score >= PasswordScore::Fair` works.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum PasswordScore {
+    /// Trivially guessable, e.g. a very common password.
+    VeryWeak,
+    /// Guessable with a modest offline attack.
+    Weak,
+    /// Reasonable against online attacks, but not offline ones.
+    Fair,
+    /// Resistant to most offline attacks.
+    Good,
+    /// Very unlikely to be guessed.
+    Strong,
+}
+
+/// StrengthReport is the result of `password_strength`. It deliberately does
+/// not contain (or print, via `Debug`) the password itself.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StrengthReport {
+    /// The bucket this password's estimated entropy falls into.
+    pub score: PasswordScore,
+    /// A rough estimate of this password's entropy, in bits.
+    pub entropy_bits: f64,
+    /// Human-readable suggestions for how the password could be improved.
+    pub suggestions: Vec<String>,
+}
+
+// A small list of the most commonly used passwords (and common base words for
+// them), which are assumed to be guessed almost immediately.
+const COMMON_PASSWORDS: &[&str] = &[
+    "123456",
+    "123456789",
+    "12345678",
+    "12345",
+    "1234567",
+    "1234567890",
+    "111111",
+    "000000",
+    "123123",
+    "654321",
+    "666666",
+    "696969",
+    "121212",
+    "password",
+    "passw0rd",
+    "p@ssw0rd",
+    "qwerty",
+    "qwertyuiop",
+    "asdfgh",
+    "zxcvbnm",
+    "1q2w3e4r",
+    "1qaz2wsx",
+    "abc123",
+    "iloveyou",
+    "admin",
+    "welcome",
+    "letmein",
+    "monkey",
+    "dragon",
+    "master",
+    "login",
+    "princess",
+    "sunshine",
+    "shadow",
+    "football",
+    "baseball",
+    "superman",
+    "batman",
+    "trustno1",
+    "starwars",
+    "freedom",
+    "whatever",
+    "hello",
+    "charlie",
+    "michael",
+    "jennifer",
+    "jordan",
+    "hunter",
+    "ashley",
+    "secret",
+    "summer",
+    "winter",
+    "changeme",
+    "default",
+    "root",
+    "toor",
+    "access",
+    "flower",
+    "cheese",
+    "computer",
+    "internet",
+    "mustang",
+    "soccer",
+    "killer",
+    "pokemon",
+];
+
+// Keyboard rows used to detect "walks" like "qwer" or "lkjh".
+const KEYBOARD_ROWS: &[&str] = &["1234567890", "qwertyuiop", "asdfghjkl", "zxcvbnm"];
+
+// The minimum number of characters a repeat, sequence, or keyboard walk must
+// span before we consider it a pattern.
+const MIN_PATTERN_LENGTH: usize = 3;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum PasswordPattern {
+    Repeat,
+    Sequence(i32),
+    KeyboardWalk(i32),
+}
+
+fn keyboard_position(c: char) -> Option<(usize, i32)> {
+    let c = c.to_ascii_lowercase();
+    KEYBOARD_ROWS
+        .iter()
+        .enumerate()
+        .find_map(|(row, keys)| keys.find(c).map(|col| (row, col as i32)))
+}
+
+fn password_pattern(a: char, b: char) -> Option<PasswordPattern> {
+    if a == b {
+        return Some(PasswordPattern::Repeat);
+    }
+    let same_class = (a.is_ascii_lowercase() && b.is_ascii_lowercase())
+        || (a.is_ascii_uppercase() && b.is_ascii_uppercase())
+        || (a.is_ascii_digit() && b.is_ascii_digit());
+    let delta = b as i32 - a as i32;
+    if same_class && delta.abs() == 1 {
+        return Some(PasswordPattern::Sequence(delta));
+    }
+    match (keyboard_position(a), keyboard_position(b)) {
+        (Some((ra, ca)), Some((rb, cb))) if ra == rb && (cb - ca).abs() == 1 => {
+            Some(PasswordPattern::KeyboardWalk(cb - ca))
+        }
+        _ => None,
+    }
+}
+
+// A class of characters, and how many characters it contains.
+type CharacterClass = (fn(&char) -> bool, f64);
+
+// The number of possible values for each character, and the number of classes
+// of characters which appear in the password.
+fn password_pool_size(chars: &[char]) -> (f64, usize) {
+    let classes: [CharacterClass; 5] = [
+        (char::is_ascii_lowercase, 26.0),
+        (char::is_ascii_uppercase, 26.0),
+        (char::is_ascii_digit, 10.0),
+        (|c| c.is_ascii() && !c.is_ascii_alphanumeric(), 33.0),
+        (|c| !c.is_ascii(), 100.0),
+    ];
+    classes
+        .iter()
+        .filter(|(f, _)| chars.iter().any(f))
+        .fold((0.0, 0), |(size, count), (_, n)| (size + n, count + 1))
+}
+
+// Estimate the entropy of the given characters, recording the patterns which
+// were found along the way.
+fn password_entropy(chars: &[char], found: &mut Vec<PasswordPattern>) -> f64 {
+    if chars.is_empty() {
+        return 0.0;
+    }
+
+    // If the whole thing is some shorter unit repeated, it's only as strong as
+    // that unit (plus a little, for the number of repetitions).
+    for period in 1..=chars.len() / 2 {
+        // usize::is_multiple_of is too new for the toolchains we support.
+        #[allow(clippy::manual_is_multiple_of)]
+        let divides = chars.len() % period == 0;
+        if divides && chars.chunks(period).all(|c| c == &chars[..period]) {
+            found.push(PasswordPattern::Repeat);
+            let repetitions = (chars.len() / period) as f64;
+            return password_entropy(&chars[..period], found) + repetitions.log2();
+        }
+    }
+
+    let bits_per_char = password_pool_size(chars).0.log2();
+    let mut predictable = vec![false; chars.len()];
+    let mut start = 0;
+    while start + 1 < chars.len() {
+        let pattern = password_pattern(chars[start], chars[start + 1]);
+        let mut end = start + 1;
+        while pattern.is_some()
+            && end + 1 < chars.len()
+            && password_pattern(chars[end], chars[end + 1]) == pattern
+        {
+            end += 1;
+        }
+        if let Some(pattern) = pattern {
+            if end - start + 1 >= MIN_PATTERN_LENGTH {
+                // Only the first character of a pattern is really chosen.
+                predictable[start + 1..=end].fill(true);
+                found.push(pattern);
+            }
+        }
+        start = end;
+    }
+
+    predictable
+        .iter()
+        .map(|&p| if p { 1.0 } else { bits_per_char })
+        .sum()
+}
+
+// If the given (lowercase) password is a common password, possibly with some
+// digits or symbols added to either end, return the remaining characters.
+fn strip_common_password(lowercase: &[char]) -> Option<Vec<char>> {
+    let is_affix = |c: &char| !c.is_alphabetic();
+    let begin = lowercase.iter().take_while(|c| is_affix(c)).count();
+    let end = lowercase.len()
+        - lowercase[begin..]
+            .iter()
+            .rev()
+            .take_while(|c| is_affix(c))
+            .count();
+    let candidates = [
+        (0, lowercase.len()),
+        (begin, lowercase.len()),
+        (0, end),
+        (begin, end),
+    ];
+    candidates.iter().find_map(|&(b, e)| {
+        let word: String = lowercase[b..e].iter().collect();
+        match COMMON_PASSWORDS.contains(&word.as_str()) {
+            false => None,
+            true => Some(
+                lowercase[..b]
+                    .iter()
+                    .chain(lowercase[e..].iter())
+                    .copied()
+                    .collect(),
+            ),
+        }
+    })
+}
+
+fn password_score(entropy_bits: f64) -> PasswordScore {
+    match entropy_bits {
+        b if b < 28.0 => PasswordScore::VeryWeak,
+        b if b < 36.0 => PasswordScore::Weak,
+        b if b < 60.0 => PasswordScore::Fair,
+        b if b < 80.0 => PasswordScore::Good,
+        _ => PasswordScore::Strong,
+    }
+}
+
+/// Estimate how hard the given password would be to guess. This is a purely
+/// local heuristic: it considers length and character variety, penalizes
+/// repeats, sequences like "abcd" or "1234", keyboard walks like "qwer", and
+/// membership in a small list of the most commonly used passwords.
+///
+/// The candidate is never logged, and the returned report doesn't contain it.
+pub fn password_strength(candidate: &str) -> StrengthReport {
+    let chars: Vec<char> = candidate.chars().collect();
+    let lowercase: Vec<char> = chars.iter().map(|c| c.to_ascii_lowercase()).collect();
+    let mut suggestions = Vec::new();
+    let mut found = Vec::new();
+
+    let entropy_bits = match strip_common_password(&lowercase) {
+        None => password_entropy(&chars, &mut found),
+        Some(rest) => {
+            suggestions.push(match rest.is_empty() {
+                true => "This is one of the most commonly used passwords.".to_string(),
+                false => "Avoid common passwords, even with digits or symbols added.".to_string(),
+            });
+            (COMMON_PASSWORDS.len() as f64).log2() + password_entropy(&rest, &mut found)
+        }
+    };
+    let score = password_score(entropy_bits);
+
+    if found.contains(&PasswordPattern::Repeat) {
+        suggestions.push("Avoid repeated characters or words, like \"aaa\".".to_string());
+    }
+    if found
+        .iter()
+        .any(|p| matches!(p, PasswordPattern::Sequence(_)))
+    {
+        suggestions.push("Avoid sequences like \"abcd\" or \"1234\".".to_string());
+    }
+    if found
+        .iter()
+        .any(|p| matches!(p, PasswordPattern::KeyboardWalk(_)))
+    {
+        suggestions.push("Avoid keyboard patterns like \"qwerty\".".to_string());
+    }
+    if score < PasswordScore::Good {
+        if chars.len() < 12 {
+            suggestions.push("Use a longer password; 12 or more characters is best.".to_string());
+        }
+        if password_pool_size(&chars).1 < 3 {
+            suggestions.push("Mix upper and lower case letters, digits, and symbols.".to_string());
+        }
+    }
+
+    StrengthReport {
+        score,
+        entropy_bits,
+        suggestions,
+    }
+}
+
+/// PasswordPolicy describes the requirements `prompt_for_new_password`
+/// enforces.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PasswordPolicy {
+    /// The minimum acceptable `password_strength` score.
+    pub min_score: PasswordScore,
+    /// The minimum acceptable length, in characters.
+    pub min_length: usize,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        PasswordPolicy {
+            min_score: PasswordScore::Fair,
+            min_length: 8,
+        }
+    }
+}
+
+/// Prompt the user for a new password, as per `prompt_for_string_confirm` with
+/// `is_sensitive` set. If the password doesn't satisfy the given policy, the
+/// user is told why (with any suggestions from `password_strength`), and is
+/// prompted again. The password itself is never echoed or logged.
+#[cfg(feature = "crypto")]
+pub fn prompt_for_new_password<IS: AbstractStream, OS: AbstractStream>(
     mut input_stream: IS,
     mut output_stream: OS,
-    description: &str,
-) -> Result<bool> {
-    let mut input_reader = build_input_reader(&mut input_stream)?;
-    let prompt = format!("{}Continue? [Yes/No] ", description);
+    prompt: &str,
+    policy: PasswordPolicy,
+) -> Result<crate::crypto::secret::Secret> {
+    use crate::crypto::secret::{zeroize, Secret};
 
+    let mut input_reader = build_input_reader(&mut input_stream)?;
+    let messages = messages();
     loop {
-        let original_response = prompt_for_string_impl(
+        let password = prompt_for_string_confirm_impl(
             &mut input_stream,
             &mut input_reader,
             &mut output_stream,
-            prompt.as_str(),
-            /*is_sensitive=*/ false,
+            prompt,
+            /*is_sensitive=*/ true,
         )?;
-        let response = original_response.trim().to_lowercase();
-        if response == "y" || response == "yes" {
-            return Ok(true);
-        } else if response == "n" || response == "no" {
-            return Ok(false);
-        } else {
-            let mut writer = match output_stream.as_writer() {
-                None => {
-                    return Err(Error::Precondition(format!(
-                        "the given output stream must support `Write`"
-                    )))
+        let report = password_strength(password.as_str());
+        let length = password.chars().count();
+        let mut password = password.into_bytes();
+
+        if length >= policy.min_length && report.score >= policy.min_score {
+            let ret = Secret::from_slice(password.as_slice());
+            zeroize(password.as_mut_slice());
+            return ret;
+        }
+        zeroize(password.as_mut_slice());
+
+        let mut writer = match output_stream.as_writer() {
+            None => {
+                return Err(Error::Precondition(
+                    "the given output stream must support `Write`".to_string(),
+                ))
+            }
+            Some(w) => w,
+        };
+        writeln!(writer, "{}", messages.password_too_weak())?;
+        if length < policy.min_length {
+            writeln!(
+                writer,
+                "  {}",
+                messages.password_too_short(policy.min_length)
+            )?;
+        }
+        for suggestion in report.suggestions.iter() {
+            writeln!(writer, "  {}", suggestion)?;
+        }
+        writer.flush()?;
+    }
+}
+
+/// Prompt the user for a secret (e.g. a passphrase), as per `prompt_for_string`
+/// with `is_sensitive` set, unless the given cache already holds an unexpired
+/// secret with the given ID, in which case that is returned without prompting
+/// at all. A newly entered secret is cached with the given TTL.
+#[cfg(feature = "crypto")]
+pub fn prompt_for_secret_cached<IS: AbstractStream, OS: AbstractStream>(
+    mut input_stream: IS,
+    mut output_stream: OS,
+    prompt: &str,
+    cache: &crate::crypto::secret::SecretCache,
+    id: &str,
+    ttl: Duration,
+) -> Result<crate::crypto::secret::Secret> {
+    use crate::crypto::secret::{zeroize, Secret};
+
+    cache.get_or_insert_with(id, ttl, || {
+        let mut input_reader = build_input_reader(&mut input_stream)?;
+        let mut secret = prompt_for_string_impl(
+            &mut input_stream,
+            &mut input_reader,
+            &mut output_stream,
+            prompt,
+            /*is_sensitive=*/ true,
+        )?
+        .into_bytes();
+        let ret = Secret::from_slice(secret.as_slice());
+        zeroize(secret.as_mut_slice());
+        ret
+    })
+}
+
+/// ErrorFormat controls how `write_error` reports errors.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ErrorFormat {
+    /// A human-readable message, e.g. "error: not found: foo".
+    #[default]
+    Text,
+    /// A single line of JSON, as returned by `Error::to_json`, so scripts can
+    /// match on the error's stable code.
+    Json,
+}
+
+/// Write the given error to the given writer, in the given format. Binaries
+/// typically call this with stderr from their `main` before exiting.
+pub fn write_error<W: Write>(mut writer: W, error: &Error, format: ErrorFormat) -> Result<()> {
+    match format {
+        ErrorFormat::Text => writeln!(writer, "error: {}", error)?,
+        ErrorFormat::Json => {
+            serde_json::to_writer(&mut writer, &error.to_json())?;
+            writeln!(writer)?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Write the given error to stderr, in the given format. Errors encountered
+/// while writing are ignored, since there's nowhere left to report them.
+pub fn print_error(error: &Error, format: ErrorFormat) {
+    let _ = write_error(io::stderr().lock(), error, format);
+}
+
+/// OutputMode selects how a command formats its output: for humans, or as
+/// JSON for scripts. Commands typically take this from an `--output` flag.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum OutputMode {
+    /// Human-readable text.
+    #[default]
+    Text,
+    /// Compact (single-line) JSON.
+    Json,
+    /// Indented, multi-line JSON.
+    JsonPretty,
+}
+
+impl OutputMode {
+    /// Every mode, in the order their names are listed in help text.
+    pub const ALL: &'static [OutputMode] =
+        &[OutputMode::Text, OutputMode::Json, OutputMode::JsonPretty];
+
+    /// Return the name of this mode, as accepted by `from_str`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OutputMode::Text => "text",
+            OutputMode::Json => "json",
+            OutputMode::JsonPretty => "json-pretty",
+        }
+    }
+
+    /// Returns whether or not this is one of the JSON modes.
+    pub fn is_json(&self) -> bool {
+        *self != OutputMode::Text
+    }
+
+    /// Return the `ErrorFormat` errors should be reported in, so failures are
+    /// machine-readable whenever the rest of the output is.
+    pub fn error_format(&self) -> ErrorFormat {
+        match self {
+            OutputMode::Text => ErrorFormat::Text,
+            _ => ErrorFormat::Json,
+        }
+    }
+}
+
+impl fmt::Display for OutputMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for OutputMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        OutputMode::ALL
+            .iter()
+            .find(|mode| mode.as_str() == s)
+            .copied()
+            .ok_or_else(|| {
+                let names: Vec<&str> = OutputMode::ALL.iter().map(OutputMode::as_str).collect();
+                Error::InvalidArgument(format!(
+                    "invalid output mode '{}' (expected one of: {})",
+                    s,
+                    names.join(", ")
+                ))
+            })
+    }
+}
+
+/// TextPolicy controls what an `OutputSink` in one of the JSON modes does
+/// with free-form text passed to `emit_text`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum TextPolicy {
+    /// Return an error, so stray text output is caught instead of silently
+    /// corrupting the JSON.
+    #[default]
+    Error,
+    /// Collect the text, and wrap the output in an object of the form
+    /// `{"result": ..., "messages": [...]}`.
+    Collect,
+}
+
+/// OutputSink is where a command writes its output, formatted according to
+/// an `OutputMode`.
+///
+/// In text mode, everything is written to the stream as it is emitted. In the
+/// JSON modes, the output is instead buffered and written as a single JSON
+/// document by `finish`, so the command's output as a whole is always valid
+/// JSON. This means a command may emit at most one value (or table) in JSON
+/// mode.
+pub struct OutputSink<S: AbstractStream> {
+    mode: OutputMode,
+    text_policy: TextPolicy,
+    stream: S,
+    result: Option<serde_json::Value>,
+    messages: Vec<String>,
+}
+
+impl<S: AbstractStream> OutputSink<S> {
+    /// Construct a new sink which writes output in the given mode to the given
+    /// stream. In the JSON modes, stray text is an error by default.
+    pub fn new(mode: OutputMode, stream: S) -> Result<Self> {
+        if stream.as_writer().is_none() {
+            return Err(Error::Precondition(
+                "the given output stream must support `Write`".to_string(),
+            ));
+        }
+        Ok(OutputSink {
+            mode,
+            text_policy: TextPolicy::default(),
+            stream,
+            result: None,
+            messages: Vec::new(),
+        })
+    }
+
+    /// Set what this sink does with text emitted in one of the JSON modes.
+    pub fn with_text_policy(mut self, text_policy: TextPolicy) -> Self {
+        self.text_policy = text_policy;
+        self
+    }
+
+    /// Return the mode this sink formats output in.
+    pub fn mode(&self) -> OutputMode {
+        self.mode
+    }
+
+    fn write(&self, s: &str) -> Result<()> {
+        // We checked in `new` that the stream supports writing.
+        let mut writer = self.stream.as_writer().unwrap();
+        writer.write_all(s.as_bytes())?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    fn set_result(&mut self, value: serde_json::Value) -> Result<()> {
+        if self.result.is_some() {
+            return Err(Error::Precondition(
+                "only one value can be emitted in JSON output mode".to_string(),
+            ));
+        }
+        self.result = Some(value);
+        Ok(())
+    }
+
+    /// Emit a line of free-form text, e.g. `sink.emit_text(format_args!(...))`.
+    /// In the JSON modes, this is handled according to the sink's
+    /// `TextPolicy`, and any styling (ANSI escape sequences) is stripped.
+    pub fn emit_text(&mut self, args: fmt::Arguments) -> Result<()> {
+        if !self.mode.is_json() {
+            return self.write(&format!("{}\n", args));
+        }
+        match self.text_policy {
+            TextPolicy::Error => Err(Error::Precondition(format!(
+                "text output '{}' is not allowed in {} output mode",
+                args, self.mode
+            ))),
+            TextPolicy::Collect => {
+                self.messages.push(strip_ansi(&args.to_string()));
+                Ok(())
+            }
+        }
+    }
+
+    /// Emit a value. In text mode, it's written immediately as indented JSON;
+    /// commands which want a nicer presentation for humans should check
+    /// `mode` and use `emit_text` instead.
+    pub fn emit_value<T: serde::Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        if self.mode.is_json() {
+            let value = serde_json::to_value(value)?;
+            return self.set_result(value);
+        }
+        let mut rendered = serde_json::to_string_pretty(value)?;
+        rendered.push('\n');
+        self.write(&rendered)
+    }
+
+    /// Emit a table. In text mode, it's rendered as per `Table::render_to`.
+    /// In the JSON modes, it's emitted as an array of objects, one per row,
+    /// keyed by the table's header, so the table must have one. Any styling
+    /// (ANSI escape sequences) is stripped from the JSON output.
+    pub fn emit_table(&mut self, table: &Table) -> Result<()> {
+        if !self.mode.is_json() {
+            return table.render_to(&self.stream);
+        }
+        let header = table.header.as_ref().ok_or_else(|| {
+            Error::InvalidArgument(
+                "tables emitted in JSON output mode must have a header".to_string(),
+            )
+        })?;
+        let rows: Vec<serde_json::Value> = table
+            .rows
+            .iter()
+            .map(|row| {
+                let object: serde_json::Map<String, serde_json::Value> = header
+                    .iter()
+                    .enumerate()
+                    .map(|(i, name)| {
+                        let cell = row.get(i).map_or_else(String::new, |cell| strip_ansi(cell));
+                        (strip_ansi(name), serde_json::Value::String(cell))
+                    })
+                    .collect();
+                serde_json::Value::Object(object)
+            })
+            .collect();
+        self.set_result(serde_json::Value::Array(rows))
+    }
+
+    /// Report that the command failed with the given error. In text mode, the
+    /// error is written immediately, as per `write_error`. In the JSON modes,
+    /// the output document becomes `{"error": ...}` (see `Error::to_json`),
+    /// replacing any value emitted so far.
+    pub fn emit_error(&mut self, error: &Error) -> Result<()> {
+        if !self.mode.is_json() {
+            let mut out = Vec::new();
+            write_error(&mut out, error, ErrorFormat::Text)?;
+            return self.write(&String::from_utf8_lossy(&out));
+        }
+        self.result = Some(serde_json::json!({ "error": error.to_json() }));
+        Ok(())
+    }
+
+    /// Finish this command's output. In the JSON modes, this writes the JSON
+    /// document (or `null`, if nothing was emitted) followed by a newline.
+    pub fn finish(self) -> Result<()> {
+        if !self.mode.is_json() {
+            return Ok(());
+        }
+        let result = self.result.clone().unwrap_or(serde_json::Value::Null);
+        let document = match self.text_policy {
+            TextPolicy::Error => result,
+            TextPolicy::Collect => serde_json::json!({
+                "result": result,
+                "messages": self.messages,
+            }),
+        };
+        let mut rendered = match self.mode {
+            OutputMode::JsonPretty => serde_json::to_string_pretty(&document)?,
+            _ => serde_json::to_string(&document)?,
+        };
+        rendered.push('\n');
+        self.write(&rendered)
+    }
+}
+
+/// VerbosityLevel is how much output the user asked for, from least to most.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum VerbosityLevel {
+    /// Only essential output (e.g. the result of a command, or errors).
+    Quiet,
+    /// The default amount of output.
+    #[default]
+    Normal,
+    /// Extra information about what's going on.
+    Verbose,
+    /// Everything, including details only useful for debugging.
+    Debug,
+}
+
+/// Verbosity is a global output verbosity setting, typically derived from the
+/// number of times `-q` / `-v` flags were given. It controls both human-facing
+/// output (see `emit`) and logging (see `to_log_filter`), so one flag
+/// configures both consistently.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct Verbosity {
+    level: VerbosityLevel,
+}
+
+impl Verbosity {
+    /// Construct a Verbosity from the number of times quiet and verbose flags
+    /// were given. The flags cancel each other out, so e.g. `-q -v` is the
+    /// same as giving neither, and `-q -vv` is the same as `-v`.
+    pub fn new(quiet: usize, verbose: usize) -> Self {
+        let level = match verbose as i64 - quiet as i64 {
+            n if n < 0 => VerbosityLevel::Quiet,
+            0 => VerbosityLevel::Normal,
+            1 => VerbosityLevel::Verbose,
+            _ => VerbosityLevel::Debug,
+        };
+        Verbosity { level }
+    }
+
+    /// Construct a Verbosity with the given level.
+    pub fn from_level(level: VerbosityLevel) -> Self {
+        Verbosity { level }
+    }
+
+    /// Return this setting's verbosity level.
+    pub fn level(&self) -> VerbosityLevel {
+        self.level
+    }
+
+    /// Return whether or not output at the given level should be shown.
+    pub fn is_enabled(&self, level: VerbosityLevel) -> bool {
+        level <= self.level
+    }
+
+    /// Return whether or not progress indicators (spinners, progress bars,
+    /// etc.) should be shown. They're only useful when a human is watching,
+    /// so they're never shown when output isn't going to a TTY, or in quiet
+    /// mode.
+    pub fn should_print_progress(&self, stdout_is_tty: bool) -> bool {
+        stdout_is_tty && self.level >= VerbosityLevel::Normal
+    }
+
+    /// Return the log level filter corresponding to this verbosity: errors
+    /// are always logged, warnings by default, and more detail as the
+    /// verbosity increases.
+    pub fn to_log_filter(&self) -> LevelFilter {
+        match self.level {
+            VerbosityLevel::Quiet => LevelFilter::ERROR,
+            VerbosityLevel::Normal => LevelFilter::WARN,
+            VerbosityLevel::Verbose => LevelFilter::INFO,
+            VerbosityLevel::Debug => LevelFilter::DEBUG,
+        }
+    }
+}
+
+/// A piece of a string which may contain ANSI escape sequences: either a
+/// single visible character, or a whole escape sequence.
+enum AnsiSegment<'a> {
+    Char(char),
+    Escape(&'a str),
+}
+
+impl AnsiSegment<'_> {
+    /// Return the number of terminal columns this segment occupies.
+    fn width(&self) -> usize {
+        match self {
+            AnsiSegment::Char(c) => char_width(*c),
+            AnsiSegment::Escape(_) => 0,
+        }
+    }
+
+    fn push_to(&self, s: &mut String) {
+        match self {
+            AnsiSegment::Char(c) => s.push(*c),
+            AnsiSegment::Escape(escape) => s.push_str(escape),
+        }
+    }
+}
+
+fn ansi_segments(s: &str) -> Vec<AnsiSegment<'_>> {
+    let mut segments = Vec::new();
+    let mut chars = s.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        if c != '\x1b' {
+            segments.push(AnsiSegment::Char(c));
+            continue;
+        }
+        match chars.next() {
+            // CSI sequences end with a byte in the range 0x40..=0x7e.
+            Some((_, '[')) => {
+                for (_, c) in chars.by_ref() {
+                    if ('\x40'..='\x7e').contains(&c) {
+                        break;
+                    }
                 }
-                Some(w) => w,
-            };
-            write!(writer, "Invalid response '{}'.\n", original_response)?;
-            // We have to flush so the user sees the prompt immediately.
-            writer.flush()?;
+            }
+            // OSC sequences end with BEL or ST (ESC \\).
+            Some((_, ']')) => {
+                while let Some((_, c)) = chars.next() {
+                    if c == '\x07' {
+                        break;
+                    }
+                    if c == '\x1b' && chars.peek().map(|&(_, c)| c) == Some('\\') {
+                        chars.next();
+                        break;
+                    }
+                }
+            }
+            // Other escapes are two characters long.
+            _ => {}
+        }
+        let end = chars.peek().map_or(s.len(), |&(i, _)| i);
+        segments.push(AnsiSegment::Escape(&s[start..end]));
+    }
+    segments
+}
+
+/// Split the given string into whitespace-separated words, like
+/// `str::split_whitespace`, except whitespace inside escape sequences doesn't
+/// separate words.
+fn ansi_words(s: &str) -> Vec<Vec<AnsiSegment<'_>>> {
+    let mut words = vec![Vec::new()];
+    for segment in ansi_segments(s) {
+        match segment {
+            AnsiSegment::Char(c) if c.is_whitespace() => {
+                if !words.last().unwrap().is_empty() {
+                    words.push(Vec::new());
+                }
+            }
+            segment => words.last_mut().unwrap().push(segment),
+        }
+    }
+    words.retain(|word| !word.is_empty());
+    words
+}
+
+/// Remove any ANSI escape sequences (e.g. colors, or cursor movement) from
+/// the given string.
+pub fn strip_ansi(s: &str) -> String {
+    ansi_segments(s)
+        .into_iter()
+        .filter_map(|segment| match segment {
+            AnsiSegment::Char(c) => Some(c),
+            AnsiSegment::Escape(_) => None,
+        })
+        .collect()
+}
+
+/// Hyperlinks controls whether `Link`s are rendered as clickable terminal
+/// hyperlinks (OSC 8 escape sequences), or as plain text.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Hyperlinks {
+    /// Emit hyperlinks only if the stream is a TTY, and the terminal is known
+    /// to support them (see `terminal_supports_hyperlinks`).
+    #[default]
+    Auto,
+    /// Always emit hyperlinks.
+    Always,
+    /// Never emit hyperlinks; always use the plain text fallback.
+    Never,
+}
+
+impl Hyperlinks {
+    /// Return whether or not hyperlinks should be emitted to the given stream.
+    pub fn enabled_for<S: AbstractStream>(self, stream: &S) -> bool {
+        match self {
+            Hyperlinks::Auto => stream.isatty() && terminal_supports_hyperlinks(),
+            Hyperlinks::Always => true,
+            Hyperlinks::Never => false,
+        }
+    }
+}
+
+// Values of TERM_PROGRAM which identify terminals known to support OSC 8.
+const HYPERLINK_TERM_PROGRAMS: &[&str] = &["iTerm.app", "WezTerm", "vscode", "Hyper", "ghostty"];
+// Values of TERM which identify terminals known to support OSC 8.
+const HYPERLINK_TERMS: &[&str] = &[
+    "xterm-kitty",
+    "xterm-ghostty",
+    "alacritty",
+    "foot",
+    "wezterm",
+];
+// The first VTE (GNOME Terminal, Tilix, etc.) version with OSC 8 support.
+const HYPERLINK_MIN_VTE_VERSION: u32 = 5000;
+
+fn hyperlinks_supported_by<F: Fn(&str) -> Option<String>>(var: F) -> bool {
+    // An explicit override always wins, in either direction.
+    if let Some(force) = var("FORCE_HYPERLINK") {
+        return !force.is_empty() && force != "0";
+    }
+    if var("TERM_PROGRAM").is_some_and(|p| HYPERLINK_TERM_PROGRAMS.contains(&p.as_str())) {
+        return true;
+    }
+    if var("TERM").is_some_and(|t| HYPERLINK_TERMS.contains(&t.as_str())) {
+        return true;
+    }
+    if var("VTE_VERSION")
+        .and_then(|v| v.parse::<u32>().ok())
+        .is_some_and(|v| v >= HYPERLINK_MIN_VTE_VERSION)
+    {
+        return true;
+    }
+    // Windows Terminal sets this for every session.
+    var("WT_SESSION").is_some()
+}
+
+/// Return whether or not the terminal we're running in is known to support
+/// OSC 8 hyperlinks. This is decided by an allowlist of terminals, based on
+/// the `TERM` and `TERM_PROGRAM` environment variables (among others).
+/// Setting `FORCE_HYPERLINK` overrides the check: `0` disables hyperlinks,
+/// and any other non-empty value enables them.
+pub fn terminal_supports_hyperlinks() -> bool {
+    hyperlinks_supported_by(|name| std::env::var(name).ok())
+}
+
+/// A Link is some text which refers to a URL. Where supported, it's rendered
+/// as a clickable terminal hyperlink; otherwise, it falls back to plain text
+/// which still includes the URL.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Link {
+    url: String,
+    text: String,
+}
+
+impl Link {
+    /// Construct a new Link, displaying the given text and pointing at the
+    /// given URL.
+    pub fn new(url: &str, text: &str) -> Self {
+        Link {
+            url: url.to_owned(),
+            text: text.to_owned(),
+        }
+    }
+
+    /// Return the URL this link points to.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Return the text this link displays.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Render this link either as an OSC 8 hyperlink, or as plain text. The
+    /// plain text form is `text (url)`, or just the text if it's the URL
+    /// itself.
+    pub fn render(&self, hyperlink: bool) -> String {
+        if !hyperlink {
+            return self.to_string();
+        }
+        // Control characters would terminate the escape sequence early, so
+        // they can't appear in either part of the link.
+        let url: String = self.url.chars().filter(|c| !c.is_control()).collect();
+        let text: String = self.text.chars().filter(|c| !c.is_control()).collect();
+        format!("\x1b]8;;{}\x1b\\{}\x1b]8;;\x1b\\", url, text)
+    }
+
+    /// Render this link appropriately for the given output stream.
+    pub fn render_for<S: AbstractStream>(&self, stream: &S, hyperlinks: Hyperlinks) -> String {
+        self.render(hyperlinks.enabled_for(stream))
+    }
+}
+
+impl fmt::Display for Link {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.text == self.url {
+            write!(f, "{}", self.text)
+        } else {
+            write!(f, "{} ({})", self.text, self.url)
+        }
+    }
+}
+
+/// Return a `Link` to the given filesystem path, using a `file://` URL. The
+/// path is made absolute (relative to the current directory) for the URL,
+/// but the link's text is the path as given. Anything other than unreserved
+/// characters and `/` (e.g. spaces, or non-ASCII characters) is
+/// percent-encoded.
+pub fn path_link(path: &Path) -> Result<Link> {
+    let absolute = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        std::env::current_dir()?.join(path)
+    };
+
+    let mut url = "file://".to_string();
+    for &b in absolute.as_os_str().as_bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~/".contains(&b) {
+            url.push(b as char);
+        } else {
+            url.push_str(&format!("%{:02X}", b));
+        }
+    }
+    Ok(Link::new(&url, &path.display().to_string()))
+}
+
+fn stream_writer<S: AbstractStream>(stream: &S) -> Result<Box<dyn Write>> {
+    stream.as_writer().ok_or_else(|| {
+        Error::Precondition("the given output stream must support `Write`".to_string())
+    })
+}
+
+/// Write a line of output to the given stream, if the given verbosity
+/// setting allows output at the given level. If the stream is not a TTY, any
+/// ANSI escape sequences are removed first.
+pub fn emit<S: AbstractStream>(
+    stream: &S,
+    verbosity: &Verbosity,
+    level: VerbosityLevel,
+    args: fmt::Arguments<'_>,
+) -> Result<()> {
+    if !verbosity.is_enabled(level) {
+        return Ok(());
+    }
+    let mut line = args.to_string();
+    if !stream.isatty() {
+        line = strip_ansi(line.as_str());
+    }
+    let mut writer = stream_writer(stream)?;
+    writeln!(writer, "{}", line)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Write (or overwrite) a single-line progress message to the given stream,
+/// if `Verbosity::should_print_progress` allows it. Otherwise, this does
+/// nothing, so callers don't need to check themselves.
+pub fn emit_progress<S: AbstractStream>(
+    stream: &S,
+    verbosity: &Verbosity,
+    args: fmt::Arguments<'_>,
+) -> Result<()> {
+    if !verbosity.should_print_progress(stream.isatty()) {
+        return Ok(());
+    }
+    let mut writer = stream_writer(stream)?;
+    // Return to the start of the line, and clear it.
+    write!(writer, "\r\x1b[2K{}", args)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// An Editor is something which lets the user interactively edit a file. The
+/// real implementation is `SystemEditor`, but this is abstracted for testing
+/// purposes. Any `Fn(&Path) -> Result<()>` is also an Editor.
+#[cfg(feature = "fs")]
+pub trait Editor {
+    /// Let the user edit the file at the given path, returning once they're
+    /// done.
+    fn edit(&self, path: &Path) -> Result<()>;
+}
+
+#[cfg(feature = "fs")]
+impl<F: Fn(&Path) -> Result<()>> Editor for F {
+    fn edit(&self, path: &Path) -> Result<()> {
+        self(path)
+    }
+}
+
+/// SystemEditor launches the user's preferred text editor: the first of
+/// `$VISUAL`, `$EDITOR`, or `vi` which is set. The editor inherits this
+/// process's standard streams, so it is attached to the controlling terminal.
+///
+/// Like e.g. Git, the editor command is interpreted by the shell, so it can
+/// include arguments (e.g. `code --wait`).
+#[cfg(feature = "fs")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemEditor;
+
+#[cfg(feature = "fs")]
+impl SystemEditor {
+    fn command() -> String {
+        ["VISUAL", "EDITOR"]
+            .iter()
+            .filter_map(|var| std::env::var(var).ok())
+            .find(|editor| !editor.trim().is_empty())
+            .unwrap_or_else(|| "vi".to_owned())
+    }
+}
+
+#[cfg(feature = "fs")]
+impl Editor for SystemEditor {
+    fn edit(&self, path: &Path) -> Result<()> {
+        let editor = Self::command();
+        debug!("launching editor '{}' on '{}'", editor, path.display());
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(format!("{} \"$@\"", editor))
+            .arg(editor.as_str())
+            .arg(path)
+            .status()?;
+        if !status.success() {
+            return Err(Error::ProcessFailed {
+                status,
+                stderr: String::new(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Options which control the behavior of `edit_text`.
+#[cfg(feature = "fs")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct EditOptions<'a> {
+    /// If set, lines starting with this prefix (e.g. "#") are treated as
+    /// comments (e.g. instructions for the user), and are removed from the
+    /// result.
+    pub comment_prefix: Option<&'a str>,
+    /// If true, returning the initial text unmodified counts as aborting.
+    pub require_change: bool,
+    /// If set, the temporary file has this extension (e.g. "md"), so editors
+    /// can e.g. enable the right syntax highlighting.
+    pub file_extension: Option<&'a str>,
+}
+
+#[cfg(feature = "fs")]
+fn strip_edited_text(text: &str, comment_prefix: Option<&str>) -> String {
+    let mut stripped = String::new();
+    for line in text.lines() {
+        if comment_prefix.is_some_and(|prefix| line.starts_with(prefix)) {
+            continue;
         }
+        stripped.push_str(line);
+        stripped.push('\n');
+    }
+    stripped.trim_end().to_owned()
+}
+
+/// Open the user's text editor (see `SystemEditor`) to edit the given initial
+/// text, e.g. to compose a message like `git commit` does. This is a
+/// convenience wrapper around `edit_text_with`, using standard input and
+/// output.
+#[cfg(feature = "fs")]
+pub fn edit_text(initial: &str, options: EditOptions<'_>) -> Result<Option<String>> {
+    edit_text_with(
+        &Stream::Stdin,
+        &Stream::Stdout,
+        &SystemEditor,
+        initial,
+        options,
+    )
+}
+
+/// Let the user edit the given initial text with the given editor. The text is
+/// written to a private temporary file, which the editor modifies, and which is
+/// removed afterwards.
+///
+/// The result has comment lines (see `EditOptions`) and trailing whitespace
+/// removed. If this leaves it empty, or if `require_change` is set and it is
+/// identical to the (similarly stripped) initial text, the user is considered
+/// to have aborted, and None is returned.
+///
+/// Both streams must be TTYs, since the editor is interactive. If the editor
+/// exits unsuccessfully, `Error::ProcessFailed` is returned.
+#[cfg(feature = "fs")]
+pub fn edit_text_with<IS: AbstractStream, OS: AbstractStream, E: Editor>(
+    input_stream: &IS,
+    output_stream: &OS,
+    editor: &E,
+    initial: &str,
+    options: EditOptions<'_>,
+) -> Result<Option<String>> {
+    if !input_stream.isatty() || !output_stream.isatty() {
+        return Err(Error::Precondition(
+            "cannot launch an editor when the I/O streams are not TTYs".to_string(),
+        ));
+    }
+
+    let suffix = options
+        .file_extension
+        .map(|ext| format!(".{}", ext.trim_start_matches('.')))
+        .unwrap_or_default();
+    let mut file = TempFile::new_in_with_suffix(&std::env::temp_dir(), "edit-", &suffix)?;
+    file.as_file_mut().write_all(initial.as_bytes())?;
+    file.as_file_mut().flush()?;
+
+    editor.edit(file.path())?;
+    // Re-open by path rather than using our handle, since some editors save by
+    // replacing the file instead of writing to it in place.
+    let edited = std::fs::read_to_string(file.path())?;
+    file.close()?;
+
+    let edited = strip_edited_text(&edited, options.comment_prefix);
+    if edited.is_empty()
+        || (options.require_change && edited == strip_edited_text(initial, options.comment_prefix))
+    {
+        return Ok(None);
     }
+    Ok(Some(edited))
 }
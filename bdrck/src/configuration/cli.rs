@@ -0,0 +1,310 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::cli::{self, AbstractStream, EditOptions, Editor, OutputSink, Stream, SystemEditor};
+use crate::configuration::{Configuration, PersistenceFormat};
+use crate::error::*;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+
+/// The comment written above the values being edited by `config edit`.
+const EDIT_INSTRUCTIONS: &str = "\
+# Edit the configuration values below, then save and exit. Lines starting
+# with '#' are ignored. To abort, exit without saving, or delete everything.
+# Secret values are shown in plain text here.
+";
+
+/// ConfigCommand is one of the subcommands of a `config` command, which lets
+/// users inspect and modify an application's `Configuration` from the command
+/// line. Applications parse the arguments following their `config` command
+/// with `parse`, and then `run` the result.
+///
+/// Commands which take a path accept dot-separated field names (e.g.
+/// "server.port"), matched case-insensitively. Output is formatted according
+/// to the given `OutputSink`'s mode, so e.g. `--output=json` works the same
+/// way it does for the application's other commands.
+///
+/// To run a command against a `SharedConfiguration`, use e.g.
+/// `shared.apply(|config| command.run(config, &mut sink))`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ConfigCommand {
+    /// Print the value at the given path. Secrets are redacted unless
+    /// `show_secrets` is set.
+    Get {
+        /// The dot-separated path of the field to print.
+        path: String,
+        /// Whether to print secret values, instead of redacting them.
+        show_secrets: bool,
+    },
+    /// Set the value at the given path, parsed according to the field's
+    /// type (see `Configuration::set_value`), and persist the result.
+    Set {
+        /// The dot-separated path of the field to set.
+        path: String,
+        /// The new value, as it was given on the command line.
+        value: String,
+    },
+    /// Revert the value at the given path to its default, and persist the
+    /// result.
+    Unset {
+        /// The dot-separated path of the field to revert.
+        path: String,
+    },
+    /// Print all of the current values. Secrets are redacted unless
+    /// `show_secrets` is set.
+    List {
+        /// Whether to print secret values, instead of redacting them.
+        show_secrets: bool,
+    },
+    /// Print the path of the file the configuration is persisted to.
+    Path,
+    /// Edit the configuration values as JSON in the user's text editor (see
+    /// `cli::edit_text`). The result is validated before being persisted; an
+    /// invalid edit is rejected, leaving the configuration untouched.
+    Edit,
+}
+
+fn usage_error(message: &str) -> Error {
+    Error::InvalidArgument(format!("{} (see 'help' for usage)", message))
+}
+
+impl ConfigCommand {
+    /// Parse a command from the given arguments, which are those following
+    /// the application's `config` command (e.g. `["set", "server.port",
+    /// "80"]`).
+    pub fn parse<S: AsRef<str>>(args: &[S]) -> Result<ConfigCommand> {
+        let (name, args) = match args.split_first() {
+            None => return Err(usage_error("missing config subcommand")),
+            Some((name, args)) => (name.as_ref(), args),
+        };
+        let show_secrets = args.iter().any(|arg| arg.as_ref() == "--show-secrets");
+        let positional: Vec<&str> = args
+            .iter()
+            .map(AsRef::as_ref)
+            .filter(|&arg| arg != "--show-secrets")
+            .collect();
+        if show_secrets && name != "get" && name != "list" {
+            return Err(usage_error(&format!(
+                "'{}' doesn't accept --show-secrets",
+                name
+            )));
+        }
+        if let Some(flag) = positional.iter().find(|arg| arg.starts_with("--")) {
+            return Err(usage_error(&format!("unrecognized flag '{}'", flag)));
+        }
+
+        let expected = match name {
+            "get" | "unset" => 1,
+            "set" => 2,
+            "list" | "path" | "edit" => 0,
+            _ => {
+                return Err(usage_error(&format!(
+                    "unrecognized config subcommand '{}'",
+                    name
+                )))
+            }
+        };
+        if positional.len() != expected {
+            return Err(usage_error(&format!(
+                "'{}' expects {} argument(s), got {}",
+                name,
+                expected,
+                positional.len()
+            )));
+        }
+
+        Ok(match name {
+            "get" => ConfigCommand::Get {
+                path: positional[0].to_owned(),
+                show_secrets,
+            },
+            "set" => ConfigCommand::Set {
+                path: positional[0].to_owned(),
+                value: positional[1].to_owned(),
+            },
+            "unset" => ConfigCommand::Unset {
+                path: positional[0].to_owned(),
+            },
+            "list" => ConfigCommand::List { show_secrets },
+            "path" => ConfigCommand::Path,
+            _ => ConfigCommand::Edit,
+        })
+    }
+
+    /// Return help text describing each of the subcommands, for a `config`
+    /// command with the given name (e.g. "config", or "settings" if the
+    /// application prefers).
+    pub fn help(name: &str) -> String {
+        let commands: &[(&str, &str)] = &[
+            (
+                "get [--show-secrets] <path>",
+                "Print the value of a field, e.g. 'server.port'",
+            ),
+            (
+                "set <path> <value>",
+                "Set a field to a new value, and save the result",
+            ),
+            ("unset <path>", "Revert a field to its default value"),
+            ("list [--show-secrets]", "Print all of the current values"),
+            ("path", "Print the location of the configuration file"),
+            (
+                "edit",
+                "Edit the configuration in $VISUAL or $EDITOR, and save the result if it is valid",
+            ),
+        ];
+        let width = commands
+            .iter()
+            .map(|(usage, _)| usage.len())
+            .max()
+            .unwrap_or(0);
+
+        let mut help = format!("Usage: {} <command> [arguments]\n\nCommands:\n", name);
+        for (usage, description) in commands {
+            help.push_str(&format!(
+                "  {:width$}  {}\n",
+                usage,
+                description,
+                width = width
+            ));
+        }
+        help.push_str(
+            "\nField paths are dot-separated field names, matched case-insensitively.\n\
+             Secrets are redacted from output unless --show-secrets is given.\n",
+        );
+        help
+    }
+
+    /// Run this command against the given configuration, writing any output
+    /// to the given sink. `Edit` uses the user's editor (see `SystemEditor`)
+    /// on standard input and output.
+    pub fn run<T: Clone + Serialize + DeserializeOwned, S: AbstractStream>(
+        &self,
+        config: &mut Configuration<T>,
+        sink: &mut OutputSink<S>,
+    ) -> Result<()> {
+        self.run_with_editor(config, sink, &Stream::Stdin, &Stream::Stdout, &SystemEditor)
+    }
+
+    /// This is identical to `run`, except `Edit` uses the given streams and
+    /// editor (see `cli::edit_text_with`).
+    pub fn run_with_editor<
+        T: Clone + Serialize + DeserializeOwned,
+        S: AbstractStream,
+        IS: AbstractStream,
+        OS: AbstractStream,
+        E: Editor,
+    >(
+        &self,
+        config: &mut Configuration<T>,
+        sink: &mut OutputSink<S>,
+        input_stream: &IS,
+        output_stream: &OS,
+        editor: &E,
+    ) -> Result<()> {
+        match self {
+            ConfigCommand::Get { path, show_secrets } => {
+                emit(sink, &config.get_value(path, *show_secrets)?)
+            }
+            ConfigCommand::Set { path, value } => {
+                config.set_value(path, value)?;
+                persist(config)
+            }
+            ConfigCommand::Unset { path } => {
+                config.unset_value(path)?;
+                persist(config)
+            }
+            ConfigCommand::List { show_secrets } => {
+                let value = match show_secrets {
+                    false => config.to_redacted_value()?,
+                    true => serde_json::to_value(config.get())?,
+                };
+                emit(sink, &value)
+            }
+            ConfigCommand::Path => {
+                let path = config.path().ok_or_else(|| {
+                    Error::Precondition("this configuration is not stored in a file".to_string())
+                })?;
+                emit(sink, &Value::String(path.display().to_string()))
+            }
+            ConfigCommand::Edit => edit(config, input_stream, output_stream, editor),
+        }
+    }
+}
+
+/// Emit the given value. In text mode, strings are printed as-is rather than
+/// as quoted JSON, so e.g. `config get` output can be used in shell scripts.
+fn emit<S: AbstractStream>(sink: &mut OutputSink<S>, value: &Value) -> Result<()> {
+    match value {
+        Value::String(s) if !sink.mode().is_json() => sink.emit_text(format_args!("{}", s)),
+        _ => sink.emit_value(value),
+    }
+}
+
+/// Persist any changes made by a command, even if the configuration is in
+/// `PersistMode::Explicit` (in `Immediate` mode, they already have been).
+fn persist<T: Clone + Serialize + DeserializeOwned>(config: &mut Configuration<T>) -> Result<()> {
+    match config.dirty() {
+        false => Ok(()),
+        true => config.persist(),
+    }
+}
+
+fn edit<
+    T: Clone + Serialize + DeserializeOwned,
+    IS: AbstractStream,
+    OS: AbstractStream,
+    E: Editor,
+>(
+    config: &mut Configuration<T>,
+    input_stream: &IS,
+    output_stream: &OS,
+    editor: &E,
+) -> Result<()> {
+    // Don't make the user edit anything if it can't be saved anyway.
+    config.check_writable()?;
+
+    // Edit the persisted values, not including any environment overrides, so
+    // saving doesn't turn the overrides into permanent values.
+    let mut initial = EDIT_INSTRUCTIONS.as_bytes().to_vec();
+    config.export_to(&mut initial, PersistenceFormat::Json)?;
+    let initial = String::from_utf8(initial)?;
+
+    let edited = cli::edit_text_with(
+        input_stream,
+        output_stream,
+        editor,
+        initial.as_str(),
+        EditOptions {
+            comment_prefix: Some("#"),
+            require_change: true,
+            file_extension: None,
+        },
+    )?;
+    let edited = match edited {
+        // The user aborted, or didn't change anything.
+        None => return Ok(()),
+        Some(edited) => edited,
+    };
+
+    config
+        .import_from(edited.as_bytes(), PersistenceFormat::Json)
+        .map_err(|e| {
+            Error::InvalidArgument(format!(
+                "the edited configuration is invalid, so it was not saved: {}",
+                e
+            ))
+        })?;
+    persist(config)
+}
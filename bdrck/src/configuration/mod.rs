@@ -0,0 +1,2203 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::error::{Error, Result};
+use once_cell::sync::Lazy;
+use rmp_serde::{Deserializer, Serializer};
+use serde::de::{self, DeserializeOwned, DeserializeSeed, MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::any::Any;
+use std::boxed::Box;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock, Weak};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// cli provides a ready-made `config` command, which lets users inspect and
+/// modify an application's configuration from the command line.
+#[cfg(all(feature = "cli", feature = "fs"))]
+pub mod cli;
+/// paths resolves the per-user directories (configuration, data, cache, and
+/// state) applications should store their files in.
+pub mod paths;
+/// redact provides `Redacted`, a wrapper for secret configuration values
+/// which keeps them out of debug output, logs, and support bundles.
+pub mod redact;
+/// testing provides fixtures for temporarily replacing configuration
+/// singletons with in-memory values in unit tests.
+#[cfg(feature = "testing")]
+pub mod testing;
+
+/// An Identifier uniquely identifies a configuration file.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Identifier {
+    /// The unique name of the application this configuration file belongs to.
+    pub application: String,
+    /// The application-specific unique name for this particular configuration
+    /// file.
+    pub name: String,
+}
+
+#[cfg(target_os = "windows")]
+fn get_configuration_directory(application: &str) -> Result<PathBuf> {
+    let mut path = PathBuf::from(env::var("APPDATA")?);
+    path.push(application);
+
+    fs::create_dir_all(path.as_path())?;
+    if !path.is_dir() {
+        return Err(Error::InvalidArgument(format!(
+            "configuration path '{}' is not a directory",
+            path.as_path().display()
+        )));
+    }
+
+    Ok(path)
+}
+
+#[cfg(not(target_os = "windows"))]
+fn get_configuration_directory(application: &str) -> Result<PathBuf> {
+    let mut path = PathBuf::new();
+    path.push(
+        env::var("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or(env::var("HOME").map(|home| {
+                let mut home = PathBuf::from(home);
+                home.push(".config");
+                home
+            }))?,
+    );
+    path.push(application);
+
+    fs::create_dir_all(path.as_path())?;
+    if !path.is_dir() {
+        return Err(Error::InvalidArgument(format!(
+            "configuration path '{}' is not a directory",
+            path.as_path().display()
+        )));
+    }
+
+    Ok(path)
+}
+
+fn get_configuration_path(id: &Identifier, custom_path: Option<&Path>) -> Result<PathBuf> {
+    custom_path.map_or(
+        {
+            let mut path = PathBuf::new();
+            path.push(get_configuration_directory(id.application.as_str())?.as_path());
+            path.push(id.name.clone() + ".mp");
+            Ok(path)
+        },
+        |custom_path| Ok(PathBuf::from(custom_path)),
+    )
+}
+
+// Persisted configuration files start with a small header, so damaged files
+// can be told apart from ones which are intact but can't be deserialized: the
+// magic string, the payload's format, its length, and its CRC-32. Files
+// written by older versions have no header, and are just the payload.
+const ENVELOPE_MAGIC: &[u8; 8] = b"BDRCKCFG";
+const ENVELOPE_FORMAT_MESSAGE_PACK: u8 = 1;
+const ENVELOPE_HEADER_LEN: usize = ENVELOPE_MAGIC.len() + 1 + 8 + 4;
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < table.len() {
+        let mut c = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            c = match c & 1 {
+                0 => c >> 1,
+                _ => 0xEDB8_8320 ^ (c >> 1),
+            };
+            bit += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &b| {
+        CRC32_TABLE[((crc ^ b as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Serialize the given value to be persisted to disk, including the integrity
+/// header.
+fn serialize<T: Serialize>(v: &T) -> Result<Vec<u8>> {
+    let mut payload = Vec::new();
+    v.serialize(&mut Serializer::new(&mut payload))?;
+
+    let mut buf = Vec::with_capacity(ENVELOPE_HEADER_LEN + payload.len());
+    buf.extend_from_slice(ENVELOPE_MAGIC);
+    buf.push(ENVELOPE_FORMAT_MESSAGE_PACK);
+    buf.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    buf.extend_from_slice(&crc32(payload.as_slice()).to_le_bytes());
+    buf.extend_from_slice(payload.as_slice());
+    Ok(buf)
+}
+
+/// Verify the integrity header of the given persisted data (read from the
+/// given path), returning just the payload. Data without a header (written by
+/// older versions) is returned as-is.
+fn open_envelope<'a>(path: &Path, data: &'a [u8]) -> Result<&'a [u8]> {
+    if !data.starts_with(ENVELOPE_MAGIC) {
+        return Ok(data);
+    }
+    let corrupted = |expected_len: usize| Error::ConfigCorrupted {
+        path: path.to_path_buf(),
+        expected_len: expected_len as u64,
+        actual_len: data.len() as u64,
+    };
+    if data.len() < ENVELOPE_HEADER_LEN {
+        return Err(corrupted(ENVELOPE_HEADER_LEN));
+    }
+
+    let (header, payload) = data.split_at(ENVELOPE_HEADER_LEN);
+    let header = &header[ENVELOPE_MAGIC.len()..];
+    let format = header[0];
+    let len = u64::from_le_bytes(header[1..9].try_into().unwrap());
+    let checksum = u32::from_le_bytes(header[9..13].try_into().unwrap());
+    let expected_len = ENVELOPE_HEADER_LEN.saturating_add(len as usize);
+    if payload.len() as u64 != len || crc32(payload) != checksum {
+        return Err(corrupted(expected_len));
+    }
+    if format != ENVELOPE_FORMAT_MESSAGE_PACK {
+        return Err(Error::Unsupported(format!(
+            "configuration file '{}' uses unknown format {}",
+            path.display(),
+            format
+        )));
+    }
+    Ok(payload)
+}
+
+fn deserialize<T: Clone + DeserializeOwned>(
+    path: &PathBuf,
+    default: &T,
+    limits: &ConfigLimits,
+) -> Result<T> {
+    match fs::File::open(path) {
+        Ok(file) => {
+            let data = limits.read(file)?;
+            limits.parse_slice(
+                open_envelope(path, data.as_slice())?,
+                PersistenceFormat::MessagePack,
+            )
+        }
+        Err(error) => match error.kind() {
+            io::ErrorKind::NotFound => Ok(default.clone()),
+            _ => Err(Error::from(error)),
+        },
+    }
+}
+
+/// Write the given serialized data to the given path atomically: the data is
+/// written to a temporary file alongside it, which is then renamed into place.
+fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    path.parent().map_or(
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Invalid configuration path",
+        )),
+        fs::create_dir_all,
+    )?;
+
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+    {
+        let mut file = fs::File::create(tmp_path.as_path())?;
+        file.write_all(data)?;
+        file.flush()?;
+        file.sync_all()?;
+    }
+    fs::rename(tmp_path.as_path(), path)?;
+    Ok(())
+}
+
+/// Merge the given JSON merge patch into the given target value. Objects are
+/// merged recursively, a null removes the corresponding key (which, for an
+/// `Option` field, is equivalent to setting it to `None`), and any other value
+/// replaces the target value entirely.
+fn merge_patch(target: &mut Value, patch: Value) {
+    let patch = match patch {
+        Value::Object(patch) => patch,
+        patch => {
+            *target = patch;
+            return;
+        }
+    };
+
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    let target = target.as_object_mut().unwrap();
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(&key);
+        } else {
+            merge_patch(target.entry(key).or_insert(Value::Null), value);
+        }
+    }
+}
+
+/// Compute a minimal JSON merge patch which, when applied to `from` with
+/// `merge_patch`, produces `to`.
+fn diff_values(from: &Value, to: &Value) -> Value {
+    match (from, to) {
+        (Value::Object(from), Value::Object(to)) => {
+            let mut patch = Map::new();
+            for (key, from_value) in from.iter() {
+                match to.get(key) {
+                    None => {
+                        patch.insert(key.clone(), Value::Null);
+                    }
+                    Some(to_value) => {
+                        if from_value != to_value {
+                            patch.insert(key.clone(), diff_values(from_value, to_value));
+                        }
+                    }
+                }
+            }
+            for (key, to_value) in to.iter() {
+                if !from.contains_key(key) {
+                    patch.insert(key.clone(), to_value.clone());
+                }
+            }
+            Value::Object(patch)
+        }
+        _ => to.clone(),
+    }
+}
+
+/// The default maximum size of configuration input, in bytes (16 MiB).
+pub const DEFAULT_MAX_INPUT_BYTES: u64 = 16 * 1024 * 1024;
+/// The default maximum nesting depth of configuration input.
+pub const DEFAULT_MAX_DEPTH: usize = 64;
+/// The default maximum number of values in configuration input.
+pub const DEFAULT_MAX_NODES: usize = 1_000_000;
+/// The default maximum length of any single string or byte array in
+/// configuration input, in bytes (1 MiB).
+pub const DEFAULT_MAX_STRING_BYTES: usize = 1024 * 1024;
+
+/// ConfigLimits bounds the size and complexity of configuration input, so a
+/// malicious or broken file (or import, or snippet) can't exhaust memory or
+/// hang the parser. Input which exceeds any limit is rejected with a specific
+/// error (`Error::ConfigTooLarge` and friends), and nothing is applied.
+///
+/// Every scalar, array, object, and object key counts as one value towards
+/// `max_nodes`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ConfigLimits {
+    /// The maximum size of the raw input, in bytes. This is checked while
+    /// reading, so at most one byte more than this is ever read.
+    pub max_input_bytes: u64,
+    /// The maximum nesting depth of arrays and objects.
+    pub max_depth: usize,
+    /// The maximum total number of values.
+    pub max_nodes: usize,
+    /// The maximum length of any single string or byte array, in bytes.
+    pub max_string_bytes: usize,
+}
+
+impl Default for ConfigLimits {
+    fn default() -> Self {
+        ConfigLimits {
+            max_input_bytes: DEFAULT_MAX_INPUT_BYTES,
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_nodes: DEFAULT_MAX_NODES,
+            max_string_bytes: DEFAULT_MAX_STRING_BYTES,
+        }
+    }
+}
+
+impl ConfigLimits {
+    /// Read all of the given reader's input, returning an error as soon as
+    /// it exceeds `max_input_bytes`.
+    pub fn read<R: Read>(&self, r: R) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        r.take(self.max_input_bytes.saturating_add(1))
+            .read_to_end(&mut data)?;
+        if data.len() as u64 > self.max_input_bytes {
+            return Err(Error::ConfigTooLarge {
+                limit: self.max_input_bytes,
+            });
+        }
+        Ok(data)
+    }
+
+    /// Check that the given serialized input, in the given format, is within
+    /// these limits. Malformed input is an error as well.
+    pub fn check(&self, data: &[u8], format: PersistenceFormat) -> Result<()> {
+        if data.len() as u64 > self.max_input_bytes {
+            return Err(Error::ConfigTooLarge {
+                limit: self.max_input_bytes,
+            });
+        }
+
+        let nodes = Cell::new(0);
+        let violation = Cell::new(None);
+        let checker = LimitChecker {
+            limits: self,
+            depth: 0,
+            nodes: &nodes,
+            violation: &violation,
+        };
+        let result: Result<()> = match format {
+            PersistenceFormat::Json => {
+                let mut deserializer = serde_json::Deserializer::from_slice(data);
+                checker
+                    .deserialize(&mut deserializer)
+                    .and_then(|_| deserializer.end())
+                    .map_err(Error::from)
+            }
+            PersistenceFormat::MessagePack => checker
+                .deserialize(&mut Deserializer::new(data))
+                .map_err(Error::from),
+        };
+        match violation.take() {
+            Some(e) => Err(e),
+            None => result,
+        }
+    }
+
+    /// Check that the given (already parsed) value is within these limits.
+    /// This doesn't check `max_input_bytes`, since the value has no
+    /// serialized size.
+    pub fn check_value(&self, value: &Value) -> Result<()> {
+        let mut nodes = 0;
+        self.check_value_impl(value, 0, &mut nodes)
+    }
+
+    fn check_value_impl(&self, value: &Value, depth: usize, nodes: &mut usize) -> Result<()> {
+        self.count_node(nodes)?;
+        match value {
+            Value::String(s) => self.check_len(s.len()),
+            Value::Array(values) => {
+                self.check_depth(depth + 1)?;
+                for v in values {
+                    self.check_value_impl(v, depth + 1, nodes)?;
+                }
+                Ok(())
+            }
+            Value::Object(map) => {
+                self.check_depth(depth + 1)?;
+                for (k, v) in map {
+                    self.count_node(nodes)?;
+                    self.check_len(k.len())?;
+                    self.check_value_impl(v, depth + 1, nodes)?;
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn count_node(&self, nodes: &mut usize) -> Result<()> {
+        *nodes += 1;
+        if *nodes > self.max_nodes {
+            return Err(Error::ConfigTooComplex {
+                nodes: self.max_nodes,
+            });
+        }
+        Ok(())
+    }
+
+    fn check_depth(&self, depth: usize) -> Result<()> {
+        if depth > self.max_depth {
+            return Err(Error::ConfigTooDeep {
+                depth: self.max_depth,
+            });
+        }
+        Ok(())
+    }
+
+    fn check_len(&self, len: usize) -> Result<()> {
+        if len > self.max_string_bytes {
+            return Err(Error::ConfigStringTooLong {
+                limit: self.max_string_bytes,
+            });
+        }
+        Ok(())
+    }
+
+    /// Read, check, and then deserialize a `T` from the given reader.
+    fn parse<T: DeserializeOwned, R: Read>(&self, r: R, format: PersistenceFormat) -> Result<T> {
+        self.parse_slice(self.read(r)?.as_slice(), format)
+    }
+
+    /// Check, and then deserialize a `T` from the given input.
+    fn parse_slice<T: DeserializeOwned>(
+        &self,
+        data: &[u8],
+        format: PersistenceFormat,
+    ) -> Result<T> {
+        self.check(data, format)?;
+        Ok(match format {
+            PersistenceFormat::Json => serde_json::from_slice(data)?,
+            PersistenceFormat::MessagePack => {
+                Deserialize::deserialize(&mut Deserializer::new(data))?
+            }
+        })
+    }
+}
+
+/// LimitChecker walks serialized input without building anything, enforcing
+/// a set of ConfigLimits. Serde errors can't carry our error type, so the
+/// specific violation is recorded on the side, and parsing is aborted with a
+/// generic error.
+struct LimitChecker<'a> {
+    limits: &'a ConfigLimits,
+    depth: usize,
+    nodes: &'a Cell<usize>,
+    violation: &'a Cell<Option<Error>>,
+}
+
+impl<'a> LimitChecker<'a> {
+    fn child(&self) -> Self {
+        LimitChecker {
+            limits: self.limits,
+            depth: self.depth + 1,
+            nodes: self.nodes,
+            violation: self.violation,
+        }
+    }
+
+    fn fail<E: de::Error>(&self, error: Error) -> E {
+        let e = E::custom(error.to_string());
+        self.violation.set(Some(error));
+        e
+    }
+
+    fn node<E: de::Error>(&self) -> std::result::Result<(), E> {
+        let mut nodes = self.nodes.get();
+        self.limits
+            .count_node(&mut nodes)
+            .map_err(|error| self.fail(error))?;
+        self.nodes.set(nodes);
+        Ok(())
+    }
+
+    fn len<E: de::Error>(&self, len: usize) -> std::result::Result<(), E> {
+        self.node()?;
+        self.limits.check_len(len).map_err(|error| self.fail(error))
+    }
+
+    fn nested<E: de::Error>(&self) -> std::result::Result<(), E> {
+        self.node()?;
+        self.limits
+            .check_depth(self.depth + 1)
+            .map_err(|error| self.fail(error))
+    }
+}
+
+impl<'de, 'a> DeserializeSeed<'de> for LimitChecker<'a> {
+    type Value = ();
+
+    fn deserialize<D: de::Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> std::result::Result<(), D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de, 'a> Visitor<'de> for LimitChecker<'a> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("any configuration value")
+    }
+
+    fn visit_bool<E: de::Error>(self, _: bool) -> std::result::Result<(), E> {
+        self.node()
+    }
+
+    fn visit_i64<E: de::Error>(self, _: i64) -> std::result::Result<(), E> {
+        self.node()
+    }
+
+    fn visit_u64<E: de::Error>(self, _: u64) -> std::result::Result<(), E> {
+        self.node()
+    }
+
+    fn visit_f64<E: de::Error>(self, _: f64) -> std::result::Result<(), E> {
+        self.node()
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> std::result::Result<(), E> {
+        self.len(v.len())
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> std::result::Result<(), E> {
+        self.len(v.len())
+    }
+
+    fn visit_none<E: de::Error>(self) -> std::result::Result<(), E> {
+        self.node()
+    }
+
+    fn visit_some<D: de::Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> std::result::Result<(), D::Error> {
+        deserializer.deserialize_any(self)
+    }
+
+    fn visit_unit<E: de::Error>(self) -> std::result::Result<(), E> {
+        self.node()
+    }
+
+    fn visit_newtype_struct<D: de::Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> std::result::Result<(), D::Error> {
+        deserializer.deserialize_any(self)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> std::result::Result<(), A::Error> {
+        self.nested()?;
+        while seq.next_element_seed(self.child())?.is_some() {}
+        Ok(())
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> std::result::Result<(), A::Error> {
+        self.nested()?;
+        while map.next_key_seed(self.child())?.is_some() {
+            map.next_value_seed(self.child())?;
+        }
+        Ok(())
+    }
+}
+
+/// EnvOverrides configures how a Configuration's values can be overridden by
+/// environment variables (see `Configuration::with_env_overrides`).
+///
+/// A variable named e.g. `MYAPP_SERVER__PORT` overrides the field `port` of
+/// the field `server`: the prefix and a single `_` are stripped, and the rest
+/// is split into path segments on `__`. Segments are matched against the
+/// serialized field names case-insensitively.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct EnvOverrides {
+    prefix: String,
+    split_arrays: bool,
+    strict: bool,
+}
+
+impl EnvOverrides {
+    /// Construct a new set of options which apply variables with the given
+    /// prefix (e.g. "MYAPP"). By default, arrays must be given as JSON, and
+    /// variables which don't match any field are ignored with a warning.
+    pub fn new(prefix: &str) -> Self {
+        EnvOverrides {
+            prefix: format!("{}_", prefix.to_uppercase()),
+            split_arrays: false,
+            strict: false,
+        }
+    }
+
+    /// Set whether or not values for array fields are split on commas (e.g.
+    /// `a,b,c`), instead of being parsed as JSON arrays.
+    pub fn split_arrays(mut self, split_arrays: bool) -> Self {
+        self.split_arrays = split_arrays;
+        self
+    }
+
+    /// Set whether or not a variable with our prefix which doesn't match any
+    /// field is an error, rather than just a warning.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Return each (name, value) pair from the environment with our prefix,
+    /// sorted by name so they're always applied in the same order.
+    fn vars(&self) -> Vec<(String, String)> {
+        let mut vars: Vec<(String, String)> = env::vars_os()
+            .filter_map(|(k, v)| Some((k.into_string().ok()?, v.into_string().ok()?)))
+            .filter(|(k, _)| {
+                k.get(..self.prefix.len())
+                    .is_some_and(|p| p.eq_ignore_ascii_case(self.prefix.as_str()))
+            })
+            .collect();
+        vars.sort();
+        vars
+    }
+
+    /// Apply all of the overrides from the environment to the given
+    /// serialized configuration values.
+    fn apply<T: DeserializeOwned>(&self, value: &mut Value) -> Result<()> {
+        for (name, raw) in self.vars() {
+            let path: Vec<&str> = name[self.prefix.len()..].split("__").collect();
+            let field = match find_field(value, path.as_slice()) {
+                Some(field) => field,
+                None => {
+                    if self.strict {
+                        return Err(Error::InvalidArgument(format!(
+                            "environment variable {} doesn't match any configuration field",
+                            name
+                        )));
+                    }
+                    warn!(
+                        "ignoring environment variable {} which doesn't match any configuration field",
+                        name
+                    );
+                    continue;
+                }
+            };
+
+            let was_null = field.is_null();
+            *field = parse_env_value(field, raw.as_str(), self.split_arrays).map_err(|e| {
+                Error::InvalidArgument(format!("invalid value for {}: {}", name, e))
+            })?;
+
+            // Check the result right away, so errors point at the variable.
+            if let Err(e) = serde_json::from_value::<T>(value.clone()) {
+                // If we had to guess the type, the value might be a string
+                // which just happens to also be valid JSON (e.g. "123").
+                if was_null {
+                    *find_field(value, path.as_slice()).unwrap() = Value::String(raw.clone());
+                    if serde_json::from_value::<T>(value.clone()).is_ok() {
+                        continue;
+                    }
+                }
+                return Err(Error::InvalidArgument(format!(
+                    "invalid value for {}: {}",
+                    name, e
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn default_redacted_fields() -> Vec<String> {
+    redact::DEFAULT_REDACTED_FIELD_PATTERNS
+        .iter()
+        .map(|p| p.to_string())
+        .collect()
+}
+
+/// Find the field at the given path in the given serialized configuration
+/// values, matching field names case-insensitively.
+fn find_field<'a>(value: &'a mut Value, path: &[&str]) -> Option<&'a mut Value> {
+    let (segment, rest) = match path.split_first() {
+        None => return Some(value),
+        Some(split) => split,
+    };
+    if segment.is_empty() {
+        return None;
+    }
+    let field = value
+        .as_object_mut()?
+        .iter_mut()
+        .find(|(k, _)| k.eq_ignore_ascii_case(segment))?
+        .1;
+    find_field(field, rest)
+}
+
+/// Parse a string from the environment into a value with the same type as the
+/// given existing value. If the existing value doesn't tell us the type (i.e.,
+/// it's null), the string is parsed as JSON if possible, or else is used as-is.
+fn parse_env_value(
+    existing: &Value,
+    raw: &str,
+    split_arrays: bool,
+) -> std::result::Result<Value, String> {
+    Ok(match existing {
+        Value::Bool(_) => Value::Bool(
+            raw.trim()
+                .parse()
+                .map_err(|_| format!("expected a boolean, got '{}'", raw))?,
+        ),
+        Value::Number(n) => {
+            let raw = raw.trim();
+            let parsed = match n.is_f64() {
+                false => raw
+                    .parse::<i64>()
+                    .map(Value::from)
+                    .or_else(|_| raw.parse::<u64>().map(Value::from))
+                    .ok(),
+                true => raw.parse::<f64>().ok().map(Value::from),
+            };
+            parsed.ok_or_else(|| format!("expected a number, got '{}'", raw))?
+        }
+        Value::String(_) => Value::String(raw.to_owned()),
+        Value::Array(elements) if split_arrays => {
+            let element = elements.first().cloned().unwrap_or(Value::Null);
+            Value::Array(
+                raw.split(',')
+                    .filter(|e| !e.is_empty())
+                    .map(|e| parse_env_value(&element, e, false))
+                    .collect::<std::result::Result<Vec<Value>, String>>()?,
+            )
+        }
+        Value::Array(_) | Value::Object(_) => {
+            serde_json::from_str(raw).map_err(|e| format!("expected JSON: {}", e))?
+        }
+        Value::Null => serde_json::from_str(raw).unwrap_or_else(|_| Value::String(raw.to_owned())),
+    })
+}
+
+/// OverridesDir configures a directory of configuration "snippets" which are
+/// layered over a main configuration file (see
+/// `Configuration::open_with_overrides`), like the `conf.d` directories many
+/// daemons support.
+///
+/// Each regular file in the directory with the configured extension is parsed
+/// as a JSON merge patch, and the patches are applied in lexical order of
+/// their file names (so e.g. `10-foo.json` is applied before `20-bar.json`).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OverridesDir {
+    path: PathBuf,
+    extension: String,
+    strict: bool,
+    limits: ConfigLimits,
+}
+
+impl OverridesDir {
+    /// Construct a new set of options which load `*.json` snippets from the
+    /// given directory. By default, invalid snippets are skipped with a
+    /// warning.
+    pub fn new(path: &Path) -> Self {
+        OverridesDir {
+            path: path.to_path_buf(),
+            extension: "json".to_string(),
+            strict: false,
+            limits: ConfigLimits::default(),
+        }
+    }
+
+    /// Set the extension (without the leading ".") snippet files must have.
+    /// Other files in the directory (e.g. backups left behind by editors) are
+    /// ignored.
+    pub fn extension(mut self, extension: &str) -> Self {
+        self.extension = extension.to_owned();
+        self
+    }
+
+    /// Set whether or not an invalid snippet is an error, rather than just a
+    /// warning.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Set the limits each snippet must be within. A snippet which exceeds
+    /// them is invalid, just like a snippet which can't be parsed.
+    pub fn limits(mut self, limits: ConfigLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Return the paths of all of the snippets in the directory, in the order
+    /// they should be applied. A missing directory has no snippets.
+    fn snippet_paths(&self) -> Result<Vec<PathBuf>> {
+        let entries = match fs::read_dir(self.path.as_path()) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut paths = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) == Some(self.extension.as_str())
+                && fs::metadata(path.as_path())?.is_file()
+            {
+                paths.push(path);
+            }
+        }
+        paths.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
+        Ok(paths)
+    }
+
+    /// Load each snippet, checking that applying it over the given serialized
+    /// values still produces a valid `T`, so errors point at the snippet.
+    fn load<T: DeserializeOwned>(&self, mut value: Value) -> Result<Vec<OverrideSnippet>> {
+        let mut snippets = Vec::new();
+        for path in self.snippet_paths()? {
+            let loaded = fs::File::open(path.as_path())
+                .map_err(Error::from)
+                .and_then(|file| self.limits.parse::<Value, _>(file, PersistenceFormat::Json))
+                .and_then(|patch| {
+                    let mut updated = value.clone();
+                    merge_patch(&mut updated, patch.clone());
+                    serde_json::from_value::<T>(updated.clone())?;
+                    Ok((patch, updated))
+                });
+            match loaded {
+                Ok((patch, updated)) => {
+                    value = updated;
+                    snippets.push(OverrideSnippet { path, patch });
+                }
+                Err(e) => {
+                    if self.strict {
+                        return Err(Error::InvalidArgument(format!(
+                            "invalid configuration snippet {}: {}",
+                            path.display(),
+                            e
+                        )));
+                    }
+                    warn!(
+                        "ignoring invalid configuration snippet {}: {}",
+                        path.display(),
+                        e
+                    );
+                }
+            }
+        }
+        Ok(snippets)
+    }
+}
+
+/// A single snippet loaded from an `OverridesDir`.
+#[derive(Clone, Debug)]
+struct OverrideSnippet {
+    path: PathBuf,
+    patch: Value,
+}
+
+/// Returns true if applying the given merge patch would set (or clear) the
+/// value at the given path.
+fn patch_sets(patch: &Value, path: &[&str]) -> bool {
+    match (path.split_first(), patch) {
+        (None, _) => true,
+        (Some((segment, rest)), Value::Object(patch)) => patch
+            .get(*segment)
+            .is_some_and(|value| patch_sets(value, rest)),
+        // Anything but an object replaces the whole subtree.
+        (Some(_), _) => true,
+    }
+}
+
+/// ValueOrigin identifies where a configuration value came from (see
+/// `Configuration::explain_value`).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ValueOrigin {
+    /// The value is the default, because it wasn't set anywhere else.
+    Default,
+    /// The value was set by the main configuration file at this path.
+    MainFile(PathBuf),
+    /// The value was last set (or cleared) by the snippet at this path.
+    Snippet(PathBuf),
+    /// The value was overridden by this environment variable.
+    Environment(String),
+}
+
+impl fmt::Display for ValueOrigin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValueOrigin::Default => write!(f, "default"),
+            ValueOrigin::MainFile(path) => write!(f, "{}", path.display()),
+            ValueOrigin::Snippet(path) => write!(f, "{}", path.display()),
+            ValueOrigin::Environment(name) => write!(f, "environment variable {}", name),
+        }
+    }
+}
+
+/// UnknownFieldPolicy controls what happens when some configuration input
+/// contains fields which don't exist in the configuration type.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum UnknownFieldPolicy {
+    /// Unknown fields are silently ignored, as serde does by default.
+    #[default]
+    Ignore,
+    /// Each unknown field is logged as a warning.
+    Warn,
+    /// Unknown fields are an `Error::ConfigUnknownFields`.
+    Deny,
+}
+
+/// UnknownField describes a field in some configuration input which doesn't
+/// exist in the configuration type (e.g., because of a typo).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct UnknownField {
+    /// The path to the field, e.g. `peers[1].hots`.
+    pub path: String,
+    /// The most similar valid field name at the same level, if any is similar
+    /// enough to plausibly be what was meant.
+    pub suggestion: Option<String>,
+}
+
+impl fmt::Display for UnknownField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.path)?;
+        if let Some(suggestion) = self.suggestion.as_ref() {
+            write!(f, " (did you mean '{}'?)", suggestion)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the edit distance between the two given strings, counting
+/// insertions, deletions, substitutions, and transpositions of adjacent
+/// characters (the most common typo) as one edit each.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let (a, b): (Vec<char>, Vec<char>) = (a.chars().collect(), b.chars().collect());
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in d[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            d[i][j] = (d[i - 1][j] + 1)
+                .min(d[i][j - 1] + 1)
+                .min(d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    d[a.len()][b.len()]
+}
+
+/// Return whichever of the given candidates is most similar to `name`, if any
+/// is within a third of its length (but always allowing one edit).
+fn suggest<'a, I: IntoIterator<Item = &'a String>>(name: &str, candidates: I) -> Option<String> {
+    let max_distance = std::cmp::max(1, name.chars().count() / 3);
+    candidates
+        .into_iter()
+        .map(|c| (edit_distance(name, c), c))
+        .filter(|(d, _)| *d <= max_distance)
+        .min()
+        .map(|(_, c)| c.clone())
+}
+
+/// UnknownFields configures detection of fields in configuration input which
+/// don't exist in the configuration type (see
+/// `Configuration::with_unknown_fields`).
+///
+/// Input is compared against the serialized form of the configuration type,
+/// so fields which are legitimately absent from it (e.g. because they are
+/// `skip_serializing`, or are only accepted via an alias) would be reported.
+/// Such sections can be excluded with `allow`. Maps and arrays which serialize
+/// as empty have no known structure, so their contents are never reported.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct UnknownFields {
+    policy: UnknownFieldPolicy,
+    allowed: Vec<String>,
+}
+
+impl UnknownFields {
+    /// Construct a new set of options which handles unknown fields according
+    /// to the given policy.
+    pub fn new(policy: UnknownFieldPolicy) -> Self {
+        UnknownFields {
+            policy,
+            allowed: Vec::new(),
+        }
+    }
+
+    /// Never report the field at the given path (e.g. "plugins" or
+    /// "peers[0].extra"), or anything inside of it.
+    pub fn allow(mut self, path: &str) -> Self {
+        self.allowed.push(path.to_owned());
+        self
+    }
+
+    fn is_allowed(&self, path: &str) -> bool {
+        self.allowed.iter().any(|prefix| {
+            path.strip_prefix(prefix.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with(['.', '[']))
+        })
+    }
+
+    fn find_impl(
+        &self,
+        input: &Value,
+        reference: &Value,
+        path: &str,
+        found: &mut Vec<UnknownField>,
+    ) {
+        match (input, reference) {
+            (Value::Object(input), Value::Object(reference)) if !reference.is_empty() => {
+                for (key, value) in input.iter() {
+                    let child = match path.is_empty() {
+                        true => key.clone(),
+                        false => format!("{}.{}", path, key),
+                    };
+                    if self.is_allowed(child.as_str()) {
+                        continue;
+                    }
+                    match reference.get(key) {
+                        None => found.push(UnknownField {
+                            path: child,
+                            suggestion: suggest(key.as_str(), reference.keys()),
+                        }),
+                        Some(r) => self.find_impl(value, r, child.as_str(), found),
+                    }
+                }
+            }
+            (Value::Array(input), Value::Array(reference)) if !reference.is_empty() => {
+                for (i, value) in input.iter().enumerate() {
+                    let child = format!("{}[{}]", path, i);
+                    if self.is_allowed(child.as_str()) {
+                        continue;
+                    }
+                    let r = reference.get(i).unwrap_or(&reference[0]);
+                    self.find_impl(value, r, child.as_str(), found);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Return every field in `input` which doesn't exist in `reference` (the
+    /// serialized form of the configuration type), excluding allowed paths.
+    pub fn find(&self, input: &Value, reference: &Value) -> Vec<UnknownField> {
+        let mut found = Vec::new();
+        self.find_impl(input, reference, "", &mut found);
+        found
+    }
+
+    /// Find unknown fields as per `find`, and then handle them according to
+    /// this instance's policy.
+    pub fn check(&self, input: &Value, reference: &Value) -> Result<()> {
+        if self.policy == UnknownFieldPolicy::Ignore {
+            return Ok(());
+        }
+        let found = self.find(input, reference);
+        if found.is_empty() {
+            return Ok(());
+        }
+        match self.policy {
+            UnknownFieldPolicy::Deny => Err(Error::ConfigUnknownFields(found)),
+            _ => {
+                for field in found {
+                    warn!("ignoring unknown configuration field {}", field);
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// PersistenceFormat identifies a serialization format which configuration
+/// values can be exported to, or imported from.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PersistenceFormat {
+    /// Pretty-printed JSON. Object keys are written in sorted order, so
+    /// exports are deterministic and diff well.
+    Json,
+    /// MessagePack, the same format configurations are persisted to disk in.
+    MessagePack,
+}
+
+/// PersistMode controls when a Configuration writes its values to disk.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PersistMode {
+    /// Partial updates (`apply_patch`) are persisted immediately. Other
+    /// changes are persisted when `persist` is called. This is the default.
+    Immediate,
+    /// Nothing is written until `persist` is called, so several changes can
+    /// be batched into a single write.
+    Explicit,
+    /// Nothing is ever written, and the values can't be modified. Any attempt
+    /// to modify or persist the configuration returns
+    /// `Error::ReadOnlyConfiguration`, except via `set` and `reset`, which
+    /// can't fail, and so leave the values untouched and log a warning
+    /// instead (use `try_set` or `try_reset` to get the error).
+    ReadOnly,
+    /// The configuration only exists in memory. It can be modified freely,
+    /// but persisting it is a no-op. See `Configuration::in_memory`.
+    InMemory,
+}
+
+/// A Configuration represents a set of configuration values, initially loaded
+/// from disk, and which can be persisted back to disk e.g. just before the
+/// application exits. Generally it is expected that only one instance per
+/// Identifier is needed globally, and the other functions in this module are
+/// intended to provide an easy singleton interface for this class.
+pub struct Configuration<T> {
+    path: PathBuf,
+    default: T,
+    current: T,
+    mode: PersistMode,
+    dirty: AtomicBool,
+    /// Serializes values for persisting on drop, which (unlike the rest of
+    /// this type's API) can't require `T: Serialize`.
+    serialize: fn(&T) -> Result<Vec<u8>>,
+    persist_on_drop: bool,
+    env_overrides: Option<EnvOverrides>,
+    unknown_fields: Option<UnknownFields>,
+    limits: ConfigLimits,
+    /// Snippets loaded from an `OverridesDir`, in the order they're applied.
+    snippets: Vec<OverrideSnippet>,
+    /// The current values with any snippets and environment overrides
+    /// applied, if there are any. This is what `get` returns, but it is never
+    /// persisted.
+    overridden: Option<T>,
+    /// Field name patterns whose values are redacted, in addition to
+    /// `Redacted` values.
+    redacted_fields: Vec<String>,
+}
+
+impl<T: Clone + Serialize + DeserializeOwned> Configuration<T> {
+    /// Initialize a new Configuration with the given identifier, default set of
+    /// configuration values, and custom disk persistence path (optional). An
+    /// error might occur if determining the persistence path to use fails, or
+    /// if deserializing the previously persisted configuration (if any) fails.
+    pub fn new(id: Identifier, default: T, custom_path: Option<&Path>) -> Result<Configuration<T>> {
+        Self::new_with_mode(id, default, custom_path, PersistMode::Immediate)
+    }
+
+    /// This is identical to `new`, except the returned instance persists its
+    /// values according to the given mode instead of the default.
+    pub fn new_with_mode(
+        id: Identifier,
+        default: T,
+        custom_path: Option<&Path>,
+        mode: PersistMode,
+    ) -> Result<Configuration<T>> {
+        let path: PathBuf = get_configuration_path(&id, custom_path)?;
+        Self::open_path(path, default, mode, ConfigLimits::default())
+    }
+
+    /// Open the configuration at the given path in read-only mode. Nothing is
+    /// ever written to disk, so this works even if the file (or its directory)
+    /// isn't writable, e.g. for inspecting another user's configuration, or
+    /// for "dry run" modes.
+    pub fn open_read_only(path: &Path, default: T) -> Result<Configuration<T>> {
+        Self::open_path(
+            path.to_path_buf(),
+            default,
+            PersistMode::ReadOnly,
+            ConfigLimits::default(),
+        )
+    }
+
+    /// Open the configuration at the given path, persisting values according
+    /// to the given mode. Unlike the other constructors, which always use
+    /// `ConfigLimits::default()`, the previously persisted file (and later,
+    /// any input to `import_from` etc.) must be within the given limits.
+    pub fn open_path_with_limits(
+        path: &Path,
+        default: T,
+        mode: PersistMode,
+        limits: ConfigLimits,
+    ) -> Result<Configuration<T>> {
+        Self::open_path(path.to_path_buf(), default, mode, limits)
+    }
+
+    /// Open the configuration file with the given name in the given
+    /// application's standard configuration directory (see
+    /// `paths::config_dir`), creating the directory if necessary.
+    pub fn open_default(
+        application: &str,
+        file_name: &str,
+        default: T,
+    ) -> Result<Configuration<T>> {
+        let path = paths::config_dir(application, true)?.join(file_name);
+        Self::open_path(
+            path,
+            default,
+            PersistMode::Immediate,
+            ConfigLimits::default(),
+        )
+    }
+
+    /// Open the main configuration file at the given path, and then layer
+    /// each snippet from the given overrides directory over it, as described
+    /// by `OverridesDir`. Like environment overrides, the snippets are
+    /// reflected by `get` but are never persisted: `persist` only ever writes
+    /// the main file. Use `explain_value` to find out which file a given value
+    /// came from.
+    pub fn open_with_overrides(
+        main_path: &Path,
+        overrides: OverridesDir,
+        default: T,
+    ) -> Result<Configuration<T>> {
+        let mut config = Self::open_path(
+            main_path.to_path_buf(),
+            default,
+            PersistMode::Immediate,
+            ConfigLimits::default(),
+        )?;
+        config.snippets = overrides.load::<T>(serde_json::to_value(&config.current)?)?;
+        config.reload_env_overrides()?;
+        Ok(config)
+    }
+
+    /// Construct a Configuration which only exists in memory, starting out
+    /// with the given default values. Nothing is ever read from or written to
+    /// disk. This is mainly useful for tests (see `configuration::testing`).
+    pub fn in_memory(default: T) -> Configuration<T> {
+        Configuration {
+            path: PathBuf::new(),
+            current: default.clone(),
+            default,
+            mode: PersistMode::InMemory,
+            dirty: AtomicBool::new(false),
+            serialize: serialize::<T>,
+            persist_on_drop: false,
+            env_overrides: None,
+            unknown_fields: None,
+            limits: ConfigLimits::default(),
+            snippets: Vec::new(),
+            overridden: None,
+            redacted_fields: default_redacted_fields(),
+        }
+    }
+
+    fn open_path(
+        path: PathBuf,
+        default: T,
+        mode: PersistMode,
+        limits: ConfigLimits,
+    ) -> Result<Configuration<T>> {
+        let current: T = deserialize(&path, &default, &limits)?;
+
+        Ok(Configuration {
+            path: path,
+            default: default,
+            current: current,
+            mode,
+            dirty: AtomicBool::new(false),
+            serialize: serialize::<T>,
+            persist_on_drop: false,
+            env_overrides: None,
+            unknown_fields: None,
+            limits,
+            snippets: Vec::new(),
+            overridden: None,
+            redacted_fields: default_redacted_fields(),
+        })
+    }
+
+    /// Enable overriding configuration values with environment variables with
+    /// the given prefix. This is shorthand for `with_env_overrides` with the
+    /// default options.
+    pub fn with_env_prefix(self, prefix: &str) -> Result<Configuration<T>> {
+        self.with_env_overrides(EnvOverrides::new(prefix))
+    }
+
+    /// Enable overriding configuration values with environment variables, as
+    /// described by `EnvOverrides`. The overrides are deep-merged over the
+    /// values loaded from disk, and are reflected by `get`. They are never
+    /// persisted; `persist` (and `export_to`, `diff`, etc.) only deal with
+    /// the underlying values.
+    ///
+    /// The environment is re-read each time the underlying values change
+    /// (e.g. via `set` or `apply_patch`), or when `reload_env_overrides` is
+    /// called. An error is returned if a variable's value can't be parsed
+    /// into the type of the field it overrides.
+    pub fn with_env_overrides(mut self, overrides: EnvOverrides) -> Result<Configuration<T>> {
+        self.env_overrides = Some(overrides);
+        self.reload_env_overrides()?;
+        Ok(self)
+    }
+
+    /// Check JSON input to `import_from` and `import_patch_from` for fields
+    /// which don't exist in the configuration type, as described by
+    /// `UnknownFields`. MessagePack input (as persisted to disk) doesn't
+    /// record field names, so it is never checked.
+    pub fn with_unknown_fields(mut self, unknown_fields: UnknownFields) -> Configuration<T> {
+        self.unknown_fields = Some(unknown_fields);
+        self
+    }
+
+    /// Set the limits input to `import_from`, `import_patch_from`, and
+    /// `apply_patch` must be within (see `ConfigLimits`). By default,
+    /// `ConfigLimits::default()` is used. Since this is only called after the
+    /// persisted file has been read, use `open_path_with_limits` to limit that
+    /// too.
+    pub fn with_limits(mut self, limits: ConfigLimits) -> Configuration<T> {
+        self.limits = limits;
+        self
+    }
+
+    /// Re-read the environment, and re-apply any overrides (and any snippets
+    /// from `open_with_overrides`) to the current configuration values. This
+    /// is a no-op if there are no overrides of either kind.
+    pub fn reload_env_overrides(&mut self) -> Result<()> {
+        self.overridden = self.layered_values(true)?;
+        Ok(())
+    }
+
+    /// Return the current values with the snippets, and optionally also the
+    /// environment overrides, layered over them, or None if there's nothing
+    /// to layer.
+    fn layered_values(&self, include_env: bool) -> Result<Option<T>> {
+        let env_overrides = self.env_overrides.as_ref().filter(|_| include_env);
+        if self.snippets.is_empty() && env_overrides.is_none() {
+            return Ok(None);
+        }
+
+        let mut value = serde_json::to_value(&self.current)?;
+        for snippet in self.snippets.iter() {
+            merge_patch(&mut value, snippet.patch.clone());
+        }
+        if let Some(overrides) = env_overrides {
+            overrides.apply::<T>(&mut value)?;
+        }
+        Ok(Some(serde_json::from_value(value)?))
+    }
+
+    /// Explain where the value at the given dot-separated path (e.g.
+    /// "server.port") came from: an environment variable, the snippet which
+    /// last set it, the main configuration file, or the defaults. Values in
+    /// the main file which are equal to the default are attributed to the
+    /// defaults. It is an error if there is no such field.
+    pub fn explain_value(&self, path: &str) -> Result<ValueOrigin> {
+        let segments: Vec<&str> = path.split('.').collect();
+        let mut value = serde_json::to_value(&self.current)?;
+        for snippet in self.snippets.iter() {
+            merge_patch(&mut value, snippet.patch.clone());
+        }
+        let last_snippet = self
+            .snippets
+            .iter()
+            .rev()
+            .find(|snippet| patch_sets(&snippet.patch, segments.as_slice()));
+        if find_field(&mut value, segments.as_slice()).is_none() && last_snippet.is_none() {
+            return Err(Error::NotFound(format!(
+                "no configuration field '{}'",
+                path
+            )));
+        }
+
+        if let Some(overrides) = self.env_overrides.as_ref() {
+            // Variables are applied in sorted order, so the last one wins.
+            let var = overrides.vars().into_iter().rev().find(|(name, _)| {
+                let var_path: Vec<&str> = name[overrides.prefix.len()..].split("__").collect();
+                var_path.len() == segments.len()
+                    && var_path
+                        .iter()
+                        .zip(segments.iter())
+                        .all(|(a, b)| a.eq_ignore_ascii_case(b))
+            });
+            if let Some((name, _)) = var {
+                return Ok(ValueOrigin::Environment(name));
+            }
+        }
+        if let Some(snippet) = last_snippet {
+            return Ok(ValueOrigin::Snippet(snippet.path.clone()));
+        }
+        if self.mode == PersistMode::InMemory || !self.path.exists() {
+            return Ok(ValueOrigin::Default);
+        }
+        // The main file stores every field, so it only supplied the value if
+        // it's different from the default.
+        let mut current = serde_json::to_value(&self.current)?;
+        let mut default = serde_json::to_value(&self.default)?;
+        let current = find_field(&mut current, segments.as_slice()).map(|v| v.clone());
+        let default = find_field(&mut default, segments.as_slice()).map(|v| v.clone());
+        Ok(match current == default {
+            true => ValueOrigin::Default,
+            false => ValueOrigin::MainFile(self.path.clone()),
+        })
+    }
+
+    /// Set the field name patterns whose values are redacted (see
+    /// `to_redacted_value`), replacing the default
+    /// `redact::DEFAULT_REDACTED_FIELD_PATTERNS`. In patterns, "*" matches any
+    /// sequence of characters, and names are matched case-insensitively.
+    pub fn with_redacted_fields(mut self, patterns: &[&str]) -> Configuration<T> {
+        self.redacted_fields = patterns.iter().map(|p| p.to_string()).collect();
+        self
+    }
+
+    /// Serialize the current configuration values (as returned by `get`),
+    /// with every `redact::Redacted` value, and the value of every field whose
+    /// name matches one of the redacted field patterns (see
+    /// `with_redacted_fields`), replaced by a placeholder. This is suitable for
+    /// e.g. logging, or including in support bundles; persistence and
+    /// `export_to` are unaffected, and always use the real values.
+    pub fn to_redacted_value(&self) -> Result<Value> {
+        redact::to_redacted_value(self.get(), self.redacted_fields.as_slice())
+    }
+
+    /// Return the current value at the given dot-separated path (e.g.
+    /// "server.port"), matching field names case-insensitively. Unless
+    /// `include_secrets` is true, secrets are redacted as described by
+    /// `to_redacted_value`. It is an error if there is no such field.
+    pub fn get_value(&self, path: &str, include_secrets: bool) -> Result<Value> {
+        let mut value = match include_secrets {
+            false => self.to_redacted_value()?,
+            true => serde_json::to_value(self.get())?,
+        };
+        let segments: Vec<&str> = path.split('.').collect();
+        match find_field(&mut value, segments.as_slice()) {
+            None => Err(Error::NotFound(format!(
+                "no configuration field '{}'",
+                path
+            ))),
+            Some(field) => Ok(field.take()),
+        }
+    }
+
+    /// Set the value at the given dot-separated path (e.g. "server.port"),
+    /// matching field names case-insensitively, from a string (e.g. a
+    /// command-line argument). The string is parsed according to the field's
+    /// current type, in the same way as environment overrides are; see
+    /// `EnvOverrides`. If the field is currently null (e.g. an `Option` which
+    /// is `None`), and parsing the string as JSON doesn't produce a valid
+    /// value for it, the string itself is used instead. The new values are
+    /// persisted as with `apply_patch`.
+    ///
+    /// It is an error if there is no such field, or if the string isn't a
+    /// valid value for it. In either case, the current configuration values
+    /// are left untouched.
+    pub fn set_value(&mut self, path: &str, raw: &str) -> Result<()> {
+        self.check_writable()?;
+        let mut was_null = false;
+        let updated = self.with_field_modified(path, |field| {
+            was_null = field.is_null();
+            *field = parse_env_value(field, raw, false).map_err(|e| {
+                Error::InvalidArgument(format!("invalid value for '{}': {}", path, e))
+            })?;
+            Ok(())
+        });
+        let updated = match updated {
+            // A null field doesn't tell us its type, so parsing the string
+            // was only a guess (e.g. "123" could be meant as a string).
+            Err(e @ Error::Json(_)) if was_null => self
+                .with_field_modified(path, |field| {
+                    *field = Value::String(raw.to_owned());
+                    Ok(())
+                })
+                .map_err(|_| e)?,
+            updated => updated?,
+        };
+        self.update(updated)
+    }
+
+    /// Revert the value at the given dot-separated path back to its default
+    /// value (specified previously on construction). The new values are
+    /// persisted as with `apply_patch`.
+    ///
+    /// If the default values don't have the field at all, it is instead
+    /// reverted in the same way as its nearest parent which they do have: a
+    /// key added to a map is removed, and e.g. a field of an `Option` which is
+    /// `None` by default reverts the whole `Option` to `None`.
+    pub fn unset_value(&mut self, path: &str) -> Result<()> {
+        self.check_writable()?;
+        let segments: Vec<&str> = path.split('.').collect();
+        let mut default = serde_json::to_value(&self.default)?;
+        // The empty path (i.e. the root) always exists.
+        let depth = (0..=segments.len())
+            .rev()
+            .find(|&depth| find_field(&mut default, &segments[..depth]).is_some())
+            .unwrap_or(0);
+        let default = find_field(&mut default, &segments[..depth])
+            .map(Value::take)
+            .unwrap_or(Value::Null);
+
+        let mut value = serde_json::to_value(&self.current)?;
+        if find_field(&mut value, segments.as_slice()).is_none() {
+            return Err(Error::NotFound(format!(
+                "no configuration field '{}'",
+                path
+            )));
+        }
+        // The field exists in the current values, so all of its parents do.
+        let parent = find_field(&mut value, &segments[..depth]).unwrap();
+        match default {
+            Value::Object(_) if depth < segments.len() => {
+                let key = parent
+                    .as_object()
+                    .and_then(|fields| {
+                        fields
+                            .keys()
+                            .find(|k| k.eq_ignore_ascii_case(segments[depth]))
+                    })
+                    .cloned();
+                if let Some(key) = key {
+                    parent.as_object_mut().unwrap().remove(&key);
+                }
+            }
+            default => *parent = default,
+        }
+        self.limits.check_value(&value)?;
+        let updated: T = serde_json::from_value(value)?;
+        self.update(updated)
+    }
+
+    /// Return the current configuration values, with the field at the given
+    /// path modified by the given function. This doesn't apply or persist the
+    /// result. It is an error if there is no such field.
+    fn with_field_modified<F: FnOnce(&mut Value) -> Result<()>>(
+        &self,
+        path: &str,
+        f: F,
+    ) -> Result<T> {
+        let mut value = serde_json::to_value(&self.current)?;
+        let segments: Vec<&str> = path.split('.').collect();
+        let field = find_field(&mut value, segments.as_slice())
+            .ok_or_else(|| Error::NotFound(format!("no configuration field '{}'", path)))?;
+        f(field)?;
+        self.limits.check_value(&value)?;
+        Ok(serde_json::from_value(value)?)
+    }
+
+    /// Return the path this instance is persisted to, or None if it only
+    /// exists in memory (see `in_memory`).
+    pub fn path(&self) -> Option<&Path> {
+        match self.mode {
+            PersistMode::InMemory => None,
+            _ => Some(self.path.as_path()),
+        }
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.mode == PersistMode::ReadOnly {
+            return Err(Error::ReadOnlyConfiguration(format!(
+                "'{}' was opened read-only",
+                self.path.display()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Return the mode which controls when this instance writes to disk.
+    pub fn mode(&self) -> PersistMode {
+        self.mode
+    }
+
+    /// Return whether or not this instance has changes which haven't been
+    /// persisted to disk yet.
+    pub fn dirty(&self) -> bool {
+        self.dirty.load(Ordering::SeqCst)
+    }
+
+    /// Set whether or not any unpersisted changes should be persisted when
+    /// this instance is dropped. This is off by default. Any error persisting
+    /// on drop is ignored; call `persist` explicitly to handle errors.
+    pub fn persist_on_drop(&mut self, persist_on_drop: bool) {
+        self.persist_on_drop = persist_on_drop;
+    }
+
+    /// Return this instance's current set of configuration values, including
+    /// any snippets (see `open_with_overrides`) and environment overrides (see
+    /// `with_env_overrides`).
+    pub fn get(&self) -> &T {
+        self.overridden.as_ref().unwrap_or(&self.current)
+    }
+
+    /// Replace all existing configuration values with the given entirely new
+    /// set of configuration values.
+    ///
+    /// Note that if environment overrides are enabled, passing in a modified
+    /// copy of `get` will persist the overridden values. Use `apply_patch`
+    /// to change individual values instead. If the environment overrides can't
+    /// be applied to the new values, a warning is logged, and they are ignored
+    /// (but any snippets still apply) until the next successful call to
+    /// `reload_env_overrides`; use `try_set` to handle this error instead.
+    ///
+    /// If this instance is read-only, the values are left untouched, and a
+    /// warning is logged; again, use `try_set` to get the error instead.
+    pub fn set(&mut self, config: T) {
+        if let Err(e) = self.check_writable() {
+            warn!("not replacing the configuration values: {}", e);
+            return;
+        }
+        self.current = config;
+        self.dirty.store(true, Ordering::SeqCst);
+        if let Err(e) = self.reload_env_overrides() {
+            warn!("ignoring environment overrides: {}", e);
+            self.overridden = match self.layered_values(false) {
+                Ok(overridden) => overridden,
+                Err(e) => {
+                    warn!("ignoring configuration snippets: {}", e);
+                    None
+                }
+            };
+        }
+    }
+
+    /// This is identical to `set`, except it returns an error if this instance
+    /// is read-only, or if any overrides can't be applied to the new values.
+    pub fn try_set(&mut self, config: T) -> Result<()> {
+        self.check_writable()?;
+        self.current = config;
+        self.dirty.store(true, Ordering::SeqCst);
+        self.reload_env_overrides()
+    }
+
+    /// Reset all of this instance's configuration values back to their default
+    /// values (specified previously on construction).
+    pub fn reset(&mut self) {
+        self.set(self.default.clone())
+    }
+
+    /// This is identical to `reset`, except errors are returned as per
+    /// `try_set`.
+    pub fn try_reset(&mut self) -> Result<()> {
+        self.try_set(self.default.clone())
+    }
+
+    /// Persist this instance's current configuration values to disk, so they
+    /// can be re-loaded on the next construction.
+    pub fn persist(&self) -> Result<()> {
+        self.check_writable()?;
+        if self.mode != PersistMode::InMemory {
+            write_atomic(self.path.as_path(), serialize(&self.current)?.as_slice())?;
+        }
+        self.dirty.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Apply a partial update to this instance's current configuration values.
+    ///
+    /// The patch is a JSON merge patch: it is deep-merged into the JSON
+    /// representation of the current values (objects are merged recursively,
+    /// other values replace the existing value, and null removes a value, e.g.
+    /// setting an `Option` field to `None`). The result must still deserialize
+    /// into a valid `T`. If it does, the new values are persisted to disk
+    /// (unless this instance is in `PersistMode::Explicit`, in which case
+    /// they're persisted on the next call to `persist`).
+    ///
+    /// If any step fails, the current configuration values are left untouched.
+    pub fn apply_patch(&mut self, patch: Value) -> Result<()> {
+        self.check_writable()?;
+        self.limits.check_value(&patch)?;
+        let mut value = serde_json::to_value(&self.current)?;
+        merge_patch(&mut value, patch);
+        let updated: T = serde_json::from_value(value)?;
+        self.update(updated)
+    }
+
+    /// Replace the current configuration values with `updated`, persisting
+    /// them according to this instance's mode.
+    fn update(&mut self, updated: T) -> Result<()> {
+        match self.mode {
+            PersistMode::Immediate => {
+                write_atomic(self.path.as_path(), serialize(&updated)?.as_slice())?
+            }
+            _ => self.dirty.store(true, Ordering::SeqCst),
+        }
+        self.current = updated;
+        self.reload_env_overrides()
+    }
+
+    /// Write this instance's current configuration values to the given
+    /// writer, in the given format. This is useful e.g. for backups, or for
+    /// inspecting the configuration with other tools.
+    pub fn export_to<W: Write>(&self, mut w: W, format: PersistenceFormat) -> Result<()> {
+        match format {
+            PersistenceFormat::Json => {
+                // Going through Value sorts object keys, so output is stable.
+                let value = serde_json::to_value(&self.current)?;
+                serde_json::to_writer_pretty(&mut w, &value)?;
+                w.write_all(b"\n")?;
+            }
+            PersistenceFormat::MessagePack => {
+                self.current.serialize(&mut Serializer::new(&mut w))?;
+            }
+        }
+        w.flush()?;
+        Ok(())
+    }
+
+    /// Replace this instance's current configuration values with those read
+    /// from the given reader, in the given format (e.g. as previously written
+    /// by `export_to`, or for MessagePack, a persisted configuration file). The
+    /// new values are persisted the same way as with `apply_patch`.
+    ///
+    /// If the input can't be deserialized, or exceeds this instance's limits
+    /// (see `with_limits`), the current configuration values are left
+    /// untouched.
+    pub fn import_from<R: Read>(&mut self, r: R, format: PersistenceFormat) -> Result<()> {
+        self.check_writable()?;
+        let imported: T = match format {
+            PersistenceFormat::Json => {
+                let value: Value = self.limits.parse(r, format)?;
+                let imported: T = serde_json::from_value(value.clone())?;
+                if let Some(unknown_fields) = self.unknown_fields.as_ref() {
+                    unknown_fields.check(&value, &serde_json::to_value(&imported)?)?;
+                }
+                imported
+            }
+            PersistenceFormat::MessagePack => self.parse_message_pack(r)?,
+        };
+        self.update(imported)
+    }
+
+    /// This is identical to `import_from`, except the input is treated as a
+    /// patch (see `apply_patch`) rather than an entire set of configuration
+    /// values. This allows restoring only part of a configuration.
+    pub fn import_patch_from<R: Read>(&mut self, r: R, format: PersistenceFormat) -> Result<()> {
+        self.check_writable()?;
+        let patch: Value = match format {
+            PersistenceFormat::Json => {
+                let patch: Value = self.limits.parse(r, format)?;
+                if let Some(unknown_fields) = self.unknown_fields.as_ref() {
+                    unknown_fields.check(&patch, &serde_json::to_value(&self.current)?)?;
+                }
+                patch
+            }
+            PersistenceFormat::MessagePack => self.parse_message_pack(r)?,
+        };
+        self.apply_patch(patch)
+    }
+
+    /// Read MessagePack input for `import_from` or `import_patch_from`. This
+    /// can either be raw MessagePack (as written by `export_to`), or a whole
+    /// persisted configuration file, whose integrity header is checked and
+    /// stripped.
+    fn parse_message_pack<V: DeserializeOwned, R: Read>(&self, r: R) -> Result<V> {
+        let data = self.limits.read(r)?;
+        self.limits.parse_slice(
+            open_envelope(Path::new("<imported data>"), data.as_slice())?,
+            PersistenceFormat::MessagePack,
+        )
+    }
+
+    /// Return a minimal patch (suitable for `apply_patch`) which would change
+    /// this instance's current configuration values into `other`.
+    pub fn diff(&self, other: &T) -> Result<Value> {
+        Ok(diff_values(
+            &serde_json::to_value(&self.current)?,
+            &serde_json::to_value(other)?,
+        ))
+    }
+}
+
+impl<T> Drop for Configuration<T> {
+    fn drop(&mut self) {
+        if self.persist_on_drop
+            && self.dirty.load(Ordering::SeqCst)
+            && self.mode != PersistMode::ReadOnly
+            && self.mode != PersistMode::InMemory
+        {
+            if let Ok(data) = (self.serialize)(&self.current) {
+                let _ = write_atomic(self.path.as_path(), data.as_slice());
+            }
+        }
+    }
+}
+
+/// SharedConfiguration wraps a Configuration so it can be shared between
+/// threads, with cheap reads: readers get an `Arc` snapshot of the current
+/// values, and never wait for a writer for longer than it takes to swap that
+/// pointer.
+///
+/// Mutations are applied to the underlying Configuration (and persisted,
+/// according to its mode) first; only then is the new snapshot published, so
+/// readers always see a complete, consistent set of values. A consequence is
+/// that snapshots may be slightly stale while a mutation is in progress, and
+/// a snapshot held by a reader is never updated in place.
+///
+/// Each mutation which actually changes the values increments a generation
+/// counter. Other components can `subscribe` to be notified of such changes,
+/// e.g. to resize a connection pool when its configured size changes.
+pub struct SharedConfiguration<T> {
+    inner: Mutex<Configuration<T>>,
+    snapshot: RwLock<Arc<T>>,
+    changes: ChangesHandle,
+}
+
+impl<T: Clone + Serialize + DeserializeOwned> SharedConfiguration<T> {
+    /// Wrap the given Configuration.
+    pub fn new(config: Configuration<T>) -> Self {
+        let snapshot = RwLock::new(Arc::new(config.get().clone()));
+        let changes = ChangesHandle(Arc::new(Changes {
+            log: Mutex::new(ChangeLog {
+                generation: 0,
+                value: serde_json::to_value(config.get()).unwrap_or(Value::Null),
+                fields: HashMap::new(),
+                closed: false,
+            }),
+            changed: Condvar::new(),
+        }));
+        SharedConfiguration {
+            inner: Mutex::new(config),
+            snapshot,
+            changes,
+        }
+    }
+
+    /// Return the current configuration values (including any environment
+    /// overrides). See `Configuration::get`.
+    pub fn snapshot(&self) -> Arc<T> {
+        match self.snapshot.read() {
+            Ok(guard) => guard.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    /// Call the given function with the current configuration values.
+    pub fn read<R, F: FnOnce(&T) -> R>(&self, f: F) -> R {
+        f(&self.snapshot())
+    }
+
+    /// Apply the given function to the underlying Configuration, e.g. to call
+    /// `apply_patch` or `persist`. Concurrent writers are serialized. Once it
+    /// returns, the (possibly) updated values are published to readers.
+    pub fn apply<R, F: FnOnce(&mut Configuration<T>) -> R>(&self, f: F) -> R {
+        let mut config = lock(&self.inner);
+        let ret = f(&mut config);
+        let updated = Arc::new(config.get().clone());
+        match self.snapshot.write() {
+            Ok(mut guard) => *guard = updated.clone(),
+            Err(poisoned) => *poisoned.into_inner() = updated.clone(),
+        }
+        // Subscribers are only notified once the new snapshot is visible.
+        match serde_json::to_value(&*updated) {
+            Ok(value) => self.changes.0.publish(value),
+            Err(e) => warn!("not notifying configuration subscribers: {}", e),
+        }
+        ret
+    }
+
+    /// Return the current generation: the number of mutations so far which
+    /// actually changed the configuration values.
+    pub fn generation(&self) -> u64 {
+        lock(&self.changes.0.log).generation
+    }
+
+    /// Subscribe to changes to the configuration values made after this call.
+    pub fn subscribe(&self) -> Subscription {
+        let log = lock(&self.changes.0.log);
+        Subscription {
+            changes: Arc::downgrade(&self.changes.0),
+            field: None,
+            seen: log.generation,
+        }
+    }
+
+    /// Subscribe to changes to the serialized value at the given
+    /// dot-separated path (e.g. "pool.max_connections") made after this
+    /// call. Changes to other values are ignored. It is an error if there is
+    /// no such field.
+    pub fn subscribe_field(&self, path: &str) -> Result<Subscription> {
+        let mut log = lock(&self.changes.0.log);
+        let pointer = field_pointer(path);
+        if log.value.pointer(pointer.as_str()).is_none() {
+            return Err(Error::NotFound(format!(
+                "no configuration field '{}'",
+                path
+            )));
+        }
+        log.fields.entry(pointer.clone()).or_insert(0);
+        Ok(Subscription {
+            changes: Arc::downgrade(&self.changes.0),
+            field: Some(pointer),
+            seen: log.generation,
+        })
+    }
+
+    /// Modify the current configuration values with the given function, then
+    /// replace them as per `Configuration::set`. The function operates on a
+    /// copy, so readers never observe a partially modified value.
+    pub fn modify<F: FnOnce(&mut T)>(&self, f: F) -> Result<()> {
+        self.apply(|config| {
+            let mut updated = config.current.clone();
+            f(&mut updated);
+            config.try_set(updated)
+        })
+    }
+
+    /// Unwrap the underlying Configuration.
+    pub fn into_inner(self) -> Configuration<T> {
+        match self.inner.into_inner() {
+            Ok(config) => config,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+/// Convert a dot-separated field path into a JSON pointer.
+fn field_pointer(path: &str) -> String {
+    path.split('.')
+        .map(|segment| format!("/{}", segment.replace('~', "~0").replace('/', "~1")))
+        .collect()
+}
+
+/// The state `Subscription`s observe.
+struct ChangeLog {
+    generation: u64,
+    /// The serialized values as of the current generation.
+    value: Value,
+    /// For each field (as a JSON pointer) anyone has subscribed to, the
+    /// generation in which it last changed (or 0, if it hasn't changed since
+    /// the first subscription to it).
+    fields: HashMap<String, u64>,
+    /// Set once the SharedConfiguration is gone.
+    closed: bool,
+}
+
+struct Changes {
+    log: Mutex<ChangeLog>,
+    changed: Condvar,
+}
+
+impl Changes {
+    fn publish(&self, value: Value) {
+        let mut log = lock(&self.log);
+        if log.value == value {
+            return;
+        }
+        log.generation += 1;
+        let ChangeLog {
+            generation,
+            value: previous,
+            fields,
+            ..
+        } = &mut *log;
+        for (pointer, changed) in fields.iter_mut() {
+            if previous.pointer(pointer) != value.pointer(pointer) {
+                *changed = *generation;
+            }
+        }
+        log.value = value;
+        self.changed.notify_all();
+    }
+}
+
+/// Wakes up any waiting subscribers once the owning SharedConfiguration is
+/// dropped.
+struct ChangesHandle(Arc<Changes>);
+
+impl Drop for ChangesHandle {
+    fn drop(&mut self) {
+        lock(&self.0.log).closed = true;
+        self.0.changed.notify_all();
+    }
+}
+
+/// A Subscription to changes to a `SharedConfiguration` (see
+/// `SharedConfiguration::subscribe`).
+///
+/// Changes are tracked by generation rather than signaled as events, so a
+/// change is never missed, even if it races with subscribing: any change not
+/// already visible when the subscription was created is reported.
+///
+/// A Subscription doesn't keep the configuration alive. Once it is dropped,
+/// no more changes are reported.
+pub struct Subscription {
+    changes: Weak<Changes>,
+    field: Option<String>,
+    /// The generation this subscriber has seen.
+    seen: u64,
+}
+
+impl Subscription {
+    /// Return the generation in which the subscribed values last changed.
+    fn changed_in(&self, log: &ChangeLog) -> u64 {
+        match self.field.as_ref() {
+            None => log.generation,
+            Some(pointer) => log.fields.get(pointer).copied().unwrap_or(0),
+        }
+    }
+
+    /// Return the last generation this subscriber has seen, i.e. the
+    /// generation when it was created, or the last one `wait_for_change`
+    /// returned.
+    pub fn generation(&self) -> u64 {
+        self.seen
+    }
+
+    /// Returns whether or not the subscribed values have changed since the
+    /// given generation.
+    pub fn changed_since(&self, generation: u64) -> bool {
+        match self.changes.upgrade() {
+            Some(changes) => self.changed_in(&lock(&changes.log)) > generation,
+            None => false,
+        }
+    }
+
+    /// Wait for the subscribed values to change after the last generation
+    /// this subscriber has seen, for up to the given timeout. Returns the
+    /// generation in which they changed, or None if they didn't (including
+    /// if the configuration is dropped while waiting).
+    pub fn wait_for_change(&mut self, timeout: Duration) -> Option<u64> {
+        let changes = self.changes.upgrade()?;
+        let deadline = Instant::now() + timeout;
+        let mut log = lock(&changes.log);
+        loop {
+            let changed_in = self.changed_in(&log);
+            if changed_in > self.seen {
+                self.seen = changed_in;
+                return Some(changed_in);
+            }
+            let now = Instant::now();
+            if log.closed || now >= deadline {
+                return None;
+            }
+            log = match changes.changed.wait_timeout(log, deadline - now) {
+                Ok((guard, _)) => guard,
+                Err(poisoned) => poisoned.into_inner().0,
+            };
+        }
+    }
+}
+
+static SINGLETONS: Lazy<Mutex<HashMap<Identifier, Box<dyn Any + Send>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// new initializes a new configuration singleton with the given identifer,
+/// default set of configuration values, and custom disk persistence path
+/// (optional). An error might occur if determining the persistence path to use
+/// fails, or if deserializing the previously persisted configuration (if any)
+/// fails.
+pub fn new<T: Clone + Serialize + DeserializeOwned + Send + 'static>(
+    id: Identifier,
+    default: T,
+    custom_path: Option<&Path>,
+) -> Result<()> {
+    use std::ops::DerefMut;
+    let config: Configuration<T> = Configuration::new(id.clone(), default, custom_path)?;
+    let mut guard = lock(&SINGLETONS);
+    guard.deref_mut().insert(id, Box::new(config));
+    Ok(())
+}
+
+/// remove persists and then removes the configuration singleton matching the
+/// given identifier. After calling this function, the configuration in question
+/// will be unavailable. Read-only configurations are removed without being
+/// persisted.
+pub fn remove<T: Clone + Serialize + DeserializeOwned + 'static>(id: &Identifier) -> Result<()> {
+    let mut guard = lock(&SINGLETONS);
+
+    if let Some(instance) = guard.get(id) {
+        if let Some(config) = instance.downcast_ref::<Configuration<T>>() {
+            if config.mode() != PersistMode::ReadOnly {
+                config.persist()?;
+            }
+        } else {
+            return Err(Error::InvalidArgument(format!(
+                "wrong type specified for configuration {:?}",
+                id
+            )));
+        }
+    }
+
+    match guard.remove(id) {
+        Some(_) => Ok(()),
+        None => {
+            return Err(Error::InvalidArgument(format!(
+                "unrecognized configuration identifier: {:?}",
+                id
+            )));
+        }
+    }
+}
+
+/// instance_apply is a very generic function which applies the given function
+/// to the configuration singleton matching the given identifier. It is an error
+/// if the identifier is unrecognized, or if the given callback operates on a
+/// Configuration of the wrong type.
+pub fn instance_apply<T: 'static, R, F: FnOnce(&Configuration<T>) -> R>(
+    id: &Identifier,
+    f: F,
+) -> Result<R> {
+    match lock(&SINGLETONS).get(id) {
+        Some(instance) => match instance.downcast_ref() {
+            Some(config) => Ok(f(config)),
+            None => {
+                return Err(Error::InvalidArgument(format!(
+                    "wrong type specified for configuration {:?}",
+                    id
+                )));
+            }
+        },
+        None => {
+            return Err(Error::InvalidArgument(format!(
+                "unrecognized configuration identifier: {:?}",
+                id
+            )));
+        }
+    }
+}
+
+/// instance_apply_mut is a very generic function which applies the given
+/// mutation function once to the configuration singleton matching the given
+/// identifier. It is an error if the identifier is unrecognized, or if the
+/// given callback operates on a Configuration of the wrong type.
+pub fn instance_apply_mut<T: 'static, R, F: FnOnce(&mut Configuration<T>) -> R>(
+    id: &Identifier,
+    f: F,
+) -> Result<R> {
+    match lock(&SINGLETONS).get_mut(id) {
+        Some(instance) => match instance.downcast_mut() {
+            Some(config) => Ok(f(config)),
+            None => {
+                return Err(Error::InvalidArgument(format!(
+                    "wrong type specified for configuration {:?}",
+                    id
+                )));
+            }
+        },
+        None => {
+            return Err(Error::InvalidArgument(format!(
+                "unrecognized configuration identifier: {:?}",
+                id
+            )));
+        }
+    }
+}
+
+/// get returns the entire current set of configuration values in the
+/// configuration singleton matching the given identifier.
+pub fn get<T: Clone + Serialize + DeserializeOwned + 'static>(id: &Identifier) -> Result<T> {
+    instance_apply::<T, T, _>(id, |instance| instance.get().clone())
+}
+
+/// set replaces all existing configuration values with the given entirely new
+/// set of configuration values in the configuration singleton matching the
+/// given identifier. Errors are returned as per `Configuration::try_set`.
+pub fn set<T: Clone + Serialize + DeserializeOwned + 'static>(
+    id: &Identifier,
+    config: T,
+) -> Result<()> {
+    instance_apply_mut(id, move |instance| instance.try_set(config))?
+}
+
+/// reset modifies the configuration singleton matching the given identifier to
+/// its default values. Errors are returned as per `Configuration::try_reset`.
+pub fn reset<T: Clone + Serialize + DeserializeOwned + 'static>(id: &Identifier) -> Result<()> {
+    instance_apply_mut::<T, _, _>(id, |instance| instance.try_reset())?
+}
+
+/// apply_patch applies the given partial update to the configuration singleton
+/// matching the given identifier, and persists the result. See
+/// `Configuration::apply_patch` for details.
+pub fn apply_patch<T: Clone + Serialize + DeserializeOwned + 'static>(
+    id: &Identifier,
+    patch: Value,
+) -> Result<()> {
+    instance_apply_mut::<T, _, _>(id, |instance| instance.apply_patch(patch))?
+}
+
+/// persist writes the configuration singleton matching the given identifier to
+/// disk.
+pub fn persist<T: Clone + Serialize + DeserializeOwned + 'static>(id: &Identifier) -> Result<()> {
+    instance_apply::<T, _, _>(id, |instance| instance.persist())?
+}
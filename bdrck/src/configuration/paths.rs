@@ -0,0 +1,174 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::error::{Error, Result};
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::path::PathBuf;
+
+/// The kinds of per-user base directories an application can store files in.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum BaseDirectory {
+    Config,
+    Data,
+    Cache,
+    State,
+}
+
+impl BaseDirectory {
+    /// The XDG environment variable which overrides this base directory.
+    #[cfg(not(windows))]
+    fn xdg_variable(self) -> &'static str {
+        match self {
+            BaseDirectory::Config => "XDG_CONFIG_HOME",
+            BaseDirectory::Data => "XDG_DATA_HOME",
+            BaseDirectory::Cache => "XDG_CACHE_HOME",
+            BaseDirectory::State => "XDG_STATE_HOME",
+        }
+    }
+
+    /// The fallback location, relative to `$HOME`, used if the XDG variable
+    /// is unset (or is not an absolute path, which the spec says to ignore).
+    #[cfg(all(not(windows), not(target_os = "macos")))]
+    fn home_fallback(self) -> &'static str {
+        match self {
+            BaseDirectory::Config => ".config",
+            BaseDirectory::Data => ".local/share",
+            BaseDirectory::Cache => ".cache",
+            BaseDirectory::State => ".local/state",
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    fn home_fallback(self) -> &'static str {
+        match self {
+            BaseDirectory::Cache => "Library/Caches",
+            _ => "Library/Application Support",
+        }
+    }
+}
+
+#[cfg(windows)]
+fn base_directory<F: Fn(&str) -> Option<OsString>>(
+    kind: BaseDirectory,
+    lookup: &F,
+) -> Result<PathBuf> {
+    // Configuration and data roam with the user's profile; caches and state
+    // are machine-specific, so they belong in the local application data.
+    let variable = match kind {
+        BaseDirectory::Config | BaseDirectory::Data => "APPDATA",
+        BaseDirectory::Cache | BaseDirectory::State => "LOCALAPPDATA",
+    };
+    lookup(variable)
+        .map(PathBuf::from)
+        .ok_or_else(|| Error::NotFound(format!("environment variable {} is not set", variable)))
+}
+
+#[cfg(not(windows))]
+fn base_directory<F: Fn(&str) -> Option<OsString>>(
+    kind: BaseDirectory,
+    lookup: &F,
+) -> Result<PathBuf> {
+    if let Some(path) = lookup(kind.xdg_variable()).map(PathBuf::from) {
+        if path.is_absolute() {
+            return Ok(path);
+        }
+    }
+
+    match lookup("HOME").map(PathBuf::from) {
+        Some(home) if !home.as_os_str().is_empty() => Ok(home.join(kind.home_fallback())),
+        _ => Err(Error::NotFound(format!(
+            "neither {} nor HOME is set",
+            kind.xdg_variable()
+        ))),
+    }
+}
+
+#[cfg(unix)]
+fn create_private_dir(path: &std::path::Path) -> Result<()> {
+    use std::os::unix::fs::DirBuilderExt;
+
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(path)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn create_private_dir(path: &std::path::Path) -> Result<()> {
+    fs::create_dir_all(path)?;
+    Ok(())
+}
+
+/// Resolve the given application's base directory of the given kind, looking
+/// up environment variables with the given function. If `ensure` is true, the
+/// directory (and any missing parents) is created with mode 0700.
+pub(crate) fn resolve_with<F: Fn(&str) -> Option<OsString>>(
+    kind: BaseDirectory,
+    application: &str,
+    ensure: bool,
+    lookup: F,
+) -> Result<PathBuf> {
+    let path = base_directory(kind, &lookup)?.join(application);
+
+    if ensure {
+        create_private_dir(&path)?;
+        if !path.is_dir() {
+            return Err(Error::InvalidArgument(format!(
+                "'{}' is not a directory",
+                path.display()
+            )));
+        }
+    }
+
+    Ok(path)
+}
+
+fn resolve(kind: BaseDirectory, application: &str, ensure: bool) -> Result<PathBuf> {
+    resolve_with(kind, application, ensure, |name| env::var_os(name))
+}
+
+/// Returns the directory the given application should store its configuration
+/// files in. On Linux and other UNIX-like systems, this is
+/// `$XDG_CONFIG_HOME/<application>`, falling back to `~/.config/<application>`.
+/// If `ensure` is true, the directory is created (with mode 0700) if needed.
+pub fn config_dir(application: &str, ensure: bool) -> Result<PathBuf> {
+    resolve(BaseDirectory::Config, application, ensure)
+}
+
+/// Returns the directory the given application should store its data files in.
+/// On Linux and other UNIX-like systems, this is `$XDG_DATA_HOME/<application>`,
+/// falling back to `~/.local/share/<application>`. If `ensure` is true, the
+/// directory is created (with mode 0700) if needed.
+pub fn data_dir(application: &str, ensure: bool) -> Result<PathBuf> {
+    resolve(BaseDirectory::Data, application, ensure)
+}
+
+/// Returns the directory the given application should store non-essential
+/// cached files in. On Linux and other UNIX-like systems, this is
+/// `$XDG_CACHE_HOME/<application>`, falling back to `~/.cache/<application>`.
+/// If `ensure` is true, the directory is created (with mode 0700) if needed.
+pub fn cache_dir(application: &str, ensure: bool) -> Result<PathBuf> {
+    resolve(BaseDirectory::Cache, application, ensure)
+}
+
+/// Returns the directory the given application should store state (e.g.
+/// history or logs, which should persist but aren't portable) in. On Linux
+/// and other UNIX-like systems, this is `$XDG_STATE_HOME/<application>`,
+/// falling back to `~/.local/state/<application>`. If `ensure` is true, the
+/// directory is created (with mode 0700) if needed.
+pub fn state_dir(application: &str, ensure: bool) -> Result<PathBuf> {
+    resolve(BaseDirectory::State, application, ensure)
+}
@@ -0,0 +1,178 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::error::Result;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+use std::cell::Cell;
+use std::fmt;
+
+/// The placeholder which replaces redacted values.
+pub const REDACTED_PLACEHOLDER: &str = "********";
+
+/// The field name patterns whose values are redacted by default, even if they
+/// aren't wrapped in `Redacted`. See `field_name_matches` for the syntax.
+pub const DEFAULT_REDACTED_FIELD_PATTERNS: &[&str] = &["*token*", "*password*", "*secret*"];
+
+thread_local! {
+    /// Whether `Redacted` values being serialized on this thread should be
+    /// replaced with `REDACTED_PLACEHOLDER`.
+    static REDACTING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Restores the previous value of `REDACTING` when dropped, even if
+/// serialization panics.
+struct RedactingGuard {
+    previous: bool,
+}
+
+impl RedactingGuard {
+    fn new() -> Self {
+        RedactingGuard {
+            previous: REDACTING.with(|r| r.replace(true)),
+        }
+    }
+}
+
+impl Drop for RedactingGuard {
+    fn drop(&mut self) {
+        REDACTING.with(|r| r.set(self.previous));
+    }
+}
+
+/// Redacted wraps a configuration value which is secret (e.g. an API token).
+///
+/// It is serialized and deserialized transparently, so persisted (or exported
+/// / imported) configuration contains the real value. But, its `Debug` and
+/// `Display` implementations print `REDACTED_PLACEHOLDER` instead, and it is
+/// replaced with the placeholder in redacted views of the configuration (see
+/// `Configuration::to_redacted_value`).
+#[derive(Clone, Copy, Default, Eq, Hash, PartialEq)]
+pub struct Redacted<T>(T);
+
+impl<T> Redacted<T> {
+    /// Wrap the given secret value.
+    pub fn new(value: T) -> Self {
+        Redacted(value)
+    }
+
+    /// Return a reference to the real, unredacted value.
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+
+    /// Return the real, unredacted value.
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> From<T> for Redacted<T> {
+    fn from(value: T) -> Self {
+        Redacted(value)
+    }
+}
+
+impl<T> fmt::Debug for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(REDACTED_PLACEHOLDER)
+    }
+}
+
+impl<T> fmt::Display for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(REDACTED_PLACEHOLDER)
+    }
+}
+
+impl<T: Serialize> Serialize for Redacted<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        match REDACTING.with(|r| r.get()) {
+            false => self.0.serialize(serializer),
+            true => serializer.serialize_str(REDACTED_PLACEHOLDER),
+        }
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Redacted<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        T::deserialize(deserializer).map(Redacted)
+    }
+}
+
+/// Returns whether the given field name matches the given pattern, ignoring
+/// case. In patterns, "*" matches any (possibly empty) sequence of
+/// characters, and everything else matches itself.
+pub fn field_name_matches(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.to_ascii_lowercase();
+    let name = name.to_ascii_lowercase();
+    let mut parts = pattern.split('*');
+    // There is always at least one part, even for an empty pattern.
+    let first = parts.next().unwrap();
+    let mut rest = match name.strip_prefix(first) {
+        None => return false,
+        Some(rest) => rest,
+    };
+    let parts: Vec<&str> = parts.collect();
+    let (last, middle) = match parts.split_last() {
+        // No wildcards at all, so the whole name must have matched.
+        None => return rest.is_empty(),
+        Some(split) => split,
+    };
+    for part in middle {
+        match rest.find(part) {
+            None => return false,
+            Some(idx) => rest = &rest[idx + part.len()..],
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// Replace the (non-null) value of every field whose name matches any of the
+/// given patterns with `REDACTED_PLACEHOLDER`, recursively.
+fn redact_fields<S: AsRef<str>>(value: &mut Value, patterns: &[S]) {
+    match value {
+        Value::Object(map) => {
+            for (name, field) in map.iter_mut() {
+                if !field.is_null()
+                    && patterns
+                        .iter()
+                        .any(|p| field_name_matches(p.as_ref(), name))
+                {
+                    *field = Value::String(REDACTED_PLACEHOLDER.to_string());
+                } else {
+                    redact_fields(field, patterns);
+                }
+            }
+        }
+        Value::Array(values) => {
+            for v in values.iter_mut() {
+                redact_fields(v, patterns);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Serialize the given value, replacing every `Redacted` value, and the value
+/// of every field whose name matches one of the given patterns (see
+/// `field_name_matches`), with `REDACTED_PLACEHOLDER`.
+pub fn to_redacted_value<T: Serialize, S: AsRef<str>>(value: &T, patterns: &[S]) -> Result<Value> {
+    let mut value = {
+        let _guard = RedactingGuard::new();
+        serde_json::to_value(value)?
+    };
+    redact_fields(&mut value, patterns);
+    Ok(value)
+}
@@ -0,0 +1,176 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::configuration::{
+    find_field, instance_apply, lock, Configuration, Identifier, SINGLETONS,
+};
+use crate::error::*;
+use once_cell::sync::Lazy;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::{Condvar, Mutex};
+use std::thread::{self, ThreadId};
+
+/// Identifiers which currently have an active fixture, and which thread owns
+/// each one. Fixtures for the same identifier from different threads (e.g.
+/// tests running in parallel) wait for each other.
+static ACTIVE_FIXTURES: Lazy<(Mutex<HashMap<Identifier, ThreadId>>, Condvar)> =
+    Lazy::new(|| (Mutex::new(HashMap::new()), Condvar::new()));
+
+fn acquire(id: &Identifier) -> Result<()> {
+    let (mutex, condvar) = &*ACTIVE_FIXTURES;
+    let mut active = lock(mutex);
+    loop {
+        match active.get(id) {
+            None => break,
+            Some(owner) if *owner == thread::current().id() => {
+                return Err(Error::Precondition(format!(
+                    "configuration {:?} already has an active fixture on this thread",
+                    id
+                )));
+            }
+            Some(_) => {
+                active = match condvar.wait(active) {
+                    Ok(guard) => guard,
+                    Err(poisoned) => poisoned.into_inner(),
+                };
+            }
+        }
+    }
+    active.insert(id.clone(), thread::current().id());
+    Ok(())
+}
+
+fn release(id: &Identifier) {
+    let (mutex, condvar) = &*ACTIVE_FIXTURES;
+    lock(mutex).remove(id);
+    condvar.notify_all();
+}
+
+/// A FixtureGuard temporarily replaces a configuration singleton with an
+/// in-memory one (see `Configuration::in_memory`). When the guard is dropped
+/// (including while unwinding from a panic), whatever was registered before
+/// is restored; if nothing was registered, the fixture is just removed.
+///
+/// Only one fixture per identifier can be active at a time. Fixtures for the
+/// same identifier created on different threads wait for each other, so tests
+/// running in parallel are serialized. Creating a second fixture for the same
+/// identifier on the same thread (i.e., nesting them) is an error, since
+/// waiting would deadlock.
+#[must_use]
+pub struct FixtureGuard {
+    id: Identifier,
+    previous: Option<Box<dyn Any + Send>>,
+}
+
+impl Drop for FixtureGuard {
+    fn drop(&mut self) {
+        {
+            let mut singletons = lock(&SINGLETONS);
+            match self.previous.take() {
+                Some(previous) => singletons.insert(self.id.clone(), previous),
+                None => singletons.remove(&self.id),
+            };
+        }
+        release(&self.id);
+    }
+}
+
+/// Replace the configuration singleton with the given identifier with an
+/// in-memory one holding the given values, until the returned guard is
+/// dropped. The singleton doesn't need to have been registered (with
+/// `configuration::new`) beforehand.
+pub fn fixture<T: Clone + Serialize + DeserializeOwned + Send + 'static>(
+    id: &Identifier,
+    value: T,
+) -> Result<FixtureGuard> {
+    acquire(id)?;
+    Ok(install(id, value))
+}
+
+/// Install the given fixture, once it has been `acquire`d.
+fn install<T: Clone + Serialize + DeserializeOwned + Send + 'static>(
+    id: &Identifier,
+    value: T,
+) -> FixtureGuard {
+    let previous = lock(&SINGLETONS).insert(id.clone(), Box::new(Configuration::in_memory(value)));
+    FixtureGuard {
+        id: id.clone(),
+        previous,
+    }
+}
+
+/// Call the given function with the configuration singleton with the given
+/// identifier temporarily replaced with the given values (see `fixture`). The
+/// previous singleton is restored afterwards, even if the function panics.
+pub fn with_fixture<
+    T: Clone + Serialize + DeserializeOwned + Send + 'static,
+    R,
+    F: FnOnce() -> R,
+>(
+    id: &Identifier,
+    value: T,
+    f: F,
+) -> Result<R> {
+    let _guard = fixture(id, value)?;
+    Ok(f())
+}
+
+/// Like `fixture`, but the fixture's values are the registered singleton's
+/// *default* values (ignoring whatever may have been loaded from disk), with
+/// just the field at the given dot-separated path (e.g. "server.port")
+/// replaced with the given value. This makes partial fixtures convenient.
+///
+/// It is an error if the singleton isn't registered, or if the path doesn't
+/// identify an existing field. Field names are matched case-insensitively,
+/// like environment overrides (see `EnvOverrides`).
+pub fn override_field<T: Clone + Serialize + DeserializeOwned + Send + 'static>(
+    id: &Identifier,
+    path: &str,
+    value: Value,
+) -> Result<FixtureGuard> {
+    // Acquire first, so we don't read the defaults from another thread's
+    // fixture.
+    acquire(id)?;
+    match overridden_defaults::<T>(id, path, value) {
+        Ok(values) => Ok(install(id, values)),
+        Err(e) => {
+            release(id);
+            Err(e)
+        }
+    }
+}
+
+fn overridden_defaults<T: Clone + Serialize + DeserializeOwned + 'static>(
+    id: &Identifier,
+    path: &str,
+    value: Value,
+) -> Result<T> {
+    let mut values = serde_json::to_value(instance_apply::<T, _, _>(id, |config| {
+        config.default.clone()
+    })?)?;
+    let segments: Vec<&str> = path.split('.').collect();
+    match find_field(&mut values, &segments) {
+        None => {
+            return Err(Error::InvalidArgument(format!(
+                "configuration {:?} has no field '{}'",
+                id, path
+            )))
+        }
+        Some(field) => *field = value,
+    }
+    Ok(serde_json::from_value(values)?)
+}
@@ -0,0 +1,246 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::crypto::digest::Digest;
+use crate::crypto::key::{AbstractKey, Nonce};
+use crate::crypto::secret::Secret;
+use crate::error::*;
+use halite_sys;
+use libc::c_ulonglong;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Curve25519 public keys are 32 bytes long.
+pub const PUBLIC_KEY_BYTES: usize = halite_sys::crypto_box_PUBLICKEYBYTES as usize;
+/// Curve25519 secret keys are 32 bytes long.
+pub const SECRET_KEY_BYTES: usize = halite_sys::crypto_box_SECRETKEYBYTES as usize;
+/// A sealed box is this many bytes longer than its plaintext (an ephemeral
+/// public key, plus an authenticator tag).
+pub const SEAL_BYTES: usize = halite_sys::crypto_box_SEALBYTES as usize;
+
+/// A PublicKey is the public half of a `KeyPair`. It can be shared freely, and
+/// anyone who has it can `seal` data which only the holder of the matching
+/// secret key can `open`.
+#[derive(Clone, Deserialize, Eq, PartialEq, Serialize)]
+pub struct PublicKey {
+    // NOTE: This is a proper structure instead of a simple tuple structure, so
+    // the serialization format can be extended later if needed.
+    public_key: [u8; PUBLIC_KEY_BYTES],
+}
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PublicKey({})", self.fingerprint())
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.fingerprint())
+    }
+}
+
+impl PublicKey {
+    /// Construct a PublicKey from a properly sized byte slice.
+    pub fn from_slice(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != PUBLIC_KEY_BYTES {
+            return Err(Error::InvalidArgument(format!(
+                "invalid PublicKey data; expected {} bytes, got {}",
+                PUBLIC_KEY_BYTES,
+                bytes.len()
+            )));
+        }
+        let mut public_key = [0; PUBLIC_KEY_BYTES];
+        public_key.copy_from_slice(bytes);
+        Ok(PublicKey { public_key })
+    }
+
+    /// Access the raw bytes which make up this PublicKey.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    /// Return a digest which identifies this public key (and therefore its
+    /// `KeyPair`).
+    pub fn get_digest(&self) -> Digest {
+        Digest::from_bytes(&self.public_key)
+    }
+
+    /// Return a short, human-readable fingerprint of this public key, in the
+    /// same format as `Digest::fingerprint`.
+    pub fn fingerprint(&self) -> String {
+        self.get_digest().fingerprint()
+    }
+}
+
+/// A KeyPair is a Curve25519 key pair, which can be used to `open` data
+/// which was `seal`-ed with its public half.
+///
+/// KeyPair implements `AbstractKey`, so it can be used as a KeyStore wrapping
+/// key: encrypting seals to its own public key, and decrypting opens with its
+/// secret key. Since sealing only needs the public key, this is mostly useful
+/// for "recovery key" style flows, where the secret key is kept offline (e.g.
+/// printed out) until it's needed.
+pub struct KeyPair {
+    public_key: PublicKey,
+    secret_key: Secret,
+}
+
+// Implement by hand, so the secret key is never printed.
+impl fmt::Debug for KeyPair {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("KeyPair")
+            .field("public_key", &self.public_key)
+            .field("secret_key", &"<redacted>")
+            .finish()
+    }
+}
+
+impl KeyPair {
+    /// Generate a new random key pair.
+    pub fn generate() -> Result<Self> {
+        let mut public_key = [0; PUBLIC_KEY_BYTES];
+        let secret_key = Secret::with_len(SECRET_KEY_BYTES)?;
+        debug_assert!(crate::init_done());
+        if unsafe {
+            halite_sys::crypto_box_keypair(public_key.as_mut_ptr(), secret_key.slice_ptr())
+        } != 0
+        {
+            return Err(Error::Crypto("generating key pair failed".to_string()));
+        }
+        Ok(KeyPair {
+            public_key: PublicKey { public_key },
+            secret_key,
+        })
+    }
+
+    /// Reconstruct a key pair from its secret key (e.g., one previously
+    /// returned by `serialize`). The public key is recomputed from it.
+    pub fn from_secret_key(secret_key: Secret) -> Result<Self> {
+        if secret_key.len() != SECRET_KEY_BYTES {
+            return Err(Error::InvalidArgument(format!(
+                "invalid KeyPair secret key; expected {} bytes, got {}",
+                SECRET_KEY_BYTES,
+                secret_key.len()
+            )));
+        }
+        let mut public_key = [0; PUBLIC_KEY_BYTES];
+        debug_assert!(crate::init_done());
+        if unsafe {
+            halite_sys::crypto_scalarmult_base(public_key.as_mut_ptr(), secret_key.slice_ptr())
+        } != 0
+        {
+            return Err(Error::Crypto(
+                "computing public key from secret key failed".to_string(),
+            ));
+        }
+        Ok(KeyPair {
+            public_key: PublicKey { public_key },
+            secret_key,
+        })
+    }
+
+    /// Return this key pair's public half.
+    pub fn public_key(&self) -> &PublicKey {
+        &self.public_key
+    }
+}
+
+/// Encrypt the given plaintext such that only the holder of the secret key
+/// matching `recipient` can decrypt it. The sender doesn't need (and can't
+/// have) any secret shared with the recipient, but conversely the recipient
+/// can't tell who the sender was.
+pub fn seal(recipient: &PublicKey, plaintext: &[u8]) -> Result<Vec<u8>> {
+    let mut ciphertext = vec![0; plaintext.len() + SEAL_BYTES];
+    debug_assert!(crate::init_done());
+    if unsafe {
+        halite_sys::crypto_box_seal(
+            ciphertext.as_mut_ptr(),
+            plaintext.as_ptr(),
+            plaintext.len() as c_ulonglong,
+            recipient.public_key.as_ptr(),
+        )
+    } != 0
+    {
+        return Err(Error::Crypto("sealing failed".to_string()));
+    }
+    Ok(ciphertext)
+}
+
+fn open_secret(keypair: &KeyPair, ciphertext: &[u8]) -> Result<Secret> {
+    if ciphertext.len() < SEAL_BYTES {
+        return Err(Error::InvalidArgument(
+            "can't open sealed box which is too short".to_string(),
+        ));
+    }
+
+    let plaintext = Secret::with_len(ciphertext.len() - SEAL_BYTES)?;
+    debug_assert!(crate::init_done());
+    if unsafe {
+        halite_sys::crypto_box_seal_open(
+            plaintext.slice_ptr(),
+            ciphertext.as_ptr(),
+            ciphertext.len() as c_ulonglong,
+            keypair.public_key.public_key.as_ptr(),
+            keypair.secret_key.slice_ptr(),
+        )
+    } == 0
+    {
+        Ok(plaintext)
+    } else {
+        Err(Error::InvalidArgument(
+            "failed to open sealed box with incorrect KeyPair".to_string(),
+        ))
+    }
+}
+
+/// Decrypt ciphertext previously produced by `seal`, using the key pair whose
+/// public half it was sealed to.
+pub fn open(keypair: &KeyPair, ciphertext: &[u8]) -> Result<Vec<u8>> {
+    Ok(unsafe { open_secret(keypair, ciphertext)?.as_slice() }.to_vec())
+}
+
+impl AbstractKey for KeyPair {
+    type Error = Error;
+
+    fn get_digest(&self) -> Digest {
+        self.public_key.get_digest()
+    }
+
+    /// Serialize this key pair's secret key. The public key is not included,
+    /// since it can be recomputed from the secret key.
+    fn serialize(&self) -> Result<Secret> {
+        self.secret_key.try_clone()
+    }
+
+    fn deserialize(data: Secret) -> Result<Self> {
+        Self::from_secret_key(data)
+    }
+
+    /// Sealed boxes don't take a nonce, so any given nonce is ignored (but, per
+    /// `AbstractKey`'s contract, it is returned as-is).
+    fn encrypt(
+        &self,
+        plaintext: &Secret,
+        nonce: Option<Nonce>,
+    ) -> Result<(Option<Nonce>, Vec<u8>)> {
+        Ok((
+            nonce,
+            seal(&self.public_key, unsafe { plaintext.as_slice() })?,
+        ))
+    }
+
+    fn decrypt(&self, _: Option<&Nonce>, ciphertext: &[u8]) -> Result<Secret> {
+        open_secret(self, ciphertext)
+    }
+}
@@ -0,0 +1,309 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::crypto::digest::Digest;
+use crate::crypto::key::Key;
+use crate::error::*;
+use halite_sys;
+use libc::c_ulonglong;
+use rmp_serde;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The libsodium KDF context used to derive the audit log's MAC key from a
+/// KeyStore's master key.
+const AUDIT_KDF_CONTEXT: &[u8; 8] = b"bdrckaud";
+const AUDIT_KDF_SUBKEY_ID: u64 = 1;
+const MAC_BYTES: usize = halite_sys::crypto_auth_BYTES as usize;
+
+/// AuditOperation identifies which KeyStore operation an `AuditEntry`
+/// records.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum AuditOperation {
+    /// An attempt to open the KeyStore with a wrapping key.
+    Open,
+    /// An attempt to add a wrapping key.
+    AddKey,
+    /// An attempt to remove a wrapping key.
+    RemoveKey,
+    /// An attempt to replace one wrapping key with another. The entry records
+    /// the key being replaced.
+    ReplaceKey,
+}
+
+/// AuditOutcome describes how a recorded operation turned out.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum AuditOutcome {
+    /// The operation succeeded.
+    Success,
+    /// The operation succeeded, but didn't change anything (e.g. the key
+    /// being added was already present).
+    Unchanged,
+    /// The operation failed, with the given error message.
+    Failure(String),
+}
+
+/// AuditEntry is a single record in an `AuditLog`.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct AuditEntry {
+    /// When the operation happened, in seconds since the UNIX epoch.
+    pub timestamp: u64,
+    /// Which operation was performed.
+    pub operation: AuditOperation,
+    /// A fingerprint (the first few bytes, hex encoded) of the digest of the
+    /// wrapping key the operation was performed with.
+    pub wrapping_key: String,
+    /// How the operation turned out.
+    pub outcome: AuditOutcome,
+    /// The digest of the previous entry's serialized bytes (empty for the
+    /// first entry), chaining the entries together.
+    previous: Vec<u8>,
+    /// A MAC over all of the above, keyed with a key derived from the
+    /// KeyStore's master key. Entries written while the KeyStore isn't open
+    /// (e.g. failed attempts to open it) have no MAC.
+    mac: Option<Vec<u8>>,
+}
+
+impl AuditEntry {
+    /// Return whether or not this entry was authenticated (MACed) when it was
+    /// written.
+    pub fn is_authenticated(&self) -> bool {
+        self.mac.is_some()
+    }
+
+    fn compute_mac(&self, master_key: &Key) -> Result<Vec<u8>> {
+        let mac_key = master_key.derive_subkey_raw(AUDIT_KDF_CONTEXT, AUDIT_KDF_SUBKEY_ID)?;
+        let data = rmp_serde::to_vec(&(
+            self.timestamp,
+            &self.operation,
+            &self.wrapping_key,
+            &self.outcome,
+            &self.previous,
+        ))?;
+        let mut mac = vec![0; MAC_BYTES];
+        if unsafe {
+            halite_sys::crypto_auth(
+                mac.as_mut_ptr(),
+                data.as_ptr(),
+                data.len() as c_ulonglong,
+                mac_key.as_secret().slice_ptr(),
+            )
+        } != 0
+        {
+            return Err(Error::Crypto("computing audit log MAC failed".to_string()));
+        }
+        Ok(mac)
+    }
+
+    fn verify_mac(&self, master_key: &Key) -> Result<bool> {
+        let expected = self.compute_mac(master_key)?;
+        Ok(match self.mac.as_ref() {
+            None => false,
+            Some(mac) => {
+                mac.len() == expected.len()
+                    && unsafe {
+                        halite_sys::sodium_memcmp(
+                            mac.as_ptr() as *const libc::c_void,
+                            expected.as_ptr() as *const libc::c_void,
+                            MAC_BYTES,
+                        )
+                    } == 0
+            }
+        })
+    }
+}
+
+/// AuditReport is the result of verifying an `AuditLog`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct AuditReport {
+    /// Every entry in the log, in order.
+    pub entries: Vec<AuditEntry>,
+    /// The indices of entries which don't follow the entry before them (e.g.
+    /// because an entry was deleted or reordered).
+    pub breaks: Vec<usize>,
+    /// The indices of entries which weren't authenticated when they were
+    /// written (e.g. failed attempts to open the KeyStore).
+    pub unauthenticated: Vec<usize>,
+    /// The indices of entries whose MAC is invalid (i.e., which have been
+    /// modified since they were written).
+    pub invalid_macs: Vec<usize>,
+    /// Whether or not the entries' MACs were checked at all; this requires the
+    /// KeyStore to be open.
+    pub macs_verified: bool,
+}
+
+impl AuditReport {
+    /// Return whether or not the log is intact: the chain is unbroken, and no
+    /// MAC is invalid. Unauthenticated entries are expected, so they don't
+    /// count against this.
+    pub fn is_intact(&self) -> bool {
+        self.breaks.is_empty() && self.invalid_macs.is_empty()
+    }
+}
+
+/// AuditLog is an append-only journal of operations performed on a KeyStore
+/// (see `ManagedKeyStore::with_audit_log`). Each entry includes a digest of
+/// the entry before it, so deleting or reordering entries is detectable, and
+/// entries written while the KeyStore is open are MACed with a key derived
+/// from its master key, so they can't be forged or modified.
+///
+/// Note that removing entries from the *end* of the log can't be detected
+/// from the log alone.
+///
+/// On disk, the log is a sequence of entries, each of which is a big-endian
+/// u32 length followed by that many bytes of MessagePack.
+#[derive(Clone, Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditLog {
+    /// Construct an AuditLog which is stored in the file at the given path.
+    /// The file is created when the first entry is appended.
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        AuditLog {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Construct an AuditLog stored next to the KeyStore file at the given
+    /// path (with ".audit" appended to its name).
+    pub fn next_to<P: AsRef<Path>>(keystore_path: P) -> Self {
+        let mut path = keystore_path.as_ref().as_os_str().to_os_string();
+        path.push(".audit");
+        AuditLog {
+            path: PathBuf::from(path),
+        }
+    }
+
+    /// Return the path of the file this log is stored in.
+    pub fn path(&self) -> &Path {
+        self.path.as_path()
+    }
+
+    fn read_records(&self) -> Result<Vec<Vec<u8>>> {
+        let data = match fs::read(self.path.as_path()) {
+            Ok(data) => data,
+            Err(e) => match e.kind() {
+                std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+                _ => return Err(e.into()),
+            },
+        };
+
+        let mut records = Vec::new();
+        let mut remaining = data.as_slice();
+        while !remaining.is_empty() {
+            if remaining.len() < 4 {
+                return Err(Error::InvalidArgument(format!(
+                    "audit log '{}' is truncated",
+                    self.path.display()
+                )));
+            }
+            let (len, rest) = remaining.split_at(4);
+            let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+            if rest.len() < len {
+                return Err(Error::InvalidArgument(format!(
+                    "audit log '{}' is truncated",
+                    self.path.display()
+                )));
+            }
+            let (record, rest) = rest.split_at(len);
+            records.push(record.to_vec());
+            remaining = rest;
+        }
+        Ok(records)
+    }
+
+    /// Return every entry in this log, in order, without verifying them.
+    pub fn entries(&self) -> Result<Vec<AuditEntry>> {
+        self.read_records()?
+            .iter()
+            .map(|r| Ok(rmp_serde::from_slice(r.as_slice())?))
+            .collect()
+    }
+
+    /// Append an entry recording the given operation. If the master key is
+    /// given, the entry is authenticated with it.
+    pub(crate) fn append(
+        &self,
+        operation: AuditOperation,
+        wrapping_digest: &Digest,
+        outcome: AuditOutcome,
+        master_key: Option<&Key>,
+    ) -> Result<()> {
+        let previous = match self.read_records()?.last() {
+            None => Vec::new(),
+            Some(record) => Digest::from_bytes(record.as_slice()).as_slice().to_vec(),
+        };
+        let mut entry = AuditEntry {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            operation,
+            wrapping_key: wrapping_digest.fingerprint(),
+            outcome,
+            previous,
+            mac: None,
+        };
+        if let Some(master_key) = master_key {
+            entry.mac = Some(entry.compute_mac(master_key)?);
+        }
+
+        let record = rmp_serde::to_vec(&entry)?;
+        let mut data = Vec::with_capacity(record.len() + 4);
+        data.extend_from_slice(&(record.len() as u32).to_be_bytes());
+        data.extend_from_slice(record.as_slice());
+
+        let mut options = fs::OpenOptions::new();
+        options.append(true).create(true);
+        #[cfg(not(target_os = "windows"))]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut f = options.open(self.path.as_path())?;
+        f.write_all(data.as_slice())?;
+        f.sync_data()?;
+        Ok(())
+    }
+
+    /// Walk this log's chain of entries, reporting any breaks in it. If the
+    /// master key is given, the entries' MACs are verified as well.
+    pub(crate) fn verify(&self, master_key: Option<&Key>) -> Result<AuditReport> {
+        let mut report = AuditReport {
+            macs_verified: master_key.is_some(),
+            ..Default::default()
+        };
+        let mut previous = Vec::new();
+        for (idx, record) in self.read_records()?.iter().enumerate() {
+            let entry: AuditEntry = rmp_serde::from_slice(record.as_slice())?;
+            if entry.previous != previous {
+                report.breaks.push(idx);
+            }
+            if !entry.is_authenticated() {
+                report.unauthenticated.push(idx);
+            } else if let Some(master_key) = master_key {
+                if !entry.verify_mac(master_key)? {
+                    report.invalid_macs.push(idx);
+                }
+            }
+            previous = Digest::from_bytes(record.as_slice()).as_slice().to_vec();
+            report.entries.push(entry);
+        }
+        Ok(report)
+    }
+}
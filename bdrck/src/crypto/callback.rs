@@ -0,0 +1,127 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::crypto::digest::Digest;
+use crate::crypto::key::{AbstractKey, Key, Nonce, KEY_BYTES};
+use crate::crypto::secret::Secret;
+use crate::crypto::util::randombytes_into;
+use crate::error::*;
+use halite_sys;
+use libc::{c_ulonglong, c_void};
+
+/// The length of the random challenge we pass to the derive callback for each
+/// encryption.
+pub const CHALLENGE_BYTES: usize = 32;
+
+/// The type of callback a `CallbackKey` uses to derive key material. Given a
+/// challenge, the callback must deterministically return the same response
+/// every time it is called with that same challenge.
+pub type DeriveCallback = Box<dyn Fn(&[u8]) -> Result<Vec<u8>> + Send + Sync>;
+
+/// A CallbackKey is an `AbstractKey` whose key material never enters this
+/// process. Instead, it is backed by a callback which deterministically derives
+/// a response from a challenge, e.g. a hardware token's HMAC operation (as in
+/// FIDO2's hmac-secret extension).
+///
+/// To encrypt, a random challenge is generated, and the callback's response to
+/// it is used to derive an ordinary `Key`. The challenge is stored alongside
+/// the ciphertext, so decryption can derive the same `Key` again.
+///
+/// Because the key material isn't available, CallbackKey only supports
+/// `get_digest`, `encrypt`, and `decrypt`. `serialize` and `deserialize`
+/// return `Error::Unsupported`. This is sufficient to use it as a wrapping key
+/// with e.g. `KeyStore`.
+pub struct CallbackKey {
+    digest: Digest,
+    derive: DeriveCallback,
+}
+
+impl CallbackKey {
+    /// Construct a new CallbackKey. The given digest must stably identify the
+    /// underlying key (e.g., it could be a digest of a hardware token's
+    /// credential ID), since computing it must not require calling `derive`.
+    pub fn new(digest: Digest, derive: DeriveCallback) -> Self {
+        CallbackKey { digest, derive }
+    }
+
+    fn derive_key(&self, challenge: &[u8]) -> Result<Key> {
+        let mut response = (self.derive)(challenge)?;
+        let key_data = Secret::with_len(KEY_BYTES)?;
+        debug_assert!(crate::init_done());
+        let ret = unsafe {
+            halite_sys::crypto_generichash(
+                key_data.slice_ptr(),
+                KEY_BYTES,
+                response.as_ptr(),
+                response.len() as c_ulonglong,
+                std::ptr::null(),
+                0,
+            )
+        };
+        unsafe {
+            halite_sys::sodium_memzero(response.as_mut_ptr() as *mut c_void, response.len());
+        }
+        if ret != 0 {
+            return Err(Error::Crypto(
+                "deriving key from callback response failed".to_string(),
+            ));
+        }
+        Key::from_raw(key_data)
+    }
+}
+
+impl AbstractKey for CallbackKey {
+    type Error = Error;
+
+    fn get_digest(&self) -> Digest {
+        self.digest.clone()
+    }
+
+    fn serialize(&self) -> Result<Secret> {
+        Err(Error::Unsupported(
+            "CallbackKey key material can't be serialized".to_string(),
+        ))
+    }
+
+    fn deserialize(_: Secret) -> Result<Self> {
+        Err(Error::Unsupported(
+            "CallbackKey can't be deserialized".to_string(),
+        ))
+    }
+
+    fn encrypt(
+        &self,
+        plaintext: &Secret,
+        nonce: Option<Nonce>,
+    ) -> Result<(Option<Nonce>, Vec<u8>)> {
+        let mut challenge = [0_u8; CHALLENGE_BYTES];
+        randombytes_into(&mut challenge);
+        let (nonce, ciphertext) = self.derive_key(&challenge)?.encrypt(plaintext, nonce)?;
+
+        let mut ret = Vec::with_capacity(CHALLENGE_BYTES + ciphertext.len());
+        ret.extend_from_slice(&challenge);
+        ret.extend(ciphertext);
+        Ok((nonce, ret))
+    }
+
+    fn decrypt(&self, nonce: Option<&Nonce>, ciphertext: &[u8]) -> Result<Secret> {
+        if ciphertext.len() < CHALLENGE_BYTES {
+            return Err(Error::InvalidArgument(
+                "can't decrypt ciphertext which is missing a challenge".to_string(),
+            ));
+        }
+        let (challenge, ciphertext) = ciphertext.split_at(CHALLENGE_BYTES);
+        self.derive_key(challenge)?.decrypt(nonce, ciphertext)
+    }
+}
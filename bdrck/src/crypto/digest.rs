@@ -18,13 +18,24 @@ use crate::crypto::util::randombytes_into;
 use crate::error::*;
 use halite_sys;
 use libc::{c_char, c_ulonglong};
+#[cfg(feature = "testing")]
+use rand::RngCore;
 use serde::de::{SeqAccess, Visitor};
 use serde::ser::SerializeSeq;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::BTreeMap;
 use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Mutex};
+use std::thread;
 
 /// This module uses sha512, which produces 64 byte digests.
 pub const DIGEST_BYTES: usize = halite_sys::crypto_hash_sha512_BYTES as usize;
+/// How many bytes of a digest are included in its fingerprint (see
+/// `Digest::fingerprint`).
+pub const FINGERPRINT_BYTES: usize = 8;
 /// scryptsalsa208sha256 uses 32 byte salts.
 pub const SALT_BYTES: usize = halite_sys::crypto_pwhash_scryptsalsa208sha256_SALTBYTES as usize;
 
@@ -117,6 +128,82 @@ impl Digest {
     pub fn from_secret(secret: &Secret) -> Self {
         Self::from_bytes(unsafe { secret.as_slice() })
     }
+
+    /// Construct a Digest from its raw bytes, e.g. as previously returned by
+    /// `as_slice`.
+    pub fn from_slice(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != DIGEST_BYTES {
+            return Err(Error::InvalidArgument(format!(
+                "expected a {}-byte digest, got {} bytes",
+                DIGEST_BYTES,
+                bytes.len()
+            )));
+        }
+        let mut digest = Digest([0; DIGEST_BYTES]);
+        digest.0.copy_from_slice(bytes);
+        Ok(digest)
+    }
+
+    /// Return the raw bytes of this digest.
+    pub fn as_slice(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+
+    /// Return a short, human-readable fingerprint of this digest (the first
+    /// `FINGERPRINT_BYTES` bytes, hex encoded). This is convenient for e.g.
+    /// displaying which key was used, but it's too short to rely on for
+    /// anything security-sensitive.
+    pub fn fingerprint(&self) -> String {
+        data_encoding::HEXLOWER.encode(&self.0[..FINGERPRINT_BYTES])
+    }
+}
+
+/// DigestBuilder computes a Digest incrementally, e.g. for data which is
+/// streamed rather than held in memory all at once. The result is identical
+/// to calling `Digest::from_bytes` on all of the data concatenated together.
+#[derive(Clone)]
+pub struct DigestBuilder {
+    state: halite_sys::crypto_hash_sha512_state,
+}
+
+impl Default for DigestBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DigestBuilder {
+    /// Start computing a new Digest.
+    pub fn new() -> Self {
+        debug_assert!(crate::init_done());
+        let mut state = std::mem::MaybeUninit::uninit();
+        unsafe {
+            halite_sys::crypto_hash_sha512_init(state.as_mut_ptr());
+        }
+        DigestBuilder {
+            state: unsafe { state.assume_init() },
+        }
+    }
+
+    /// Add the given chunk of data to the Digest being computed.
+    pub fn update(&mut self, data: &[u8]) {
+        unsafe {
+            halite_sys::crypto_hash_sha512_update(
+                &mut self.state,
+                data.as_ptr(),
+                data.len() as c_ulonglong,
+            );
+        }
+    }
+
+    /// Return the Digest of all of the data passed to `update` so far.
+    pub fn finish(mut self) -> Digest {
+        let mut digest = Digest([0; DIGEST_BYTES]);
+        unsafe {
+            halite_sys::crypto_hash_sha512_final(&mut self.state, digest.0.as_mut_ptr());
+        }
+        digest
+    }
 }
 
 /// A salt is an arbitrary byte sequence which is used for password-based key
@@ -132,6 +219,19 @@ impl Default for Salt {
     }
 }
 
+impl Salt {
+    /// Return a new salt filled with bytes from the given RNG, instead of the
+    /// OS's secure RNG. This is intended for generating reproducible test
+    /// fixtures (e.g. with `testing::crypto::DeterministicRng`), and should not
+    /// be used otherwise.
+    #[cfg(feature = "testing")]
+    pub fn from_rng<R: RngCore>(rng: &mut R) -> Self {
+        let mut s = Salt(compat::Salt::default());
+        rng.fill_bytes(&mut s.0 .0);
+        s
+    }
+}
+
 /// Hash the given password using the given salt and ops limits, placing the result in the given
 /// buffer. Note that the length of "out" is mostly arbitrary.
 ///
@@ -172,3 +272,440 @@ pub fn derive_key(
         )))
     }
 }
+
+/// The size of the buffer used to read files when computing their digests.
+const FILE_READ_BUFFER_BYTES: usize = 64 * 1024;
+
+/// The maximum number of threads `digest_tree_parallel` uses by default.
+/// Hashing large trees tends to be I/O bound, so more threads than this rarely
+/// help.
+pub const DEFAULT_MAX_TREE_DIGEST_THREADS: usize = 8;
+
+/// Compute the digest of the contents of the file at the given path. The file
+/// is streamed, rather than being read into memory all at once.
+pub fn digest_file<P: AsRef<Path>>(path: P) -> Result<Digest> {
+    let mut file = File::open(path)?;
+    let mut builder = DigestBuilder::new();
+    let mut buf = vec![0; FILE_READ_BUFFER_BYTES];
+    loop {
+        match file.read(buf.as_mut_slice()) {
+            Ok(0) => break,
+            Ok(n) => builder.update(&buf[..n]),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(builder.finish())
+}
+
+/// A TreeManifest lists the digest of every file in a directory tree, keyed by
+/// each file's path relative to the root of the tree. Files (or directories)
+/// which couldn't be read are listed separately, along with the reason why.
+///
+/// Only regular files (and symlinks, whose targets are hashed) are included;
+/// directories are recursed into, and anything else (sockets, FIFOs, device
+/// nodes) is skipped.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct TreeManifest {
+    files: BTreeMap<PathBuf, Digest>,
+    errors: BTreeMap<PathBuf, String>,
+}
+
+impl TreeManifest {
+    /// Return the digest of each file in the tree, sorted by path.
+    pub fn files(&self) -> &BTreeMap<PathBuf, Digest> {
+        &self.files
+    }
+
+    /// Return the paths (relative to the root of the tree) which couldn't be
+    /// hashed, along with a description of the error. If this isn't empty,
+    /// the manifest (and therefore its `digest`) is incomplete.
+    pub fn errors(&self) -> &BTreeMap<PathBuf, String> {
+        &self.errors
+    }
+
+    fn record(&mut self, relative: PathBuf, result: Result<Digest>) {
+        match result {
+            Ok(digest) => {
+                self.files.insert(relative, digest);
+            }
+            Err(e) => {
+                self.errors.insert(relative, e.to_string());
+            }
+        }
+    }
+
+    /// Compute a single digest which identifies the entire tree, covering
+    /// both the path and the contents of every file. Two manifests have the
+    /// same digest if and only if they list the same files with the same
+    /// contents (errors are not included).
+    pub fn digest(&self) -> Digest {
+        let mut builder = DigestBuilder::new();
+        for (path, digest) in self.files.iter() {
+            let path = path.as_os_str().as_encoded_bytes();
+            builder.update(&(path.len() as u64).to_le_bytes());
+            builder.update(path);
+            builder.update(digest.as_slice());
+        }
+        builder.finish()
+    }
+}
+
+fn check_tree_root(root: &Path) -> Result<()> {
+    if !fs::metadata(root)?.is_dir() {
+        return Err(Error::InvalidArgument(format!(
+            "'{}' is not a directory",
+            root.display()
+        )));
+    }
+    Ok(())
+}
+
+/// Walk the tree rooted at `root`, calling `visit` with the path of each file
+/// to hash (relative to `root`). Errors listing directories are recorded in
+/// `errors`.
+fn walk_tree<F: FnMut(PathBuf)>(
+    root: &Path,
+    relative: &Path,
+    errors: &mut BTreeMap<PathBuf, String>,
+    visit: &mut F,
+) {
+    let entries = match fs::read_dir(root.join(relative)) {
+        Ok(entries) => entries,
+        Err(e) => {
+            errors.insert(relative.to_path_buf(), e.to_string());
+            return;
+        }
+    };
+    for entry in entries {
+        let (path, file_type) = match entry.and_then(|e| Ok((e.file_name(), e.file_type()?))) {
+            Ok((name, file_type)) => (relative.join(name), file_type),
+            Err(e) => {
+                errors.insert(relative.to_path_buf(), e.to_string());
+                continue;
+            }
+        };
+        if file_type.is_dir() {
+            walk_tree(root, &path, errors, visit);
+        } else if file_type.is_file() || file_type.is_symlink() {
+            visit(path);
+        }
+    }
+}
+
+/// Compute a manifest of the directory tree rooted at the given path, hashing
+/// one file at a time. See `digest_tree_parallel` for a faster alternative
+/// for large trees; the two always produce identical manifests.
+///
+/// Files which can't be hashed don't stop the rest of the tree from being
+/// hashed; they're listed in the manifest's `errors` instead. An error is only
+/// returned if `path` itself isn't a directory.
+pub fn digest_tree<P: AsRef<Path>>(path: P) -> Result<TreeManifest> {
+    let root = path.as_ref();
+    check_tree_root(root)?;
+    let mut manifest = TreeManifest::default();
+    let mut files = BTreeMap::new();
+    walk_tree(root, Path::new(""), &mut manifest.errors, &mut |relative| {
+        files.insert(relative.clone(), digest_file(root.join(&relative)));
+    });
+    for (relative, result) in files {
+        manifest.record(relative, result);
+    }
+    Ok(manifest)
+}
+
+/// Compute a manifest of the directory tree rooted at the given path, like
+/// `digest_tree`, but hash files on a pool of worker threads. By default (if
+/// `threads` is None), one thread per CPU is used, up to
+/// `DEFAULT_MAX_TREE_DIGEST_THREADS`.
+///
+/// The resulting manifest is identical to what `digest_tree` would produce,
+/// regardless of the order in which the workers finish.
+pub fn digest_tree_parallel<P: AsRef<Path>>(
+    path: P,
+    threads: Option<usize>,
+) -> Result<TreeManifest> {
+    digest_tree_parallel_with_progress(path, threads, |_, _| {})
+}
+
+/// This is identical to `digest_tree_parallel`, except `progress` is called
+/// each time a file has been hashed, with the number of files hashed so far
+/// and the number of files discovered so far (which increases while the tree
+/// is still being walked).
+pub fn digest_tree_parallel_with_progress<P: AsRef<Path>, F: FnMut(usize, usize)>(
+    path: P,
+    threads: Option<usize>,
+    mut progress: F,
+) -> Result<TreeManifest> {
+    let root = path.as_ref();
+    check_tree_root(root)?;
+    let threads = threads
+        .unwrap_or_else(|| {
+            thread::available_parallelism()
+                .map_or(1, |n| n.get())
+                .min(DEFAULT_MAX_TREE_DIGEST_THREADS)
+        })
+        .max(1);
+
+    let mut manifest = TreeManifest::default();
+    let (job_tx, job_rx) = mpsc::channel::<PathBuf>();
+    let job_rx = Mutex::new(job_rx);
+    let (result_tx, result_rx) = mpsc::channel();
+
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            let result_tx = result_tx.clone();
+            let job_rx = &job_rx;
+            scope.spawn(move || loop {
+                // Hold the lock only long enough to take the next job.
+                let relative = match job_rx.lock().unwrap().recv() {
+                    Ok(relative) => relative,
+                    Err(_) => return,
+                };
+                let result = digest_file(root.join(&relative));
+                if result_tx.send((relative, result)).is_err() {
+                    return;
+                }
+            });
+        }
+        drop(result_tx);
+
+        let mut discovered = 0;
+        let mut done = 0;
+        let mut walk_errors = BTreeMap::new();
+        walk_tree(root, Path::new(""), &mut walk_errors, &mut |relative| {
+            discovered += 1;
+            // The workers only stop once we drop `job_tx`, so this can't fail.
+            job_tx.send(relative).unwrap();
+            while let Ok((relative, result)) = result_rx.try_recv() {
+                manifest.record(relative, result);
+                done += 1;
+                progress(done, discovered);
+            }
+        });
+        drop(job_tx);
+
+        for (relative, result) in result_rx.iter() {
+            manifest.record(relative, result);
+            done += 1;
+            progress(done, discovered);
+        }
+        manifest.errors.extend(walk_errors);
+    });
+
+    Ok(manifest)
+}
+
+/// The default size of the chunks `MerkleTree` splits its input into.
+pub const DEFAULT_MERKLE_CHUNK_BYTES: usize = 1024 * 1024;
+
+// Leaves and interior nodes are hashed with different prefixes, so an interior
+// node can never be passed off as a leaf (or vice versa).
+const MERKLE_LEAF_PREFIX: u8 = 0x00;
+const MERKLE_INTERIOR_PREFIX: u8 = 0x01;
+
+fn merkle_parent(left: &Digest, right: &Digest) -> Digest {
+    let mut builder = DigestBuilder::new();
+    builder.update(&[MERKLE_INTERIOR_PREFIX]);
+    builder.update(left.as_slice());
+    builder.update(right.as_slice());
+    builder.finish()
+}
+
+/// Compute the next level up of a Merkle tree. If the level has an odd number
+/// of nodes, the last one is promoted to the next level unchanged.
+fn merkle_level(nodes: &[Digest]) -> Vec<Digest> {
+    nodes
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => merkle_parent(left, right),
+            [only] => only.clone(),
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+/// Fill as much of the given buffer as possible, stopping early only at EOF.
+/// Returns the number of bytes read.
+fn read_chunk<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(filled)
+}
+
+/// A MerkleTree is a tree of digests over fixed-size chunks of some data (e.g.
+/// a large file). Unlike a single digest of the whole file, it can be used to
+/// find out which chunks differ between two versions of the data, or to prove
+/// that a single chunk belongs to the data, given only the root digest.
+///
+/// Leaves are hashed as `H(0x00 || chunk)`, and interior nodes as
+/// `H(0x01 || left || right)`. When a level has an odd number of nodes, the
+/// last node is promoted to the next level unchanged (rather than being
+/// paired with itself). Empty input produces a tree with a single leaf, the
+/// digest of the empty chunk, so every tree has a well-defined root.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct MerkleTree {
+    chunk_size: usize,
+    /// Each level of the tree, starting with the leaves and ending with the
+    /// root.
+    levels: Vec<Vec<Digest>>,
+}
+
+impl MerkleTree {
+    /// Compute the leaf digest of the given chunk of data.
+    pub fn leaf_digest(chunk: &[u8]) -> Digest {
+        let mut builder = DigestBuilder::new();
+        builder.update(&[MERKLE_LEAF_PREFIX]);
+        builder.update(chunk);
+        builder.finish()
+    }
+
+    /// Build a tree over all of the data read from the given reader, split
+    /// into chunks of the given size (the last chunk may be shorter). See
+    /// `DEFAULT_MERKLE_CHUNK_BYTES` for a reasonable default.
+    pub fn from_reader<R: Read>(mut reader: R, chunk_size: usize) -> Result<Self> {
+        if chunk_size == 0 {
+            return Err(Error::InvalidArgument(
+                "Merkle tree chunk size must be nonzero".to_string(),
+            ));
+        }
+
+        let mut leaves = Vec::new();
+        let mut buf = vec![0; chunk_size];
+        loop {
+            let n = read_chunk(&mut reader, buf.as_mut_slice())?;
+            if n > 0 || leaves.is_empty() {
+                leaves.push(Self::leaf_digest(&buf[..n]));
+            }
+            if n < chunk_size {
+                break;
+            }
+        }
+
+        let mut levels = vec![leaves];
+        while levels.last().unwrap().len() > 1 {
+            let next = merkle_level(levels.last().unwrap());
+            levels.push(next);
+        }
+        Ok(MerkleTree { chunk_size, levels })
+    }
+
+    /// Return the size of the chunks this tree was built over.
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    /// Return the root digest, which identifies the entire input.
+    pub fn root(&self) -> Digest {
+        self.levels.last().unwrap()[0].clone()
+    }
+
+    /// Return the number of leaves (i.e., chunks) in this tree.
+    pub fn leaf_count(&self) -> usize {
+        self.levels[0].len()
+    }
+
+    /// Return the digest of the leaf at the given index, if it exists.
+    pub fn leaf(&self, index: usize) -> Option<&Digest> {
+        self.levels[0].get(index)
+    }
+
+    /// Return a proof that the leaf at the given index is part of this tree,
+    /// which can be checked against just the root with `MerkleProof::verify`.
+    pub fn proof(&self, leaf_index: usize) -> Result<MerkleProof> {
+        if leaf_index >= self.leaf_count() {
+            return Err(Error::InvalidArgument(format!(
+                "leaf index {} is out of range for a tree with {} leaves",
+                leaf_index,
+                self.leaf_count()
+            )));
+        }
+
+        let mut siblings = Vec::new();
+        let mut index = leaf_index;
+        for level in &self.levels[..self.levels.len() - 1] {
+            // A promoted node has no sibling at this level.
+            if let Some(sibling) = level.get(index ^ 1) {
+                siblings.push(sibling.clone());
+            }
+            index /= 2;
+        }
+        Ok(MerkleProof { siblings })
+    }
+
+    /// Return the indices of the leaves (i.e., chunks) which differ between
+    /// this tree and another one. If one tree has more leaves than the other,
+    /// the extra leaves are all considered different. It is an error if the
+    /// trees were built with different chunk sizes, since their leaves can't
+    /// be compared meaningfully.
+    pub fn diff(&self, other: &MerkleTree) -> Result<Vec<usize>> {
+        if self.chunk_size != other.chunk_size {
+            return Err(Error::InvalidArgument(format!(
+                "can't compare Merkle trees with different chunk sizes ({} vs. {})",
+                self.chunk_size, other.chunk_size
+            )));
+        }
+        let count = self.leaf_count().max(other.leaf_count());
+        Ok((0..count)
+            .filter(|&i| self.leaf(i) != other.leaf(i))
+            .collect())
+    }
+}
+
+/// A MerkleProof shows that a single leaf is part of a `MerkleTree`, given
+/// only the tree's root digest and leaf count.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct MerkleProof {
+    /// The sibling of each node on the path from the leaf to the root, skipping
+    /// levels where the node was promoted without a sibling.
+    siblings: Vec<Digest>,
+}
+
+impl MerkleProof {
+    /// Returns true if this proof shows that the given leaf digest (see
+    /// `MerkleTree::leaf_digest`) is the leaf at the given index of the tree
+    /// with the given root and number of leaves.
+    ///
+    /// The leaf count must be trusted just like the root is (e.g., derived
+    /// from the data's known length and chunk size). It determines the shape
+    /// of the tree, so if it came from the proof itself, a proof for one leaf
+    /// could be made to verify at a different index.
+    pub fn verify(
+        &self,
+        root: &Digest,
+        leaf_count: usize,
+        leaf_digest: &Digest,
+        index: usize,
+    ) -> bool {
+        if index >= leaf_count {
+            return false;
+        }
+
+        let mut siblings = self.siblings.iter();
+        let mut current = leaf_digest.clone();
+        let mut index = index;
+        let mut level_len = leaf_count;
+        while level_len > 1 {
+            if index ^ 1 < level_len {
+                let sibling = match siblings.next() {
+                    None => return false,
+                    Some(sibling) => sibling,
+                };
+                current = match index % 2 {
+                    0 => merkle_parent(&current, sibling),
+                    _ => merkle_parent(sibling, &current),
+                };
+            }
+            index /= 2;
+            level_len = level_len.div_ceil(2);
+        }
+        siblings.next().is_none() && current == *root
+    }
+}
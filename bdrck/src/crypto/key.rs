@@ -19,6 +19,8 @@ use crate::crypto::util::*;
 use crate::error::*;
 use halite_sys;
 use libc::c_ulonglong;
+#[cfg(feature = "testing")]
+use rand::RngCore;
 use serde::{Deserialize, Serialize};
 
 /// This module uses xsalsa20poly1305, whose nonces are 24 bytes long.
@@ -27,6 +29,12 @@ pub const NONCE_BYTES: usize = halite_sys::crypto_secretbox_xsalsa20poly1305_NON
 pub const KEY_BYTES: usize = halite_sys::crypto_secretbox_xsalsa20poly1305_KEYBYTES as usize;
 /// xsalsa20poly1305 authenticator tags are 16 bytes.
 pub const TAG_BYTES: usize = halite_sys::crypto_secretbox_xsalsa20poly1305_MACBYTES as usize;
+/// Sub-key derivation contexts (see `Key::derive_subkey`) are at most 8 bytes.
+pub const SUBKEY_CONTEXT_BYTES: usize = halite_sys::crypto_kdf_CONTEXTBYTES as usize;
+
+/// Sub-key derivation contexts starting with this prefix are reserved for
+/// bdrck's own use (e.g. the KeyStore's integrity MAC key).
+const RESERVED_SUBKEY_CONTEXT_PREFIX: &str = "bdrck";
 
 /// A cryptographic nonce is an arbitrary number that can be used only once
 /// (e.g. for encryption).
@@ -57,6 +65,17 @@ impl Nonce {
         }
     }
 
+    /// Return a new nonce filled with bytes from the given RNG, instead of
+    /// the OS's secure RNG. This is intended for generating reproducible test
+    /// fixtures (e.g. with `testing::crypto::DeterministicRng`), and should not
+    /// be used otherwise.
+    #[cfg(feature = "testing")]
+    pub fn from_rng<R: RngCore>(rng: &mut R) -> Self {
+        let mut nonce = Nonce::new();
+        rng.fill_bytes(&mut nonce.nonce.0);
+        nonce
+    }
+
     /// Construct a Nonce from a properly sized byte slice.
     pub fn from_slice(bytes: &[u8]) -> Result<Self> {
         Ok(Nonce {
@@ -271,6 +290,86 @@ impl AbstractKey for Key {
 }
 
 impl Key {
+    /// Construct a Key directly from the given raw key data, which must be
+    /// exactly `KEY_BYTES` long.
+    pub(crate) fn from_raw(key_data: Secret) -> Result<Self> {
+        if key_data.len() != KEY_BYTES {
+            return Err(Error::InvalidArgument(format!(
+                "invalid Key data; expected {} bytes, got {}",
+                KEY_BYTES,
+                key_data.len()
+            )));
+        }
+        Ok(Key { key_data })
+    }
+
+    /// Return this Key's raw key data.
+    pub(crate) fn as_secret(&self) -> &Secret {
+        &self.key_data
+    }
+
+    /// Derive a sub-key from this key using libsodium's KDF, with a context
+    /// which has already been mapped to the primitive's fixed-size format.
+    pub(crate) fn derive_subkey_raw(
+        &self,
+        context: &[u8; SUBKEY_CONTEXT_BYTES],
+        index: u64,
+    ) -> Result<Key> {
+        let subkey = Secret::with_len(KEY_BYTES)?;
+        debug_assert!(crate::init_done());
+        if unsafe {
+            halite_sys::crypto_kdf_derive_from_key(
+                subkey.slice_ptr(),
+                KEY_BYTES,
+                index,
+                context.as_ptr() as *const libc::c_char,
+                self.key_data.slice_ptr(),
+            )
+        } != 0
+        {
+            return Err(Error::Crypto("deriving sub-key failed".to_string()));
+        }
+        Ok(Key { key_data: subkey })
+    }
+
+    /// Deterministically derive a sub-key from this key, so a single key can
+    /// be used for several unrelated purposes without one purpose's keys
+    /// being useful for any other. The same (context, index) pair always
+    /// yields the same sub-key from the same key, and any other pair yields an
+    /// independent key.
+    ///
+    /// The context identifies the purpose, e.g. "dbfields". It follows
+    /// libsodium's `crypto_kdf` rules: it must be at most
+    /// `SUBKEY_CONTEXT_BYTES` (8) bytes long, and it is zero-padded to that
+    /// length. Since padding would otherwise make e.g. "ab" and "ab\0"
+    /// equivalent, contexts containing NUL bytes are rejected, as are longer
+    /// contexts (rather than truncating them, which could map two different
+    /// contexts onto the same key). Contexts starting with "bdrck" are
+    /// reserved for this crate's internal use.
+    pub fn derive_subkey(&self, context: &str, index: u64) -> Result<Key> {
+        if context.len() > SUBKEY_CONTEXT_BYTES {
+            return Err(Error::InvalidArgument(format!(
+                "sub-key context '{}' is longer than {} bytes",
+                context, SUBKEY_CONTEXT_BYTES
+            )));
+        }
+        if context.contains('\0') {
+            return Err(Error::InvalidArgument(
+                "sub-key contexts must not contain NUL bytes".to_string(),
+            ));
+        }
+        if context.starts_with(RESERVED_SUBKEY_CONTEXT_PREFIX) {
+            return Err(Error::InvalidArgument(format!(
+                "sub-key context '{}' is reserved",
+                context
+            )));
+        }
+
+        let mut padded = [0_u8; SUBKEY_CONTEXT_BYTES];
+        padded[..context.len()].copy_from_slice(context.as_bytes());
+        self.derive_subkey_raw(&padded, index)
+    }
+
     /// Generate a new random key.
     pub fn new_random() -> Result<Self> {
         let mut key_buffer = Secret::with_len(KEY_BYTES)?;
@@ -280,6 +379,19 @@ impl Key {
         })
     }
 
+    /// Generate a new key using bytes from the given RNG, instead of the OS's
+    /// secure RNG. This is intended for generating reproducible test fixtures
+    /// (e.g. with `testing::crypto::DeterministicRng`), and should not be used
+    /// otherwise.
+    #[cfg(feature = "testing")]
+    pub fn new_from_rng<R: RngCore>(rng: &mut R) -> Result<Self> {
+        let mut key_buffer = Secret::with_len(KEY_BYTES)?;
+        rng.fill_bytes(unsafe { key_buffer.as_mut_slice() });
+        Ok(Key {
+            key_data: key_buffer,
+        })
+    }
+
     /// Derive a new key from the given password. Note that the derived key will
     /// be different if any of the parameters to this function change, so they
     /// need to remain fixed if you e.g. re-derive the key to decrypt some
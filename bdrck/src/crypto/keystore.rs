@@ -12,19 +12,35 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::crypto::audit::{AuditLog, AuditOperation, AuditOutcome, AuditReport};
+use crate::crypto::digest::Digest;
 use crate::crypto::key::{AbstractKey, Key, Nonce};
 use crate::crypto::secret::Secret;
 use crate::crypto::wrap::WrappedKey;
 use crate::error::*;
+#[cfg(feature = "fs")]
+use crate::fs::{atomic_write_with, real_fs, FsOps};
+use crate::testing::clock::{Clock, SystemClock};
 use data_encoding;
+use halite_sys;
+use libc::c_ulonglong;
 use once_cell::sync::Lazy;
+#[cfg(feature = "testing")]
+use rand::RngCore;
 use rmp_serde;
 use serde::{Deserialize, Serialize};
 use std::fs;
-use std::io::{Read, Write};
+use std::io::Read;
+#[cfg(not(feature = "fs"))]
+use std::io::Write;
 use std::ops::{Deref, DerefMut};
 use std::path::{Path, PathBuf};
-use tracing::{debug, error};
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "fs")]
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, error, warn};
 
 /// This token is used to verify that authentication was successful. We encrypt it with a master
 /// key which we then wrap with user key(s), so we can verify that the user presented a valid
@@ -38,6 +54,62 @@ static AUTH_TOKEN_CONTENTS: Lazy<Secret> = Lazy::new(|| {
     secret
 });
 
+/// The libsodium KDF context used to derive the KeyStore's MAC key from its
+/// master key. This keeps the MAC key distinct from any other key derived from
+/// the master key.
+const MAC_KDF_CONTEXT: &[u8; 8] = b"bdrckmac";
+const MAC_KDF_SUBKEY_ID: u64 = 1;
+const MAC_BYTES: usize = 32;
+
+fn compute_mac(
+    master_key: &Key,
+    token_nonce: &Option<Nonce>,
+    token: &Vec<u8>,
+    wrapped_keys: &Vec<WrappedKey>,
+    key_metadata: &[KeyMetadata],
+) -> Result<Vec<u8>> {
+    let mac_key = master_key.derive_subkey_raw(MAC_KDF_CONTEXT, MAC_KDF_SUBKEY_ID)?;
+
+    // KeyStores without any key metadata are MACed exactly as they were before
+    // metadata existed, so their existing MACs remain valid.
+    let data = match key_metadata.is_empty() {
+        true => rmp_serde::to_vec(&(token_nonce, token, wrapped_keys))?,
+        false => rmp_serde::to_vec(&(token_nonce, token, wrapped_keys, key_metadata))?,
+    };
+    let mut mac = vec![0; MAC_BYTES];
+    if unsafe {
+        halite_sys::crypto_auth(
+            mac.as_mut_ptr(),
+            data.as_ptr(),
+            data.len() as c_ulonglong,
+            mac_key.as_secret().slice_ptr(),
+        )
+    } != 0
+    {
+        return Err(Error::Crypto("computing KeyStore MAC failed".to_string()));
+    }
+    Ok(mac)
+}
+
+fn verify_mac(
+    master_key: &Key,
+    mac: &[u8],
+    token_nonce: &Option<Nonce>,
+    token: &Vec<u8>,
+    wrapped_keys: &Vec<WrappedKey>,
+    key_metadata: &[KeyMetadata],
+) -> Result<bool> {
+    let expected = compute_mac(master_key, token_nonce, token, wrapped_keys, key_metadata)?;
+    Ok(mac.len() == expected.len()
+        && unsafe {
+            halite_sys::sodium_memcmp(
+                mac.as_ptr() as *const libc::c_void,
+                expected.as_ptr() as *const libc::c_void,
+                MAC_BYTES,
+            )
+        } == 0)
+}
+
 /// Returns true if the given key is this structure's "master key" which was
 /// used to encrypt the `token` upon construction.
 fn is_master_key<K: AbstractKey>(key: &K, nonce: Option<&Nonce>, token: &[u8]) -> bool {
@@ -48,6 +120,86 @@ fn is_master_key<K: AbstractKey>(key: &K, nonce: Option<&Nonce>, token: &[u8]) -
     unsafe { decrypted.as_slice() == AUTH_TOKEN_CONTENTS.as_slice() }
 }
 
+/// KeyUsage describes what a KeyStore's wrapping key is intended to be used
+/// for. KeyStore just records this, so applications can enforce their own
+/// policies (e.g. refusing to use a `WrapOnly` key for anything else).
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub enum KeyUsage {
+    /// The key may be used for other purposes, in addition to wrapping this
+    /// KeyStore's master key.
+    #[default]
+    General,
+    /// The key should only ever be used to wrap this KeyStore's master key.
+    WrapOnly,
+}
+
+/// KeyOptions is optional metadata which can be attached to a wrapping key
+/// when it is added to a KeyStore (see `KeyStore::add_key_with_options`).
+#[derive(Clone, Debug, Default)]
+pub struct KeyOptions {
+    /// A human-readable label for this key, e.g. for listing keys or in error
+    /// messages.
+    pub label: Option<String>,
+    /// When this key expires. After this point, `KeyStore::open` refuses to
+    /// open the KeyStore with this key. This is stored with one second
+    /// precision.
+    pub expires_at: Option<SystemTime>,
+    /// What this key is intended to be used for.
+    pub usage: KeyUsage,
+}
+
+/// The metadata stored alongside a wrapped key, identified by the digest of the
+/// key it was wrapped with.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+struct KeyMetadata {
+    wrapping_digest: Digest,
+    label: Option<String>,
+    /// Seconds since the UNIX epoch.
+    expires_at: Option<u64>,
+    usage: KeyUsage,
+    /// When this key replaced some other key (see `KeyStore::replace_key`), in
+    /// seconds since the UNIX epoch. Omitted if it never did, so metadata
+    /// written by older versions is MACed (and serialized) identically.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rotated_at: Option<u64>,
+}
+
+impl KeyMetadata {
+    fn expires_at(&self) -> Option<SystemTime> {
+        self.expires_at
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    fn rotated_at(&self) -> Option<SystemTime> {
+        self.rotated_at
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+    }
+}
+
+/// KeyInfo describes one of a KeyStore's wrapping keys, as returned by
+/// `KeyStore::list_keys`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct KeyInfo {
+    /// The digest of the wrapping key.
+    pub wrapping_digest: Digest,
+    /// The key's label, if it was given one.
+    pub label: Option<String>,
+    /// When the key expires, if ever.
+    pub expires_at: Option<SystemTime>,
+    /// What the key is intended to be used for.
+    pub usage: KeyUsage,
+    /// When the key replaced some other key (see `KeyStore::replace_key`), if
+    /// it did.
+    pub rotated_at: Option<SystemTime>,
+}
+
+impl KeyInfo {
+    /// Returns whether or not this key has expired as of the given time.
+    pub fn is_expired_at(&self, now: SystemTime) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
 /// A KeyStore is a structure which contains a single "master key", wrapped with
 /// one or more other keys. This is useful in cases where we want to encrypt
 /// data with a single key, while allowing users to add or remove keys at will,
@@ -74,6 +226,35 @@ pub struct KeyStore {
     token_nonce: Option<Nonce>,
     token: Vec<u8>,
     wrapped_keys: Vec<WrappedKey>,
+
+    /// A MAC over all of the above fields, keyed with a key derived from the
+    /// master key. This is computed whenever an open KeyStore is serialized.
+    /// KeyStores serialized by older versions don't have one.
+    #[serde(default)]
+    mac: Option<Vec<u8>>,
+    /// Optional metadata (expiry, etc.) for some of `wrapped_keys`. KeyStores
+    /// serialized by older versions (or with no metadata at all) omit this.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    key_metadata: Vec<KeyMetadata>,
+    /// Whether or not this KeyStore had a MAC when it was loaded.
+    #[serde(skip_serializing, skip_deserializing)]
+    had_mac: bool,
+    /// Whether or not this KeyStore was loaded in read-only mode, in which
+    /// case all attempts to modify it fail.
+    #[serde(skip_serializing, skip_deserializing)]
+    read_only: bool,
+}
+
+/// This mirrors the serialized format of a KeyStore, so we can serialize it
+/// with a freshly computed MAC without needing to copy everything else.
+#[derive(Serialize)]
+struct SerializedKeyStore<'a> {
+    token_nonce: &'a Option<Nonce>,
+    token: &'a Vec<u8>,
+    wrapped_keys: &'a Vec<WrappedKey>,
+    mac: Option<Vec<u8>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    key_metadata: &'a Vec<KeyMetadata>,
 }
 
 impl KeyStore {
@@ -83,28 +264,61 @@ impl KeyStore {
     /// to wrap the master key with at least one key, via `add_key`.
     pub fn new() -> Result<Self> {
         // Generate a new master key. We'll store this *wrapped with `key`*.
-        let master_key = Key::new_random()?;
+        Self::with_master_key(Key::new_random()?, None)
+    }
+
+    /// Construct a new KeyStore, like `new`, except all of the randomness
+    /// involved (the master key and the token nonce) comes from the given RNG.
+    /// Together with `add_key_with_rng`, this makes it possible to generate
+    /// byte-for-byte reproducible KeyStores for test fixtures. This should
+    /// never be used otherwise.
+    #[cfg(feature = "testing")]
+    pub fn new_from_rng<R: RngCore>(rng: &mut R) -> Result<Self> {
+        let master_key = Key::new_from_rng(rng)?;
+        Self::with_master_key(master_key, Some(Nonce::from_rng(rng)))
+    }
+
+    fn with_master_key(master_key: Key, nonce: Option<Nonce>) -> Result<Self> {
         // Encrypt the auth token with the master key. This is so we can decrypt
         // it later, and verify we get the right result, to guarantee we have
         // the right master key.
-        let (nonce, ciphertext) = master_key.encrypt(&AUTH_TOKEN_CONTENTS, None)?;
+        let (nonce, ciphertext) = master_key.encrypt(&AUTH_TOKEN_CONTENTS, nonce)?;
 
         Ok(KeyStore {
             master_key: Some(master_key),
             token_nonce: nonce,
             token: ciphertext,
             wrapped_keys: Vec::new(),
+            mac: None,
+            key_metadata: Vec::new(),
+            had_mac: false,
+            read_only: false,
         })
     }
 
+    fn loaded(mut self) -> Self {
+        self.had_mac = self.mac.is_some();
+        self
+    }
+
     /// Load a previously-serialized (with `to_vec`) KeyStore from a byte slice.
     pub fn load_slice(data: &[u8]) -> Result<Self> {
-        Ok(rmp_serde::from_slice(data)?)
+        Ok(KeyStore::loaded(rmp_serde::from_slice(data)?))
     }
 
     /// Load a previously-serialized (with `to_vec`) KeyStore from a reader.
     pub fn load_read<R: Read>(rd: R) -> Result<Self> {
-        Ok(rmp_serde::from_read(rd)?)
+        Ok(KeyStore::loaded(rmp_serde::from_read(rd)?))
+    }
+
+    /// Return whether or not this KeyStore had an integrity MAC when it was
+    /// loaded. If it did, `open` verifies it. KeyStores serialized by older
+    /// versions of this library, or serialized while they were not open (and
+    /// modified since being loaded), don't have one, in which case
+    /// applications may want to warn the user (and then open and re-persist
+    /// the KeyStore, to add one).
+    pub fn had_integrity_mac(&self) -> bool {
+        self.had_mac
     }
 
     /// Return a string which "uniquely" identifies this KeyStore.
@@ -121,6 +335,24 @@ impl KeyStore {
         self.master_key.is_some()
     }
 
+    /// Return whether or not this KeyStore was loaded in read-only mode (see
+    /// `ReadOnlyKeyStore`). Read-only KeyStores can be opened and inspected,
+    /// but every attempt to modify them returns `Error::ReadOnlyKeyStore`.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn check_writable(&self, operation: &str) -> Result<()> {
+        match self.read_only {
+            false => Ok(()),
+            true => Err(Error::ReadOnlyKeyStore(format!(
+                "can't {} KeyStore {}",
+                operation,
+                self.get_id()
+            ))),
+        }
+    }
+
     /// Return whether or not this KeyStore is meaningfully "persistable". In
     /// other words, this returns whether or not this KeyStore has at least one
     /// wrapping key.
@@ -135,18 +367,52 @@ impl KeyStore {
     /// Open this KeyStore (attempt to unwrap the master key) using the given
     /// wrapping key. If this fails, the structure will still be in a valid
     /// state, so you could e.g. try again with a different wrapping key.
+    ///
+    /// If this KeyStore has an integrity MAC, it is verified once the master
+    /// key has been recovered. If verification fails, `Error::KeyStoreTampered`
+    /// is returned, and the KeyStore is left unopened.
+    ///
+    /// If the given key has expired (see `KeyOptions`), `Error::KeyExpired` is
+    /// returned, and the KeyStore is left unopened. Use `open_allow_expired`
+    /// to open it anyway, e.g. to replace the expired key.
     pub fn open<K: AbstractKey>(&mut self, key: &K) -> Result<()> {
+        self.open_with_clock(key, &SystemClock)
+    }
+
+    /// This is identical to `open`, except key expiry is checked against the
+    /// given clock's current time, instead of the system time.
+    pub fn open_with_clock<K: AbstractKey, C: Clock + ?Sized>(
+        &mut self,
+        key: &K,
+        clock: &C,
+    ) -> Result<()> {
+        self.open_impl(key, Some(clock.now_utc()))
+    }
+
+    /// This is identical to `open`, except the KeyStore is opened even if the
+    /// given key has expired.
+    pub fn open_allow_expired<K: AbstractKey>(&mut self, key: &K) -> Result<()> {
+        self.open_impl(key, None)
+    }
+
+    fn open_impl<K: AbstractKey>(&mut self, key: &K, now: Option<SystemTime>) -> Result<()> {
         if self.master_key.is_some() {
             // We're already opened, this will be a no-op.
             return Ok(());
         }
 
-        let mut master_key: Option<Key> = None;
+        let mut master_key: Option<(Key, &Digest)> = None;
         for wrapped_key in self.wrapped_keys.iter() {
-            match wrapped_key.unwrap(key) {
+            // Keys added by older versions aren't bound to this KeyStore's
+            // token, so we still accept them for compatibility.
+            let unwrapped = match wrapped_key.has_aad() {
+                false => wrapped_key.unwrap(key),
+                true => wrapped_key.unwrap_with_aad(key, self.token.as_slice()),
+            };
+            match unwrapped {
                 Ok(k) => {
                     if is_master_key(&k, self.token_nonce.as_ref(), self.token.as_slice()) {
-                        master_key = Some(k);
+                        master_key = Some((k, wrapped_key.get_wrapping_digest()));
                         break;
                     } else {
                         debug!("unwrapped key {:?}, but unwrapped key doesn't match our expected master key", wrapped_key.get_digest());
@@ -160,19 +426,74 @@ impl KeyStore {
             }
         }
 
-        if master_key.is_none() {
-            return Err(Error::InvalidArgument(format!(
-                "KeyStore unlocking failed: the given key is not present in this KeyStore"
-            )));
+        let (master_key, wrapping_digest) = match master_key {
+            None => {
+                return Err(Error::InvalidArgument(format!(
+                    "KeyStore unlocking failed: the given key is not present in this KeyStore"
+                )))
+            }
+            Some(k) => k,
+        };
+
+        if let Some(mac) = self.mac.as_ref() {
+            if !verify_mac(
+                &master_key,
+                mac.as_slice(),
+                &self.token_nonce,
+                &self.token,
+                &self.wrapped_keys,
+                &self.key_metadata,
+            )? {
+                return Err(Error::KeyStoreTampered(format!(
+                    "KeyStore {} has been modified since it was persisted",
+                    self.get_id()
+                )));
+            }
+        }
+
+        // Only check expiry once we know the key is valid (and the metadata is
+        // authentic), so this error is distinguishable from a wrong key.
+        if let Some(now) = now {
+            if let Some(metadata) = self.get_key_metadata(wrapping_digest) {
+                if let Some(expired_at) = metadata.expires_at().filter(|&e| e <= now) {
+                    return Err(Error::KeyExpired {
+                        expired_at,
+                        label: metadata.label.clone().unwrap_or_else(|| {
+                            data_encoding::HEXLOWER.encode(wrapping_digest.as_slice())
+                        }),
+                    });
+                }
+            }
         }
 
-        self.master_key = master_key;
+        self.master_key = Some(master_key);
         Ok(())
     }
 
     /// Serialize this KeyStore, so it can be persisted and then reloaded later.
+    ///
+    /// If this KeyStore is open, a MAC over its contents is included, so
+    /// modifications can be detected when it is opened again. If it isn't
+    /// open, the MAC it was loaded with (if any) is kept, unless it has been
+    /// modified since (in which case it is serialized without a MAC).
     pub fn to_vec(&self) -> Result<Vec<u8>> {
-        Ok(rmp_serde::to_vec(self)?)
+        let mac = match self.master_key.as_ref() {
+            None => self.mac.clone(),
+            Some(mk) => Some(compute_mac(
+                mk,
+                &self.token_nonce,
+                &self.token,
+                &self.wrapped_keys,
+                &self.key_metadata,
+            )?),
+        };
+        Ok(rmp_serde::to_vec(&SerializedKeyStore {
+            token_nonce: &self.token_nonce,
+            token: &self.token,
+            wrapped_keys: &self.wrapped_keys,
+            mac,
+            key_metadata: &self.key_metadata,
+        })?)
     }
 
     /// Return the unwrapped master key from this KeyStore. If this KeyStore
@@ -187,22 +508,90 @@ impl KeyStore {
         )))
     }
 
+    /// Derive a sub-key from this KeyStore's master key, as per
+    /// `Key::derive_subkey`. Since the master key never changes, neither do
+    /// the derived keys, even as wrapping keys are added or removed. This
+    /// KeyStore must be open.
+    pub fn derive(&self, context: &str, index: u64) -> Result<Key> {
+        self.get_master_key()?.derive_subkey(context, index)
+    }
+
     /// Add the given wrapping key to this KeyStore. When the KeyStore is opened
     /// in the future, this key can be used. Returns true if the key was
     /// successfully added, or false if it was already present in the KeyStore.
     ///
+    /// The wrapped master key is bound to this KeyStore's token, so it can't
+    /// be copied into some other KeyStore.
+    ///
     /// If this KeyStore has no master key (it was neither newly generated nor
     /// unwrapped), this will return an error instead.
     pub fn add_key<K: AbstractKey>(&mut self, key: &K) -> Result<bool> {
-        let wrapped_key = match self.master_key.as_ref() {
-            None => {
-                return Err(Error::Precondition(format!(
-                    "KeyStore must be `new` or opened to add keys"
-                )))
-            }
-            Some(mk) => WrappedKey::wrap(/*to_wrap=*/ mk, /*wrap_with=*/ key)?,
-        };
+        self.check_writable("add a key to")?;
+        let wrapped_key = WrappedKey::wrap_with_aad(
+            /*to_wrap=*/ self.master_key_for_adding()?,
+            /*wrap_with=*/ key,
+            /*aad=*/ self.token.as_slice(),
+        )?;
+        Ok(self.add_wrapped_key(wrapped_key))
+    }
 
+    /// Like `add_key`, except the given metadata (e.g. an expiry time) is
+    /// recorded alongside the key. Returns false (without changing the
+    /// existing key's metadata) if the key was already present.
+    pub fn add_key_with_options<K: AbstractKey>(
+        &mut self,
+        key: &K,
+        options: KeyOptions,
+    ) -> Result<bool> {
+        let added = self.add_key(key)?;
+        let has_metadata = options.label.is_some()
+            || options.expires_at.is_some()
+            || options.usage != KeyUsage::default();
+        if added && has_metadata {
+            self.key_metadata.push(KeyMetadata {
+                wrapping_digest: key.get_digest(),
+                label: options.label,
+                expires_at: options.expires_at.map(|t| {
+                    t.duration_since(UNIX_EPOCH)
+                        .map(|d| d.as_secs())
+                        .unwrap_or(0)
+                }),
+                usage: options.usage,
+                rotated_at: None,
+            });
+        }
+        Ok(added)
+    }
+
+    /// Like `add_key`, except the nonce used to wrap the master key comes from
+    /// the given RNG. See `new_from_rng` for details; this should only be used
+    /// to generate reproducible test fixtures.
+    #[cfg(feature = "testing")]
+    pub fn add_key_with_rng<K: AbstractKey, R: RngCore>(
+        &mut self,
+        key: &K,
+        rng: &mut R,
+    ) -> Result<bool> {
+        self.check_writable("add a key to")?;
+        let wrapped_key = WrappedKey::wrap_with_aad_and_nonce(
+            /*to_wrap=*/ self.master_key_for_adding()?,
+            /*wrap_with=*/ key,
+            /*aad=*/ self.token.as_slice(),
+            /*nonce=*/ Nonce::from_rng(rng),
+        )?;
+        Ok(self.add_wrapped_key(wrapped_key))
+    }
+
+    fn master_key_for_adding(&self) -> Result<&Key> {
+        match self.master_key.as_ref() {
+            None => Err(Error::Precondition(format!(
+                "KeyStore must be `new` or opened to add keys"
+            ))),
+            Some(mk) => Ok(mk),
+        }
+    }
+
+    fn add_wrapped_key(&mut self, wrapped_key: WrappedKey) -> bool {
         // If this key is already in the KeyStore, just return.
         if self
             .wrapped_keys
@@ -211,11 +600,113 @@ impl KeyStore {
             .next()
             .is_some()
         {
-            return Ok(false);
+            return false;
         }
 
         self.wrapped_keys.push(wrapped_key);
-        Ok(true)
+        true
+    }
+
+    /// Replace the wrapping key `old` with `new`, e.g. when a user changes
+    /// their password. Unlike calling `add_key` and then `remove_key`, this is
+    /// a single operation, so the KeyStore is never serialized with both keys
+    /// (or neither) present. Any metadata `old` was added with (label, expiry,
+    /// etc.) is carried over to `new`, and the time of the replacement is
+    /// recorded (see `KeyInfo::rotated_at`).
+    ///
+    /// `old` must actually unwrap this KeyStore's master key; if it doesn't,
+    /// an error is returned and the KeyStore is left unchanged. If this
+    /// KeyStore hasn't been opened yet, it is opened with `old`.
+    pub fn replace_key<K1: AbstractKey, K2: AbstractKey>(
+        &mut self,
+        old: &K1,
+        new: &K2,
+    ) -> Result<()> {
+        self.replace_key_with_clock(old, new, &SystemClock)
+    }
+
+    /// This is identical to `replace_key`, except the time of the replacement
+    /// is taken from the given clock, instead of the system time.
+    pub fn replace_key_with_clock<K1: AbstractKey, K2: AbstractKey, C: Clock + ?Sized>(
+        &mut self,
+        old: &K1,
+        new: &K2,
+        clock: &C,
+    ) -> Result<()> {
+        self.check_writable("replace a key in")?;
+        let old_digest = old.get_digest();
+        let new_digest = new.get_digest();
+        if old_digest == new_digest {
+            return Err(Error::InvalidArgument(
+                "can't replace a KeyStore key with itself".to_string(),
+            ));
+        }
+        if self
+            .wrapped_keys
+            .iter()
+            .any(|k| *k.get_wrapping_digest() == new_digest)
+        {
+            return Err(Error::InvalidArgument(
+                "the replacement key is already present in this KeyStore".to_string(),
+            ));
+        }
+
+        // Don't just trust the digest: make sure the old key really does
+        // unwrap the master key.
+        let index = self.wrapped_keys.iter().position(|wrapped_key| {
+            if *wrapped_key.get_wrapping_digest() != old_digest {
+                return false;
+            }
+            let unwrapped = match wrapped_key.has_aad() {
+                false => wrapped_key.unwrap(old),
+                true => wrapped_key.unwrap_with_aad(old, self.token.as_slice()),
+            };
+            unwrapped.is_ok_and(|k: Key| {
+                is_master_key(&k, self.token_nonce.as_ref(), self.token.as_slice())
+            })
+        });
+        let index = match index {
+            None => {
+                return Err(Error::InvalidArgument(
+                    "KeyStore key replacement failed: the old key doesn't open this KeyStore"
+                        .to_string(),
+                ))
+            }
+            Some(index) => index,
+        };
+        if self.master_key.is_none() {
+            self.open_impl(old, None)?;
+        }
+
+        let wrapped_key = WrappedKey::wrap_with_aad(
+            /*to_wrap=*/ self.master_key_for_adding()?,
+            /*wrap_with=*/ new,
+            /*aad=*/ self.token.as_slice(),
+        )?;
+        let now = clock
+            .now_utc()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        match self
+            .key_metadata
+            .iter_mut()
+            .find(|m| m.wrapping_digest == old_digest)
+        {
+            Some(metadata) => {
+                metadata.wrapping_digest = new_digest;
+                metadata.rotated_at = Some(now);
+            }
+            None => self.key_metadata.push(KeyMetadata {
+                wrapping_digest: new_digest,
+                label: None,
+                expires_at: None,
+                usage: KeyUsage::default(),
+                rotated_at: Some(now),
+            }),
+        }
+        self.wrapped_keys[index] = wrapped_key;
+        Ok(())
     }
 
     /// Remove the given key from this KeyStore, so it can no longer be used to
@@ -227,6 +718,7 @@ impl KeyStore {
     /// Note that it is possible to do this even if the KeyStore has no
     /// unwrapped master key (e.g., even if it has not been opened).
     pub fn remove_key<K: AbstractKey>(&mut self, key: &K) -> Result<bool> {
+        self.check_writable("remove a key from")?;
         if self.wrapped_keys.len() == 1 {
             if let Some(wrapped_key) = self.wrapped_keys.first() {
                 if *wrapped_key.get_wrapping_digest() == key.get_digest() {
@@ -244,7 +736,15 @@ impl KeyStore {
             .filter(|k| *k.get_wrapping_digest() != key.get_digest())
             .collect();
         self.wrapped_keys = wrapped_keys;
-        Ok(original_length != self.wrapped_keys.len())
+        self.key_metadata
+            .retain(|m| m.wrapping_digest != key.get_digest());
+        let removed = original_length != self.wrapped_keys.len();
+        if removed && self.master_key.is_none() {
+            // We can't compute a new MAC without the master key, and the old
+            // one no longer matches, so drop it.
+            self.mac = None;
+        }
+        Ok(removed)
     }
 
     /// Return an immutable iterator over this KeyStore's wrapped keys. This
@@ -256,27 +756,444 @@ impl KeyStore {
     pub fn iter_wrapped_keys(&self) -> impl Iterator<Item = &WrappedKey> {
         self.wrapped_keys.iter()
     }
+
+    fn get_key_metadata(&self, wrapping_digest: &Digest) -> Option<&KeyMetadata> {
+        self.key_metadata
+            .iter()
+            .find(|m| m.wrapping_digest == *wrapping_digest)
+    }
+
+    /// Return information about each of this KeyStore's wrapping keys,
+    /// including any metadata they were added with. CLIs can use this e.g. to
+    /// warn about keys which will expire soon.
+    ///
+    /// This works even if the KeyStore has no unwrapped master key (e.g., even
+    /// if it has not been opened).
+    pub fn list_keys(&self) -> Vec<KeyInfo> {
+        self.wrapped_keys
+            .iter()
+            .map(|k| {
+                let metadata = self.get_key_metadata(k.get_wrapping_digest());
+                KeyInfo {
+                    wrapping_digest: k.get_wrapping_digest().clone(),
+                    label: metadata.and_then(|m| m.label.clone()),
+                    expires_at: metadata.and_then(|m| m.expires_at()),
+                    usage: metadata.map(|m| m.usage).unwrap_or_default(),
+                    rotated_at: metadata.and_then(|m| m.rotated_at()),
+                }
+            })
+            .collect()
+    }
 }
 
-fn persist_key_store<P: AsRef<Path>>(path: P, keystore: &KeyStore) -> Result<()> {
+fn persist_key_store<S: KeyStoreStorage + ?Sized>(storage: &S, keystore: &KeyStore) -> Result<()> {
     if !keystore.is_persistable() {
         return Err(Error::Precondition(format!(
             "KeyStore with no wrapping keys cannot be persisted"
         )));
     }
 
-    let mut f = fs::File::create(path.as_ref())?;
-    let data = keystore.to_vec()?;
-    f.write_all(data.as_slice())?;
-    Ok(())
+    storage.store(keystore.to_vec()?.as_slice())
+}
+
+/// Load a KeyStore from the given storage in read-only mode. Unlike the
+/// read-write constructors, it is an error if nothing has been stored yet.
+fn load_read_only<S: KeyStoreStorage + ?Sized>(storage: &S) -> Result<KeyStore> {
+    let mut keystore = match storage.load()? {
+        None => {
+            return Err(Error::NotFound(
+                "no KeyStore has been stored yet".to_string(),
+            ))
+        }
+        Some(data) => KeyStore::load_slice(data.as_slice())?,
+    };
+    keystore.read_only = true;
+    Ok(keystore)
+}
+
+/// KeyStoreStorage abstracts over where a serialized KeyStore is kept (e.g. a
+/// file, a system keyring, or a database), so `ManagedKeyStore` can deal with
+/// loading and persisting it.
+pub trait KeyStoreStorage {
+    /// Load a previously stored serialized KeyStore, or return None if nothing
+    /// has been stored yet.
+    fn load(&self) -> Result<Option<Vec<u8>>>;
+
+    /// Store the given serialized KeyStore, replacing whatever was stored
+    /// previously.
+    fn store(&self, data: &[u8]) -> Result<()>;
+}
+
+/// FileStorage stores a serialized KeyStore in a file on disk. A missing or
+/// empty file is treated as if nothing has been stored yet.
+///
+/// With the "fs" feature enabled, the file is replaced atomically (by writing
+/// a temporary file next to it, and then renaming it into place), so a crash
+/// never leaves a partially written KeyStore behind.
+pub struct FileStorage {
+    path: PathBuf,
+    #[cfg(feature = "fs")]
+    ops: Arc<dyn FsOps>,
+}
+
+impl FileStorage {
+    /// Construct a new FileStorage which uses the file at the given path.
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        FileStorage {
+            path: path.as_ref().to_path_buf(),
+            #[cfg(feature = "fs")]
+            ops: real_fs(),
+        }
+    }
+
+    /// Perform all filesystem operations when storing via the given `FsOps`.
+    #[cfg(all(feature = "fs", feature = "testing"))]
+    pub(crate) fn with_fs_ops(mut self, ops: Arc<dyn FsOps>) -> Self {
+        self.ops = ops;
+        self
+    }
+
+    /// Return the path of the file this storage uses.
+    pub fn path(&self) -> &Path {
+        self.path.as_path()
+    }
+}
+
+impl KeyStoreStorage for FileStorage {
+    fn load(&self) -> Result<Option<Vec<u8>>> {
+        match fs::read(self.path.as_path()) {
+            Ok(data) => Ok(match data.is_empty() {
+                true => None,
+                false => Some(data),
+            }),
+            Err(e) => match e.kind() {
+                std::io::ErrorKind::NotFound => Ok(None),
+                _ => Err(e.into()),
+            },
+        }
+    }
+
+    #[cfg(feature = "fs")]
+    fn store(&self, data: &[u8]) -> Result<()> {
+        atomic_write_with(&self.ops, self.path.as_path(), data)
+    }
+
+    #[cfg(not(feature = "fs"))]
+    fn store(&self, data: &[u8]) -> Result<()> {
+        let mut f = fs::File::create(self.path.as_path())?;
+        f.write_all(data)?;
+        Ok(())
+    }
+}
+
+/// MemoryStorage stores a serialized KeyStore in memory. This is mainly useful
+/// for testing, or as an example of implementing `KeyStoreStorage`.
+#[derive(Default)]
+pub struct MemoryStorage {
+    data: Mutex<Option<Vec<u8>>>,
+    stores: AtomicUsize,
+}
+
+impl MemoryStorage {
+    /// Construct a new, empty MemoryStorage.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Construct a new MemoryStorage, which initially contains the given
+    /// serialized KeyStore.
+    pub fn with_data(data: Vec<u8>) -> Self {
+        MemoryStorage {
+            data: Mutex::new(Some(data)),
+            stores: AtomicUsize::new(0),
+        }
+    }
+
+    /// Return a copy of the currently stored data, if any.
+    pub fn data(&self) -> Option<Vec<u8>> {
+        self.data.lock().unwrap().clone()
+    }
+
+    /// Return how many times `store` has been called on this storage.
+    pub fn store_count(&self) -> usize {
+        self.stores.load(Ordering::SeqCst)
+    }
+}
+
+impl KeyStoreStorage for MemoryStorage {
+    fn load(&self) -> Result<Option<Vec<u8>>> {
+        Ok(self.data())
+    }
+
+    fn store(&self, data: &[u8]) -> Result<()> {
+        *self.data.lock().unwrap() = Some(data.to_vec());
+        self.stores.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+/// ManagedKeyStore wraps a KeyStore, dealing with loading it from and
+/// persisting it to some `KeyStoreStorage`. It keeps track of whether or not
+/// the KeyStore has changed since it was last persisted, so `flush` only
+/// writes to the storage when needed.
+///
+/// Unlike `DiskKeyStore`, changes are never persisted implicitly: callers must
+/// call `flush`, so they can handle any errors. Dropping a ManagedKeyStore with
+/// unflushed changes logs a warning.
+///
+/// Optionally, every attempt to open the KeyStore or to add or remove keys can
+/// be recorded in an `AuditLog` (see `with_audit_log`).
+pub struct ManagedKeyStore<S: KeyStoreStorage> {
+    storage: S,
+    inner: KeyStore,
+    dirty: bool,
+    audit_log: Option<AuditLog>,
+}
+
+impl<S: KeyStoreStorage> ManagedKeyStore<S> {
+    /// Construct a new ManagedKeyStore. If the given storage already contains a
+    /// KeyStore, it is loaded. Otherwise, a brand new KeyStore is initialized
+    /// (it is up to the caller to check for this case, and to add a key as
+    /// appropriate).
+    ///
+    /// If `force_overwrite` is set to `true`, then a fresh KeyStore is created,
+    /// even if one was already stored (it replaces the stored one on the next
+    /// `flush`).
+    pub fn new(storage: S, force_overwrite: bool) -> Result<Self> {
+        let existing = match force_overwrite {
+            false => storage.load()?,
+            true => None,
+        };
+        let (inner, dirty) = match existing {
+            None => (KeyStore::new()?, true),
+            Some(data) => (KeyStore::load_slice(data.as_slice())?, false),
+        };
+        Ok(ManagedKeyStore {
+            storage,
+            inner,
+            dirty,
+            audit_log: None,
+        })
+    }
+
+    /// Load an existing KeyStore from the given storage in read-only mode. It
+    /// can be opened and inspected, but every attempt to modify it returns
+    /// `Error::ReadOnlyKeyStore`, so `flush` never writes to the storage. It
+    /// is an error if the storage doesn't contain a KeyStore yet.
+    ///
+    /// Code which doesn't need to go through `ManagedKeyStore` should prefer
+    /// `ReadOnlyKeyStore`, which has no mutating methods at all.
+    pub fn open_read_only(storage: S) -> Result<Self> {
+        let inner = load_read_only(&storage)?;
+        Ok(ManagedKeyStore {
+            storage,
+            inner,
+            dirty: false,
+            audit_log: None,
+        })
+    }
+
+    /// Record every subsequent attempt to open this KeyStore, or to add or
+    /// remove keys, in the given audit log. If an entry can't be appended to
+    /// the log, the operation's result is replaced with that error (although
+    /// the operation itself has already happened, in memory).
+    pub fn with_audit_log(mut self, audit_log: AuditLog) -> Self {
+        self.audit_log = Some(audit_log);
+        self
+    }
+
+    /// Return the audit log this KeyStore's operations are recorded in, if
+    /// any.
+    pub fn audit_log(&self) -> Option<&AuditLog> {
+        self.audit_log.as_ref()
+    }
+
+    /// Verify this KeyStore's audit log. Entries' MACs can only be verified
+    /// if the KeyStore is open; otherwise, only the chain itself is checked.
+    /// It is an error to call this if no audit log was configured.
+    pub fn verify_audit_log(&self) -> Result<AuditReport> {
+        match self.audit_log.as_ref() {
+            None => Err(Error::Precondition(
+                "this KeyStore has no audit log".to_string(),
+            )),
+            Some(audit_log) => audit_log.verify(self.inner.master_key.as_ref()),
+        }
+    }
+
+    fn audit<T, F: FnOnce(&T) -> AuditOutcome>(
+        &self,
+        operation: AuditOperation,
+        wrapping_digest: &Digest,
+        result: Result<T>,
+        outcome: F,
+    ) -> Result<T> {
+        let audit_log = match self.audit_log.as_ref() {
+            None => return result,
+            Some(audit_log) => audit_log,
+        };
+        let outcome = match result.as_ref() {
+            Ok(value) => outcome(value),
+            Err(e) => AuditOutcome::Failure(e.to_string()),
+        };
+        audit_log.append(
+            operation,
+            wrapping_digest,
+            outcome,
+            self.inner.master_key.as_ref(),
+        )?;
+        result
+    }
+
+    /// Return the storage this KeyStore is persisted to.
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    /// Return whether or not this KeyStore has changes which haven't been
+    /// flushed to its storage yet.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Open the KeyStore. See `KeyStore::open`.
+    pub fn open<K: AbstractKey>(&mut self, key: &K) -> Result<()> {
+        if self.inner.is_open() {
+            return Ok(());
+        }
+        let result = self.inner.open(key);
+        self.audit(AuditOperation::Open, &key.get_digest(), result, |_| {
+            AuditOutcome::Success
+        })
+    }
+
+    /// Open the KeyStore, even if the given key has expired. See
+    /// `KeyStore::open_allow_expired`.
+    pub fn open_allow_expired<K: AbstractKey>(&mut self, key: &K) -> Result<()> {
+        if self.inner.is_open() {
+            return Ok(());
+        }
+        let result = self.inner.open_allow_expired(key);
+        self.audit(AuditOperation::Open, &key.get_digest(), result, |_| {
+            AuditOutcome::Success
+        })
+    }
+
+    /// Add the given wrapping key to the KeyStore. See `KeyStore::add_key`.
+    pub fn add_key<K: AbstractKey>(&mut self, key: &K) -> Result<bool> {
+        let result = self.inner.add_key(key);
+        self.dirty |= *result.as_ref().unwrap_or(&false);
+        self.audit(AuditOperation::AddKey, &key.get_digest(), result, changed)
+    }
+
+    /// Add the given wrapping key to the KeyStore, along with the given
+    /// metadata. See `KeyStore::add_key_with_options`.
+    pub fn add_key_with_options<K: AbstractKey>(
+        &mut self,
+        key: &K,
+        options: KeyOptions,
+    ) -> Result<bool> {
+        let result = self.inner.add_key_with_options(key, options);
+        self.dirty |= *result.as_ref().unwrap_or(&false);
+        self.audit(AuditOperation::AddKey, &key.get_digest(), result, changed)
+    }
+
+    /// Remove the given wrapping key from the KeyStore. See
+    /// `KeyStore::remove_key`.
+    pub fn remove_key<K: AbstractKey>(&mut self, key: &K) -> Result<bool> {
+        let result = self.inner.remove_key(key);
+        self.dirty |= *result.as_ref().unwrap_or(&false);
+        self.audit(
+            AuditOperation::RemoveKey,
+            &key.get_digest(),
+            result,
+            changed,
+        )
+    }
+
+    /// Replace the wrapping key `old` with `new`. See `KeyStore::replace_key`.
+    /// Since this is a single in-memory change, the next `flush` persists it
+    /// atomically (as long as the storage's writes are atomic, as
+    /// `FileStorage`'s are).
+    pub fn replace_key<K1: AbstractKey, K2: AbstractKey>(
+        &mut self,
+        old: &K1,
+        new: &K2,
+    ) -> Result<()> {
+        self.replace_key_with_clock(old, new, &SystemClock)
+    }
+
+    /// Replace the wrapping key `old` with `new`, recording the time of the
+    /// replacement from the given clock. See `KeyStore::replace_key_with_clock`.
+    pub fn replace_key_with_clock<K1: AbstractKey, K2: AbstractKey, C: Clock + ?Sized>(
+        &mut self,
+        old: &K1,
+        new: &K2,
+        clock: &C,
+    ) -> Result<()> {
+        let result = self.inner.replace_key_with_clock(old, new, clock);
+        self.dirty |= result.is_ok();
+        self.audit(
+            AuditOperation::ReplaceKey,
+            &old.get_digest(),
+            result,
+            |_| AuditOutcome::Success,
+        )
+    }
+
+    /// Persist the KeyStore to its storage, if it has changed since it was
+    /// loaded or last flushed. It is an error to flush a KeyStore which isn't
+    /// persistable (see `KeyStore::is_persistable`).
+    pub fn flush(&mut self) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        self.inner.check_writable("persist")?;
+        persist_key_store(&self.storage, &self.inner)?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+/// Return the audit outcome for an operation which returns whether or not it
+/// changed anything.
+fn changed(changed: &bool) -> AuditOutcome {
+    match *changed {
+        true => AuditOutcome::Success,
+        false => AuditOutcome::Unchanged,
+    }
+}
+
+impl<S: KeyStoreStorage> Deref for ManagedKeyStore<S> {
+    type Target = KeyStore;
+
+    fn deref(&self) -> &KeyStore {
+        &self.inner
+    }
+}
+
+impl<S: KeyStoreStorage> Drop for ManagedKeyStore<S> {
+    fn drop(&mut self) {
+        if self.dirty && self.inner.is_persistable() {
+            warn!(
+                "KeyStore {} dropped with unflushed changes",
+                self.inner.get_id()
+            );
+        }
+    }
 }
 
 /// DiskKeyStore is a very simple wrapper around KeyStore, which deals with
 /// persisting it to disk. This is provided because it is expected this is a
 /// very common use case, but users of this library can just use KeyStore
 /// directly and persist it however they like.
+///
+/// DiskKeyStore persists the KeyStore when it is dropped, so any errors doing
+/// so can only be logged. New code should generally prefer
+/// `ManagedKeyStore<FileStorage>`, which reports errors from `flush`.
+///
+/// A DiskKeyStore opened with `open_read_only` is never persisted.
 pub struct DiskKeyStore {
-    path: PathBuf,
+    storage: FileStorage,
     inner: KeyStore,
 }
 
@@ -302,7 +1219,7 @@ impl DiskKeyStore {
             .open(path.as_ref())?;
 
         Ok(DiskKeyStore {
-            path: path.as_ref().to_path_buf(),
+            storage: FileStorage::new(path.as_ref()),
             inner: if f.metadata()?.len() == 0 {
                 // If the file was of zero length, just remove it. Most likely
                 // we created it, but if this key store doens't end up being
@@ -314,6 +1231,20 @@ impl DiskKeyStore {
             },
         })
     }
+
+    /// Load an existing key store from the given path in read-only mode. The
+    /// file is only ever opened for reading, so this works even if we don't
+    /// have write access to it. The key store can be opened and inspected,
+    /// but every attempt to modify it returns `Error::ReadOnlyKeyStore`, and
+    /// it is not persisted when dropped.
+    ///
+    /// Code which doesn't need to go through `DiskKeyStore` should prefer
+    /// `ReadOnlyKeyStore`, which has no mutating methods at all.
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let storage = FileStorage::new(path);
+        let inner = load_read_only(&storage)?;
+        Ok(DiskKeyStore { storage, inner })
+    }
 }
 
 impl Deref for DiskKeyStore {
@@ -332,8 +1263,73 @@ impl DerefMut for DiskKeyStore {
 
 impl Drop for DiskKeyStore {
     fn drop(&mut self) {
-        if let Err(e) = persist_key_store(&self.path, &self.inner) {
+        if self.inner.is_read_only() {
+            return;
+        }
+        if let Err(e) = persist_key_store(&self.storage, &self.inner) {
             error!("{} (KeyStore {})", e, self.inner.get_id());
         }
     }
 }
+
+/// ReadOnlyKeyStore is a view of a KeyStore which can be opened and inspected
+/// (e.g. by verification or audit tooling), but never modified or persisted.
+/// Unlike the read-only modes of `DiskKeyStore` and `ManagedKeyStore`, this is
+/// enforced structurally: it only derefs to `&KeyStore`, so none of the
+/// mutating methods can even be called, and it has no storage to write to.
+pub struct ReadOnlyKeyStore {
+    inner: KeyStore,
+}
+
+impl ReadOnlyKeyStore {
+    /// Load an existing KeyStore from the file at the given path. The file is
+    /// only ever opened for reading.
+    pub fn load_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::load(&FileStorage::new(path))
+    }
+
+    /// Load an existing KeyStore from the given storage. It is an error if the
+    /// storage doesn't contain a KeyStore yet.
+    pub fn load<S: KeyStoreStorage + ?Sized>(storage: &S) -> Result<Self> {
+        Ok(ReadOnlyKeyStore {
+            inner: load_read_only(storage)?,
+        })
+    }
+
+    /// Load a previously-serialized (with `KeyStore::to_vec`) KeyStore from a
+    /// byte slice.
+    pub fn load_slice(data: &[u8]) -> Result<Self> {
+        let mut inner = KeyStore::load_slice(data)?;
+        inner.read_only = true;
+        Ok(ReadOnlyKeyStore { inner })
+    }
+
+    /// Open the KeyStore. See `KeyStore::open`.
+    pub fn open<K: AbstractKey>(&mut self, key: &K) -> Result<()> {
+        self.inner.open(key)
+    }
+
+    /// Open the KeyStore, checking key expiry against the given clock. See
+    /// `KeyStore::open_with_clock`.
+    pub fn open_with_clock<K: AbstractKey, C: Clock + ?Sized>(
+        &mut self,
+        key: &K,
+        clock: &C,
+    ) -> Result<()> {
+        self.inner.open_with_clock(key, clock)
+    }
+
+    /// Open the KeyStore, even if the given key has expired. See
+    /// `KeyStore::open_allow_expired`.
+    pub fn open_allow_expired<K: AbstractKey>(&mut self, key: &K) -> Result<()> {
+        self.inner.open_allow_expired(key)
+    }
+}
+
+impl Deref for ReadOnlyKeyStore {
+    type Target = KeyStore;
+
+    fn deref(&self) -> &KeyStore {
+        &self.inner
+    }
+}
@@ -12,6 +12,14 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+/// asymmetric defines public-key "sealed box" encryption, for encrypting data
+/// to a recipient without sharing any secret with them.
+pub mod asymmetric;
+/// audit defines a tamper-evident log of operations performed on a KeyStore.
+pub mod audit;
+/// callback defines an `AbstractKey` implementation backed by user-supplied callbacks, e.g. for
+/// keys which live on external hardware.
+pub mod callback;
 mod compat;
 
 /// digest defines an API for computing cryptographically secure digests of data.
@@ -21,9 +29,18 @@ pub mod digest;
 pub mod key;
 /// keystore defines a structure for persisting a "master key" on disk, via key wrapping.
 pub mod keystore;
+/// password provides password hashing for *verification* (storing a hash of e.g. an administrator
+/// password, and later checking candidate passwords against it), as opposed to key derivation.
+pub mod password;
 /// secret defines a structure for "safely" storing "secret" data in memory. Think things like keys,
 /// plaintext, etc.
 pub mod secret;
+/// stream defines authenticated, chunked encryption of arbitrarily large
+/// streams of data, optionally compressing the data before encrypting it.
+pub mod stream;
+/// totp implements time-based one-time passwords (RFC 6238), as used by
+/// authenticator apps for two-factor authentication.
+pub mod totp;
 /// util provides some trivial crypto-related utility functions.
 pub mod util;
 /// wrap defines utilities for "wrapping" a key with another key. This is useful, for instance, to
@@ -0,0 +1,346 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::crypto::secret::Secret;
+use crate::crypto::util::{constant_time_eq, randombytes_into};
+use crate::error::*;
+use data_encoding::BASE64_NOPAD;
+use halite_sys;
+use libc::{c_char, c_int, c_ulonglong};
+
+/// The length of the random salt generated for each new password hash.
+pub const SALT_BYTES: usize = halite_sys::crypto_pwhash_argon2id_SALTBYTES as usize;
+/// The length of the password hashes produced by `hash`.
+pub const HASH_BYTES: usize = 32;
+
+/// The minimum hash length accepted when parsing an encoded string.
+const MIN_HASH_BYTES: usize = halite_sys::crypto_pwhash_argon2id_BYTES_MIN as usize;
+/// The maximum hash length accepted when parsing an encoded string.
+const MAX_HASH_BYTES: usize = 64;
+
+/// The Argon2 version number we produce and accept (0x13).
+const ARGON2_VERSION: u32 = 19;
+/// libsodium's Argon2id implementation always uses a single lane.
+const ARGON2_PARALLELISM: u32 = 1;
+/// The smallest memory cost libsodium accepts for Argon2id, in KiB.
+const ARGON2_MIN_MEMORY_KIB: u32 = halite_sys::crypto_pwhash_argon2id_MEMLIMIT_MIN / 1024;
+
+/// The algorithm (and its cost parameters) used to hash a password.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PasswordHashParams {
+    /// Argon2id, as implemented by libsodium. This is the recommended algorithm.
+    Argon2id {
+        /// The amount of memory to use, in KiB.
+        memory_kib: u32,
+        /// The number of passes over memory.
+        iterations: u32,
+    },
+    /// scrypt, as implemented by libsodium.
+    Scrypt {
+        /// The base-2 logarithm of the CPU/memory cost parameter N.
+        log_n: u8,
+        /// The block size parameter.
+        r: u32,
+        /// The parallelization parameter.
+        p: u32,
+    },
+}
+
+impl Default for PasswordHashParams {
+    /// The default parameters are libsodium's "interactive" Argon2id limits (64 MiB of memory and
+    /// two passes), which are appropriate for e.g. verifying a login.
+    fn default() -> Self {
+        PasswordHashParams::Argon2id {
+            memory_kib: halite_sys::crypto_pwhash_argon2id_MEMLIMIT_INTERACTIVE / 1024,
+            iterations: halite_sys::crypto_pwhash_argon2id_OPSLIMIT_INTERACTIVE,
+        }
+    }
+}
+
+impl PasswordHashParams {
+    fn algorithm_id(&self) -> &'static str {
+        match *self {
+            PasswordHashParams::Argon2id { .. } => "argon2id",
+            PasswordHashParams::Scrypt { .. } => "scrypt",
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        match *self {
+            PasswordHashParams::Argon2id {
+                memory_kib,
+                iterations,
+            } => {
+                if memory_kib < ARGON2_MIN_MEMORY_KIB {
+                    return Err(Error::InvalidArgument(format!(
+                        "argon2id memory cost must be at least {} KiB",
+                        ARGON2_MIN_MEMORY_KIB
+                    )));
+                }
+                if iterations < 1 {
+                    return Err(Error::InvalidArgument(
+                        "argon2id iteration count must be at least 1".to_string(),
+                    ));
+                }
+            }
+            PasswordHashParams::Scrypt { log_n, r, p } => {
+                if !(1..=63).contains(&log_n) {
+                    return Err(Error::InvalidArgument(
+                        "scrypt log2(N) must be between 1 and 63".to_string(),
+                    ));
+                }
+                if r < 1 || p < 1 {
+                    return Err(Error::InvalidArgument(
+                        "scrypt r and p parameters must be at least 1".to_string(),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns true if these parameters are weaker than `desired` in any respect, or if they use
+    /// a different algorithm entirely.
+    fn is_weaker_than(&self, desired: &PasswordHashParams) -> bool {
+        match (*self, *desired) {
+            (
+                PasswordHashParams::Argon2id {
+                    memory_kib,
+                    iterations,
+                },
+                PasswordHashParams::Argon2id {
+                    memory_kib: desired_memory_kib,
+                    iterations: desired_iterations,
+                },
+            ) => memory_kib < desired_memory_kib || iterations < desired_iterations,
+            (
+                PasswordHashParams::Scrypt { log_n, r, p },
+                PasswordHashParams::Scrypt {
+                    log_n: desired_log_n,
+                    r: desired_r,
+                    p: desired_p,
+                },
+            ) => log_n < desired_log_n || r < desired_r || p < desired_p,
+            _ => true,
+        }
+    }
+}
+
+/// A parsed PHC-format encoded password hash.
+struct EncodedHash {
+    params: PasswordHashParams,
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+impl EncodedHash {
+    fn encode(&self) -> String {
+        let params = match self.params {
+            PasswordHashParams::Argon2id {
+                memory_kib,
+                iterations,
+            } => format!(
+                "v={}$m={},t={},p={}",
+                ARGON2_VERSION, memory_kib, iterations, ARGON2_PARALLELISM
+            ),
+            PasswordHashParams::Scrypt { log_n, r, p } => format!("ln={},r={},p={}", log_n, r, p),
+        };
+        format!(
+            "${}${}${}${}",
+            self.params.algorithm_id(),
+            params,
+            BASE64_NOPAD.encode(&self.salt),
+            BASE64_NOPAD.encode(&self.hash)
+        )
+    }
+
+    fn parse(encoded: &str) -> Result<Self> {
+        let malformed = || Error::InvalidArgument(format!("malformed password hash '{}'", encoded));
+
+        let mut fields = encoded.split('$');
+        if fields.next() != Some("") {
+            return Err(malformed());
+        }
+        let algorithm = fields.next().ok_or_else(malformed)?;
+        let params = match algorithm {
+            "argon2id" => {
+                if fields.next() != Some(&format!("v={}", ARGON2_VERSION)[..]) {
+                    return Err(malformed());
+                }
+                let values =
+                    parse_param_values(fields.next().ok_or_else(malformed)?, &["m", "t", "p"])
+                        .ok_or_else(malformed)?;
+                if values[2] != ARGON2_PARALLELISM as u64 {
+                    return Err(malformed());
+                }
+                PasswordHashParams::Argon2id {
+                    memory_kib: u32::try_from(values[0]).map_err(|_| malformed())?,
+                    iterations: u32::try_from(values[1]).map_err(|_| malformed())?,
+                }
+            }
+            "scrypt" => {
+                let values =
+                    parse_param_values(fields.next().ok_or_else(malformed)?, &["ln", "r", "p"])
+                        .ok_or_else(malformed)?;
+                PasswordHashParams::Scrypt {
+                    log_n: u8::try_from(values[0]).map_err(|_| malformed())?,
+                    r: u32::try_from(values[1]).map_err(|_| malformed())?,
+                    p: u32::try_from(values[2]).map_err(|_| malformed())?,
+                }
+            }
+            _ => {
+                return Err(Error::InvalidArgument(format!(
+                    "unsupported password hash algorithm '{}'",
+                    algorithm
+                )))
+            }
+        };
+
+        let salt = BASE64_NOPAD
+            .decode(fields.next().ok_or_else(malformed)?.as_bytes())
+            .map_err(|_| malformed())?;
+        let hash = BASE64_NOPAD
+            .decode(fields.next().ok_or_else(malformed)?.as_bytes())
+            .map_err(|_| malformed())?;
+        if fields.next().is_some() {
+            return Err(malformed());
+        }
+        if salt.len() < SALT_BYTES || hash.len() < MIN_HASH_BYTES || hash.len() > MAX_HASH_BYTES {
+            return Err(malformed());
+        }
+        params.validate().map_err(|_| malformed())?;
+
+        let parsed = EncodedHash { params, salt, hash };
+        // Only accept the canonical encoding, so strings round-trip exactly (no leading zeros,
+        // "+" signs, etc.).
+        if parsed.encode() != encoded {
+            return Err(malformed());
+        }
+        Ok(parsed)
+    }
+}
+
+/// Parse a comma-separated list of `name=value` pairs, which must have exactly the given names in
+/// exactly the given order.
+fn parse_param_values(s: &str, names: &[&str]) -> Option<Vec<u64>> {
+    let pairs: Vec<&str> = s.split(',').collect();
+    if pairs.len() != names.len() {
+        return None;
+    }
+    pairs
+        .iter()
+        .zip(names.iter())
+        .map(|(pair, name)| {
+            let (k, v) = pair.split_once('=')?;
+            if k != *name || v.is_empty() || !v.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            v.parse::<u64>().ok()
+        })
+        .collect()
+}
+
+fn compute(
+    password: &Secret,
+    params: &PasswordHashParams,
+    salt: &[u8],
+    out: &mut [u8],
+) -> Result<()> {
+    debug_assert!(crate::init_done());
+    let ret: c_int = match *params {
+        PasswordHashParams::Argon2id {
+            memory_kib,
+            iterations,
+        } => {
+            if salt.len() != SALT_BYTES {
+                return Err(Error::InvalidArgument(format!(
+                    "argon2id salts must be exactly {} bytes",
+                    SALT_BYTES
+                )));
+            }
+            unsafe {
+                halite_sys::crypto_pwhash_argon2id(
+                    out.as_mut_ptr(),
+                    out.len() as c_ulonglong,
+                    password.slice_ptr() as *const c_char,
+                    password.len() as c_ulonglong,
+                    salt.as_ptr(),
+                    iterations as c_ulonglong,
+                    memory_kib as usize * 1024,
+                    halite_sys::crypto_pwhash_argon2id_ALG_ARGON2ID13 as c_int,
+                )
+            }
+        }
+        PasswordHashParams::Scrypt { log_n, r, p } => unsafe {
+            halite_sys::crypto_pwhash_scryptsalsa208sha256_ll(
+                password.slice_ptr() as *const u8,
+                password.len(),
+                salt.as_ptr(),
+                salt.len(),
+                1_u64 << log_n,
+                r,
+                p,
+                out.as_mut_ptr(),
+                out.len(),
+            )
+        },
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        // libsodium fails if the parameters are out of range for this platform (e.g. they require
+        // more memory than can be allocated).
+        Err(Error::Crypto(format!(
+            "hashing password with {} failed",
+            params.algorithm_id()
+        )))
+    }
+}
+
+/// Hash the given password with a freshly generated random salt, returning a self-describing
+/// PHC-format string suitable for storing and later passing to `verify`. For example:
+///
+/// ```text
+/// $argon2id$v=19$m=65536,t=2,p=1$<salt>$<hash>
+/// $scrypt$ln=14,r=8,p=1$<salt>$<hash>
+/// ```
+///
+/// The salt and hash are unpadded standard base64. Argon2id strings produced here are also
+/// accepted by libsodium's `crypto_pwhash_str_verify`.
+pub fn hash(password: &Secret, params: PasswordHashParams) -> Result<String> {
+    params.validate()?;
+    let mut salt = vec![0; SALT_BYTES];
+    randombytes_into(&mut salt);
+    let mut hash = vec![0; HASH_BYTES];
+    compute(password, &params, &salt, &mut hash)?;
+    Ok(EncodedHash { params, salt, hash }.encode())
+}
+
+/// Check whether the given password matches the given encoded hash (as returned by `hash`). The
+/// hashes are compared in constant time. An error is returned if the encoded hash is malformed or
+/// uses an unknown algorithm, rather than treating it as a mismatch.
+pub fn verify(password: &Secret, encoded: &str) -> Result<bool> {
+    let parsed = EncodedHash::parse(encoded)?;
+    let mut hash = vec![0; parsed.hash.len()];
+    compute(password, &parsed.params, &parsed.salt, &mut hash)?;
+    Ok(constant_time_eq(&hash, &parsed.hash))
+}
+
+/// Returns true if the given encoded hash was computed with a different algorithm, or weaker
+/// parameters, than `desired`. Applications can use this after a successful `verify` to
+/// transparently upgrade stored hashes as parameters are increased over time.
+pub fn needs_rehash(encoded: &str, desired: &PasswordHashParams) -> Result<bool> {
+    let parsed = EncodedHash::parse(encoded)?;
+    Ok(parsed.params.is_weaker_than(desired) || parsed.hash.len() < HASH_BYTES)
+}
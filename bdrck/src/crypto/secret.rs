@@ -12,8 +12,19 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::error::Result;
+use crate::crypto::digest::Digest;
+use crate::error::{Error, Result};
+use crate::testing::clock::{Clock, SystemClock};
+use halite_sys;
 use libc::{c_int, c_long, c_void};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
 use tracing::error;
 
 // Not included in the libc crate yet, so hardcode it here.
@@ -69,6 +80,26 @@ fn close(fd: c_int) -> Result<()> {
     Ok(())
 }
 
+/// Zero out the given buffer, in a way the compiler won't optimize away.
+pub(crate) fn zeroize(buf: &mut [u8]) {
+    unsafe {
+        halite_sys::sodium_memzero(buf.as_mut_ptr() as *mut c_void, buf.len());
+    }
+}
+
+/// LoadOptions controls how secrets are loaded from files or from the
+/// environment (e.g. by `Secret::from_file_with_options`).
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct LoadOptions {
+    /// By default, files which are readable by their group or by other users
+    /// are rejected. Set this to true to allow them anyway.
+    pub allow_insecure_permissions: bool,
+    /// By default, environment variables are removed from the process's
+    /// environment after being read, so child processes don't inherit them.
+    /// Set this to true to leave them in place instead.
+    pub keep_env: bool,
+}
+
 /// Secret is somewhat like a Vec<u8>, but for sensitive data. It guarantees that its contents
 /// won't be swapped out, and it also guarantees that the contents won't be visible to any other
 /// process, or even the kernel.
@@ -199,6 +230,125 @@ impl Secret {
         std::slice::from_raw_parts_mut(self.slice_ptr(), self.len)
     }
 
+    /// Create a new Secret containing a copy of the given bytes. The caller is
+    /// responsible for zeroing `data` afterwards, if needed.
+    pub(crate) fn from_slice(data: &[u8]) -> Result<Self> {
+        let mut s = Secret::with_len(data.len())?;
+        unsafe { s.as_mut_slice().copy_from_slice(data) }
+        Ok(s)
+    }
+
+    /// Read a Secret from the file at the given path, with the default
+    /// `LoadOptions`. See `from_file_with_options`.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_file_with_options(path, &LoadOptions::default())
+    }
+
+    /// Read a Secret from the file at the given path. A single trailing
+    /// newline (if any) is stripped, since most tools used to write such
+    /// files add one. Unless the given options say otherwise, files which
+    /// are readable by anyone other than their owner are rejected.
+    pub fn from_file_with_options<P: AsRef<Path>>(path: P, options: &LoadOptions) -> Result<Self> {
+        let path = path.as_ref();
+        let mut file = fs::File::open(path)?;
+        let metadata = file.metadata()?;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let mode = metadata.permissions().mode();
+            if !options.allow_insecure_permissions && mode & 0o044 != 0 {
+                return Err(Error::Precondition(format!(
+                    "refusing to read secret from '{}' with insecure permissions {:o}",
+                    path.display(),
+                    mode & 0o777
+                )));
+            }
+        }
+
+        // Preallocate, so the buffer (hopefully) isn't reallocated while we
+        // read, which would leave unzeroed copies of the secret behind.
+        let mut buf: Vec<u8> = Vec::with_capacity(metadata.len() as usize + 1);
+        let ret = file
+            .read_to_end(&mut buf)
+            .map_err(Error::from)
+            .and_then(|_| {
+                let mut data: &[u8] = buf.as_slice();
+                if let Some(stripped) = data.strip_suffix(b"\n") {
+                    data = stripped.strip_suffix(b"\r").unwrap_or(stripped);
+                }
+                Self::from_slice(data)
+            });
+        zeroize(buf.as_mut_slice());
+        ret
+    }
+
+    /// Read a Secret from the given environment variable, with the default
+    /// `LoadOptions`. See `from_env_with_options`.
+    pub fn from_env(var: &str) -> Result<Self> {
+        Self::from_env_with_options(var, &LoadOptions::default())
+    }
+
+    /// Read a Secret from the given environment variable. Unless the given
+    /// options say otherwise, the variable is then removed from this process's
+    /// environment, so child processes don't inherit it.
+    pub fn from_env_with_options(var: &str, options: &LoadOptions) -> Result<Self> {
+        let value = match env::var_os(var) {
+            None => {
+                return Err(Error::NotFound(format!(
+                    "environment variable '{}' is not set",
+                    var
+                )))
+            }
+            Some(value) => value,
+        };
+        if !options.keep_env {
+            env::remove_var(var);
+        }
+
+        #[cfg(unix)]
+        let mut buf = {
+            use std::os::unix::ffi::OsStringExt;
+            value.into_vec()
+        };
+        #[cfg(not(unix))]
+        let mut buf = value
+            .into_string()
+            .map_err(|_| {
+                Error::InvalidArgument(format!(
+                    "environment variable '{}' is not valid unicode",
+                    var
+                ))
+            })?
+            .into_bytes();
+
+        let ret = Self::from_slice(buf.as_slice());
+        zeroize(buf.as_mut_slice());
+        ret
+    }
+
+    /// Load a Secret using the common "file or environment" convention, with
+    /// the default `LoadOptions`. See `from_file_or_env_with_options`.
+    pub fn from_file_or_env(file_var: &str, env_var: &str) -> Result<Self> {
+        Self::from_file_or_env_with_options(file_var, env_var, &LoadOptions::default())
+    }
+
+    /// Load a Secret using the common "file or environment" convention: if the
+    /// environment variable `file_var` is set (e.g. `MYAPP_TOKEN_FILE`), the
+    /// secret is read from the file it names. Otherwise, the secret is read
+    /// directly from the environment variable `env_var` (e.g. `MYAPP_TOKEN`).
+    pub fn from_file_or_env_with_options(
+        file_var: &str,
+        env_var: &str,
+        options: &LoadOptions,
+    ) -> Result<Self> {
+        match env::var_os(file_var) {
+            Some(path) if !path.is_empty() => Self::from_file_with_options(path, options),
+            _ => Self::from_env_with_options(env_var, options),
+        }
+    }
+
     /// Try to copy this Secret's contents into a new Secret.
     pub fn try_clone(&self) -> Result<Self> {
         let mut other = Secret::with_len(self.len())?;
@@ -209,3 +359,244 @@ impl Secret {
 
 unsafe impl Send for Secret {}
 unsafe impl Sync for Secret {}
+
+/// The default maximum number of entries a `SecretCache` holds.
+pub const DEFAULT_SECRET_CACHE_ENTRIES: usize = 16;
+
+/// Options which control the behavior of a `SecretCache`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SecretCacheOptions {
+    /// The maximum number of entries the cache holds. When a new entry is
+    /// added to a full cache, the least recently used entry is evicted.
+    pub max_entries: usize,
+    /// If true, each successful `get` restarts the entry's TTL, so secrets
+    /// which are used regularly stay cached.
+    pub refresh_on_get: bool,
+}
+
+impl Default for SecretCacheOptions {
+    fn default() -> Self {
+        SecretCacheOptions {
+            max_entries: DEFAULT_SECRET_CACHE_ENTRIES,
+            refresh_on_get: false,
+        }
+    }
+}
+
+struct SecretCacheEntry {
+    secret: Secret,
+    ttl: Duration,
+    expires: Instant,
+    /// When this entry was last used, for LRU eviction. This is a counter
+    /// rather than a time, so ordering is well defined even if the clock
+    /// doesn't move.
+    last_used: u64,
+}
+
+impl Drop for SecretCacheEntry {
+    fn drop(&mut self) {
+        zeroize(unsafe { self.secret.as_mut_slice() });
+    }
+}
+
+struct SecretCacheState {
+    entries: HashMap<String, SecretCacheEntry>,
+    counter: u64,
+}
+
+impl SecretCacheState {
+    fn touch(&mut self) -> u64 {
+        self.counter += 1;
+        self.counter
+    }
+
+    fn clear_expired(&mut self, now: Instant) -> usize {
+        let before = self.entries.len();
+        self.entries.retain(|_, entry| entry.expires > now);
+        before - self.entries.len()
+    }
+}
+
+/// SecretCache holds secrets (e.g. keys derived from passphrases) in memory
+/// for a limited time, so interactive programs can avoid asking the user for
+/// the same secret over and over. Secrets are zeroed as soon as they expire
+/// (expiry is checked whenever the cache is accessed, or periodically by
+/// `start_sweeper`), are evicted, or the cache is cleared or dropped.
+///
+/// Caching is strictly opt-in, and entries never outlive the process.
+pub struct SecretCache {
+    state: Mutex<SecretCacheState>,
+    options: SecretCacheOptions,
+    clock: Arc<dyn Clock>,
+}
+
+impl SecretCache {
+    /// Construct a new, empty cache with the given options.
+    pub fn new(options: SecretCacheOptions) -> Self {
+        Self::with_clock(options, Arc::new(SystemClock))
+    }
+
+    /// This is identical to `new`, except TTLs are measured with the given
+    /// `Clock` (e.g. a `MockClock` in tests).
+    pub fn with_clock(options: SecretCacheOptions, clock: Arc<dyn Clock>) -> Self {
+        SecretCache {
+            state: Mutex::new(SecretCacheState {
+                entries: HashMap::new(),
+                counter: 0,
+            }),
+            options,
+            clock,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, SecretCacheState> {
+        let mut state = self.state.lock().unwrap();
+        state.clear_expired(self.clock.now_instant());
+        state
+    }
+
+    /// Add the given secret to the cache with the given ID, replacing (and
+    /// zeroing) any existing secret with the same ID. It expires after the
+    /// given TTL.
+    pub fn put(&self, id: &str, secret: Secret, ttl: Duration) {
+        if self.options.max_entries == 0 {
+            return;
+        }
+        let now = self.clock.now_instant();
+        let mut state = self.lock();
+        if !state.entries.contains_key(id) && state.entries.len() >= self.options.max_entries {
+            let lru = state
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(id, _)| id.clone());
+            if let Some(lru) = lru {
+                state.entries.remove(&lru);
+            }
+        }
+
+        let last_used = state.touch();
+        state.entries.insert(
+            id.to_owned(),
+            SecretCacheEntry {
+                secret,
+                ttl,
+                expires: now + ttl,
+                last_used,
+            },
+        );
+    }
+
+    /// Return a copy of the secret with the given ID, if it is cached and
+    /// hasn't expired yet.
+    pub fn get(&self, id: &str) -> Result<Option<Secret>> {
+        let now = self.clock.now_instant();
+        let mut state = self.lock();
+        let last_used = state.touch();
+        let entry = match state.entries.get_mut(id) {
+            None => return Ok(None),
+            Some(entry) => entry,
+        };
+        entry.last_used = last_used;
+        if self.options.refresh_on_get {
+            entry.expires = now + entry.ttl;
+        }
+        Ok(Some(entry.secret.try_clone()?))
+    }
+
+    /// Return the cached secret with the given ID as per `get`, or else call
+    /// the given function to obtain it (e.g. by prompting the user), caching
+    /// the result with the given TTL.
+    pub fn get_or_insert_with<F: FnOnce() -> Result<Secret>>(
+        &self,
+        id: &str,
+        ttl: Duration,
+        f: F,
+    ) -> Result<Secret> {
+        if let Some(secret) = self.get(id)? {
+            return Ok(secret);
+        }
+        let secret = f()?;
+        self.put(id, secret.try_clone()?, ttl);
+        Ok(secret)
+    }
+
+    /// Return whether or not a secret with the given ID is cached and hasn't
+    /// expired yet.
+    pub fn contains(&self, id: &str) -> bool {
+        self.lock().entries.contains_key(id)
+    }
+
+    /// Return the number of unexpired entries in the cache.
+    pub fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    /// Return whether or not the cache has no unexpired entries.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Return the fingerprint (see `Digest::fingerprint`) of the secret with
+    /// the given ID, if it is cached and hasn't expired yet. This is useful
+    /// for e.g. debugging which secret is cached, without revealing it.
+    pub fn fingerprint(&self, id: &str) -> Option<String> {
+        self.lock()
+            .entries
+            .get(id)
+            .map(|entry| Digest::from_secret(&entry.secret).fingerprint())
+    }
+
+    /// Remove (and zero) every entry in the cache.
+    pub fn clear(&self) {
+        self.state.lock().unwrap().entries.clear();
+    }
+
+    /// Remove (and zero) every entry which has expired, returning how many
+    /// entries were removed. Expired entries are never returned in any case,
+    /// but this makes sure they don't linger in memory.
+    pub fn clear_expired(&self) -> usize {
+        self.state
+            .lock()
+            .unwrap()
+            .clear_expired(self.clock.now_instant())
+    }
+
+    /// Start a background thread which calls `clear_expired` at the given
+    /// interval, until the returned handle is dropped (or the cache itself is
+    /// dropped). Note that the interval is measured in real time, regardless
+    /// of this cache's `Clock`.
+    pub fn start_sweeper(self: &Arc<Self>, interval: Duration) -> SecretCacheSweeper {
+        let cache = Arc::downgrade(self);
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = thread::spawn(move || {
+            while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                match cache.upgrade() {
+                    None => break,
+                    Some(cache) => cache.clear_expired(),
+                };
+            }
+        });
+        SecretCacheSweeper {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+}
+
+/// A handle to a `SecretCache`'s background sweeper thread (see
+/// `SecretCache::start_sweeper`), which stops it when dropped.
+pub struct SecretCacheSweeper {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Drop for SecretCacheSweeper {
+    fn drop(&mut self) {
+        // Dropping the sender wakes the thread up immediately.
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,1027 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+#[cfg(feature = "fs")]
+use crate::crypto::digest::{Digest, DigestBuilder};
+use crate::crypto::key::{AbstractKey, Key, Nonce};
+use crate::crypto::secret::Secret;
+use crate::error::*;
+use halite_sys;
+use libc::c_ulonglong;
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+#[cfg(feature = "fs")]
+use std::io::{Seek, SeekFrom};
+use std::mem::{size_of, MaybeUninit};
+#[cfg(feature = "fs")]
+use std::path::Path;
+#[cfg(feature = "fs")]
+use std::time::SystemTime;
+
+/// The magic bytes every stream starts with.
+const MAGIC: &[u8; 8] = b"BDRCKSTR";
+/// The current stream format version.
+const VERSION: u8 = 1;
+/// The length of the stream prefix (magic, version, and compression id).
+/// This prefix is authenticated as additional data on every chunk.
+const PREFIX_BYTES: usize = MAGIC.len() + 2;
+/// The length of the libsodium secretstream header.
+const HEADER_BYTES: usize = halite_sys::crypto_secretstream_xchacha20poly1305_HEADERBYTES as usize;
+/// The per-chunk overhead added by encryption.
+const ABYTES: usize = halite_sys::crypto_secretstream_xchacha20poly1305_ABYTES as usize;
+
+/// The maximum number of plaintext bytes in a single encrypted chunk.
+pub const CHUNK_BYTES: usize = 64 * 1024;
+
+type StreamState = halite_sys::crypto_secretstream_xchacha20poly1305_state;
+
+/// Compression identifies how plaintext is compressed before being encrypted.
+/// The choice is recorded in the (authenticated) stream header, so readers
+/// know how to decompress the data, and tampering with it is detected.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Compression {
+    /// The plaintext is encrypted as-is.
+    None,
+    /// The plaintext is compressed with DEFLATE. Sealing or opening streams
+    /// using this mode requires the `flate2` feature.
+    Deflate,
+}
+
+impl Compression {
+    fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Deflate => 1,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Deflate),
+            _ => None,
+        }
+    }
+}
+
+fn prefix(compression: Compression) -> [u8; PREFIX_BYTES] {
+    let mut prefix = [0; PREFIX_BYTES];
+    prefix[..MAGIC.len()].copy_from_slice(MAGIC);
+    prefix[MAGIC.len()] = VERSION;
+    prefix[MAGIC.len() + 1] = compression.id();
+    prefix
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// State wraps libsodium's secretstream state, making sure it is zeroed out
+/// once we're done with it.
+struct State(Box<StreamState>);
+
+impl State {
+    fn new() -> Self {
+        State(Box::new(unsafe { MaybeUninit::zeroed().assume_init() }))
+    }
+}
+
+impl Drop for State {
+    fn drop(&mut self) {
+        let state: *mut StreamState = &mut *self.0;
+        unsafe {
+            halite_sys::sodium_memzero(state.cast(), size_of::<StreamState>());
+        }
+    }
+}
+
+/// ResumeState records how far an `EncryptWriter` has gotten, so an
+/// interrupted stream can be continued later with `EncryptWriter::resume`.
+///
+/// The secretstream state it contains is itself encrypted with the stream's
+/// key, so this can be safely persisted (e.g. next to the partial output).
+/// Resuming produces exactly the same output as an uninterrupted run would
+/// have, so the resulting stream is read by `DecryptReader` as usual.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ResumeState {
+    header: Vec<u8>,
+    chunks: u64,
+    nonce: Nonce,
+    sealed_state: Vec<u8>,
+}
+
+impl ResumeState {
+    /// Return the number of complete chunks which had been written.
+    pub fn chunks(&self) -> u64 {
+        self.chunks
+    }
+
+    /// Return how many bytes of plaintext those chunks contain. This is where
+    /// the input should be resumed from.
+    pub fn plaintext_offset(&self) -> u64 {
+        self.chunks * CHUNK_BYTES as u64
+    }
+
+    /// Return how many bytes of output had been written. Any output past this
+    /// point should be discarded before resuming.
+    pub fn ciphertext_offset(&self) -> u64 {
+        (PREFIX_BYTES + HEADER_BYTES) as u64
+            + self.chunks * (size_of::<u32>() + CHUNK_BYTES + ABYTES) as u64
+    }
+}
+
+/// The length of the plaintext sealed in a `ResumeState`: the raw
+/// secretstream state, followed by the stream header and chunk count, so a
+/// sealed state can't be paired with some other header or position.
+const SEALED_STATE_BYTES: usize = size_of::<StreamState>() + HEADER_BYTES + size_of::<u64>();
+
+/// EncryptWriter encrypts everything written to it with a `Key`, writing the
+/// resulting stream to an inner writer. Data is split into chunks of at most
+/// `CHUNK_BYTES`, each of which is authenticated individually, so streams of
+/// any size can be processed in constant memory.
+///
+/// `finish` must be called once all data has been written. Otherwise, the
+/// stream is missing its final chunk, and readers will reject it as truncated.
+pub struct EncryptWriter<W: Write> {
+    inner: Option<W>,
+    state: State,
+    prefix: [u8; PREFIX_BYTES],
+    header: [u8; HEADER_BYTES],
+    chunks: u64,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    /// Start a new encrypted stream, writing its header to the given writer.
+    pub fn new(key: &Key, inner: W) -> Result<Self> {
+        Self::with_compression(key, inner, Compression::None)
+    }
+
+    /// Start a new encrypted stream, whose header records the given
+    /// compression mode. The data written to this writer is expected to
+    /// already have been compressed accordingly.
+    fn with_compression(key: &Key, mut inner: W, compression: Compression) -> Result<Self> {
+        let prefix = prefix(compression);
+        let mut state = State::new();
+        let mut header = [0; HEADER_BYTES];
+        debug_assert!(crate::init_done());
+        if unsafe {
+            halite_sys::crypto_secretstream_xchacha20poly1305_init_push(
+                &mut *state.0,
+                header.as_mut_ptr(),
+                key.as_secret().slice_ptr(),
+            )
+        } != 0
+        {
+            return Err(Error::Crypto(
+                "initializing encrypted stream failed".to_string(),
+            ));
+        }
+
+        inner.write_all(&prefix)?;
+        inner.write_all(&header)?;
+        Ok(EncryptWriter {
+            inner: Some(inner),
+            state,
+            prefix,
+            header,
+            chunks: 0,
+            buf: Vec::with_capacity(CHUNK_BYTES),
+        })
+    }
+
+    /// Continue a stream which was interrupted, from the given state (see
+    /// `resume_state`). The given writer must already contain exactly the
+    /// first `state.ciphertext_offset()` bytes of the stream, and the caller
+    /// should continue by writing the plaintext from `state.plaintext_offset()`
+    /// onwards.
+    ///
+    /// A state which was exported with some other key, or which has been
+    /// tampered with, is rejected.
+    ///
+    /// Each state must be resumed at most once, and only to write the same
+    /// plaintext which originally followed it. The state includes the
+    /// stream's nonce, so resuming it twice with different plaintext (e.g.
+    /// from a copy of an old checkpoint, or after the input has changed)
+    /// reuses that nonce, which breaks the confidentiality of both versions.
+    pub fn resume(key: &Key, state: &ResumeState, inner: W) -> Result<Self> {
+        let mismatch = || {
+            Error::InvalidArgument(
+                "resume state is corrupt or doesn't match the given key".to_string(),
+            )
+        };
+        let plaintext = key
+            .decrypt(Some(&state.nonce), &state.sealed_state)
+            .map_err(|_| mismatch())?;
+        if plaintext.len() != SEALED_STATE_BYTES {
+            return Err(mismatch());
+        }
+        let plaintext = unsafe { plaintext.as_slice() };
+        let (raw_state, rest) = plaintext.split_at(size_of::<StreamState>());
+        let (header, chunks) = rest.split_at(HEADER_BYTES);
+        if header != state.header.as_slice() || chunks != state.chunks.to_be_bytes().as_slice() {
+            return Err(mismatch());
+        }
+
+        let mut restored = State::new();
+        let restored_ptr: *mut StreamState = &mut *restored.0;
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                raw_state.as_ptr(),
+                restored_ptr.cast::<u8>(),
+                size_of::<StreamState>(),
+            );
+        }
+        let mut header = [0; HEADER_BYTES];
+        header.copy_from_slice(&state.header);
+        Ok(EncryptWriter {
+            inner: Some(inner),
+            state: restored,
+            prefix: prefix(Compression::None),
+            header,
+            chunks: state.chunks,
+            buf: Vec::with_capacity(CHUNK_BYTES),
+        })
+    }
+
+    /// Flush the inner writer, and then return the state needed to resume
+    /// this stream from the last complete chunk written so far. Any buffered
+    /// data past that point isn't included, so it must be written again after
+    /// resuming. The state is encrypted with the given key, which must be the
+    /// one this stream is being encrypted with.
+    pub fn resume_state(&mut self, key: &Key) -> Result<ResumeState> {
+        self.flush()?;
+
+        let raw_state: *const StreamState = &*self.state.0;
+        let mut plaintext = Vec::with_capacity(SEALED_STATE_BYTES);
+        plaintext.extend_from_slice(unsafe {
+            std::slice::from_raw_parts(raw_state.cast::<u8>(), size_of::<StreamState>())
+        });
+        plaintext.extend_from_slice(&self.header);
+        plaintext.extend_from_slice(&self.chunks.to_be_bytes());
+        let secret = Secret::from_slice(&plaintext);
+        crate::crypto::secret::zeroize(&mut plaintext);
+
+        let (nonce, sealed_state) = key.encrypt(&secret?, None)?;
+        Ok(ResumeState {
+            header: self.header.to_vec(),
+            chunks: self.chunks,
+            // Our keys always use (and return) a nonce.
+            nonce: nonce.unwrap(),
+            sealed_state,
+        })
+    }
+
+    /// Return the number of complete chunks written to the inner writer so
+    /// far (not counting the final chunk written by `finish`).
+    pub fn chunks_written(&self) -> u64 {
+        self.chunks
+    }
+
+    /// Returns a reference to the inner writer.
+    pub fn get_ref(&self) -> &W {
+        // The inner writer is only taken in `finish`, which consumes self.
+        self.inner.as_ref().unwrap()
+    }
+
+    fn push(&mut self, len: usize, tag: u8) -> io::Result<()> {
+        let mut ciphertext = vec![0; len + ABYTES];
+        debug_assert!(crate::init_done());
+        if unsafe {
+            halite_sys::crypto_secretstream_xchacha20poly1305_push(
+                &mut *self.state.0,
+                ciphertext.as_mut_ptr(),
+                std::ptr::null_mut(),
+                self.buf.as_ptr(),
+                len as c_ulonglong,
+                self.prefix.as_ptr(),
+                PREFIX_BYTES as c_ulonglong,
+                tag,
+            )
+        } != 0
+        {
+            return Err(io::Error::other("encrypting stream chunk failed"));
+        }
+
+        crate::crypto::secret::zeroize(&mut self.buf[..len]);
+        self.buf.drain(..len);
+        if tag == halite_sys::crypto_secretstream_xchacha20poly1305_TAG_MESSAGE as u8 {
+            self.chunks += 1;
+        }
+
+        // The inner writer is only taken in `finish`, which consumes self.
+        let inner = self.inner.as_mut().unwrap();
+        inner.write_all(&(ciphertext.len() as u32).to_be_bytes())?;
+        inner.write_all(&ciphertext)
+    }
+
+    /// Encrypt any remaining buffered data as the final chunk of the stream,
+    /// and return the inner writer.
+    pub fn finish(mut self) -> Result<W> {
+        let len = self.buf.len();
+        self.push(
+            len,
+            halite_sys::crypto_secretstream_xchacha20poly1305_TAG_FINAL as u8,
+        )?;
+        let mut inner = self.inner.take().unwrap();
+        inner.flush()?;
+        Ok(inner)
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Always keep at least one byte buffered, so the final chunk (which
+        // is only written by `finish`) is never empty unless the whole stream
+        // is.
+        let len = buf.len().min(CHUNK_BYTES + 1 - self.buf.len());
+        self.buf.extend_from_slice(&buf[..len]);
+        if self.buf.len() > CHUNK_BYTES {
+            self.push(
+                CHUNK_BYTES,
+                halite_sys::crypto_secretstream_xchacha20poly1305_TAG_MESSAGE as u8,
+            )?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Partial chunks are only written by `finish`, so just flush whatever
+        // complete chunks we've written so far.
+        self.inner.as_mut().unwrap().flush()
+    }
+}
+
+impl<W: Write> Drop for EncryptWriter<W> {
+    fn drop(&mut self) {
+        crate::crypto::secret::zeroize(self.buf.as_mut_slice());
+    }
+}
+
+/// DecryptReader reads and authenticates a stream written by
+/// `EncryptWriter`, returning the original plaintext. Truncated streams,
+/// streams with trailing data, and any modified chunks are rejected with an
+/// error.
+///
+/// Note that plaintext is returned chunk-by-chunk as each chunk is
+/// authenticated, so callers should not act on the data until the reader has
+/// returned EOF.
+pub struct DecryptReader<R: Read> {
+    inner: R,
+    state: State,
+    prefix: [u8; PREFIX_BYTES],
+    buf: Vec<u8>,
+    pos: usize,
+    finished: bool,
+}
+
+impl<R: Read> DecryptReader<R> {
+    /// Start reading an encrypted stream, by reading and validating its
+    /// header from the given reader. Streams containing compressed data (see
+    /// `SealWriter`) must be read with `OpenReader` instead.
+    pub fn new(key: &Key, inner: R) -> Result<Self> {
+        let reader = Self::open(key, inner)?;
+        if reader.compression() != Compression::None {
+            return Err(Error::InvalidArgument(
+                "encrypted stream is compressed; read it with OpenReader instead".to_string(),
+            ));
+        }
+        Ok(reader)
+    }
+
+    fn open(key: &Key, mut inner: R) -> Result<Self> {
+        let mut prefix = [0; PREFIX_BYTES];
+        inner.read_exact(&mut prefix)?;
+        if &prefix[..MAGIC.len()] != MAGIC {
+            return Err(Error::InvalidArgument(
+                "input is not an encrypted stream".to_string(),
+            ));
+        }
+        if prefix[MAGIC.len()] != VERSION {
+            return Err(Error::Unsupported(format!(
+                "unsupported encrypted stream version {}",
+                prefix[MAGIC.len()]
+            )));
+        }
+        if Compression::from_id(prefix[MAGIC.len() + 1]).is_none() {
+            return Err(Error::InvalidArgument(format!(
+                "unknown encrypted stream compression id {}",
+                prefix[MAGIC.len() + 1]
+            )));
+        }
+
+        let mut header = [0; HEADER_BYTES];
+        inner.read_exact(&mut header)?;
+        let mut state = State::new();
+        debug_assert!(crate::init_done());
+        if unsafe {
+            halite_sys::crypto_secretstream_xchacha20poly1305_init_pull(
+                &mut *state.0,
+                header.as_ptr(),
+                key.as_secret().slice_ptr(),
+            )
+        } != 0
+        {
+            return Err(Error::InvalidArgument(
+                "invalid encrypted stream header".to_string(),
+            ));
+        }
+
+        Ok(DecryptReader {
+            inner,
+            state,
+            prefix,
+            buf: Vec::new(),
+            pos: 0,
+            finished: false,
+        })
+    }
+
+    /// Return the compression mode recorded in this stream's header.
+    pub fn compression(&self) -> Compression {
+        // We validated the id when reading the header.
+        Compression::from_id(self.prefix[MAGIC.len() + 1]).unwrap()
+    }
+
+    fn pull(&mut self) -> io::Result<()> {
+        let mut len = [0; 4];
+        self.inner
+            .read_exact(&mut len)
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::UnexpectedEof => invalid_data("encrypted stream is truncated"),
+                _ => e,
+            })?;
+        let len = u32::from_be_bytes(len) as usize;
+        if !(ABYTES..=CHUNK_BYTES + ABYTES).contains(&len) {
+            return Err(invalid_data(format!(
+                "invalid encrypted stream chunk length {}",
+                len
+            )));
+        }
+
+        let mut ciphertext = vec![0; len];
+        self.inner
+            .read_exact(&mut ciphertext)
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::UnexpectedEof => invalid_data("encrypted stream is truncated"),
+                _ => e,
+            })?;
+
+        crate::crypto::secret::zeroize(self.buf.as_mut_slice());
+        self.buf.resize(len - ABYTES, 0);
+        self.pos = 0;
+        let mut tag = 0;
+        debug_assert!(crate::init_done());
+        if unsafe {
+            halite_sys::crypto_secretstream_xchacha20poly1305_pull(
+                &mut *self.state.0,
+                self.buf.as_mut_ptr(),
+                std::ptr::null_mut(),
+                &mut tag,
+                ciphertext.as_ptr(),
+                len as c_ulonglong,
+                self.prefix.as_ptr(),
+                PREFIX_BYTES as c_ulonglong,
+            )
+        } != 0
+        {
+            self.buf.clear();
+            return Err(invalid_data(
+                "failed to authenticate encrypted stream chunk",
+            ));
+        }
+
+        if tag == halite_sys::crypto_secretstream_xchacha20poly1305_TAG_FINAL as u8 {
+            self.finished = true;
+            let mut extra = [0; 1];
+            if self.inner.read(&mut extra)? != 0 {
+                return Err(invalid_data(
+                    "unexpected trailing data after encrypted stream",
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buf.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            self.pull()?;
+        }
+
+        let len = buf.len().min(self.buf.len() - self.pos);
+        buf[..len].copy_from_slice(&self.buf[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+impl<R: Read> Drop for DecryptReader<R> {
+    fn drop(&mut self) {
+        crate::crypto::secret::zeroize(self.buf.as_mut_slice());
+    }
+}
+
+enum SealInner<W: Write> {
+    None(EncryptWriter<W>),
+    #[cfg(feature = "flate2")]
+    Deflate(flate2::write::DeflateEncoder<EncryptWriter<W>>),
+}
+
+/// SealWriter compresses and then encrypts everything written to it. The
+/// compression mode is recorded in the authenticated stream header, so
+/// `OpenReader` can undo both steps in the right order.
+///
+/// Streams sealed with `Compression::None` use exactly the same format as
+/// `EncryptWriter`. As with `EncryptWriter`, `finish` must be called once all
+/// data has been written.
+pub struct SealWriter<W: Write> {
+    inner: SealInner<W>,
+}
+
+impl<W: Write> SealWriter<W> {
+    /// Start a new sealed stream, writing its header to the given writer.
+    /// Using `Compression::Deflate` without the `flate2` feature enabled
+    /// results in an error.
+    pub fn new(key: &Key, inner: W, compression: Compression) -> Result<Self> {
+        Ok(SealWriter {
+            inner: match compression {
+                Compression::None => SealInner::None(EncryptWriter::new(key, inner)?),
+                #[cfg(feature = "flate2")]
+                Compression::Deflate => SealInner::Deflate(flate2::write::DeflateEncoder::new(
+                    EncryptWriter::with_compression(key, inner, compression)?,
+                    flate2::Compression::default(),
+                )),
+                #[cfg(not(feature = "flate2"))]
+                Compression::Deflate => {
+                    return Err(Error::Unsupported(
+                        "DEFLATE compression requires the flate2 feature".to_string(),
+                    ))
+                }
+            },
+        })
+    }
+
+    /// Flush any remaining compressed data, encrypt it as the final chunk of
+    /// the stream, and return the inner writer.
+    pub fn finish(self) -> Result<W> {
+        match self.inner {
+            SealInner::None(w) => w.finish(),
+            #[cfg(feature = "flate2")]
+            SealInner::Deflate(w) => w.finish()?.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for SealWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.inner {
+            SealInner::None(ref mut w) => w.write(buf),
+            #[cfg(feature = "flate2")]
+            SealInner::Deflate(ref mut w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.inner {
+            SealInner::None(ref mut w) => w.flush(),
+            #[cfg(feature = "flate2")]
+            SealInner::Deflate(ref mut w) => w.flush(),
+        }
+    }
+}
+
+enum OpenInner<R: Read> {
+    None(DecryptReader<R>),
+    #[cfg(feature = "flate2")]
+    Deflate(flate2::read::DeflateDecoder<DecryptReader<R>>),
+}
+
+/// OpenReader reads a stream written by `SealWriter` (or `EncryptWriter`),
+/// decrypting and then decompressing it according to the compression mode
+/// recorded in its header. Streams with unknown compression ids are rejected.
+pub struct OpenReader<R: Read> {
+    compression: Compression,
+    inner: OpenInner<R>,
+}
+
+impl<R: Read> OpenReader<R> {
+    /// Start reading a sealed stream, by reading and validating its header
+    /// from the given reader. Opening a `Compression::Deflate` stream without
+    /// the `flate2` feature enabled results in an error.
+    pub fn new(key: &Key, inner: R) -> Result<Self> {
+        let reader = DecryptReader::open(key, inner)?;
+        let compression = reader.compression();
+        Ok(OpenReader {
+            compression,
+            inner: match compression {
+                Compression::None => OpenInner::None(reader),
+                #[cfg(feature = "flate2")]
+                Compression::Deflate => {
+                    OpenInner::Deflate(flate2::read::DeflateDecoder::new(reader))
+                }
+                #[cfg(not(feature = "flate2"))]
+                Compression::Deflate => {
+                    return Err(Error::Unsupported(
+                        "DEFLATE compression requires the flate2 feature".to_string(),
+                    ))
+                }
+            },
+        })
+    }
+
+    /// Return the compression mode recorded in this stream's header.
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+}
+
+impl<R: Read> Read for OpenReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.inner {
+            OpenInner::None(ref mut r) => r.read(buf),
+            #[cfg(feature = "flate2")]
+            OpenInner::Deflate(ref mut r) => r.read(buf),
+        }
+    }
+}
+
+#[cfg(feature = "fs")]
+fn temp_file_for(dst: &Path) -> Result<crate::fs::TempFile> {
+    let dir = match dst.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    crate::fs::TempFile::new_in(dir, ".stream")
+}
+
+/// Seal the file at `src` with the given key and compression mode, writing
+/// the result to `dst`. The output is written to a temporary file which is
+/// then atomically moved into place, so `dst` is never left partially
+/// written.
+#[cfg(feature = "fs")]
+pub fn seal_file(key: &Key, src: &Path, dst: &Path, compression: Compression) -> Result<()> {
+    let mut input = std::fs::File::open(src)?;
+    let mut file = temp_file_for(dst)?;
+    let mut writer = SealWriter::new(key, file.as_file_mut(), compression)?;
+    io::copy(&mut input, &mut writer)?;
+    writer.finish()?;
+    file.persist(dst)
+}
+
+/// Open the sealed file at `src` with the given key, writing the original
+/// plaintext to `dst`. As with `seal_file`, the output is written atomically;
+/// in particular, if the input fails to authenticate, `dst` is not modified.
+#[cfg(feature = "fs")]
+pub fn open_file(key: &Key, src: &Path, dst: &Path) -> Result<()> {
+    let input = io::BufReader::new(std::fs::File::open(src)?);
+    let mut file = temp_file_for(dst)?;
+    let mut reader = OpenReader::new(key, input)?;
+    io::copy(&mut reader, file.as_file_mut())?;
+    file.persist(dst)
+}
+
+/// The default number of chunks `seal_file_resumable` encrypts between
+/// checkpoints (16 MiB of plaintext).
+#[cfg(feature = "fs")]
+pub const DEFAULT_CHECKPOINT_CHUNKS: u64 = 256;
+
+/// A function called with each checkpoint `seal_file_resumable_with` saves.
+#[cfg(feature = "fs")]
+pub type CheckpointCallback<'a> = &'a mut dyn FnMut(&ResumeState) -> Result<()>;
+
+/// Options which control the behavior of `seal_file_resumable_with`.
+#[cfg(feature = "fs")]
+pub struct ResumeOptions<'a> {
+    /// The number of chunks to encrypt between checkpoints. Zero is treated
+    /// as one.
+    pub checkpoint_chunks: u64,
+    /// If set, this is called with the state each checkpoint recorded, after
+    /// it has been saved. If it returns an error, sealing stops with that
+    /// error (and can be resumed later).
+    pub on_checkpoint: Option<CheckpointCallback<'a>>,
+}
+
+#[cfg(feature = "fs")]
+impl<'a> Default for ResumeOptions<'a> {
+    fn default() -> Self {
+        ResumeOptions {
+            checkpoint_chunks: DEFAULT_CHECKPOINT_CHUNKS,
+            on_checkpoint: None,
+        }
+    }
+}
+
+/// SourceIdentity describes the source file `seal_file_resumable` is
+/// encrypting, so a resumed run can tell if it was replaced or modified.
+#[cfg(feature = "fs")]
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+struct SourceIdentity {
+    len: u64,
+    modified: SystemTime,
+    /// The file's inode number, or zero on platforms which don't have them.
+    inode: u64,
+}
+
+#[cfg(feature = "fs")]
+impl SourceIdentity {
+    fn of(file: &std::fs::File) -> Result<Self> {
+        let metadata = file.metadata()?;
+        #[cfg(unix)]
+        let inode = std::os::unix::fs::MetadataExt::ino(&metadata);
+        #[cfg(not(unix))]
+        let inode = 0;
+        Ok(SourceIdentity {
+            len: metadata.len(),
+            modified: metadata.modified()?,
+            inode,
+        })
+    }
+}
+
+/// Checkpoint is what `seal_file_resumable` saves in its state file: the
+/// stream's resume state, plus enough information to check that the source
+/// and partial output are still the ones it describes.
+#[cfg(feature = "fs")]
+#[derive(Deserialize, Serialize)]
+struct Checkpoint {
+    source: SourceIdentity,
+    /// The digest of the plaintext consumed so far, i.e. the first
+    /// `state.plaintext_offset()` bytes of the source.
+    plaintext_digest: Digest,
+    output_digest: Digest,
+    state: ResumeState,
+}
+
+/// PlaintextDigest computes the digest of the plaintext `seal_file_resumable`
+/// has consumed, i.e. which has been encrypted into complete chunks. Data
+/// which the `EncryptWriter` is still buffering is held back until its chunk
+/// is complete, so at each checkpoint the digest covers exactly the plaintext
+/// the checkpoint does.
+#[cfg(feature = "fs")]
+struct PlaintextDigest {
+    digest: DigestBuilder,
+    len: u64,
+    pending: Vec<u8>,
+}
+
+#[cfg(feature = "fs")]
+impl PlaintextDigest {
+    /// Record that `data` was written to the stream, after which `consumed`
+    /// bytes have been encrypted into complete chunks.
+    fn update(&mut self, data: &[u8], consumed: u64) {
+        self.pending.extend_from_slice(data);
+        let ready = (consumed - self.len) as usize;
+        self.digest.update(&self.pending[..ready]);
+        self.pending.drain(..ready);
+        self.len = consumed;
+    }
+}
+
+#[cfg(feature = "fs")]
+impl Drop for PlaintextDigest {
+    fn drop(&mut self) {
+        crate::crypto::secret::zeroize(&mut self.pending);
+    }
+}
+
+/// HashingWriter computes a digest of everything written through it.
+#[cfg(feature = "fs")]
+struct HashingWriter<W: Write> {
+    inner: W,
+    digest: DigestBuilder,
+}
+
+#[cfg(feature = "fs")]
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.digest.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(feature = "fs")]
+fn stale_state(state_path: &Path, reason: &str) -> Error {
+    Error::InvalidArgument(format!(
+        "resume state '{}' is stale: {}",
+        state_path.display(),
+        reason
+    ))
+}
+
+/// Verify that the given source file is the one described by the given
+/// checkpoint, and that the plaintext it already consumed is unmodified.
+/// Returns the digest of that plaintext, with the source positioned right
+/// after it.
+#[cfg(feature = "fs")]
+fn resume_source(
+    checkpoint: &Checkpoint,
+    source: &mut std::fs::File,
+    state_path: &Path,
+) -> Result<PlaintextDigest> {
+    if SourceIdentity::of(source)? != checkpoint.source {
+        return Err(stale_state(state_path, "the source file has changed"));
+    }
+
+    let offset = checkpoint.state.plaintext_offset();
+    let mut digest = DigestBuilder::new();
+    let mut buf = vec![0; CHUNK_BYTES];
+    let mut prefix = (&mut *source).take(offset);
+    let mut len = 0;
+    loop {
+        let n = prefix.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        digest.update(&buf[..n]);
+        len += n as u64;
+    }
+    crate::crypto::secret::zeroize(&mut buf);
+    if len != offset || digest.clone().finish() != checkpoint.plaintext_digest {
+        return Err(stale_state(state_path, "the source file has been modified"));
+    }
+    Ok(PlaintextDigest {
+        digest,
+        len,
+        pending: Vec::with_capacity(2 * CHUNK_BYTES),
+    })
+}
+
+/// Open the partial output at `dst` described by the given checkpoint,
+/// verifying that it still matches, and truncating it to the checkpoint.
+#[cfg(feature = "fs")]
+fn resume_output(
+    checkpoint: &Checkpoint,
+    dst: &Path,
+    state_path: &Path,
+) -> Result<HashingWriter<std::fs::File>> {
+    let stale = |reason: &str| stale_state(state_path, reason);
+    let mut output = match std::fs::OpenOptions::new().read(true).write(true).open(dst) {
+        Ok(output) => output,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(stale("the partial output is missing"))
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let offset = checkpoint.state.ciphertext_offset();
+    if output.metadata()?.len() < offset {
+        return Err(stale("the partial output is too short"));
+    }
+
+    let mut digest = DigestBuilder::new();
+    let mut buf = vec![0; CHUNK_BYTES];
+    let mut prefix = (&mut output).take(offset);
+    loop {
+        let n = prefix.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        digest.update(&buf[..n]);
+    }
+    if digest.clone().finish() != checkpoint.output_digest {
+        return Err(stale("the partial output has been modified"));
+    }
+
+    output.set_len(offset)?;
+    output.seek(SeekFrom::Start(offset))?;
+    Ok(HashingWriter {
+        inner: output,
+        digest,
+    })
+}
+
+#[cfg(feature = "fs")]
+fn save_checkpoint(
+    key: &Key,
+    writer: &mut EncryptWriter<HashingWriter<std::fs::File>>,
+    source: &SourceIdentity,
+    plaintext: &PlaintextDigest,
+    state_path: &Path,
+    options: &mut ResumeOptions<'_>,
+) -> Result<()> {
+    let state = writer.resume_state(key)?;
+    debug_assert_eq!(state.plaintext_offset(), plaintext.len);
+    let output = writer.get_ref();
+    // The output must be durable before a checkpoint referring to it is.
+    output.inner.sync_data()?;
+    let checkpoint = Checkpoint {
+        source: source.clone(),
+        plaintext_digest: plaintext.digest.clone().finish(),
+        output_digest: output.digest.clone().finish(),
+        state,
+    };
+
+    let mut file = temp_file_for(state_path)?;
+    file.as_file_mut()
+        .write_all(&rmp_serde::to_vec(&checkpoint)?)?;
+    file.persist(state_path)?;
+
+    if let Some(on_checkpoint) = options.on_checkpoint.as_mut() {
+        on_checkpoint(&checkpoint.state)?;
+    }
+    Ok(())
+}
+
+/// Encrypt the file at `src` with the given key, writing the result to `dst`,
+/// such that an interrupted run can be resumed instead of starting over. This
+/// is `seal_file_resumable_with` using the default options.
+#[cfg(feature = "fs")]
+pub fn seal_file_resumable(key: &Key, src: &Path, dst: &Path, state_path: &Path) -> Result<()> {
+    seal_file_resumable_with(key, src, dst, state_path, ResumeOptions::default())
+}
+
+/// Encrypt the file at `src` with the given key, writing the result to `dst`
+/// (without compression, so the output can be read with `DecryptReader` or
+/// `open_file`).
+///
+/// Unlike `seal_file`, the output is written directly to `dst`, and every
+/// `checkpoint_chunks` chunks the progress made so far is saved (atomically)
+/// to `state_path`. If `dst` and `state_path` both exist when this is called,
+/// encryption is resumed from the last checkpoint, after verifying that the
+/// already-written output is unmodified, and that the source is the same file
+/// (by length, modification time, and inode) and the plaintext already
+/// encrypted from it is unmodified; if anything doesn't match, an error is
+/// returned instead. Once the output is complete, `state_path` is removed.
+#[cfg(feature = "fs")]
+pub fn seal_file_resumable_with(
+    key: &Key,
+    src: &Path,
+    dst: &Path,
+    state_path: &Path,
+    mut options: ResumeOptions<'_>,
+) -> Result<()> {
+    let mut input = std::fs::File::open(src)?;
+    let source = SourceIdentity::of(&input)?;
+
+    let (mut writer, mut plaintext) = if state_path.exists() {
+        let checkpoint: Checkpoint = rmp_serde::from_slice(&std::fs::read(state_path)?)?;
+        let plaintext = resume_source(&checkpoint, &mut input, state_path)?;
+        let output = resume_output(&checkpoint, dst, state_path)?;
+        (
+            EncryptWriter::resume(key, &checkpoint.state, output)?,
+            plaintext,
+        )
+    } else {
+        let output = HashingWriter {
+            inner: std::fs::File::create(dst)?,
+            digest: DigestBuilder::new(),
+        };
+        let plaintext = PlaintextDigest {
+            digest: DigestBuilder::new(),
+            len: 0,
+            pending: Vec::with_capacity(2 * CHUNK_BYTES),
+        };
+        (EncryptWriter::new(key, output)?, plaintext)
+    };
+
+    let checkpoint_chunks = options.checkpoint_chunks.max(1);
+    let mut last_checkpoint = writer.chunks_written();
+    let mut buf = vec![0; CHUNK_BYTES];
+    loop {
+        let n = match input.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        writer.write_all(&buf[..n])?;
+        plaintext.update(&buf[..n], writer.chunks_written() * CHUNK_BYTES as u64);
+        if writer.chunks_written() - last_checkpoint >= checkpoint_chunks {
+            save_checkpoint(
+                key,
+                &mut writer,
+                &source,
+                &plaintext,
+                state_path,
+                &mut options,
+            )?;
+            last_checkpoint = writer.chunks_written();
+        }
+    }
+    crate::crypto::secret::zeroize(&mut buf);
+
+    writer.finish()?.inner.sync_all()?;
+    match std::fs::remove_file(state_path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
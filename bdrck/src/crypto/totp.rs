@@ -0,0 +1,499 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::crypto::secret::Secret;
+use crate::crypto::util::constant_time_eq;
+use crate::error::*;
+use crate::testing::clock::Clock;
+use data_encoding::BASE32_NOPAD;
+use halite_sys;
+use std::fmt;
+use std::mem::MaybeUninit;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+/// The fewest digits RFC 4226 allows a one-time code to have.
+pub const MIN_DIGITS: u32 = 6;
+/// The most digits a one-time code can have; the truncated HMAC is a 31-bit
+/// number, so any more digits would always be leading zeros.
+pub const MAX_DIGITS: u32 = 9;
+/// The default number of digits in each code.
+pub const DEFAULT_DIGITS: u32 = 6;
+/// The default time step; each code is valid for this long.
+pub const DEFAULT_PERIOD: Duration = Duration::from_secs(30);
+
+/// The URI scheme (and type) used to provision TOTP keys, e.g. via QR codes.
+const URI_PREFIX: &str = "otpauth://totp/";
+
+/// The HMAC algorithm used to compute one-time codes.
+///
+/// SHA-1 is the default, and the only algorithm most authenticator apps
+/// support. Note that SHA-1's collision weaknesses don't affect its use in
+/// HMAC, so it remains safe for this purpose.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum TotpAlgorithm {
+    /// HMAC-SHA-1.
+    #[default]
+    Sha1,
+    /// HMAC-SHA-256.
+    Sha256,
+    /// HMAC-SHA-512.
+    Sha512,
+}
+
+impl TotpAlgorithm {
+    /// Return the name of this algorithm, as used in `otpauth://` URIs.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TotpAlgorithm::Sha1 => "SHA1",
+            TotpAlgorithm::Sha256 => "SHA256",
+            TotpAlgorithm::Sha512 => "SHA512",
+        }
+    }
+}
+
+impl fmt::Display for TotpAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for TotpAlgorithm {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_uppercase().as_str() {
+            "SHA1" => Ok(TotpAlgorithm::Sha1),
+            "SHA256" => Ok(TotpAlgorithm::Sha256),
+            "SHA512" => Ok(TotpAlgorithm::Sha512),
+            _ => Err(Error::InvalidArgument(format!(
+                "unsupported TOTP algorithm '{}'",
+                s
+            ))),
+        }
+    }
+}
+
+/// TotpParams describes how one-time codes are computed from a key. These
+/// must match the parameters the key was provisioned with.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TotpParams {
+    /// The number of digits in each code, between `MIN_DIGITS` and
+    /// `MAX_DIGITS`.
+    pub digits: u32,
+    /// The time step; each code is valid for this long. Must be a nonzero
+    /// whole number of seconds.
+    pub period: Duration,
+    /// The HMAC algorithm used to compute codes.
+    pub algorithm: TotpAlgorithm,
+}
+
+impl Default for TotpParams {
+    fn default() -> Self {
+        TotpParams {
+            digits: DEFAULT_DIGITS,
+            period: DEFAULT_PERIOD,
+            algorithm: TotpAlgorithm::default(),
+        }
+    }
+}
+
+impl TotpParams {
+    fn validate(&self) -> Result<()> {
+        if !(MIN_DIGITS..=MAX_DIGITS).contains(&self.digits) {
+            return Err(Error::InvalidArgument(format!(
+                "TOTP codes must have between {} and {} digits, not {}",
+                MIN_DIGITS, MAX_DIGITS, self.digits
+            )));
+        }
+        if self.period.as_secs() == 0 || self.period.subsec_nanos() != 0 {
+            return Err(Error::InvalidArgument(format!(
+                "TOTP period must be a nonzero whole number of seconds, not {:?}",
+                self.period
+            )));
+        }
+        Ok(())
+    }
+
+    /// Return the time step containing the given time.
+    fn step_at(&self, time: SystemTime) -> Result<u64> {
+        let since_epoch = time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_err(|_| Error::InvalidArgument("TOTP time is before the epoch".to_string()))?;
+        Ok(since_epoch.as_secs() / self.period.as_secs())
+    }
+}
+
+/// The SHA-1 compression function, applied to a single 64-byte block.
+fn sha1_block(state: &mut [u32; 5], block: &[u8]) {
+    let mut w = [0_u32; 80];
+    for (i, word) in block.chunks_exact(4).enumerate() {
+        w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+    }
+    for i in 16..80 {
+        w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+    }
+
+    let [mut a, mut b, mut c, mut d, mut e] = *state;
+    for (i, word) in w.iter().enumerate() {
+        let (f, k) = match i {
+            0..=19 => ((b & c) | (!b & d), 0x5a827999),
+            20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+            40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+            _ => (b ^ c ^ d, 0xca62c1d6),
+        };
+        let temp = a
+            .rotate_left(5)
+            .wrapping_add(f)
+            .wrapping_add(e)
+            .wrapping_add(k)
+            .wrapping_add(*word);
+        e = d;
+        d = c;
+        c = b.rotate_left(30);
+        b = a;
+        a = temp;
+    }
+
+    for (s, v) in state.iter_mut().zip([a, b, c, d, e]) {
+        *s = s.wrapping_add(v);
+    }
+}
+
+/// Compute the SHA-1 digest of the concatenation of the given parts.
+/// libsodium doesn't provide SHA-1, and it's only needed here (for HMAC), so
+/// this is a minimal implementation of FIPS 180-4.
+fn sha1(parts: &[&[u8]]) -> [u8; 20] {
+    let mut state: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+    let mut message: Vec<u8> = parts.concat();
+    let bit_len = (message.len() as u64) * 8;
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&bit_len.to_be_bytes());
+
+    for block in message.chunks_exact(64) {
+        sha1_block(&mut state, block);
+    }
+    message.fill(0);
+
+    let mut digest = [0_u8; 20];
+    for (out, word) in digest.chunks_exact_mut(4).zip(state) {
+        out.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+/// Compute HMAC-SHA-1 (RFC 2104) of the given message with the given key.
+fn hmac_sha1(key: &[u8], message: &[u8]) -> Vec<u8> {
+    const BLOCK_BYTES: usize = 64;
+
+    let mut block_key = [0_u8; BLOCK_BYTES];
+    if key.len() > BLOCK_BYTES {
+        block_key[..20].copy_from_slice(&sha1(&[key]));
+    } else {
+        block_key[..key.len()].copy_from_slice(key);
+    }
+
+    let mut ipad = block_key.map(|b| b ^ 0x36);
+    let mut opad = block_key.map(|b| b ^ 0x5c);
+    let inner = sha1(&[&ipad, message]);
+    let outer = sha1(&[&opad, &inner]);
+
+    block_key.fill(0);
+    ipad.fill(0);
+    opad.fill(0);
+    outer.to_vec()
+}
+
+macro_rules! sodium_hmac {
+    ($name:ident, $state:ident, $init:ident, $update:ident, $final:ident, $bytes:ident) => {
+        fn $name(key: &[u8], message: &[u8]) -> Vec<u8> {
+            debug_assert!(crate::init_done());
+            let mut mac = vec![0_u8; halite_sys::$bytes as usize];
+            unsafe {
+                let mut state = MaybeUninit::<halite_sys::$state>::uninit();
+                halite_sys::$init(state.as_mut_ptr(), key.as_ptr(), key.len());
+                let mut state = state.assume_init();
+                halite_sys::$update(&mut state, message.as_ptr(), message.len() as u64);
+                halite_sys::$final(&mut state, mac.as_mut_ptr());
+            }
+            mac
+        }
+    };
+}
+
+sodium_hmac!(
+    hmac_sha256,
+    crypto_auth_hmacsha256_state,
+    crypto_auth_hmacsha256_init,
+    crypto_auth_hmacsha256_update,
+    crypto_auth_hmacsha256_final,
+    crypto_auth_hmacsha256_BYTES
+);
+sodium_hmac!(
+    hmac_sha512,
+    crypto_auth_hmacsha512_state,
+    crypto_auth_hmacsha512_init,
+    crypto_auth_hmacsha512_update,
+    crypto_auth_hmacsha512_final,
+    crypto_auth_hmacsha512_BYTES
+);
+
+/// TotpKey is a shared secret used to generate and verify time-based one-time
+/// passwords, per RFC 6238. The secret is kept in a `Secret`, so it isn't
+/// exposed in ordinary process memory.
+pub struct TotpKey {
+    secret: Secret,
+}
+
+impl TotpKey {
+    /// Construct a key from the given raw secret bytes.
+    pub fn new(secret: Secret) -> Self {
+        TotpKey { secret }
+    }
+
+    /// Decode a key from its standard base32 encoding. Case, whitespace, and
+    /// padding are all ignored, since keys are frequently typed in by hand.
+    pub fn from_base32(encoded: &str) -> Result<Self> {
+        let mut normalized: Vec<u8> = encoded
+            .bytes()
+            .filter(|b| !b.is_ascii_whitespace() && *b != b'=')
+            .map(|b| b.to_ascii_uppercase())
+            .collect();
+        let invalid = || Error::InvalidArgument("invalid base32 TOTP key".to_string());
+
+        let result = BASE32_NOPAD
+            .decode_len(normalized.len())
+            .map_err(|_| invalid())
+            .and_then(|len| {
+                if len == 0 {
+                    return Err(Error::InvalidArgument("empty TOTP key".to_string()));
+                }
+                // Decode straight into the Secret, so the key is never copied
+                // anywhere else.
+                let mut secret = Secret::with_len(len)?;
+                let written = BASE32_NOPAD
+                    .decode_mut(&normalized, unsafe { secret.as_mut_slice() })
+                    .map_err(|_| invalid())?;
+                secret.resize(written)?;
+                Ok(TotpKey { secret })
+            });
+        normalized.fill(0);
+        result
+    }
+
+    /// Return this key's standard (unpadded) base32 encoding.
+    pub fn to_base32(&self) -> String {
+        BASE32_NOPAD.encode(unsafe { self.secret.as_slice() })
+    }
+
+    fn hmac(&self, algorithm: TotpAlgorithm, message: &[u8]) -> Vec<u8> {
+        let key = unsafe { self.secret.as_slice() };
+        match algorithm {
+            TotpAlgorithm::Sha1 => hmac_sha1(key, message),
+            TotpAlgorithm::Sha256 => hmac_sha256(key, message),
+            TotpAlgorithm::Sha512 => hmac_sha512(key, message),
+        }
+    }
+
+    /// Compute the code for the given time step (i.e., the HOTP code for the
+    /// given counter value, per RFC 4226).
+    fn code_for_step(&self, step: u64, params: &TotpParams) -> String {
+        let mut mac = self.hmac(params.algorithm, &step.to_be_bytes());
+        let offset = (mac[mac.len() - 1] & 0xf) as usize;
+        let truncated = u32::from_be_bytes([
+            mac[offset] & 0x7f,
+            mac[offset + 1],
+            mac[offset + 2],
+            mac[offset + 3],
+        ]);
+        mac.fill(0);
+        format!(
+            "{:0width$}",
+            truncated % 10_u32.pow(params.digits),
+            width = params.digits as usize
+        )
+    }
+
+    /// Compute the code which is valid at the given time.
+    ///
+    /// The time is a `SystemTime` rather than e.g. a chrono `DateTime<Utc>`,
+    /// because that's what `Clock` (and so `current_code` and `verify`) deals
+    /// in, and this crate doesn't otherwise depend on chrono. A `DateTime<Utc>`
+    /// converts losslessly with `SystemTime::from`.
+    pub fn code_at(&self, time: SystemTime, params: &TotpParams) -> Result<String> {
+        params.validate()?;
+        Ok(self.code_for_step(params.step_at(time)?, params))
+    }
+
+    /// Compute the code which is currently valid, according to the given
+    /// clock.
+    pub fn current_code(&self, clock: &dyn Clock, params: &TotpParams) -> Result<String> {
+        self.code_at(clock.now_utc(), params)
+    }
+
+    /// Check whether the given candidate code is currently valid, according to
+    /// the given clock. To tolerate clock drift (and the time it takes to type
+    /// a code in), codes from up to `skew_steps` time steps before or after
+    /// the current one are also accepted.
+    ///
+    /// Every code in the window is computed and compared in constant time,
+    /// regardless of whether an earlier one matched, so the time this takes
+    /// reveals nothing about the candidate.
+    pub fn verify(
+        &self,
+        candidate: &str,
+        clock: &dyn Clock,
+        params: &TotpParams,
+        skew_steps: u32,
+    ) -> Result<bool> {
+        params.validate()?;
+        let current = params.step_at(clock.now_utc())?;
+        let first = current.saturating_sub(skew_steps as u64);
+        let last = current.saturating_add(skew_steps as u64);
+
+        let mut matched = false;
+        for step in first..=last {
+            let code = self.code_for_step(step, params);
+            matched |= constant_time_eq(code.as_bytes(), candidate.as_bytes());
+        }
+        Ok(matched)
+    }
+}
+
+/// Percent-encode the given string for use in a URI, leaving only RFC 3986
+/// unreserved characters as-is.
+fn percent_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(b as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+/// Decode a percent-encoded URI component.
+fn percent_decode(s: &str) -> Result<String> {
+    let invalid = || Error::InvalidArgument(format!("invalid percent-encoding in '{}'", s));
+    let mut decoded = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'%' {
+            decoded.push(b);
+            continue;
+        }
+        let hex = [
+            bytes.next().ok_or_else(invalid)?,
+            bytes.next().ok_or_else(invalid)?,
+        ];
+        let hex = std::str::from_utf8(&hex).map_err(|_| invalid())?;
+        decoded.push(u8::from_str_radix(hex, 16).map_err(|_| invalid())?);
+    }
+    String::from_utf8(decoded).map_err(|_| invalid())
+}
+
+/// TotpUri is the contents of an `otpauth://totp/` URI, the de facto standard
+/// format (popularized by Google Authenticator) for provisioning TOTP keys,
+/// typically by encoding it in a QR code.
+pub struct TotpUri {
+    /// The label identifying the account, e.g. "Example:alice@example.com".
+    pub label: String,
+    /// The provider or service the account belongs to, if known.
+    pub issuer: Option<String>,
+    /// The shared secret.
+    pub key: TotpKey,
+    /// The parameters used to compute codes.
+    pub params: TotpParams,
+}
+
+impl TotpUri {
+    /// Parse an `otpauth://totp/` URI. Parameters which aren't specified take
+    /// their default values, and unrecognized parameters are ignored. If there
+    /// is no `issuer` parameter, the issuer is taken from the label's prefix
+    /// (e.g. "Example" for "Example:alice@example.com"), if any.
+    pub fn parse(uri: &str) -> Result<Self> {
+        let rest = uri
+            .get(..URI_PREFIX.len())
+            .filter(|prefix| prefix.eq_ignore_ascii_case(URI_PREFIX))
+            .map(|_| &uri[URI_PREFIX.len()..])
+            .ok_or_else(|| {
+                Error::InvalidArgument(format!("'{}' is not an otpauth://totp/ URI", uri))
+            })?;
+        let (label, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let label = percent_decode(label)?;
+
+        let mut secret: Option<TotpKey> = None;
+        let mut issuer: Option<String> = None;
+        let mut params = TotpParams::default();
+        for pair in query.split('&').filter(|p| !p.is_empty()) {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let invalid = |what: &str| {
+                Error::InvalidArgument(format!("invalid otpauth {} '{}'", what, value))
+            };
+            match name {
+                "secret" => secret = Some(TotpKey::from_base32(&percent_decode(value)?)?),
+                "issuer" => issuer = Some(percent_decode(value)?),
+                "algorithm" => params.algorithm = value.parse()?,
+                "digits" => params.digits = value.parse().map_err(|_| invalid("digits"))?,
+                "period" => {
+                    params.period =
+                        Duration::from_secs(value.parse().map_err(|_| invalid("period"))?)
+                }
+                _ => {}
+            }
+        }
+        params.validate()?;
+
+        let key = secret.ok_or_else(|| {
+            Error::InvalidArgument("otpauth URI has no secret parameter".to_string())
+        })?;
+        let issuer = issuer.or_else(|| {
+            label
+                .split_once(':')
+                .map(|(issuer, _)| issuer.trim().to_string())
+        });
+        Ok(TotpUri {
+            label,
+            issuer,
+            key,
+            params,
+        })
+    }
+
+    /// Format this as an `otpauth://totp/` URI. Since the result contains the
+    /// secret, be careful where it ends up.
+    pub fn to_uri(&self) -> String {
+        let mut uri = format!(
+            "{}{}?secret={}",
+            URI_PREFIX,
+            percent_encode(&self.label),
+            self.key.to_base32()
+        );
+        if let Some(issuer) = self.issuer.as_ref() {
+            uri.push_str(&format!("&issuer={}", percent_encode(issuer)));
+        }
+        uri.push_str(&format!(
+            "&algorithm={}&digits={}&period={}",
+            self.params.algorithm,
+            self.params.digits,
+            self.params.period.as_secs()
+        ));
+        uri
+    }
+}
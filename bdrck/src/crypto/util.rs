@@ -31,3 +31,20 @@ pub fn randombytes_into(buf: &mut [u8]) {
 pub fn randombytes_into_secret(s: &mut Secret) {
     randombytes_into(unsafe { s.as_mut_slice() });
 }
+
+/// Compare two byte strings for equality in constant time (with respect to their contents; the
+/// lengths are not considered secret). This should be used any time secret values, like MACs or
+/// password hashes, are compared, to avoid leaking information via timing.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    debug_assert!(crate::init_done());
+    unsafe {
+        halite_sys::sodium_memcmp(
+            a.as_ptr() as *const c_void,
+            b.as_ptr() as *const c_void,
+            a.len(),
+        ) == 0
+    }
+}
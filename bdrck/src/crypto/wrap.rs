@@ -12,16 +12,66 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::crypto::digest::Digest;
+use crate::crypto::digest::{Digest, DIGEST_BYTES};
 use crate::crypto::key::{AbstractKey, Nonce};
+use crate::crypto::secret::Secret;
 use crate::error::*;
 use serde::{Deserialize, Serialize};
 use tracing::debug;
 
+/// Bind the given associated data to the given serialized key, by prepending a digest of the
+/// associated data to it. Since the result is then encrypted (and authenticated) as a whole, this
+/// lets us detect when a wrapped key is unwrapped with different associated data, for any
+/// `AbstractKey` implementation.
+fn bind_aad(data: &Secret, aad: &[u8]) -> Result<Secret> {
+    let mut bound = Secret::with_len(DIGEST_BYTES + data.len())?;
+    unsafe {
+        bound.as_mut_slice()[..DIGEST_BYTES].copy_from_slice(Digest::from_bytes(aad).as_slice());
+        bound.as_mut_slice()[DIGEST_BYTES..].copy_from_slice(data.as_slice());
+    }
+    Ok(bound)
+}
+
+/// The inverse of `bind_aad`: verify the given data was bound to the given associated data, and
+/// return the original serialized key if so.
+fn unbind_aad(mut data: Secret, aad: &[u8]) -> Result<Secret> {
+    if data.len() < DIGEST_BYTES {
+        return Err(Error::Crypto(
+            "wrapped key data is too short to contain associated data".to_string(),
+        ));
+    }
+
+    let expected = Digest::from_bytes(aad);
+    debug_assert!(crate::init_done());
+    if unsafe {
+        halite_sys::sodium_memcmp(
+            data.slice_ptr() as *const libc::c_void,
+            expected.as_slice().as_ptr() as *const libc::c_void,
+            DIGEST_BYTES,
+        )
+    } != 0
+    {
+        return Err(Error::Crypto(
+            "wrapped key associated data doesn't match".to_string(),
+        ));
+    }
+
+    let len = data.len() - DIGEST_BYTES;
+    unsafe {
+        std::ptr::copy(data.slice_ptr().add(DIGEST_BYTES), data.slice_ptr(), len);
+    }
+    data.resize(len)?;
+    Ok(data)
+}
+
 /// A wrapped key is an `AbstractKey` which has been wrapped (encrypted) with another `AbstractKey`.
 /// This is useful because it lets us have e.g. a single "master key" which is wrapped by several
 /// sub-keys, which can be added / removed at will without having to actually re-encrypt all of the
 /// data encrypted with the "master key".
+///
+/// Optionally, a wrapped key can be bound to some associated data (e.g. an identifier for the
+/// context it belongs to). Such a key can only be unwrapped by providing the same associated data,
+/// which prevents wrapped keys from being copied from one context to another.
 #[derive(Deserialize, Serialize)]
 pub struct WrappedKey {
     /// The `serialize`-ed `AbstractKey` data, encrypted. This data has to be unwrapped (decrypted)
@@ -31,17 +81,29 @@ pub struct WrappedKey {
     nonce: Option<Nonce>,
     /// The digest of the key used to wrap this key.
     wrapping_digest: Digest,
+    /// Whether or not associated data was bound to this key when it was wrapped. Keys wrapped by
+    /// older versions of this library never have associated data.
+    #[serde(default)]
+    aad: bool,
 }
 
 impl WrappedKey {
-    /// Wrap the key `to_wrap` with the key `wrap_with` used for encryption.
-    pub fn wrap<KA: AbstractKey, KB: AbstractKey>(to_wrap: &KA, wrap_with: &KB) -> Result<Self> {
+    fn wrap_impl<KA: AbstractKey, KB: AbstractKey>(
+        to_wrap: &KA,
+        wrap_with: &KB,
+        aad: Option<&[u8]>,
+        nonce: Option<Nonce>,
+    ) -> Result<Self> {
         let data = match to_wrap.serialize() {
             Err(e) => return Err(Error::Crypto(format!("serializing key failed: {}", e))),
             Ok(d) => d,
         };
+        let data = match aad {
+            None => data,
+            Some(aad) => bind_aad(&data, aad)?,
+        };
 
-        let (nonce, data) = match wrap_with.encrypt(&data, None) {
+        let (nonce, data) = match wrap_with.encrypt(&data, nonce) {
             Err(e) => return Err(Error::Crypto(format!("wrapping key failed: {}", e))),
             Ok(nd) => nd,
         };
@@ -50,12 +112,15 @@ impl WrappedKey {
             data: data,
             nonce: nonce,
             wrapping_digest: wrap_with.get_digest(),
+            aad: aad.is_some(),
         })
     }
 
-    /// Unwrap the previously wrapped key this structure represents. This basically decrypts and
-    /// then deserializes the underlying key data, returning the newly constructed key.
-    pub fn unwrap<KA: AbstractKey, KB: AbstractKey>(&self, wrapped_with: &KB) -> Result<KA> {
+    fn unwrap_impl<KA: AbstractKey, KB: AbstractKey>(
+        &self,
+        wrapped_with: &KB,
+        aad: Option<&[u8]>,
+    ) -> Result<KA> {
         debug!(
             "trying to unwrap key {:?} with wrapping key {:?}, expected wrapping digest {:?}",
             self.get_digest(),
@@ -67,11 +132,22 @@ impl WrappedKey {
                 "the specified key is not the correct wrapping key"
             )));
         }
+        if aad.is_some() != self.aad {
+            return Err(Error::InvalidArgument(format!(
+                "wrapped key {} associated data, but {} was provided",
+                if self.aad { "has" } else { "has no" },
+                if aad.is_some() { "some" } else { "none" }
+            )));
+        }
 
         let data = match wrapped_with.decrypt(self.nonce.as_ref(), self.data.as_slice()) {
             Err(e) => return Err(Error::Crypto(format!("unwrapping key failed: {}", e))),
             Ok(d) => d,
         };
+        let data = match aad {
+            None => data,
+            Some(aad) => unbind_aad(data, aad)?,
+        };
 
         match KA::deserialize(data) {
             Err(e) => return Err(Error::Crypto(format!("deserializing key failed: {}", e))),
@@ -79,6 +155,59 @@ impl WrappedKey {
         }
     }
 
+    /// Wrap the key `to_wrap` with the key `wrap_with` used for encryption.
+    pub fn wrap<KA: AbstractKey, KB: AbstractKey>(to_wrap: &KA, wrap_with: &KB) -> Result<Self> {
+        Self::wrap_impl(to_wrap, wrap_with, None, None)
+    }
+
+    /// Wrap the key `to_wrap` with the key `wrap_with` used for encryption, additionally binding
+    /// the given associated data to it. The resulting key can only be unwrapped with
+    /// `unwrap_with_aad`, given the exact same associated data.
+    pub fn wrap_with_aad<KA: AbstractKey, KB: AbstractKey>(
+        to_wrap: &KA,
+        wrap_with: &KB,
+        aad: &[u8],
+    ) -> Result<Self> {
+        Self::wrap_impl(to_wrap, wrap_with, Some(aad), None)
+    }
+
+    /// Like `wrap_with_aad`, but encrypts using the given nonce instead of a
+    /// randomly generated one. This is only useful for generating
+    /// reproducible test fixtures; reusing a nonce with the same wrapping key
+    /// is insecure.
+    #[cfg(feature = "testing")]
+    pub(crate) fn wrap_with_aad_and_nonce<KA: AbstractKey, KB: AbstractKey>(
+        to_wrap: &KA,
+        wrap_with: &KB,
+        aad: &[u8],
+        nonce: Nonce,
+    ) -> Result<Self> {
+        Self::wrap_impl(to_wrap, wrap_with, Some(aad), Some(nonce))
+    }
+
+    /// Unwrap the previously wrapped key this structure represents. This basically decrypts and
+    /// then deserializes the underlying key data, returning the newly constructed key.
+    ///
+    /// This fails if this key was wrapped with associated data; use `unwrap_with_aad` instead.
+    pub fn unwrap<KA: AbstractKey, KB: AbstractKey>(&self, wrapped_with: &KB) -> Result<KA> {
+        self.unwrap_impl(wrapped_with, None)
+    }
+
+    /// Unwrap a key previously wrapped with `wrap_with_aad`. This fails if the given associated
+    /// data doesn't match the associated data the key was wrapped with.
+    pub fn unwrap_with_aad<KA: AbstractKey, KB: AbstractKey>(
+        &self,
+        wrapped_with: &KB,
+        aad: &[u8],
+    ) -> Result<KA> {
+        self.unwrap_impl(wrapped_with, Some(aad))
+    }
+
+    /// Return whether or not this key was wrapped with associated data.
+    pub fn has_aad(&self) -> bool {
+        self.aad
+    }
+
     /// Return a digest/signature computed from the encrypted key data.
     pub fn get_digest(&self) -> Digest {
         Digest::from_bytes(self.data.as_slice())
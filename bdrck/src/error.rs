@@ -12,15 +12,96 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use std::fmt;
+use std::io::ErrorKind;
 use thiserror::Error;
 
 /// Error is a structure which denotes all of the possible kinds of errors bdrck
 /// can produce, including errors from any of its underlying dependencies.
 #[derive(Debug, Error)]
 pub enum Error {
+    /// An HTTP request's credentials were rejected, even after refreshing
+    /// them (see `http::auth::AuthenticatingClient`), or refreshing them
+    /// failed.
+    #[error("HTTP authentication failed with status {status}: {body_prefix}")]
+    AuthenticationFailed {
+        /// The final response's status code.
+        status: u16,
+        /// The first few bytes of the final response's body, for debugging.
+        body_prefix: String,
+    },
+    /// Some configuration input contained fields which don't exist in the
+    /// configuration type (see `configuration::UnknownFields`).
+    #[cfg(feature = "configuration")]
+    #[error(
+        "unknown configuration fields: {}",
+        .0.iter().map(|f| f.to_string()).collect::<Vec<_>>().join(", ")
+    )]
+    ConfigUnknownFields(Vec<crate::configuration::UnknownField>),
+    /// Some configuration input was larger than the configured maximum size
+    /// (see `configuration::ConfigLimits`).
+    #[cfg(feature = "configuration")]
+    #[error("configuration input exceeds the maximum size of {limit} bytes")]
+    ConfigTooLarge {
+        /// The maximum size, in bytes.
+        limit: u64,
+    },
+    /// Some configuration input was nested more deeply than the configured
+    /// maximum depth (see `configuration::ConfigLimits`).
+    #[cfg(feature = "configuration")]
+    #[error("configuration input exceeds the maximum nesting depth of {depth}")]
+    ConfigTooDeep {
+        /// The maximum nesting depth.
+        depth: usize,
+    },
+    /// Some configuration input contained more values than the configured
+    /// maximum (see `configuration::ConfigLimits`).
+    #[cfg(feature = "configuration")]
+    #[error("configuration input exceeds the maximum of {nodes} values")]
+    ConfigTooComplex {
+        /// The maximum number of values.
+        nodes: usize,
+    },
+    /// Some configuration input contained a string or byte array longer than
+    /// the configured maximum (see `configuration::ConfigLimits`).
+    #[cfg(feature = "configuration")]
+    #[error("configuration input contains a string longer than {limit} bytes")]
+    ConfigStringTooLong {
+        /// The maximum string length, in bytes.
+        limit: usize,
+    },
+    /// A persisted configuration file failed its integrity check: it was
+    /// truncated, or its contents were damaged. This is distinct from a file
+    /// which is intact, but can't be deserialized (e.g. because it was written
+    /// by an incompatible version).
+    #[cfg(feature = "configuration")]
+    #[error(
+        "configuration file '{}' is corrupted (expected {expected_len} bytes with a valid checksum, found {actual_len} bytes); restore it from a backup",
+        .path.display()
+    )]
+    ConfigCorrupted {
+        /// The path to the corrupted file.
+        path: std::path::PathBuf,
+        /// The file's length, according to its header.
+        expected_len: u64,
+        /// The file's actual length.
+        actual_len: u64,
+    },
     /// An error encountered while performing a cryptographic operation.
     #[error("cryptographic operation failed: {0}")]
     Crypto(String),
+    /// Some data (e.g. a downloaded file) didn't match the digest it was
+    /// expected to have.
+    #[cfg(feature = "crypto")]
+    #[error("digest mismatch: expected {expected:?}, got {actual:?}")]
+    DigestMismatch {
+        /// The digest the data was expected to have.
+        expected: Box<crate::crypto::digest::Digest>,
+        /// The digest the data actually had.
+        actual: Box<crate::crypto::digest::Digest>,
+    },
     /// An error encountered while trying to interact with environment
     /// variables.
     #[error("{0}")]
@@ -40,9 +121,26 @@ pub enum Error {
     #[cfg(feature = "reqwest")]
     #[error("{0}")]
     Http(#[from] reqwest::Error),
+    /// An HTTP response had a different content type than the caller
+    /// expected (e.g. HTML instead of JSON).
+    #[error("unexpected HTTP response content type '{content_type}': {body_prefix}")]
+    HttpContentType {
+        /// The response's content type (empty if it didn't have one).
+        content_type: String,
+        /// The first few bytes of the response body, for debugging.
+        body_prefix: String,
+    },
     /// An HTTP request failed, despite multiple retries.
     #[error("HTTP request failed despite retries: {0}")]
     HttpRetry(String),
+    /// An HTTP request was answered with an unsuccessful status code.
+    #[error("HTTP request failed with status {status}: {body_prefix}")]
+    HttpStatus {
+        /// The response's status code.
+        status: u16,
+        /// The first few bytes of the response body, for debugging.
+        body_prefix: String,
+    },
     /// This error indicates that we were reading some input, and we encountered
     /// too many bytes (e.g. because there was an upper bound on how much we
     /// were willing to read).
@@ -63,6 +161,24 @@ pub enum Error {
     #[cfg(feature = "serde_json")]
     #[error("{0}")]
     Json(#[from] serde_json::Error),
+    /// A KeyStore was opened with a wrapping key which has expired. Use
+    /// `KeyStore::open_allow_expired` to open it anyway (e.g., to rotate the
+    /// expired key).
+    #[error(
+        "key {label} expired at {} (seconds since the epoch)",
+        .expired_at.duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+    )]
+    KeyExpired {
+        /// When the key expired.
+        expired_at: std::time::SystemTime,
+        /// The key's label, or its digest if it wasn't given a label.
+        label: String,
+    },
+    /// A KeyStore's integrity check failed, meaning its contents were modified
+    /// by something other than this library (e.g., wrapped keys were removed
+    /// or spliced in from some other KeyStore).
+    #[error("KeyStore integrity check failed: {0}")]
+    KeyStoreTampered(String),
     /// An error encountered when decoding a serialized message.
     #[cfg(feature = "rmp-serde")]
     #[error("{0}")]
@@ -71,6 +187,9 @@ pub enum Error {
     #[cfg(feature = "rmp-serde")]
     #[error("{0}")]
     MsgEncode(#[from] rmp_serde::encode::Error),
+    /// A network operation timed out (e.g., we never received a response).
+    #[error("network operation timed out: {0}")]
+    NetTimeout(String),
     /// Errors akin to ENOENT - something like e.g. "file not found", although
     /// this is not necessarily *always* about files.
     #[error("not found: {0}")]
@@ -89,19 +208,684 @@ pub enum Error {
     /// when one or more of its preconditions were not satisfied.
     #[error("precondition not satisfied: {0}")]
     Precondition(String),
+    /// A child process exited unsuccessfully.
+    #[error("child process exited with {status}: {stderr}")]
+    ProcessFailed {
+        /// The child process's exit status.
+        status: std::process::ExitStatus,
+        /// Everything the child process wrote to stderr.
+        stderr: String,
+    },
+    /// A child process didn't finish within its allotted time, so it was
+    /// killed.
+    #[error("child process timed out: {0}")]
+    ProcessTimeout(String),
+    /// An attempt was made to modify or persist a configuration which was
+    /// opened in read-only mode.
+    #[error("configuration is read-only: {0}")]
+    ReadOnlyConfiguration(String),
+    /// An attempt was made to modify or persist a KeyStore which was opened in
+    /// read-only mode.
+    #[error("KeyStore is read-only: {0}")]
+    ReadOnlyKeyStore(String),
     /// An error encountered in either parsing or applying a regular expression.
     #[cfg(feature = "regex")]
     #[error("{0}")]
     Regex(#[from] regex::Error),
+    /// Setting or getting a socket option failed.
+    #[error("socket option {option} failed: {error}")]
+    SocketOption {
+        /// The name of the socket option (e.g. "SO_KEEPALIVE").
+        option: String,
+        /// The underlying error.
+        error: std::io::Error,
+    },
     /// An awkward hack; this error exists to use String's FromStr impl, but
     /// this operation won't actually ever fail.
     #[error("{0}")]
     StringParse(#[from] std::string::ParseError),
+    /// The requested operation is not supported (e.g., by this particular
+    /// implementation of some trait).
+    #[error("unsupported operation: {0}")]
+    Unsupported(String),
     /// An error in decoding a URL.
     #[cfg(feature = "url")]
     #[error("{0}")]
     Url(#[from] url::ParseError),
+    /// Some other error, whose retryability has been overridden (see
+    /// `Error::as_permanent` and `Error::as_transient`). In every other
+    /// respect (e.g. its message and code), this is the same as the wrapped
+    /// error.
+    #[error("{source}")]
+    WithRetryability {
+        /// The overridden retryability.
+        retryability: Retryability,
+        /// The wrapped error.
+        source: Box<Error>,
+    },
 }
 
 /// A Result type which uses bdrck's internal Error type.
 pub type Result<T> = std::result::Result<T, Error>;
+
+/// ErrorCategory is a coarse grouping of `ErrorCode`s, e.g. for deciding on a
+/// process exit status.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize),
+    serde(rename_all = "SCREAMING_SNAKE_CASE")
+)]
+pub enum ErrorCategory {
+    /// The error was caused by the caller (e.g. invalid input or arguments).
+    Usage,
+    /// The error came from the filesystem, a child process, or the OS.
+    Io,
+    /// A cryptographic operation or integrity check failed.
+    Crypto,
+    /// A network or HTTP operation failed.
+    Http,
+    /// An internal error, which likely indicates a bug.
+    Internal,
+}
+
+impl ErrorCategory {
+    /// Returns this category's stable string representation.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCategory::Usage => "USAGE",
+            ErrorCategory::Io => "IO",
+            ErrorCategory::Crypto => "CRYPTO",
+            ErrorCategory::Http => "HTTP",
+            ErrorCategory::Internal => "INTERNAL",
+        }
+    }
+}
+
+impl fmt::Display for ErrorCategory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Retryability describes whether an operation which failed with some error is
+/// worth retrying (see `Error::retryability`).
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Retryability {
+    /// The error is likely to go away on its own (e.g. a timeout, or a server
+    /// error), so retrying may succeed.
+    Transient,
+    /// Retrying would just fail the same way again (e.g. invalid input).
+    Permanent,
+    /// It isn't known whether retrying might help.
+    Unknown,
+}
+
+impl Retryability {
+    /// Returns the retryability of an I/O error of the given kind.
+    pub fn from_io_kind(kind: ErrorKind) -> Self {
+        match kind {
+            ErrorKind::Interrupted
+            | ErrorKind::WouldBlock
+            | ErrorKind::TimedOut
+            | ErrorKind::ConnectionRefused
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::NotConnected
+            | ErrorKind::BrokenPipe => Retryability::Transient,
+            ErrorKind::NotFound
+            | ErrorKind::PermissionDenied
+            | ErrorKind::AlreadyExists
+            | ErrorKind::InvalidInput
+            | ErrorKind::InvalidData
+            | ErrorKind::Unsupported => Retryability::Permanent,
+            _ => Retryability::Unknown,
+        }
+    }
+
+    /// Returns the retryability of an HTTP response with the given status
+    /// code: server errors, timeouts, and rate limiting are transient, and
+    /// any other status is permanent.
+    pub fn from_http_status(status: u16) -> Self {
+        match status {
+            408 | 429 | 500..=599 => Retryability::Transient,
+            _ => Retryability::Permanent,
+        }
+    }
+}
+
+/// ErrorCode is a stable, machine-readable identifier for an `Error`. Unlike
+/// error messages, these codes are part of this crate's public interface:
+/// existing codes are never renamed or removed, so scripts can safely match
+/// on them.
+///
+/// Codes are serialized as SCREAMING_SNAKE_CASE strings (see `as_str`).
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize),
+    serde(rename_all = "SCREAMING_SNAKE_CASE")
+)]
+pub enum ErrorCode {
+    /// `Error::AuthenticationFailed`.
+    AuthenticationFailed,
+    /// `Error::ConfigUnknownFields`.
+    ConfigUnknownFields,
+    /// `Error::ConfigTooLarge`.
+    ConfigTooLarge,
+    /// `Error::ConfigTooDeep`.
+    ConfigTooDeep,
+    /// `Error::ConfigTooComplex`.
+    ConfigTooComplex,
+    /// `Error::ConfigStringTooLong`.
+    ConfigStringTooLong,
+    /// `Error::ConfigCorrupted`.
+    ConfigCorrupted,
+    /// `Error::Crypto`.
+    Crypto,
+    /// `Error::DigestMismatch`.
+    DigestMismatch,
+    /// `Error::EnvVar`.
+    EnvVar,
+    /// `Error::FromUtf8` or `Error::FromUtf8Str`.
+    InvalidUtf8,
+    /// `Error::HexDecode`.
+    HexDecode,
+    /// `Error::Http`.
+    Http,
+    /// `Error::HttpContentType`.
+    HttpContentType,
+    /// `Error::HttpRetry`.
+    HttpRetry,
+    /// `Error::HttpStatus`.
+    HttpStatus,
+    /// `Error::InputTooBig`.
+    InputTooBig,
+    /// `Error::Internal`.
+    Internal,
+    /// `Error::InvalidArgument`.
+    InvalidArgument,
+    /// `Error::Io` with any `io::ErrorKind` not covered by a more specific
+    /// code.
+    Io,
+    /// `Error::Io` with `io::ErrorKind::NotFound`.
+    IoNotFound,
+    /// `Error::Io` with `io::ErrorKind::PermissionDenied`.
+    IoPermissionDenied,
+    /// `Error::Io` with `io::ErrorKind::AlreadyExists`.
+    IoAlreadyExists,
+    /// `Error::Io` with `io::ErrorKind::TimedOut` or `io::ErrorKind::WouldBlock`.
+    IoTimedOut,
+    /// `Error::Io` with `io::ErrorKind::UnexpectedEof`.
+    IoUnexpectedEof,
+    /// `Error::Io` with `io::ErrorKind::InvalidInput` or
+    /// `io::ErrorKind::InvalidData`.
+    IoInvalidData,
+    /// `Error::Io` with a connection-related `io::ErrorKind` (refused, reset,
+    /// aborted, etc.).
+    IoConnection,
+    /// `Error::Json`.
+    Json,
+    /// `Error::KeyExpired`.
+    KeyExpired,
+    /// `Error::KeyStoreTampered`.
+    KeyStoreTampered,
+    /// `Error::MsgDecode`.
+    MsgDecode,
+    /// `Error::MsgEncode`.
+    MsgEncode,
+    /// `Error::NetTimeout`.
+    NetTimeout,
+    /// `Error::NotFound`.
+    NotFound,
+    /// `Error::Nul`.
+    Nul,
+    /// `Error::ParseInt`.
+    ParseInt,
+    /// `Error::ParseIpAddr`.
+    ParseIpAddr,
+    /// `Error::Precondition`.
+    Precondition,
+    /// `Error::ProcessFailed`.
+    ProcessFailed,
+    /// `Error::ProcessTimeout`.
+    ProcessTimeout,
+    /// `Error::ReadOnlyConfiguration`.
+    ReadOnlyConfiguration,
+    /// `Error::ReadOnlyKeyStore`.
+    ReadOnlyKeyStore,
+    /// `Error::Regex`.
+    Regex,
+    /// `Error::SocketOption`.
+    SocketOption,
+    /// `Error::StringParse`.
+    StringParse,
+    /// `Error::Unsupported`.
+    Unsupported,
+    /// `Error::Url`.
+    Url,
+}
+
+impl ErrorCode {
+    /// Every error code, in declaration order.
+    pub const ALL: &'static [ErrorCode] = &[
+        ErrorCode::AuthenticationFailed,
+        ErrorCode::ConfigUnknownFields,
+        ErrorCode::ConfigTooLarge,
+        ErrorCode::ConfigTooDeep,
+        ErrorCode::ConfigTooComplex,
+        ErrorCode::ConfigStringTooLong,
+        ErrorCode::ConfigCorrupted,
+        ErrorCode::Crypto,
+        ErrorCode::DigestMismatch,
+        ErrorCode::EnvVar,
+        ErrorCode::InvalidUtf8,
+        ErrorCode::HexDecode,
+        ErrorCode::Http,
+        ErrorCode::HttpContentType,
+        ErrorCode::HttpRetry,
+        ErrorCode::HttpStatus,
+        ErrorCode::InputTooBig,
+        ErrorCode::Internal,
+        ErrorCode::InvalidArgument,
+        ErrorCode::Io,
+        ErrorCode::IoNotFound,
+        ErrorCode::IoPermissionDenied,
+        ErrorCode::IoAlreadyExists,
+        ErrorCode::IoTimedOut,
+        ErrorCode::IoUnexpectedEof,
+        ErrorCode::IoInvalidData,
+        ErrorCode::IoConnection,
+        ErrorCode::Json,
+        ErrorCode::KeyExpired,
+        ErrorCode::KeyStoreTampered,
+        ErrorCode::MsgDecode,
+        ErrorCode::MsgEncode,
+        ErrorCode::NetTimeout,
+        ErrorCode::NotFound,
+        ErrorCode::Nul,
+        ErrorCode::ParseInt,
+        ErrorCode::ParseIpAddr,
+        ErrorCode::Precondition,
+        ErrorCode::ProcessFailed,
+        ErrorCode::ProcessTimeout,
+        ErrorCode::ReadOnlyConfiguration,
+        ErrorCode::ReadOnlyKeyStore,
+        ErrorCode::Regex,
+        ErrorCode::SocketOption,
+        ErrorCode::StringParse,
+        ErrorCode::Unsupported,
+        ErrorCode::Url,
+    ];
+
+    /// Returns the error code corresponding to the given `io::ErrorKind`.
+    pub fn from_io_kind(kind: ErrorKind) -> Self {
+        match kind {
+            ErrorKind::NotFound => ErrorCode::IoNotFound,
+            ErrorKind::PermissionDenied => ErrorCode::IoPermissionDenied,
+            ErrorKind::AlreadyExists => ErrorCode::IoAlreadyExists,
+            ErrorKind::TimedOut | ErrorKind::WouldBlock => ErrorCode::IoTimedOut,
+            ErrorKind::UnexpectedEof => ErrorCode::IoUnexpectedEof,
+            ErrorKind::InvalidInput | ErrorKind::InvalidData => ErrorCode::IoInvalidData,
+            ErrorKind::ConnectionRefused
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::NotConnected
+            | ErrorKind::AddrInUse
+            | ErrorKind::AddrNotAvailable
+            | ErrorKind::BrokenPipe => ErrorCode::IoConnection,
+            _ => ErrorCode::Io,
+        }
+    }
+
+    /// Returns this code's stable string representation, e.g.
+    /// "INVALID_ARGUMENT".
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::AuthenticationFailed => "AUTHENTICATION_FAILED",
+            ErrorCode::ConfigUnknownFields => "CONFIG_UNKNOWN_FIELDS",
+            ErrorCode::ConfigTooLarge => "CONFIG_TOO_LARGE",
+            ErrorCode::ConfigTooDeep => "CONFIG_TOO_DEEP",
+            ErrorCode::ConfigTooComplex => "CONFIG_TOO_COMPLEX",
+            ErrorCode::ConfigStringTooLong => "CONFIG_STRING_TOO_LONG",
+            ErrorCode::ConfigCorrupted => "CONFIG_CORRUPTED",
+            ErrorCode::Crypto => "CRYPTO",
+            ErrorCode::DigestMismatch => "DIGEST_MISMATCH",
+            ErrorCode::EnvVar => "ENV_VAR",
+            ErrorCode::InvalidUtf8 => "INVALID_UTF8",
+            ErrorCode::HexDecode => "HEX_DECODE",
+            ErrorCode::Http => "HTTP",
+            ErrorCode::HttpContentType => "HTTP_CONTENT_TYPE",
+            ErrorCode::HttpRetry => "HTTP_RETRY",
+            ErrorCode::HttpStatus => "HTTP_STATUS",
+            ErrorCode::InputTooBig => "INPUT_TOO_BIG",
+            ErrorCode::Internal => "INTERNAL",
+            ErrorCode::InvalidArgument => "INVALID_ARGUMENT",
+            ErrorCode::Io => "IO",
+            ErrorCode::IoNotFound => "IO_NOT_FOUND",
+            ErrorCode::IoPermissionDenied => "IO_PERMISSION_DENIED",
+            ErrorCode::IoAlreadyExists => "IO_ALREADY_EXISTS",
+            ErrorCode::IoTimedOut => "IO_TIMED_OUT",
+            ErrorCode::IoUnexpectedEof => "IO_UNEXPECTED_EOF",
+            ErrorCode::IoInvalidData => "IO_INVALID_DATA",
+            ErrorCode::IoConnection => "IO_CONNECTION",
+            ErrorCode::Json => "JSON",
+            ErrorCode::KeyExpired => "KEY_EXPIRED",
+            ErrorCode::KeyStoreTampered => "KEY_STORE_TAMPERED",
+            ErrorCode::MsgDecode => "MSG_DECODE",
+            ErrorCode::MsgEncode => "MSG_ENCODE",
+            ErrorCode::NetTimeout => "NET_TIMEOUT",
+            ErrorCode::NotFound => "NOT_FOUND",
+            ErrorCode::Nul => "NUL",
+            ErrorCode::ParseInt => "PARSE_INT",
+            ErrorCode::ParseIpAddr => "PARSE_IP_ADDR",
+            ErrorCode::Precondition => "PRECONDITION",
+            ErrorCode::ProcessFailed => "PROCESS_FAILED",
+            ErrorCode::ProcessTimeout => "PROCESS_TIMEOUT",
+            ErrorCode::ReadOnlyConfiguration => "READ_ONLY_CONFIGURATION",
+            ErrorCode::ReadOnlyKeyStore => "READ_ONLY_KEY_STORE",
+            ErrorCode::Regex => "REGEX",
+            ErrorCode::SocketOption => "SOCKET_OPTION",
+            ErrorCode::StringParse => "STRING_PARSE",
+            ErrorCode::Unsupported => "UNSUPPORTED",
+            ErrorCode::Url => "URL",
+        }
+    }
+
+    /// Returns the coarse category this code belongs to.
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            ErrorCode::ConfigUnknownFields
+            | ErrorCode::ConfigTooLarge
+            | ErrorCode::ConfigTooDeep
+            | ErrorCode::ConfigTooComplex
+            | ErrorCode::ConfigStringTooLong
+            | ErrorCode::EnvVar
+            | ErrorCode::InvalidUtf8
+            | ErrorCode::HexDecode
+            | ErrorCode::InputTooBig
+            | ErrorCode::InvalidArgument
+            | ErrorCode::NotFound
+            | ErrorCode::ParseInt
+            | ErrorCode::ParseIpAddr
+            | ErrorCode::Precondition
+            | ErrorCode::ReadOnlyConfiguration
+            | ErrorCode::ReadOnlyKeyStore
+            | ErrorCode::Unsupported
+            | ErrorCode::Url => ErrorCategory::Usage,
+            ErrorCode::ConfigCorrupted
+            | ErrorCode::Io
+            | ErrorCode::IoNotFound
+            | ErrorCode::IoPermissionDenied
+            | ErrorCode::IoAlreadyExists
+            | ErrorCode::IoTimedOut
+            | ErrorCode::IoUnexpectedEof
+            | ErrorCode::IoInvalidData
+            | ErrorCode::IoConnection
+            | ErrorCode::ProcessFailed
+            | ErrorCode::ProcessTimeout
+            | ErrorCode::SocketOption => ErrorCategory::Io,
+            ErrorCode::Crypto
+            | ErrorCode::DigestMismatch
+            | ErrorCode::KeyExpired
+            | ErrorCode::KeyStoreTampered => ErrorCategory::Crypto,
+            ErrorCode::AuthenticationFailed
+            | ErrorCode::Http
+            | ErrorCode::HttpContentType
+            | ErrorCode::HttpRetry
+            | ErrorCode::HttpStatus
+            | ErrorCode::NetTimeout => ErrorCategory::Http,
+            ErrorCode::Internal
+            | ErrorCode::Json
+            | ErrorCode::MsgDecode
+            | ErrorCode::MsgEncode
+            | ErrorCode::Nul
+            | ErrorCode::Regex
+            | ErrorCode::StringParse => ErrorCategory::Internal,
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Error {
+    /// Returns this error's stable, machine-readable code.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Error::AuthenticationFailed { .. } => ErrorCode::AuthenticationFailed,
+            #[cfg(feature = "configuration")]
+            Error::ConfigUnknownFields(_) => ErrorCode::ConfigUnknownFields,
+            #[cfg(feature = "configuration")]
+            Error::ConfigTooLarge { .. } => ErrorCode::ConfigTooLarge,
+            #[cfg(feature = "configuration")]
+            Error::ConfigTooDeep { .. } => ErrorCode::ConfigTooDeep,
+            #[cfg(feature = "configuration")]
+            Error::ConfigTooComplex { .. } => ErrorCode::ConfigTooComplex,
+            #[cfg(feature = "configuration")]
+            Error::ConfigStringTooLong { .. } => ErrorCode::ConfigStringTooLong,
+            #[cfg(feature = "configuration")]
+            Error::ConfigCorrupted { .. } => ErrorCode::ConfigCorrupted,
+            Error::Crypto(_) => ErrorCode::Crypto,
+            #[cfg(feature = "crypto")]
+            Error::DigestMismatch { .. } => ErrorCode::DigestMismatch,
+            Error::EnvVar(_) => ErrorCode::EnvVar,
+            Error::FromUtf8(_) | Error::FromUtf8Str(_) => ErrorCode::InvalidUtf8,
+            #[cfg(feature = "data-encoding")]
+            Error::HexDecode(_) => ErrorCode::HexDecode,
+            #[cfg(feature = "reqwest")]
+            Error::Http(_) => ErrorCode::Http,
+            Error::HttpContentType { .. } => ErrorCode::HttpContentType,
+            Error::HttpRetry(_) => ErrorCode::HttpRetry,
+            Error::HttpStatus { .. } => ErrorCode::HttpStatus,
+            Error::InputTooBig(_) => ErrorCode::InputTooBig,
+            Error::Internal(_) => ErrorCode::Internal,
+            Error::InvalidArgument(_) => ErrorCode::InvalidArgument,
+            Error::Io(e) => ErrorCode::from_io_kind(e.kind()),
+            #[cfg(feature = "serde_json")]
+            Error::Json(_) => ErrorCode::Json,
+            Error::KeyExpired { .. } => ErrorCode::KeyExpired,
+            Error::KeyStoreTampered(_) => ErrorCode::KeyStoreTampered,
+            #[cfg(feature = "rmp-serde")]
+            Error::MsgDecode(_) => ErrorCode::MsgDecode,
+            #[cfg(feature = "rmp-serde")]
+            Error::MsgEncode(_) => ErrorCode::MsgEncode,
+            Error::NetTimeout(_) => ErrorCode::NetTimeout,
+            Error::NotFound(_) => ErrorCode::NotFound,
+            Error::Nul(_) => ErrorCode::Nul,
+            Error::ParseInt(_) => ErrorCode::ParseInt,
+            Error::ParseIpAddr(_) => ErrorCode::ParseIpAddr,
+            Error::Precondition(_) => ErrorCode::Precondition,
+            Error::ProcessFailed { .. } => ErrorCode::ProcessFailed,
+            Error::ProcessTimeout(_) => ErrorCode::ProcessTimeout,
+            Error::ReadOnlyConfiguration(_) => ErrorCode::ReadOnlyConfiguration,
+            Error::ReadOnlyKeyStore(_) => ErrorCode::ReadOnlyKeyStore,
+            #[cfg(feature = "regex")]
+            Error::Regex(_) => ErrorCode::Regex,
+            Error::SocketOption { .. } => ErrorCode::SocketOption,
+            Error::StringParse(_) => ErrorCode::StringParse,
+            Error::Unsupported(_) => ErrorCode::Unsupported,
+            #[cfg(feature = "url")]
+            Error::Url(_) => ErrorCode::Url,
+            Error::WithRetryability { source, .. } => source.code(),
+        }
+    }
+
+    /// Returns whether or not the operation which failed with this error is
+    /// worth retrying. See `retry` for a generic retry loop which uses this.
+    pub fn retryability(&self) -> Retryability {
+        match self {
+            Error::Io(e) => Retryability::from_io_kind(e.kind()),
+            Error::SocketOption { error, .. } => Retryability::from_io_kind(error.kind()),
+            Error::HttpStatus { status, .. } => Retryability::from_http_status(*status),
+            #[cfg(feature = "reqwest")]
+            Error::Http(e) => {
+                if e.is_timeout() || e.is_connect() {
+                    Retryability::Transient
+                } else if let Some(status) = e.status() {
+                    Retryability::from_http_status(status.as_u16())
+                } else if e.is_builder() || e.is_redirect() {
+                    Retryability::Permanent
+                } else {
+                    Retryability::Unknown
+                }
+            }
+            Error::HttpRetry(_) | Error::NetTimeout(_) | Error::ProcessTimeout(_) => {
+                Retryability::Transient
+            }
+            Error::Internal(_) | Error::ProcessFailed { .. } => Retryability::Unknown,
+            #[cfg(feature = "crypto")]
+            Error::DigestMismatch { .. } => Retryability::Unknown,
+            Error::WithRetryability { retryability, .. } => *retryability,
+            // Everything else is caused by invalid input, state, or data,
+            // which retrying won't change.
+            _ => Retryability::Permanent,
+        }
+    }
+
+    fn with_retryability(self, retryability: Retryability) -> Self {
+        let source = match self {
+            Error::WithRetryability { source, .. } => source,
+            e => Box::new(e),
+        };
+        Error::WithRetryability {
+            retryability,
+            source,
+        }
+    }
+
+    /// Mark this error as permanent, regardless of its usual retryability.
+    pub fn as_permanent(self) -> Self {
+        self.with_retryability(Retryability::Permanent)
+    }
+
+    /// Mark this error as transient, regardless of its usual retryability.
+    pub fn as_transient(self) -> Self {
+        self.with_retryability(Retryability::Transient)
+    }
+
+    /// Returns the coarse category this error belongs to.
+    pub fn category(&self) -> ErrorCategory {
+        self.code().category()
+    }
+
+    /// Returns the messages of this error's underlying causes, outermost
+    /// first. Causes whose message is identical to the previous one (e.g.
+    /// because a variant just forwards its source's message) are skipped.
+    pub fn context(&self) -> Vec<String> {
+        let mut previous = self.to_string();
+        let mut context = Vec::new();
+        let mut source = std::error::Error::source(self);
+        while let Some(cause) = source {
+            let message = cause.to_string();
+            if message != previous {
+                context.push(message.clone());
+            }
+            previous = message;
+            source = cause.source();
+        }
+        context
+    }
+
+    /// Returns a structured representation of this error, suitable for
+    /// machine-readable output:
+    /// `{"code": ..., "category": ..., "message": ..., "context": [...]}`.
+    #[cfg(feature = "serde_json")]
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "code": self.code().as_str(),
+            "category": self.category().as_str(),
+            "message": self.to_string(),
+            "context": self.context(),
+        })
+    }
+}
+
+/// RetryPolicy describes how many times, and how often, an operation is
+/// retried (see `retry`). The delay before each retry grows exponentially,
+/// starting at `initial_delay`, up to `max_delay`, plus a random amount of up
+/// to `jitter`.
+#[cfg(feature = "rand")]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RetryPolicy {
+    /// The maximum number of retries; the operation is attempted up to
+    /// `max_retries + 1` times in total.
+    pub max_retries: usize,
+    /// The delay before the first retry.
+    pub initial_delay: std::time::Duration,
+    /// How much the delay grows between each retry.
+    pub multiplier: u32,
+    /// The maximum delay between retries, before adding jitter.
+    pub max_delay: std::time::Duration,
+    /// The maximum random amount of time (with millisecond granularity) added
+    /// to each delay, so many clients failing at once don't all retry at once.
+    pub jitter: std::time::Duration,
+}
+
+#[cfg(feature = "rand")]
+impl Default for RetryPolicy {
+    /// By default, an operation is retried up to 3 times, waiting 100ms, then
+    /// 200ms, and then 400ms (plus up to 10ms of jitter) between attempts.
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            initial_delay: std::time::Duration::from_millis(100),
+            multiplier: 2,
+            max_delay: std::time::Duration::from_secs(30),
+            jitter: std::time::Duration::from_millis(10),
+        }
+    }
+}
+
+#[cfg(feature = "rand")]
+impl RetryPolicy {
+    /// Return how long to wait before the given retry (starting from 1).
+    pub fn delay<R: rand::Rng>(&self, retry: usize, rng: &mut R) -> std::time::Duration {
+        let exponent = u32::try_from(retry.saturating_sub(1)).unwrap_or(u32::MAX);
+        let delay = self
+            .multiplier
+            .checked_pow(exponent)
+            .and_then(|factor| self.initial_delay.checked_mul(factor))
+            .map_or(self.max_delay, |delay| delay.min(self.max_delay));
+        let jitter = match self.jitter.as_millis() as u64 {
+            0 => 0,
+            jitter => rng.gen_range(0..jitter),
+        };
+        delay.saturating_add(std::time::Duration::from_millis(jitter))
+    }
+}
+
+/// Call `op` until it succeeds, according to the given policy, returning the
+/// final result. Errors whose `retryability` is `Retryability::Permanent` are
+/// returned immediately; other errors are retried until the policy's retries
+/// are exhausted, in which case the last error is returned.
+#[cfg(feature = "rand")]
+pub fn retry<T, F: FnMut() -> Result<T>>(policy: &RetryPolicy, op: F) -> Result<T> {
+    retry_with_clock(policy, &crate::testing::clock::SystemClock, op)
+}
+
+/// This is identical to `retry`, except the given `Clock` is used to wait
+/// between retries. This is mainly useful for testing.
+#[cfg(feature = "rand")]
+pub fn retry_with_clock<T, F: FnMut() -> Result<T>>(
+    policy: &RetryPolicy,
+    clock: &dyn crate::testing::clock::Clock,
+    mut op: F,
+) -> Result<T> {
+    let mut rng = rand::thread_rng();
+    let mut retry = 0;
+    loop {
+        let error = match op() {
+            Ok(value) => return Ok(value),
+            Err(e) => e,
+        };
+        if retry >= policy.max_retries || error.retryability() == Retryability::Permanent {
+            return Err(error);
+        }
+        retry += 1;
+        clock.sleep(policy.delay(retry, &mut rng));
+    }
+}
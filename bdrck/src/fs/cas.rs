@@ -0,0 +1,288 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::crypto::digest::{Digest, DigestBuilder};
+use crate::error::*;
+use crate::fs::{list_dir, EntryKind, ListOptions, TempFile};
+use data_encoding::HEXLOWER;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// The digest algorithm objects are addressed by. This is recorded in each
+/// store's metadata file, so stores created with some other algorithm (e.g.
+/// by a future version) are detected instead of misread.
+pub const DIGEST_ALGORITHM: &str = "sha512";
+
+/// The name of the metadata file at the root of every store.
+const METADATA_FILE: &str = "metadata";
+/// The version of the store layout.
+const LAYOUT_VERSION: &str = "1";
+/// The directory (under the root) objects are stored in.
+const OBJECTS_DIR: &str = "objects";
+/// The directory (under the root) objects are written to before they're moved
+/// into place. This is inside the store, so it's on the same filesystem.
+const TMP_DIR: &str = "tmp";
+/// The number of hex digits of an object's digest used to name the fan-out
+/// directory it's stored in.
+const FAN_OUT_DIGITS: usize = 2;
+
+fn metadata_contents() -> String {
+    format!("version {}\ndigest {}\n", LAYOUT_VERSION, DIGEST_ALGORITHM)
+}
+
+/// Hash everything read from the given reader, returning its digest and
+/// length. If a writer is given, the data is also copied to it.
+fn hash_reader<R: Read>(mut reader: R, mut writer: Option<&mut File>) -> Result<(Digest, u64)> {
+    let mut digest = DigestBuilder::new();
+    let mut len = 0;
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        digest.update(&buf[..n]);
+        if let Some(writer) = writer.as_mut() {
+            writer.write_all(&buf[..n])?;
+        }
+        len += n as u64;
+    }
+    Ok((digest.finish(), len))
+}
+
+/// GcStats summarizes what `Store::gc` did.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct GcStats {
+    /// The number of objects which were kept, because they were live.
+    pub retained: u64,
+    /// The number of objects which were removed.
+    pub removed: u64,
+    /// The total size of the removed objects, in bytes.
+    pub bytes_freed: u64,
+}
+
+/// Store is a content-addressed file store: each object is stored in a file
+/// named after the digest of its contents, so storing the same content twice
+/// only keeps one copy.
+///
+/// Objects are written to a temporary file and then atomically renamed into
+/// place, so readers never see partially written objects, and several
+/// processes can safely add objects to the same store concurrently (if two of
+/// them add the same object at once, one identical copy simply replaces the
+/// other).
+pub struct Store {
+    root: PathBuf,
+    deep_verify: bool,
+}
+
+impl Store {
+    /// Open the store rooted at the given directory, creating it if it
+    /// doesn't exist yet. It's an error if the directory contains a store
+    /// which uses some other layout or digest algorithm.
+    pub fn open(root: &Path) -> Result<Self> {
+        fs::create_dir_all(root.join(OBJECTS_DIR))?;
+        fs::create_dir_all(root.join(TMP_DIR))?;
+
+        let metadata_path = root.join(METADATA_FILE);
+        match fs::read_to_string(&metadata_path) {
+            Ok(contents) => {
+                if contents != metadata_contents() {
+                    return Err(Error::Unsupported(format!(
+                        "content-addressed store '{}' has unsupported metadata {:?} (expected {:?})",
+                        root.display(),
+                        contents,
+                        metadata_contents()
+                    )));
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let mut file = TempFile::new_in(&root.join(TMP_DIR), ".metadata")?;
+                file.as_file_mut()
+                    .write_all(metadata_contents().as_bytes())?;
+                file.persist(&metadata_path)?;
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        Ok(Store {
+            root: root.to_path_buf(),
+            deep_verify: false,
+        })
+    }
+
+    /// Set whether or not objects' contents are re-hashed to verify them,
+    /// whenever an object is read with `get`, or when `put` finds that an
+    /// object already exists. Corrupt objects are then reported as errors by
+    /// `get`, and replaced by `put`. By default, only their sizes are checked
+    /// (by `put`).
+    pub fn with_deep_verify(mut self, deep_verify: bool) -> Self {
+        self.deep_verify = deep_verify;
+        self
+    }
+
+    /// Return the directory this store is rooted at.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Return the path the object with the given digest is stored at (whether
+    /// or not it exists).
+    pub fn object_path(&self, digest: &Digest) -> PathBuf {
+        let hex = HEXLOWER.encode(digest.as_slice());
+        let (fan_out, rest) = hex.split_at(FAN_OUT_DIGITS);
+        self.root.join(OBJECTS_DIR).join(fan_out).join(rest)
+    }
+
+    fn object_is_valid(&self, digest: &Digest, len: u64) -> Result<bool> {
+        let path = self.object_path(digest);
+        match fs::metadata(&path) {
+            Ok(metadata) if metadata.len() == len => {}
+            Ok(_) => return Ok(false),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e.into()),
+        }
+        if !self.deep_verify {
+            return Ok(true);
+        }
+        self.verify(digest)
+    }
+
+    /// Store the content read from the given reader, returning its digest.
+    /// If the store already contains this content, it isn't written again.
+    pub fn put<R: Read>(&self, reader: R) -> Result<Digest> {
+        let mut file = TempFile::new_in(&self.root.join(TMP_DIR), ".object")?;
+        let (digest, len) = hash_reader(reader, Some(file.as_file_mut()))?;
+        if self.object_is_valid(&digest, len)? {
+            // Dropping the temporary file removes it.
+            return Ok(digest);
+        }
+
+        let path = self.object_path(&digest);
+        // We checked above that the path has a fan-out directory as a parent.
+        fs::create_dir_all(path.parent().unwrap())?;
+        file.persist(&path)?;
+        Ok(digest)
+    }
+
+    /// Open the object with the given digest for reading, or return None if
+    /// this store doesn't contain it.
+    pub fn get(&self, digest: &Digest) -> Result<Option<File>> {
+        let file = match File::open(self.object_path(digest)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if self.deep_verify && !self.verify(digest)? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("object {:?} is corrupt", self.object_path(digest)),
+            )
+            .into());
+        }
+        Ok(Some(file))
+    }
+
+    /// Re-hash the object with the given digest, returning whether or not its
+    /// contents (still) match. It's an error if the object doesn't exist.
+    pub fn verify(&self, digest: &Digest) -> Result<bool> {
+        let file = File::open(self.object_path(digest))?;
+        Ok(hash_reader(file, None)?.0 == *digest)
+    }
+
+    /// Returns whether or not this store contains the object with the given
+    /// digest.
+    pub fn contains(&self, digest: &Digest) -> Result<bool> {
+        match fs::metadata(self.object_path(digest)) {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Remove the object with the given digest, returning whether or not it
+    /// existed.
+    pub fn remove(&self, digest: &Digest) -> Result<bool> {
+        match fs::remove_file(self.object_path(digest)) {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Return the digests of all of the objects in this store, in sorted
+    /// order. Any files in the objects directory which aren't named like
+    /// objects are ignored.
+    pub fn iter(&self) -> Result<std::vec::IntoIter<Digest>> {
+        let mut digests = Vec::new();
+        let fan_outs = list_dir(
+            self.root.join(OBJECTS_DIR),
+            ListOptions {
+                file_type_filter: Some(EntryKind::Directory),
+                ..Default::default()
+            },
+        )?;
+        for fan_out in fan_outs {
+            let fan_out = fan_out?;
+            for object in list_dir(fan_out.path(), ListOptions::default())? {
+                let object = object?;
+                let name = format!(
+                    "{}{}",
+                    fan_out.name().to_string_lossy(),
+                    object.name().to_string_lossy()
+                );
+                if let Some(digest) = HEXLOWER
+                    .decode(name.as_bytes())
+                    .ok()
+                    .and_then(|bytes| Digest::from_slice(&bytes).ok())
+                {
+                    digests.push(digest);
+                }
+            }
+        }
+        digests.sort_by(|a, b| a.as_slice().cmp(b.as_slice()));
+        Ok(digests.into_iter())
+    }
+
+    /// Remove every object which isn't in the given set of live objects.
+    ///
+    /// Note that objects added concurrently (after the caller computed the
+    /// live set) may be removed, so callers should make sure nothing else is
+    /// using the store while this runs.
+    pub fn gc<I: IntoIterator<Item = Digest>>(&self, live: I) -> Result<GcStats> {
+        // Digests aren't hashable, so key the set by their raw bytes.
+        let live: HashSet<Vec<u8>> = live.into_iter().map(|d| d.as_slice().to_vec()).collect();
+
+        let mut stats = GcStats::default();
+        for digest in self.iter()? {
+            if live.contains(digest.as_slice()) {
+                stats.retained += 1;
+                continue;
+            }
+            let path = self.object_path(&digest);
+            let len = match fs::metadata(&path) {
+                Ok(metadata) => metadata.len(),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if self.remove(&digest)? {
+                stats.removed += 1;
+                stats.bytes_freed += len;
+            }
+        }
+        Ok(stats)
+    }
+}
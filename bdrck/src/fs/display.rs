@@ -0,0 +1,161 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::width::{char_width, take_width};
+use std::borrow::Cow;
+use std::env;
+use std::path::{Component, Path, PathBuf};
+
+/// The ellipsis which replaces the part of a path removed by `truncate_middle`.
+const ELLIPSIS: &str = "…";
+
+/// Returns the current user's home directory, according to the HOME
+/// environment variable, if it is set to an absolute path.
+pub fn home_dir() -> Option<PathBuf> {
+    env::var_os("HOME")
+        .map(PathBuf::from)
+        .filter(|home| home.is_absolute())
+}
+
+fn str_width(s: &str) -> usize {
+    s.chars().map(char_width).sum()
+}
+
+/// Returns the given path as a string, replacing the current user's home
+/// directory (see `home_dir`) with "~" if the path is inside it.
+pub fn contract_home(path: &Path) -> Cow<'_, str> {
+    contract_home_with(path, home_dir().as_deref())
+}
+
+/// This is the same as `contract_home`, but the home directory is given
+/// explicitly, instead of being looked up from the environment.
+pub fn contract_home_with<'a>(path: &'a Path, home: Option<&Path>) -> Cow<'a, str> {
+    let rest = match home.and_then(|home| path.strip_prefix(home).ok()) {
+        // A home directory of "/" would contract everything, which is silly.
+        Some(rest) if home != Some(Path::new("/")) => rest,
+        _ => return path.to_string_lossy(),
+    };
+    match rest.as_os_str().is_empty() {
+        true => Cow::Borrowed("~"),
+        false => Cow::Owned(format!("~/{}", rest.to_string_lossy())),
+    }
+}
+
+/// Returns a relative path from `base` to `path` (which may include ".."
+/// components), if both are absolute and the relative path is shorter than
+/// `path` itself. Otherwise, `path` is returned unchanged.
+///
+/// This is purely lexical: symbolic links aren't resolved, so `base` should
+/// be canonical (e.g. as returned by `std::env::current_dir`).
+pub fn relative_if_shorter<'a>(path: &'a Path, base: &Path) -> Cow<'a, Path> {
+    if !path.is_absolute() || !base.is_absolute() {
+        return Cow::Borrowed(path);
+    }
+
+    let path_components: Vec<Component> = path.components().collect();
+    let base_components: Vec<Component> = base.components().collect();
+    let common = path_components
+        .iter()
+        .zip(base_components.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut relative = PathBuf::new();
+    for _ in common..base_components.len() {
+        relative.push("..");
+    }
+    for component in &path_components[common..] {
+        relative.push(component);
+    }
+    if relative.as_os_str().is_empty() {
+        relative.push(".");
+    }
+
+    match relative.as_os_str().len() < path.as_os_str().len() {
+        true => Cow::Owned(relative),
+        false => Cow::Borrowed(path),
+    }
+}
+
+/// Shorten the given path (as a string) to fit in `max_width` terminal
+/// columns, by replacing the middle of it with an ellipsis, e.g.
+/// "/very/long/…/leaf.txt". Wide (e.g. CJK) characters are taken into
+/// account.
+///
+/// The final component is always preserved intact if it fits (along with an
+/// ellipsis and separator before it). Otherwise, the final component is
+/// itself truncated in the middle.
+pub fn truncate_middle(path: &str, max_width: usize) -> Cow<'_, str> {
+    if str_width(path) <= max_width {
+        return Cow::Borrowed(path);
+    }
+
+    let (dir, leaf) = match path.trim_end_matches('/').rfind('/') {
+        Some(idx) => (&path[..idx + 1], &path[idx + 1..]),
+        None => ("", path),
+    };
+    let prefix_width = max_width.checked_sub(str_width(ELLIPSIS) + 1 + str_width(leaf));
+    if let (false, Some(prefix_width)) = (dir.is_empty(), prefix_width) {
+        let prefix: String = take_width(dir.chars(), prefix_width).into_iter().collect();
+        return Cow::Owned(format!("{}{}/{}", prefix, ELLIPSIS, leaf));
+    }
+
+    // The leaf itself doesn't fit, so keep its beginning and end (which
+    // often has a meaningful extension).
+    if max_width < str_width(ELLIPSIS) {
+        return Cow::Owned(String::new());
+    }
+    let available = max_width - str_width(ELLIPSIS);
+    let tail_width = available / 2;
+    let head: String = take_width(leaf.chars(), available - tail_width)
+        .into_iter()
+        .collect();
+    let mut tail = take_width(leaf.chars().rev(), tail_width);
+    tail.reverse();
+    Cow::Owned(format!(
+        "{}{}{}",
+        head,
+        ELLIPSIS,
+        tail.into_iter().collect::<String>()
+    ))
+}
+
+/// Return a "friendly" version of the given path for display to users: the
+/// home directory is contracted to "~" (see `contract_home`), or the path is
+/// made relative to the current directory (see `relative_if_shorter`) if
+/// that's even shorter, and then the result is truncated to fit in
+/// `max_width` columns (see `truncate_middle`).
+pub fn friendly(path: &Path, max_width: usize) -> String {
+    let cwd = env::current_dir().ok();
+    friendly_with(path, cwd.as_deref(), home_dir().as_deref(), max_width)
+}
+
+/// This is the same as `friendly`, but the current directory and home
+/// directory are given explicitly, instead of being looked up from the
+/// environment.
+pub fn friendly_with(
+    path: &Path,
+    base: Option<&Path>,
+    home: Option<&Path>,
+    max_width: usize,
+) -> String {
+    let contracted = contract_home_with(path, home);
+    let shortest = match base.map(|base| relative_if_shorter(path, base)) {
+        Some(Cow::Owned(relative)) if relative.as_os_str().len() < contracted.len() => {
+            Cow::Owned(relative.to_string_lossy().into_owned())
+        }
+        _ => contracted,
+    };
+    truncate_middle(&shortest, max_width).into_owned()
+}
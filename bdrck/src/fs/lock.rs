@@ -0,0 +1,420 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::error::*;
+use crate::fs::TempFile;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// The default interval between attempts to acquire a held lock, when
+/// blocking.
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// LockInfo describes the holder of a `NamedLock`. This is what is written to
+/// the lock file, so tooling can display who holds a lock (see `read`).
+///
+/// The file format is a series of `key: value` lines, as produced by this
+/// structure's `Display` implementation.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LockInfo {
+    /// The process ID of the holder.
+    pub pid: u32,
+    /// The hostname of the machine the holder is running on.
+    pub hostname: String,
+    /// When the lock was acquired.
+    pub started: SystemTime,
+    /// Uniquely identifies this particular acquisition, since several
+    /// threads in one process might hold (and break) the same lock over time.
+    token: String,
+}
+
+impl LockInfo {
+    /// Describe a lock held by the current process, acquired just now.
+    fn current() -> Result<Self> {
+        Ok(LockInfo {
+            pid: std::process::id(),
+            hostname: hostname()?,
+            started: SystemTime::now(),
+            token: thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(16)
+                .map(char::from)
+                .collect(),
+        })
+    }
+
+    /// Read the information about the current holder of the lock at the given
+    /// path. If the lock isn't held, an `io::ErrorKind::NotFound` error is
+    /// returned.
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
+        fs::read_to_string(path.as_ref())?.parse()
+    }
+
+    /// Return how long ago this lock was acquired.
+    pub fn age(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(self.started)
+            .unwrap_or_default()
+    }
+
+    /// Returns whether or not the holder of this lock is definitely gone: it
+    /// was running on this machine, and its process no longer exists. On
+    /// other machines, or on platforms where we can't tell, holders are
+    /// assumed to still be alive.
+    pub fn is_dead(&self) -> bool {
+        match hostname() {
+            Ok(hostname) if hostname == self.hostname => !process_exists(self.pid),
+            _ => false,
+        }
+    }
+
+    /// Returns whether or not this lock is stale: either its holder is dead,
+    /// or it is older than the given maximum age.
+    pub fn is_stale(&self, stale_after: Option<Duration>) -> bool {
+        self.is_dead() || stale_after.is_some_and(|stale_after| self.age() > stale_after)
+    }
+}
+
+impl fmt::Display for LockInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let started = self.started.duration_since(UNIX_EPOCH).unwrap_or_default();
+        writeln!(f, "pid: {}", self.pid)?;
+        writeln!(f, "hostname: {}", self.hostname)?;
+        writeln!(
+            f,
+            "started: {}.{:09}",
+            started.as_secs(),
+            started.subsec_nanos()
+        )?;
+        writeln!(f, "token: {}", self.token)
+    }
+}
+
+impl FromStr for LockInfo {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut pid = None;
+        let mut hostname = None;
+        let mut started = None;
+        let mut token = String::new();
+        for line in s.lines() {
+            let (key, value) = match line.split_once(": ") {
+                Some(kv) => kv,
+                None => continue,
+            };
+            match key {
+                "pid" => pid = Some(value.parse::<u32>()?),
+                "hostname" => hostname = Some(value.to_owned()),
+                "started" => {
+                    let (secs, nanos) = value.split_once('.').unwrap_or((value, "0"));
+                    started = Some(
+                        UNIX_EPOCH + Duration::new(secs.parse::<u64>()?, nanos.parse::<u32>()?),
+                    );
+                }
+                "token" => token = value.to_owned(),
+                // Ignore unknown keys, for forward compatibility.
+                _ => {}
+            }
+        }
+        match (pid, hostname, started) {
+            (Some(pid), Some(hostname), Some(started)) => Ok(LockInfo {
+                pid,
+                hostname,
+                started,
+                token,
+            }),
+            _ => Err(Error::InvalidArgument(format!(
+                "invalid lock file contents: {:?}",
+                s
+            ))),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn hostname() -> Result<String> {
+    let mut buf = [0_u8; 256];
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    Ok(String::from_utf8_lossy(&buf[..len]).into_owned())
+}
+
+#[cfg(not(unix))]
+fn hostname() -> Result<String> {
+    Ok(std::env::var("COMPUTERNAME").unwrap_or_default())
+}
+
+#[cfg(unix)]
+fn process_exists(pid: u32) -> bool {
+    let pid = match libc::pid_t::try_from(pid) {
+        Ok(pid) if pid > 0 => pid,
+        _ => return false,
+    };
+    // Signal 0 checks whether the process exists, without signaling it.
+    // EPERM means it exists, but belongs to someone else.
+    let ret = unsafe { libc::kill(pid, 0) };
+    ret == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn process_exists(_: u32) -> bool {
+    true
+}
+
+/// LockOptions controls how `NamedLock::acquire` behaves if the lock is
+/// already held.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct LockOptions {
+    /// If true, wait for the lock to be released. Otherwise, fail right away
+    /// with an `io::ErrorKind::WouldBlock` error.
+    pub blocking: bool,
+    /// When blocking, give up with an `io::ErrorKind::TimedOut` error after
+    /// this long. If None, wait forever.
+    pub timeout: Option<Duration>,
+    /// Consider locks older than this stale, even if their holder appears to
+    /// still be alive. Locks whose holder is dead are always stale.
+    pub stale_after: Option<Duration>,
+    /// When blocking, how long to wait between attempts.
+    pub poll_interval: Duration,
+}
+
+impl Default for LockOptions {
+    fn default() -> Self {
+        LockOptions {
+            blocking: false,
+            timeout: None,
+            stale_after: None,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+        }
+    }
+}
+
+/// NamedLock is an advisory lock shared between processes, represented by a
+/// lock file at a well-known path, e.g. to ensure only one instance of some
+/// maintenance task runs at a time.
+///
+/// The lock file is written to a temporary file first, and then hard linked
+/// into place, which fails if the lock file already exists. Unlike `flock`,
+/// this works on most network filesystems too, and it lets us record who
+/// holds the lock (see `LockInfo`), so locks left behind by processes which
+/// died without releasing them can be detected and broken.
+///
+/// Stale detection is best effort: a holder's process can only be checked
+/// if it's on the same machine, and locks held by other machines are only
+/// stale once they exceed `LockOptions::stale_after`. Choose that timeout
+/// conservatively, since a live holder whose lock is broken won't notice.
+pub struct NamedLock;
+
+impl NamedLock {
+    /// Acquire the lock at the given path. Stale locks (see `break_stale`)
+    /// are broken automatically. The lock is released when the returned
+    /// guard is dropped.
+    pub fn acquire<P: AsRef<Path>>(path: P, options: LockOptions) -> Result<LockGuard> {
+        let path = path.as_ref();
+        let start = Instant::now();
+        loop {
+            if let Some(guard) = Self::try_acquire(path)? {
+                return Ok(guard);
+            }
+            if Self::break_stale(path, options.stale_after)? {
+                continue;
+            }
+
+            if !options.blocking {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    format!("lock {} is already held", path.display()),
+                )
+                .into());
+            }
+            if options
+                .timeout
+                .is_some_and(|timeout| start.elapsed() >= timeout)
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("timed out waiting for lock {}", path.display()),
+                )
+                .into());
+            }
+            thread::sleep(options.poll_interval);
+        }
+    }
+
+    /// Try to create the lock file once, returning None if it already
+    /// exists.
+    fn try_acquire(path: &Path) -> Result<Option<LockGuard>> {
+        let dir = match path.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new("."),
+        };
+        let info = LockInfo::current()?;
+        let mut tmp = TempFile::new_in(dir, ".lock")?;
+        tmp.as_file_mut().write_all(info.to_string().as_bytes())?;
+        tmp.as_file_mut().sync_all()?;
+        match fs::hard_link(tmp.path(), path) {
+            Ok(_) => Ok(Some(LockGuard {
+                path: path.to_path_buf(),
+                info,
+            })),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Break the lock at the given path if it is stale: if its holder is
+    /// dead, or it is older than `stale_after`. Returns whether or not the
+    /// lock was broken by this call.
+    ///
+    /// This is safe against several processes racing to break (or release)
+    /// the same lock: the lock file is only checked and removed while holding
+    /// an exclusive `flock` on it (see `open_locked`), so exactly one of them
+    /// removes a given lock file, and never one which has replaced it.
+    pub fn break_stale<P: AsRef<Path>>(path: P, stale_after: Option<Duration>) -> Result<bool> {
+        let path = path.as_ref();
+        let file = match open_locked(path)? {
+            None => return Ok(false),
+            Some(file) => file,
+        };
+        let info: LockInfo = io::read_to_string(&file)?.parse()?;
+        if !info.is_stale(stale_after) {
+            return Ok(false);
+        }
+
+        warn!(
+            "breaking stale lock {} held by pid {} on {}",
+            path.display(),
+            info.pid,
+            info.hostname
+        );
+        fs::remove_file(path)?;
+        Ok(true)
+    }
+}
+
+/// Open the lock file at the given path, and take an exclusive `flock` on it
+/// (waiting for anyone else who has one). Lock files are only ever removed
+/// while holding this, so once we have it, as long as the path still refers
+/// to the file we opened, nobody else can remove it (or replace it, since
+/// acquiring only succeeds if the path doesn't exist). Returns None if there
+/// is no lock file, or if it was removed while we were waiting.
+fn open_locked(path: &Path) -> Result<Option<fs::File>> {
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    flock_exclusive(&file)?;
+    let current = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(match is_same_file(&file.metadata()?, &current) {
+        false => None,
+        true => Some(file),
+    })
+}
+
+#[cfg(unix)]
+fn flock_exclusive(file: &fs::File) -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    loop {
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } == 0 {
+            return Ok(());
+        }
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(error.into());
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn flock_exclusive(_: &fs::File) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn is_same_file(a: &fs::Metadata, b: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+#[cfg(not(unix))]
+fn is_same_file(_: &fs::Metadata, _: &fs::Metadata) -> bool {
+    true
+}
+
+/// LockGuard represents a held `NamedLock`. The lock is released when it is
+/// dropped (including while unwinding from a panic).
+#[derive(Debug)]
+pub struct LockGuard {
+    path: PathBuf,
+    info: LockInfo,
+}
+
+impl LockGuard {
+    /// Return the path to the lock file.
+    pub fn path(&self) -> &Path {
+        self.path.as_path()
+    }
+
+    /// Return the information recorded in the lock file.
+    pub fn info(&self) -> &LockInfo {
+        &self.info
+    }
+}
+
+impl Drop for LockGuard {
+    fn drop(&mut self) {
+        // If our lock was broken and someone else holds it now, leave theirs
+        // alone.
+        let file = match open_locked(self.path.as_path()) {
+            Ok(Some(file)) => file,
+            Ok(None) => {
+                warn!("lock {} was broken while we held it", self.path.display());
+                return;
+            }
+            Err(e) => {
+                warn!("failed to release lock {}: {}", self.path.display(), e);
+                return;
+            }
+        };
+        match io::read_to_string(&file)
+            .map_err(Error::from)
+            .and_then(|s| s.parse::<LockInfo>())
+        {
+            Ok(info) if info == self.info => {
+                if let Err(e) = fs::remove_file(self.path.as_path()) {
+                    warn!("failed to release lock {}: {}", self.path.display(), e);
+                }
+            }
+            _ => warn!("lock {} was broken while we held it", self.path.display()),
+        }
+    }
+}
@@ -0,0 +1,2413 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::error::*;
+use errno;
+use libc;
+use once_cell::sync::Lazy;
+use once_cell::unsync::OnceCell;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+#[cfg(not(target_os = "windows"))]
+use std::ffi::CStr;
+use std::ffi::{CString, OsStr, OsString};
+use std::fmt;
+use std::fs::{self, Permissions};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tracing::{debug, warn};
+
+/// xattr provides functions for reading and modifying files' extended
+/// attributes.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+pub mod xattr;
+
+/// display provides helpers for presenting paths to users, e.g. contracting
+/// the home directory to "~", or truncating long paths to fit in a column.
+pub mod display;
+
+/// lock provides a named lock shared between processes, based upon lock
+/// files, with detection of stale locks.
+pub mod lock;
+
+/// watch provides a simple polling-based watcher, which reports when files or
+/// directories are created, modified, or removed.
+pub mod watch;
+
+/// cas provides a content-addressed file store, e.g. for caches or
+/// deduplicated storage.
+#[cfg(feature = "crypto")]
+pub mod cas;
+
+/// Returns the given Path as a byte vector. This function may be useful for
+/// some kinds of serialization, or for calling C functions.
+#[cfg(not(target_os = "windows"))]
+pub fn path_to_bytes<P: AsRef<Path>>(path: P) -> Result<Vec<u8>> {
+    use std::os::unix::ffi::OsStrExt;
+
+    Ok(Vec::from(path.as_ref().as_os_str().as_bytes()))
+}
+
+/// Returns the given path as a byte vector. Note that on Windows OS strings
+/// are UTF-16, so the returned bytes are *not* necessarily valid UTF-8.
+/// However, is is guaranteed that the path can be reconstructed losslessly
+/// via path_from_bytes.
+#[cfg(target_os = "windows")]
+pub fn path_to_bytes<P: AsRef<Path>>(path: P) -> Result<Vec<u8>> {
+    use byteorder::{BigEndian, WriteBytesExt};
+    use std::os::windows::ffi::OsStrExt;
+
+    let chars: Vec<u16> = path.as_ref().as_os_str().encode_wide().collect();
+    let mut bytes: Vec<u8> = Vec::with_capacity(chars.len() * 2);
+    for c in chars {
+        bytes.write_u16::<BigEndian>(c)?;
+    }
+    Ok(bytes)
+}
+
+/// Construct a PathBuf from its byte representation, for example as returned by
+/// `path_to_bytes`.
+#[cfg(not(target_os = "windows"))]
+pub fn path_from_bytes(bytes: Vec<u8>) -> Result<PathBuf> {
+    use std::os::unix::ffi::OsStringExt;
+
+    Ok(PathBuf::from(OsString::from_vec(bytes)))
+}
+
+/// Construct a PathBuf from its UTF-16 byte representation. This function may
+/// fail if converting to a vector of u16 fails.
+#[cfg(target_os = "windows")]
+pub fn path_from_bytes(mut bytes: Vec<u8>) -> Result<PathBuf> {
+    use byteorder::{BigEndian, ReadBytesExt};
+    use std::os::windows::ffi::OsStringExt;
+
+    let mut chars: Vec<u16> = vec![0; bytes.len() / 2];
+    bytes.read_u16_into::<BigEndian>(chars.as_mut_slice())?;
+    Ok(PathBuf::from(OsString::from_wide(chars.as_slice())))
+}
+
+/// This is a simple utility to create a new empty file. If a file at the given
+/// path already exists, it will be truncated. It's an error if the path
+/// already exists but is, for example, a directory.
+pub fn create_file<P: AsRef<Path>>(path: P) -> Result<()> {
+    let _f = fs::File::create(path)?;
+    Ok(())
+}
+
+/// An implementation of a function to create symbolic links on UNIX-style
+/// OSes. This works equivalently to "ln -s target symlink".
+#[cfg(not(target_os = "windows"))]
+pub fn create_symlink<T: AsRef<Path>, S: AsRef<Path>>(target: T, symlink: S) -> Result<()> {
+    Ok(::std::os::unix::fs::symlink(target, symlink)?)
+}
+
+/// An implementation of a fucntion to create symbolic links on Windows.
+/// Windows has some weird policies here, in that this usually requires
+/// administrator rights, and the underlying function call is different for
+/// files and directories. This function attempts to handle all of this, but
+/// the runtime semantics may differ from UNIX.
+#[cfg(target_os = "windows")]
+pub fn create_symlink<T: AsRef<Path>, S: AsRef<Path>>(target: T, symlink: S) -> Result<()> {
+    Ok(if target.as_ref().is_dir() {
+        ::std::os::windows::fs::symlink_dir(target, symlink)?
+    } else {
+        ::std::os::windows::fs::symlink_file(target, symlink)?
+    })
+}
+
+/// Set the permissions mode for the given file or directory. This is roughly
+/// equivalent to `chmod(2)` on Linux. Note that UNIX-style systems do not
+/// support changing mode for a symlink itself, so this function always follows
+/// symlinks (see `man 2 fchmodat` for details).
+#[cfg(not(target_os = "windows"))]
+pub fn set_permissions_mode<P: AsRef<Path>>(path: P, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let permissions = Permissions::from_mode(mode);
+    Ok(::std::fs::set_permissions(path, permissions)?)
+}
+
+/// This function sets the UNIX-style permissions mode of the file at the
+/// given path. On Windows, this concept is generally not supported, so
+/// this function is just a no-op. This function exists so callers can
+/// deal with this in a platform-agnostic way.
+#[cfg(target_os = "windows")]
+pub fn set_permissions_mode<P: AsRef<Path>>(_: P, _: u32) -> Result<()> {
+    Ok(())
+}
+
+// The classes of users a symbolic mode clause can apply to.
+const MODE_WHO_USER: u8 = 1;
+const MODE_WHO_GROUP: u8 = 2;
+const MODE_WHO_OTHER: u8 = 4;
+const MODE_WHO_ALL: u8 = MODE_WHO_USER | MODE_WHO_GROUP | MODE_WHO_OTHER;
+
+// The permissions a symbolic mode clause can add, remove, or set.
+const MODE_PERM_READ: u8 = 1;
+const MODE_PERM_WRITE: u8 = 2;
+const MODE_PERM_EXECUTE: u8 = 4;
+const MODE_PERM_EXECUTE_CONDITIONAL: u8 = 8;
+const MODE_PERM_SETID: u8 = 16;
+const MODE_PERM_STICKY: u8 = 32;
+
+const MODE_WHO_CHARS: &[(char, u8)] = &[
+    ('u', MODE_WHO_USER),
+    ('g', MODE_WHO_GROUP),
+    ('o', MODE_WHO_OTHER),
+    ('a', MODE_WHO_ALL),
+];
+
+const MODE_PERM_CHARS: &[(char, u8)] = &[
+    ('r', MODE_PERM_READ),
+    ('w', MODE_PERM_WRITE),
+    ('x', MODE_PERM_EXECUTE),
+    ('X', MODE_PERM_EXECUTE_CONDITIONAL),
+    ('s', MODE_PERM_SETID),
+    ('t', MODE_PERM_STICKY),
+];
+
+/// The operations a symbolic mode clause can perform.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ModeOp {
+    /// Add the given permissions ("+").
+    Add,
+    /// Remove the given permissions ("-").
+    Remove,
+    /// Set exactly the given permissions, clearing any others ("=").
+    Set,
+}
+
+impl ModeOp {
+    fn to_char(self) -> char {
+        match self {
+            ModeOp::Add => '+',
+            ModeOp::Remove => '-',
+            ModeOp::Set => '=',
+        }
+    }
+}
+
+/// A single comma-separated clause of a symbolic mode, e.g. "u+rwX".
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ModeClause {
+    who: u8,
+    actions: Vec<(ModeOp, u8)>,
+}
+
+impl ModeClause {
+    fn parse(clause: &str) -> Result<Self> {
+        let invalid = || Error::InvalidArgument(format!("invalid symbolic mode '{}'", clause));
+
+        let mut chars = clause.chars().peekable();
+        let mut who: u8 = 0;
+        while let Some(&(_, w)) = chars
+            .peek()
+            .and_then(|c| MODE_WHO_CHARS.iter().find(|(wc, _)| wc == c))
+        {
+            who |= w;
+            chars.next();
+        }
+        // Like chmod, no explicit class means all classes. Unlike chmod, the
+        // umask is not taken into account.
+        if who == 0 {
+            who = MODE_WHO_ALL;
+        }
+
+        let mut actions = Vec::new();
+        while let Some(c) = chars.next() {
+            let op = match c {
+                '+' => ModeOp::Add,
+                '-' => ModeOp::Remove,
+                '=' => ModeOp::Set,
+                _ => return Err(invalid()),
+            };
+            let mut perms: u8 = 0;
+            while let Some(&(_, p)) = chars
+                .peek()
+                .and_then(|c| MODE_PERM_CHARS.iter().find(|(pc, _)| pc == c))
+            {
+                perms |= p;
+                chars.next();
+            }
+            actions.push((op, perms));
+        }
+        if actions.is_empty() {
+            return Err(invalid());
+        }
+
+        Ok(ModeClause { who, actions })
+    }
+
+    /// Return the mode bits the given permissions correspond to, for this
+    /// clause's classes.
+    fn bits(&self, perms: u8, current: u32, is_dir: bool) -> u32 {
+        let class_mask = self.class_mask();
+        let mut bits: u32 = 0;
+        if perms & MODE_PERM_READ != 0 {
+            bits |= 0o444 & class_mask;
+        }
+        if perms & MODE_PERM_WRITE != 0 {
+            bits |= 0o222 & class_mask;
+        }
+        if perms & MODE_PERM_EXECUTE != 0 {
+            bits |= 0o111 & class_mask;
+        }
+        // "X" means execute, but only for directories, or for files which are
+        // already executable by someone.
+        if perms & MODE_PERM_EXECUTE_CONDITIONAL != 0 && (is_dir || current & 0o111 != 0) {
+            bits |= 0o111 & class_mask;
+        }
+        if perms & MODE_PERM_SETID != 0 {
+            bits |= self.special_mask() & 0o6000;
+        }
+        if perms & MODE_PERM_STICKY != 0 {
+            bits |= self.special_mask() & 0o1000;
+        }
+        bits
+    }
+
+    /// The rwx bits belonging to this clause's classes.
+    fn class_mask(&self) -> u32 {
+        let mut mask = 0;
+        if self.who & MODE_WHO_USER != 0 {
+            mask |= 0o700;
+        }
+        if self.who & MODE_WHO_GROUP != 0 {
+            mask |= 0o070;
+        }
+        if self.who & MODE_WHO_OTHER != 0 {
+            mask |= 0o007;
+        }
+        mask
+    }
+
+    /// The setuid / setgid / sticky bits belonging to this clause's classes.
+    fn special_mask(&self) -> u32 {
+        let mut mask = 0;
+        if self.who & MODE_WHO_USER != 0 {
+            mask |= 0o4000;
+        }
+        if self.who & MODE_WHO_GROUP != 0 {
+            mask |= 0o2000;
+        }
+        if self.who & MODE_WHO_OTHER != 0 {
+            mask |= 0o1000;
+        }
+        mask
+    }
+
+    fn apply(&self, mut mode: u32, is_dir: bool) -> u32 {
+        for &(op, perms) in &self.actions {
+            let bits = self.bits(perms, mode, is_dir);
+            match op {
+                ModeOp::Add => mode |= bits,
+                ModeOp::Remove => mode &= !bits,
+                ModeOp::Set => mode = (mode & !(self.class_mask() | self.special_mask())) | bits,
+            }
+        }
+        mode
+    }
+}
+
+impl fmt::Display for ModeClause {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.who == MODE_WHO_ALL {
+            write!(f, "a")?;
+        } else {
+            for &(c, w) in &MODE_WHO_CHARS[..3] {
+                if self.who & w != 0 {
+                    write!(f, "{}", c)?;
+                }
+            }
+        }
+        for &(op, perms) in &self.actions {
+            write!(f, "{}", op.to_char())?;
+            for &(c, p) in MODE_PERM_CHARS {
+                if perms & p != 0 {
+                    write!(f, "{}", c)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Mode is a UNIX-style permissions mode, as understood by chmod(1). It can be
+/// parsed either from an absolute octal string like "0640", or from a
+/// symbolic expression like "u+rwX,go-w", which modifies a file's existing
+/// mode.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Mode {
+    /// An absolute mode, consisting of permission bits (including the
+    /// setuid, setgid, and sticky bits).
+    Absolute(u32),
+    /// A symbolic mode, which is applied relative to a file's current mode.
+    Symbolic(Vec<ModeClause>),
+}
+
+impl Mode {
+    /// Return the mode which results from applying this mode to a file or
+    /// directory whose current mode is `current`. For absolute modes, this
+    /// is just the mode itself.
+    pub fn apply(&self, current: u32, is_dir: bool) -> u32 {
+        match self {
+            Mode::Absolute(mode) => *mode,
+            Mode::Symbolic(clauses) => clauses
+                .iter()
+                .fold(current & 0o7777, |mode, clause| clause.apply(mode, is_dir)),
+        }
+    }
+}
+
+impl FromStr for Mode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if !s.is_empty() && s.chars().all(|c| c.is_digit(8)) {
+            let mode = u32::from_str_radix(s, 8)?;
+            if mode > 0o7777 {
+                return Err(Error::InvalidArgument(format!(
+                    "invalid octal mode '{}'",
+                    s
+                )));
+            }
+            return Ok(Mode::Absolute(mode));
+        }
+
+        Ok(Mode::Symbolic(
+            s.split(',')
+                .map(ModeClause::parse)
+                .collect::<Result<Vec<_>>>()?,
+        ))
+    }
+}
+
+impl fmt::Display for Mode {
+    /// Absolute modes are formatted like `ls -l` does, e.g. "rw-r-----".
+    /// Symbolic modes are formatted as a symbolic expression.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Mode::Absolute(mode) => {
+                const CLASSES: &[(u32, u32, char)] =
+                    &[(6, 0o4000, 's'), (3, 0o2000, 's'), (0, 0o1000, 't')];
+                for &(shift, special, special_char) in CLASSES {
+                    let bits = (mode >> shift) & 0o7;
+                    let is_special = mode & special != 0;
+                    write!(
+                        f,
+                        "{}{}{}",
+                        if bits & 0o4 != 0 { 'r' } else { '-' },
+                        if bits & 0o2 != 0 { 'w' } else { '-' },
+                        match (bits & 0o1 != 0, is_special) {
+                            (true, true) => special_char,
+                            (false, true) => special_char.to_ascii_uppercase(),
+                            (true, false) => 'x',
+                            (false, false) => '-',
+                        }
+                    )?;
+                }
+                Ok(())
+            }
+            Mode::Symbolic(clauses) => {
+                for (i, clause) in clauses.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", clause)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Return the permissions mode of the given file or directory (following
+/// symlinks), as an absolute `Mode`.
+#[cfg(not(target_os = "windows"))]
+pub fn get_mode<P: AsRef<Path>>(path: P) -> Result<Mode> {
+    use std::os::unix::fs::PermissionsExt;
+    Ok(Mode::Absolute(
+        fs::metadata(path)?.permissions().mode() & 0o7777,
+    ))
+}
+
+/// Set the permissions mode of the given file or directory (following
+/// symlinks). Symbolic modes are applied relative to its current mode, like
+/// chmod(1) does.
+#[cfg(not(target_os = "windows"))]
+pub fn set_mode<P: AsRef<Path>>(path: P, mode: &Mode) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let metadata = fs::metadata(path.as_ref())?;
+    let new_mode = mode.apply(metadata.permissions().mode(), metadata.is_dir());
+    set_permissions_mode(path, new_mode)
+}
+
+/// Set the permissions mode of the given file or directory. On Windows, this
+/// concept is generally not supported, so this function is just a no-op.
+#[cfg(target_os = "windows")]
+pub fn set_mode<P: AsRef<Path>>(_: P, _: &Mode) -> Result<()> {
+    Ok(())
+}
+
+/// This function is a safe wrapper around chown(). If fail_on_access_denied
+/// is set to true, then an EACCES error is considered a failure, and we'll
+/// return Err(...). Otherwise, this is considered a soft failure, and a warning
+/// will be logged, but Ok(()) will still be returned.
+#[cfg(not(target_os = "windows"))]
+pub fn set_ownership<P: AsRef<Path>>(
+    path: P,
+    uid: u32,
+    gid: u32,
+    fail_on_access_denied: bool,
+    follow: bool,
+) -> Result<()> {
+    use errno::errno;
+    use libc::c_int;
+
+    let path_cstr = CString::new(path_to_bytes(path.as_ref())?)?;
+    let ret: c_int = unsafe {
+        match follow {
+            false => libc::lchown(path_cstr.as_ptr(), uid, gid),
+            true => libc::chown(path_cstr.as_ptr(), uid, gid),
+        }
+    };
+
+    if ret == -1 {
+        let error = errno();
+        if (error.0 == libc::EACCES || error.0 == libc::EPERM) && !fail_on_access_denied {
+            warn!(
+                "Failed to change ownership of '{}': {}",
+                path.as_ref().display(),
+                error
+            );
+        } else {
+            return Err(std::io::Error::from_raw_os_error(error.into()).into());
+        }
+    }
+
+    Ok(())
+}
+
+/// This function is a safe implementation of chown(), effectively. On
+/// Windows there is no concept of file ownership, so this function is a
+/// no-op.
+#[cfg(target_os = "windows")]
+pub fn set_ownership<P: AsRef<Path>>(_: P, _: u32, _: u32, _: bool, _: bool) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(not(target_os = "windows"))]
+#[derive(Debug)]
+enum SysconfBufferKind {
+    User,
+    Group,
+}
+
+#[cfg(not(target_os = "windows"))]
+fn get_sysconf_buffer_size(kind: SysconfBufferKind) -> usize {
+    let k = match kind {
+        SysconfBufferKind::User => libc::_SC_GETPW_R_SIZE_MAX,
+        SysconfBufferKind::Group => libc::_SC_GETGR_R_SIZE_MAX,
+    };
+
+    let mut s = unsafe { libc::sysconf(k) } as usize;
+
+    /*
+     * This platform might not have any real maximum, in which case -1 is returned. Check for this,
+     * so we don't try to allocate a buffer of 2^64 bytes. :)
+     *
+     * In this case, we just default to 1024 bytes, which seems to be a common value returned by
+     * most Linux libc implementations.
+     *
+     * Also, just for safety's sake, deal with any "unreasonably large" value.
+     */
+    if s == usize::MAX {
+        debug!(
+            "libc has no maximum {:?} buffer size; defaulting to 1024 bytes",
+            kind
+        );
+        s = 1024;
+    } else if s > 1024 * 1024 * 10 {
+        debug!(
+            "libc returned unreasonable {:?} buffer size ({} bytes); defaulting to 1024 bytes",
+            kind, s
+        );
+        s = 1024;
+    }
+
+    s
+}
+
+/// Returns the UNIX uid for the user with the given name.
+#[cfg(not(target_os = "windows"))]
+fn lookup_uid(name: &str) -> Result<u32> {
+    let mut passwd = unsafe {
+        mem::transmute::<[u8; mem::size_of::<libc::passwd>()], libc::passwd>(
+            [0_u8; mem::size_of::<libc::passwd>()],
+        )
+    };
+    let mut passwd_ptr: *mut libc::passwd = ptr::null_mut();
+    let cname = CString::new(name)?;
+    let buf_len = get_sysconf_buffer_size(SysconfBufferKind::User);
+    let mut buf = vec![0_i8; buf_len];
+    let ret = unsafe {
+        libc::getpwnam_r(
+            cname.as_ptr(),
+            &mut passwd,
+            buf.as_mut_ptr(),
+            buf_len,
+            &mut passwd_ptr,
+        )
+    };
+    if passwd_ptr.is_null() {
+        if ret == 0
+            || ret == libc::ENOENT
+            || ret == libc::ESRCH
+            || ret == libc::EBADF
+            || ret == libc::EPERM
+        {
+            return Err(Error::NotFound(format!(
+                "unrecognized user name '{}'",
+                name
+            )));
+        } else {
+            return Err(std::io::Error::from_raw_os_error(ret).into());
+        }
+    }
+    Ok(passwd.pw_uid)
+}
+
+/// Returns the UNIX gid for the group with the given name.
+#[cfg(not(target_os = "windows"))]
+fn lookup_gid(name: &str) -> Result<u32> {
+    let mut group = libc::group {
+        gr_name: ptr::null_mut(),
+        gr_passwd: ptr::null_mut(),
+        gr_gid: 0,
+        gr_mem: ptr::null_mut(),
+    };
+    let mut group_ptr: *mut libc::group = ptr::null_mut();
+    let cname = CString::new(name)?;
+    let buf_len = get_sysconf_buffer_size(SysconfBufferKind::Group);
+    let mut buf = vec![0_i8; buf_len];
+    let ret = unsafe {
+        libc::getgrnam_r(
+            cname.as_ptr(),
+            &mut group,
+            buf.as_mut_ptr(),
+            buf_len,
+            &mut group_ptr,
+        )
+    };
+    if group_ptr.is_null() {
+        if ret == 0
+            || ret == libc::ENOENT
+            || ret == libc::ESRCH
+            || ret == libc::EBADF
+            || ret == libc::EPERM
+        {
+            return Err(Error::NotFound(format!(
+                "unrecognized group name '{}'",
+                name
+            )));
+        } else {
+            return Err(std::io::Error::from_raw_os_error(ret).into());
+        }
+    }
+    Ok(group.gr_gid)
+}
+
+/// Set the user and group ownership of a file or directory. This is a
+/// convenience wrapper around `set_ownership` which allows the user and group
+/// to be specified by name instead of by ID.
+#[cfg(not(target_os = "windows"))]
+pub fn set_ownership_by_name<P: AsRef<Path>>(
+    path: P,
+    user: &str,
+    group: &str,
+    fail_on_access_denied: bool,
+    follow: bool,
+) -> Result<()> {
+    set_ownership(
+        path,
+        lookup_uid(user)?,
+        lookup_gid(group)?,
+        fail_on_access_denied,
+        follow,
+    )
+}
+
+/// Set the user and group ownership of a file or directory. This is a
+/// convenience wrapper around `set_ownership` which allows the user and group
+/// to be specified by name instead of by ID.
+#[cfg(target_os = "windows")]
+pub fn set_ownership_by_name<P: AsRef<Path>>(
+    _: P,
+    _: &str,
+    _: &str,
+    _: bool,
+    _: bool,
+) -> Result<()> {
+    Ok(())
+}
+
+/// Change the user and / or group ownership of a file or directory (following
+/// symlinks), specified by name. Whichever of `user` or `group` is None is
+/// left unchanged. Unknown names result in `Error::NotFound`, and lacking
+/// permission to change ownership results in an `Error::Io` with kind
+/// `PermissionDenied`.
+#[cfg(not(target_os = "windows"))]
+pub fn chown_by_name<P: AsRef<Path>>(
+    path: P,
+    user: Option<&str>,
+    group: Option<&str>,
+) -> Result<()> {
+    // chown(2) leaves the owner or group unchanged if the ID is -1.
+    let uid = user.map(lookup_uid).transpose()?.unwrap_or(u32::MAX);
+    let gid = group.map(lookup_gid).transpose()?.unwrap_or(u32::MAX);
+    set_ownership(path, uid, gid, true, true)
+}
+
+/// Change the user and / or group ownership of a file or directory. On
+/// Windows there is no concept of file ownership, so this function is a
+/// no-op.
+#[cfg(target_os = "windows")]
+pub fn chown_by_name<P: AsRef<Path>>(_: P, _: Option<&str>, _: Option<&str>) -> Result<()> {
+    Ok(())
+}
+
+/// The number of random characters appended to the prefix of temporary file or
+/// directory names.
+const TEMP_NAME_RAND_CHARS: usize = 32;
+/// The number of times we'll try to generate a unique temporary name before
+/// giving up.
+const TEMP_NAME_RAND_RETRIES: usize = 1024;
+
+/// Generate a random name suitable for a temporary file or directory, with the
+/// given prefix (if non-empty).
+pub(crate) fn random_name<R: Rng>(rng: &mut R, prefix: &str) -> String {
+    let suffix: String = rng
+        .sample_iter(&Alphanumeric)
+        .map(char::from)
+        .take(TEMP_NAME_RAND_CHARS)
+        .collect();
+    if prefix.is_empty() {
+        suffix
+    } else {
+        format!("{}-{}", prefix, suffix)
+    }
+}
+
+/// Repeatedly generate random names within the given directory, calling
+/// `create` on each one until it succeeds. `create` should fail with
+/// `AlreadyExists` if the path is already in use, in which case we'll retry
+/// with a different name.
+pub(crate) fn create_unique<R, T, F>(
+    rng: &mut R,
+    dir: &Path,
+    prefix: &str,
+    mut create: F,
+) -> Result<(PathBuf, T)>
+where
+    R: Rng,
+    F: FnMut(&Path) -> std::io::Result<T>,
+{
+    for _ in 0..TEMP_NAME_RAND_RETRIES {
+        let path = dir.join(random_name(rng, prefix));
+        match create(&path) {
+            Ok(t) => return Ok((path, t)),
+            Err(ref e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Err(Error::Io(std::io::Error::new(
+        std::io::ErrorKind::AlreadyExists,
+        "Failed to find unique random temporary name",
+    )))
+}
+
+#[cfg(not(target_os = "windows"))]
+fn create_new_private(path: &Path) -> std::io::Result<fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+}
+
+#[cfg(target_os = "windows")]
+fn create_new_private(path: &Path) -> std::io::Result<fs::File> {
+    fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(path)
+}
+
+/// FsOps is the narrow set of filesystem operations `TempFile` (and so
+/// `atomic_write`) and `list_dir` perform. Normally these go straight to the
+/// real filesystem, but tests can substitute an implementation which injects
+/// failures (see `testing::temp::FaultyFs`).
+pub(crate) trait FsOps: Send + Sync {
+    /// Exclusively create a new file, private to the current user.
+    fn create_new(&self, path: &Path) -> std::io::Result<fs::File>;
+    /// Write all of the given data to the given file.
+    fn write_all(&self, file: &mut fs::File, data: &[u8]) -> std::io::Result<()>;
+    /// Flush the given file's contents to disk.
+    fn fsync(&self, file: &fs::File) -> std::io::Result<()>;
+    /// Rename a file, replacing any existing file at the destination.
+    fn rename(&self, from: &Path, to: &Path) -> std::io::Result<()>;
+    /// Remove a file.
+    fn remove(&self, path: &Path) -> std::io::Result<()>;
+    /// Return the metadata for the given path, without following symlinks.
+    fn symlink_metadata(&self, path: &Path) -> std::io::Result<fs::Metadata>;
+}
+
+/// RealFs implements `FsOps` by simply calling the standard library.
+pub(crate) struct RealFs;
+
+impl FsOps for RealFs {
+    fn create_new(&self, path: &Path) -> std::io::Result<fs::File> {
+        create_new_private(path)
+    }
+
+    fn write_all(&self, file: &mut fs::File, data: &[u8]) -> std::io::Result<()> {
+        std::io::Write::write_all(file, data)
+    }
+
+    fn fsync(&self, file: &fs::File) -> std::io::Result<()> {
+        file.sync_all()
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> std::io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn remove(&self, path: &Path) -> std::io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> std::io::Result<fs::Metadata> {
+        fs::symlink_metadata(path)
+    }
+}
+
+static REAL_FS: Lazy<Arc<dyn FsOps>> = Lazy::new(|| Arc::new(RealFs));
+
+/// Return the (shared) `FsOps` implementation for the real filesystem.
+pub(crate) fn real_fs() -> Arc<dyn FsOps> {
+    REAL_FS.clone()
+}
+
+/// TempFile is an exclusively-created temporary file, which is removed when
+/// it goes out of scope unless it has been persisted to some final
+/// destination. Unlike `testing::temp`, this is intended for production use,
+/// e.g. for writing a file next to its destination and then atomically
+/// renaming it into place.
+///
+/// On UNIX-style OSes, the file is created with mode 0600. On other platforms
+/// the default permissions are used.
+pub struct TempFile {
+    path: PathBuf,
+    file: Option<fs::File>,
+    persisted: bool,
+    ops: Arc<dyn FsOps>,
+}
+
+impl TempFile {
+    /// Create a new temporary file in the given directory, with the given
+    /// prefix in its name. The file is created exclusively (O_EXCL), so it is
+    /// guaranteed not to have existed previously.
+    pub fn new_in(dir: &Path, prefix: &str) -> Result<TempFile> {
+        Self::new_in_with_rng(&mut thread_rng(), dir, prefix)
+    }
+
+    /// Identical to `new_in`, but the file's name additionally ends with the
+    /// given suffix (e.g. an extension like ".md", so other programs which
+    /// open the file can tell what kind of file it is).
+    pub fn new_in_with_suffix(dir: &Path, prefix: &str, suffix: &str) -> Result<TempFile> {
+        let ops = real_fs();
+        let (_, (path, file)) = create_unique(&mut thread_rng(), dir, prefix, |p| {
+            let mut path = p.as_os_str().to_os_string();
+            path.push(suffix);
+            let path = PathBuf::from(path);
+            ops.create_new(&path).map(|f| (path, f))
+        })?;
+        Ok(TempFile {
+            path,
+            file: Some(file),
+            persisted: false,
+            ops,
+        })
+    }
+
+    /// Identical to `new_in`, but the random portion of the name is generated
+    /// using the given RNG.
+    pub(crate) fn new_in_with_rng<R: Rng>(
+        rng: &mut R,
+        dir: &Path,
+        prefix: &str,
+    ) -> Result<TempFile> {
+        Self::new_in_with_rng_and_ops(rng, real_fs(), dir, prefix)
+    }
+
+    /// Identical to `new_in`, but all filesystem operations on the file are
+    /// performed via the given `FsOps`.
+    pub(crate) fn new_in_with_ops(
+        ops: Arc<dyn FsOps>,
+        dir: &Path,
+        prefix: &str,
+    ) -> Result<TempFile> {
+        Self::new_in_with_rng_and_ops(&mut thread_rng(), ops, dir, prefix)
+    }
+
+    fn new_in_with_rng_and_ops<R: Rng>(
+        rng: &mut R,
+        ops: Arc<dyn FsOps>,
+        dir: &Path,
+        prefix: &str,
+    ) -> Result<TempFile> {
+        let (path, file) = create_unique(rng, dir, prefix, |p| ops.create_new(p))?;
+        Ok(TempFile {
+            path,
+            file: Some(file),
+            persisted: false,
+            ops,
+        })
+    }
+
+    /// Exclusively create a new temporary file at exactly the given path,
+    /// instead of generating a unique name.
+    pub(crate) fn new_at(path: &Path) -> Result<TempFile> {
+        let ops = real_fs();
+        let file = ops.create_new(path)?;
+        Ok(TempFile {
+            path: path.to_path_buf(),
+            file: Some(file),
+            persisted: false,
+            ops,
+        })
+    }
+
+    /// Take ownership of an existing filesystem entry (e.g. a symlink), so it
+    /// is removed when the returned TempFile is closed or dropped. There is no
+    /// open file handle, so `as_file_mut` must not be called on the result.
+    pub(crate) fn from_existing(path: PathBuf) -> TempFile {
+        TempFile {
+            path,
+            file: None,
+            persisted: false,
+            ops: real_fs(),
+        }
+    }
+
+    /// Return the path to this temporary file.
+    pub fn path(&self) -> &Path {
+        self.path.as_path()
+    }
+
+    /// Return a mutable reference to the open file handle, e.g. for writing
+    /// contents to it.
+    pub fn as_file_mut(&mut self) -> &mut fs::File {
+        // The file is only ever taken in `persist` or `close`, both of which
+        // consume self, and is only missing to begin with for entries
+        // adopted by `from_existing`.
+        self.file.as_mut().unwrap()
+    }
+
+    /// Write all of the given data to this file.
+    pub fn write_all(&mut self, data: &[u8]) -> Result<()> {
+        let ops = self.ops.clone();
+        Ok(ops.write_all(self.as_file_mut(), data)?)
+    }
+
+    /// Flush this file's contents to disk, and then move it to the given
+    /// destination path, replacing any existing file there. If the destination
+    /// is on a different filesystem, the contents are instead copied to a new
+    /// temporary file next to the destination, which is then renamed into
+    /// place.
+    pub fn persist(self, dest: &Path) -> Result<()> {
+        let ops = self.ops.clone();
+        self.persist_with(dest, |from, to| ops.rename(from, to))
+    }
+
+    /// Identical to `persist`, but the initial rename is done by calling the
+    /// given function instead of `std::fs::rename`.
+    pub(crate) fn persist_with<F: FnOnce(&Path, &Path) -> std::io::Result<()>>(
+        mut self,
+        dest: &Path,
+        rename: F,
+    ) -> Result<()> {
+        if let Some(file) = self.file.take() {
+            self.ops.fsync(&file)?;
+        }
+
+        match rename(&self.path, dest) {
+            Ok(_) => {}
+            Err(ref e) if e.raw_os_error() == Some(libc::EXDEV) => {
+                debug!(
+                    "cross-device rename from '{}' to '{}', copying instead",
+                    self.path.display(),
+                    dest.display()
+                );
+                let dir = match dest.parent() {
+                    Some(p) if !p.as_os_str().is_empty() => p,
+                    _ => Path::new("."),
+                };
+                let mut copy = TempFile::new_in_with_ops(self.ops.clone(), dir, "")?;
+                std::io::copy(&mut fs::File::open(&self.path)?, copy.as_file_mut())?;
+                copy.persist(dest)?;
+                self.ops.remove(&self.path)?;
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        self.persisted = true;
+        Ok(())
+    }
+
+    fn close_impl(&mut self) -> Result<()> {
+        self.file = None;
+        if self.persisted {
+            return Ok(());
+        }
+        self.persisted = true;
+        Ok(self.ops.remove(self.path.as_path())?)
+    }
+
+    /// "Close" this temporary file by deleting it. This is called
+    /// automatically by the Drop implementation, but it can also be called
+    /// manually if you want to dispose of this instance and handle any errors.
+    pub fn close(mut self) -> Result<()> {
+        self.close_impl()
+    }
+}
+
+impl Drop for TempFile {
+    #[allow(unused_must_use)]
+    fn drop(&mut self) {
+        self.close_impl();
+    }
+}
+
+/// Replace the contents of the file at the given path with the given data,
+/// atomically: the data is written to a temporary file alongside it, which is
+/// flushed to disk and then renamed into place. So, even if this fails (or the
+/// system crashes), the file contains either its old or its new contents,
+/// never a mix of the two.
+pub fn atomic_write<P: AsRef<Path>>(path: P, data: &[u8]) -> Result<()> {
+    atomic_write_with(&real_fs(), path.as_ref(), data)
+}
+
+/// Identical to `atomic_write`, but all filesystem operations are performed
+/// via the given `FsOps`.
+pub(crate) fn atomic_write_with(ops: &Arc<dyn FsOps>, path: &Path, data: &[u8]) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut prefix = OsString::from(".");
+    prefix.push(path.file_name().unwrap_or_default());
+    let mut file = TempFile::new_in_with_ops(ops.clone(), dir, &prefix.to_string_lossy())?;
+    file.write_all(data)?;
+    file.persist(path)
+}
+
+/// ClonePolicy lists which methods `clone_file` is allowed to use, from
+/// cheapest to most expensive.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ClonePolicy {
+    /// Allow a copy-on-write clone (a "reflink"), on filesystems which support
+    /// it (e.g. btrfs or XFS). The clone shares storage with the original, but
+    /// the two are otherwise independent.
+    pub allow_reflink: bool,
+    /// Allow a hard link. The "copy" is the same file as the original, so this
+    /// is only appropriate if neither will ever be modified in place.
+    pub allow_hardlink: bool,
+    /// Allow an actual byte-for-byte copy.
+    pub allow_copy: bool,
+    /// When copying, only copy the source's data, recreating its holes (see
+    /// `copy_sparse`) instead of filling them in with zeros.
+    pub preserve_sparseness: bool,
+}
+
+impl Default for ClonePolicy {
+    /// By default, reflinks and copies are allowed, but hard links aren't,
+    /// since they change the semantics of modifying either file.
+    fn default() -> Self {
+        ClonePolicy {
+            allow_reflink: true,
+            allow_hardlink: false,
+            allow_copy: true,
+            preserve_sparseness: false,
+        }
+    }
+}
+
+/// CloneMethod identifies how `clone_file` produced its copy.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CloneMethod {
+    /// The copy is a copy-on-write clone of the original.
+    Reflink,
+    /// The copy is a hard link to the original.
+    HardLink,
+    /// The original's contents were copied.
+    Copy,
+}
+
+/// Returns true if the given error means a reflink isn't possible (as opposed
+/// to some other failure), so we should fall back to something else.
+#[cfg(target_os = "linux")]
+fn is_reflink_unsupported(error: &std::io::Error) -> bool {
+    match error.raw_os_error() {
+        Some(errno) => [
+            libc::EOPNOTSUPP,
+            libc::ENOTTY,
+            libc::EXDEV,
+            libc::EINVAL,
+            libc::ENOSYS,
+            libc::EPERM,
+        ]
+        .contains(&errno),
+        None => false,
+    }
+}
+
+/// Try to create `dst` as a reflink of `src`. Returns false (leaving nothing
+/// behind at `dst`) if the filesystem doesn't support this.
+#[cfg(target_os = "linux")]
+fn try_reflink(src: &fs::File, dst: &Path) -> Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    let dst_file = create_new_private(dst)?;
+    if unsafe { libc::ioctl(dst_file.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) } == 0 {
+        return Ok(true);
+    }
+    let error = std::io::Error::last_os_error();
+    drop(dst_file);
+    fs::remove_file(dst)?;
+    match is_reflink_unsupported(&error) {
+        true => Ok(false),
+        false => Err(error.into()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn try_reflink(_: &fs::File, _: &Path) -> Result<bool> {
+    Ok(false)
+}
+
+/// Returns whether or not `src` and the directory `dst` would be created in
+/// are on the same device. If this can't be determined, assume they are, and
+/// let the link / reflink attempt fail if not.
+#[cfg(not(target_os = "windows"))]
+fn is_same_device(src: &fs::Metadata, dst: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    let parent = match dst.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    fs::metadata(parent).map_or(true, |m| m.dev() == src.dev())
+}
+
+#[cfg(target_os = "windows")]
+fn is_same_device(_: &fs::Metadata, _: &Path) -> bool {
+    true
+}
+
+/// Returns whether or not the two given metadata describe the same file (e.g.
+/// the same path, or two hard links to one file).
+#[cfg(not(target_os = "windows"))]
+fn is_same_file(a: &fs::Metadata, b: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+#[cfg(target_os = "windows")]
+fn is_same_file(_: &fs::Metadata, _: &fs::Metadata) -> bool {
+    false
+}
+
+/// "Copy" the file at `src` to `dst` (which must not already exist), using the
+/// cheapest method allowed by the given policy: a reflink, then a hard link,
+/// then an actual copy. Returns which method was used.
+///
+/// If `src` and `dst` are on different devices, only copying is possible, so
+/// the other methods aren't attempted. Reflinks and copies have the same
+/// permissions as `src`.
+///
+/// If none of the allowed methods work, `Error::Unsupported` is returned.
+pub fn clone_file<S: AsRef<Path>, D: AsRef<Path>>(
+    src: S,
+    dst: D,
+    policy: ClonePolicy,
+) -> Result<CloneMethod> {
+    let (src, dst) = (src.as_ref(), dst.as_ref());
+    let mut src_file = fs::File::open(src)?;
+    let metadata = src_file.metadata()?;
+    if !metadata.is_file() {
+        return Err(Error::InvalidArgument(format!(
+            "'{}' is not a regular file",
+            src.display()
+        )));
+    }
+
+    if is_same_device(&metadata, dst) {
+        if policy.allow_reflink && try_reflink(&src_file, dst)? {
+            fs::set_permissions(dst, metadata.permissions())?;
+            return Ok(CloneMethod::Reflink);
+        }
+        if policy.allow_hardlink {
+            match fs::hard_link(src, dst) {
+                Ok(_) => return Ok(CloneMethod::HardLink),
+                // Some filesystems don't support hard links, or limit how
+                // many a file can have.
+                Err(ref e)
+                    if [libc::EXDEV, libc::EMLINK, libc::EPERM, libc::EOPNOTSUPP]
+                        .iter()
+                        .any(|&errno| e.raw_os_error() == Some(errno)) =>
+                {
+                    debug!("hard linking '{}' failed: {}", src.display(), e);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    } else {
+        debug!(
+            "'{}' and '{}' are on different devices; only copying is possible",
+            src.display(),
+            dst.display()
+        );
+    }
+
+    if !policy.allow_copy {
+        return Err(Error::Unsupported(format!(
+            "none of the allowed methods can clone '{}' to '{}'",
+            src.display(),
+            dst.display()
+        )));
+    }
+    let mut dst_file = create_new_private(dst)?;
+    match policy.preserve_sparseness {
+        false => {
+            crate::io::copy_file_ctl(
+                &mut src_file,
+                &mut dst_file,
+                crate::io::CopyOptions::default(),
+                /*fast_path=*/ true,
+            )?;
+        }
+        true => {
+            copy_sparse_file(&mut src_file, &mut dst_file)?;
+        }
+    }
+    dst_file.set_permissions(metadata.permissions())?;
+    dst_file.sync_all()?;
+    Ok(CloneMethod::Copy)
+}
+
+/// ExtentKind identifies whether an `Extent` of a file contains data, or is a
+/// hole (which reads as zeros, but has no storage allocated for it).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ExtentKind {
+    /// The extent contains data.
+    Data,
+    /// The extent is a hole.
+    Hole,
+}
+
+/// An Extent is a contiguous range of a file's contents.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Extent {
+    /// The offset of the start of this extent, in bytes.
+    pub offset: u64,
+    /// The length of this extent, in bytes.
+    pub len: u64,
+    /// Whether this extent contains data, or is a hole.
+    pub kind: ExtentKind,
+}
+
+/// Returns the result of the given `lseek` call, or None if there's no data
+/// (or hole) after the given offset.
+#[cfg(target_os = "linux")]
+fn seek_extent(file: &fs::File, offset: u64, whence: libc::c_int) -> Result<Option<u64>> {
+    use std::os::unix::io::AsRawFd;
+
+    let ret = unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, whence) };
+    if ret >= 0 {
+        return Ok(Some(ret as u64));
+    }
+    let error = std::io::Error::last_os_error();
+    match error.raw_os_error() {
+        Some(libc::ENXIO) => Ok(None),
+        Some(libc::EINVAL) | Some(libc::EOPNOTSUPP) => Err(Error::Unsupported(
+            "this filesystem doesn't support querying file extents".to_string(),
+        )),
+        _ => Err(error.into()),
+    }
+}
+
+/// Return the given file's extents, in order, using `SEEK_DATA` and
+/// `SEEK_HOLE`. Together the extents cover the whole file, and adjacent
+/// extents are always of different kinds. Filesystems which don't track holes
+/// report the whole file as a single data extent.
+///
+/// This changes the file's current offset. If the filesystem doesn't support
+/// extent queries at all, `Error::Unsupported` is returned.
+#[cfg(target_os = "linux")]
+pub fn extents(file: &fs::File) -> Result<Vec<Extent>> {
+    let size = file.metadata()?.len();
+    let mut extents = Vec::new();
+    let mut offset = 0;
+    while offset < size {
+        let data = seek_extent(file, offset, libc::SEEK_DATA)?.unwrap_or(size);
+        if data > offset {
+            extents.push(Extent {
+                offset,
+                len: data - offset,
+                kind: ExtentKind::Hole,
+            });
+        }
+        if data >= size {
+            break;
+        }
+        let hole = seek_extent(file, data, libc::SEEK_HOLE)?.unwrap_or(size);
+        extents.push(Extent {
+            offset: data,
+            len: hole - data,
+            kind: ExtentKind::Data,
+        });
+        offset = hole;
+    }
+    Ok(extents)
+}
+
+/// Return the given file's extents. This is currently only implemented on
+/// Linux.
+#[cfg(not(target_os = "linux"))]
+pub fn extents(_: &fs::File) -> Result<Vec<Extent>> {
+    Err(Error::Unsupported(
+        "querying file extents is not supported on this platform".to_string(),
+    ))
+}
+
+/// Deallocate the given range of the file, turning it into a hole (so it reads
+/// as zeros). The file's size is unchanged. If the filesystem doesn't support
+/// this, `Error::Unsupported` is returned.
+#[cfg(target_os = "linux")]
+pub fn punch_hole(file: &fs::File, offset: u64, len: u64) -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let ret = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            offset as libc::off_t,
+            len as libc::off_t,
+        )
+    };
+    if ret == 0 {
+        return Ok(());
+    }
+    let error = std::io::Error::last_os_error();
+    match error.raw_os_error() {
+        Some(libc::EOPNOTSUPP) | Some(libc::ENOSYS) => Err(Error::Unsupported(
+            "this filesystem doesn't support punching holes".to_string(),
+        )),
+        _ => Err(error.into()),
+    }
+}
+
+/// Deallocate the given range of the file. This is currently only implemented
+/// on Linux.
+#[cfg(not(target_os = "linux"))]
+pub fn punch_hole(_: &fs::File, _: u64, _: u64) -> Result<()> {
+    Err(Error::Unsupported(
+        "punching holes is not supported on this platform".to_string(),
+    ))
+}
+
+/// SparseCopyStats describes what `copy_sparse` did.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SparseCopyStats {
+    /// The number of bytes of data which were copied.
+    pub data_bytes: u64,
+    /// The number of bytes of holes which were recreated, instead of being
+    /// copied.
+    pub hole_bytes: u64,
+    /// Whether or not the source's extents could be queried. If not, the
+    /// whole file was copied (so `hole_bytes` is zero).
+    pub sparse: bool,
+}
+
+fn copy_sparse_file(src: &mut fs::File, dst: &mut fs::File) -> Result<SparseCopyStats> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let extents = match extents(src) {
+        Err(Error::Unsupported(e)) => {
+            debug!("falling back to a full copy: {}", e);
+            src.seek(SeekFrom::Start(0))?;
+            let outcome = crate::io::copy_file_ctl(
+                src,
+                dst,
+                crate::io::CopyOptions::default(),
+                /*fast_path=*/ true,
+            )?;
+            return Ok(SparseCopyStats {
+                data_bytes: outcome.bytes_copied,
+                hole_bytes: 0,
+                sparse: false,
+            });
+        }
+        r => r?,
+    };
+
+    let mut stats = SparseCopyStats {
+        sparse: true,
+        ..Default::default()
+    };
+    for extent in extents {
+        match extent.kind {
+            ExtentKind::Hole => stats.hole_bytes += extent.len,
+            ExtentKind::Data => {
+                src.seek(SeekFrom::Start(extent.offset))?;
+                dst.seek(SeekFrom::Start(extent.offset))?;
+                stats.data_bytes += std::io::copy(&mut src.by_ref().take(extent.len), dst)?;
+            }
+        }
+    }
+    // Any trailing hole (or the whole file, if it's entirely a hole) isn't
+    // created by the writes above.
+    dst.set_len(src.metadata()?.len())?;
+    Ok(stats)
+}
+
+/// Copy the file at `src` to `dst` (replacing it, if it already exists),
+/// copying only `src`'s data and recreating its holes, so the copy uses no more
+/// space than the original. `dst` gets the same permissions as `src`.
+///
+/// If `src`'s extents can't be queried (see `extents`), the whole file is
+/// copied instead. It is an error if `src` and `dst` are the same file, since
+/// replacing `dst` would destroy `src`.
+pub fn copy_sparse<S: AsRef<Path>, D: AsRef<Path>>(src: S, dst: D) -> Result<SparseCopyStats> {
+    let mut src_file = fs::File::open(src.as_ref())?;
+    let metadata = src_file.metadata()?;
+    if !metadata.is_file() {
+        return Err(Error::InvalidArgument(format!(
+            "'{}' is not a regular file",
+            src.as_ref().display()
+        )));
+    }
+    if fs::metadata(dst.as_ref()).is_ok_and(|dst_metadata| is_same_file(&metadata, &dst_metadata)) {
+        return Err(Error::InvalidArgument(format!(
+            "can't copy '{}' to itself ('{}')",
+            src.as_ref().display(),
+            dst.as_ref().display()
+        )));
+    }
+    let mut dst_file = fs::File::create(dst.as_ref())?;
+    let stats = copy_sparse_file(&mut src_file, &mut dst_file)?;
+    dst_file.set_permissions(metadata.permissions())?;
+    dst_file.sync_all()?;
+    Ok(stats)
+}
+
+/// EntryKind identifies what sort of filesystem entry something is. Symlinks
+/// are reported as such, regardless of what they point to.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum EntryKind {
+    /// A regular file (or any other non-directory, non-symlink entry).
+    File,
+    /// A directory.
+    Directory,
+    /// A symbolic link.
+    Symlink,
+}
+
+impl EntryKind {
+    fn from_file_type(file_type: &fs::FileType) -> Self {
+        if file_type.is_symlink() {
+            EntryKind::Symlink
+        } else if file_type.is_dir() {
+            EntryKind::Directory
+        } else {
+            EntryKind::File
+        }
+    }
+}
+
+/// A callback `remove_tree` calls for each entry it removes.
+pub type RemoveCallback<'a> = &'a mut dyn FnMut(&Path, EntryKind);
+
+/// RemoveOptions controls the behavior of `remove_tree`.
+#[derive(Default)]
+pub struct RemoveOptions<'a> {
+    /// If true, nothing is actually removed; entries are just reported (via
+    /// `on_entry` and the returned stats) as if they had been.
+    pub dry_run: bool,
+    /// If set, `remove_tree` refuses to remove anything unless the given path
+    /// is inside this directory.
+    pub must_be_under: Option<&'a Path>,
+    /// If true, the given path must be a directory, and only its contents are
+    /// removed, leaving it empty.
+    pub keep_root: bool,
+    /// If set, this is called for each entry just before it is removed (or,
+    /// in dry run mode, instead of removing it).
+    pub on_entry: Option<RemoveCallback<'a>>,
+}
+
+/// RemoveStats describes what `remove_tree` removed (or would have removed, in
+/// dry run mode), and what it failed to remove.
+#[derive(Debug, Default)]
+pub struct RemoveStats {
+    /// The number of files removed.
+    pub files: usize,
+    /// The number of directories removed.
+    pub directories: usize,
+    /// The number of symlinks removed.
+    pub symlinks: usize,
+    /// The total size of the files removed, in bytes.
+    pub bytes: u64,
+    /// The entries which couldn't be removed, and why. Directories whose
+    /// contents couldn't all be removed aren't listed separately.
+    pub failures: Vec<(PathBuf, Error)>,
+}
+
+impl RemoveStats {
+    /// Returns true if every entry was removed successfully.
+    pub fn is_complete(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// Resolve the given path to an absolute path without following it if it is
+/// itself a symlink (only its parent directories are resolved).
+fn canonicalize_parent(path: &Path) -> Result<PathBuf> {
+    Ok(match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) if !parent.as_os_str().is_empty() => {
+            parent.canonicalize()?.join(name)
+        }
+        (_, Some(name)) => std::env::current_dir()?.join(name),
+        _ => path.canonicalize()?,
+    })
+}
+
+/// A directory file descriptor, which is closed when dropped. `remove_tree`
+/// works relative to these, so a directory can't be swapped for a symlink
+/// between checking it and removing its contents.
+#[cfg(not(target_os = "windows"))]
+struct DirFd(libc::c_int);
+
+#[cfg(not(target_os = "windows"))]
+impl DirFd {
+    /// Open the directory with the given name inside this one (or, if
+    /// `parent` is None, the given absolute path). Symlinks are never
+    /// followed; opening one is an error.
+    fn open_at(parent: Option<&DirFd>, name: &CStr) -> std::io::Result<DirFd> {
+        let fd = unsafe {
+            libc::openat(
+                parent.map_or(libc::AT_FDCWD, |p| p.0),
+                name.as_ptr(),
+                libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(DirFd(fd))
+    }
+
+    /// Open the directory at the given canonical (absolute, symlink-free)
+    /// path, one component at a time, so a symlink swapped in for any of its
+    /// components is detected rather than followed.
+    fn open_canonical(path: &Path) -> Result<DirFd> {
+        use std::path::Component;
+
+        let mut dir = DirFd::open_at(None, &CString::new("/")?)?;
+        for component in path.components() {
+            match component {
+                Component::RootDir => {}
+                Component::Normal(name) => {
+                    dir = DirFd::open_at(Some(&dir), &CString::new(path_to_bytes(name)?)?)?
+                }
+                _ => {
+                    return Err(Error::InvalidArgument(format!(
+                        "'{}' is not a canonical path",
+                        path.display()
+                    )))
+                }
+            }
+        }
+        Ok(dir)
+    }
+
+    fn stat(&self) -> std::io::Result<libc::stat> {
+        let mut st: libc::stat = unsafe { mem::zeroed() };
+        if unsafe { libc::fstat(self.0, &mut st) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(st)
+    }
+
+    /// Stat the entry with the given name inside this directory, without
+    /// following it if it's a symlink.
+    fn stat_at(&self, name: &CStr) -> std::io::Result<libc::stat> {
+        let mut st: libc::stat = unsafe { mem::zeroed() };
+        if unsafe { libc::fstatat(self.0, name.as_ptr(), &mut st, libc::AT_SYMLINK_NOFOLLOW) } != 0
+        {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(st)
+    }
+
+    /// Return the names of the entries in this directory, other than "." and
+    /// "..".
+    fn entries(&self) -> std::io::Result<Vec<CString>> {
+        let fd = unsafe { libc::dup(self.0) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let dir = unsafe { libc::fdopendir(fd) };
+        if dir.is_null() {
+            let error = std::io::Error::last_os_error();
+            unsafe { libc::close(fd) };
+            return Err(error);
+        }
+
+        let mut entries = Vec::new();
+        let result = loop {
+            errno::set_errno(errno::Errno(0));
+            let entry = unsafe { libc::readdir(dir) };
+            if entry.is_null() {
+                break match errno::errno().0 {
+                    0 => Ok(()),
+                    e => Err(std::io::Error::from_raw_os_error(e)),
+                };
+            }
+            let name = unsafe { CStr::from_ptr((*entry).d_name.as_ptr()) };
+            if name.to_bytes() != b"." && name.to_bytes() != b".." {
+                entries.push(name.to_owned());
+            }
+        };
+        unsafe { libc::closedir(dir) };
+        result.map(|_| entries)
+    }
+
+    /// Make sure this directory is writable by its owner, so its contents can
+    /// be removed.
+    fn make_writable(&self, path: &Path) -> std::io::Result<()> {
+        let mode = self.stat()?.st_mode;
+        if mode & 0o200 == 0 {
+            debug!("making read-only directory '{}' writable", path.display());
+            if unsafe { libc::fchmod(self.0, (mode | 0o200) & 0o7777) } != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove the entry with the given name from this directory.
+    fn unlink_at(&self, name: &CStr, kind: EntryKind) -> std::io::Result<()> {
+        let flags = match kind {
+            EntryKind::Directory => libc::AT_REMOVEDIR,
+            _ => 0,
+        };
+        if unsafe { libc::unlinkat(self.0, name.as_ptr(), flags) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+impl Drop for DirFd {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn entry_kind_from_mode(mode: libc::mode_t) -> EntryKind {
+    match mode & libc::S_IFMT {
+        libc::S_IFLNK => EntryKind::Symlink,
+        libc::S_IFDIR => EntryKind::Directory,
+        _ => EntryKind::File,
+    }
+}
+
+/// Remove the contents of the given directory, returning true if all of them
+/// were removed.
+#[cfg(not(target_os = "windows"))]
+fn remove_tree_contents(
+    dir: &DirFd,
+    path: &Path,
+    options: &mut RemoveOptions<'_>,
+    stats: &mut RemoveStats,
+) -> bool {
+    if !options.dry_run {
+        if let Err(e) = dir.make_writable(path) {
+            stats.failures.push((path.to_path_buf(), e.into()));
+            return false;
+        }
+    }
+
+    let entries = match dir.entries() {
+        Err(e) => {
+            stats.failures.push((path.to_path_buf(), e.into()));
+            return false;
+        }
+        Ok(entries) => entries,
+    };
+    let mut complete = true;
+    for name in entries {
+        use std::os::unix::ffi::OsStrExt;
+        let entry_path = path.join(OsStr::from_bytes(name.to_bytes()));
+        complete &= remove_tree_entry(dir, &name, &entry_path, options, stats);
+    }
+    complete
+}
+
+/// Remove the entry with the given name from the given directory
+/// (recursively, if it's a directory), returning true if it was removed.
+/// `path` is only used to report the entry.
+#[cfg(not(target_os = "windows"))]
+fn remove_tree_entry(
+    parent: &DirFd,
+    name: &CStr,
+    path: &Path,
+    options: &mut RemoveOptions<'_>,
+    stats: &mut RemoveStats,
+) -> bool {
+    let st = match parent.stat_at(name) {
+        Err(e) => {
+            stats.failures.push((path.to_path_buf(), e.into()));
+            return false;
+        }
+        Ok(st) => st,
+    };
+    let kind = entry_kind_from_mode(st.st_mode);
+    if kind == EntryKind::Directory {
+        // O_NOFOLLOW means this fails if the directory was replaced with a
+        // symlink since we checked; also make sure it wasn't replaced with
+        // some other directory.
+        let dir = match DirFd::open_at(Some(parent), name).and_then(|dir| {
+            let opened = dir.stat()?;
+            match opened.st_dev == st.st_dev && opened.st_ino == st.st_ino {
+                true => Ok(dir),
+                false => Err(std::io::Error::other(
+                    "directory was replaced while removing it",
+                )),
+            }
+        }) {
+            Err(e) => {
+                stats.failures.push((path.to_path_buf(), e.into()));
+                return false;
+            }
+            Ok(dir) => dir,
+        };
+        if !remove_tree_contents(&dir, path, options, stats) {
+            return false;
+        }
+    }
+
+    if let Some(on_entry) = options.on_entry.as_mut() {
+        on_entry(path, kind);
+    }
+    if !options.dry_run {
+        if let Err(e) = parent.unlink_at(name, kind) {
+            stats.failures.push((path.to_path_buf(), e.into()));
+            return false;
+        }
+    }
+
+    match kind {
+        EntryKind::File => {
+            stats.files += 1;
+            stats.bytes += st.st_size as u64;
+        }
+        EntryKind::Directory => stats.directories += 1,
+        EntryKind::Symlink => stats.symlinks += 1,
+    }
+    true
+}
+
+/// Remove the contents of the given directory, returning true if all of them
+/// were removed.
+#[cfg(target_os = "windows")]
+fn remove_tree_contents(
+    path: &Path,
+    options: &mut RemoveOptions<'_>,
+    stats: &mut RemoveStats,
+) -> bool {
+    let entries = match fs::read_dir(path) {
+        Err(e) => {
+            stats.failures.push((path.to_path_buf(), e.into()));
+            return false;
+        }
+        Ok(entries) => entries,
+    };
+    let mut complete = true;
+    for entry in entries {
+        complete &= match entry {
+            Err(e) => {
+                stats.failures.push((path.to_path_buf(), e.into()));
+                false
+            }
+            Ok(entry) => remove_tree_entry(&entry.path(), options, stats),
+        };
+    }
+    complete
+}
+
+/// Remove the given entry (recursively, if it's a directory), returning true
+/// if it was removed.
+#[cfg(target_os = "windows")]
+fn remove_tree_entry(
+    path: &Path,
+    options: &mut RemoveOptions<'_>,
+    stats: &mut RemoveStats,
+) -> bool {
+    let metadata = match fs::symlink_metadata(path) {
+        Err(e) => {
+            stats.failures.push((path.to_path_buf(), e.into()));
+            return false;
+        }
+        Ok(metadata) => metadata,
+    };
+    let kind = EntryKind::from_file_type(&metadata.file_type());
+    if kind == EntryKind::Directory && !remove_tree_contents(path, options, stats) {
+        return false;
+    }
+
+    if let Some(on_entry) = options.on_entry.as_mut() {
+        on_entry(path, kind);
+    }
+    if !options.dry_run {
+        let result = match kind {
+            EntryKind::Directory => fs::remove_dir(path),
+            _ => fs::remove_file(path),
+        };
+        if let Err(e) = result {
+            stats.failures.push((path.to_path_buf(), e.into()));
+            return false;
+        }
+    }
+
+    match kind {
+        EntryKind::File => {
+            stats.files += 1;
+            stats.bytes += metadata.len();
+        }
+        EntryKind::Directory => stats.directories += 1,
+        EntryKind::Symlink => stats.symlinks += 1,
+    }
+    true
+}
+
+/// Recursively remove the file, symlink, or directory at the given path.
+/// Symlinks are never followed: a symlink is removed itself, leaving whatever
+/// it points to untouched. Read-only directories are made writable so their
+/// contents can be removed.
+///
+/// On Unix, the tree is walked relative to open directory handles, and never
+/// through symlinks, so replacing a directory with a symlink while it is being
+/// removed can't redirect the removal elsewhere.
+///
+/// Failing to remove individual entries doesn't stop the removal; instead, the
+/// failures are collected in the returned stats. An error is returned only if
+/// the removal couldn't be started at all, e.g. because `path` doesn't exist,
+/// or it isn't inside (and distinct from) the `must_be_under` directory.
+pub fn remove_tree<P: AsRef<Path>>(path: P, mut options: RemoveOptions<'_>) -> Result<RemoveStats> {
+    let path = path.as_ref();
+    let resolved = canonicalize_parent(path)?;
+    if let Some(root) = options.must_be_under {
+        let root = root.canonicalize()?;
+        if resolved == root || !resolved.starts_with(&root) {
+            return Err(Error::Precondition(format!(
+                "refusing to remove '{}', which is not inside of '{}'",
+                resolved.display(),
+                root.display()
+            )));
+        }
+    }
+
+    let mut stats = RemoveStats::default();
+    #[cfg(not(target_os = "windows"))]
+    {
+        let (parent, name) = match (resolved.parent(), resolved.file_name()) {
+            (Some(parent), Some(name)) => (parent, CString::new(path_to_bytes(name)?)?),
+            _ => {
+                return Err(Error::InvalidArgument(format!(
+                    "refusing to remove '{}'",
+                    resolved.display()
+                )))
+            }
+        };
+        let parent = DirFd::open_canonical(parent)?;
+        let st = parent.stat_at(&name)?;
+        if options.keep_root {
+            if entry_kind_from_mode(st.st_mode) != EntryKind::Directory {
+                return Err(Error::InvalidArgument(format!(
+                    "can't keep '{}', which is not a directory",
+                    path.display()
+                )));
+            }
+            let dir = DirFd::open_at(Some(&parent), &name)?;
+            remove_tree_contents(&dir, path, &mut options, &mut stats);
+        } else {
+            remove_tree_entry(&parent, &name, path, &mut options, &mut stats);
+        }
+    }
+    #[cfg(target_os = "windows")]
+    {
+        let metadata = fs::symlink_metadata(path)?;
+        if options.keep_root {
+            if !metadata.is_dir() {
+                return Err(Error::InvalidArgument(format!(
+                    "can't keep '{}', which is not a directory",
+                    path.display()
+                )));
+            }
+            remove_tree_contents(path, &mut options, &mut stats);
+        } else {
+            remove_tree_entry(path, &mut options, &mut stats);
+        }
+    }
+    Ok(stats)
+}
+
+/// ListOrder controls the order `list_dir` returns entries in.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ListOrder {
+    /// Whatever order the filesystem returns entries in. This is the only
+    /// order in which entries are streamed, rather than buffered in memory.
+    #[default]
+    Unsorted,
+    /// Sorted by name.
+    Name,
+    /// Sorted by modification time, oldest first. Ties are broken by name.
+    Mtime,
+    /// Sorted by size, smallest first. Ties are broken by name.
+    Size,
+}
+
+/// StatMode controls when `list_dir` fetches each entry's metadata.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum StatMode {
+    /// Only fetch metadata when it's needed, either for sorting or because
+    /// the caller asked for it (see `DirEntryInfo::metadata`).
+    #[default]
+    Lazy,
+    /// Fetch every entry's metadata up front. Entries which disappear before
+    /// this happens are left out of the listing.
+    Eager,
+}
+
+/// ListOptions controls the behavior of `list_dir`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ListOptions {
+    /// The order to return entries in.
+    pub order: ListOrder,
+    /// If true, entries whose names start with "." are included.
+    pub include_hidden: bool,
+    /// If set, only entries of this kind are included. Where the platform
+    /// reports entry types while listing a directory (e.g. Linux), this
+    /// doesn't require fetching each entry's metadata.
+    pub file_type_filter: Option<EntryKind>,
+    /// When to fetch each entry's metadata.
+    pub stat_mode: StatMode,
+}
+
+/// DirEntryInfo describes one of the entries returned by `list_dir`. Its
+/// metadata is fetched at most once, the first time it's needed.
+pub struct DirEntryInfo {
+    name: OsString,
+    path: PathBuf,
+    kind: EntryKind,
+    metadata: OnceCell<fs::Metadata>,
+    ops: Arc<dyn FsOps>,
+}
+
+impl DirEntryInfo {
+    /// Return this entry's file name.
+    pub fn name(&self) -> &OsStr {
+        self.name.as_os_str()
+    }
+
+    /// Return this entry's full path (the listed directory joined with its
+    /// name).
+    pub fn path(&self) -> &Path {
+        self.path.as_path()
+    }
+
+    /// Return what sort of entry this is. Symlinks are reported as such.
+    pub fn kind(&self) -> EntryKind {
+        self.kind
+    }
+
+    /// Return this entry's metadata (not following symlinks), fetching it if
+    /// it hasn't been already. This fails with `Error::Io` if e.g. the entry
+    /// was removed since it was listed.
+    pub fn metadata(&self) -> Result<&fs::Metadata> {
+        Ok(self
+            .metadata
+            .get_or_try_init(|| self.ops.symlink_metadata(self.path.as_path()))?)
+    }
+
+    /// Return this entry's size in bytes. See `metadata`.
+    pub fn size(&self) -> Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    /// Return this entry's last modification time. See `metadata`.
+    pub fn modified(&self) -> Result<SystemTime> {
+        Ok(self.metadata()?.modified()?)
+    }
+}
+
+impl fmt::Debug for DirEntryInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DirEntryInfo")
+            .field("path", &self.path)
+            .field("kind", &self.kind)
+            .field("metadata", &self.metadata.get())
+            .finish()
+    }
+}
+
+/// ListDir is the iterator `list_dir` returns.
+pub struct ListDir {
+    dir: PathBuf,
+    options: ListOptions,
+    ops: Arc<dyn FsOps>,
+    entries: ListDirEntries,
+}
+
+enum ListDirEntries {
+    Streaming(fs::ReadDir),
+    Sorted(std::vec::IntoIter<Result<DirEntryInfo>>),
+}
+
+impl ListDir {
+    /// Turn a raw directory entry into a DirEntryInfo, returning None if it
+    /// should be left out of the listing.
+    fn entry(&self, entry: std::io::Result<fs::DirEntry>) -> Option<Result<DirEntryInfo>> {
+        let entry = match entry {
+            Err(e) => return Some(Err(e.into())),
+            Ok(entry) => entry,
+        };
+        let name = entry.file_name();
+        if !self.options.include_hidden && name.to_string_lossy().starts_with('.') {
+            return None;
+        }
+        let kind = match entry.file_type() {
+            Ok(file_type) => EntryKind::from_file_type(&file_type),
+            // The entry was removed since we listed it.
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+            Err(e) => return Some(Err(e.into())),
+        };
+        if self
+            .options
+            .file_type_filter
+            .is_some_and(|filter| filter != kind)
+        {
+            return None;
+        }
+
+        let path = self.dir.join(&name);
+        let metadata = OnceCell::new();
+        let needs_metadata = self.options.stat_mode == StatMode::Eager
+            || matches!(self.options.order, ListOrder::Mtime | ListOrder::Size);
+        if needs_metadata {
+            match self.ops.symlink_metadata(path.as_path()) {
+                Ok(m) => metadata.set(m).unwrap(),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+                Err(e) => return Some(Err(e.into())),
+            }
+        }
+        Some(Ok(DirEntryInfo {
+            name,
+            path,
+            kind,
+            metadata,
+            ops: self.ops.clone(),
+        }))
+    }
+}
+
+impl Iterator for ListDir {
+    type Item = Result<DirEntryInfo>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let entry = match &mut self.entries {
+                ListDirEntries::Sorted(entries) => return entries.next(),
+                ListDirEntries::Streaming(read_dir) => read_dir.next()?,
+            };
+            if let Some(entry) = self.entry(entry) {
+                return Some(entry);
+            }
+        }
+    }
+}
+
+/// Compare two entries for sorting. Their metadata has already been fetched
+/// if the order needs it, so the comparison itself can't fail.
+fn compare_entries(order: ListOrder, a: &DirEntryInfo, b: &DirEntryInfo) -> std::cmp::Ordering {
+    let by_metadata = match order {
+        ListOrder::Mtime => a
+            .metadata
+            .get()
+            .and_then(|m| m.modified().ok())
+            .cmp(&b.metadata.get().and_then(|m| m.modified().ok())),
+        ListOrder::Size => a
+            .metadata
+            .get()
+            .map(fs::Metadata::len)
+            .cmp(&b.metadata.get().map(fs::Metadata::len)),
+        ListOrder::Unsorted | ListOrder::Name => std::cmp::Ordering::Equal,
+    };
+    by_metadata.then_with(|| a.name.cmp(&b.name))
+}
+
+/// List the entries in the given directory, without the "." and ".."
+/// entries.
+///
+/// Entries are only stat-ed if the options require it, and in
+/// `ListOrder::Unsorted` they're streamed as the filesystem returns them, so
+/// listing even a directory with a very large number of entries takes
+/// constant memory. The other orders necessarily read the whole directory
+/// before returning anything.
+///
+/// An error listing an individual entry is returned in its place, and doesn't
+/// end the listing. Entries which are removed while the directory is being
+/// listed may or may not be included; they're never reported as errors unless
+/// the caller asks for their metadata afterwards. In the sorted orders, any
+/// errors are returned after all of the entries.
+pub fn list_dir<P: AsRef<Path>>(path: P, options: ListOptions) -> Result<ListDir> {
+    list_dir_with(&real_fs(), path.as_ref(), options)
+}
+
+/// This is identical to `list_dir`, except metadata is fetched through the
+/// given `FsOps`.
+pub(crate) fn list_dir_with(
+    ops: &Arc<dyn FsOps>,
+    path: &Path,
+    options: ListOptions,
+) -> Result<ListDir> {
+    let mut list = ListDir {
+        dir: path.to_path_buf(),
+        options,
+        ops: ops.clone(),
+        entries: ListDirEntries::Streaming(fs::read_dir(path)?),
+    };
+    if options.order == ListOrder::Unsorted {
+        return Ok(list);
+    }
+
+    let (mut entries, errors): (Vec<_>, Vec<_>) = list.by_ref().partition(|e| e.is_ok());
+    entries.sort_by(|a, b| match (a, b) {
+        (Ok(a), Ok(b)) => compare_entries(options.order, a, b),
+        _ => std::cmp::Ordering::Equal,
+    });
+    entries.extend(errors);
+    list.entries = ListDirEntries::Sorted(entries.into_iter());
+    Ok(list)
+}
+
+/// Return the number of entries in the given directory (not including "."
+/// and ".."). This doesn't fetch any entry's metadata.
+pub fn count_entries<P: AsRef<Path>>(path: P) -> Result<usize> {
+    let mut count = 0;
+    for entry in fs::read_dir(path)? {
+        entry?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Return the given path if it exists, or else its nearest ancestor which
+/// does. This lets us query the filesystem a path *would* be created on.
+fn nearest_existing_ancestor(path: &Path) -> PathBuf {
+    let mut current = path;
+    loop {
+        if fs::symlink_metadata(current).is_ok() {
+            return current.to_path_buf();
+        }
+        current = match current.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => {
+                return PathBuf::from(match path.is_absolute() {
+                    true => "/",
+                    false => ".",
+                })
+            }
+        };
+    }
+}
+
+/// Widen an integer of whatever size the platform's structs use to a u64.
+fn widen<T: Into<u64>>(v: T) -> u64 {
+    v.into()
+}
+
+/// SpaceInfo describes the size and usage of a mounted filesystem.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SpaceInfo {
+    /// The total size of the filesystem, in bytes.
+    pub total_bytes: u64,
+    /// The number of bytes available to the current (unprivileged) user.
+    /// This can be less than `free_bytes`, e.g. due to space reserved for
+    /// root.
+    pub available_bytes: u64,
+    /// The number of free bytes, including any reserved for privileged users.
+    pub free_bytes: u64,
+    /// The total number of inodes, if the filesystem / platform has them.
+    pub inodes_total: Option<u64>,
+    /// The number of inodes available to the current user, if the filesystem
+    /// / platform has them.
+    pub inodes_available: Option<u64>,
+}
+
+/// Return information about the size and usage of the filesystem containing
+/// the given path. The path doesn't need to exist; if it doesn't, its nearest
+/// existing ancestor is used instead.
+#[cfg(not(target_os = "windows"))]
+pub fn space<P: AsRef<Path>>(path: P) -> Result<SpaceInfo> {
+    let path = nearest_existing_ancestor(path.as_ref());
+    let cstr = CString::new(path_to_bytes(&path)?)?;
+    let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statvfs(cstr.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::from_raw_os_error(errno::errno().0).into());
+    }
+
+    let fragment_size = widen(stat.f_frsize);
+    Ok(SpaceInfo {
+        total_bytes: widen(stat.f_blocks).saturating_mul(fragment_size),
+        available_bytes: widen(stat.f_bavail).saturating_mul(fragment_size),
+        free_bytes: widen(stat.f_bfree).saturating_mul(fragment_size),
+        inodes_total: Some(widen(stat.f_files)),
+        inodes_available: Some(widen(stat.f_favail)),
+    })
+}
+
+/// Return information about the size and usage of the filesystem containing
+/// the given path. This isn't implemented on Windows yet.
+#[cfg(target_os = "windows")]
+pub fn space<P: AsRef<Path>>(_: P) -> Result<SpaceInfo> {
+    Err(Error::Unsupported(
+        "querying free space is not supported on this platform".to_string(),
+    ))
+}
+
+/// Check that the filesystem containing the given path (see `space`) has at
+/// least `floor` bytes available, e.g. before writing a backup. If it does, the
+/// filesystem's `SpaceInfo` is returned. Otherwise, `Error::Precondition` is
+/// returned.
+pub fn check_space<P: AsRef<Path>>(path: P, floor: u64) -> Result<SpaceInfo> {
+    let path = path.as_ref();
+    let info = space(path)?;
+    if info.available_bytes < floor {
+        return Err(Error::Precondition(format!(
+            "only {} bytes are available for '{}', but at least {} are required",
+            info.available_bytes,
+            path.display(),
+            floor
+        )));
+    }
+    Ok(info)
+}
+
+/// FsType identifies the type of a mounted filesystem.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FsType {
+    /// ext2, ext3, or ext4 (which are indistinguishable this way).
+    Ext4,
+    /// btrfs.
+    Btrfs,
+    /// XFS.
+    Xfs,
+    /// ZFS.
+    Zfs,
+    /// F2FS.
+    F2fs,
+    /// tmpfs (an in-memory filesystem).
+    Tmpfs,
+    /// ramfs (an in-memory filesystem).
+    Ramfs,
+    /// OverlayFS, e.g. as used by container runtimes.
+    Overlay,
+    /// Any FUSE filesystem.
+    Fuse,
+    /// FAT (e.g. FAT32 / VFAT).
+    Fat,
+    /// exFAT.
+    Exfat,
+    /// NTFS.
+    Ntfs,
+    /// NFS.
+    Nfs,
+    /// SMB / CIFS.
+    Smb,
+    /// Ceph.
+    Ceph,
+    /// The 9P protocol, e.g. as used to share files with VMs.
+    NineP,
+    /// procfs.
+    Proc,
+    /// sysfs.
+    Sysfs,
+    /// Some other filesystem, identified by its raw magic number.
+    Unknown(u64),
+}
+
+impl FsType {
+    /// Identify a filesystem from the magic number Linux's statfs reports for
+    /// it (`f_type`).
+    pub fn from_magic(magic: u64) -> Self {
+        match magic {
+            0xef53 => FsType::Ext4,
+            0x9123_683e => FsType::Btrfs,
+            0x5846_5342 => FsType::Xfs,
+            0x2fc1_2fc1 => FsType::Zfs,
+            0xf2f5_2010 => FsType::F2fs,
+            0x0102_1994 => FsType::Tmpfs,
+            0x8584_58f6 => FsType::Ramfs,
+            0x794c_7630 => FsType::Overlay,
+            0x6573_5546 => FsType::Fuse,
+            0x4d44 => FsType::Fat,
+            0x2011_bab0 => FsType::Exfat,
+            0x5346_544e => FsType::Ntfs,
+            0x6969 => FsType::Nfs,
+            0xff53_4d42 | 0xfe53_4d42 | 0x517b => FsType::Smb,
+            0x00c3_6400 => FsType::Ceph,
+            0x0102_1997 => FsType::NineP,
+            0x9fa0 => FsType::Proc,
+            0x6265_6572 => FsType::Sysfs,
+            _ => FsType::Unknown(magic),
+        }
+    }
+
+    /// Return a short, human-readable name for this filesystem type.
+    pub fn name(&self) -> &'static str {
+        match self {
+            FsType::Ext4 => "ext4",
+            FsType::Btrfs => "btrfs",
+            FsType::Xfs => "xfs",
+            FsType::Zfs => "zfs",
+            FsType::F2fs => "f2fs",
+            FsType::Tmpfs => "tmpfs",
+            FsType::Ramfs => "ramfs",
+            FsType::Overlay => "overlay",
+            FsType::Fuse => "fuse",
+            FsType::Fat => "vfat",
+            FsType::Exfat => "exfat",
+            FsType::Ntfs => "ntfs",
+            FsType::Nfs => "nfs",
+            FsType::Smb => "smb",
+            FsType::Ceph => "ceph",
+            FsType::NineP => "9p",
+            FsType::Proc => "proc",
+            FsType::Sysfs => "sysfs",
+            FsType::Unknown(_) => "unknown",
+        }
+    }
+
+    /// Returns true if this is a network filesystem, where e.g. locking and
+    /// atomic renames may not behave as they do locally.
+    pub fn is_network(&self) -> bool {
+        matches!(
+            self,
+            FsType::Nfs | FsType::Smb | FsType::Ceph | FsType::NineP
+        )
+    }
+
+    /// Returns true if this filesystem generally supports user extended
+    /// attributes (see `fs::xattr`). This is only a hint: support can also
+    /// depend on mount options or kernel version, so callers should still
+    /// handle `Error::Unsupported`.
+    pub fn supports_xattr_hint(&self) -> bool {
+        matches!(
+            self,
+            FsType::Ext4
+                | FsType::Btrfs
+                | FsType::Xfs
+                | FsType::Zfs
+                | FsType::F2fs
+                | FsType::Tmpfs
+                | FsType::Overlay
+                | FsType::Ceph
+        )
+    }
+}
+
+impl fmt::Display for FsType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FsType::Unknown(magic) => write!(f, "unknown ({:#x})", magic),
+            _ => f.write_str(self.name()),
+        }
+    }
+}
+
+/// FsInfo describes a mounted filesystem (see `filesystem_info`).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FsInfo {
+    /// The type of the filesystem. Its name is available via `FsType::name`.
+    pub fs_type: FsType,
+    /// Whether or not this is a network filesystem (see `FsType::is_network`).
+    pub is_network: bool,
+    /// Whether or not this filesystem is likely to support extended
+    /// attributes (see `FsType::supports_xattr_hint`).
+    pub supports_xattr_hint: bool,
+    /// The filesystem's preferred I/O block size, in bytes.
+    pub block_size: u64,
+}
+
+/// Return information about the filesystem containing the given path. The path
+/// doesn't need to exist; if it doesn't, its nearest existing ancestor is used
+/// instead.
+#[cfg(target_os = "linux")]
+pub fn filesystem_info<P: AsRef<Path>>(path: P) -> Result<FsInfo> {
+    let path = nearest_existing_ancestor(path.as_ref());
+    let cstr = CString::new(path_to_bytes(&path)?)?;
+    let mut stat: libc::statfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statfs(cstr.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::from_raw_os_error(errno::errno().0).into());
+    }
+
+    // The magic number's type varies between architectures, and is sometimes
+    // signed, but the values themselves always fit in 32 bits.
+    let fs_type = FsType::from_magic(stat.f_type as u32 as u64);
+    Ok(FsInfo {
+        fs_type,
+        is_network: fs_type.is_network(),
+        supports_xattr_hint: fs_type.supports_xattr_hint(),
+        block_size: stat.f_bsize as u64,
+    })
+}
+
+/// Return information about the filesystem containing the given path. This is
+/// currently only implemented on Linux.
+#[cfg(not(target_os = "linux"))]
+pub fn filesystem_info<P: AsRef<Path>>(_: P) -> Result<FsInfo> {
+    Err(Error::Unsupported(
+        "querying filesystem information is not supported on this platform".to_string(),
+    ))
+}
@@ -0,0 +1,340 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::error::*;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, VecDeque};
+use std::ffi::OsString;
+use std::fs;
+use std::hash::Hasher;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::debug;
+
+/// The default interval between polls of the watched paths.
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Comparison determines how a `Watcher` decides whether a file has changed.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Comparison {
+    /// Compare the file's modification time and size. This is cheap, but it
+    /// may report changes which didn't change the contents (e.g. `touch`).
+    #[default]
+    MtimeSize,
+    /// Compare a hash of the file's contents. This reads every watched file on
+    /// each poll, so it's only suitable for small files.
+    ContentHash,
+}
+
+/// WatchOptions configures a `Watcher`.
+#[derive(Clone, Debug)]
+pub struct WatchOptions {
+    /// How often the watched paths are checked for changes.
+    pub poll_interval: Duration,
+    /// How files are compared to decide whether they have changed.
+    pub compare: Comparison,
+}
+
+impl Default for WatchOptions {
+    fn default() -> Self {
+        WatchOptions {
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            compare: Comparison::default(),
+        }
+    }
+}
+
+/// The kinds of changes a `Watcher` reports.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EventKind {
+    /// The path didn't exist before, but it does now.
+    Created,
+    /// The path's contents changed.
+    Modified,
+    /// The path existed before, but it doesn't anymore.
+    Removed,
+}
+
+/// An Event describes a single change to a watched path.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Event<T> {
+    /// The tag the watched path was registered with.
+    pub tag: T,
+    /// The path which changed. For watched directories, this is the path of
+    /// the entry inside the directory which changed.
+    pub path: PathBuf,
+    /// What kind of change happened.
+    pub kind: EventKind,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Fingerprint {
+    mtime: Option<SystemTime>,
+    len: u64,
+    hash: Option<u64>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Snapshot {
+    Missing,
+    File(Fingerprint),
+    Directory(BTreeMap<OsString, Fingerprint>),
+}
+
+fn hash_file(path: &Path) -> io::Result<u64> {
+    let mut f = fs::File::open(path)?;
+    let mut hasher = DefaultHasher::new();
+    let mut buf = [0; 8192];
+    loop {
+        let n = f.read(&mut buf)?;
+        if n == 0 {
+            return Ok(hasher.finish());
+        }
+        hasher.write(&buf[..n]);
+    }
+}
+
+fn fingerprint(
+    path: &Path,
+    metadata: &fs::Metadata,
+    compare: Comparison,
+) -> io::Result<Fingerprint> {
+    if metadata.is_dir() {
+        // Directory sizes are meaningless, so just use their mtime.
+        return Ok(Fingerprint {
+            mtime: metadata.modified().ok(),
+            len: 0,
+            hash: None,
+        });
+    }
+
+    Ok(match compare {
+        Comparison::MtimeSize => Fingerprint {
+            mtime: metadata.modified().ok(),
+            len: metadata.len(),
+            hash: None,
+        },
+        Comparison::ContentHash => Fingerprint {
+            mtime: None,
+            len: metadata.len(),
+            hash: Some(hash_file(path)?),
+        },
+    })
+}
+
+fn snapshot(path: &Path, compare: Comparison) -> io::Result<Snapshot> {
+    let metadata = match fs::metadata(path) {
+        Ok(m) => m,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Snapshot::Missing),
+        Err(e) => return Err(e),
+    };
+
+    if !metadata.is_dir() {
+        return Ok(Snapshot::File(fingerprint(path, &metadata, compare)?));
+    }
+
+    let mut manifest = BTreeMap::new();
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let entry_path = entry.path();
+        // Entries may disappear while we're listing the directory; if so, just
+        // leave them out of the manifest.
+        let metadata = match fs::metadata(&entry_path) {
+            Ok(m) => m,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        match fingerprint(&entry_path, &metadata, compare) {
+            Ok(fp) => {
+                manifest.insert(entry.file_name(), fp);
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(Snapshot::Directory(manifest))
+}
+
+fn diff_snapshots<T: Clone>(
+    tag: &T,
+    path: &Path,
+    old: &Snapshot,
+    new: &Snapshot,
+    events: &mut Vec<Event<T>>,
+) {
+    let mut push = |path: PathBuf, kind: EventKind| {
+        events.push(Event {
+            tag: tag.clone(),
+            path,
+            kind,
+        })
+    };
+
+    match (old, new) {
+        (Snapshot::Missing, Snapshot::Missing) => {}
+        (Snapshot::Missing, _) => push(path.to_path_buf(), EventKind::Created),
+        (_, Snapshot::Missing) => push(path.to_path_buf(), EventKind::Removed),
+        (Snapshot::Directory(old), Snapshot::Directory(new)) => {
+            for (name, fp) in old.iter() {
+                match new.get(name) {
+                    None => push(path.join(name), EventKind::Removed),
+                    Some(new_fp) if new_fp != fp => push(path.join(name), EventKind::Modified),
+                    Some(_) => {}
+                }
+            }
+            for name in new.keys().filter(|name| !old.contains_key(*name)) {
+                push(path.join(name), EventKind::Created);
+            }
+        }
+        (old, new) => {
+            if old != new {
+                push(path.to_path_buf(), EventKind::Modified);
+            }
+        }
+    }
+}
+
+struct Watch<T> {
+    path: PathBuf,
+    tag: T,
+    snapshot: Snapshot,
+}
+
+/// Watcher polls a set of files and / or directories, and reports changes to
+/// them as `Event`s.
+///
+/// Each watched path is compared against its previous state once per poll, so
+/// several changes in quick succession are coalesced into a single event.
+/// Watched paths don't need to exist yet; a `Created` event is reported when
+/// they appear. Directories are watched non-recursively: events are reported
+/// for entries being created, modified, or removed inside them.
+///
+/// Files which are updated atomically (by writing a temporary file and then
+/// renaming it into place) are never observed half-written. Writers which
+/// modify files in place may produce spurious `Modified` events, e.g. one
+/// while the write is in progress, and another once it's finished.
+pub struct Watcher<T> {
+    options: WatchOptions,
+    watches: Vec<Watch<T>>,
+    pending: VecDeque<Event<T>>,
+}
+
+impl<T: Clone> Watcher<T> {
+    /// Construct a new Watcher, which isn't watching anything yet.
+    pub fn new(options: WatchOptions) -> Self {
+        Watcher {
+            options,
+            watches: Vec::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Start watching the given file or directory (which need not exist yet).
+    /// Events for it are reported with the given tag.
+    pub fn watch_path<P: AsRef<Path>>(&mut self, path: P, tag: T) -> Result<()> {
+        let path = path.as_ref().to_path_buf();
+        let snapshot = snapshot(&path, self.options.compare)?;
+        self.watches.push(Watch {
+            path,
+            tag,
+            snapshot,
+        });
+        Ok(())
+    }
+
+    /// Check every watched path once, returning any changes since the last
+    /// check (or since it started being watched).
+    pub fn poll(&mut self) -> Vec<Event<T>> {
+        let mut events = Vec::new();
+        for watch in self.watches.iter_mut() {
+            let new = match snapshot(&watch.path, self.options.compare) {
+                Ok(s) => s,
+                Err(e) => {
+                    // Assume nothing changed; we'll try again on the next poll.
+                    debug!("Failed to check {}: {}", watch.path.display(), e);
+                    continue;
+                }
+            };
+            diff_snapshots(&watch.tag, &watch.path, &watch.snapshot, &new, &mut events);
+            watch.snapshot = new;
+        }
+        events
+    }
+
+    /// Wait for the next event, polling every `poll_interval`. Returns None
+    /// if no event occurred before the given timeout elapsed.
+    pub fn next_event(&mut self, timeout: Duration) -> Option<Event<T>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if self.pending.is_empty() {
+                let events = self.poll();
+                self.pending.extend(events);
+            }
+            if let Some(event) = self.pending.pop_front() {
+                return Some(event);
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            thread::sleep(std::cmp::min(self.options.poll_interval, deadline - now));
+        }
+    }
+
+    /// Move this watcher to a background thread, which calls `callback` with
+    /// each event as it occurs. The thread is stopped when the returned
+    /// `WatchSubscription` is dropped.
+    pub fn subscribe<F>(mut self, mut callback: F) -> WatchSubscription
+    where
+        T: Send + 'static,
+        F: FnMut(Event<T>) + Send + 'static,
+    {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let interval = self.options.poll_interval;
+        let thread = thread::spawn(move || {
+            while !thread_stop.load(Ordering::SeqCst) {
+                if let Some(event) = self.next_event(interval) {
+                    callback(event);
+                }
+            }
+        });
+        WatchSubscription {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+/// A handle to a background thread started by `Watcher::subscribe`. Dropping
+/// this stops the thread (and drops the underlying watcher).
+pub struct WatchSubscription {
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Drop for WatchSubscription {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                debug!("File watcher callback thread panicked");
+            }
+        }
+    }
+}
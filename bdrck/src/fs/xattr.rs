@@ -0,0 +1,280 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::error::*;
+use crate::fs::path_to_bytes;
+use errno::errno;
+use libc::{c_char, c_int, c_void, size_t, ssize_t};
+use std::ffi::CString;
+use std::path::Path;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const ENOATTR: c_int = libc::ENODATA;
+#[cfg(target_os = "macos")]
+const ENOATTR: c_int = libc::ENOATTR;
+
+/// SetFlags controls what `set` does depending on whether or not the
+/// attribute already exists.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum SetFlags {
+    /// Create the attribute; it is an error if it already exists.
+    Create,
+    /// Replace the attribute's value; it is an error if it doesn't exist.
+    Replace,
+    /// Create the attribute, or replace its value if it already exists.
+    #[default]
+    Either,
+}
+
+impl SetFlags {
+    fn to_raw(self) -> c_int {
+        match self {
+            SetFlags::Create => libc::XATTR_CREATE,
+            SetFlags::Replace => libc::XATTR_REPLACE,
+            SetFlags::Either => 0,
+        }
+    }
+}
+
+// Linux and macOS disagree on how these functions look, so smooth over the
+// differences here.
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+unsafe fn sys_get(
+    path: *const c_char,
+    name: *const c_char,
+    value: *mut c_void,
+    size: size_t,
+    follow: bool,
+) -> ssize_t {
+    match follow {
+        true => libc::getxattr(path, name, value, size),
+        false => libc::lgetxattr(path, name, value, size),
+    }
+}
+
+#[cfg(target_os = "macos")]
+unsafe fn sys_get(
+    path: *const c_char,
+    name: *const c_char,
+    value: *mut c_void,
+    size: size_t,
+    follow: bool,
+) -> ssize_t {
+    let options = if follow { 0 } else { libc::XATTR_NOFOLLOW };
+    libc::getxattr(path, name, value, size, 0, options)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+unsafe fn sys_set(
+    path: *const c_char,
+    name: *const c_char,
+    value: *const c_void,
+    size: size_t,
+    flags: c_int,
+    follow: bool,
+) -> c_int {
+    match follow {
+        true => libc::setxattr(path, name, value, size, flags),
+        false => libc::lsetxattr(path, name, value, size, flags),
+    }
+}
+
+#[cfg(target_os = "macos")]
+unsafe fn sys_set(
+    path: *const c_char,
+    name: *const c_char,
+    value: *const c_void,
+    size: size_t,
+    flags: c_int,
+    follow: bool,
+) -> c_int {
+    let options = if follow { 0 } else { libc::XATTR_NOFOLLOW };
+    libc::setxattr(path, name, value, size, 0, flags | options)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+unsafe fn sys_remove(path: *const c_char, name: *const c_char, follow: bool) -> c_int {
+    match follow {
+        true => libc::removexattr(path, name),
+        false => libc::lremovexattr(path, name),
+    }
+}
+
+#[cfg(target_os = "macos")]
+unsafe fn sys_remove(path: *const c_char, name: *const c_char, follow: bool) -> c_int {
+    let options = if follow { 0 } else { libc::XATTR_NOFOLLOW };
+    libc::removexattr(path, name, options)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+unsafe fn sys_list(path: *const c_char, list: *mut c_char, size: size_t, follow: bool) -> ssize_t {
+    match follow {
+        true => libc::listxattr(path, list, size),
+        false => libc::llistxattr(path, list, size),
+    }
+}
+
+#[cfg(target_os = "macos")]
+unsafe fn sys_list(path: *const c_char, list: *mut c_char, size: size_t, follow: bool) -> ssize_t {
+    let options = if follow { 0 } else { libc::XATTR_NOFOLLOW };
+    libc::listxattr(path, list, size, options)
+}
+
+/// Convert the current errno into an error, distinguishing filesystems which
+/// don't support extended attributes at all.
+fn last_error(path: &Path) -> Error {
+    let error = errno();
+    if error.0 == libc::ENOTSUP || error.0 == libc::EOPNOTSUPP {
+        return Error::Unsupported(format!(
+            "extended attributes are not supported for '{}'",
+            path.display()
+        ));
+    }
+    std::io::Error::from_raw_os_error(error.into()).into()
+}
+
+/// Call the given function to read a variable-length value, first to find out
+/// how big it is, and then again to actually read it. If the value grows in
+/// between the two calls (ERANGE), we just try again.
+///
+/// Returns None if the function fails with ENOATTR.
+fn read_sized<F: Fn(*mut c_void, size_t) -> ssize_t>(path: &Path, f: F) -> Result<Option<Vec<u8>>> {
+    loop {
+        let size = f(std::ptr::null_mut(), 0);
+        if size < 0 {
+            if errno().0 == ENOATTR {
+                return Ok(None);
+            }
+            return Err(last_error(path));
+        }
+
+        let mut buf = vec![0_u8; size as usize];
+        let size = f(buf.as_mut_ptr() as *mut c_void, buf.len());
+        if size < 0 {
+            match errno().0 {
+                libc::ERANGE => continue,
+                ENOATTR => return Ok(None),
+                _ => return Err(last_error(path)),
+            }
+        }
+        buf.truncate(size as usize);
+        return Ok(Some(buf));
+    }
+}
+
+fn get_impl(path: &Path, name: &str, follow: bool) -> Result<Option<Vec<u8>>> {
+    let path_cstr = CString::new(path_to_bytes(path)?)?;
+    let name_cstr = CString::new(name)?;
+    read_sized(path, |buf, size| unsafe {
+        sys_get(path_cstr.as_ptr(), name_cstr.as_ptr(), buf, size, follow)
+    })
+}
+
+fn set_impl(path: &Path, name: &str, value: &[u8], flags: SetFlags, follow: bool) -> Result<()> {
+    let path_cstr = CString::new(path_to_bytes(path)?)?;
+    let name_cstr = CString::new(name)?;
+    let ret = unsafe {
+        sys_set(
+            path_cstr.as_ptr(),
+            name_cstr.as_ptr(),
+            value.as_ptr() as *const c_void,
+            value.len(),
+            flags.to_raw(),
+            follow,
+        )
+    };
+    match ret {
+        0 => Ok(()),
+        _ => Err(last_error(path)),
+    }
+}
+
+fn remove_impl(path: &Path, name: &str, follow: bool) -> Result<()> {
+    let path_cstr = CString::new(path_to_bytes(path)?)?;
+    let name_cstr = CString::new(name)?;
+    match unsafe { sys_remove(path_cstr.as_ptr(), name_cstr.as_ptr(), follow) } {
+        0 => Ok(()),
+        _ => Err(last_error(path)),
+    }
+}
+
+fn list_impl(path: &Path, follow: bool) -> Result<Vec<String>> {
+    let path_cstr = CString::new(path_to_bytes(path)?)?;
+    let names = read_sized(path, |buf, size| unsafe {
+        sys_list(path_cstr.as_ptr(), buf as *mut c_char, size, follow)
+    })?
+    .unwrap_or_default();
+    names
+        .split(|b| *b == 0)
+        .filter(|name| !name.is_empty())
+        .map(|name| Ok(String::from_utf8(name.to_vec())?))
+        .collect()
+}
+
+/// Return the value of the given extended attribute of the file at the given
+/// path (following symlinks), or None if it doesn't have that attribute.
+///
+/// If the filesystem doesn't support extended attributes at all,
+/// `Error::Unsupported` is returned.
+pub fn get<P: AsRef<Path>>(path: P, name: &str) -> Result<Option<Vec<u8>>> {
+    get_impl(path.as_ref(), name, true)
+}
+
+/// Like `get`, but if the path is a symlink, the symlink's own attributes are
+/// used.
+pub fn lget<P: AsRef<Path>>(path: P, name: &str) -> Result<Option<Vec<u8>>> {
+    get_impl(path.as_ref(), name, false)
+}
+
+/// Set the given extended attribute of the file at the given path (following
+/// symlinks) to the given value. Whether or not the attribute must already
+/// exist is controlled by `flags`.
+///
+/// If the filesystem doesn't support extended attributes at all,
+/// `Error::Unsupported` is returned.
+pub fn set<P: AsRef<Path>>(path: P, name: &str, value: &[u8], flags: SetFlags) -> Result<()> {
+    set_impl(path.as_ref(), name, value, flags, true)
+}
+
+/// Like `set`, but if the path is a symlink, the symlink's own attributes are
+/// modified.
+pub fn lset<P: AsRef<Path>>(path: P, name: &str, value: &[u8], flags: SetFlags) -> Result<()> {
+    set_impl(path.as_ref(), name, value, flags, false)
+}
+
+/// Remove the given extended attribute from the file at the given path
+/// (following symlinks). It is an error if the attribute doesn't exist.
+pub fn remove<P: AsRef<Path>>(path: P, name: &str) -> Result<()> {
+    remove_impl(path.as_ref(), name, true)
+}
+
+/// Like `remove`, but if the path is a symlink, the symlink's own attributes
+/// are modified.
+pub fn lremove<P: AsRef<Path>>(path: P, name: &str) -> Result<()> {
+    remove_impl(path.as_ref(), name, false)
+}
+
+/// Return the names of all of the extended attributes of the file at the
+/// given path (following symlinks). Note that this only includes attributes
+/// the caller has permission to see (e.g., on Linux, "trusted.*" attributes
+/// are hidden from unprivileged users).
+pub fn list<P: AsRef<Path>>(path: P) -> Result<Vec<String>> {
+    list_impl(path.as_ref(), true)
+}
+
+/// Like `list`, but if the path is a symlink, the symlink's own attributes
+/// are listed.
+pub fn llist<P: AsRef<Path>>(path: P) -> Result<Vec<String>> {
+    list_impl(path.as_ref(), false)
+}
@@ -0,0 +1,287 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::error::*;
+use crate::http::body::RequestBody;
+use crate::http::client::{body_prefix, parse_json, AbstractClient};
+use crate::http::types::ResponseMetadata;
+use reqwest::header::{HeaderValue, AUTHORIZATION};
+use reqwest::{Request, RequestBuilder, StatusCode, Url};
+use serde_json::Value;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+use tracing::debug;
+
+/// The JSON field `BearerTokenProvider` reads new tokens from by default, as
+/// used by OAuth 2.0 token endpoints.
+pub const DEFAULT_TOKEN_FIELD: &str = "access_token";
+
+/// AuthProvider supplies the credentials an `AuthenticatingClient` attaches
+/// to its requests, as the value of the `Authorization` header.
+pub trait AuthProvider: Send + Sync {
+    /// Return the credential to send with requests right now, if any. This
+    /// should be cheap: it's called once per request.
+    fn current(&self) -> Result<Option<HeaderValue>>;
+
+    /// Obtain a new credential, e.g. from a token refresh endpoint, because
+    /// the current one was rejected. Afterwards, `current` should return the
+    /// new credential as well.
+    fn refresh(&self) -> Result<HeaderValue>;
+}
+
+/// Returns whether or not the given response indicates that the request's
+/// credential was rejected.
+fn is_auth_failure(metadata: &ResponseMetadata) -> Result<bool> {
+    let status = metadata.get_status()?;
+    Ok(status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN)
+}
+
+fn authentication_failed(res: &(ResponseMetadata, Vec<u8>)) -> Error {
+    Error::AuthenticationFailed {
+        status: res.0.status,
+        body_prefix: body_prefix(res.1.as_slice()),
+    }
+}
+
+fn set_credential(request: &mut Request, credential: Option<HeaderValue>) {
+    if let Some(mut credential) = credential {
+        credential.set_sensitive(true);
+        request.headers_mut().insert(AUTHORIZATION, credential);
+    }
+}
+
+/// Tracks refreshes, so concurrent requests which are all rejected share a
+/// single refresh.
+#[derive(Default)]
+struct RefreshState {
+    /// Incremented each time a refresh succeeds.
+    generation: u64,
+    /// The credential the most recent refresh returned.
+    credential: Option<HeaderValue>,
+}
+
+/// AuthenticatingClient wraps another AbstractClient, attaching the current
+/// credential from an `AuthProvider` to each request.
+///
+/// If a request is rejected (with 401 Unauthorized or 403 Forbidden), the
+/// credential is refreshed and the request is retried exactly once. Refreshes
+/// are serialized: if several requests are rejected at the same time, only
+/// the first one actually refreshes, and the others reuse its result. If the
+/// retry is rejected as well, `Error::AuthenticationFailed` is returned.
+///
+/// Requests with streamed bodies (`execute_body`) can't be retried, since
+/// their bodies have already been consumed. If one is rejected, the
+/// credential is still refreshed (so a retry by the caller uses the new one),
+/// but `Error::AuthenticationFailed` is returned.
+pub struct AuthenticatingClient<C: AbstractClient> {
+    inner: C,
+    provider: Box<dyn AuthProvider>,
+    state: Mutex<RefreshState>,
+}
+
+impl<C: AbstractClient> AuthenticatingClient<C> {
+    /// Construct a new AuthenticatingClient which sends requests with `inner`,
+    /// using credentials from the given provider.
+    pub fn new<P: AuthProvider + 'static>(inner: C, provider: P) -> Self {
+        AuthenticatingClient {
+            inner,
+            provider: Box::new(provider),
+            state: Mutex::new(RefreshState::default()),
+        }
+    }
+
+    /// Return the client this wraps.
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    /// Return the provider this client gets its credentials from.
+    pub fn provider(&self) -> &dyn AuthProvider {
+        self.provider.as_ref()
+    }
+
+    fn lock_state(&self) -> MutexGuard<'_, RefreshState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Return the current refresh generation, and the credential to use.
+    fn credential(&self) -> Result<(u64, Option<HeaderValue>)> {
+        let generation = self.lock_state().generation;
+        Ok((generation, self.provider.current()?))
+    }
+
+    /// Refresh the credential, because a request sent during the given
+    /// generation was rejected. If some other request already refreshed it
+    /// since then, its result is reused instead.
+    fn refresh(&self, generation: u64) -> Result<HeaderValue> {
+        let mut state = self.lock_state();
+        if state.generation != generation {
+            if let Some(credential) = state.credential.as_ref() {
+                return Ok(credential.clone());
+            }
+        }
+        debug!("HTTP credential rejected, refreshing it");
+        let credential = self.provider.refresh()?;
+        state.generation += 1;
+        state.credential = Some(credential.clone());
+        Ok(credential)
+    }
+}
+
+impl<C: AbstractClient> AbstractClient for AuthenticatingClient<C> {
+    fn execute(&self, mut request: Request) -> Result<(ResponseMetadata, Vec<u8>)> {
+        let (generation, credential) = self.credential()?;
+        let retry = request.try_clone();
+        set_credential(&mut request, credential);
+        let res = self.inner.execute(request)?;
+        if !is_auth_failure(&res.0)? {
+            return Ok(res);
+        }
+
+        let credential = self.refresh(generation)?;
+        let mut retry = match retry {
+            Some(retry) => retry,
+            None => return Err(authentication_failed(&res)),
+        };
+        set_credential(&mut retry, Some(credential));
+        let res = self.inner.execute(retry)?;
+        if is_auth_failure(&res.0)? {
+            return Err(authentication_failed(&res));
+        }
+        Ok(res)
+    }
+
+    fn execute_body(
+        &self,
+        mut request: Request,
+        body: RequestBody,
+    ) -> Result<(ResponseMetadata, Vec<u8>)> {
+        let (generation, credential) = self.credential()?;
+        set_credential(&mut request, credential);
+        let res = self.inner.execute_body(request, body)?;
+        if !is_auth_failure(&res.0)? {
+            return Ok(res);
+        }
+        self.refresh(generation)?;
+        Err(authentication_failed(&res))
+    }
+
+    fn sleep(&self, sleep: fn(Duration), duration: Duration) {
+        self.inner.sleep(sleep, duration)
+    }
+
+    fn get(&self, url: Url) -> RequestBuilder {
+        self.inner.get(url)
+    }
+    fn post(&self, url: Url) -> RequestBuilder {
+        self.inner.post(url)
+    }
+    fn put(&self, url: Url) -> RequestBuilder {
+        self.inner.put(url)
+    }
+    fn patch(&self, url: Url) -> RequestBuilder {
+        self.inner.patch(url)
+    }
+    fn delete(&self, url: Url) -> RequestBuilder {
+        self.inner.delete(url)
+    }
+    fn head(&self, url: Url) -> RequestBuilder {
+        self.inner.head(url)
+    }
+}
+
+/// A function which builds the request used to refresh a bearer token.
+pub type RefreshRequestFn<C> = Box<dyn Fn(&C) -> Result<Request> + Send + Sync>;
+
+/// BearerTokenProvider is an `AuthProvider` for bearer tokens which are
+/// refreshed by sending a request to a token endpoint, which responds with a
+/// JSON object containing the new token (in the `access_token` field, by
+/// default).
+///
+/// Refresh requests are sent with the given client, so like any other
+/// request they can be recorded and replayed in tests.
+pub struct BearerTokenProvider<C: AbstractClient> {
+    client: C,
+    refresh_request: RefreshRequestFn<C>,
+    token_field: String,
+    token: Mutex<Option<HeaderValue>>,
+}
+
+impl<C: AbstractClient> BearerTokenProvider<C> {
+    /// Construct a new provider, which starts out without a token. The given
+    /// function builds the refresh request, which is sent with `client`.
+    pub fn new<F: Fn(&C) -> Result<Request> + Send + Sync + 'static>(
+        client: C,
+        refresh_request: F,
+    ) -> Self {
+        BearerTokenProvider {
+            client,
+            refresh_request: Box::new(refresh_request),
+            token_field: DEFAULT_TOKEN_FIELD.to_owned(),
+            token: Mutex::new(None),
+        }
+    }
+
+    /// Start out with the given token (e.g. one cached from a previous run),
+    /// instead of no token at all.
+    pub fn with_token(self, token: &str) -> Result<Self> {
+        *self.token.lock().unwrap_or_else(PoisonError::into_inner) =
+            Some(Self::header_value(token)?);
+        Ok(self)
+    }
+
+    /// Read new tokens from the given field of the token endpoint's response,
+    /// instead of `DEFAULT_TOKEN_FIELD`.
+    pub fn with_token_field(mut self, field: &str) -> Self {
+        self.token_field = field.to_owned();
+        self
+    }
+
+    fn header_value(token: &str) -> Result<HeaderValue> {
+        let mut value = HeaderValue::from_str(format!("Bearer {}", token).as_str())
+            .map_err(|_| Error::InvalidArgument("invalid bearer token".to_string()))?;
+        value.set_sensitive(true);
+        Ok(value)
+    }
+}
+
+impl<C: AbstractClient + Send + Sync> AuthProvider for BearerTokenProvider<C> {
+    fn current(&self) -> Result<Option<HeaderValue>> {
+        Ok(self
+            .token
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone())
+    }
+
+    fn refresh(&self) -> Result<HeaderValue> {
+        let res = self.client.execute((self.refresh_request)(&self.client)?)?;
+        if !res.0.get_status()?.is_success() {
+            return Err(authentication_failed(&res));
+        }
+        let body: Value = parse_json(&res.0, res.1.as_slice())?;
+        let token = match body.get(self.token_field.as_str()).and_then(Value::as_str) {
+            Some(token) => token,
+            None => {
+                return Err(Error::InvalidArgument(format!(
+                    "token refresh response has no '{}' field",
+                    self.token_field
+                )))
+            }
+        };
+        let value = Self::header_value(token)?;
+        *self.token.lock().unwrap_or_else(PoisonError::into_inner) = Some(value.clone());
+        Ok(value)
+    }
+}
@@ -0,0 +1,252 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::error::*;
+use futures::Stream;
+use reqwest::header::{HeaderValue, CONTENT_LENGTH};
+use reqwest::{Body, Request};
+use std::io::{self, Read};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+/// The size of the chunks we read from a reader-backed body at a time.
+const READ_CHUNK_BYTES: usize = 64 * 1024;
+
+/// A callback which is called with the total number of body bytes sent so
+/// far, each time a chunk of a request body is sent.
+pub type ProgressCallback = Box<dyn FnMut(u64) + Send>;
+
+/// A function which opens a fresh reader for a request body. This lets bodies
+/// be re-read from the start when a request is retried.
+pub type ReaderFactory = Box<dyn Fn() -> Result<Box<dyn Read + Send>> + Send + Sync>;
+
+/// A callback which observes each chunk of a request body as it is sent.
+pub(crate) type BodyObserver<'a> = Box<dyn FnMut(&[u8]) + Send + 'a>;
+
+enum Source {
+    Bytes(Vec<u8>),
+    Reader(Box<dyn Read + Send>),
+    Factory(Arc<ReaderFactory>),
+}
+
+/// RequestBody is the body of an HTTP request. Unlike a plain byte buffer, a
+/// RequestBody can also be backed by a `Read`, so large uploads can be
+/// streamed rather than being read fully into memory first.
+pub struct RequestBody {
+    source: Source,
+    len: Option<u64>,
+    progress: Option<Arc<Mutex<ProgressCallback>>>,
+}
+
+impl RequestBody {
+    /// Construct a body from an in-memory buffer.
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        RequestBody {
+            len: Some(bytes.len() as u64),
+            source: Source::Bytes(bytes),
+            progress: None,
+        }
+    }
+
+    /// Construct a body which is streamed from the given reader. If the length
+    /// is known it is sent as the Content-Length, otherwise chunked transfer
+    /// encoding is used.
+    ///
+    /// Note that a body like this can only be read once, so it can't be used
+    /// with requests which might need to be retried. See `from_factory` for
+    /// that.
+    pub fn from_reader(reader: Box<dyn Read + Send>, len: Option<u64>) -> Self {
+        RequestBody {
+            source: Source::Reader(reader),
+            len,
+            progress: None,
+        }
+    }
+
+    /// Construct a body which is streamed from readers returned by the given
+    /// factory. A new reader is opened each time the request is sent, so
+    /// unlike `from_reader` this kind of body can be retried.
+    pub fn from_factory(factory: ReaderFactory, len: Option<u64>) -> Self {
+        RequestBody {
+            source: Source::Factory(Arc::new(factory)),
+            len,
+            progress: None,
+        }
+    }
+
+    /// Call the given function with the number of bytes sent so far, as the
+    /// body is being sent.
+    pub fn with_progress(mut self, progress: ProgressCallback) -> Self {
+        self.progress = Some(Arc::new(Mutex::new(progress)));
+        self
+    }
+
+    /// Returns the length of this body, if it is known up front. This is sent
+    /// as the request's Content-Length.
+    pub fn content_length(&self) -> Option<u64> {
+        self.len
+    }
+
+    /// Returns whether or not this body can be sent more than once (e.g., for
+    /// retries). Bodies constructed with `from_reader` are not replayable.
+    pub fn is_replayable(&self) -> bool {
+        !matches!(self.source, Source::Reader(_))
+    }
+
+    /// Return a copy of this body which can be sent independently of this
+    /// one, or None if this body is not replayable.
+    pub(crate) fn try_clone(&self) -> Option<RequestBody> {
+        Some(RequestBody {
+            source: match &self.source {
+                Source::Bytes(b) => Source::Bytes(b.clone()),
+                Source::Reader(_) => return None,
+                Source::Factory(f) => Source::Factory(f.clone()),
+            },
+            len: self.len,
+            progress: self.progress.clone(),
+        })
+    }
+
+    fn into_stream_state<'a>(
+        self,
+        observer: Option<BodyObserver<'a>>,
+    ) -> Result<ReaderStreamState<'a>> {
+        let reader = match self.source {
+            Source::Bytes(b) => Box::new(io::Cursor::new(b)),
+            Source::Reader(r) => r,
+            Source::Factory(f) => f()?,
+        };
+        Ok(ReaderStreamState {
+            reader,
+            observer,
+            progress: self.progress,
+            sent: 0,
+            done: false,
+        })
+    }
+
+    /// Set this body on the given request. The observer (if any) is called with
+    /// each chunk of the body as it is sent.
+    pub(crate) fn apply(
+        self,
+        request: &mut Request,
+        observer: Option<BodyObserver<'static>>,
+    ) -> Result<()> {
+        if let Some(len) = self.len {
+            request
+                .headers_mut()
+                .insert(CONTENT_LENGTH, HeaderValue::from(len));
+        }
+
+        if let Source::Bytes(bytes) = self.source {
+            if let Some(mut observer) = observer {
+                observer(bytes.as_slice());
+            }
+            if let Some(progress) = self.progress.as_ref() {
+                (progress.lock().unwrap())(bytes.len() as u64);
+            }
+            *request.body_mut() = Some(bytes.into());
+            return Ok(());
+        }
+
+        let state = self.into_stream_state(observer)?;
+        *request.body_mut() = Some(Body::wrap_stream(ReaderStream(Mutex::new(state))));
+        Ok(())
+    }
+
+    /// Read this body in its entirety, passing each chunk to the given
+    /// observer, without actually sending it anywhere. The given request's
+    /// headers are updated as they would be by `apply`. This is useful for
+    /// test stubs, which want to inspect the body which would have been sent.
+    #[cfg(all(feature = "testing", debug_assertions))]
+    pub(crate) fn consume<'a>(
+        self,
+        request: &mut Request,
+        observer: BodyObserver<'a>,
+    ) -> Result<()> {
+        if let Some(len) = self.len {
+            request
+                .headers_mut()
+                .insert(CONTENT_LENGTH, HeaderValue::from(len));
+        }
+
+        let mut state = self.into_stream_state(Some(observer))?;
+        while let Some(chunk) = state.next_chunk() {
+            chunk?;
+        }
+        Ok(())
+    }
+}
+
+impl From<Vec<u8>> for RequestBody {
+    fn from(bytes: Vec<u8>) -> Self {
+        RequestBody::from_bytes(bytes)
+    }
+}
+
+struct ReaderStreamState<'a> {
+    reader: Box<dyn Read + Send>,
+    observer: Option<BodyObserver<'a>>,
+    progress: Option<Arc<Mutex<ProgressCallback>>>,
+    sent: u64,
+    done: bool,
+}
+
+impl ReaderStreamState<'_> {
+    fn next_chunk(&mut self) -> Option<io::Result<Vec<u8>>> {
+        if self.done {
+            return None;
+        }
+
+        let mut chunk = vec![0; READ_CHUNK_BYTES];
+        let read = loop {
+            match self.reader.read(chunk.as_mut_slice()) {
+                Ok(n) => break n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        };
+        if read == 0 {
+            self.done = true;
+            return None;
+        }
+        chunk.truncate(read);
+
+        self.sent += read as u64;
+        if let Some(observer) = self.observer.as_mut() {
+            observer(chunk.as_slice());
+        }
+        if let Some(progress) = self.progress.as_ref() {
+            (progress.lock().unwrap())(self.sent);
+        }
+        Some(Ok(chunk))
+    }
+}
+
+// Reading from the underlying reader is blocking, but this is no worse than
+// the rest of this client, which blocks on each request anyway. The Mutex is
+// just there to make this Sync, as reqwest requires.
+struct ReaderStream(Mutex<ReaderStreamState<'static>>);
+
+impl Stream for ReaderStream {
+    type Item = io::Result<Vec<u8>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Poll::Ready(self.0.get_mut().unwrap().next_chunk())
+    }
+}
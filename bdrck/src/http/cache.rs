@@ -0,0 +1,309 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::error::*;
+use crate::fs::TempFile;
+use crate::http::body::RequestBody;
+use crate::http::client::AbstractClient;
+use crate::http::types::{HttpData, ResponseMetadata};
+use reqwest::header::{HeaderValue, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::{Method, Request, RequestBuilder, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::debug;
+
+/// The default maximum age of cache entries, after which they are discarded
+/// instead of being revalidated.
+pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(60 * 60 * 24);
+/// The default maximum number of entries kept in a cache directory.
+pub const DEFAULT_MAX_ENTRIES: usize = 1000;
+
+const CACHE_ENTRY_EXTENSION: &str = "json";
+
+/// A single cached response, as stored on disk.
+#[derive(Deserialize, Serialize)]
+struct CacheEntry {
+    /// When this entry was stored, in seconds since the UNIX epoch.
+    stored_at: u64,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    metadata: ResponseMetadata,
+    body: HttpData,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn get_header(metadata: &ResponseMetadata, name: &str) -> Option<String> {
+    metadata
+        .get_headers()
+        .get(name)
+        .and_then(|values| values.first())
+        .and_then(|value| value.clone().try_into_string().ok())
+}
+
+fn set_header(request: &mut Request, name: reqwest::header::HeaderName, value: &str) {
+    if request.headers().contains_key(&name) {
+        return;
+    }
+    match HeaderValue::from_str(value) {
+        Ok(value) => {
+            request.headers_mut().insert(name, value);
+        }
+        Err(e) => debug!("Not sending cached {} '{}': {}", name, value, e),
+    }
+}
+
+/// CachingClient wraps another AbstractClient, adding a simple on-disk cache
+/// of GET responses which carry an ETag or Last-Modified header. Subsequent
+/// requests for the same resource are sent as conditional requests
+/// (If-None-Match / If-Modified-Since), and if the server responds with 304
+/// Not Modified, the cached response is returned instead (see
+/// `ResponseMetadata::is_from_cache`).
+///
+/// Entries are keyed by method, URL, and (optionally) the values of a chosen
+/// set of request headers. They are discarded once they are older than the
+/// maximum age, and the least recently used entries are evicted once there
+/// are more than the maximum number of entries.
+pub struct CachingClient<C: AbstractClient> {
+    inner: C,
+    dir: PathBuf,
+    max_age: Duration,
+    max_entries: usize,
+    key_headers: Vec<String>,
+}
+
+impl<C: AbstractClient> CachingClient<C> {
+    /// Construct a new CachingClient which sends requests with `inner`, and
+    /// stores cached responses in the given directory (which is created if it
+    /// doesn't already exist).
+    pub fn new<P: AsRef<Path>>(inner: C, dir: P) -> Result<Self> {
+        fs::create_dir_all(dir.as_ref())?;
+        Ok(CachingClient {
+            inner,
+            dir: dir.as_ref().to_path_buf(),
+            max_age: DEFAULT_MAX_AGE,
+            max_entries: DEFAULT_MAX_ENTRIES,
+            key_headers: Vec::new(),
+        })
+    }
+
+    /// Set the maximum age of cache entries. Older entries are discarded
+    /// instead of being revalidated.
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    /// Set the maximum number of entries kept in the cache.
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = max_entries;
+        self
+    }
+
+    /// Include the value of the given request header in cache keys, e.g. so
+    /// responses for different `Accept` or `Authorization` values are cached
+    /// separately.
+    pub fn with_key_header(mut self, name: &str) -> Self {
+        self.key_headers.push(name.to_ascii_lowercase());
+        self
+    }
+
+    /// Return the client this cache wraps.
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    /// Remove all entries from the cache.
+    pub fn clear(&self) -> Result<()> {
+        for (_, path) in self.list_entries()? {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    fn entry_path(&self, request: &Request) -> PathBuf {
+        let mut hasher = Sha256::new();
+        hasher.update(request.method().as_str());
+        hasher.update(b"\n");
+        hasher.update(request.url().as_str());
+        for name in self.key_headers.iter() {
+            for value in request.headers().get_all(name.as_str()) {
+                hasher.update(b"\n");
+                hasher.update(name.as_bytes());
+                hasher.update(b": ");
+                hasher.update(value.as_bytes());
+            }
+        }
+        let mut path = self.dir.clone();
+        path.push(format!("{:x}.{}", hasher.finalize(), CACHE_ENTRY_EXTENSION));
+        path
+    }
+
+    fn load_entry(&self, path: &Path) -> Option<CacheEntry> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(_) => return None,
+        };
+        let entry: CacheEntry = match serde_json::from_slice(data.as_slice()) {
+            Ok(entry) => entry,
+            Err(e) => {
+                debug!("Discarding invalid cache entry {}: {}", path.display(), e);
+                let _ = fs::remove_file(path);
+                return None;
+            }
+        };
+        if now_secs().saturating_sub(entry.stored_at) > self.max_age.as_secs() {
+            debug!("Discarding expired cache entry {}", path.display());
+            let _ = fs::remove_file(path);
+            return None;
+        }
+        Some(entry)
+    }
+
+    fn store_entry(&self, path: &Path, entry: &CacheEntry) -> Result<()> {
+        let mut file = TempFile::new_in(self.dir.as_path(), ".cache")?;
+        file.as_file_mut()
+            .write_all(serde_json::to_vec(entry)?.as_slice())?;
+        file.persist(path)?;
+        self.evict()
+    }
+
+    fn list_entries(&self) -> Result<Vec<(SystemTime, PathBuf)>> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(self.dir.as_path())? {
+            let entry = entry?;
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(CACHE_ENTRY_EXTENSION) {
+                continue;
+            }
+            entries.push((entry.metadata()?.modified()?, path));
+        }
+        Ok(entries)
+    }
+
+    /// Remove the least recently used entries (by modification time), until
+    /// there are at most `max_entries` left.
+    fn evict(&self) -> Result<()> {
+        let mut entries = self.list_entries()?;
+        if entries.len() <= self.max_entries {
+            return Ok(());
+        }
+        entries.sort();
+        let excess = entries.len() - self.max_entries;
+        for (_, path) in entries.drain(..excess) {
+            debug!("Evicting cache entry {}", path.display());
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+impl<C: AbstractClient> AbstractClient for CachingClient<C> {
+    fn execute(&self, mut request: Request) -> Result<(ResponseMetadata, Vec<u8>)> {
+        if *request.method() != Method::GET || request.body().is_some() {
+            return self.inner.execute(request);
+        }
+
+        let path = self.entry_path(&request);
+        let cached = self.load_entry(path.as_path());
+        if let Some(cached) = cached.as_ref() {
+            if let Some(etag) = cached.etag.as_ref() {
+                set_header(&mut request, IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = cached.last_modified.as_ref() {
+                set_header(&mut request, IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+
+        let (metadata, body) = self.inner.execute(request)?;
+        let status = metadata.get_status()?;
+
+        if let (StatusCode::NOT_MODIFIED, Some(cached)) = (status, cached) {
+            // Mark the entry as recently used, for eviction purposes.
+            if let Err(e) = fs::File::options()
+                .write(true)
+                .open(path.as_path())
+                .and_then(|f| f.set_modified(SystemTime::now()))
+            {
+                debug!("Failed to touch cache entry {}: {}", path.display(), e);
+            }
+            let timings = metadata.timings;
+            let mut metadata = cached.metadata;
+            metadata.from_cache = true;
+            // Report how long the revalidation took, not the original request.
+            metadata.timings = timings;
+            return Ok((metadata, cached.body.into_bytes()));
+        }
+
+        if status == StatusCode::OK {
+            let etag = get_header(&metadata, ETAG.as_str());
+            let last_modified = get_header(&metadata, LAST_MODIFIED.as_str());
+            if etag.is_some() || last_modified.is_some() {
+                self.store_entry(
+                    path.as_path(),
+                    &CacheEntry {
+                        stored_at: now_secs(),
+                        etag,
+                        last_modified,
+                        metadata: metadata.clone(),
+                        body: HttpData::from(body.as_slice()),
+                    },
+                )?;
+            }
+        }
+
+        Ok((metadata, body))
+    }
+
+    fn execute_body(
+        &self,
+        request: Request,
+        body: RequestBody,
+    ) -> Result<(ResponseMetadata, Vec<u8>)> {
+        // Requests with bodies are never cached.
+        self.inner.execute_body(request, body)
+    }
+
+    fn sleep(&self, sleep: fn(Duration), duration: Duration) {
+        self.inner.sleep(sleep, duration)
+    }
+
+    fn get(&self, url: Url) -> RequestBuilder {
+        self.inner.get(url)
+    }
+    fn post(&self, url: Url) -> RequestBuilder {
+        self.inner.post(url)
+    }
+    fn put(&self, url: Url) -> RequestBuilder {
+        self.inner.put(url)
+    }
+    fn patch(&self, url: Url) -> RequestBuilder {
+        self.inner.patch(url)
+    }
+    fn delete(&self, url: Url) -> RequestBuilder {
+        self.inner.delete(url)
+    }
+    fn head(&self, url: Url) -> RequestBuilder {
+        self.inner.head(url)
+    }
+}
@@ -12,30 +12,143 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+#[cfg(feature = "crypto")]
+use crate::crypto::digest::{Digest, DigestBuilder};
 use crate::error::*;
+#[cfg(all(feature = "crypto", feature = "fs"))]
+use crate::fs::TempFile;
+use crate::http::body::RequestBody;
+use crate::http::proxy::ProxyConfig;
 // For recordings.
 #[cfg(debug_assertions)]
-use crate::http::recording::{RecordedRequest, RecordedResponse, Recording, RecordingEntry};
-use crate::http::types::ResponseMetadata;
+use crate::http::recording::{
+    BodyCapture, RecordedRequest, RecordedResponse, Recording, RecordingEntry, ResponseCapture,
+    ScrubConfig, StreamIds,
+};
+use crate::http::types::{ApiResult, RequestTimings, ResponseMetadata};
+#[cfg(unix)]
+use crate::http::unix;
+use crate::testing::clock::Clock;
 use futures::executor::block_on;
-use rand::Rng;
-use reqwest::header::HeaderMap;
+use reqwest::header::{HeaderMap, ACCEPT, CONTENT_TYPE};
 use reqwest::Client as InnerClient;
 use reqwest::{Method, Request, RequestBuilder, Url};
-// For recordings.
-#[cfg(debug_assertions)]
-use std::path::{Path, PathBuf};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+#[cfg(any(debug_assertions, all(feature = "crypto", feature = "fs")))]
+use std::path::Path;
+#[cfg(any(debug_assertions, unix))]
+use std::path::PathBuf;
+use std::sync::Arc;
 // For recordings.
 #[cfg(debug_assertions)]
 use std::sync::Mutex;
-use std::time::Duration;
-use tracing::{debug, info};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
 
 /// AbstractClient defines the generic interface for an HTTP client.
 pub trait AbstractClient {
     /// Execute (send) a previously-constructed HTTP request.
     fn execute(&self, request: Request) -> Result<(ResponseMetadata, Vec<u8>)>;
 
+    /// Execute (send) a previously-constructed HTTP request, with the given
+    /// body. Unlike bodies set directly on the Request, the body may be
+    /// streamed from a reader.
+    fn execute_body(
+        &self,
+        mut request: Request,
+        body: RequestBody,
+    ) -> Result<(ResponseMetadata, Vec<u8>)> {
+        body.apply(&mut request, None)?;
+        self.execute(request)
+    }
+
+    /// Execute (send) a previously-constructed HTTP request, passing the
+    /// response body to `on_chunk` piece by piece as it is received, instead
+    /// of buffering all of it. If `on_chunk` returns an error, the transfer is
+    /// aborted and that error is returned.
+    fn execute_streamed(
+        &self,
+        request: Request,
+        on_chunk: &mut dyn FnMut(&[u8]) -> Result<()>,
+    ) -> Result<ResponseMetadata> {
+        let (metadata, body) = self.execute(request)?;
+        on_chunk(body.as_slice())?;
+        Ok(metadata)
+    }
+
+    /// This is identical to `execute_streamed`, except the digest of the
+    /// response body is computed incrementally as it streams, and returned
+    /// along with the response metadata.
+    #[cfg(feature = "crypto")]
+    fn execute_digested(
+        &self,
+        request: Request,
+        on_chunk: &mut dyn FnMut(&[u8]) -> Result<()>,
+    ) -> Result<(ResponseMetadata, Digest)> {
+        let mut builder = DigestBuilder::new();
+        let metadata = self.execute_streamed(request, &mut |chunk| {
+            builder.update(chunk);
+            on_chunk(chunk)
+        })?;
+        Ok((metadata, builder.finish()))
+    }
+
+    /// Execute (send) a previously-constructed HTTP request, and verify that
+    /// the response body has the given digest (if any). If it doesn't,
+    /// `Error::DigestMismatch` is returned instead of the response.
+    #[cfg(feature = "crypto")]
+    fn execute_verified(
+        &self,
+        request: Request,
+        expected_digest: Option<&Digest>,
+    ) -> Result<(ResponseMetadata, Vec<u8>)> {
+        let expected = match expected_digest {
+            None => return self.execute(request),
+            Some(expected) => expected,
+        };
+        let mut body = Vec::new();
+        let (metadata, actual) = self.execute_digested(request, &mut |chunk| {
+            body.extend_from_slice(chunk);
+            Ok(())
+        })?;
+        verify_digest(expected, actual)?;
+        Ok((metadata, body))
+    }
+
+    /// Execute (send) a previously-constructed HTTP request, and write the
+    /// response body to the file at `dest`. The body is written to a temporary
+    /// file alongside `dest`, which is only moved into place if the body has
+    /// the given digest (if any). Otherwise, the temporary file is discarded,
+    /// `dest` is left untouched, and `Error::DigestMismatch` is returned.
+    ///
+    /// Note that the body is written regardless of the response's status
+    /// code; check the returned metadata to see if the request succeeded.
+    #[cfg(all(feature = "crypto", feature = "fs"))]
+    fn download_to(
+        &self,
+        request: Request,
+        dest: &Path,
+        expected_digest: Option<&Digest>,
+    ) -> Result<ResponseMetadata> {
+        use std::io::Write;
+
+        let dir = match dest.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let mut file = TempFile::new_in(dir, ".download")?;
+        let (metadata, actual) = self.execute_digested(request, &mut |chunk| {
+            file.as_file_mut().write_all(chunk)?;
+            Ok(())
+        })?;
+        if let Some(expected) = expected_digest {
+            verify_digest(expected, actual)?;
+        }
+        file.persist(dest)?;
+        Ok(metadata)
+    }
+
     /// This function calls the given custom sleep function with the given
     /// Duration. This can be overridden by a trait implementor to add extra
     /// logic, if needed.
@@ -49,7 +162,8 @@ pub trait AbstractClient {
     ///
     /// Unfortunately, to do this we need to be able to create copies of the
     /// request, meaning in particular the Body needs to be copyable. So, this
-    /// function can only support Vec<u8>-based request Bodies.
+    /// function can only support Vec<u8>-based request Bodies. See
+    /// execute_with_retries_body for streamed bodies.
     ///
     /// This function returns the response metadata (e.g. response code) and
     /// body, as well as the number of retries required (i.e., retries + 1
@@ -86,47 +200,125 @@ pub trait AbstractClient {
         headers: Option<&HeaderMap>,
         body: Option<&[u8]>,
     ) -> Result<(ResponseMetadata, Vec<u8>)> {
-        // Below we calculate 2^retry * 100 + 10 as a maximum, so the largest
-        // retry value we can store in a u64 is 57 (so max_retries must
-        // be <= 58, so retry will be in the range [0, 57)).
-        if max_retries > 58 {
-            return Err(Error::InvalidArgument(format!("max_retries must be <= 58")));
-        }
+        execute_with_retries_impl(
+            self,
+            |duration| self.sleep(sleep, duration),
+            max_retries,
+            add_jitter,
+            method,
+            url,
+            headers,
+            body.map(|b| RequestBody::from_bytes(b.to_vec())),
+        )
+    }
 
-        let mut rng = rand::thread_rng();
-        for retry in 0..max_retries + 1 {
-            let mut request = Request::new(method.clone(), url.clone());
-            if let Some(headers) = headers {
-                (*request.headers_mut()) = headers.clone();
-            }
-            if let Some(body) = body {
-                (*request.body_mut()) = Some(body.to_vec().into());
-            }
-
-            if retry > 0 {
-                let jitter: u64 = match add_jitter {
-                    false => 0,
-                    true => rng.gen_range(0..10),
-                };
-                let wait: u64 = (1_u64 << retry - 1) * 100 + jitter;
-                info!("Sleep for {}ms before retrying {} {}", wait, method, url);
-                self.sleep(sleep, Duration::from_millis(wait));
-            }
-
-            let (res_metadata, res_body) = self.execute(request)?;
-            let status = res_metadata.get_status()?;
-
-            if status.is_server_error() {
-                info!("{} {} returned {}, retrying...", method, url, status);
-            } else {
-                return Ok((res_metadata, res_body));
-            }
-        }
+    /// This is the same as execute_with_retries, but it accepts any kind of
+    /// RequestBody. Since the body may need to be sent more than once, it
+    /// must be replayable; i.e., a body constructed with
+    /// `RequestBody::from_reader` is rejected with an error, but one
+    /// constructed with `RequestBody::from_factory` is fine.
+    fn execute_with_retries_body(
+        &self,
+        max_retries: usize,
+        add_jitter: bool,
+        method: Method,
+        url: Url,
+        headers: Option<&HeaderMap>,
+        body: Option<RequestBody>,
+    ) -> Result<(ResponseMetadata, Vec<u8>)> {
+        execute_with_retries_impl(
+            self,
+            |duration| self.sleep(std::thread::sleep, duration),
+            max_retries,
+            add_jitter,
+            method,
+            url,
+            headers,
+            body,
+        )
+    }
 
-        Err(Error::HttpRetry(format!(
-            "failed to get a success response after {} retries.",
-            max_retries
-        )))
+    /// This is the same as execute_with_retries, but the given `Clock` is used
+    /// to wait between retries. This is mainly useful for testing, as a
+    /// `MockClock` lets the entire backoff be observed without really sleeping.
+    #[allow(clippy::too_many_arguments)]
+    fn execute_with_retries_clock(
+        &self,
+        clock: &dyn Clock,
+        max_retries: usize,
+        add_jitter: bool,
+        method: Method,
+        url: Url,
+        headers: Option<&HeaderMap>,
+        body: Option<&[u8]>,
+    ) -> Result<(ResponseMetadata, Vec<u8>)> {
+        execute_with_retries_impl(
+            self,
+            |duration| clock.sleep(duration),
+            max_retries,
+            add_jitter,
+            method,
+            url,
+            headers,
+            body.map(|b| RequestBody::from_bytes(b.to_vec())),
+        )
+    }
+
+    /// Send a GET request to the given URL, and deserialize the JSON
+    /// response body. Unsuccessful (non-2xx) responses result in
+    /// `Error::HttpStatus`, and responses which aren't JSON result in
+    /// `Error::HttpContentType`.
+    fn get_json<T: DeserializeOwned>(&self, url: Url) -> Result<T>
+    where
+        Self: Sized,
+    {
+        let request = self.get(url).header(ACCEPT, JSON_CONTENT_TYPE).build()?;
+        let (metadata, body) = self.execute(request)?;
+        check_status(&metadata, body.as_slice())?;
+        parse_json(&metadata, body.as_slice())
+    }
+
+    /// Send a POST request to the given URL with the given value serialized
+    /// as JSON as its body, and deserialize the JSON response body. Errors are
+    /// handled as `get_json` describes.
+    fn post_json<B: Serialize, T: DeserializeOwned>(&self, url: Url, body: &B) -> Result<T>
+    where
+        Self: Sized,
+    {
+        let request = self
+            .post(url)
+            .header(ACCEPT, JSON_CONTENT_TYPE)
+            .header(CONTENT_TYPE, JSON_CONTENT_TYPE)
+            .body(serde_json::to_vec(body)?)
+            .build()?;
+        let (metadata, body) = self.execute(request)?;
+        check_status(&metadata, body.as_slice())?;
+        parse_json(&metadata, body.as_slice())
+    }
+
+    /// Execute (send) a previously-constructed HTTP request, and deserialize
+    /// the JSON response body. For APIs which describe failures in their
+    /// response bodies, an unsuccessful (non-2xx) response is deserialized as
+    /// an error body of type `E` instead, and returned as `ApiResult::Err`.
+    ///
+    /// Responses which aren't JSON (of either kind) result in
+    /// `Error::HttpContentType`.
+    fn execute_json<T: DeserializeOwned, E: DeserializeOwned>(
+        &self,
+        request: Request,
+    ) -> Result<ApiResult<T, E>>
+    where
+        Self: Sized,
+    {
+        let (metadata, body) = self.execute(request)?;
+        let status = metadata.get_status()?;
+        Ok(match status.is_success() {
+            true => ApiResult::Ok(parse_json(&metadata, body.as_slice())?),
+            false => ApiResult::Err {
+                status: status.as_u16(),
+                error: parse_json(&metadata, body.as_slice())?,
+            },
+        })
     }
 
     /// Returns a builder for an HTTP GET request.
@@ -143,54 +335,387 @@ pub trait AbstractClient {
     fn head(&self, url: Url) -> RequestBuilder;
 }
 
+#[cfg(feature = "crypto")]
+fn verify_digest(expected: &Digest, actual: Digest) -> Result<()> {
+    if actual != *expected {
+        return Err(Error::DigestMismatch {
+            expected: Box::new(expected.clone()),
+            actual: Box::new(actual),
+        });
+    }
+    Ok(())
+}
+
+/// The URL scheme used to address a server listening on a Unix domain socket
+/// directly, as in `unix:///run/app.sock:/v1/status`.
+pub(crate) const UNIX_SCHEME: &str = "unix";
+
+/// Start building a request for the given URL with the given reqwest client.
+/// reqwest refuses to build requests for URLs without a host, so `unix:` URLs
+/// bypass its check; it's up to the client to send them appropriately.
+pub(crate) fn request_builder(inner: &InnerClient, method: Method, url: Url) -> RequestBuilder {
+    match url.scheme() {
+        UNIX_SCHEME => RequestBuilder::from_parts(inner.clone(), Request::new(method, url)),
+        _ => inner.request(method, url),
+    }
+}
+
+/// The content type JSON request bodies are sent with.
+const JSON_CONTENT_TYPE: &str = "application/json";
+
+/// How much of a response body to include in errors. Bodies may be large, or
+/// contain sensitive data, so we never include the whole thing.
+const ERROR_BODY_PREFIX_BYTES: usize = 64;
+
+/// Return the beginning of the given response body, for use in error messages.
+pub(crate) fn body_prefix(body: &[u8]) -> String {
+    let len = body.len().min(ERROR_BODY_PREFIX_BYTES);
+    let mut prefix = String::from_utf8_lossy(&body[..len]).into_owned();
+    if len < body.len() {
+        prefix.push_str("...");
+    }
+    prefix
+}
+
+/// Return an error if the given response has an unsuccessful status.
+fn check_status(metadata: &ResponseMetadata, body: &[u8]) -> Result<()> {
+    let status = metadata.get_status()?;
+    if !status.is_success() {
+        return Err(Error::HttpStatus {
+            status: status.as_u16(),
+            body_prefix: body_prefix(body),
+        });
+    }
+    Ok(())
+}
+
+/// Deserialize the given response body, after checking that the response
+/// claims to be JSON (i.e., "application/json", or a "+json" type).
+pub(crate) fn parse_json<T: DeserializeOwned>(
+    metadata: &ResponseMetadata,
+    body: &[u8],
+) -> Result<T> {
+    let content_type = match metadata.get_headers().get(CONTENT_TYPE.as_str()) {
+        Some(values) if !values.is_empty() => values[0].clone().try_into_string()?,
+        _ => String::new(),
+    };
+    let media_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    if media_type != JSON_CONTENT_TYPE && !media_type.ends_with("+json") {
+        return Err(Error::HttpContentType {
+            content_type,
+            body_prefix: body_prefix(body),
+        });
+    }
+    Ok(serde_json::from_slice(body)?)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn execute_with_retries_impl<C: AbstractClient + ?Sized, S: Fn(Duration)>(
+    client: &C,
+    sleep: S,
+    max_retries: usize,
+    add_jitter: bool,
+    method: Method,
+    url: Url,
+    headers: Option<&HeaderMap>,
+    body: Option<RequestBody>,
+) -> Result<(ResponseMetadata, Vec<u8>)> {
+    if body.as_ref().is_some_and(|b| !b.is_replayable()) {
+        return Err(Error::InvalidArgument(
+            "request bodies read from a reader can't be replayed, so they can't be retried; use RequestBody::from_factory instead".to_string(),
+        ));
+    }
+
+    // Below we calculate 2^retry * 100 + 10 as a maximum, so the largest
+    // retry value we can store in a u64 is 57 (so max_retries must
+    // be <= 58, so retry will be in the range [0, 57)).
+    if max_retries > 58 {
+        return Err(Error::InvalidArgument(format!("max_retries must be <= 58")));
+    }
+
+    let policy = RetryPolicy {
+        max_retries,
+        initial_delay: Duration::from_millis(100),
+        multiplier: 2,
+        max_delay: Duration::MAX,
+        jitter: match add_jitter {
+            false => Duration::ZERO,
+            true => Duration::from_millis(10),
+        },
+    };
+    let mut rng = rand::thread_rng();
+    for retry in 0..max_retries + 1 {
+        let mut request = Request::new(method.clone(), url.clone());
+        if let Some(headers) = headers {
+            (*request.headers_mut()) = headers.clone();
+        }
+        let attempt_body = body.as_ref().and_then(|b| b.try_clone());
+
+        if retry > 0 {
+            let wait = policy.delay(retry, &mut rng);
+            info!(
+                "Sleep for {}ms before retrying {} {}",
+                wait.as_millis(),
+                method,
+                url
+            );
+            sleep(wait);
+        }
+
+        let (res_metadata, res_body) = match attempt_body {
+            None => client.execute(request)?,
+            Some(b) => client.execute_body(request, b)?,
+        };
+        let status = res_metadata.get_status()?;
+
+        if status.is_server_error() {
+            info!("{} {} returned {}, retrying...", method, url, status);
+        } else {
+            return Ok((res_metadata, res_body));
+        }
+    }
+
+    Err(Error::HttpRetry(format!(
+        "failed to get a success response after {} retries.",
+        max_retries
+    )))
+}
+
 /// Client is the standard, non-testing implementation of AbstractClient. If
 /// debug assersions are enabled, then this structure also provides a mechanism
 /// for recording an HTTP session.
+///
+/// On Unix, requests can also be sent to a server listening on a Unix domain
+/// socket, either by addressing it directly with a URL like
+/// `unix:///run/app.sock:/v1/status`, or by configuring the client with
+/// `with_unix_socket`.
 pub struct Client {
     inner: InnerClient,
+    proxy: Arc<ProxyConfig>,
+    #[cfg(unix)]
+    unix_socket: Option<PathBuf>,
     #[cfg(debug_assertions)]
     recording: Option<Mutex<Recording>>,
     #[cfg(debug_assertions)]
     recording_output: Option<PathBuf>,
+    #[cfg(debug_assertions)]
+    scrub: ScrubConfig,
+    #[cfg(debug_assertions)]
+    stream_ids: StreamIds,
+}
+
+/// Build a reqwest client which uses the given proxy configuration, instead of
+/// reqwest's own handling of the proxy environment variables, so the same
+/// decisions are made (and recorded) everywhere.
+fn build_inner(proxy: &Arc<ProxyConfig>) -> InnerClient {
+    let proxy = proxy.clone();
+    InnerClient::builder()
+        .proxy(reqwest::Proxy::custom(move |url| {
+            proxy.proxy_for(url).map(|p| p.url().clone())
+        }))
+        .build()
+        .expect("failed to build HTTP client")
+}
+
+fn default_proxy_config() -> Arc<ProxyConfig> {
+    Arc::new(ProxyConfig::from_env().unwrap_or_else(|e| {
+        warn!("ignoring invalid proxy environment variables: {}", e);
+        ProxyConfig::default()
+    }))
 }
 
 impl Client {
-    /// Initialize a new client.
+    /// Initialize a new client. Requests are proxied according to the
+    /// standard environment variables (see `ProxyConfig::from_env`).
     pub fn new() -> Self {
+        let proxy = default_proxy_config();
         Client {
-            inner: InnerClient::new(),
+            inner: build_inner(&proxy),
+            proxy,
+            #[cfg(unix)]
+            unix_socket: None,
             #[cfg(debug_assertions)]
             recording: None,
             #[cfg(debug_assertions)]
             recording_output: None,
+            #[cfg(debug_assertions)]
+            scrub: ScrubConfig::default(),
+            #[cfg(debug_assertions)]
+            stream_ids: StreamIds::default(),
         }
     }
 
     /// Initialize a new client, which will record its HTTP session and write
     /// the result to the given path once it is destructed.
+    ///
+    /// Sensitive headers are scrubbed from the recording according to
+    /// `ScrubConfig::default()`.
     #[cfg(debug_assertions)]
     pub fn new_with_recording<P: AsRef<Path>>(recording_output: P) -> Self {
+        Self::new_with_scrubbed_recording(recording_output, ScrubConfig::default())
+    }
+
+    /// Initialize a new client, which will record its HTTP session and write
+    /// the result to the given path once it is destructed. Requests are
+    /// scrubbed according to the given configuration before being recorded.
+    #[cfg(debug_assertions)]
+    pub fn new_with_scrubbed_recording<P: AsRef<Path>>(
+        recording_output: P,
+        scrub: ScrubConfig,
+    ) -> Self {
+        let proxy = default_proxy_config();
         Client {
-            inner: InnerClient::new(),
+            inner: build_inner(&proxy),
+            proxy,
+            #[cfg(unix)]
+            unix_socket: None,
             recording: Some(Mutex::new(Recording::default())),
             recording_output: Some(recording_output.as_ref().to_path_buf()),
+            scrub,
+            stream_ids: StreamIds::default(),
         }
     }
 
+    /// Use the given proxy configuration, instead of the one from the
+    /// environment.
+    pub fn with_proxy(mut self, proxy: ProxyConfig) -> Self {
+        self.proxy = Arc::new(proxy);
+        self.inner = build_inner(&self.proxy);
+        self
+    }
+
+    /// Return the proxy configuration this client uses.
+    pub fn proxy(&self) -> &ProxyConfig {
+        &self.proxy
+    }
+
+    /// Initialize a new client, which sends all of its requests to the server
+    /// listening on the given Unix domain socket. This is equivalent to
+    /// `Client::new().with_unix_socket(socket)`.
+    #[cfg(unix)]
+    pub fn unix<P: AsRef<Path>>(socket: P) -> Self {
+        Self::new().with_unix_socket(socket)
+    }
+
+    /// Send all requests to the server listening on the given Unix domain
+    /// socket, instead of over TCP. Only the path and query of each request's
+    /// URL are used, so e.g. `http://localhost/v1/status` requests
+    /// `/v1/status`. Requests sent this way are never proxied.
+    #[cfg(unix)]
+    pub fn with_unix_socket<P: AsRef<Path>>(mut self, socket: P) -> Self {
+        self.unix_socket = Some(socket.as_ref().to_path_buf());
+        self
+    }
+
+    /// Return the socket the given request should be sent over, and the path
+    /// to request, if it should be sent over a Unix domain socket.
+    #[cfg(unix)]
+    fn unix_target(&self, url: &Url) -> Result<Option<(PathBuf, String)>> {
+        if url.scheme() == UNIX_SCHEME {
+            return unix::split_unix_url(url).map(Some);
+        }
+        Ok(self
+            .unix_socket
+            .as_ref()
+            .map(|socket| (socket.clone(), unix::request_target(url))))
+    }
+
     fn execute_impl(&self, request: Request) -> Result<(ResponseMetadata, Vec<u8>)> {
+        let mut body = Vec::new();
+        let metadata = self.execute_streamed_impl(request, &mut |chunk| {
+            body.extend_from_slice(chunk);
+            Ok(())
+        })?;
+        Ok((metadata, body))
+    }
+
+    fn execute_streamed_impl(
+        &self,
+        request: Request,
+        on_chunk: &mut dyn FnMut(&[u8]) -> Result<()>,
+    ) -> Result<ResponseMetadata> {
         #[cfg(debug_assertions)]
         let method = request.method().clone();
         #[cfg(debug_assertions)]
         let url = request.url().clone();
 
-        let res = block_on(self.inner.execute(request))?;
-        let metadata = ResponseMetadata::from(&res);
-        let body: Vec<u8> = block_on(res.bytes())?.into_iter().collect();
+        let start = Instant::now();
+        #[cfg(unix)]
+        if let Some((socket, target)) = self.unix_target(request.url())? {
+            let (mut metadata, body) = unix::execute(&socket, &request, &target)?;
+            on_chunk(body.as_slice())?;
+            metadata.set_timings(Some(RequestTimings {
+                total: start.elapsed(),
+                ..Default::default()
+            }));
+
+            #[cfg(debug_assertions)]
+            debug!(
+                "{} {} (via {}) => {}",
+                method,
+                url,
+                socket.display(),
+                metadata.get_status().unwrap()
+            );
+
+            return Ok(metadata);
+        }
+
+        let mut res = block_on(self.inner.execute(request))?;
+        let first_byte = start.elapsed();
+        let mut metadata = ResponseMetadata::from(&res);
+        while let Some(chunk) = block_on(res.chunk())? {
+            on_chunk(chunk.as_ref())?;
+        }
+        metadata.set_timings(Some(RequestTimings {
+            first_byte: Some(first_byte),
+            total: start.elapsed(),
+            ..Default::default()
+        }));
 
         #[cfg(debug_assertions)]
         debug!("{} {} => {}", method, url, metadata.get_status().unwrap());
 
-        Ok((metadata, body))
+        Ok(metadata)
+    }
+
+    /// Build the RecordedRequest for the given request, including the proxy
+    /// it will be sent through (if any).
+    #[cfg(debug_assertions)]
+    pub(crate) fn recorded_request(&self, request: &Request) -> RecordedRequest {
+        let mut recorded = RecordedRequest::from(request);
+        #[cfg(unix)]
+        if matches!(self.unix_target(request.url()), Ok(Some(_))) {
+            return recorded;
+        }
+        recorded.proxy = self
+            .proxy
+            .proxy_for(request.url())
+            .map(|p| p.url().to_string());
+        recorded
+    }
+
+    #[cfg(debug_assertions)]
+    fn record(&self, req: RecordedRequest, res: &(ResponseMetadata, Vec<u8>)) {
+        self.record_response(req, RecordedResponse::from(res));
+    }
+
+    #[cfg(debug_assertions)]
+    fn record_response(&self, req: RecordedRequest, res: RecordedResponse) {
+        if let Some(recording) = self.recording.as_ref() {
+            let recorded_res = self.scrub.scrub_response(res);
+            let mut lock = recording.lock().unwrap();
+            lock.0.push_back(RecordingEntry {
+                req: self.scrub.scrub(req),
+                res: recorded_res,
+                stream_id: Some(self.stream_ids.current()),
+            });
+        }
     }
 }
 
@@ -200,40 +725,99 @@ impl AbstractClient for Client {
         self.execute_impl(request)
     }
 
+    #[cfg(not(debug_assertions))]
+    fn execute_body(
+        &self,
+        mut request: Request,
+        body: RequestBody,
+    ) -> Result<(ResponseMetadata, Vec<u8>)> {
+        body.apply(&mut request, None)?;
+        self.execute_impl(request)
+    }
+
+    #[cfg(not(debug_assertions))]
+    fn execute_streamed(
+        &self,
+        request: Request,
+        on_chunk: &mut dyn FnMut(&[u8]) -> Result<()>,
+    ) -> Result<ResponseMetadata> {
+        self.execute_streamed_impl(request, on_chunk)
+    }
+
+    #[cfg(debug_assertions)]
+    fn execute_streamed(
+        &self,
+        request: Request,
+        on_chunk: &mut dyn FnMut(&[u8]) -> Result<()>,
+    ) -> Result<ResponseMetadata> {
+        if self.recording.is_none() {
+            return self.execute_streamed_impl(request, on_chunk);
+        }
+
+        // Large bodies are recorded as a digest, so they needn't be buffered.
+        let recorded_req = self.recorded_request(&request);
+        let mut capture = ResponseCapture::default();
+        let metadata = self.execute_streamed_impl(request, &mut |chunk| {
+            capture.update(chunk);
+            on_chunk(chunk)
+        })?;
+        self.record_response(recorded_req, capture.into_response(metadata.clone()));
+        Ok(metadata)
+    }
+
     #[cfg(debug_assertions)]
     fn execute(&self, request: Request) -> Result<(ResponseMetadata, Vec<u8>)> {
-        let recorded_req = RecordedRequest::from(&request);
+        let recorded_req = self.recorded_request(&request);
         let res = self.execute_impl(request)?;
+        self.record(recorded_req, &res);
+        Ok(res)
+    }
 
-        if let Some(recording) = self.recording.as_ref() {
-            let recorded_res = RecordedResponse::from(&res);
-            let mut lock = recording.lock().unwrap();
-            lock.0.push_back(RecordingEntry {
-                req: recorded_req,
-                res: recorded_res,
-            });
+    #[cfg(debug_assertions)]
+    fn execute_body(
+        &self,
+        mut request: Request,
+        body: RequestBody,
+    ) -> Result<(ResponseMetadata, Vec<u8>)> {
+        if self.recording.is_none() {
+            body.apply(&mut request, None)?;
+            return self.execute_impl(request);
         }
 
+        // Tee the body into a capture as it's sent, so it can be recorded.
+        let capture = Arc::new(Mutex::new(BodyCapture::default()));
+        let observer_capture = capture.clone();
+        body.apply(
+            &mut request,
+            Some(Box::new(move |data| {
+                observer_capture.lock().unwrap().update(data)
+            })),
+        )?;
+
+        let mut recorded_req = self.recorded_request(&request);
+        let res = self.execute_impl(request)?;
+        recorded_req.set_body_capture(std::mem::take(&mut *capture.lock().unwrap()));
+        self.record(recorded_req, &res);
         Ok(res)
     }
 
     fn get(&self, url: Url) -> RequestBuilder {
-        self.inner.get(url)
+        request_builder(&self.inner, Method::GET, url)
     }
     fn post(&self, url: Url) -> RequestBuilder {
-        self.inner.post(url)
+        request_builder(&self.inner, Method::POST, url)
     }
     fn put(&self, url: Url) -> RequestBuilder {
-        self.inner.put(url)
+        request_builder(&self.inner, Method::PUT, url)
     }
     fn patch(&self, url: Url) -> RequestBuilder {
-        self.inner.patch(url)
+        request_builder(&self.inner, Method::PATCH, url)
     }
     fn delete(&self, url: Url) -> RequestBuilder {
-        self.inner.delete(url)
+        request_builder(&self.inner, Method::DELETE, url)
     }
     fn head(&self, url: Url) -> RequestBuilder {
-        self.inner.head(url)
+        request_builder(&self.inner, Method::HEAD, url)
     }
 }
 
@@ -0,0 +1,273 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#[cfg(feature = "cli")]
+use crate::cli::{Alignment, Table};
+use crate::error::*;
+use crate::http::body::RequestBody;
+use crate::http::client::AbstractClient;
+use crate::http::types::{RequestTimings, ResponseMetadata};
+use crate::testing::clock::{Clock, SystemClock};
+use reqwest::{Request, RequestBuilder, Url};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+/// The default number of (most recent) request durations kept, to compute
+/// latency percentiles from.
+pub const DEFAULT_MAX_SAMPLES: usize = 1024;
+
+/// ClientMetrics is a snapshot of the counters a `MeteredClient` maintains.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ClientMetrics {
+    /// The number of requests issued.
+    pub requests: u64,
+    /// The number of requests which failed without a response (e.g. because
+    /// the connection failed).
+    pub errors: u64,
+    /// The number of responses received, by status class: the element at
+    /// index `i` counts `(i + 1)xx` responses.
+    pub responses_by_class: [u64; 5],
+    /// The number of request body bytes sent, as far as they're known up
+    /// front (streamed bodies without a known length aren't counted).
+    pub bytes_sent: u64,
+    /// The number of response body bytes received.
+    pub bytes_received: u64,
+    /// The number of requests which were known to open a new connection.
+    pub connections_opened: u64,
+    /// The number of requests which were known to reuse an existing
+    /// connection.
+    pub connections_reused: u64,
+    /// The number of TLS handshakes which were known to be performed.
+    pub tls_handshakes: u64,
+    /// The median total request duration, over the most recent requests.
+    pub latency_p50: Option<Duration>,
+    /// The 95th percentile total request duration, over the most recent
+    /// requests.
+    pub latency_p95: Option<Duration>,
+}
+
+#[cfg(feature = "cli")]
+fn format_latency(latency: Option<Duration>) -> String {
+    match latency {
+        None => "-".to_string(),
+        Some(latency) => format!("{:.1}ms", latency.as_secs_f64() * 1000.0),
+    }
+}
+
+impl ClientMetrics {
+    /// Build a two-column table summarizing these metrics, e.g. for printing
+    /// at exit when a `--stats` flag is given.
+    #[cfg(feature = "cli")]
+    pub fn to_table(&self) -> Table {
+        let mut table = Table::new()
+            .header(["metric", "value"])
+            .align(1, Alignment::Right);
+        table.add_row(["requests".to_string(), self.requests.to_string()]);
+        table.add_row(["errors".to_string(), self.errors.to_string()]);
+        for (i, count) in self.responses_by_class.iter().enumerate() {
+            table.add_row([format!("responses {}xx", i + 1), count.to_string()]);
+        }
+        table.add_row(["bytes sent".to_string(), self.bytes_sent.to_string()]);
+        table.add_row([
+            "bytes received".to_string(),
+            self.bytes_received.to_string(),
+        ]);
+        table.add_row([
+            "connections opened".to_string(),
+            self.connections_opened.to_string(),
+        ]);
+        table.add_row([
+            "connections reused".to_string(),
+            self.connections_reused.to_string(),
+        ]);
+        table.add_row([
+            "TLS handshakes".to_string(),
+            self.tls_handshakes.to_string(),
+        ]);
+        table.add_row(["latency p50".to_string(), format_latency(self.latency_p50)]);
+        table.add_row(["latency p95".to_string(), format_latency(self.latency_p95)]);
+        table
+    }
+}
+
+/// Return the given percentile (in the range [0, 100]) of the given samples,
+/// using the nearest-rank method.
+fn percentile(sorted: &[Duration], p: u32) -> Option<Duration> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (p as usize * sorted.len()).div_ceil(100).max(1);
+    Some(sorted[rank - 1])
+}
+
+#[derive(Default)]
+struct MetricsState {
+    metrics: ClientMetrics,
+    samples: VecDeque<Duration>,
+}
+
+/// MeteredClient wraps another AbstractClient, and keeps track of some
+/// metrics about the requests sent through it (see `ClientMetrics`), e.g. to
+/// debug performance problems.
+///
+/// Timings come from the `RequestTimings` the inner client attaches to its
+/// responses, if any (so replayed sessions report deterministic metrics).
+/// Otherwise, the total duration of each request is measured with this
+/// client's `Clock`.
+pub struct MeteredClient<C: AbstractClient> {
+    inner: C,
+    clock: Arc<dyn Clock>,
+    max_samples: usize,
+    state: Mutex<MetricsState>,
+}
+
+impl<C: AbstractClient> MeteredClient<C> {
+    /// Construct a new MeteredClient which sends requests with `inner`.
+    pub fn new(inner: C) -> Self {
+        MeteredClient {
+            inner,
+            clock: Arc::new(SystemClock),
+            max_samples: DEFAULT_MAX_SAMPLES,
+            state: Mutex::new(MetricsState::default()),
+        }
+    }
+
+    /// Use the given clock to measure requests whose responses don't carry
+    /// their own timings.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Set the number of (most recent) request durations kept, to compute
+    /// latency percentiles from.
+    pub fn with_max_samples(mut self, max_samples: usize) -> Self {
+        self.max_samples = max_samples.max(1);
+        self
+    }
+
+    /// Return the wrapped client.
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    /// Return a snapshot of this client's metrics, so far.
+    pub fn metrics(&self) -> ClientMetrics {
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut sorted: Vec<Duration> = state.samples.iter().cloned().collect();
+        sorted.sort();
+        ClientMetrics {
+            latency_p50: percentile(&sorted, 50),
+            latency_p95: percentile(&sorted, 95),
+            ..state.metrics.clone()
+        }
+    }
+
+    /// Reset all of this client's metrics to zero, returning a snapshot of
+    /// the metrics from before they were reset.
+    pub fn reset_metrics(&self) -> ClientMetrics {
+        let snapshot = self.metrics();
+        *self.state.lock().unwrap_or_else(PoisonError::into_inner) = MetricsState::default();
+        snapshot
+    }
+
+    fn metered<F: FnOnce() -> Result<(ResponseMetadata, Vec<u8>)>>(
+        &self,
+        bytes_sent: Option<u64>,
+        f: F,
+    ) -> Result<(ResponseMetadata, Vec<u8>)> {
+        let start = self.clock.now_instant();
+        let res = f();
+        let elapsed = self.clock.now_instant().saturating_duration_since(start);
+
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.metrics.requests += 1;
+        state.metrics.bytes_sent += bytes_sent.unwrap_or(0);
+        let (metadata, body) = match res.as_ref() {
+            Ok(res) => res,
+            Err(_) => {
+                state.metrics.errors += 1;
+                return res;
+            }
+        };
+
+        let class = (metadata.status / 100) as usize;
+        if (1..=5).contains(&class) {
+            state.metrics.responses_by_class[class - 1] += 1;
+        }
+        state.metrics.bytes_received += body.len() as u64;
+
+        let timings = metadata.get_timings().cloned().unwrap_or(RequestTimings {
+            total: elapsed,
+            ..Default::default()
+        });
+        match (timings.connect.is_some(), timings.connection_reused) {
+            (true, _) | (_, Some(false)) => state.metrics.connections_opened += 1,
+            (false, Some(true)) => state.metrics.connections_reused += 1,
+            (false, None) => {}
+        }
+        if timings.tls.is_some() {
+            state.metrics.tls_handshakes += 1;
+        }
+        if state.samples.len() >= self.max_samples {
+            state.samples.pop_front();
+        }
+        state.samples.push_back(timings.total);
+
+        res
+    }
+}
+
+impl<C: AbstractClient> AbstractClient for MeteredClient<C> {
+    fn execute(&self, request: Request) -> Result<(ResponseMetadata, Vec<u8>)> {
+        let bytes_sent = request
+            .body()
+            .and_then(|b| b.as_bytes())
+            .map(|b| b.len() as u64);
+        self.metered(bytes_sent, || self.inner.execute(request))
+    }
+
+    fn execute_body(
+        &self,
+        request: Request,
+        body: RequestBody,
+    ) -> Result<(ResponseMetadata, Vec<u8>)> {
+        let bytes_sent = body.content_length();
+        self.metered(bytes_sent, || self.inner.execute_body(request, body))
+    }
+
+    fn sleep(&self, sleep: fn(Duration), duration: Duration) {
+        self.inner.sleep(sleep, duration)
+    }
+
+    fn get(&self, url: Url) -> RequestBuilder {
+        self.inner.get(url)
+    }
+    fn post(&self, url: Url) -> RequestBuilder {
+        self.inner.post(url)
+    }
+    fn put(&self, url: Url) -> RequestBuilder {
+        self.inner.put(url)
+    }
+    fn patch(&self, url: Url) -> RequestBuilder {
+        self.inner.patch(url)
+    }
+    fn delete(&self, url: Url) -> RequestBuilder {
+        self.inner.delete(url)
+    }
+    fn head(&self, url: Url) -> RequestBuilder {
+        self.inner.head(url)
+    }
+}
@@ -12,14 +12,33 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+/// auth provides an HTTP client wrapper which attaches credentials to
+/// requests, and transparently refreshes them when they expire.
+pub mod auth;
+/// body provides request bodies which can be streamed from a reader, instead
+/// of being buffered in memory.
+pub mod body;
+/// cache provides an optional on-disk cache of HTTP responses, which uses
+/// conditional requests to revalidate cached responses.
+#[cfg(feature = "fs")]
+pub mod cache;
 /// client provides a simple HTTP client trait and implementation, based upon
 /// reqwest.
 pub mod client;
+/// metrics provides an HTTP client wrapper which keeps track of request
+/// counts, transfer sizes, and latencies, for debugging performance problems.
+pub mod metrics;
+/// proxy decides which HTTP proxy (if any) requests should be sent through.
+pub mod proxy;
 /// recording provides structures used to record HTTP sessions, so they can
 /// later be replayed and verified in unit tests.
 #[cfg(debug_assertions)]
 pub mod recording;
 /// types defines custom types for modeling HTTP requests / responses.
 pub mod types;
+/// unix implements just enough of HTTP/1.1 to send requests to servers
+/// listening on Unix domain sockets, which reqwest doesn't support.
+#[cfg(unix)]
+mod unix;
 /// util contains various HTTP-related utility functions.
 pub mod util;
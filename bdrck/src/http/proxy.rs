@@ -0,0 +1,263 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::error::*;
+use reqwest::Url;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use url::Host;
+
+/// The placeholder which replaces proxy credentials in redacted proxy URLs.
+pub const REDACTED_CREDENTIAL: &str = "__SCRUBBED__";
+
+/// ProxyUrl is the URL of an HTTP proxy server. Its `Debug` and `Display`
+/// implementations redact any credentials embedded in the URL.
+#[derive(Clone, Eq, PartialEq)]
+pub struct ProxyUrl(Url);
+
+impl ProxyUrl {
+    /// Return the full URL of this proxy, including any credentials.
+    pub fn url(&self) -> &Url {
+        &self.0
+    }
+
+    /// Return this proxy's URL, with any embedded username and password
+    /// replaced with `REDACTED_CREDENTIAL`.
+    pub fn redacted(&self) -> String {
+        redact_proxy_url(self.0.as_str())
+    }
+}
+
+impl FromStr for ProxyUrl {
+    type Err = Error;
+
+    /// Parse a proxy URL. As is conventional, a URL without a scheme (e.g.
+    /// "proxy.corp.example:3128") is assumed to be an HTTP proxy.
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let url = match s.contains("://") {
+            true => Url::parse(s),
+            false => Url::parse(format!("http://{}", s).as_str()),
+        }
+        .map_err(|_| Error::InvalidArgument("invalid proxy URL".to_string()))?;
+        if url.host().is_none() {
+            return Err(Error::InvalidArgument(
+                "proxy URLs must include a host".to_string(),
+            ));
+        }
+        Ok(ProxyUrl(url))
+    }
+}
+
+impl fmt::Debug for ProxyUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ProxyUrl").field(&self.redacted()).finish()
+    }
+}
+
+impl fmt::Display for ProxyUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.redacted())
+    }
+}
+
+/// Replace any username and password embedded in the given URL with
+/// `REDACTED_CREDENTIAL`. Strings which aren't valid URLs are returned as-is.
+pub fn redact_proxy_url(url: &str) -> String {
+    let mut parsed = match Url::parse(url) {
+        Err(_) => return url.to_owned(),
+        Ok(u) => u,
+    };
+    if parsed.password().is_some() {
+        let _ = parsed.set_password(Some(REDACTED_CREDENTIAL));
+    }
+    if !parsed.username().is_empty() {
+        let _ = parsed.set_username(REDACTED_CREDENTIAL);
+    }
+    parsed.into()
+}
+
+/// NoProxyRule describes a set of hosts which should be contacted directly,
+/// instead of via a proxy. Rules are parsed from the usual `NO_PROXY` syntax;
+/// ports are not supported.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum NoProxyRule {
+    /// "*" matches every host.
+    Any,
+    /// A host name, which matches only exactly that host (case-insensitively).
+    Host(String),
+    /// ".corp.example" (or "*.corp.example") matches "corp.example" and any of
+    /// its subdomains. The leading dot is not stored.
+    DomainSuffix(String),
+    /// An IP address, which matches only that address.
+    Ip(IpAddr),
+    /// A CIDR range (e.g. "10.0.0.0/8"), which matches any address in it.
+    Cidr(IpAddr, u8),
+}
+
+impl FromStr for NoProxyRule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim().to_ascii_lowercase();
+        if s.is_empty() {
+            return Err(Error::InvalidArgument("empty no-proxy rule".to_string()));
+        }
+        if s == "*" {
+            return Ok(NoProxyRule::Any);
+        }
+        if let Some((network, prefix_len)) = s.split_once('/') {
+            let invalid = || Error::InvalidArgument(format!("invalid CIDR range '{}'", s));
+            let network: IpAddr = network.parse().map_err(|_| invalid())?;
+            let prefix_len: u8 = prefix_len.parse().map_err(|_| invalid())?;
+            let max_len = match network {
+                IpAddr::V4(_) => 32,
+                IpAddr::V6(_) => 128,
+            };
+            if prefix_len > max_len {
+                return Err(invalid());
+            }
+            return Ok(NoProxyRule::Cidr(network, prefix_len));
+        }
+        let unbracketed = s.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(ip) = unbracketed.parse() {
+            return Ok(NoProxyRule::Ip(ip));
+        }
+        if let Some(suffix) = s.strip_prefix("*.").or_else(|| s.strip_prefix('.')) {
+            return Ok(NoProxyRule::DomainSuffix(suffix.to_owned()));
+        }
+        Ok(NoProxyRule::Host(s))
+    }
+}
+
+fn cidr_contains(network: &IpAddr, prefix_len: u8, ip: &IpAddr) -> bool {
+    match (network, ip) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+            u32::from(*network) & mask == u32::from(*ip) & mask
+        }
+        (IpAddr::V6(network), IpAddr::V6(ip)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0);
+            u128::from(*network) & mask == u128::from(*ip) & mask
+        }
+        _ => false,
+    }
+}
+
+impl NoProxyRule {
+    /// Returns whether or not this rule matches the given host.
+    pub fn matches(&self, host: &Host<&str>) -> bool {
+        let ip = match host {
+            Host::Domain(_) => None,
+            Host::Ipv4(ip) => Some(IpAddr::V4(*ip)),
+            Host::Ipv6(ip) => Some(IpAddr::V6(*ip)),
+        };
+        match (self, host, ip) {
+            (NoProxyRule::Any, _, _) => true,
+            (NoProxyRule::Host(rule), Host::Domain(domain), _) => rule.eq_ignore_ascii_case(domain),
+            (NoProxyRule::DomainSuffix(suffix), Host::Domain(domain), _) => {
+                let domain = domain.to_ascii_lowercase();
+                domain == *suffix
+                    || domain
+                        .strip_suffix(suffix.as_str())
+                        .is_some_and(|prefix| prefix.ends_with('.'))
+            }
+            (NoProxyRule::Ip(rule), _, Some(ip)) => *rule == ip,
+            (NoProxyRule::Cidr(network, prefix_len), _, Some(ip)) => {
+                cidr_contains(network, *prefix_len, &ip)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// ProxyConfig decides which proxy (if any) each request should be sent
+/// through. By default, `Client` uses `ProxyConfig::from_env`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ProxyConfig {
+    /// The proxy to use for "http" URLs.
+    pub http: Option<ProxyUrl>,
+    /// The proxy to use for "https" URLs.
+    pub https: Option<ProxyUrl>,
+    /// Hosts matching any of these rules are contacted directly.
+    pub no_proxy: Vec<NoProxyRule>,
+}
+
+impl ProxyConfig {
+    /// Build a ProxyConfig from the standard `HTTP_PROXY`, `HTTPS_PROXY`,
+    /// `ALL_PROXY` and `NO_PROXY` environment variables. See `from_lookup`.
+    pub fn from_env() -> Result<Self> {
+        Self::from_lookup(|name| std::env::var(name).ok())
+    }
+
+    /// Build a ProxyConfig from the standard proxy environment variables,
+    /// looked up with the given function. As is conventional, lowercase
+    /// variable names take precedence over uppercase ones, `ALL_PROXY` applies
+    /// to both schemes if they don't have a more specific setting, and empty
+    /// values are ignored.
+    ///
+    /// If `REQUEST_METHOD` is set, we're probably running as a CGI script, in
+    /// which case `HTTP_PROXY` may have come from a request's "Proxy" header
+    /// ("httpoxy"), so it is ignored; only `http_proxy` is used.
+    pub fn from_lookup<F: Fn(&str) -> Option<String>>(lookup: F) -> Result<Self> {
+        let lookup = |name: &str| lookup(name).filter(|v| !v.trim().is_empty());
+        let is_cgi = lookup("REQUEST_METHOD").is_some();
+        let get = |name: &str| {
+            lookup(name.to_ascii_lowercase().as_str()).or_else(|| match name {
+                "HTTP_PROXY" if is_cgi => None,
+                _ => lookup(name),
+            })
+        };
+        // Errors don't include the values, since they may contain credentials.
+        let parse_proxy = |name: &str| -> Result<Option<ProxyUrl>> {
+            get(name)
+                .map(|v| {
+                    v.parse().map_err(|_| {
+                        Error::InvalidArgument(format!("invalid proxy URL in {}", name))
+                    })
+                })
+                .transpose()
+        };
+
+        let all = parse_proxy("ALL_PROXY")?;
+        let no_proxy = match get("NO_PROXY") {
+            None => Vec::new(),
+            Some(v) => v
+                .split(',')
+                .filter(|rule| !rule.trim().is_empty())
+                .map(NoProxyRule::from_str)
+                .collect::<Result<_>>()?,
+        };
+        Ok(ProxyConfig {
+            http: parse_proxy("HTTP_PROXY")?.or_else(|| all.clone()),
+            https: parse_proxy("HTTPS_PROXY")?.or(all),
+            no_proxy,
+        })
+    }
+
+    /// Return the proxy the given URL should be requested through, or None if
+    /// it should be requested directly.
+    pub fn proxy_for(&self, url: &Url) -> Option<&ProxyUrl> {
+        let proxy = match url.scheme() {
+            "http" => self.http.as_ref(),
+            "https" => self.https.as_ref(),
+            _ => None,
+        }?;
+        let host = url.host()?;
+        match self.no_proxy.iter().any(|rule| rule.matches(&host)) {
+            true => None,
+            false => Some(proxy),
+        }
+    }
+}
@@ -13,14 +13,383 @@
 // limitations under the License.
 
 use crate::error::*;
-use crate::http::types::{HttpData, ResponseMetadata};
-use reqwest::Request;
+use crate::http::proxy::redact_proxy_url;
+use crate::http::types::{HeaderMap, HttpData, RequestTimings, ResponseMetadata};
+use data_encoding::BASE64;
+use regex::Regex;
+use reqwest::{Request, Url};
 use serde::{Deserialize, Serialize};
-use serde_json;
-use std::collections::{HashMap, VecDeque};
-use std::fs::File;
+use serde_json::{self, Value};
+use sha2::{Digest, Sha256, Sha512};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::fs::{self, File};
 use std::io::Write;
 use std::path::Path;
+use std::sync::Mutex;
+use std::thread::{self, ThreadId};
+
+/// The placeholder which replaces scrubbed values in recordings. When replaying
+/// a recording, a placeholder matches any actual value.
+pub const SCRUBBED_PLACEHOLDER: &str = "__SCRUBBED__";
+
+/// Request bodies (and streamed response bodies) larger than this are recorded
+/// as a digest plus length, instead of in their entirety.
+pub const RECORDED_BODY_DIGEST_THRESHOLD: u64 = 64 * 1024;
+
+/// The version of the on-disk recording format written by `Recording::flush`.
+/// Version 1 (a bare JSON array of entries) is still supported when loading.
+pub const RECORDING_FORMAT_VERSION: u32 = 2;
+
+/// The headers which are scrubbed from recordings by default.
+pub const DEFAULT_SCRUBBED_HEADERS: &[&str] =
+    &["authorization", "cookie", "set-cookie", "x-api-key"];
+
+thread_local! {
+    static STREAM_TAG: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Restores the previous stream tag when dropped, even if the closure passed
+/// to `with_stream_tag` panics.
+struct StreamTagGuard(Option<String>);
+
+impl Drop for StreamTagGuard {
+    fn drop(&mut self) {
+        let previous = self.0.take();
+        STREAM_TAG.with(|tag| *tag.borrow_mut() = previous);
+    }
+}
+
+/// Call the given function with the given stream tag set for the current
+/// thread. Requests recorded (or replayed) while the tag is set belong to the
+/// logical stream with that ID (see `RecordingEntry::stream_id`).
+///
+/// Code which issues requests concurrently should tag each thread's requests,
+/// so that replaying them doesn't depend on how the threads are scheduled.
+pub fn with_stream_tag<R, F: FnOnce() -> R>(tag: &str, f: F) -> R {
+    let previous = STREAM_TAG.with(|t| t.borrow_mut().replace(tag.to_owned()));
+    let _guard = StreamTagGuard(previous);
+    f()
+}
+
+/// Return the current thread's stream tag, if one was set with
+/// `with_stream_tag`.
+pub fn current_stream_tag() -> Option<String> {
+    STREAM_TAG.with(|tag| tag.borrow().clone())
+}
+
+/// StreamIds assigns each request a stream ID: the current thread's stream
+/// tag if it has one, or else an ID based on the order in which threads first
+/// made requests ("thread-0", "thread-1", ...). The latter is only stable if
+/// threads start making requests in a consistent order, so explicit tags are
+/// preferable.
+#[derive(Default)]
+pub(crate) struct StreamIds {
+    threads: Mutex<HashMap<ThreadId, usize>>,
+}
+
+impl StreamIds {
+    pub(crate) fn current(&self) -> String {
+        if let Some(tag) = current_stream_tag() {
+            return tag;
+        }
+        let mut threads = self.threads.lock().unwrap();
+        let next = threads.len();
+        format!(
+            "thread-{}",
+            threads.entry(thread::current().id()).or_insert(next)
+        )
+    }
+}
+
+fn scrub_json_value(value: &mut Value, path: &[&str]) {
+    let (first, rest) = match path.split_first() {
+        None => {
+            *value = Value::String(SCRUBBED_PLACEHOLDER.to_owned());
+            return;
+        }
+        Some(split) => split,
+    };
+
+    match value {
+        Value::Object(map) => {
+            if let Some(v) = map.get_mut(*first) {
+                scrub_json_value(v, rest);
+            }
+        }
+        // Paths apply to every element of an array.
+        Value::Array(values) => {
+            for v in values.iter_mut() {
+                scrub_json_value(v, path);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// ScrubConfig describes which sensitive values (e.g. credentials) should be
+/// removed from recordings before they are written out. Scrubbed values are
+/// replaced with `SCRUBBED_PLACEHOLDER`.
+///
+/// By default, the headers in `DEFAULT_SCRUBBED_HEADERS` are scrubbed. Use
+/// `ScrubConfig::none` to opt out of this.
+#[derive(Clone, Debug)]
+pub struct ScrubConfig {
+    headers: HashSet<String>,
+    json_fields: Vec<String>,
+    body_patterns: Vec<Regex>,
+    query_params: HashSet<String>,
+}
+
+impl Default for ScrubConfig {
+    fn default() -> Self {
+        let mut config = Self::none();
+        for header in DEFAULT_SCRUBBED_HEADERS {
+            config = config.scrub_header(header);
+        }
+        config
+    }
+}
+
+impl ScrubConfig {
+    /// Return a ScrubConfig which doesn't scrub anything, apart from proxy
+    /// credentials (which are always scrubbed).
+    pub fn none() -> Self {
+        ScrubConfig {
+            headers: HashSet::new(),
+            json_fields: Vec::new(),
+            body_patterns: Vec::new(),
+            query_params: HashSet::new(),
+        }
+    }
+
+    /// Scrub the value(s) of the given header. Header names are matched
+    /// case-insensitively.
+    pub fn scrub_header(mut self, name: &str) -> Self {
+        self.headers.insert(name.to_ascii_lowercase());
+        self
+    }
+
+    /// Scrub the given field from JSON request and response bodies. The path is a list of
+    /// object keys separated by '.' (e.g. "auth.token"). If an array is
+    /// encountered along the path, the rest of the path is applied to each of
+    /// its elements.
+    pub fn scrub_json_field(mut self, path: &str) -> Self {
+        self.json_fields.push(path.to_owned());
+        self
+    }
+
+    /// Scrub any matches of the given regular expression from non-JSON
+    /// request and response bodies.
+    pub fn scrub_body_pattern(mut self, pattern: &str) -> Result<Self> {
+        self.body_patterns.push(Regex::new(pattern)?);
+        Ok(self)
+    }
+
+    /// Scrub the value(s) of the given query parameter from request URLs.
+    pub fn scrub_query_param(mut self, name: &str) -> Self {
+        self.query_params.insert(name.to_owned());
+        self
+    }
+
+    fn scrub_url(&self, url: String) -> String {
+        let mut parsed = match Url::parse(url.as_str()) {
+            Err(_) => return url,
+            Ok(u) => u,
+        };
+        if parsed.query().is_none() {
+            return url;
+        }
+
+        let pairs: Vec<(String, String)> = parsed
+            .query_pairs()
+            .map(|(k, v)| match self.query_params.contains(k.as_ref()) {
+                false => (k.into_owned(), v.into_owned()),
+                true => (k.into_owned(), SCRUBBED_PLACEHOLDER.to_owned()),
+            })
+            .collect();
+        parsed.query_pairs_mut().clear().extend_pairs(pairs);
+        parsed.into()
+    }
+
+    fn scrub_body(&self, body: String) -> String {
+        if let Ok(mut value) = serde_json::from_str::<Value>(body.as_str()) {
+            if self.json_fields.is_empty() {
+                return body;
+            }
+            for path in self.json_fields.iter() {
+                let path: Vec<&str> = path.split('.').collect();
+                scrub_json_value(&mut value, path.as_slice());
+            }
+            return value.to_string();
+        }
+
+        let mut body = body;
+        for pattern in self.body_patterns.iter() {
+            body = pattern
+                .replace_all(body.as_str(), SCRUBBED_PLACEHOLDER)
+                .into_owned();
+        }
+        body
+    }
+
+    fn scrub_headers(&self, headers: &mut HeaderMap) {
+        for (name, values) in headers.iter_mut() {
+            if self.headers.contains(&name.to_ascii_lowercase()) {
+                for value in values.iter_mut() {
+                    *value = HttpData::Text(SCRUBBED_PLACEHOLDER.to_owned());
+                }
+            }
+        }
+    }
+
+    /// Scrub the given request according to this configuration.
+    pub fn scrub(&self, mut req: RecordedRequest) -> RecordedRequest {
+        self.scrub_headers(&mut req.headers);
+        if !self.query_params.is_empty() {
+            req.url = self.scrub_url(req.url);
+        }
+        req.body = req.body.map(|b| self.scrub_body(b));
+        req.proxy = req.proxy.map(|p| redact_proxy_url(p.as_str()));
+        req
+    }
+
+    /// Scrub the given response according to this configuration. The same
+    /// header, JSON field, and body pattern rules apply as for requests
+    /// (e.g., `set-cookie` is scrubbed by default). Binary bodies are left
+    /// as-is.
+    pub fn scrub_response(&self, mut res: RecordedResponse) -> RecordedResponse {
+        self.scrub_headers(&mut res.metadata.headers);
+        if let HttpData::Text(body) = res.body {
+            res.body = HttpData::Text(self.scrub_body(body));
+        }
+        res
+    }
+}
+
+fn is_placeholder(data: &HttpData) -> bool {
+    match data {
+        HttpData::Text(s) => s == SCRUBBED_PLACEHOLDER,
+        HttpData::Binary(_) => false,
+    }
+}
+
+fn json_matches(recorded: &Value, actual: &Value) -> bool {
+    match (recorded, actual) {
+        (Value::String(s), _) if s == SCRUBBED_PLACEHOLDER => true,
+        (Value::Array(r), Value::Array(a)) => {
+            r.len() == a.len() && r.iter().zip(a.iter()).all(|(r, a)| json_matches(r, a))
+        }
+        (Value::Object(r), Value::Object(a)) => {
+            r.len() == a.len()
+                && r.iter()
+                    .all(|(k, r)| a.get(k).is_some_and(|a| json_matches(r, a)))
+        }
+        _ => recorded == actual,
+    }
+}
+
+/// Returns whether `actual` matches `recorded`, where each placeholder in
+/// `recorded` matches any (possibly empty) substring.
+fn string_matches(recorded: &str, actual: &str) -> bool {
+    let mut parts = recorded.split(SCRUBBED_PLACEHOLDER);
+    let first = parts.next().unwrap_or("");
+    let mut rest = match actual.strip_prefix(first) {
+        None => return false,
+        Some(rest) => rest,
+    };
+
+    let parts: Vec<&str> = parts.collect();
+    let last = match parts.split_last() {
+        None => return rest.is_empty(),
+        Some((last, _)) => *last,
+    };
+    for part in &parts[..parts.len() - 1] {
+        rest = match rest.find(part) {
+            None => return false,
+            Some(idx) => &rest[idx + part.len()..],
+        };
+    }
+    rest.ends_with(last)
+}
+
+/// Returns whether or not the given actual request body matches the given
+/// recorded one. JSON bodies are compared structurally, and scrubbed values
+/// match anything.
+pub(crate) fn body_matches(recorded: &str, actual: &str) -> bool {
+    match (
+        serde_json::from_str::<Value>(recorded),
+        serde_json::from_str::<Value>(actual),
+    ) {
+        (Ok(r), Ok(a)) => json_matches(&r, &a),
+        _ => string_matches(recorded, actual),
+    }
+}
+
+fn url_matches(recorded: &str, actual: &str) -> bool {
+    let (recorded, actual) = match (Url::parse(recorded), Url::parse(actual)) {
+        (Ok(r), Ok(a)) => (r, a),
+        _ => return recorded == actual,
+    };
+
+    let mut recorded_base = recorded.clone();
+    recorded_base.set_query(None);
+    let mut actual_base = actual.clone();
+    actual_base.set_query(None);
+    if recorded_base != actual_base {
+        return false;
+    }
+
+    let recorded_pairs: Vec<_> = recorded.query_pairs().collect();
+    let actual_pairs: Vec<_> = actual.query_pairs().collect();
+    recorded_pairs.len() == actual_pairs.len()
+        && recorded_pairs
+            .iter()
+            .zip(actual_pairs.iter())
+            .all(|(r, a)| r.0 == a.0 && (r.1 == SCRUBBED_PLACEHOLDER || r.1 == a.1))
+}
+
+/// BodyCapture accumulates a request body as it is streamed, so it can be
+/// recorded. Only bodies up to `RECORDED_BODY_DIGEST_THRESHOLD` bytes are
+/// buffered; past that, only a digest of the body is kept.
+pub struct BodyCapture {
+    buffer: Option<Vec<u8>>,
+    hasher: Sha256,
+    len: u64,
+}
+
+impl Default for BodyCapture {
+    fn default() -> Self {
+        BodyCapture {
+            buffer: Some(Vec::new()),
+            hasher: Sha256::new(),
+            len: 0,
+        }
+    }
+}
+
+impl BodyCapture {
+    /// Add the given chunk of body data to this capture.
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+        self.len += data.len() as u64;
+        if self.len > RECORDED_BODY_DIGEST_THRESHOLD {
+            self.buffer = None;
+        } else if let Some(buffer) = self.buffer.as_mut() {
+            buffer.extend_from_slice(data);
+        }
+    }
+}
+
+/// BodyDigest identifies a request body which was too large to record in its
+/// entirety.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct BodyDigest {
+    /// The hex-encoded SHA-256 digest of the body.
+    pub sha256: String,
+    /// The length of the body, in bytes.
+    pub len: u64,
+}
 
 /// RecordedRequest represents a recorded HTTP request.
 #[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
@@ -33,6 +402,14 @@ pub struct RecordedRequest {
     pub headers: HashMap<String, Vec<HttpData>>,
     /// The request body (if any).
     pub body: Option<String>,
+    /// For large streamed bodies, a digest of the body (in which case `body`
+    /// is None).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body_digest: Option<BodyDigest>,
+    /// The URL of the proxy the request was sent through, if any. This is
+    /// informational, and isn't considered when matching requests.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
 }
 
 impl<'a> From<&'a Request> for RecordedRequest {
@@ -53,7 +430,154 @@ impl<'a> From<&'a Request> for RecordedRequest {
             method: req.method().to_string(),
             url: req.url().as_str().to_owned(),
             headers: headers,
-            body: req.body().map(|b| format!("{:?}", b)),
+            body: req.body().map(|b| match b.as_bytes() {
+                Some(bytes) => String::from_utf8_lossy(bytes).into_owned(),
+                None => format!("{:?}", b),
+            }),
+            body_digest: None,
+            proxy: None,
+        }
+    }
+}
+
+impl RecordedRequest {
+    /// Replace this request's body with the one captured while it was being
+    /// sent.
+    pub fn set_body_capture(&mut self, capture: BodyCapture) {
+        match capture.buffer {
+            Some(buffer) => {
+                self.body = Some(String::from_utf8_lossy(buffer.as_slice()).into_owned());
+                self.body_digest = None;
+            }
+            None => {
+                self.body = None;
+                self.body_digest = Some(BodyDigest {
+                    sha256: format!("{:x}", capture.hasher.finalize()),
+                    len: capture.len,
+                });
+            }
+        }
+    }
+
+    /// Returns whether or not the given actual request matches this recorded
+    /// request. Unlike `==`, any value in this request which was replaced with
+    /// `SCRUBBED_PLACEHOLDER` matches any actual value.
+    pub fn matches(&self, actual: &RecordedRequest) -> bool {
+        self.diff(actual).is_empty()
+    }
+
+    /// Describe each field of the given actual request which doesn't match
+    /// this recorded request (as per `matches`), one line per field, with the
+    /// expected and actual values. The result is empty if the requests match.
+    pub fn diff(&self, actual: &RecordedRequest) -> Vec<String> {
+        let mut diff = Vec::new();
+        if self.method != actual.method {
+            diff.push(format!(
+                "method: expected {}, actual {}",
+                self.method, actual.method
+            ));
+        }
+        if !url_matches(&self.url, &actual.url) {
+            diff.push(format!("url: expected {}, actual {}", self.url, actual.url));
+        }
+
+        let names: BTreeSet<&String> = self.headers.keys().chain(actual.headers.keys()).collect();
+        for name in names {
+            let (values, actual_values) = (self.headers.get(name), actual.headers.get(name));
+            let matches = match (values, actual_values) {
+                (Some(r), Some(a)) => {
+                    r.len() == a.len()
+                        && r.iter()
+                            .zip(a.iter())
+                            .all(|(r, a)| is_placeholder(r) || r == a)
+                }
+                _ => false,
+            };
+            if !matches {
+                diff.push(format!(
+                    "header {}: expected {:?}, actual {:?}",
+                    name, values, actual_values
+                ));
+            }
+        }
+
+        if self.body_digest != actual.body_digest {
+            diff.push(format!(
+                "body digest: expected {:?}, actual {:?}",
+                self.body_digest, actual.body_digest
+            ));
+        }
+        let body_matches = match (self.body.as_ref(), actual.body.as_ref()) {
+            (None, None) => true,
+            (Some(r), Some(a)) => body_matches(r, a),
+            _ => false,
+        };
+        if !body_matches {
+            diff.push(format!(
+                "body: expected {:?}, actual {:?}",
+                self.body, actual.body
+            ));
+        }
+        diff
+    }
+}
+
+/// ResponseDigest identifies a streamed response body which was too large to
+/// record in its entirety. The digest is SHA-512, the same as
+/// `crypto::digest::Digest`, so replayed downloads can still be verified.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ResponseDigest {
+    /// The hex-encoded SHA-512 digest of the body.
+    pub sha512: String,
+    /// The length of the body, in bytes.
+    pub len: u64,
+}
+
+/// ResponseCapture accumulates a response body as it is streamed, so it can be
+/// recorded. As with `BodyCapture`, only bodies up to
+/// `RECORDED_BODY_DIGEST_THRESHOLD` bytes are buffered; past that, only a
+/// digest of the body is kept.
+pub struct ResponseCapture {
+    buffer: Option<Vec<u8>>,
+    hasher: Sha512,
+    len: u64,
+}
+
+impl Default for ResponseCapture {
+    fn default() -> Self {
+        ResponseCapture {
+            buffer: Some(Vec::new()),
+            hasher: Sha512::new(),
+            len: 0,
+        }
+    }
+}
+
+impl ResponseCapture {
+    /// Add the given chunk of body data to this capture.
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+        self.len += data.len() as u64;
+        if self.len > RECORDED_BODY_DIGEST_THRESHOLD {
+            self.buffer = None;
+        } else if let Some(buffer) = self.buffer.as_mut() {
+            buffer.extend_from_slice(data);
+        }
+    }
+
+    /// Build the RecordedResponse for the captured body, with the given
+    /// metadata.
+    pub fn into_response(self, metadata: ResponseMetadata) -> RecordedResponse {
+        match self.buffer {
+            Some(buffer) => RecordedResponse::from(&(metadata, buffer)),
+            None => RecordedResponse {
+                metadata,
+                body: HttpData::Binary(Vec::new()),
+                body_digest: Some(ResponseDigest {
+                    sha512: format!("{:x}", self.hasher.finalize()),
+                    len: self.len,
+                }),
+            },
         }
     }
 }
@@ -65,6 +589,11 @@ pub struct RecordedResponse {
     pub metadata: ResponseMetadata,
     /// The response body.
     pub body: HttpData,
+    /// For large streamed bodies, a digest of the body (in which case `body`
+    /// is empty). When replayed, the body is empty, but its digest is still
+    /// reported by `AbstractClient::execute_digested`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body_digest: Option<ResponseDigest>,
 }
 
 impl<'a> From<&'a (ResponseMetadata, Vec<u8>)> for RecordedResponse {
@@ -72,6 +601,7 @@ impl<'a> From<&'a (ResponseMetadata, Vec<u8>)> for RecordedResponse {
         RecordedResponse {
             metadata: res.0.clone(),
             body: HttpData::from(res.1.as_slice()),
+            body_digest: None,
         }
     }
 }
@@ -84,19 +614,212 @@ pub struct RecordingEntry {
     pub req: RecordedRequest,
     /// The matching response
     pub res: RecordedResponse,
+    /// The logical stream this interaction belongs to, if known. When
+    /// replaying concurrent requests, interactions in the same stream are
+    /// matched in order, but different streams may be interleaved arbitrarily.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream_id: Option<String>,
+}
+
+/// HTTP data, as stored in the on-disk recording format: UTF-8 data is inlined
+/// as a string, so it can be read (and reviewed) easily, and anything else is
+/// base64 encoded.
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum StoredData {
+    Text(String),
+    Binary { base64: String },
+}
+
+impl From<&HttpData> for StoredData {
+    fn from(data: &HttpData) -> Self {
+        match data {
+            HttpData::Text(s) => StoredData::Text(s.clone()),
+            HttpData::Binary(b) => StoredData::Binary {
+                base64: BASE64.encode(b.as_slice()),
+            },
+        }
+    }
+}
+
+impl StoredData {
+    fn into_data(self) -> Result<HttpData> {
+        Ok(match self {
+            StoredData::Text(s) => HttpData::Text(s),
+            StoredData::Binary { base64 } => HttpData::Binary(BASE64.decode(base64.as_bytes())?),
+        })
+    }
+}
+
+type StoredHeaders = BTreeMap<String, Vec<StoredData>>;
+
+fn store_headers(headers: &HeaderMap) -> StoredHeaders {
+    headers
+        .iter()
+        .map(|(name, values)| (name.clone(), values.iter().map(StoredData::from).collect()))
+        .collect()
+}
+
+fn load_headers(headers: StoredHeaders) -> Result<HeaderMap> {
+    headers
+        .into_iter()
+        .map(|(name, values)| {
+            let values: Result<Vec<HttpData>> =
+                values.into_iter().map(StoredData::into_data).collect();
+            Ok((name, values?))
+        })
+        .collect()
+}
+
+#[derive(Deserialize, Serialize)]
+struct StoredRequest {
+    method: String,
+    url: String,
+    headers: StoredHeaders,
+    body: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    body_digest: Option<BodyDigest>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    proxy: Option<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct StoredResponse {
+    status: u16,
+    headers: StoredHeaders,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    from_cache: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timings: Option<RequestTimings>,
+    body: StoredData,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    body_digest: Option<ResponseDigest>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct StoredEntry {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    stream_id: Option<String>,
+    request: StoredRequest,
+    response: StoredResponse,
+}
+
+/// The on-disk recording format. Headers are kept in sorted maps, so the same
+/// recording is always written out identically, which keeps diffs of recorded
+/// fixtures readable.
+#[derive(Deserialize, Serialize)]
+struct StoredRecording {
+    version: u32,
+    entries: Vec<StoredEntry>,
+}
+
+impl From<&RecordingEntry> for StoredEntry {
+    fn from(entry: &RecordingEntry) -> Self {
+        StoredEntry {
+            stream_id: entry.stream_id.clone(),
+            request: StoredRequest {
+                method: entry.req.method.clone(),
+                url: entry.req.url.clone(),
+                headers: store_headers(&entry.req.headers),
+                body: entry.req.body.clone(),
+                body_digest: entry.req.body_digest.clone(),
+                proxy: entry.req.proxy.clone(),
+            },
+            response: StoredResponse {
+                status: entry.res.metadata.status,
+                headers: store_headers(&entry.res.metadata.headers),
+                from_cache: entry.res.metadata.from_cache,
+                timings: entry.res.metadata.timings.clone(),
+                body: StoredData::from(&entry.res.body),
+                body_digest: entry.res.body_digest.clone(),
+            },
+        }
+    }
+}
+
+impl StoredEntry {
+    fn into_entry(self) -> Result<RecordingEntry> {
+        Ok(RecordingEntry {
+            req: RecordedRequest {
+                method: self.request.method,
+                url: self.request.url,
+                headers: load_headers(self.request.headers)?,
+                body: self.request.body,
+                body_digest: self.request.body_digest,
+                proxy: self.request.proxy,
+            },
+            res: RecordedResponse {
+                metadata: ResponseMetadata {
+                    status: self.response.status,
+                    headers: load_headers(self.response.headers)?,
+                    from_cache: self.response.from_cache,
+                    timings: self.response.timings,
+                },
+                body: self.response.body.into_data()?,
+                body_digest: self.response.body_digest,
+            },
+            stream_id: self.stream_id,
+        })
+    }
 }
 
 /// A Recording is a series of RecordingEntry objects, representing an entire
 /// HTTP session.
+///
+/// Note that Recording's `Serialize` / `Deserialize` implementations use the
+/// legacy (version 1) format. Use `to_vec` / `load_slice` for the current
+/// on-disk format.
 #[derive(Deserialize, Serialize)]
 pub struct Recording(pub VecDeque<RecordingEntry>);
 
 impl Recording {
+    /// Serialize this Recording in the current on-disk format (see
+    /// `RECORDING_FORMAT_VERSION`): pretty-printed JSON, with stable key
+    /// ordering, and with bodies inlined as text where possible.
+    pub fn to_vec(&self) -> Result<Vec<u8>> {
+        let stored = StoredRecording {
+            version: RECORDING_FORMAT_VERSION,
+            entries: self.0.iter().map(StoredEntry::from).collect(),
+        };
+        let mut data = serde_json::to_vec_pretty(&stored)?;
+        data.push(b'\n');
+        Ok(data)
+    }
+
+    /// Load a Recording from the given serialized bytes, in either the current
+    /// on-disk format, or the legacy (version 1) format.
+    pub fn load_slice(data: &[u8]) -> Result<Self> {
+        let value: Value = serde_json::from_slice(data)?;
+        if value.is_array() {
+            return Ok(serde_json::from_value(value)?);
+        }
+
+        let stored: StoredRecording = serde_json::from_value(value)?;
+        if stored.version != RECORDING_FORMAT_VERSION {
+            return Err(Error::Unsupported(format!(
+                "unsupported recording format version {}",
+                stored.version
+            )));
+        }
+        let entries: Result<VecDeque<RecordingEntry>> = stored
+            .entries
+            .into_iter()
+            .map(StoredEntry::into_entry)
+            .collect();
+        Ok(Recording(entries?))
+    }
+
+    /// Load a Recording from the given file, in either the current on-disk
+    /// format or the legacy one.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::load_slice(fs::read(path)?.as_slice())
+    }
+
     /// flush serializes the entire Recording, and writes it out to the given
     /// file on disk (e.g. so it can be loaded and replayed later).
     pub fn flush<P: AsRef<Path>>(&self, output: P) -> Result<()> {
         let mut f = File::create(output)?;
-        serde_json::to_writer_pretty(&mut f, self)?;
+        f.write_all(self.to_vec()?.as_slice())?;
         f.flush()?;
         Ok(())
     }
@@ -107,3 +830,121 @@ impl Default for Recording {
         Recording(VecDeque::new())
     }
 }
+
+fn data_len(data: &HttpData) -> u64 {
+    match data {
+        HttpData::Text(s) => s.len() as u64,
+        HttpData::Binary(b) => b.len() as u64,
+    }
+}
+
+/// RecordingSummary describes the contents of a recording file, e.g. so it can
+/// be reviewed without reading the whole thing.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RecordingSummary {
+    /// The format version the file was written in.
+    pub format_version: u32,
+    /// The number of request / response pairs in the recording.
+    pub interactions: usize,
+    /// The number of requests made with each HTTP method.
+    pub methods: BTreeMap<String, usize>,
+    /// Every distinct URL requested.
+    pub urls: BTreeSet<String>,
+    /// The total size of all request and response bodies, in bytes.
+    pub body_bytes: u64,
+    /// The number of scrubbed values in each part of the recorded requests
+    /// and responses, keyed by e.g. "header authorization", "url", "body", or
+    /// "response header set-cookie". Reviewers can
+    /// use this to check that everything which should have been scrubbed was.
+    pub scrubbed: BTreeMap<String, usize>,
+}
+
+/// Summarize the recording stored in the given file.
+pub fn inspect<P: AsRef<Path>>(path: P) -> Result<RecordingSummary> {
+    let data = fs::read(path)?;
+    let recording = Recording::load_slice(data.as_slice())?;
+    let mut summary = RecordingSummary {
+        format_version: match serde_json::from_slice::<Value>(data.as_slice())?.is_array() {
+            true => 1,
+            false => RECORDING_FORMAT_VERSION,
+        },
+        interactions: recording.0.len(),
+        ..Default::default()
+    };
+
+    for entry in recording.0.iter() {
+        *summary.methods.entry(entry.req.method.clone()).or_insert(0) += 1;
+        summary.urls.insert(entry.req.url.clone());
+        summary.body_bytes += match (entry.req.body.as_ref(), entry.req.body_digest.as_ref()) {
+            (Some(body), _) => body.len() as u64,
+            (None, Some(digest)) => digest.len,
+            (None, None) => 0,
+        };
+        summary.body_bytes += match entry.res.body_digest.as_ref() {
+            Some(digest) => digest.len,
+            None => data_len(&entry.res.body),
+        };
+
+        let mut scrubbed = |location: String, count: usize| {
+            if count > 0 {
+                *summary.scrubbed.entry(location).or_insert(0) += count;
+            }
+        };
+        for (name, values) in entry.req.headers.iter() {
+            scrubbed(
+                format!("header {}", name.to_ascii_lowercase()),
+                values.iter().filter(|v| is_placeholder(v)).count(),
+            );
+        }
+        scrubbed(
+            "url".to_string(),
+            entry.req.url.matches(SCRUBBED_PLACEHOLDER).count(),
+        );
+        if let Some(body) = entry.req.body.as_ref() {
+            scrubbed(
+                "body".to_string(),
+                body.matches(SCRUBBED_PLACEHOLDER).count(),
+            );
+        }
+        for (name, values) in entry.res.metadata.headers.iter() {
+            scrubbed(
+                format!("response header {}", name.to_ascii_lowercase()),
+                values.iter().filter(|v| is_placeholder(v)).count(),
+            );
+        }
+        if let HttpData::Text(body) = &entry.res.body {
+            scrubbed(
+                "response body".to_string(),
+                body.matches(SCRUBBED_PLACEHOLDER).count(),
+            );
+        }
+    }
+
+    Ok(summary)
+}
+
+/// Rewrite the recording stored in the given file, by passing each of its
+/// entries through the given function. Entries for which it returns None are
+/// dropped. The result is written back in the current on-disk format.
+///
+/// This is useful e.g. to scrub values from an existing recording, or to
+/// upgrade it from an older format (with a function which returns each entry
+/// unmodified).
+pub fn rewrite<P: AsRef<Path>, F: FnMut(RecordingEntry) -> Option<RecordingEntry>>(
+    path: P,
+    f: F,
+) -> Result<()> {
+    let recording = Recording::load(path.as_ref())?;
+    Recording(recording.0.into_iter().filter_map(f).collect()).flush(path)
+}
+
+/// Concatenate the recordings stored in the given files (in order) into a
+/// single recording, which is written to `output` in the current on-disk
+/// format.
+pub fn merge<P: AsRef<Path>, Q: AsRef<Path>>(paths: &[P], output: Q) -> Result<()> {
+    let mut merged = Recording::default();
+    for path in paths {
+        merged.0.extend(Recording::load(path)?.0);
+    }
+    merged.flush(output)
+}
@@ -13,10 +13,15 @@
 // limitations under the License.
 
 use crate::error::*;
-use reqwest::header::HeaderValue;
-use reqwest::{Response, StatusCode};
+use crate::http::body::RequestBody;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use reqwest::header::{HeaderValue, CONTENT_TYPE};
+use reqwest::{Request, Response, StatusCode};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::io::Read;
+use std::time::Duration;
 
 /// HTTP data, which is either valid UTF-8 or is treated as binary.
 ///
@@ -42,6 +47,14 @@ impl HttpData {
             HttpData::Binary(b) => String::from_utf8(b)?,
         })
     }
+
+    /// Convert this data into raw bytes, regardless of its representation.
+    pub fn into_bytes(self) -> Vec<u8> {
+        match self {
+            HttpData::Text(s) => s.into_bytes(),
+            HttpData::Binary(b) => b,
+        }
+    }
 }
 
 // We can't just derive PartialEq, because we want to treat structures which
@@ -88,6 +101,11 @@ pub struct ResponseMetadata {
     // Stored as u16 to allow serialization.
     pub(crate) status: u16,
     pub(crate) headers: HeaderMap,
+    // Set by `CachingClient` for responses served from its cache.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) from_cache: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) timings: Option<RequestTimings>,
 }
 
 impl ResponseMetadata {
@@ -106,6 +124,80 @@ impl ResponseMetadata {
     pub fn get_headers(&self) -> &HashMap<String, Vec<HttpData>> {
         &self.headers
     }
+
+    /// Returns whether or not this response was served from a cache (after
+    /// the server confirmed it was still valid), rather than being sent by
+    /// the server in its entirety.
+    pub fn is_from_cache(&self) -> bool {
+        self.from_cache
+    }
+
+    /// Returns the timing breakdown of the request which produced this
+    /// response, if it's known.
+    pub fn get_timings(&self) -> Option<&RequestTimings> {
+        self.timings.as_ref()
+    }
+
+    /// Attach the given timing breakdown to this response.
+    pub fn set_timings(&mut self, timings: Option<RequestTimings>) {
+        self.timings = timings;
+    }
+}
+
+/// RequestTimings is a breakdown of how long the phases of a single HTTP
+/// request took. Phases the underlying transport doesn't report are `None`;
+/// in particular, reqwest doesn't expose DNS, connection, or TLS timings, nor
+/// whether a pooled connection was reused, so the standard `Client` only ever
+/// reports `first_byte` and `total`. Timings are stored in recordings, so
+/// replayed sessions report the same timings every time.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct RequestTimings {
+    /// How long it took to resolve the server's address.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dns: Option<Duration>,
+    /// How long it took to establish a new connection. This is `None` if an
+    /// existing connection was reused (or if it isn't known).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connect: Option<Duration>,
+    /// How long the TLS handshake took, if one was performed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls: Option<Duration>,
+    /// How long it took from sending the request until the response headers
+    /// were received.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub first_byte: Option<Duration>,
+    /// How long the whole request took, including reading the response body.
+    pub total: Duration,
+    /// Whether or not the request was sent on an existing (pooled)
+    /// connection, if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connection_reused: Option<bool>,
+}
+
+/// ApiResult is the result of a JSON API call (see
+/// `AbstractClient::execute_json`) which reached the server: either the
+/// successful response, or the error body the server sent back instead.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ApiResult<T, E> {
+    /// The server responded with a successful (2xx) status.
+    Ok(T),
+    /// The server responded with an unsuccessful status.
+    Err {
+        /// The response's status code.
+        status: u16,
+        /// The deserialized error body.
+        error: E,
+    },
+}
+
+impl<T, E> ApiResult<T, E> {
+    /// Convert this into a standard `Result`, discarding the status code.
+    pub fn into_result(self) -> std::result::Result<T, E> {
+        match self {
+            ApiResult::Ok(value) => Ok(value),
+            ApiResult::Err { error, .. } => Err(error),
+        }
+    }
 }
 
 impl<'a> From<&'a Response> for ResponseMetadata {
@@ -125,6 +217,166 @@ impl<'a> From<&'a Response> for ResponseMetadata {
         ResponseMetadata {
             status: res.status().as_u16(),
             headers: headers,
+            from_cache: false,
+            timings: None,
         }
     }
 }
+
+/// The number of random characters in a multipart boundary.
+const MULTIPART_BOUNDARY_RANDOM_CHARS: usize = 32;
+
+/// Escape a field name or filename for use in a quoted Content-Disposition
+/// parameter. Per RFC 7578 (and what browsers do), quotes and line breaks are
+/// percent-encoded.
+fn escape_multipart_param(value: &str) -> String {
+    value
+        .replace('"', "%22")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+/// Generate a random multipart boundary.
+pub(crate) fn random_multipart_boundary<R: Rng>(rng: &mut R) -> String {
+    let random: String = rng
+        .sample_iter(&Alphanumeric)
+        .take(MULTIPART_BOUNDARY_RANDOM_CHARS)
+        .map(char::from)
+        .collect();
+    format!("bdrck-{}", random)
+}
+
+struct MultipartPart {
+    headers: String,
+    data: Vec<u8>,
+}
+
+/// MultipartBuilder assembles a `multipart/form-data` request body (RFC
+/// 7578), e.g. for uploading files along with some metadata fields.
+#[derive(Default)]
+pub struct MultipartBuilder {
+    parts: Vec<MultipartPart>,
+}
+
+impl MultipartBuilder {
+    /// Construct a new builder, with no fields.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a simple text field with the given name and value.
+    pub fn text_field(mut self, name: &str, value: &str) -> Self {
+        self.parts.push(MultipartPart {
+            headers: format!(
+                "Content-Disposition: form-data; name=\"{}\"\r\n",
+                escape_multipart_param(name)
+            ),
+            data: value.as_bytes().to_vec(),
+        });
+        self
+    }
+
+    /// Add a file field with the given name, filename, and contents.
+    pub fn file_field<D: Into<Vec<u8>>>(
+        mut self,
+        name: &str,
+        filename: &str,
+        content_type: &str,
+        data: D,
+    ) -> Self {
+        self.parts.push(MultipartPart {
+            headers: format!(
+                "Content-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\nContent-Type: {}\r\n",
+                escape_multipart_param(name),
+                escape_multipart_param(filename),
+                content_type.replace(['\r', '\n'], "")
+            ),
+            data: data.into(),
+        });
+        self
+    }
+
+    /// Add a file field like `file_field`, reading its contents from the given
+    /// reader. The contents are buffered in memory.
+    pub fn file_field_from_reader<R: Read>(
+        self,
+        name: &str,
+        filename: &str,
+        content_type: &str,
+        mut reader: R,
+    ) -> Result<Self> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        Ok(self.file_field(name, filename, content_type, data))
+    }
+
+    /// Assemble the body, with a randomly generated boundary.
+    pub fn build(self) -> Multipart {
+        self.build_with_rng(&mut thread_rng())
+    }
+
+    /// Identical to `build`, but the boundary is generated using the given
+    /// RNG.
+    pub(crate) fn build_with_rng<R: Rng>(self, rng: &mut R) -> Multipart {
+        // The boundary must not appear anywhere in the body, so if it happens
+        // to, just pick another one.
+        let boundary = loop {
+            let boundary = random_multipart_boundary(rng);
+            let collides = self.parts.iter().any(|part| {
+                part.headers.contains(boundary.as_str())
+                    || part
+                        .data
+                        .windows(boundary.len())
+                        .any(|w| w == boundary.as_bytes())
+            });
+            if !collides {
+                break boundary;
+            }
+        };
+
+        let mut body = Vec::new();
+        for part in self.parts {
+            body.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+            body.extend_from_slice(part.headers.as_bytes());
+            body.extend_from_slice(b"\r\n");
+            body.extend_from_slice(part.data.as_slice());
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+
+        Multipart { boundary, body }
+    }
+}
+
+/// Multipart is an assembled `multipart/form-data` body, produced by
+/// `MultipartBuilder`.
+pub struct Multipart {
+    boundary: String,
+    body: Vec<u8>,
+}
+
+impl Multipart {
+    /// Return the boundary which separates the parts of this body.
+    pub fn boundary(&self) -> &str {
+        self.boundary.as_str()
+    }
+
+    /// Return the value the request's Content-Type header must be set to.
+    pub fn content_type(&self) -> String {
+        format!("multipart/form-data; boundary={}", self.boundary)
+    }
+
+    /// Return the raw bytes of this body.
+    pub fn as_bytes(&self) -> &[u8] {
+        self.body.as_slice()
+    }
+
+    /// Set the Content-Type header on the given request, and return the body
+    /// to send with it (e.g. with `AbstractClient::execute_body`).
+    pub fn apply(self, request: &mut Request) -> RequestBody {
+        // Our boundaries are always alphanumeric, so this can't fail.
+        let content_type = HeaderValue::from_str(self.content_type().as_str()).unwrap();
+        request.headers_mut().insert(CONTENT_TYPE, content_type);
+        RequestBody::from_bytes(self.body)
+    }
+}
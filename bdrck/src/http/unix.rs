@@ -0,0 +1,371 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::error::*;
+use crate::http::types::{HeaderMap, HttpData, ResponseMetadata};
+use reqwest::header::{CONNECTION, CONTENT_LENGTH, HOST};
+use reqwest::{Method, Request, Url};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+/// The longest status or header line we'll accept in a response.
+const MAX_LINE_BYTES: u64 = 64 * 1024;
+
+fn invalid_response<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> Error {
+    io::Error::new(io::ErrorKind::InvalidData, e).into()
+}
+
+/// Split a `unix:` URL into the path of the socket, and the path (and query)
+/// to request from the server listening on it.
+pub(crate) fn split_unix_url(url: &Url) -> Result<(PathBuf, String)> {
+    let (socket, path) = url.path().split_once(":/").ok_or_else(|| {
+        Error::InvalidArgument(format!(
+            "'{}' is not of the form unix:///path/to/socket:/request/path",
+            url
+        ))
+    })?;
+    let target = match url.query() {
+        None => format!("/{}", path),
+        Some(query) => format!("/{}?{}", path, query),
+    };
+    Ok((PathBuf::from(socket), target))
+}
+
+/// Return the path (and query) of the given URL, as used in a request line.
+pub(crate) fn request_target(url: &Url) -> String {
+    match url.query() {
+        None => url.path().to_string(),
+        Some(query) => format!("{}?{}", url.path(), query),
+    }
+}
+
+fn connect_blocking(socket: &Path) -> Result<UnixStream> {
+    UnixStream::connect(socket).map_err(|e| {
+        // Keep the original kind, so e.g. a missing socket file is
+        // distinguishable from the server refusing the connection.
+        let reason = match e.kind() {
+            io::ErrorKind::NotFound => "socket file does not exist".to_string(),
+            io::ErrorKind::PermissionDenied => "permission denied".to_string(),
+            io::ErrorKind::ConnectionRefused => "connection refused".to_string(),
+            _ => e.to_string(),
+        };
+        io::Error::new(
+            e.kind(),
+            format!("connecting to '{}' failed: {}", socket.display(), reason),
+        )
+        .into()
+    })
+}
+
+/// Connect to the given socket, giving up with `Error::NetTimeout` if that
+/// hasn't succeeded by the given deadline (if any). The standard library has
+/// no way to connect to a Unix domain socket with a timeout, so this connects
+/// on a separate thread, which is abandoned if it doesn't finish in time.
+fn connect(socket: &Path, deadline: Option<Instant>, timeout: Duration) -> Result<UnixStream> {
+    let deadline = match deadline {
+        None => return connect_blocking(socket),
+        Some(deadline) => deadline,
+    };
+    let (tx, rx) = mpsc::channel();
+    let path = socket.to_path_buf();
+    std::thread::spawn(move || {
+        // If we've given up waiting, there's no one to send this to.
+        let _ = tx.send(connect_blocking(&path));
+    });
+    match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+        Ok(result) => result,
+        Err(mpsc::RecvTimeoutError::Timeout) => Err(Error::NetTimeout(format!(
+            "connecting to '{}' took longer than {:?}",
+            socket.display(),
+            timeout
+        ))),
+        Err(mpsc::RecvTimeoutError::Disconnected) => Err(Error::Internal(format!(
+            "connecting to '{}' failed unexpectedly",
+            socket.display()
+        ))),
+    }
+}
+
+/// DeadlineStream wraps a connected socket, limiting each read or write to
+/// the time remaining before a deadline (if any). Once the deadline has
+/// passed, every operation fails with an `io::ErrorKind::TimedOut` error.
+struct DeadlineStream<'s> {
+    stream: &'s UnixStream,
+    deadline: Option<Instant>,
+}
+
+impl DeadlineStream<'_> {
+    fn remaining(&self) -> io::Result<Option<Duration>> {
+        match self.deadline {
+            None => Ok(None),
+            Some(deadline) => match deadline.saturating_duration_since(Instant::now()) {
+                remaining if remaining.is_zero() => Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "the request's deadline has passed",
+                )),
+                remaining => Ok(Some(remaining)),
+            },
+        }
+    }
+}
+
+impl Read for DeadlineStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.set_read_timeout(self.remaining()?)?;
+        self.stream.read(buf)
+    }
+}
+
+impl Write for DeadlineStream<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.set_write_timeout(self.remaining()?)?;
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+fn write_request<W: Write>(mut writer: W, request: &Request, target: &str) -> Result<()> {
+    let body: &[u8] = match request.body() {
+        None => &[],
+        Some(body) => body.as_bytes().ok_or_else(|| {
+            Error::Unsupported(
+                "streaming request bodies can't be sent over a Unix domain socket".to_string(),
+            )
+        })?,
+    };
+
+    let mut head = format!("{} {} HTTP/1.1\r\n", request.method(), target).into_bytes();
+    let headers = request.headers();
+    if !headers.contains_key(HOST) {
+        head.extend_from_slice(b"host: localhost\r\n");
+    }
+    for (name, value) in headers {
+        if name == CONTENT_LENGTH || name == CONNECTION {
+            continue;
+        }
+        head.extend_from_slice(name.as_str().as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(value.as_bytes());
+        head.extend_from_slice(b"\r\n");
+    }
+    if request.body().is_some() {
+        head.extend_from_slice(format!("content-length: {}\r\n", body.len()).as_bytes());
+    }
+    // We don't pool connections, so just let the server know we're done.
+    head.extend_from_slice(b"connection: close\r\n\r\n");
+
+    writer.write_all(&head)?;
+    writer.write_all(body)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Read a single CRLF- (or LF-) terminated line, without its terminator.
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Vec<u8>> {
+    let mut line = Vec::new();
+    reader.take(MAX_LINE_BYTES).read_until(b'\n', &mut line)?;
+    if line.last() != Some(&b'\n') {
+        return Err(invalid_response(
+            "HTTP response ended unexpectedly, or has an overlong line",
+        ));
+    }
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(line)
+}
+
+fn read_status<R: BufRead>(reader: &mut R) -> Result<u16> {
+    let line = read_line(reader)?;
+    let line = String::from_utf8_lossy(&line);
+    let mut parts = line.splitn(3, ' ');
+    match (parts.next(), parts.next().map(str::parse::<u16>)) {
+        (Some(version), Some(Ok(status))) if version.starts_with("HTTP/1.") => Ok(status),
+        _ => Err(invalid_response(format!(
+            "invalid HTTP status line '{}'",
+            line
+        ))),
+    }
+}
+
+fn read_headers<R: BufRead>(reader: &mut R) -> Result<HeaderMap> {
+    let mut headers = HeaderMap::new();
+    loop {
+        let line = read_line(reader)?;
+        if line.is_empty() {
+            return Ok(headers);
+        }
+        let colon = line.iter().position(|&b| b == b':').ok_or_else(|| {
+            invalid_response(format!(
+                "invalid HTTP header line '{}'",
+                String::from_utf8_lossy(&line)
+            ))
+        })?;
+        let name = String::from_utf8_lossy(&line[..colon])
+            .trim()
+            .to_ascii_lowercase();
+        let value = HttpData::from(line[colon + 1..].trim_ascii());
+        headers.entry(name).or_default().push(value);
+    }
+}
+
+fn header_text<'h>(headers: &'h HeaderMap, name: &str) -> Option<&'h str> {
+    match headers.get(name)?.last()? {
+        HttpData::Text(text) => Some(text.as_str()),
+        HttpData::Binary(_) => None,
+    }
+}
+
+fn read_chunked_body<R: BufRead>(reader: &mut R) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let line = read_line(reader)?;
+        let line = String::from_utf8_lossy(&line);
+        // Ignore any chunk extensions.
+        let size = line.split(';').next().unwrap_or("").trim();
+        let size = u64::from_str_radix(size, 16)
+            .map_err(|_| invalid_response(format!("invalid HTTP chunk size '{}'", size)))?;
+        if size == 0 {
+            break;
+        }
+        let start = body.len();
+        reader.take(size).read_to_end(&mut body)?;
+        if (body.len() - start) as u64 != size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "HTTP response ended in the middle of a chunk",
+            )
+            .into());
+        }
+        if !read_line(reader)?.is_empty() {
+            return Err(invalid_response("HTTP chunk is longer than its size"));
+        }
+    }
+    // Skip any trailers.
+    while !read_line(reader)?.is_empty() {}
+    Ok(body)
+}
+
+fn read_body<R: BufRead>(
+    reader: &mut R,
+    method: &Method,
+    status: u16,
+    headers: &HeaderMap,
+) -> Result<Vec<u8>> {
+    if *method == Method::HEAD || (100..200).contains(&status) || status == 204 || status == 304 {
+        return Ok(Vec::new());
+    }
+    if header_text(headers, "transfer-encoding")
+        .is_some_and(|te| te.to_ascii_lowercase().contains("chunked"))
+    {
+        return read_chunked_body(reader);
+    }
+
+    let mut body = Vec::new();
+    match header_text(headers, "content-length") {
+        Some(len) => {
+            let len: u64 = len
+                .trim()
+                .parse()
+                .map_err(|_| invalid_response(format!("invalid content-length '{}'", len)))?;
+            reader.take(len).read_to_end(&mut body)?;
+            if body.len() as u64 != len {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "HTTP response body is shorter than its content-length",
+                )
+                .into());
+            }
+        }
+        // Without either, the body extends until the server closes the
+        // connection.
+        None => {
+            reader.read_to_end(&mut body)?;
+        }
+    }
+    Ok(body)
+}
+
+/// Send the given request to the server listening on the given Unix domain
+/// socket, using a minimal HTTP/1.1 implementation, and return its response.
+/// Only the path and query of the request's URL are used.
+///
+/// If the request has a timeout, it limits the whole exchange (connecting,
+/// sending the request, and reading the response), and exceeding it is
+/// reported as `Error::NetTimeout`.
+pub(crate) fn execute(
+    socket: &Path,
+    request: &Request,
+    target: &str,
+) -> Result<(ResponseMetadata, Vec<u8>)> {
+    let timeout = request.timeout().copied();
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let timeout = timeout.unwrap_or_default();
+
+    let stream = connect(socket, deadline, timeout)?;
+    let stream = DeadlineStream {
+        stream: &stream,
+        deadline,
+    };
+    exchange(stream, request, target).map_err(|e| match e {
+        // Depending on the platform, a socket timing out is reported as
+        // either of these kinds.
+        Error::Io(e)
+            if deadline.is_some()
+                && (e.kind() == io::ErrorKind::TimedOut
+                    || e.kind() == io::ErrorKind::WouldBlock) =>
+        {
+            Error::NetTimeout(format!(
+                "no complete response from '{}' within {:?}",
+                socket.display(),
+                timeout
+            ))
+        }
+        e => e,
+    })
+}
+
+fn exchange(
+    mut stream: DeadlineStream<'_>,
+    request: &Request,
+    target: &str,
+) -> Result<(ResponseMetadata, Vec<u8>)> {
+    write_request(&mut stream, request, target)?;
+
+    let mut reader = BufReader::new(stream);
+    let mut status = read_status(&mut reader)?;
+    let mut headers = read_headers(&mut reader)?;
+    // Skip any interim responses (e.g. "100 Continue").
+    while (100..200).contains(&status) {
+        status = read_status(&mut reader)?;
+        headers = read_headers(&mut reader)?;
+    }
+    let body = read_body(&mut reader, request.method(), status, &headers)?;
+
+    Ok((
+        ResponseMetadata {
+            status,
+            headers,
+            from_cache: false,
+            timings: None,
+        },
+        body,
+    ))
+}
@@ -13,7 +13,9 @@
 // limitations under the License.
 
 use crate::error::*;
-use std::io::{self, Read};
+use std::fs::File;
+use std::io::{self, BufRead, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
 
 /// Reads from the givne `Read` until the buffer is filled. If EOF is reached
 /// first, this is fine. If we hit EOF exactly when the buffer is filled, that's
@@ -95,3 +97,446 @@ pub fn read_at_most<R: Read>(r: &mut R, maximum_bytes: usize) -> Result<Vec<u8>>
     buf.truncate(bytes_read);
     Ok(buf)
 }
+
+/// Utf8Mode controls how `LineReader` deals with lines which aren't valid
+/// UTF-8.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Utf8Mode {
+    /// Lines which aren't valid UTF-8 are an error.
+    #[default]
+    Strict,
+    /// Invalid UTF-8 sequences are replaced with U+FFFD.
+    Lossy,
+    /// Lines aren't decoded at all; they are returned as raw bytes.
+    Bytes,
+}
+
+/// A single line returned by `LineReader`, without its line ending.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Line {
+    /// A line decoded as UTF-8 (with `Utf8Mode::Strict` or `Utf8Mode::Lossy`).
+    Text(String),
+    /// A raw line (with `Utf8Mode::Bytes`).
+    Bytes(Vec<u8>),
+}
+
+impl Line {
+    /// Return this line's contents as raw bytes.
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Line::Text(s) => s.as_bytes(),
+            Line::Bytes(b) => b.as_slice(),
+        }
+    }
+
+    /// Return this line's contents as a string, if it was decoded as UTF-8.
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Line::Text(s) => Some(s.as_str()),
+            Line::Bytes(_) => None,
+        }
+    }
+
+    /// Consume this line, returning its contents as raw bytes.
+    pub fn into_bytes(self) -> Vec<u8> {
+        match self {
+            Line::Text(s) => s.into_bytes(),
+            Line::Bytes(b) => b,
+        }
+    }
+}
+
+/// LineReader reads lines from a `BufRead`, like `BufRead::lines`, but is
+/// suitable for untrusted input: the length of each line can be bounded, and
+/// the handling of invalid UTF-8 is configurable.
+///
+/// Lines are terminated by `\n` or `\r\n`, and the terminator is not included
+/// in the returned lines. A bare `\r` is not a line terminator, and is
+/// returned as part of the line.
+///
+/// Errors (other than I/O errors) include the (1-based) number of the line
+/// which caused them. After such an error, the reader skips to the start of
+/// the next line, so iteration can continue.
+pub struct LineReader<R: BufRead> {
+    inner: R,
+    max_line_len: Option<usize>,
+    utf8_mode: Utf8Mode,
+    line_number: usize,
+}
+
+impl<R: BufRead> LineReader<R> {
+    /// Construct a new LineReader with no maximum line length, which requires
+    /// lines to be valid UTF-8.
+    pub fn new(inner: R) -> Self {
+        LineReader {
+            inner,
+            max_line_len: None,
+            utf8_mode: Utf8Mode::default(),
+            line_number: 0,
+        }
+    }
+
+    /// Set the maximum length of a line in bytes (not including its line
+    /// ending). Longer lines result in an `Error::InputTooBig`; they are never
+    /// buffered in their entirety.
+    pub fn max_line_len(mut self, max_line_len: usize) -> Self {
+        self.max_line_len = Some(max_line_len);
+        self
+    }
+
+    /// Set how lines which aren't valid UTF-8 are dealt with.
+    pub fn utf8_mode(mut self, utf8_mode: Utf8Mode) -> Self {
+        self.utf8_mode = utf8_mode;
+        self
+    }
+
+    /// Return the number of the line most recently returned (or which caused
+    /// an error), starting from 1. Before anything has been read, this is 0.
+    pub fn line_number(&self) -> usize {
+        self.line_number
+    }
+
+    /// Return the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Read the next line's raw bytes, up to and including its `\n` (if any).
+    /// Returns the bytes, and whether or not the line was too long (in which
+    /// case the bytes are truncated). Returns None at EOF.
+    fn read_raw_line(&mut self) -> io::Result<Option<(Vec<u8>, bool)>> {
+        // Allow an extra byte, in case the line ends with "\r\n".
+        let limit = self.max_line_len.map(|max| max.saturating_add(1));
+        let mut line = Vec::new();
+        let mut too_long = false;
+        let mut read_any = false;
+        loop {
+            let available = match self.inner.fill_buf() {
+                Ok(b) => b,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if available.is_empty() {
+                break;
+            }
+            read_any = true;
+
+            let (chunk, done) = match available.iter().position(|&b| b == b'\n') {
+                Some(i) => (&available[..i], true),
+                None => (available, false),
+            };
+            if !too_long {
+                line.extend_from_slice(chunk);
+                if limit.is_some_and(|limit| line.len() > limit) {
+                    too_long = true;
+                    line.clear();
+                }
+            }
+
+            let consumed = chunk.len() + done as usize;
+            self.inner.consume(consumed);
+            if done {
+                break;
+            }
+        }
+
+        if !read_any {
+            return Ok(None);
+        }
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        if let Some(max) = self.max_line_len {
+            too_long = too_long || line.len() > max;
+        }
+        Ok(Some((line, too_long)))
+    }
+}
+
+impl<R: BufRead> Iterator for LineReader<R> {
+    type Item = Result<Line>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (line, too_long) = match self.read_raw_line() {
+            Err(e) => return Some(Err(e.into())),
+            Ok(None) => return None,
+            Ok(Some(l)) => l,
+        };
+        self.line_number += 1;
+
+        if too_long {
+            return Some(Err(Error::InputTooBig(format!(
+                "line {} is longer than {} bytes",
+                self.line_number,
+                self.max_line_len.unwrap()
+            ))));
+        }
+
+        Some(match self.utf8_mode {
+            Utf8Mode::Strict => String::from_utf8(line).map(Line::Text).map_err(|e| {
+                Error::InvalidArgument(format!(
+                    "line {} is not valid UTF-8: {}",
+                    self.line_number, e
+                ))
+            }),
+            Utf8Mode::Lossy => Ok(Line::Text(String::from_utf8_lossy(&line).into_owned())),
+            Utf8Mode::Bytes => Ok(Line::Bytes(line)),
+        })
+    }
+}
+
+/// The default size of the buffer `copy_ctl` copies data through.
+pub const DEFAULT_COPY_BUFFER_BYTES: usize = 64 * 1024;
+
+/// Options which control the behavior of `copy_ctl`.
+pub struct CopyOptions<'a> {
+    /// If set, stop after copying at most this many bytes.
+    pub max_bytes: Option<u64>,
+    /// If set, this is called with the number of bytes copied since the last
+    /// call, roughly every `progress_interval` bytes, and once more at the
+    /// end for any remainder. The counts passed to it always add up to the
+    /// total number of bytes copied.
+    pub progress: Option<&'a mut dyn FnMut(u64)>,
+    /// How many bytes to copy between calls to `progress`. If zero, it is
+    /// called after every write.
+    pub progress_interval: u64,
+    /// If set, this flag is checked between writes, and the copy is stopped
+    /// as soon as it is set.
+    pub cancel: Option<&'a AtomicBool>,
+    /// The size of the buffer data is copied through.
+    pub buffer_size: usize,
+}
+
+impl<'a> Default for CopyOptions<'a> {
+    fn default() -> Self {
+        CopyOptions {
+            max_bytes: None,
+            progress: None,
+            progress_interval: 0,
+            cancel: None,
+            buffer_size: DEFAULT_COPY_BUFFER_BYTES,
+        }
+    }
+}
+
+/// The result of a `copy_ctl` call.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CopyOutcome {
+    /// The number of bytes which were copied.
+    pub bytes_copied: u64,
+    /// Whether or not the entire input was copied (i.e., we reached EOF).
+    pub completed: bool,
+    /// Whether or not the copy was stopped early because it was cancelled.
+    pub cancelled: bool,
+}
+
+/// CopyState tracks a copy's progress, so both the generic and the
+/// `copy_file_range` implementations can share the bookkeeping.
+struct CopyState<'o, 'a> {
+    options: &'o mut CopyOptions<'a>,
+    outcome: CopyOutcome,
+    unreported: u64,
+}
+
+impl<'o, 'a> CopyState<'o, 'a> {
+    fn new(options: &'o mut CopyOptions<'a>) -> Self {
+        CopyState {
+            options,
+            outcome: CopyOutcome::default(),
+            unreported: 0,
+        }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.options
+            .cancel
+            .is_some_and(|cancel| cancel.load(Ordering::SeqCst))
+    }
+
+    /// Return how many more bytes we're allowed to copy, capped at `limit`.
+    fn remaining(&self, limit: u64) -> u64 {
+        match self.options.max_bytes {
+            None => limit,
+            Some(max) => (max - self.outcome.bytes_copied).min(limit),
+        }
+    }
+
+    fn record(&mut self, n: u64) {
+        self.outcome.bytes_copied += n;
+        self.unreported += n;
+        if self.unreported >= self.options.progress_interval {
+            self.report();
+        }
+    }
+
+    fn report(&mut self) {
+        if self.unreported > 0 {
+            if let Some(progress) = self.options.progress.as_mut() {
+                progress(self.unreported);
+            }
+            self.unreported = 0;
+        }
+    }
+
+    fn finish(mut self, completed: bool, cancelled: bool) -> CopyOutcome {
+        self.report();
+        self.outcome.completed = completed;
+        self.outcome.cancelled = cancelled;
+        self.outcome
+    }
+}
+
+fn copy_ctl_impl<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    mut state: CopyState<'_, '_>,
+) -> Result<CopyOutcome> {
+    let mut buf = vec![0; state.options.buffer_size.max(1)];
+    loop {
+        if state.is_cancelled() {
+            writer.flush()?;
+            return Ok(state.finish(false, true));
+        }
+
+        let want = state.remaining(buf.len() as u64) as usize;
+        if want == 0 {
+            // We've hit the limit. Check whether there's any more input (see
+            // the caveat in `copy_ctl`'s documentation).
+            let completed = loop {
+                match reader.read(&mut buf[..1]) {
+                    Ok(n) => break n == 0,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e.into()),
+                }
+            };
+            writer.flush()?;
+            return Ok(state.finish(completed, false));
+        }
+
+        let n = match reader.read(&mut buf[..want]) {
+            Ok(0) => {
+                writer.flush()?;
+                return Ok(state.finish(true, false));
+            }
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        writer.write_all(&buf[..n])?;
+        state.record(n as u64);
+    }
+}
+
+/// Copy all of the data from the given reader to the given writer, like
+/// `std::io::copy`, but with the additional controls described by
+/// `CopyOptions`: a limit on how much data to copy, progress reporting, and
+/// cancellation.
+///
+/// Stopping at `max_bytes` isn't an error; the returned outcome just isn't
+/// `completed`. Note that, like `read_at_most_into`, if exactly `max_bytes`
+/// are copied we have to read one extra byte to find out whether we reached
+/// EOF, which is then discarded.
+pub fn copy_ctl<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    mut options: CopyOptions<'_>,
+) -> Result<CopyOutcome> {
+    copy_ctl_impl(reader, writer, CopyState::new(&mut options))
+}
+
+/// Try to copy from `src` to `dst` with `copy_file_range`, which lets the
+/// kernel copy the data without passing it through userspace (or even share
+/// the underlying blocks, on some filesystems). Returns whether the copy was
+/// completed or cancelled, or `None` if `copy_file_range` isn't supported for
+/// these files or didn't copy anything (in which case no data was copied).
+#[cfg(target_os = "linux")]
+fn copy_file_range_impl(
+    src: &mut File,
+    dst: &mut File,
+    state: &mut CopyState<'_, '_>,
+) -> Result<Option<(bool, bool)>> {
+    use std::io::Seek;
+    use std::os::unix::io::AsRawFd;
+
+    // Copy in large chunks, but not so large that we don't check for
+    // cancellation or report progress regularly.
+    let chunk = (state.options.buffer_size.max(1) as u64).max(1024 * 1024);
+    loop {
+        if state.is_cancelled() {
+            return Ok(Some((false, true)));
+        }
+        let want = state.remaining(chunk);
+        if want == 0 {
+            // Unlike the generic implementation, we can check for EOF without
+            // consuming any input.
+            let position = src.stream_position()?;
+            return Ok(Some((position >= src.metadata()?.len(), false)));
+        }
+
+        let ret = unsafe {
+            libc::copy_file_range(
+                src.as_raw_fd(),
+                std::ptr::null_mut(),
+                dst.as_raw_fd(),
+                std::ptr::null_mut(),
+                want as usize,
+                0,
+            )
+        };
+        if ret == 0 {
+            // Some files (e.g. in procfs or sysfs) report EOF immediately,
+            // even though reading them produces data, so if we haven't
+            // copied anything yet let the generic implementation decide.
+            return Ok(match state.outcome.bytes_copied {
+                0 => None,
+                _ => Some((true, false)),
+            });
+        } else if ret > 0 {
+            state.record(ret as u64);
+            continue;
+        }
+
+        let error = io::Error::last_os_error();
+        match error.raw_os_error() {
+            Some(libc::EINTR) => {}
+            // These indicate copy_file_range isn't supported at all, or not
+            // for these particular files (e.g. they're on different
+            // filesystems, on older kernels).
+            Some(libc::ENOSYS)
+            | Some(libc::EXDEV)
+            | Some(libc::EINVAL)
+            | Some(libc::EOPNOTSUPP)
+            | Some(libc::EPERM)
+                if state.outcome.bytes_copied == 0 =>
+            {
+                return Ok(None)
+            }
+            _ => return Err(error.into()),
+        }
+    }
+}
+
+/// This is identical to `copy_ctl`, except it copies between two files. On
+/// Linux, this uses `copy_file_range` if `fast_path` is true and the kernel
+/// supports it for these files, transparently falling back to the generic
+/// implementation otherwise.
+pub fn copy_file_ctl(
+    src: &mut File,
+    dst: &mut File,
+    mut options: CopyOptions<'_>,
+    fast_path: bool,
+) -> Result<CopyOutcome> {
+    #[cfg(target_os = "linux")]
+    {
+        if fast_path {
+            let mut state = CopyState::new(&mut options);
+            if let Some((completed, cancelled)) = copy_file_range_impl(src, dst, &mut state)? {
+                return Ok(state.finish(completed, cancelled));
+            }
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = fast_path;
+
+    copy_ctl_impl(src, dst, CopyState::new(&mut options))
+}
@@ -57,10 +57,16 @@ pub mod io;
 /// available in std.
 #[cfg(feature = "net")]
 pub mod net;
+/// proc provides utilities for running child processes.
+#[cfg(feature = "proc")]
+pub mod proc;
 /// testing provides utilities which are useful for unit testing real production
-/// code.
-#[cfg(feature = "testing")]
+/// code. Most of this module requires the "testing" feature.
 pub mod testing;
+// width computes how many terminal columns text occupies, for laying out
+// user-facing output.
+#[cfg(any(feature = "cli", feature = "fs"))]
+mod width;
 
 // Tests have significantly more dependencies than the code being tested. Don't
 // bother running tests unless all features are enabled.
@@ -71,6 +77,7 @@ pub mod testing;
     feature = "fs",
     feature = "http",
     feature = "net",
+    feature = "proc",
     feature = "testing"
 ))]
 #[cfg(test)]
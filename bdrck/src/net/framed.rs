@@ -0,0 +1,229 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::error::*;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+/// The default maximum line length `LineChannel` accepts, in bytes, not
+/// including the terminator.
+pub const DEFAULT_MAX_LINE_LENGTH: usize = 8 * 1024;
+
+/// ReadTimeout is implemented by streams whose reads can be given a timeout,
+/// so `LineChannel` can bound how long it waits for a line.
+pub trait ReadTimeout {
+    /// Returns the stream's current read timeout, if any.
+    fn read_timeout(&self) -> io::Result<Option<Duration>>;
+
+    /// Set (or clear) the stream's read timeout.
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl ReadTimeout for TcpStream {
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        TcpStream::read_timeout(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+#[cfg(unix)]
+impl ReadTimeout for std::os::unix::net::UnixStream {
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        std::os::unix::net::UnixStream::read_timeout(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        std::os::unix::net::UnixStream::set_read_timeout(self, timeout)
+    }
+}
+
+/// LineTerminator selects which terminator `LineChannel` appends to the lines
+/// it writes. Either terminator is accepted when reading.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum LineTerminator {
+    /// "\r\n", as used by most Internet text protocols.
+    #[default]
+    CrLf,
+    /// A bare "\n".
+    Lf,
+}
+
+impl LineTerminator {
+    fn as_str(&self) -> &'static str {
+        match self {
+            LineTerminator::CrLf => "\r\n",
+            LineTerminator::Lf => "\n",
+        }
+    }
+}
+
+/// LineChannel wraps a stream (e.g. a `TcpStream` or `UnixStream`), reading
+/// and writing newline-terminated lines of UTF-8 text.
+///
+/// If reading a line fails (e.g. because it timed out or was too long), any
+/// partially read line is discarded, so the caller should generally treat the
+/// channel as broken.
+#[derive(Debug)]
+pub struct LineChannel<S: Read + Write + ReadTimeout> {
+    stream: S,
+    buffer: Vec<u8>,
+    max_length: usize,
+    terminator: LineTerminator,
+}
+
+impl<S: Read + Write + ReadTimeout> LineChannel<S> {
+    /// Construct a new channel wrapping the given stream, which accepts lines
+    /// of up to `DEFAULT_MAX_LINE_LENGTH` bytes and writes CRLF-terminated
+    /// lines.
+    pub fn new(stream: S) -> Self {
+        LineChannel {
+            stream,
+            buffer: Vec::new(),
+            max_length: DEFAULT_MAX_LINE_LENGTH,
+            terminator: LineTerminator::default(),
+        }
+    }
+
+    /// Set the maximum length (in bytes, not including the terminator) of
+    /// lines this channel will read.
+    pub fn with_max_length(mut self, max_length: usize) -> Self {
+        self.max_length = max_length;
+        self
+    }
+
+    /// Set the terminator this channel appends to the lines it writes.
+    pub fn with_terminator(mut self, terminator: LineTerminator) -> Self {
+        self.terminator = terminator;
+        self
+    }
+
+    /// Returns a reference to the underlying stream.
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Return the underlying stream. Note that any input which was read from
+    /// the stream but not yet returned as a line is lost.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    // Remove and return the first complete line in our buffer, if any.
+    fn take_line(&mut self) -> Result<Option<String>> {
+        let end = match self.buffer.iter().position(|&b| b == b'\n') {
+            None => return Ok(None),
+            Some(end) => end,
+        };
+        let mut line: Vec<u8> = self.buffer.drain(..=end).collect();
+        line.pop();
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        if line.len() > self.max_length {
+            return Err(self.too_big());
+        }
+        Ok(Some(String::from_utf8(line)?))
+    }
+
+    fn too_big(&mut self) -> Error {
+        self.buffer.clear();
+        Error::InputTooBig(format!(
+            "line exceeds the maximum length of {} bytes",
+            self.max_length
+        ))
+    }
+
+    fn read_line_impl(&mut self, timeout: Duration) -> Result<String> {
+        let deadline = Instant::now() + timeout;
+        let mut chunk = [0_u8; 1024];
+        loop {
+            if let Some(line) = self.take_line()? {
+                return Ok(line);
+            }
+            // Allow for a "\r\n" terminator we haven't fully read yet.
+            if self.buffer.len() > self.max_length + 1 {
+                return Err(self.too_big());
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                self.buffer.clear();
+                return Err(Error::NetTimeout(format!(
+                    "no complete line was received within {:?}",
+                    timeout
+                )));
+            }
+            self.stream.set_read_timeout(Some(remaining))?;
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    self.buffer.clear();
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "connection closed before a complete line was received",
+                    )
+                    .into());
+                }
+                Ok(n) => self.buffer.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                // Depending on the platform, a read timing out is reported as
+                // either of these kinds; the next iteration notices the
+                // deadline has passed.
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Read the next line from the stream, without its terminator, waiting at
+    /// most the given amount of time for it to arrive. A timeout is reported as
+    /// `Error::NetTimeout`, the peer closing the connection mid-line as an
+    /// `io::ErrorKind::UnexpectedEof` error, and a line longer than the
+    /// maximum length as `Error::InputTooBig`.
+    ///
+    /// The stream's existing read timeout (if any) is restored afterwards.
+    pub fn read_line(&mut self, timeout: Duration) -> Result<String> {
+        let original_timeout = self.stream.read_timeout()?;
+        let ret = self.read_line_impl(timeout);
+        self.stream.set_read_timeout(original_timeout)?;
+        ret
+    }
+
+    /// Write the given line to the stream, followed by this channel's
+    /// terminator, and flush it.
+    pub fn write_line(&mut self, line: &str) -> Result<()> {
+        if line.contains('\n') {
+            return Err(Error::InvalidArgument(format!(
+                "line {:?} must not contain a newline",
+                line
+            )));
+        }
+        self.stream.write_all(line.as_bytes())?;
+        self.stream.write_all(self.terminator.as_str().as_bytes())?;
+        self.stream.flush()?;
+        Ok(())
+    }
+
+    /// Write the given request line, and then read a single response line as
+    /// per `read_line`.
+    pub fn request(&mut self, line: &str, timeout: Duration) -> Result<String> {
+        self.write_line(line)?;
+        self.read_line(timeout)
+    }
+}
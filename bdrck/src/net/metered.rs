@@ -0,0 +1,367 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::net::framed::ReadTimeout;
+use crate::testing::clock::{Clock, SystemClock};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The default window `MeteredStream` computes its moving average transfer
+/// rates over.
+pub const DEFAULT_RATE_WINDOW: Duration = Duration::from_secs(5);
+
+/// The number of buckets a rate window is divided into. Bytes are counted
+/// per bucket, so the moving average advances in steps of `window / BUCKETS`.
+const BUCKETS: u64 = 10;
+
+static GLOBAL_BYTES_IN: AtomicU64 = AtomicU64::new(0);
+static GLOBAL_BYTES_OUT: AtomicU64 = AtomicU64::new(0);
+
+/// Totals is a count of the bytes transferred in each direction.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Totals {
+    /// The number of bytes read.
+    pub bytes_in: u64,
+    /// The number of bytes written.
+    pub bytes_out: u64,
+}
+
+/// Return the total number of bytes transferred by every `MeteredStream`
+/// which opted in with `with_global_accounting`, over the life of the
+/// process.
+pub fn global_totals() -> Totals {
+    Totals {
+        bytes_in: GLOBAL_BYTES_IN.load(Ordering::Relaxed),
+        bytes_out: GLOBAL_BYTES_OUT.load(Ordering::Relaxed),
+    }
+}
+
+/// ShutdownStream is implemented by streams which can be shut down (in one or
+/// both directions) without being dropped, so `MeteredStream` can pass this
+/// through.
+pub trait ShutdownStream {
+    /// Shut down the read half, write half, or both halves of this stream.
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+}
+
+impl ShutdownStream for TcpStream {
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+}
+
+#[cfg(unix)]
+impl ShutdownStream for std::os::unix::net::UnixStream {
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        std::os::unix::net::UnixStream::shutdown(self, how)
+    }
+}
+
+/// RateLimit describes a token bucket limit on the rate of a transfer.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RateLimit {
+    /// The sustained rate allowed, in bytes per second.
+    pub bytes_per_second: u64,
+    /// The most bytes which can be transferred in a single burst, after the
+    /// stream has been idle.
+    pub burst: u64,
+}
+
+impl RateLimit {
+    /// Construct a new limit allowing the given number of bytes per second,
+    /// with a burst of up to one second's worth of data.
+    pub fn new(bytes_per_second: u64) -> Self {
+        RateLimit {
+            bytes_per_second,
+            burst: bytes_per_second,
+        }
+    }
+
+    /// Set the most bytes which can be transferred in a single burst.
+    pub fn with_burst(mut self, burst: u64) -> Self {
+        self.burst = burst;
+        self
+    }
+}
+
+struct TokenBucket {
+    // Both of these are clamped to at least 1, so we always make progress.
+    bytes_per_second: f64,
+    burst: f64,
+    tokens: f64,
+    last_refill: Option<Instant>,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit) -> Self {
+        let burst = limit.burst.max(1) as f64;
+        TokenBucket {
+            bytes_per_second: limit.bytes_per_second.max(1) as f64,
+            burst,
+            tokens: burst,
+            last_refill: None,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if let Some(last) = self.last_refill {
+            let elapsed = now.saturating_duration_since(last).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * self.bytes_per_second).min(self.burst);
+        }
+        self.last_refill = Some(now);
+    }
+
+    /// Wait until at least one byte may be transferred, and then return how
+    /// many (up to `want`) may be transferred right now.
+    ///
+    /// We never wait for more than a single byte's worth of tokens. Waiting
+    /// for the whole buffer could wait forever if it's larger than the burst
+    /// size, and for reads it would delay data which is already available
+    /// (e.g. a short line) for no reason.
+    fn acquire(&mut self, clock: &dyn Clock, want: usize) -> usize {
+        if want == 0 {
+            return 0;
+        }
+        loop {
+            self.refill(clock.now_instant());
+            if self.tokens >= 1.0 {
+                return want.min(self.tokens as usize);
+            }
+            let wait = (1.0 - self.tokens) / self.bytes_per_second;
+            clock.sleep(Duration::from_secs_f64(wait).max(Duration::from_nanos(1)));
+        }
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.tokens -= n as f64;
+    }
+}
+
+/// Meter counts the bytes transferred in one direction, both in total and in
+/// timestamped buckets covering the most recent rate window.
+#[derive(Default)]
+struct Meter {
+    total: u64,
+    // (bucket index, bytes) pairs, oldest first.
+    buckets: VecDeque<(u64, u64)>,
+}
+
+impl Meter {
+    fn expire(&mut self, current: u64) {
+        while let Some(&(index, _)) = self.buckets.front() {
+            if index + BUCKETS > current {
+                break;
+            }
+            self.buckets.pop_front();
+        }
+    }
+
+    fn record(&mut self, current: u64, n: u64) {
+        self.total += n;
+        self.expire(current);
+        match self.buckets.back_mut() {
+            Some((index, bytes)) if *index == current => *bytes += n,
+            _ => self.buckets.push_back((current, n)),
+        }
+    }
+
+    fn sum(&mut self, current: u64) -> u64 {
+        self.expire(current);
+        self.buckets.iter().map(|&(_, bytes)| bytes).sum()
+    }
+}
+
+/// MeteredStream wraps a stream (e.g. a `TcpStream`), counting the bytes
+/// read from and written to it, and optionally limiting the rate at which
+/// data is transferred in either direction.
+///
+/// Transfer rates are reported as a moving average over a window (by default
+/// `DEFAULT_RATE_WINDOW`), as if the stream had been idle before it was
+/// constructed.
+///
+/// Rate limiting is done by sleeping the calling thread until enough time has
+/// passed. Individual reads and writes may be shortened to stay within the
+/// limit (which `write_all`, `read_exact`, and friends already handle), but a
+/// read or write is never delayed waiting for more than a single byte's worth
+/// of allowance, so this is safe to use with line-based protocols.
+pub struct MeteredStream<S: Read + Write> {
+    stream: S,
+    clock: Arc<dyn Clock>,
+    epoch: Option<Instant>,
+    bucket_width: Duration,
+    meter_in: Meter,
+    meter_out: Meter,
+    limit_in: Option<TokenBucket>,
+    limit_out: Option<TokenBucket>,
+    global_accounting: bool,
+}
+
+impl<S: Read + Write> MeteredStream<S> {
+    /// Construct a new MeteredStream wrapping the given stream, with no rate
+    /// limits.
+    pub fn new(stream: S) -> Self {
+        MeteredStream {
+            stream,
+            clock: Arc::new(SystemClock),
+            epoch: None,
+            bucket_width: DEFAULT_RATE_WINDOW / BUCKETS as u32,
+            meter_in: Meter::default(),
+            meter_out: Meter::default(),
+            limit_in: None,
+            limit_out: None,
+            global_accounting: false,
+        }
+    }
+
+    /// Use the given clock to measure transfer rates and to wait for rate
+    /// limits, instead of the system clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Set the window over which `rate_in` and `rate_out` are averaged.
+    pub fn with_window(mut self, window: Duration) -> Self {
+        self.bucket_width = (window / BUCKETS as u32).max(Duration::from_nanos(1));
+        self
+    }
+
+    /// Limit the rate at which data is read from the stream.
+    pub fn with_limit_in(mut self, limit: RateLimit) -> Self {
+        self.limit_in = Some(TokenBucket::new(limit));
+        self
+    }
+
+    /// Limit the rate at which data is written to the stream.
+    pub fn with_limit_out(mut self, limit: RateLimit) -> Self {
+        self.limit_out = Some(TokenBucket::new(limit));
+        self
+    }
+
+    /// Also count this stream's transfers in the process-wide totals returned
+    /// by `global_totals`.
+    pub fn with_global_accounting(mut self) -> Self {
+        self.global_accounting = true;
+        self
+    }
+
+    /// Returns a reference to the underlying stream.
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Returns a mutable reference to the underlying stream. Data transferred
+    /// directly through this reference isn't counted.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Return the underlying stream.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    /// Return the total number of bytes transferred in each direction.
+    pub fn totals(&self) -> Totals {
+        Totals {
+            bytes_in: self.meter_in.total,
+            bytes_out: self.meter_out.total,
+        }
+    }
+
+    /// Return the average rate at which data has been read, in bytes per
+    /// second, over the most recent window.
+    pub fn rate_in(&mut self) -> f64 {
+        let current = self.current_bucket();
+        self.meter_in.sum(current) as f64 / self.window().as_secs_f64()
+    }
+
+    /// Return the average rate at which data has been written, in bytes per
+    /// second, over the most recent window.
+    pub fn rate_out(&mut self) -> f64 {
+        let current = self.current_bucket();
+        self.meter_out.sum(current) as f64 / self.window().as_secs_f64()
+    }
+
+    fn window(&self) -> Duration {
+        self.bucket_width * BUCKETS as u32
+    }
+
+    fn current_bucket(&mut self) -> u64 {
+        let now = self.clock.now_instant();
+        let epoch = *self.epoch.get_or_insert(now);
+        (now.saturating_duration_since(epoch).as_nanos() / self.bucket_width.as_nanos()) as u64
+    }
+}
+
+impl<S: Read + Write> Read for MeteredStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = match self.limit_in.as_mut() {
+            None => buf.len(),
+            Some(limit) => limit.acquire(self.clock.as_ref(), buf.len()),
+        };
+        let n = self.stream.read(&mut buf[..len])?;
+        if let Some(limit) = self.limit_in.as_mut() {
+            limit.consume(n);
+        }
+        let current = self.current_bucket();
+        self.meter_in.record(current, n as u64);
+        if self.global_accounting {
+            GLOBAL_BYTES_IN.fetch_add(n as u64, Ordering::Relaxed);
+        }
+        Ok(n)
+    }
+}
+
+impl<S: Read + Write> Write for MeteredStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = match self.limit_out.as_mut() {
+            None => buf.len(),
+            Some(limit) => limit.acquire(self.clock.as_ref(), buf.len()),
+        };
+        let n = self.stream.write(&buf[..len])?;
+        if let Some(limit) = self.limit_out.as_mut() {
+            limit.consume(n);
+        }
+        let current = self.current_bucket();
+        self.meter_out.record(current, n as u64);
+        if self.global_accounting {
+            GLOBAL_BYTES_OUT.fetch_add(n as u64, Ordering::Relaxed);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl<S: Read + Write + ReadTimeout> ReadTimeout for MeteredStream<S> {
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        self.stream.read_timeout()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+}
+
+impl<S: Read + Write + ShutdownStream> ShutdownStream for MeteredStream<S> {
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.stream.shutdown(how)
+    }
+}
@@ -0,0 +1,1465 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// framed provides helpers for simple line-based protocols, e.g. control
+/// sockets or test doubles for text-based services.
+pub mod framed;
+/// metered provides a stream wrapper which measures (and can limit) the rate
+/// at which data is transferred over a connection.
+pub mod metered;
+/// probe provides ping-like reachability checks, which use ordinary TCP or UDP
+/// sockets instead of raw ICMP sockets.
+pub mod probe;
+/// unix provides conveniences for listening on and connecting to Unix domain
+/// sockets.
+#[cfg(unix)]
+pub mod unix;
+
+use crate::error::*;
+use data_encoding::HEXLOWER_PERMISSIVE;
+use serde::de::{Deserialize, Deserializer, Unexpected, Visitor};
+use serde::ser::{Serialize, Serializer};
+use std::cmp::Ordering;
+use std::fmt;
+use std::io;
+use std::marker::PhantomData;
+use std::net::{
+    IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket,
+};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+struct ParseableVisitor<T: FromStr<Err = Error>> {
+    phantom: PhantomData<T>,
+}
+
+impl<'de, T: FromStr<Err = Error>> Visitor<'de> for ParseableVisitor<T> {
+    type Value = T;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a parseable value")
+    }
+
+    fn visit_str<E: ::serde::de::Error>(self, v: &str) -> ::std::result::Result<Self::Value, E> {
+        match v.parse::<T>() {
+            Err(e) => Err(E::invalid_value(
+                Unexpected::Str(v),
+                &e.to_string().as_str(),
+            )),
+            Ok(addr) => Ok(addr),
+        }
+    }
+}
+
+impl<T: FromStr<Err = Error>> Default for ParseableVisitor<T> {
+    fn default() -> Self {
+        ParseableVisitor {
+            phantom: PhantomData,
+        }
+    }
+}
+
+fn increment_ip_bytes(bytes: &mut [u8]) {
+    for byte in bytes.iter_mut().rev() {
+        match byte.checked_add(1) {
+            None => *byte = 0,
+            Some(new_byte) => {
+                *byte = new_byte;
+                return;
+            }
+        }
+    }
+}
+
+/// Returns the IP address which immediately follows the given IP address. If
+/// the increment overflowed (i.e., the given input IP was already the largest
+/// possible IP address), None is returned instead.
+pub fn increment_ip(ip: IpAddr) -> Option<IpAddr> {
+    match ip {
+        IpAddr::V4(ip) => {
+            let mut bytes: [u8; 4] = ip.octets();
+            increment_ip_bytes(&mut bytes);
+            if bytes.iter().fold(true, |acc, byte| acc && *byte == 0) {
+                return None;
+            }
+            Some(Ipv4Addr::from(bytes).into())
+        }
+        IpAddr::V6(ip) => {
+            let mut bytes: [u8; 16] = ip.octets();
+            increment_ip_bytes(&mut bytes);
+            if bytes.iter().fold(true, |acc, byte| acc && *byte == 0) {
+                return None;
+            }
+            Some(Ipv6Addr::from(bytes).into())
+        }
+    }
+}
+
+/// Compare the two given IP addresses, returning the ordering between them. If
+/// the given IP addresses are not of the same type (V4/V6), returns None
+/// instead.
+pub fn compare_ips(a: IpAddr, b: IpAddr) -> Option<Ordering> {
+    Some(match a {
+        IpAddr::V4(a) => match b {
+            IpAddr::V4(b) => a.cmp(&b),
+            IpAddr::V6(_) => return None,
+        },
+        IpAddr::V6(a) => match b {
+            IpAddr::V4(_) => return None,
+            IpAddr::V6(b) => a.cmp(&b),
+        },
+    })
+}
+
+/// Return the smaller of the two IP addresses, or None if they cannot be
+/// compared (see compare_ips).
+pub fn min_ip(a: IpAddr, b: IpAddr) -> Option<IpAddr> {
+    match compare_ips(a, b) {
+        None => None,
+        Some(ordering) => Some(match ordering {
+            Ordering::Greater => b,
+            _ => a,
+        }),
+    }
+}
+
+/// Return the larger of the two IP addresses, or None if they cannot be
+/// compared (see compare_ips).
+pub fn max_ip(a: IpAddr, b: IpAddr) -> Option<IpAddr> {
+    match compare_ips(a, b) {
+        None => None,
+        Some(ordering) => Some(match ordering {
+            Ordering::Less => b,
+            _ => a,
+        }),
+    }
+}
+
+/// This structure denotes a hardware MAC address.
+#[derive(Clone, Copy, Debug, Hash, Eq, Ord, PartialEq, PartialOrd)]
+pub struct HardwareAddr {
+    address: [u8; 6],
+}
+
+impl HardwareAddr {
+    /// Return the 6 bytes which make up the MAC address as a slice.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.address
+    }
+}
+
+impl fmt::Display for HardwareAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            self.address[0],
+            self.address[1],
+            self.address[2],
+            self.address[3],
+            self.address[4],
+            self.address[5]
+        )
+    }
+}
+
+impl FromStr for HardwareAddr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        // Common / valid MAC address formats are "xx:xx:xx:xx:xx:xx",
+        // "xx-xx-xx-xx-xx-xx", and "xxxx.xxxx.xxxx" where "xx" is a hex byte.
+        let normalized = s.replace(":", "");
+        let normalized = normalized.replace("-", "");
+        let normalized = normalized.replace(".", "");
+        let address_vec = match HEXLOWER_PERMISSIVE.decode(normalized.as_bytes()) {
+            Ok(data) => data,
+            Err(e) => return Err(Error::HexDecode(e)),
+        };
+        if address_vec.len() != 6 {
+            return Err(Error::InvalidArgument(format!(
+                "invalid MAC address '{}', expected 6 bytes found {}",
+                s,
+                address_vec.len()
+            )));
+        }
+
+        let mut address = [0_u8; 6];
+        for (dst, src) in address.iter_mut().zip(address_vec.into_iter()) {
+            *dst = src;
+        }
+
+        Ok(HardwareAddr { address: address })
+    }
+}
+
+impl Serialize for HardwareAddr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.to_string().as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for HardwareAddr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> ::std::result::Result<Self, D::Error> {
+        deserializer.deserialize_str(ParseableVisitor::<HardwareAddr>::default())
+    }
+}
+
+/// Apply the given mask to the given IP address bytes. See IpNet::apply_mask
+/// for details on behavior.
+fn apply_ip_mask_bytes(ip: &mut [u8], mask: &[u8], invert: bool, set: bool) {
+    debug_assert!(ip.len() == mask.len());
+    for (b, m) in ip.iter_mut().zip(mask.iter()) {
+        let m: u8 = match invert {
+            false => *m,
+            true => !*m,
+        };
+
+        if set {
+            *b |= m;
+        } else {
+            *b &= m;
+        }
+    }
+}
+
+/// Apply the given mask to the given IP address. See IpNet::apply_mask for
+/// details on behavior.
+fn apply_ip_mask(ip: IpAddr, mask: &[u8], invert: bool, set: bool) -> IpAddr {
+    debug_assert!(mask.len() == 16);
+    let mut bytes: [u8; 16] = match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_compatible().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    };
+    apply_ip_mask_bytes(&mut bytes, mask, invert, set);
+    let masked_ip = Ipv6Addr::from(bytes);
+    match ip.is_ipv4() {
+        false => IpAddr::V6(masked_ip),
+        true => {
+            let bytes = masked_ip.octets();
+            IpAddr::V4(Ipv4Addr::new(bytes[12], bytes[13], bytes[14], bytes[15]))
+        }
+    }
+}
+
+/// An IpNet represents an IP network. Networks are typically identified in CIDR
+/// notation, like (for example) "192.0.0.0/24".
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub struct IpNet {
+    ip: IpAddr,
+    mask: [u8; 16],
+}
+
+impl IpNet {
+    /// Return the network IP. This address will have been normalized, such that
+    /// any non-masked bits will have been turned off during construction.
+    pub fn get_ip(&self) -> IpAddr {
+        self.ip
+    }
+
+    /// Return the network mask, as a byte slice. This slice will always be 16
+    /// bytes long, even if this is an IPv4 network.
+    pub fn get_mask(&self) -> &[u8] {
+        &self.mask
+    }
+
+    /// Apply this network's mask to the given IP address, returning the
+    /// modified copy.
+    ///
+    /// The "default" behavior is when invert=false and set=false. In this
+    /// case, any bits which are "0" in the mask are turned off in the given
+    /// IP address' bytes.
+    ///
+    /// If invert=true, then each bit in the mask is flipped before it is
+    /// applied - in other words, we apply the inverse mask.
+    ///
+    /// If set=true, then we switch from bitwise AND to bitwise OR, meaning
+    /// instead of the behavior described above, any bits which are "1" in the
+    /// mask are turned *on* in the IP address, and other bits are left
+    /// unchanged.
+    pub fn apply_mask(&self, ip: IpAddr, invert: bool, set: bool) -> IpAddr {
+        apply_ip_mask(ip, &self.mask, invert, set)
+    }
+
+    /// Returns the number of "1" bits in this network's mask. Although the mask
+    /// is always 16 bytes long, for IPv4 networks only the last 4 bytes of the
+    /// mask are considered.
+    pub fn get_one_bits(&self) -> usize {
+        self.mask
+            .iter()
+            .skip(if self.ip.is_ipv4() { 12 } else { 0 })
+            .fold(0_u32, |acc, &b| acc + b.count_ones()) as usize
+    }
+
+    /// Returns whether or not this network's mask is "canonical" - i.e., if all
+    /// of its mask's "1" bits are contiguous (no "0" bits in-between them).
+    pub fn is_canonical(&self) -> bool {
+        let first_zero_bit = match self.mask.iter().position(|b| *b != 0xff_u8) {
+            None => return true,
+            Some(idx) => idx,
+        };
+        if self.mask[first_zero_bit].count_zeros() != self.mask[first_zero_bit].trailing_zeros() {
+            return false;
+        }
+        self.mask
+            .iter()
+            .skip(first_zero_bit + 1)
+            .fold(true, |acc, byte| acc && (*byte == 0x00_u8))
+    }
+
+    /// Return the netmask IP address for this network.
+    pub fn netmask(&self) -> IpAddr {
+        self.apply_mask(self.ip, false, true)
+    }
+
+    /// Return the broadcast IP address for this network.
+    pub fn broadcast(&self) -> IpAddr {
+        self.apply_mask(self.ip, true, true)
+    }
+
+    /// Return whether or not the given IP address is contained within this
+    /// network. If strict=true, then the "network address" and "broadcast
+    /// address" are both not considered to be "within" this network (this is
+    /// useful if you want to check if the given IP address is actually usable
+    /// for a host on the network).
+    pub fn contains(&self, ip: IpAddr, strict: bool) -> bool {
+        let contains = self.apply_mask(ip, false, false) == self.ip;
+        match strict {
+            false => contains,
+            true => contains && ip != self.ip && ip != self.broadcast(),
+        }
+    }
+
+    /// Increment the given IP address as if with increment_ip(), but returning
+    /// None if the resulting IP address does not lie within this network. The
+    /// "strict" parameter behaves as IpNet::contains() describes.
+    pub fn increment_in(&self, ip: IpAddr, strict: bool) -> Option<IpAddr> {
+        let next_ip = match increment_ip(ip) {
+            None => return None,
+            Some(ip) => ip,
+        };
+        match self.contains(next_ip, strict) {
+            false => None,
+            true => Some(next_ip),
+        }
+    }
+
+    /// Return the first IP address which falls within this network.
+    pub fn first(&self) -> Option<IpAddr> {
+        increment_ip(self.ip)
+    }
+
+    /// Return the last non-broadcast IP address which falls within this
+    /// network.
+    pub fn last(&self) -> IpAddr {
+        match self.broadcast() {
+            IpAddr::V4(ip) => {
+                let mut bytes = ip.octets();
+                let idx = bytes.len() - 1;
+                bytes[idx] -= 1;
+                Ipv4Addr::from(bytes).into()
+            }
+            IpAddr::V6(ip) => {
+                let mut bytes = ip.octets();
+                let idx = bytes.len() - 1;
+                bytes[idx] -= 1;
+                Ipv6Addr::from(bytes).into()
+            }
+        }
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}/{}",
+            self.ip,
+            match self.is_canonical() {
+                false => HEXLOWER_PERMISSIVE
+                    .encode(&self.mask)
+                    .chars()
+                    .skip(if self.ip.is_ipv4() { 24 } else { 0 })
+                    .collect::<String>(),
+                true => self.get_one_bits().to_string(),
+            }
+        )
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (ip, mask): (&str, &str) = s.split_at(match s.find('/') {
+            None => {
+                return Err(Error::InvalidArgument(format!(
+                    "invalid IP network specifier '{}'",
+                    s
+                )));
+            }
+            Some(idx) => idx,
+        });
+        let ip: IpAddr = ip.parse()?;
+        let mask: &str = &mask[1..];
+
+        let mut mask_vec: Vec<u8> = vec![
+            0xff_u8;
+            match ip.is_ipv4() {
+                false => 0,
+                true => 12,
+            }
+        ];
+
+        let mask_is_hex = (ip.is_ipv4() && mask.len() == 8) || (ip.is_ipv6() && mask.len() == 32);
+        if mask_is_hex {
+            mask_vec.extend(
+                match HEXLOWER_PERMISSIVE.decode(mask.as_bytes()) {
+                    Ok(data) => data,
+                    Err(e) => return Err(Error::HexDecode(e)),
+                }
+                .into_iter(),
+            );
+        } else {
+            let ones = u8::from_str_radix(mask, 10)?;
+            let mut v = vec![0xff_u8; (ones / 8) as usize];
+            let extra_ones = ones % 8;
+            if extra_ones > 0 {
+                v.push((0xff_u8 >> (8 - extra_ones)) << (8 - extra_ones));
+            }
+            mask_vec.extend(v.into_iter());
+        }
+
+        let mut mask = [0_u8; 16];
+        for (dst, src) in mask.iter_mut().zip(mask_vec.into_iter()) {
+            *dst = src;
+        }
+
+        Ok(IpNet {
+            ip: apply_ip_mask(ip, &mask, false, false),
+            mask: mask,
+        })
+    }
+}
+
+impl Serialize for IpNet {
+    fn serialize<S: Serializer>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.to_string().as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for IpNet {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> ::std::result::Result<Self, D::Error> {
+        deserializer.deserialize_str(ParseableVisitor::<IpNet>::default())
+    }
+}
+
+/// The largest UDP datagram we'll ever receive.
+const MAX_DATAGRAM_BYTES: usize = 65535;
+
+/// Options which control how `udp_query` retries requests.
+#[derive(Clone, Debug)]
+pub struct QueryOptions {
+    /// The total number of times to send the request before giving up.
+    pub attempts: usize,
+    /// How long to wait for a response to the first attempt.
+    pub timeout: Duration,
+    /// After each attempt, the timeout is multiplied by this factor. A value of
+    /// 1.0 means the timeout is the same for every attempt. It must be finite
+    /// and non-negative.
+    pub backoff: f64,
+}
+
+impl Default for QueryOptions {
+    fn default() -> Self {
+        QueryOptions {
+            attempts: 3,
+            timeout: Duration::from_secs(1),
+            backoff: 2.0,
+        }
+    }
+}
+
+pub(crate) fn bind_udp_socket_for(addr: &SocketAddr) -> Result<UdpSocket> {
+    let local: SocketAddr = match addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    Ok(UdpSocket::bind(local)?)
+}
+
+/// Receive datagrams on the given socket until one satisfies `match_response`,
+/// or until the given deadline passes (in which case None is returned).
+fn udp_recv_matching<F: Fn(&[u8]) -> bool>(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+    match_response: &F,
+    deadline: Instant,
+) -> Result<Option<(Vec<u8>, SocketAddr)>> {
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(None);
+        }
+        socket.set_read_timeout(Some(deadline - now))?;
+
+        match socket.recv_from(buf) {
+            Ok((len, from)) => {
+                if match_response(&buf[..len]) {
+                    return Ok(Some((buf[..len].to_vec(), from)));
+                }
+                // Otherwise, this is e.g. a response to some other concurrent
+                // query. Ignore it and keep waiting.
+            }
+            Err(e) => match e.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => return Ok(None),
+                _ => return Err(e.into()),
+            },
+        }
+    }
+}
+
+/// Run the given function with the given socket (or a newly bound one, if
+/// None), making sure its original read timeout is restored afterwards.
+fn with_udp_socket<R, F: FnOnce(&UdpSocket) -> Result<R>>(
+    socket: Option<&UdpSocket>,
+    addr: &SocketAddr,
+    broadcast: bool,
+    f: F,
+) -> Result<R> {
+    let owned = match socket {
+        Some(_) => None,
+        None => {
+            let s = bind_udp_socket_for(addr)?;
+            s.set_broadcast(broadcast)?;
+            Some(s)
+        }
+    };
+    let socket = socket.or(owned.as_ref()).unwrap();
+
+    let original_timeout = socket.read_timeout()?;
+    let ret = f(socket);
+    socket.set_read_timeout(original_timeout)?;
+    ret
+}
+
+/// Send a request datagram to the given address, and wait for a response. This
+/// is useful for simple request / response datagram protocols.
+///
+/// If a socket is given, it is used to send the request and receive the
+/// response. Otherwise, a new socket is bound to an ephemeral port.
+///
+/// Any received datagram for which `match_response` returns false (e.g. a
+/// response to some other concurrent query) is discarded, without counting as
+/// a failed attempt. If no matching response is received in time, the request
+/// is re-sent, according to the given options. If all attempts are exhausted,
+/// `Error::NetTimeout` is returned. If the options' backoff factor is invalid
+/// (see `QueryOptions`), or makes the timeout overflow, `Error::InvalidArgument`
+/// is returned.
+///
+/// On success, the response and the address it was received from are returned.
+pub fn udp_query<F: Fn(&[u8]) -> bool>(
+    socket: Option<&UdpSocket>,
+    addr: SocketAddr,
+    request: &[u8],
+    match_response: F,
+    opts: &QueryOptions,
+) -> Result<(Vec<u8>, SocketAddr)> {
+    if !opts.backoff.is_finite() || opts.backoff < 0.0 {
+        return Err(Error::InvalidArgument(format!(
+            "invalid UDP query backoff factor {}",
+            opts.backoff
+        )));
+    }
+    let overflow = || {
+        Error::InvalidArgument(format!(
+            "UDP query timeout overflowed with backoff factor {}",
+            opts.backoff
+        ))
+    };
+
+    with_udp_socket(socket, &addr, /*broadcast=*/ false, |socket| {
+        let mut buf = vec![0_u8; MAX_DATAGRAM_BYTES];
+        let mut timeout = opts.timeout;
+        for _ in 0..opts.attempts {
+            let deadline = Instant::now().checked_add(timeout).ok_or_else(overflow)?;
+            socket.send_to(request, addr)?;
+            if let Some(ret) = udp_recv_matching(socket, &mut buf, &match_response, deadline)? {
+                return Ok(ret);
+            }
+            timeout = Duration::try_from_secs_f64(timeout.as_secs_f64() * opts.backoff)
+                .map_err(|_| overflow())?;
+        }
+
+        Err(Error::NetTimeout(format!(
+            "no response from {} after {} attempts",
+            addr, opts.attempts
+        )))
+    })
+}
+
+/// Send a single request datagram to the given (typically broadcast or
+/// multicast) address, and collect all matching responses received within the
+/// given window. This is useful for e.g. discovery protocols.
+///
+/// If a socket is given, it is used to send the request and receive the
+/// responses. Otherwise, a new socket is bound to an ephemeral port, with
+/// broadcasting enabled.
+///
+/// Unlike `udp_query`, receiving no responses at all is not an error; an empty
+/// list is returned instead.
+pub fn udp_query_all<F: Fn(&[u8]) -> bool>(
+    socket: Option<&UdpSocket>,
+    addr: SocketAddr,
+    request: &[u8],
+    match_response: F,
+    window: Duration,
+) -> Result<Vec<(Vec<u8>, SocketAddr)>> {
+    with_udp_socket(socket, &addr, /*broadcast=*/ true, |socket| {
+        let mut buf = vec![0_u8; MAX_DATAGRAM_BYTES];
+        let mut responses = Vec::new();
+        socket.send_to(request, addr)?;
+        let deadline = Instant::now() + window;
+        while let Some(ret) = udp_recv_matching(socket, &mut buf, &match_response, deadline)? {
+            responses.push(ret);
+        }
+        Ok(responses)
+    })
+}
+
+/// Parameters for TCP keepalive probes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct KeepaliveParams {
+    /// How long the connection must be idle before the first probe is sent.
+    pub idle: Duration,
+    /// How long to wait between unacknowledged probes.
+    pub interval: Duration,
+    /// How many unacknowledged probes to send before the connection is
+    /// considered dead.
+    pub count: u32,
+}
+
+/// SocketConfig is a set of socket options which can be applied to TCP
+/// sockets. Any option which isn't explicitly set is left at the OS default.
+#[derive(Clone, Debug, Default)]
+pub struct SocketConfig {
+    keepalive: Option<Option<KeepaliveParams>>,
+    nodelay: Option<bool>,
+    reuse_addr: Option<bool>,
+    linger: Option<Option<Duration>>,
+    recv_buffer_size: Option<usize>,
+    send_buffer_size: Option<usize>,
+}
+
+impl SocketConfig {
+    /// Enable TCP keepalive with the given parameters, or disable it (None).
+    pub fn keepalive(mut self, keepalive: Option<KeepaliveParams>) -> Self {
+        self.keepalive = Some(keepalive);
+        self
+    }
+
+    /// Set whether or not Nagle's algorithm is disabled (TCP_NODELAY).
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = Some(nodelay);
+        self
+    }
+
+    /// Set whether or not local addresses can be reused (SO_REUSEADDR). Note
+    /// that this only has an effect if it is set before the socket is bound.
+    pub fn reuse_addr(mut self, reuse_addr: bool) -> Self {
+        self.reuse_addr = Some(reuse_addr);
+        self
+    }
+
+    /// Set how long closing the socket blocks waiting for unsent data to be
+    /// sent (SO_LINGER), or disable lingering (None).
+    pub fn linger(mut self, linger: Option<Duration>) -> Self {
+        self.linger = Some(linger);
+        self
+    }
+
+    /// Set the size of the socket's receive buffer (SO_RCVBUF). The OS may
+    /// round or clamp this value.
+    pub fn recv_buffer_size(mut self, size: usize) -> Self {
+        self.recv_buffer_size = Some(size);
+        self
+    }
+
+    /// Set the size of the socket's send buffer (SO_SNDBUF). The OS may round
+    /// or clamp this value.
+    pub fn send_buffer_size(mut self, size: usize) -> Self {
+        self.send_buffer_size = Some(size);
+        self
+    }
+
+    /// Apply this configuration to the given connected TCP stream.
+    pub fn apply_to(&self, stream: &TcpStream) -> Result<()> {
+        self.apply_to_socket(stream)
+    }
+
+    /// Apply this configuration to the given TCP listener. On most platforms,
+    /// sockets accepted from the listener inherit these options.
+    pub fn apply_to_listener(&self, listener: &TcpListener) -> Result<()> {
+        self.apply_to_socket(listener)
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    fn apply_to_socket<S: std::os::unix::io::AsRawFd>(&self, socket: &S) -> Result<()> {
+        let fd = socket.as_raw_fd();
+        if let Some(nodelay) = self.nodelay {
+            set_socket_option(
+                fd,
+                libc::IPPROTO_TCP,
+                libc::TCP_NODELAY,
+                "TCP_NODELAY",
+                nodelay as libc::c_int,
+            )?;
+        }
+        if let Some(reuse_addr) = self.reuse_addr {
+            set_socket_option(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_REUSEADDR,
+                "SO_REUSEADDR",
+                reuse_addr as libc::c_int,
+            )?;
+        }
+        if let Some(keepalive) = self.keepalive {
+            set_socket_option(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_KEEPALIVE,
+                "SO_KEEPALIVE",
+                keepalive.is_some() as libc::c_int,
+            )?;
+            if let Some(params) = keepalive {
+                set_keepalive_params(fd, &params)?;
+            }
+        }
+        if let Some(linger) = self.linger {
+            let value = libc::linger {
+                l_onoff: linger.is_some() as libc::c_int,
+                l_linger: socket_option_value("SO_LINGER", linger.map_or(0, |l| l.as_secs()))?,
+            };
+            set_socket_option(fd, libc::SOL_SOCKET, libc::SO_LINGER, "SO_LINGER", value)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            set_socket_option(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_RCVBUF,
+                "SO_RCVBUF",
+                socket_option_value("SO_RCVBUF", size as u64)?,
+            )?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            set_socket_option(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_SNDBUF,
+                "SO_SNDBUF",
+                socket_option_value("SO_SNDBUF", size as u64)?,
+            )?;
+        }
+        Ok(())
+    }
+
+    #[cfg(target_os = "windows")]
+    fn apply_to_socket<S>(&self, _: &S) -> Result<()> {
+        Err(Error::Unsupported(
+            "socket options are not supported on this platform".to_string(),
+        ))
+    }
+}
+
+/// Convert the given value to the integer type socket options expect, or
+/// return an error naming the option if it is out of range.
+#[cfg(not(target_os = "windows"))]
+fn socket_option_value(option: &str, value: u64) -> Result<libc::c_int> {
+    libc::c_int::try_from(value).map_err(|_| Error::SocketOption {
+        option: option.to_owned(),
+        error: io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("value {} is out of range", value),
+        ),
+    })
+}
+
+#[cfg(not(target_os = "windows"))]
+fn set_socket_option<T>(
+    fd: libc::c_int,
+    level: libc::c_int,
+    name: libc::c_int,
+    option: &str,
+    value: T,
+) -> Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            std::ptr::addr_of!(value) as *const libc::c_void,
+            size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(Error::SocketOption {
+            option: option.to_owned(),
+            error: io::Error::last_os_error(),
+        });
+    }
+    Ok(())
+}
+
+/// Read back the value of an integer socket option (e.g., `libc::SOL_SOCKET`,
+/// `libc::SO_KEEPALIVE`). This is mostly useful for verifying that options
+/// were applied.
+#[cfg(not(target_os = "windows"))]
+pub fn get_socket_option<S: std::os::unix::io::AsRawFd>(
+    socket: &S,
+    level: libc::c_int,
+    name: libc::c_int,
+) -> Result<libc::c_int> {
+    let fd = socket.as_raw_fd();
+    let mut value: libc::c_int = 0;
+    let mut len = size_of::<libc::c_int>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            level,
+            name,
+            std::ptr::addr_of_mut!(value) as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(Error::SocketOption {
+            option: format!("{}/{}", level, name),
+            error: io::Error::last_os_error(),
+        });
+    }
+    Ok(value)
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+const TCP_KEEPIDLE: (libc::c_int, &str) = (libc::TCP_KEEPALIVE, "TCP_KEEPALIVE");
+#[cfg(not(any(target_os = "macos", target_os = "ios", target_os = "windows")))]
+const TCP_KEEPIDLE: (libc::c_int, &str) = (libc::TCP_KEEPIDLE, "TCP_KEEPIDLE");
+
+#[cfg(not(target_os = "windows"))]
+fn set_keepalive_params(fd: libc::c_int, params: &KeepaliveParams) -> Result<()> {
+    set_socket_option(
+        fd,
+        libc::IPPROTO_TCP,
+        TCP_KEEPIDLE.0,
+        TCP_KEEPIDLE.1,
+        socket_option_value(TCP_KEEPIDLE.1, params.idle.as_secs().max(1))?,
+    )?;
+    set_socket_option(
+        fd,
+        libc::IPPROTO_TCP,
+        libc::TCP_KEEPINTVL,
+        "TCP_KEEPINTVL",
+        socket_option_value("TCP_KEEPINTVL", params.interval.as_secs().max(1))?,
+    )?;
+    set_socket_option(
+        fd,
+        libc::IPPROTO_TCP,
+        libc::TCP_KEEPCNT,
+        "TCP_KEEPCNT",
+        socket_option_value("TCP_KEEPCNT", params.count as u64)?,
+    )?;
+    Ok(())
+}
+
+/// Connect to the given address, giving up after the given timeout, and then
+/// apply the given socket configuration to the connected stream.
+pub fn connect_with(
+    addr: &SocketAddr,
+    config: &SocketConfig,
+    timeout: Duration,
+) -> Result<TcpStream> {
+    let stream = match TcpStream::connect_timeout(addr, timeout) {
+        Ok(s) => s,
+        Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+            return Err(Error::NetTimeout(format!(
+                "connecting to {} took longer than {:?}",
+                addr, timeout
+            )))
+        }
+        Err(e) => return Err(e.into()),
+    };
+    config.apply_to(&stream)?;
+    Ok(stream)
+}
+
+/// AddressFamily selects which kind of address is tried first, when a host
+/// name resolves to both IPv4 and IPv6 addresses.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum AddressFamily {
+    /// Prefer IPv4 addresses.
+    V4,
+    /// Prefer IPv6 addresses.
+    V6,
+    /// Keep the order the system resolver returned.
+    #[default]
+    SystemOrder,
+}
+
+/// Reorder the given addresses so ones of the preferred family come first.
+/// The relative order of addresses within each family is preserved.
+pub fn order_addrs(addrs: &mut [SocketAddr], prefer: AddressFamily) {
+    match prefer {
+        AddressFamily::V4 => addrs.sort_by_key(|a| !a.is_ipv4()),
+        AddressFamily::V6 => addrs.sort_by_key(|a| !a.is_ipv6()),
+        AddressFamily::SystemOrder => {}
+    }
+}
+
+/// HostPort is a host name (or IP address) and a port, e.g. as given on the
+/// command line. Its string form is "host:port", with IPv6 addresses in
+/// brackets (e.g. "[::1]:8080").
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct HostPort {
+    /// The host name or IP address.
+    pub host: String,
+    /// The port number.
+    pub port: u16,
+}
+
+impl HostPort {
+    /// Construct a new HostPort from the given host and port.
+    pub fn new<H: Into<String>>(host: H, port: u16) -> Self {
+        HostPort {
+            host: host.into(),
+            port,
+        }
+    }
+
+    /// Resolve this host and port to socket addresses; see
+    /// `resolve_with_timeout`.
+    pub fn resolve(&self, timeout: Duration, prefer: AddressFamily) -> Result<Vec<SocketAddr>> {
+        resolve_with_timeout(self.host.as_str(), self.port, timeout, prefer)
+    }
+}
+
+impl From<SocketAddr> for HostPort {
+    fn from(addr: SocketAddr) -> Self {
+        HostPort::new(addr.ip().to_string(), addr.port())
+    }
+}
+
+impl fmt::Display for HostPort {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.host.contains(':') {
+            false => write!(f, "{}:{}", self.host, self.port),
+            true => write!(f, "[{}]:{}", self.host, self.port),
+        }
+    }
+}
+
+impl FromStr for HostPort {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidArgument(format!("invalid host:port '{}'", s));
+        let (host, port) = s.rsplit_once(':').ok_or_else(invalid)?;
+        let host = match host.strip_prefix('[') {
+            None if host.contains(':') => return Err(invalid()),
+            None => host,
+            Some(bracketed) => bracketed.strip_suffix(']').ok_or_else(invalid)?,
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        Ok(HostPort::new(host, port.parse().map_err(|_| invalid())?))
+    }
+}
+
+/// Resolve the given host name (or IP address) and port, giving up after the
+/// given timeout, and order the results according to `prefer`.
+///
+/// The system resolver is blocking and can't be cancelled, so resolution is
+/// done on a helper thread. If it times out, that thread is abandoned: it
+/// keeps running (and holding its resources) until the resolver eventually
+/// returns, at which point it exits, and its result is discarded.
+pub fn resolve_with_timeout(
+    host: &str,
+    port: u16,
+    timeout: Duration,
+    prefer: AddressFamily,
+) -> Result<Vec<SocketAddr>> {
+    let (tx, rx) = mpsc::channel();
+    let target = (host.to_owned(), port);
+    thread::Builder::new()
+        .name("bdrck-resolve".to_owned())
+        .spawn(move || {
+            let result = target
+                .to_socket_addrs()
+                .map(|addrs| addrs.collect::<Vec<SocketAddr>>());
+            // If the receiver is gone we timed out, so just discard the result.
+            let _ = tx.send(result);
+        })?;
+
+    let mut addrs = match rx.recv_timeout(timeout) {
+        Ok(result) => result?,
+        Err(_) => {
+            return Err(Error::NetTimeout(format!(
+                "resolving {} took longer than {:?}",
+                host, timeout
+            )))
+        }
+    };
+    if addrs.is_empty() {
+        return Err(Error::NotFound(format!("{} has no addresses", host)));
+    }
+    order_addrs(&mut addrs, prefer);
+    Ok(addrs)
+}
+
+/// How long `connect_happy` waits for a connection attempt before starting the
+/// next one in parallel.
+pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Connect to the first of the given addresses which accepts a connection, in
+/// the style of "happy eyeballs" (RFC 8305): the addresses are tried in order,
+/// but each attempt only gets `CONNECTION_ATTEMPT_DELAY` to succeed before the
+/// next one starts in parallel (each attempt gives up after
+/// `per_attempt_timeout`). The first successful connection is returned, and
+/// any other connections which succeed later are closed.
+///
+/// If every attempt fails, the last error is returned.
+pub fn connect_happy(addrs: &[SocketAddr], per_attempt_timeout: Duration) -> Result<TcpStream> {
+    if addrs.is_empty() {
+        return Err(Error::InvalidArgument(
+            "no addresses to connect to".to_string(),
+        ));
+    }
+
+    let done = Arc::new(AtomicBool::new(false));
+    let (tx, rx) = mpsc::channel();
+    for (i, addr) in addrs.iter().cloned().enumerate() {
+        let done = done.clone();
+        let tx = tx.clone();
+        thread::Builder::new()
+            .name("bdrck-connect".to_owned())
+            .spawn(move || {
+                thread::sleep(CONNECTION_ATTEMPT_DELAY * i as u32);
+                if done.load(AtomicOrdering::SeqCst) {
+                    return;
+                }
+                let result = connect_with(&addr, &SocketConfig::default(), per_attempt_timeout);
+                // If we lost the race, this drops (closes) our connection.
+                let _ = tx.send(result);
+            })?;
+    }
+    drop(tx);
+
+    let mut last_error = None;
+    for result in rx.iter() {
+        match result {
+            Ok(stream) => {
+                done.store(true, AtomicOrdering::SeqCst);
+                return Ok(stream);
+            }
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap())
+}
+
+/// InterfaceFlags is a set of flags describing a network interface's state
+/// and capabilities. Flags can be combined with `|`, and checked with
+/// `contains`.
+#[derive(Clone, Copy, Debug, Default, Hash, Eq, PartialEq)]
+pub struct InterfaceFlags(u32);
+
+impl InterfaceFlags {
+    /// The interface is administratively up.
+    pub const UP: InterfaceFlags = InterfaceFlags(1 << 0);
+    /// The interface has resources allocated, and is operationally up.
+    pub const RUNNING: InterfaceFlags = InterfaceFlags(1 << 1);
+    /// The interface is a loopback interface.
+    pub const LOOPBACK: InterfaceFlags = InterfaceFlags(1 << 2);
+    /// The interface supports broadcast.
+    pub const BROADCAST: InterfaceFlags = InterfaceFlags(1 << 3);
+    /// The interface supports multicast.
+    pub const MULTICAST: InterfaceFlags = InterfaceFlags(1 << 4);
+    /// The interface is a point-to-point link.
+    pub const POINT_TO_POINT: InterfaceFlags = InterfaceFlags(1 << 5);
+
+    /// Return an empty set of flags.
+    pub fn empty() -> Self {
+        InterfaceFlags(0)
+    }
+
+    /// Return the raw bits making up this set of flags.
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+
+    /// Return whether or not this set contains no flags at all.
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// Return whether or not every flag in `other` is also set in `self`.
+    pub fn contains(&self, other: InterfaceFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    fn from_raw(raw: libc::c_uint) -> Self {
+        let raw = raw as libc::c_int;
+        let mut flags = InterfaceFlags::empty();
+        for &(bit, flag) in &[
+            (libc::IFF_UP, InterfaceFlags::UP),
+            (libc::IFF_RUNNING, InterfaceFlags::RUNNING),
+            (libc::IFF_LOOPBACK, InterfaceFlags::LOOPBACK),
+            (libc::IFF_BROADCAST, InterfaceFlags::BROADCAST),
+            (libc::IFF_MULTICAST, InterfaceFlags::MULTICAST),
+            (libc::IFF_POINTOPOINT, InterfaceFlags::POINT_TO_POINT),
+        ] {
+            if raw & bit != 0 {
+                flags |= flag;
+            }
+        }
+        flags
+    }
+}
+
+impl std::ops::BitOr for InterfaceFlags {
+    type Output = InterfaceFlags;
+
+    fn bitor(self, rhs: InterfaceFlags) -> InterfaceFlags {
+        InterfaceFlags(self.0 | rhs.0)
+    }
+}
+
+impl std::ops::BitOrAssign for InterfaceFlags {
+    fn bitor_assign(&mut self, rhs: InterfaceFlags) {
+        self.0 |= rhs.0;
+    }
+}
+
+/// AddressScope describes where an interface address is valid.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub enum AddressScope {
+    /// The address is only valid on this host (e.g. 127.0.0.1 or ::1).
+    Host,
+    /// The address is only valid on the directly attached link (e.g.
+    /// 169.254.0.0/16 or fe80::/10).
+    Link,
+    /// The address is valid beyond the attached link.
+    Global,
+}
+
+impl AddressScope {
+    /// Return the scope of the given IP address.
+    pub fn of(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(ip) if ip.is_loopback() => AddressScope::Host,
+            IpAddr::V4(ip) if ip.is_link_local() => AddressScope::Link,
+            IpAddr::V6(ip) if ip.is_loopback() => AddressScope::Host,
+            IpAddr::V6(ip) if ip.segments()[0] & 0xffc0 == 0xfe80 => AddressScope::Link,
+            _ => AddressScope::Global,
+        }
+    }
+}
+
+/// A single address assigned to a network interface.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub struct InterfaceAddr {
+    /// The address itself.
+    pub addr: IpAddr,
+    /// The length of the network prefix, derived from the address' netmask.
+    pub prefix_len: u8,
+    /// Where this address is valid.
+    pub scope: AddressScope,
+}
+
+/// A local network interface, as returned by `interfaces`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Interface {
+    /// The interface's name (e.g. "lo" or "eth0").
+    pub name: String,
+    /// The interface's index, as used by e.g. IPv6 scope IDs.
+    pub index: u32,
+    /// The interface's state and capabilities.
+    pub flags: InterfaceFlags,
+    /// The interface's MTU, if it could be determined.
+    pub mtu: Option<u32>,
+    /// Every IPv4 and IPv6 address assigned to the interface.
+    pub addrs: Vec<InterfaceAddr>,
+    /// The interface's hardware address, if it has one.
+    pub hardware_addr: Option<HardwareAddr>,
+}
+
+/// Return the prefix length corresponding to the given netmask (e.g. 24 for
+/// 255.255.255.0), or None if the mask's "1" bits are not contiguous.
+pub fn prefix_len_from_mask(mask: &[u8]) -> Option<u8> {
+    let prefix_len = match mask.iter().position(|b| *b != 0xff) {
+        None => mask.len() as u32 * 8,
+        Some(idx) => idx as u32 * 8 + mask[idx].leading_ones(),
+    };
+    match mask.iter().map(|b| b.count_ones()).sum::<u32>() == prefix_len {
+        false => None,
+        true => Some(prefix_len as u8),
+    }
+}
+
+/// Convert the given socket address into an IP address, if it is an IPv4 or
+/// IPv6 address.
+#[cfg(not(target_os = "windows"))]
+unsafe fn sockaddr_ip(addr: *const libc::sockaddr) -> Option<IpAddr> {
+    if addr.is_null() {
+        return None;
+    }
+    match (*addr).sa_family as libc::c_int {
+        libc::AF_INET => {
+            let addr = &*(addr as *const libc::sockaddr_in);
+            Some(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)).into())
+        }
+        libc::AF_INET6 => {
+            let addr = &*(addr as *const libc::sockaddr_in6);
+            Some(Ipv6Addr::from(addr.sin6_addr.s6_addr).into())
+        }
+        _ => None,
+    }
+}
+
+/// Extract the hardware address from the given link-layer socket address, if
+/// it is one.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+unsafe fn sockaddr_hardware_addr(addr: *const libc::sockaddr) -> Option<HardwareAddr> {
+    if addr.is_null() || (*addr).sa_family as libc::c_int != libc::AF_PACKET {
+        return None;
+    }
+    let addr = &*(addr as *const libc::sockaddr_ll);
+    if addr.sll_halen != 6 {
+        return None;
+    }
+    let mut address = [0_u8; 6];
+    address.copy_from_slice(&addr.sll_addr[..6]);
+    // Interfaces without a real hardware address (e.g. loopback) report all
+    // zeros.
+    match address == [0; 6] {
+        false => Some(HardwareAddr { address }),
+        true => None,
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "windows")))]
+unsafe fn sockaddr_hardware_addr(_addr: *const libc::sockaddr) -> Option<HardwareAddr> {
+    None
+}
+
+/// Look up the MTU of the interface with the given name.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn interface_mtu(name: &str) -> Option<u32> {
+    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+    let mut req: libc::ifreq = unsafe { std::mem::zeroed() };
+    if name.len() >= req.ifr_name.len() {
+        return None;
+    }
+    for (dst, src) in req.ifr_name.iter_mut().zip(name.bytes()) {
+        *dst = src as libc::c_char;
+    }
+    let ret = unsafe {
+        libc::ioctl(
+            std::os::unix::io::AsRawFd::as_raw_fd(&socket),
+            libc::SIOCGIFMTU,
+            &mut req,
+        )
+    };
+    match ret {
+        0 => u32::try_from(unsafe { req.ifr_ifru.ifru_mtu }).ok(),
+        _ => None,
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "windows")))]
+fn interface_mtu(_name: &str) -> Option<u32> {
+    None
+}
+
+/// IfAddrs owns the list returned by `getifaddrs`, making sure it is freed
+/// however we're done with it.
+#[cfg(not(target_os = "windows"))]
+struct IfAddrs(*mut libc::ifaddrs);
+
+#[cfg(not(target_os = "windows"))]
+impl Drop for IfAddrs {
+    fn drop(&mut self) {
+        unsafe { libc::freeifaddrs(self.0) };
+    }
+}
+
+/// Return every local network interface, along with its addresses. The
+/// interfaces are returned in the order the OS lists them.
+#[cfg(not(target_os = "windows"))]
+pub fn interfaces() -> Result<Vec<Interface>> {
+    let mut head: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut head) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let ifaddrs = IfAddrs(head);
+
+    let mut interfaces: Vec<Interface> = Vec::new();
+    let mut current = ifaddrs.0;
+    while !current.is_null() {
+        let entry = unsafe { &*current };
+        current = entry.ifa_next;
+
+        let name = unsafe { std::ffi::CStr::from_ptr(entry.ifa_name) }
+            .to_str()?
+            .to_owned();
+        let idx = match interfaces.iter().position(|i| i.name == name) {
+            Some(idx) => idx,
+            None => {
+                let c_name = std::ffi::CString::new(name.as_str())?;
+                interfaces.push(Interface {
+                    index: unsafe { libc::if_nametoindex(c_name.as_ptr()) },
+                    flags: InterfaceFlags::from_raw(entry.ifa_flags),
+                    mtu: interface_mtu(name.as_str()),
+                    name,
+                    addrs: Vec::new(),
+                    hardware_addr: None,
+                });
+                interfaces.len() - 1
+            }
+        };
+        let interface = &mut interfaces[idx];
+
+        if let Some(addr) = unsafe { sockaddr_ip(entry.ifa_addr) } {
+            let prefix_len = match unsafe { sockaddr_ip(entry.ifa_netmask) } {
+                Some(IpAddr::V4(mask)) => prefix_len_from_mask(&mask.octets()),
+                Some(IpAddr::V6(mask)) => prefix_len_from_mask(&mask.octets()),
+                None => None,
+            };
+            interface.addrs.push(InterfaceAddr {
+                addr,
+                prefix_len: prefix_len.unwrap_or(match addr {
+                    IpAddr::V4(_) => 32,
+                    IpAddr::V6(_) => 128,
+                }),
+                scope: AddressScope::of(addr),
+            });
+        } else if let Some(hardware_addr) = unsafe { sockaddr_hardware_addr(entry.ifa_addr) } {
+            interface.hardware_addr = Some(hardware_addr);
+        }
+    }
+
+    Ok(interfaces)
+}
+
+/// Return every local network interface, along with its addresses. This is
+/// not supported on this platform.
+#[cfg(target_os = "windows")]
+pub fn interfaces() -> Result<Vec<Interface>> {
+    Err(Error::Unsupported(
+        "listing network interfaces is not supported on this platform".to_string(),
+    ))
+}
+
+/// Return the local network interface with the given name.
+pub fn interface_by_name(name: &str) -> Result<Interface> {
+    interfaces()?
+        .into_iter()
+        .find(|i| i.name == name)
+        .ok_or_else(|| Error::NotFound(format!("network interface '{}' not found", name)))
+}
+
+/// Parse the contents of Linux's /proc/net/route, returning the name of the
+/// interface the lowest-metric IPv4 default route goes through, if any.
+pub(crate) fn parse_ipv4_default_route(contents: &str) -> Option<String> {
+    contents
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 8 {
+                return None;
+            }
+            let flags = u32::from_str_radix(fields[3], 16).ok()?;
+            let metric: u32 = fields[6].parse().ok()?;
+            match fields[1] == "00000000" && fields[7] == "00000000" && flags & 0x1 != 0 {
+                false => None,
+                true => Some((metric, fields[0].to_owned())),
+            }
+        })
+        .min_by_key(|(metric, _)| *metric)
+        .map(|(_, name)| name)
+}
+
+/// Parse the contents of Linux's /proc/net/ipv6_route, returning the name of
+/// the interface the lowest-metric IPv6 default route goes through, if any.
+pub(crate) fn parse_ipv6_default_route(contents: &str) -> Option<String> {
+    const RTF_UP: u32 = 0x1;
+    const RTF_REJECT: u32 = 0x200;
+
+    contents
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 10 {
+                return None;
+            }
+            let metric = u32::from_str_radix(fields[5], 16).ok()?;
+            let flags = u32::from_str_radix(fields[8], 16).ok()?;
+            let is_default = fields[0].bytes().all(|b| b == b'0') && fields[1] == "00";
+            match is_default && flags & RTF_UP != 0 && flags & RTF_REJECT == 0 {
+                false => None,
+                true => Some((metric, fields[9].to_owned())),
+            }
+        })
+        .min_by_key(|(metric, _)| *metric)
+        .map(|(_, name)| name)
+}
+
+/// Return the interface the system's default route goes through, preferring
+/// the IPv4 default route if there is one. If there is no default route at
+/// all, None is returned.
+///
+/// This is currently only supported on Linux, where the routing tables are
+/// read from /proc; other platforms return `Error::Unsupported`.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn default_route_interface() -> Result<Option<Interface>> {
+    let read = |path: &str| match std::fs::read_to_string(path) {
+        Ok(contents) => Ok(contents),
+        // The IPv6 table doesn't exist if IPv6 is disabled.
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+        Err(e) => Err(e),
+    };
+    let name = match parse_ipv4_default_route(read("/proc/net/route")?.as_str()) {
+        Some(name) => Some(name),
+        None => parse_ipv6_default_route(read("/proc/net/ipv6_route")?.as_str()),
+    };
+    match name {
+        None => Ok(None),
+        Some(name) => interface_by_name(name.as_str()).map(Some),
+    }
+}
+
+/// Return the interface the system's default route goes through. This is not
+/// supported on this platform.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn default_route_interface() -> Result<Option<Interface>> {
+    Err(Error::Unsupported(
+        "looking up the default route is not supported on this platform".to_string(),
+    ))
+}
@@ -0,0 +1,257 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::error::*;
+use crate::net::{bind_udp_socket_for, AddressFamily, HostPort};
+use crate::testing::clock::{Clock, SystemClock};
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+/// ProbeProtocol selects how `probe` checks whether a target is reachable.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ProbeProtocol {
+    /// Open a TCP connection, measuring how long the handshake takes. The
+    /// connection is closed again right away.
+    #[default]
+    Tcp,
+    /// Send an empty UDP datagram. Most services won't respond to it, but if
+    /// the port is closed, the target's "port unreachable" response tells us
+    /// the host itself is reachable.
+    Udp,
+}
+
+/// ProbeOutcome is the result of a single probe attempt.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ProbeOutcome {
+    /// The TCP handshake completed, or a UDP response was received.
+    Connected,
+    /// The TCP connection was refused: the host is reachable, but nothing is
+    /// listening on the port.
+    Refused,
+    /// An ICMP port unreachable error was received in response to a UDP
+    /// probe: the host is reachable, but nothing is listening on the port.
+    PortUnreachable,
+    /// Nothing was received before the timeout. The host may be down, or
+    /// probes may be filtered.
+    TimedOut,
+    /// Some other error occurred (e.g. there's no route to the host).
+    Failed(String),
+}
+
+impl ProbeOutcome {
+    /// Returns whether or not this outcome means the target host responded,
+    /// even if only to say the port is closed.
+    pub fn is_reachable(&self) -> bool {
+        matches!(
+            self,
+            ProbeOutcome::Connected | ProbeOutcome::Refused | ProbeOutcome::PortUnreachable
+        )
+    }
+}
+
+impl fmt::Display for ProbeOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProbeOutcome::Connected => write!(f, "connected"),
+            ProbeOutcome::Refused => write!(f, "connection refused"),
+            ProbeOutcome::PortUnreachable => write!(f, "port unreachable"),
+            ProbeOutcome::TimedOut => write!(f, "timed out"),
+            ProbeOutcome::Failed(e) => write!(f, "failed: {}", e),
+        }
+    }
+}
+
+/// ProbeAttempt records a single probe attempt.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ProbeAttempt {
+    /// The attempt's sequence number, starting from 0.
+    pub sequence: usize,
+    /// What happened.
+    pub outcome: ProbeOutcome,
+    /// How long it took for the target to respond, if it did (see
+    /// `ProbeOutcome::is_reachable`).
+    pub latency: Option<Duration>,
+}
+
+/// ProbeReport summarizes a series of probe attempts.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ProbeReport {
+    /// The target which was probed.
+    pub target: HostPort,
+    /// The address the target resolved to, which is the one that was
+    /// actually probed.
+    pub addr: SocketAddr,
+    /// The protocol the probes were sent with.
+    pub protocol: ProbeProtocol,
+    /// Every attempt, in order.
+    pub attempts: Vec<ProbeAttempt>,
+}
+
+impl ProbeReport {
+    fn latencies(&self) -> impl Iterator<Item = Duration> + '_ {
+        self.attempts.iter().filter_map(|a| a.latency)
+    }
+
+    /// Return how many attempts the target responded to.
+    pub fn received(&self) -> usize {
+        self.latencies().count()
+    }
+
+    /// Return the fraction of attempts (from 0.0 to 1.0) which the target
+    /// didn't respond to. With no attempts at all, this is 0.0.
+    pub fn loss(&self) -> f64 {
+        if self.attempts.is_empty() {
+            return 0.0;
+        }
+        (self.attempts.len() - self.received()) as f64 / self.attempts.len() as f64
+    }
+
+    /// Return the lowest latency of any attempt, if any responded.
+    pub fn min_latency(&self) -> Option<Duration> {
+        self.latencies().min()
+    }
+
+    /// Return the mean latency of the attempts which responded, if any did.
+    pub fn avg_latency(&self) -> Option<Duration> {
+        let received = self.received();
+        if received == 0 {
+            return None;
+        }
+        Some(self.latencies().sum::<Duration>() / received as u32)
+    }
+
+    /// Return the highest latency of any attempt, if any responded.
+    pub fn max_latency(&self) -> Option<Duration> {
+        self.latencies().max()
+    }
+}
+
+/// The default number of probe attempts.
+pub const DEFAULT_ATTEMPTS: usize = 4;
+/// The default time to wait for each attempt.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+/// The default time between the start of each attempt.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// ProbeOptions controls how `probe` probes a target.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ProbeOptions {
+    /// How many attempts to make.
+    pub attempts: usize,
+    /// How long to wait for each attempt.
+    pub timeout: Duration,
+    /// The protocol to probe with.
+    pub protocol: ProbeProtocol,
+    /// The time between the start of each attempt, like ping's interval. If
+    /// an attempt takes longer than this, the next one starts right away.
+    pub interval: Duration,
+}
+
+impl Default for ProbeOptions {
+    fn default() -> Self {
+        ProbeOptions {
+            attempts: DEFAULT_ATTEMPTS,
+            timeout: DEFAULT_TIMEOUT,
+            protocol: ProbeProtocol::default(),
+            interval: DEFAULT_INTERVAL,
+        }
+    }
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::TimedOut || e.kind() == io::ErrorKind::WouldBlock
+}
+
+fn probe_tcp(target: &SocketAddr, timeout: Duration) -> ProbeOutcome {
+    match TcpStream::connect_timeout(target, timeout) {
+        Ok(_) => ProbeOutcome::Connected,
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => ProbeOutcome::Refused,
+        Err(e) if is_timeout(&e) => ProbeOutcome::TimedOut,
+        Err(e) => ProbeOutcome::Failed(e.to_string()),
+    }
+}
+
+fn probe_udp(target: &SocketAddr, timeout: Duration) -> Result<ProbeOutcome> {
+    let socket = bind_udp_socket_for(target)?;
+    // Connecting the socket is what lets us see ICMP errors for it.
+    socket.connect(target)?;
+    socket.set_read_timeout(Some(timeout))?;
+    if let Err(e) = socket.send(&[]) {
+        return Ok(ProbeOutcome::Failed(e.to_string()));
+    }
+    let mut buf = [0; 1];
+    Ok(match socket.recv(&mut buf) {
+        Ok(_) => ProbeOutcome::Connected,
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => ProbeOutcome::PortUnreachable,
+        Err(e) if is_timeout(&e) => ProbeOutcome::TimedOut,
+        Err(e) => ProbeOutcome::Failed(e.to_string()),
+    })
+}
+
+/// Check whether the given target is reachable, in the style of ping, but
+/// using ordinary TCP or UDP sockets, so no special privileges are needed.
+/// This is shorthand for `probe_with` using the system clock, without a
+/// callback.
+pub fn probe(target: HostPort, options: &ProbeOptions) -> Result<ProbeReport> {
+    probe_with(target, options, &SystemClock, |_| {})
+}
+
+/// Check whether the given target is reachable, as with `probe`. The given
+/// callback is called after each attempt, so e.g. CLIs can print results as
+/// they come in. Latencies and the interval between attempts are measured
+/// with the given clock.
+///
+/// The target is resolved once, up front (waiting at most `timeout`), and
+/// the first address it resolves to is probed each time.
+///
+/// Errors are only returned if probing can't be done at all (e.g. the target
+/// can't be resolved, or a socket can't be created). Failed attempts are
+/// recorded in the report instead.
+pub fn probe_with<F: FnMut(&ProbeAttempt)>(
+    target: HostPort,
+    options: &ProbeOptions,
+    clock: &dyn Clock,
+    mut callback: F,
+) -> Result<ProbeReport> {
+    let addr = target.resolve(options.timeout, AddressFamily::SystemOrder)?[0];
+    let mut report = ProbeReport {
+        target,
+        addr,
+        protocol: options.protocol,
+        attempts: Vec::with_capacity(options.attempts),
+    };
+    for sequence in 0..options.attempts {
+        let start = clock.now_instant();
+        let outcome = match options.protocol {
+            ProbeProtocol::Tcp => probe_tcp(&addr, options.timeout),
+            ProbeProtocol::Udp => probe_udp(&addr, options.timeout)?,
+        };
+        let elapsed = clock.now_instant().saturating_duration_since(start);
+        let attempt = ProbeAttempt {
+            sequence,
+            latency: outcome.is_reachable().then_some(elapsed),
+            outcome,
+        };
+        callback(&attempt);
+        report.attempts.push(attempt);
+
+        if sequence + 1 < options.attempts {
+            if let Some(remaining) = options.interval.checked_sub(elapsed) {
+                clock.sleep(remaining);
+            }
+        }
+    }
+    Ok(report)
+}
@@ -0,0 +1,101 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::error::*;
+use std::fs;
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+
+/// UnixSocketListener listens on a Unix domain socket, and removes the socket
+/// file when it is dropped.
+#[derive(Debug)]
+pub struct UnixSocketListener {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl UnixSocketListener {
+    /// Bind a new listener to the given path.
+    ///
+    /// If a socket file already exists there, but nothing is listening on it
+    /// (e.g. because a previous process crashed without cleaning it up), the
+    /// stale file is removed first. It is an error if something *is*
+    /// listening on it, or if the path exists but isn't a socket.
+    pub fn bind<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        match fs::symlink_metadata(path) {
+            Ok(metadata) => {
+                if !metadata.file_type().is_socket() {
+                    return Err(Error::Precondition(format!(
+                        "refusing to replace non-socket file {}",
+                        path.display()
+                    )));
+                }
+                match UnixStream::connect(path) {
+                    Ok(_) => {
+                        return Err(Error::Precondition(format!(
+                            "socket {} is already in use",
+                            path.display()
+                        )))
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                        fs::remove_file(path)?
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        Ok(UnixSocketListener {
+            listener: UnixListener::bind(path)?,
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Returns the path this listener is bound to.
+    pub fn path(&self) -> &Path {
+        self.path.as_path()
+    }
+
+    /// Returns a reference to the underlying listener.
+    pub fn get_ref(&self) -> &UnixListener {
+        &self.listener
+    }
+
+    /// Wait for and accept a new connection.
+    pub fn accept(&self) -> Result<UnixStream> {
+        Ok(self.listener.accept()?.0)
+    }
+}
+
+impl Drop for UnixSocketListener {
+    fn drop(&mut self) {
+        // There isn't much we can do if this fails, and the next `bind` will
+        // clean up the stale file anyway.
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Connect to the Unix domain socket at the given path.
+pub fn connect<P: AsRef<Path>>(path: P) -> Result<UnixStream> {
+    let path = path.as_ref();
+    UnixStream::connect(path).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => Error::NotFound(format!("socket {}", path.display())),
+        _ => e.into(),
+    })
+}
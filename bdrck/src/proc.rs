@@ -0,0 +1,376 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::error::*;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// How often we check whether or not a child process has exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// ProcCommand describes a child process to run. It is a thin builder on top of
+/// `std::process::Command`, with a few extra options `run` knows how to deal
+/// with (stdin contents, timeouts, exit status checking).
+#[derive(Clone, Debug)]
+pub struct ProcCommand {
+    program: OsString,
+    args: Vec<OsString>,
+    envs: Vec<(OsString, OsString)>,
+    clear_env: bool,
+    cwd: Option<PathBuf>,
+    stdin: Option<Vec<u8>>,
+    timeout: Option<Duration>,
+    check: bool,
+}
+
+impl ProcCommand {
+    /// Start building a command which runs the given program. By default, the
+    /// command inherits this process's environment and working directory, has
+    /// no timeout, and a non-zero exit status is considered an error.
+    pub fn new<S: AsRef<OsStr>>(program: S) -> Self {
+        ProcCommand {
+            program: program.as_ref().to_os_string(),
+            args: Vec::new(),
+            envs: Vec::new(),
+            clear_env: false,
+            cwd: None,
+            stdin: None,
+            timeout: None,
+            check: true,
+        }
+    }
+
+    /// Add a single argument to the command.
+    pub fn arg<S: AsRef<OsStr>>(mut self, arg: S) -> Self {
+        self.args.push(arg.as_ref().to_os_string());
+        self
+    }
+
+    /// Add several arguments to the command.
+    pub fn args<I: IntoIterator<Item = S>, S: AsRef<OsStr>>(mut self, args: I) -> Self {
+        self.args
+            .extend(args.into_iter().map(|a| a.as_ref().to_os_string()));
+        self
+    }
+
+    /// Set an environment variable for the child process.
+    pub fn env<K: AsRef<OsStr>, V: AsRef<OsStr>>(mut self, key: K, value: V) -> Self {
+        self.envs
+            .push((key.as_ref().to_os_string(), value.as_ref().to_os_string()));
+        self
+    }
+
+    /// Set several environment variables for the child process.
+    pub fn envs<I, K, V>(mut self, envs: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        for (k, v) in envs {
+            self = self.env(k, v);
+        }
+        self
+    }
+
+    /// Don't inherit any of this process's environment variables; the child
+    /// will see only those set explicitly with `env` / `envs`.
+    pub fn clear_env(mut self) -> Self {
+        self.clear_env = true;
+        self
+    }
+
+    /// Run the child process in the given working directory.
+    pub fn current_dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.cwd = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Write the given bytes to the child's stdin. If this isn't set, the
+    /// child's stdin is connected to /dev/null (or equivalent).
+    pub fn stdin(mut self, stdin: Vec<u8>) -> Self {
+        self.stdin = Some(stdin);
+        self
+    }
+
+    /// Kill the child process if it hasn't exited after the given amount of
+    /// time.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Set whether or not a non-zero exit status should be turned into an
+    /// error. This is true by default.
+    pub fn check(mut self, check: bool) -> Self {
+        self.check = check;
+        self
+    }
+
+    fn to_command(&self) -> Command {
+        let mut cmd = Command::new(&self.program);
+        cmd.args(&self.args);
+        if self.clear_env {
+            cmd.env_clear();
+        }
+        cmd.envs(self.envs.iter().map(|(k, v)| (k, v)));
+        if let Some(cwd) = self.cwd.as_ref() {
+            cmd.current_dir(cwd);
+        }
+        cmd.stdin(match self.stdin {
+            None => Stdio::null(),
+            Some(_) => Stdio::piped(),
+        });
+        cmd.stdout(Stdio::piped());
+        cmd.stderr(Stdio::piped());
+        set_process_group(&mut cmd);
+        cmd
+    }
+}
+
+impl fmt::Display for ProcCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.program.to_string_lossy())?;
+        for arg in self.args.iter() {
+            let arg = arg.to_string_lossy();
+            if arg.is_empty() || arg.contains(char::is_whitespace) || arg.contains('"') {
+                write!(f, " {:?}", arg)?;
+            } else {
+                write!(f, " {}", arg)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Output is the result of running a child process to completion.
+#[derive(Clone, Debug)]
+pub struct Output {
+    /// The child's exit status.
+    pub status: ExitStatus,
+    /// Everything the child wrote to stdout.
+    pub stdout: Vec<u8>,
+    /// Everything the child wrote to stderr.
+    pub stderr: Vec<u8>,
+    /// How long the child took to run.
+    pub duration: Duration,
+}
+
+/// Identifies which of a child process's output streams a line came from.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OutputStream {
+    /// The child's standard output.
+    Stdout,
+    /// The child's standard error.
+    Stderr,
+}
+
+/// Put the child in its own process group, so on timeout we can kill any
+/// processes it has spawned too.
+#[cfg(not(target_os = "windows"))]
+fn set_process_group(cmd: &mut Command) {
+    use std::os::unix::process::CommandExt;
+    cmd.process_group(0);
+}
+
+#[cfg(target_os = "windows")]
+fn set_process_group(_: &mut Command) {}
+
+#[cfg(not(target_os = "windows"))]
+fn kill(child: &mut Child) {
+    // Since the child is the leader of its own process group, its pid is also
+    // the process group ID.
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+    let _ = child.kill();
+}
+
+#[cfg(target_os = "windows")]
+fn kill(child: &mut Child) {
+    let _ = child.kill();
+}
+
+type Line = (OutputStream, String);
+type LineCallback<'a> = &'a mut dyn FnMut(OutputStream, &str);
+type ReaderHandle = JoinHandle<std::io::Result<Vec<u8>>>;
+
+/// Read the given stream to completion on a separate thread. If a sender is
+/// given, each line is also sent to it as it is read.
+fn spawn_reader<R: Read + Send + 'static>(
+    stream: R,
+    which: OutputStream,
+    lines: Option<Sender<Line>>,
+) -> ReaderHandle {
+    thread::spawn(move || {
+        let mut reader = BufReader::new(stream);
+        let mut contents = Vec::new();
+        match lines {
+            None => {
+                reader.read_to_end(&mut contents)?;
+            }
+            Some(lines) => loop {
+                let start = contents.len();
+                if reader.read_until(b'\n', &mut contents)? == 0 {
+                    break;
+                }
+                let line = String::from_utf8_lossy(&contents[start..]);
+                let line = line.trim_end_matches(['\r', '\n']).to_owned();
+                // If the receiver is gone, we still want to read the rest of
+                // the output, so just ignore errors.
+                let _ = lines.send((which, line));
+            },
+        }
+        Ok(contents)
+    })
+}
+
+fn join_reader(handle: ReaderHandle) -> Result<Vec<u8>> {
+    match handle.join() {
+        Ok(r) => Ok(r?),
+        Err(_) => Err(Error::Internal(
+            "child process output reader thread panicked".to_string(),
+        )),
+    }
+}
+
+fn run_impl(cmd: ProcCommand, mut callback: Option<LineCallback<'_>>) -> Result<Output> {
+    debug!("running: {}", cmd);
+    let start = Instant::now();
+    let mut child = cmd.to_command().spawn()?;
+
+    // Stdin is written on its own thread, so a child which produces a lot of
+    // output before reading its input can't deadlock us.
+    let stdin_writer = match (child.stdin.take(), cmd.stdin.clone()) {
+        (Some(mut stdin), Some(contents)) => Some(thread::spawn(move || {
+            // The child may legitimately exit without reading all of its
+            // input, so ignore errors (e.g. EPIPE).
+            let _ = stdin.write_all(contents.as_slice());
+        })),
+        _ => None,
+    };
+
+    let (sender, receiver): (_, Receiver<Line>) = mpsc::channel();
+    let sender = callback.as_ref().map(|_| sender);
+    let stdout = spawn_reader(
+        child.stdout.take().unwrap(),
+        OutputStream::Stdout,
+        sender.clone(),
+    );
+    let stderr = spawn_reader(child.stderr.take().unwrap(), OutputStream::Stderr, sender);
+
+    let deadline = cmd.timeout.map(|t| start + t);
+    let timed_out = |child: &mut Child| {
+        kill(child);
+        let _ = child.wait();
+        Error::ProcessTimeout(format!(
+            "'{}' did not finish within {:?}",
+            cmd,
+            cmd.timeout.unwrap()
+        ))
+    };
+    // Wait a little while, passing along any lines of output which arrive in
+    // the meantime. Once both readers are done, the channel disconnects, and
+    // from then on we just sleep.
+    let mut lines_open = callback.is_some();
+    let mut poll = |callback: &mut Option<LineCallback<'_>>| match callback.as_mut() {
+        Some(callback) if lines_open => match receiver.recv_timeout(POLL_INTERVAL) {
+            Ok((which, line)) => callback(which, line.as_str()),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => lines_open = false,
+        },
+        _ => thread::sleep(POLL_INTERVAL),
+    };
+
+    let status = loop {
+        poll(&mut callback);
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if deadline.is_some_and(|d| Instant::now() >= d) {
+            return Err(timed_out(&mut child));
+        }
+    };
+
+    // The child's own children may have inherited its stdout / stderr, and
+    // keep them open after it exits. Waiting for them is still subject to the
+    // timeout; if it elapses, they're killed along with the rest of the
+    // child's process group.
+    while !stdout.is_finished() || !stderr.is_finished() {
+        poll(&mut callback);
+        if deadline.is_some_and(|d| Instant::now() >= d) {
+            return Err(timed_out(&mut child));
+        }
+    }
+
+    let stdout = join_reader(stdout)?;
+    let stderr = join_reader(stderr)?;
+    if let Some(stdin_writer) = stdin_writer {
+        let _ = stdin_writer.join();
+    }
+    if let Some(callback) = callback.as_mut() {
+        for (which, line) in receiver.try_iter() {
+            callback(which, line.as_str());
+        }
+    }
+
+    let duration = start.elapsed();
+    debug!("'{}' exited with {} after {:?}", cmd, status, duration);
+    if cmd.check && !status.success() {
+        return Err(Error::ProcessFailed {
+            status,
+            stderr: String::from_utf8_lossy(stderr.as_slice()).into_owned(),
+        });
+    }
+
+    Ok(Output {
+        status,
+        stdout,
+        stderr,
+        duration,
+    })
+}
+
+/// Run the given command to completion, capturing its output. Stdout and
+/// stderr are read concurrently, so a child which produces a lot of output on
+/// both can't deadlock.
+///
+/// If the command has a timeout and the child doesn't exit (and close its
+/// output streams, which any processes it spawned may have inherited) before
+/// it elapses, the child (and, on UNIX, its entire process group) is killed and
+/// `Error::ProcessTimeout` is returned. If the command checks its exit status
+/// (the default) and the child exits unsuccessfully, `Error::ProcessFailed`
+/// is returned, carrying the child's stderr.
+pub fn run(cmd: ProcCommand) -> Result<Output> {
+    run_impl(cmd, None)
+}
+
+/// This is identical to `run`, except each line the child writes to stdout or
+/// stderr is also passed to the given callback as it is produced (e.g., to
+/// display progress). Lines are passed without their trailing newline, and
+/// any invalid UTF-8 is replaced. The returned Output still contains the full,
+/// unmodified output.
+pub fn run_streaming<F: FnMut(OutputStream, &str)>(
+    cmd: ProcCommand,
+    mut callback: F,
+) -> Result<Output> {
+    run_impl(cmd, Some(&mut callback))
+}
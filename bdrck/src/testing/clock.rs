@@ -0,0 +1,111 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+/// A Clock is a source of the current time. Library components which need to
+/// know the current time (or which need to wait for some amount of time) can
+/// accept a Clock, so tests can substitute a `MockClock` instead of depending
+/// on (and waiting for) the real time.
+pub trait Clock: Send + Sync {
+    /// Return the current wall-clock time.
+    fn now_utc(&self) -> SystemTime;
+
+    /// Return the current monotonic time.
+    fn now_instant(&self) -> Instant;
+
+    /// Wait for the given amount of time to pass.
+    fn sleep(&self, duration: Duration);
+}
+
+/// SystemClock is the default `Clock` implementation, which just uses the real
+/// system time.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_utc(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn now_instant(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration)
+    }
+}
+
+struct MockClockState {
+    utc: SystemTime,
+    instant: Instant,
+}
+
+/// MockClock is a `Clock` whose time only changes when explicitly told to. In
+/// particular, `sleep` returns immediately, just advancing the clock by the
+/// given duration.
+///
+/// The monotonic time (`now_instant`) never goes backwards, even if the
+/// wall-clock time is `set` to some earlier time.
+pub struct MockClock {
+    state: Mutex<MockClockState>,
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        MockClock::new(SystemTime::UNIX_EPOCH)
+    }
+}
+
+impl MockClock {
+    /// Construct a new MockClock, whose wall-clock time is initially the given
+    /// time.
+    pub fn new(utc: SystemTime) -> Self {
+        MockClock {
+            state: Mutex::new(MockClockState {
+                utc,
+                instant: Instant::now(),
+            }),
+        }
+    }
+
+    /// Move this clock forward by the given amount of time.
+    pub fn advance(&self, duration: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.utc += duration;
+        state.instant += duration;
+    }
+
+    /// Set this clock's wall-clock time to the given time. This doesn't affect
+    /// the monotonic time.
+    pub fn set(&self, utc: SystemTime) {
+        self.state.lock().unwrap().utc = utc;
+    }
+}
+
+impl Clock for MockClock {
+    fn now_utc(&self) -> SystemTime {
+        self.state.lock().unwrap().utc
+    }
+
+    fn now_instant(&self) -> Instant {
+        self.state.lock().unwrap().instant
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration)
+    }
+}
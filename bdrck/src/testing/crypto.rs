@@ -0,0 +1,250 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::crypto::callback::CallbackKey;
+use crate::crypto::digest::Digest;
+use crate::crypto::key::{AbstractKey, Key, Nonce};
+use crate::crypto::keystore::KeyStore;
+use crate::crypto::secret::Secret;
+use crate::crypto::util::{randombytes_into, randombytes_into_secret};
+use crate::error::*;
+use halite_sys;
+use libc::c_ulonglong;
+use rand::RngCore;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const DETERMINISTIC_RNG_KEY_BYTES: usize = halite_sys::crypto_stream_chacha20_KEYBYTES as usize;
+const DETERMINISTIC_RNG_NONCE_BYTES: usize = halite_sys::crypto_stream_chacha20_NONCEBYTES as usize;
+const DETERMINISTIC_RNG_BLOCK_BYTES: usize = 64;
+
+const MOCK_HARDWARE_KEY_BYTES: usize = halite_sys::crypto_auth_hmacsha256_KEYBYTES as usize;
+const MOCK_HARDWARE_RESPONSE_BYTES: usize = halite_sys::crypto_auth_hmacsha256_BYTES as usize;
+
+/// DeterministicRng is an RNG whose output is entirely determined by the seed
+/// it was constructed with. Combined with constructors like
+/// `Key::new_from_rng`, `Nonce::from_rng`, and `KeyStore::new_from_rng`, this
+/// can be used to generate test fixtures which can be regenerated
+/// byte-for-byte.
+///
+/// The output is a ChaCha20 keystream keyed with the seed, so it is stable
+/// across versions of this library. But, since the seed is only 64 bits and is
+/// typically hard-coded, this is *not* safe to use for anything other than
+/// tests.
+pub struct DeterministicRng {
+    key: [u8; DETERMINISTIC_RNG_KEY_BYTES],
+    counter: u64,
+    block: [u8; DETERMINISTIC_RNG_BLOCK_BYTES],
+    offset: usize,
+}
+
+impl DeterministicRng {
+    /// Construct a new RNG which produces a fixed stream of bytes determined
+    /// by the given seed.
+    pub fn seeded(seed: u64) -> Self {
+        let mut key = [0_u8; DETERMINISTIC_RNG_KEY_BYTES];
+        key[..8].copy_from_slice(&seed.to_le_bytes());
+        DeterministicRng {
+            key,
+            counter: 0,
+            block: [0; DETERMINISTIC_RNG_BLOCK_BYTES],
+            offset: DETERMINISTIC_RNG_BLOCK_BYTES,
+        }
+    }
+
+    fn refill(&mut self) {
+        let zeros = [0_u8; DETERMINISTIC_RNG_BLOCK_BYTES];
+        let nonce = [0_u8; DETERMINISTIC_RNG_NONCE_BYTES];
+        debug_assert!(crate::init_done());
+        unsafe {
+            halite_sys::crypto_stream_chacha20_xor_ic(
+                self.block.as_mut_ptr(),
+                zeros.as_ptr(),
+                DETERMINISTIC_RNG_BLOCK_BYTES as c_ulonglong,
+                nonce.as_ptr(),
+                self.counter,
+                self.key.as_ptr(),
+            );
+        }
+        self.counter += 1;
+        self.offset = 0;
+    }
+}
+
+impl RngCore for DeterministicRng {
+    fn next_u32(&mut self) -> u32 {
+        let mut buf = [0_u8; 4];
+        self.fill_bytes(&mut buf);
+        u32::from_le_bytes(buf)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let mut buf = [0_u8; 8];
+        self.fill_bytes(&mut buf);
+        u64::from_le_bytes(buf)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        let mut filled = 0;
+        while filled < dest.len() {
+            if self.offset == DETERMINISTIC_RNG_BLOCK_BYTES {
+                self.refill();
+            }
+            let n = (dest.len() - filled).min(DETERMINISTIC_RNG_BLOCK_BYTES - self.offset);
+            dest[filled..filled + n].copy_from_slice(&self.block[self.offset..self.offset + n]);
+            filled += n;
+            self.offset += n;
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> std::result::Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+/// Generate a KeyStore (and the single key it is wrapped with) entirely from
+/// the given seed. The same seed always produces a KeyStore which serializes
+/// to exactly the same bytes, so this is useful for generating golden fixtures
+/// of the KeyStore serialization format.
+pub fn golden_key_store(seed: u64) -> Result<(Key, KeyStore)> {
+    let mut rng = DeterministicRng::seeded(seed);
+    let mut keystore = KeyStore::new_from_rng(&mut rng)?;
+    let key = Key::new_from_rng(&mut rng)?;
+    keystore.add_key_with_rng(&key, &mut rng)?;
+    Ok((key, keystore))
+}
+
+struct MockHardwareKeyState {
+    secret: Secret,
+    delay: Duration,
+    present: AtomicBool,
+    prompts: AtomicU64,
+    on_prompt: Mutex<Option<Box<dyn Fn() + Send + Sync>>>,
+}
+
+impl MockHardwareKeyState {
+    fn respond(&self, challenge: &[u8]) -> Result<Vec<u8>> {
+        self.prompts.fetch_add(1, Ordering::SeqCst);
+        if let Some(on_prompt) = self.on_prompt.lock().unwrap().as_ref() {
+            on_prompt();
+        }
+        std::thread::sleep(self.delay);
+
+        if !self.present.load(Ordering::SeqCst) {
+            return Err(Error::Crypto(
+                "timed out waiting for user presence".to_string(),
+            ));
+        }
+
+        let mut response = vec![0_u8; MOCK_HARDWARE_RESPONSE_BYTES];
+        debug_assert!(crate::init_done());
+        unsafe {
+            halite_sys::crypto_auth_hmacsha256(
+                response.as_mut_ptr(),
+                challenge.as_ptr(),
+                challenge.len() as c_ulonglong,
+                self.secret.slice_ptr(),
+            );
+        }
+        Ok(response)
+    }
+}
+
+/// MockHardwareKey simulates a hardware-backed key (e.g. a security key which
+/// the user has to touch) for testing. Each time it is used to encrypt or
+/// decrypt, it "prompts" the user, and then takes some time to respond, like a
+/// real hardware token would.
+///
+/// Internally, this is a `CallbackKey`, so it supports exactly the same set of
+/// operations.
+pub struct MockHardwareKey {
+    state: Arc<MockHardwareKeyState>,
+    inner: CallbackKey,
+}
+
+impl MockHardwareKey {
+    /// Construct a new mock hardware key with random key material, which takes
+    /// the given amount of time to respond to each prompt.
+    pub fn new(delay: Duration) -> Result<Self> {
+        let mut secret = Secret::with_len(MOCK_HARDWARE_KEY_BYTES)?;
+        randombytes_into_secret(&mut secret);
+        let mut id = [0_u8; 16];
+        randombytes_into(&mut id);
+
+        let state = Arc::new(MockHardwareKeyState {
+            secret,
+            delay,
+            present: AtomicBool::new(true),
+            prompts: AtomicU64::new(0),
+            on_prompt: Mutex::new(None),
+        });
+        let derive_state = state.clone();
+        Ok(MockHardwareKey {
+            state,
+            inner: CallbackKey::new(
+                Digest::from_bytes(&id),
+                Box::new(move |challenge| derive_state.respond(challenge)),
+            ),
+        })
+    }
+
+    /// Set a callback which is called each time the user would be prompted to
+    /// e.g. touch their security key.
+    pub fn set_on_prompt(&self, on_prompt: Box<dyn Fn() + Send + Sync>) {
+        *self.state.on_prompt.lock().unwrap() = Some(on_prompt);
+    }
+
+    /// Set whether or not the simulated user responds to prompts. If not, each
+    /// operation which prompts will fail, as if it had timed out.
+    pub fn set_present(&self, present: bool) {
+        self.state.present.store(present, Ordering::SeqCst);
+    }
+
+    /// Return the number of times this key has prompted the user so far.
+    pub fn prompt_count(&self) -> u64 {
+        self.state.prompts.load(Ordering::SeqCst)
+    }
+}
+
+impl AbstractKey for MockHardwareKey {
+    type Error = Error;
+
+    fn get_digest(&self) -> Digest {
+        self.inner.get_digest()
+    }
+
+    fn serialize(&self) -> Result<Secret> {
+        self.inner.serialize()
+    }
+
+    fn deserialize(_: Secret) -> Result<Self> {
+        Err(Error::Unsupported(
+            "MockHardwareKey can't be deserialized".to_string(),
+        ))
+    }
+
+    fn encrypt(
+        &self,
+        plaintext: &Secret,
+        nonce: Option<Nonce>,
+    ) -> Result<(Option<Nonce>, Vec<u8>)> {
+        self.inner.encrypt(plaintext, nonce)
+    }
+
+    fn decrypt(&self, nonce: Option<&Nonce>, ciphertext: &[u8]) -> Result<Secret> {
+        self.inner.decrypt(nonce, ciphertext)
+    }
+}
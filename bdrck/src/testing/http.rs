@@ -12,100 +12,225 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+#[cfg(feature = "crypto")]
+use crate::crypto::digest::Digest;
 use crate::error::*;
-use crate::http::client::AbstractClient;
-use crate::http::recording::{RecordedRequest, Recording, RecordingEntry};
-use crate::http::types::{HttpData, ResponseMetadata};
+use crate::http::body::RequestBody;
+use crate::http::client::{request_builder, AbstractClient};
+use crate::http::recording::{
+    current_stream_tag, BodyCapture, RecordedRequest, RecordedResponse, Recording, RecordingEntry,
+    StreamIds,
+};
+use crate::http::types::{HeaderMap, HttpData, ResponseMetadata};
+#[cfg(feature = "crypto")]
+use data_encoding::HEXLOWER;
 use reqwest::Client as InnerClient;
-use reqwest::{Request, RequestBuilder, Url};
+use reqwest::{Method, Request, RequestBuilder, Url};
+use serde::Serialize;
 use serde_json;
 use std::collections::VecDeque;
 use std::sync::Mutex;
 
+/// ReplayMode controls how strictly a `TestStubClient` enforces the order of
+/// the interactions in its recordings.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ReplayMode {
+    /// Each request must match the next recorded interaction. If it doesn't,
+    /// it may instead match the next remaining interaction in the request's
+    /// logical stream (see `RecordingEntry::stream_id`), so concurrent streams
+    /// can be interleaved arbitrarily.
+    #[default]
+    Ordered,
+    /// Like `Ordered`, but if neither of those match, a request may match any
+    /// remaining interaction.
+    Unordered,
+}
+
 /// TestStubClient provides an HTTP-client-like interface for unit testing.
 /// Instead of interacting with real servers, it loads a previously recorded
 /// HTTP session and verifies application behavior against it.
+///
+/// TestStubClient is `Sync`, so it can be shared between threads which issue
+/// requests concurrently. See `ReplayMode` for how such requests are matched.
 pub struct TestStubClient {
     inner: InnerClient,
+    mode: ReplayMode,
     recordings: Mutex<VecDeque<Recording>>,
+    stream_ids: StreamIds,
 }
 
 impl TestStubClient {
-    /// Create a new, empty test stub client.
+    /// Create a new, empty test stub client, which replays its recordings in
+    /// `ReplayMode::Ordered`.
     pub fn new() -> Self {
+        Self::new_with_mode(ReplayMode::Ordered)
+    }
+
+    /// Create a new, empty test stub client, which replays its recordings in
+    /// the given mode.
+    pub fn new_with_mode(mode: ReplayMode) -> Self {
         TestStubClient {
             inner: InnerClient::new(),
+            mode,
             recordings: Mutex::new(VecDeque::new()),
+            stream_ids: StreamIds::default(),
         }
     }
 
-    /// Push the given recording (the serialized bytes) into this test stub.
+    /// Push the given recording (the serialized bytes, in any format supported
+    /// by `Recording::load_slice`) into this test stub.
     pub fn push_recording(&self, recording: &[u8]) -> Result<&Self> {
         self.recordings
             .lock()
             .unwrap()
-            .push_back(serde_json::from_slice(recording)?);
+            .push_back(Recording::load_slice(recording)?);
         Ok(self)
     }
-}
 
-impl AbstractClient for TestStubClient {
-    fn execute(&self, request: Request) -> Result<(ResponseMetadata, Vec<u8>)> {
-        // Get the next RecordingEntry out, and pop empty Recordings (if any).
+    /// Find the interaction in the given (current) recording which the given
+    /// actual request should consume, according to this client's mode.
+    fn find(
+        &self,
+        recording: &Recording,
+        assert_req: &RecordedRequest,
+        stream_id: &str,
+    ) -> Option<usize> {
+        // Values scrubbed from the recording match anything.
+        if recording
+            .0
+            .front()
+            .is_some_and(|entry| entry.req.matches(assert_req))
+        {
+            return Some(0);
+        }
 
-        let entry: RecordingEntry;
-        let pop: bool;
-        let mut recordings = self.recordings.lock().unwrap();
+        if let Some(index) = recording
+            .0
+            .iter()
+            .position(|entry| entry.stream_id.as_deref() == Some(stream_id))
+        {
+            if recording.0[index].req.matches(assert_req) {
+                return Some(index);
+            }
+        }
+
+        match self.mode {
+            ReplayMode::Ordered => None,
+            ReplayMode::Unordered => recording
+                .0
+                .iter()
+                .position(|entry| entry.req.matches(assert_req)),
+        }
+    }
 
+    /// Check the given actual request against the remaining recorded requests,
+    /// and return the matching recorded response.
+    fn replay(&self, assert_req: RecordedRequest) -> Result<RecordedResponse> {
+        let stream_id = self.stream_ids.current();
+        let mut recordings = self.recordings.lock().unwrap();
+        // Skip empty Recordings (e.g. of sessions which made no requests).
+        while recordings
+            .front()
+            .is_some_and(|recording| recording.0.is_empty())
         {
-            let recording = match recordings.front_mut() {
-                None => {
-                    panic!("Unexpected call to AbstractClient::execute (no more mock recordings)")
-                }
-                Some(recording) => recording,
-            };
-            entry = recording.0.pop_front().unwrap();
-            pop = recording.0.is_empty();
+            recordings.pop_front();
         }
 
-        if pop {
+        let index = recordings
+            .front()
+            .and_then(|recording| self.find(recording, &assert_req, &stream_id));
+        let entry = match index {
+            Some(index) => recordings.front_mut().unwrap().0.remove(index).unwrap(),
+            None => {
+                let remaining: Vec<String> = recordings
+                    .iter()
+                    .flat_map(|r| r.0.iter())
+                    .map(|entry| {
+                        format!(
+                            "[stream {}] {} {}",
+                            entry.stream_id.as_deref().unwrap_or("none"),
+                            entry.req.method,
+                            entry.req.url
+                        )
+                    })
+                    .collect();
+                return Err(Error::NotFound(format!(
+                    "HTTP request {:#?} (stream {}) doesn't match any remaining recorded interaction; remaining: {:#?}",
+                    assert_req, stream_id, remaining
+                )));
+            }
+        };
+
+        // Pop empty Recordings (if any).
+        if recordings
+            .front()
+            .is_some_and(|recording| recording.0.is_empty())
+        {
             recordings.pop_front();
         }
 
-        // Make sure the request matches what we're expecting.
-        let assert_req = RecordedRequest::from(&request);
-        assert_eq!(
-            entry.req, assert_req,
-            "HTTP server expected {:#?}, got {:#?}",
-            entry.req, assert_req
-        );
+        Ok(entry.res)
+    }
+}
 
-        Ok((
-            entry.res.metadata,
-            match entry.res.body {
-                HttpData::Text(text) => text.into_bytes(),
-                HttpData::Binary(bytes) => bytes,
-            },
-        ))
+impl AbstractClient for TestStubClient {
+    fn execute(&self, request: Request) -> Result<(ResponseMetadata, Vec<u8>)> {
+        let res = self.replay(RecordedRequest::from(&request))?;
+        Ok((res.metadata, res.body.into_bytes()))
+    }
+
+    /// Large response bodies are recorded only as a digest, in which case the
+    /// replayed body is empty, but the recorded digest is still returned, so
+    /// e.g. `download_to` can be tested against it.
+    #[cfg(feature = "crypto")]
+    fn execute_digested(
+        &self,
+        request: Request,
+        on_chunk: &mut dyn FnMut(&[u8]) -> Result<()>,
+    ) -> Result<(ResponseMetadata, Digest)> {
+        let res = self.replay(RecordedRequest::from(&request))?;
+        let body = res.body.into_bytes();
+        on_chunk(body.as_slice())?;
+        let digest = match res.body_digest {
+            None => Digest::from_bytes(body.as_slice()),
+            Some(recorded) => {
+                Digest::from_slice(HEXLOWER.decode(recorded.sha512.as_bytes())?.as_slice())?
+            }
+        };
+        Ok((res.metadata, digest))
+    }
+
+    fn execute_body(
+        &self,
+        mut request: Request,
+        body: RequestBody,
+    ) -> Result<(ResponseMetadata, Vec<u8>)> {
+        // Nothing is really sent, so just read the body in its entirety.
+        let mut capture = BodyCapture::default();
+        body.consume(&mut request, Box::new(|data| capture.update(data)))?;
+        let mut assert_req = RecordedRequest::from(&request);
+        assert_req.set_body_capture(capture);
+        let res = self.replay(assert_req)?;
+        Ok((res.metadata, res.body.into_bytes()))
     }
 
     fn get(&self, url: Url) -> RequestBuilder {
-        self.inner.get(url)
+        request_builder(&self.inner, Method::GET, url)
     }
     fn post(&self, url: Url) -> RequestBuilder {
-        self.inner.post(url)
+        request_builder(&self.inner, Method::POST, url)
     }
     fn put(&self, url: Url) -> RequestBuilder {
-        self.inner.put(url)
+        request_builder(&self.inner, Method::PUT, url)
     }
     fn patch(&self, url: Url) -> RequestBuilder {
-        self.inner.patch(url)
+        request_builder(&self.inner, Method::PATCH, url)
     }
     fn delete(&self, url: Url) -> RequestBuilder {
-        self.inner.delete(url)
+        request_builder(&self.inner, Method::DELETE, url)
     }
     fn head(&self, url: Url) -> RequestBuilder {
-        self.inner.head(url)
+        request_builder(&self.inner, Method::HEAD, url)
     }
 }
 
@@ -127,3 +252,400 @@ impl Drop for TestStubClient {
         }
     }
 }
+
+/// How many times an `Expectation` must be matched.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Times {
+    Exactly(usize),
+    Any,
+}
+
+/// Expectation describes a single request a `FakeClient` expects to receive,
+/// and the response it should return. Expectations are constructed with
+/// `FakeClient::expect`, and then refined with the builder-style methods
+/// below.
+#[derive(Debug)]
+pub struct Expectation {
+    method: Method,
+    path: String,
+    query: Vec<(String, String)>,
+    headers: Vec<(String, String)>,
+    body: Option<String>,
+    times: Times,
+    calls: usize,
+    status: u16,
+    response_headers: HeaderMap,
+    response_body: Vec<u8>,
+}
+
+impl Expectation {
+    fn new(method: Method, path: &str) -> Self {
+        Expectation {
+            method,
+            path: path.to_owned(),
+            query: Vec::new(),
+            headers: Vec::new(),
+            body: None,
+            times: Times::Exactly(1),
+            calls: 0,
+            status: 200,
+            response_headers: HeaderMap::new(),
+            response_body: Vec::new(),
+        }
+    }
+
+    /// Only match requests with the given query parameter. Other query
+    /// parameters are ignored.
+    pub fn with_query(&mut self, name: &str, value: &str) -> &mut Self {
+        self.query.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Only match requests with the given header. Other headers are ignored.
+    pub fn with_header(&mut self, name: &str, value: &str) -> &mut Self {
+        self.headers
+            .push((name.to_ascii_lowercase(), value.to_owned()));
+        self
+    }
+
+    /// Only match requests with the given body. JSON bodies are compared
+    /// structurally, the same way recorded requests are.
+    pub fn with_body(&mut self, body: &str) -> &mut Self {
+        self.body = Some(body.to_owned());
+        self
+    }
+
+    /// Expect exactly `n` matching requests.
+    pub fn times(&mut self, n: usize) -> &mut Self {
+        self.times = Times::Exactly(n);
+        self
+    }
+
+    /// Expect any number of matching requests, including none at all.
+    pub fn any_times(&mut self) -> &mut Self {
+        self.times = Times::Any;
+        self
+    }
+
+    /// Respond to matching requests with the given HTTP status code.
+    pub fn respond(&mut self, status: u16) -> &mut Self {
+        self.status = status;
+        self
+    }
+
+    /// Add the given header to the response.
+    pub fn header(&mut self, name: &str, value: &str) -> &mut Self {
+        self.response_headers
+            .entry(name.to_ascii_lowercase())
+            .or_default()
+            .push(HttpData::Text(value.to_owned()));
+        self
+    }
+
+    /// Respond with the given raw body.
+    pub fn body<B: Into<Vec<u8>>>(&mut self, body: B) -> &mut Self {
+        self.response_body = body.into();
+        self
+    }
+
+    /// Respond with the given value serialized as JSON, and set the response's
+    /// Content-Type accordingly.
+    pub fn json<T: Serialize>(&mut self, value: &T) -> &mut Self {
+        self.response_headers.insert(
+            "content-type".to_owned(),
+            vec![HttpData::Text("application/json".to_owned())],
+        );
+        self.response_body = serde_json::to_vec(value).expect("Serializing JSON body failed");
+        self
+    }
+
+    fn has_capacity(&self) -> bool {
+        match self.times {
+            Times::Exactly(n) => self.calls < n,
+            Times::Any => true,
+        }
+    }
+
+    fn is_satisfied(&self) -> bool {
+        match self.times {
+            Times::Exactly(n) => self.calls == n,
+            Times::Any => true,
+        }
+    }
+
+    /// Build the request this expectation expects, given the actual request:
+    /// anything this expectation doesn't constrain is copied from `actual`.
+    /// This way, requests are matched (and mismatches are described) by the
+    /// same logic which is used to replay recordings.
+    fn expected_request(&self, actual: &RecordedRequest) -> RecordedRequest {
+        let url = match Url::parse(&actual.url) {
+            Ok(mut url) => {
+                url.set_path(&self.path);
+                let mut pairs: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+                for (n, v) in self.query.iter() {
+                    if pairs.iter().any(|(an, av)| an == n && av == v) {
+                        continue;
+                    }
+                    match pairs.iter_mut().find(|(an, _)| an == n) {
+                        Some(pair) => pair.1 = v.clone(),
+                        None => pairs.push((n.clone(), v.clone())),
+                    }
+                }
+                match pairs.is_empty() {
+                    true => url.set_query(None),
+                    false => {
+                        url.query_pairs_mut().clear().extend_pairs(pairs);
+                    }
+                }
+                url.to_string()
+            }
+            Err(_) => self.path.clone(),
+        };
+
+        let mut headers = actual.headers.clone();
+        for (n, v) in self.headers.iter() {
+            let value = HttpData::Text(v.clone());
+            if !actual
+                .headers
+                .get(n)
+                .is_some_and(|values| values.contains(&value))
+            {
+                headers.entry(n.clone()).or_default().push(value);
+            }
+        }
+
+        RecordedRequest {
+            method: self.method.to_string(),
+            url,
+            headers,
+            body: self.body.clone().or_else(|| actual.body.clone()),
+            body_digest: actual.body_digest.clone(),
+            proxy: actual.proxy.clone(),
+        }
+    }
+
+    fn matches(&self, actual: &RecordedRequest) -> bool {
+        self.expected_request(actual).matches(actual)
+    }
+
+    /// Describe this expectation, e.g. for error messages.
+    fn describe(&self) -> String {
+        let mut description = format!("{} {}", self.method, self.path);
+        for (n, v) in self.query.iter() {
+            description.push_str(&format!(" [query {}={}]", n, v));
+        }
+        for (n, v) in self.headers.iter() {
+            description.push_str(&format!(" [header {}: {}]", n, v));
+        }
+        if let Some(body) = self.body.as_ref() {
+            description.push_str(&format!(" [body {}]", body));
+        }
+        description
+    }
+
+    fn response(&self) -> (ResponseMetadata, Vec<u8>) {
+        (
+            ResponseMetadata {
+                status: self.status,
+                headers: self.response_headers.clone(),
+                from_cache: false,
+                timings: None,
+            },
+            self.response_body.clone(),
+        )
+    }
+}
+
+#[derive(Default)]
+struct FakeClientState {
+    expectations: Vec<Expectation>,
+    unexpected: Vec<RecordedRequest>,
+    session: VecDeque<RecordingEntry>,
+}
+
+/// FakeClient provides an HTTP-client-like interface for unit testing, like
+/// `TestStubClient`. Instead of replaying a previously recorded session,
+/// though, the requests it expects and the responses it returns are declared
+/// in code, via `expect`.
+///
+/// Requests which don't match any (remaining) expectation result in an error.
+/// Once the code under test is done, call `verify` to check that every
+/// expectation was met and no unexpected requests were made.
+pub struct FakeClient {
+    inner: InnerClient,
+    ordered: bool,
+    state: Mutex<FakeClientState>,
+}
+
+impl FakeClient {
+    /// Create a new FakeClient, whose expectations can be met in any order.
+    pub fn new() -> Self {
+        FakeClient {
+            inner: InnerClient::new(),
+            ordered: false,
+            state: Mutex::new(FakeClientState::default()),
+        }
+    }
+
+    /// Create a new FakeClient, whose expectations must be met in the order
+    /// they were declared. An expectation with `any_times` matches requests
+    /// until a request doesn't match it, at which point the next expectation
+    /// is considered.
+    pub fn new_ordered() -> Self {
+        FakeClient {
+            ordered: true,
+            ..Self::new()
+        }
+    }
+
+    /// Expect a request with the given method, for the given URL path. The
+    /// returned `Expectation` can be used to refine the match, or to declare
+    /// the response. By default, the request is expected exactly once, and
+    /// the response is an empty 200.
+    pub fn expect(&mut self, method: Method, path: &str) -> &mut Expectation {
+        let expectations = &mut self.state.get_mut().unwrap().expectations;
+        expectations.push(Expectation::new(method, path));
+        expectations.last_mut().unwrap()
+    }
+
+    /// Check that every expectation was met, and that no unexpected requests
+    /// were made. If not, the returned error describes what went wrong.
+    pub fn verify(&self) -> Result<()> {
+        let state = self.state.lock().unwrap();
+        let mut problems = Vec::new();
+        for e in state.expectations.iter().filter(|e| !e.is_satisfied()) {
+            if let Times::Exactly(n) = e.times {
+                problems.push(format!(
+                    "unmet expectation {}: expected {} call(s), got {}",
+                    e.describe(),
+                    n,
+                    e.calls
+                ));
+            }
+        }
+        for actual in state.unexpected.iter() {
+            problems.push(format!(
+                "unexpected request {} {}",
+                actual.method, actual.url
+            ));
+            // Describe how the request differs from the closest expectation.
+            let closest = state
+                .expectations
+                .iter()
+                .map(|e| (e, e.expected_request(actual).diff(actual)))
+                .min_by_key(|(_, diff)| diff.len());
+            if let Some((e, diff)) = closest {
+                problems.push(format!("  closest expectation: {}", e.describe()));
+                match diff.is_empty() {
+                    true => problems.push(
+                        "    (it matches, but was out of order, or already called enough times)"
+                            .to_owned(),
+                    ),
+                    false => problems.extend(diff.iter().map(|line| format!("    {}", line))),
+                }
+            }
+        }
+        if problems.is_empty() {
+            return Ok(());
+        }
+        Err(Error::Precondition(format!(
+            "HTTP expectations not met:\n{}",
+            problems.join("\n")
+        )))
+    }
+
+    /// Convert the session this client has served so far into a `Recording`,
+    /// which can be saved (see `Recording::flush`) and later replayed with a
+    /// `TestStubClient`.
+    pub fn into_recording(self) -> Recording {
+        Recording(self.state.into_inner().unwrap().session)
+    }
+
+    fn handle(&self, actual: RecordedRequest) -> Result<(ResponseMetadata, Vec<u8>)> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+
+        let index = match self.ordered {
+            false => state
+                .expectations
+                .iter()
+                .position(|e| e.has_capacity() && e.matches(&actual)),
+            true => {
+                let mut index = None;
+                for (i, e) in state.expectations.iter().enumerate() {
+                    if e.has_capacity() && e.matches(&actual) {
+                        index = Some(i);
+                        break;
+                    }
+                    // Unsatisfied expectations must be met before later ones.
+                    if !e.is_satisfied() {
+                        break;
+                    }
+                }
+                index
+            }
+        };
+
+        let index = match index {
+            None => {
+                let err = Error::NotFound(format!("unexpected HTTP request: {:#?}", actual));
+                state.unexpected.push(actual);
+                return Err(err);
+            }
+            Some(index) => index,
+        };
+
+        let expectation = &mut state.expectations[index];
+        expectation.calls += 1;
+        let res = expectation.response();
+        state.session.push_back(RecordingEntry {
+            req: actual,
+            res: RecordedResponse::from(&res),
+            stream_id: current_stream_tag(),
+        });
+        Ok(res)
+    }
+}
+
+impl Default for FakeClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AbstractClient for FakeClient {
+    fn execute(&self, request: Request) -> Result<(ResponseMetadata, Vec<u8>)> {
+        self.handle(RecordedRequest::from(&request))
+    }
+
+    fn execute_body(
+        &self,
+        mut request: Request,
+        body: RequestBody,
+    ) -> Result<(ResponseMetadata, Vec<u8>)> {
+        let mut capture = BodyCapture::default();
+        body.consume(&mut request, Box::new(|data| capture.update(data)))?;
+        let mut actual = RecordedRequest::from(&request);
+        actual.set_body_capture(capture);
+        self.handle(actual)
+    }
+
+    fn get(&self, url: Url) -> RequestBuilder {
+        self.inner.get(url)
+    }
+    fn post(&self, url: Url) -> RequestBuilder {
+        self.inner.post(url)
+    }
+    fn put(&self, url: Url) -> RequestBuilder {
+        self.inner.put(url)
+    }
+    fn patch(&self, url: Url) -> RequestBuilder {
+        self.inner.patch(url)
+    }
+    fn delete(&self, url: Url) -> RequestBuilder {
+        self.inner.delete(url)
+    }
+    fn head(&self, url: Url) -> RequestBuilder {
+        self.inner.head(url)
+    }
+}
@@ -0,0 +1,196 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record as SpanRecord};
+use tracing::subscriber::Interest;
+use tracing::{Dispatch, Event, Level, Metadata, Subscriber};
+
+/// Record is a single captured log event. Everything is formatted eagerly when
+/// the event is captured, since events only borrow their data.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Record {
+    /// The event's level.
+    pub level: Level,
+    /// The event's target (by default, the module path it was logged from).
+    pub target: String,
+    /// The path of the module the event was logged from, if known.
+    pub module: Option<String>,
+    /// The event's formatted message, followed by any other fields it has as
+    /// ` key=value` pairs.
+    pub message: String,
+}
+
+/// CapturedLogs is the list of events logged inside a call to `capture`.
+#[derive(Clone, Debug, Default)]
+pub struct CapturedLogs {
+    records: Vec<Record>,
+}
+
+impl CapturedLogs {
+    /// Return all of the captured events, in the order they were logged.
+    pub fn records(&self) -> &[Record] {
+        &self.records
+    }
+
+    /// Returns whether or not an event was logged at exactly the given level,
+    /// whose message contains the given substring.
+    pub fn contains(&self, level: Level, substring: &str) -> bool {
+        self.records
+            .iter()
+            .any(|r| r.level == level && r.message.contains(substring))
+    }
+
+    /// Panic unless `contains` returns true for the given level and
+    /// substring.
+    pub fn assert_contains(&self, level: Level, substring: &str) {
+        assert!(
+            self.contains(level, substring),
+            "expected a {} event containing {:?}, but captured: {:#?}",
+            level,
+            substring,
+            self.records
+        );
+    }
+
+    /// Panic if any event was logged at the given level or any more severe
+    /// level (e.g., for `Level::WARN`, any warnings or errors).
+    pub fn assert_none_at_or_above(&self, level: Level) {
+        // Note that tracing orders levels by verbosity, so more severe levels
+        // compare as *less than* less severe ones.
+        let found: Vec<&Record> = self.records.iter().filter(|r| r.level <= level).collect();
+        assert!(
+            found.is_empty(),
+            "expected no events at or above {}, but captured: {:#?}",
+            level,
+            found
+        );
+    }
+}
+
+struct MessageVisitor<'a> {
+    message: &'a mut String,
+    fields: Vec<String>,
+}
+
+impl<'a> Visit for MessageVisitor<'a> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message.push_str(value);
+        } else {
+            self.fields.push(format!("{}={}", field.name(), value));
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.message.push_str(&format!("{:?}", value));
+        } else {
+            self.fields.push(format!("{}={:?}", field.name(), value));
+        }
+    }
+}
+
+/// CaptureSubscriber records every event into a list, and passes everything
+/// (events and spans) through to whichever subscriber was the default when
+/// capturing started, so any logger the application installed keeps working.
+struct CaptureSubscriber {
+    records: Arc<Mutex<Vec<Record>>>,
+    inner: Dispatch,
+}
+
+impl Subscriber for CaptureSubscriber {
+    fn register_callsite(&self, _: &'static Metadata<'static>) -> Interest {
+        // Interest is cached globally, but we only capture on some threads
+        // some of the time, so we need to be asked every time.
+        Interest::sometimes()
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.is_event() || self.inner.enabled(metadata)
+    }
+
+    fn new_span(&self, span: &Attributes<'_>) -> Id {
+        self.inner.new_span(span)
+    }
+
+    fn record(&self, span: &Id, values: &SpanRecord<'_>) {
+        self.inner.record(span, values)
+    }
+
+    fn record_follows_from(&self, span: &Id, follows: &Id) {
+        self.inner.record_follows_from(span, follows)
+    }
+
+    fn event(&self, event: &Event<'_>) {
+        let metadata = event.metadata();
+        let mut message = String::new();
+        let mut visitor = MessageVisitor {
+            message: &mut message,
+            fields: Vec::new(),
+        };
+        event.record(&mut visitor);
+        for field in visitor.fields {
+            message.push(' ');
+            message.push_str(&field);
+        }
+        self.records.lock().unwrap().push(Record {
+            level: *metadata.level(),
+            target: metadata.target().to_string(),
+            module: metadata.module_path().map(str::to_string),
+            message,
+        });
+
+        if self.inner.enabled(metadata) {
+            self.inner.event(event);
+        }
+    }
+
+    fn enter(&self, span: &Id) {
+        self.inner.enter(span)
+    }
+
+    fn exit(&self, span: &Id) {
+        self.inner.exit(span)
+    }
+
+    fn clone_span(&self, id: &Id) -> Id {
+        self.inner.clone_span(id)
+    }
+
+    fn try_close(&self, id: Id) -> bool {
+        self.inner.try_close(id)
+    }
+}
+
+/// Call the given function, and return every event it logs (via `tracing`).
+///
+/// Only events logged on the calling thread are captured, so tests running
+/// in parallel don't see each other's events (but, by the same token, events
+/// logged by threads the function spawns aren't captured either). Captured
+/// events are also passed through to the subscriber which was already in
+/// effect (e.g. one the application installed globally), so capturing
+/// doesn't change what is actually logged.
+pub fn capture<F: FnOnce()>(f: F) -> CapturedLogs {
+    let records = Arc::new(Mutex::new(Vec::new()));
+    let subscriber = CaptureSubscriber {
+        records: records.clone(),
+        inner: tracing::dispatcher::get_default(Dispatch::clone),
+    };
+    tracing::subscriber::with_default(subscriber, f);
+
+    let records = std::mem::take(&mut *records.lock().unwrap());
+    CapturedLogs { records }
+}
@@ -12,12 +12,30 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+/// clock provides an abstraction over the current time, so code which depends
+/// on it can be tested deterministically. Unlike the rest of this module, this
+/// is available even without the "testing" feature, since non-test code needs
+/// to accept a `Clock` to be testable.
+pub mod clock;
+/// crypto provides testing support for the crypto submodule.
+#[cfg(all(feature = "testing", feature = "crypto"))]
+pub mod crypto;
 /// fn_instrumentation provides utilities for instrumenting function calls
 /// during unit tests.
+#[cfg(feature = "testing")]
 pub mod fn_instrumentation;
 /// http provides testing support for the http submodule.
-#[cfg(debug_assertions)]
+#[cfg(all(feature = "testing", debug_assertions))]
 pub mod http;
+/// logging provides a way to capture log output in unit tests, to make
+/// assertions about it.
+#[cfg(feature = "testing")]
+pub mod logging;
+/// prop provides a lightweight property-based testing framework, for checking
+/// invariants (e.g. round-tripping) against many generated inputs.
+#[cfg(feature = "testing")]
+pub mod prop;
 /// temp provides utilities for creating temporary files or directories in unit
 /// tests.
+#[cfg(feature = "testing")]
 pub mod temp;
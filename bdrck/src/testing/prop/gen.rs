@@ -0,0 +1,262 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::testing::prop::{Gen, Rng};
+use rand::Rng as RandRng;
+use std::fmt;
+use std::ops::{Bound, RangeBounds};
+
+/// Convert the given range bounds into an inclusive [min, max] pair, where
+/// unbounded ends are replaced with the given limits.
+fn inclusive_bounds<T: Int, R: RangeBounds<T>>(range: &R) -> (i128, i128) {
+    let min = match range.start_bound() {
+        Bound::Included(v) => v.to_i128(),
+        Bound::Excluded(v) => v.to_i128() + 1,
+        Bound::Unbounded => T::MIN.to_i128(),
+    };
+    let max = match range.end_bound() {
+        Bound::Included(v) => v.to_i128(),
+        Bound::Excluded(v) => v.to_i128() - 1,
+        Bound::Unbounded => T::MAX.to_i128(),
+    };
+    assert!(min <= max, "generator range must not be empty");
+    (min, max)
+}
+
+/// Shrink an integer towards `target`: first try the target itself, then
+/// successively smaller steps towards it.
+fn shrink_towards(value: i128, target: i128) -> Vec<i128> {
+    let mut candidates = Vec::new();
+    let mut distance = value - target;
+    while distance != 0 {
+        candidates.push(value - distance);
+        distance /= 2;
+    }
+    candidates
+}
+
+/// Shrink a vector whose length must be at least `min_len`, by removing
+/// elements and then by shrinking individual elements.
+fn shrink_vec<T: Clone, F: Fn(&T) -> Vec<T>>(
+    values: &[T],
+    min_len: usize,
+    shrink_elem: F,
+) -> Vec<Vec<T>> {
+    let mut candidates = Vec::new();
+
+    // Try removing progressively smaller chunks, from every position.
+    let mut chunk = values.len().saturating_sub(min_len);
+    while chunk > 0 {
+        let mut start = 0;
+        while start + chunk <= values.len() {
+            let mut candidate = values[..start].to_vec();
+            candidate.extend_from_slice(&values[start + chunk..]);
+            candidates.push(candidate);
+            start += chunk;
+        }
+        chunk /= 2;
+    }
+
+    for (i, value) in values.iter().enumerate() {
+        for shrunk in shrink_elem(value) {
+            let mut candidate = values.to_vec();
+            candidate[i] = shrunk;
+            candidates.push(candidate);
+        }
+    }
+
+    candidates
+}
+
+/// Int is implemented for the primitive integer types `int` can generate.
+pub trait Int: Copy + fmt::Debug {
+    /// The smallest value of this type.
+    const MIN: Self;
+    /// The largest value of this type.
+    const MAX: Self;
+
+    /// Losslessly widen this value.
+    fn to_i128(self) -> i128;
+    /// Narrow the given value, which is known to be in range for this type.
+    fn from_i128(v: i128) -> Self;
+}
+
+macro_rules! impl_int {
+    ($($t:ty),*) => {
+        $(
+            impl Int for $t {
+                const MIN: Self = <$t>::MIN;
+                const MAX: Self = <$t>::MAX;
+
+                fn to_i128(self) -> i128 {
+                    self as i128
+                }
+
+                fn from_i128(v: i128) -> Self {
+                    v as $t
+                }
+            }
+        )*
+    };
+}
+
+impl_int!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+/// A generator of integers in a range, which shrinks towards zero (or the end
+/// of the range closest to zero, if zero is out of range).
+#[derive(Clone, Debug)]
+pub struct IntGen<T> {
+    min: i128,
+    max: i128,
+    _phantom: std::marker::PhantomData<T>,
+}
+
+impl<T: Int> IntGen<T> {
+    fn target(&self) -> i128 {
+        0.clamp(self.min, self.max)
+    }
+}
+
+impl<T: Int> Gen for IntGen<T> {
+    type Value = T;
+
+    fn generate(&self, rng: &mut Rng) -> T {
+        T::from_i128(rng.gen_range(self.min..=self.max))
+    }
+
+    fn shrink(&self, value: &T) -> Vec<T> {
+        shrink_towards(value.to_i128(), self.target())
+            .into_iter()
+            .map(T::from_i128)
+            .collect()
+    }
+}
+
+/// Generate integers in the given range, e.g. `gen::int(0..64_u32)` or
+/// `gen::int(..=u8::MAX)`.
+pub fn int<T: Int, R: RangeBounds<T>>(range: R) -> IntGen<T> {
+    let (min, max) = inclusive_bounds(&range);
+    IntGen {
+        min,
+        max,
+        _phantom: std::marker::PhantomData,
+    }
+}
+
+/// A generator of vectors whose elements come from another generator.
+#[derive(Clone, Debug)]
+pub struct VecGen<G> {
+    elem: G,
+    min_len: usize,
+    max_len: usize,
+}
+
+impl<G: Gen> Gen for VecGen<G> {
+    type Value = Vec<G::Value>;
+
+    fn generate(&self, rng: &mut Rng) -> Self::Value {
+        let len = rng.gen_range(self.min_len..=self.max_len);
+        (0..len).map(|_| self.elem.generate(rng)).collect()
+    }
+
+    fn shrink(&self, value: &Self::Value) -> Vec<Self::Value> {
+        shrink_vec(value, self.min_len, |v| self.elem.shrink(v))
+    }
+}
+
+/// Generate vectors with a length in the given range, whose elements come
+/// from the given generator, e.g. `gen::vec(gen::int(..=u8::MAX), 0..16)`.
+pub fn vec<G: Gen, R: RangeBounds<usize>>(elem: G, len: R) -> VecGen<G> {
+    let (min_len, max_len) = inclusive_bounds(&len);
+    VecGen {
+        elem,
+        min_len: min_len as usize,
+        max_len: max_len as usize,
+    }
+}
+
+/// The printable ASCII characters, which `ascii_string` draws from.
+const ASCII_PRINTABLE: std::ops::RangeInclusive<u8> = b' '..=b'~';
+
+/// A generator of printable ASCII strings. Strings shrink by removing
+/// characters, and by replacing characters with 'a'.
+#[derive(Clone, Debug)]
+pub struct AsciiStringGen {
+    min_len: usize,
+    max_len: usize,
+}
+
+impl Gen for AsciiStringGen {
+    type Value = String;
+
+    fn generate(&self, rng: &mut Rng) -> String {
+        let len = rng.gen_range(self.min_len..=self.max_len);
+        (0..len)
+            .map(|_| rng.gen_range(ASCII_PRINTABLE) as char)
+            .collect()
+    }
+
+    fn shrink(&self, value: &String) -> Vec<String> {
+        shrink_vec(value.as_bytes(), self.min_len, |&b| match b {
+            b'a' => vec![],
+            _ => vec![b'a'],
+        })
+        .into_iter()
+        .map(|bytes| String::from_utf8(bytes).unwrap())
+        .collect()
+    }
+}
+
+/// Generate printable ASCII strings with a length in the given range, e.g.
+/// `gen::ascii_string(0..64)`.
+pub fn ascii_string<R: RangeBounds<usize>>(len: R) -> AsciiStringGen {
+    let (min_len, max_len) = inclusive_bounds(&len);
+    AsciiStringGen {
+        min_len: min_len as usize,
+        max_len: max_len as usize,
+    }
+}
+
+/// A generator of pairs of values from two other generators.
+#[derive(Clone, Debug)]
+pub struct PairGen<A, B>(A, B);
+
+impl<A: Gen, B: Gen> Gen for PairGen<A, B> {
+    type Value = (A::Value, B::Value);
+
+    fn generate(&self, rng: &mut Rng) -> Self::Value {
+        (self.0.generate(rng), self.1.generate(rng))
+    }
+
+    fn shrink(&self, value: &Self::Value) -> Vec<Self::Value> {
+        let mut candidates: Vec<Self::Value> = self
+            .0
+            .shrink(&value.0)
+            .into_iter()
+            .map(|a| (a, value.1.clone()))
+            .collect();
+        candidates.extend(
+            self.1
+                .shrink(&value.1)
+                .into_iter()
+                .map(|b| (value.0.clone(), b)),
+        );
+        candidates
+    }
+}
+
+/// Generate pairs of values, shrinking each half independently.
+pub fn pair<A: Gen, B: Gen>(a: A, b: B) -> PairGen<A, B> {
+    PairGen(a, b)
+}
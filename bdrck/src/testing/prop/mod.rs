@@ -0,0 +1,162 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// gen provides a small library of generators (implementations of `Gen`) for
+/// common types, which know how to shrink the values they generate.
+pub mod gen;
+
+use rand::SeedableRng;
+use std::env;
+use std::fmt;
+
+/// The random number generator passed to generators. It is seeded
+/// deterministically, so a run can be reproduced from its seed.
+pub type Rng = rand::rngs::StdRng;
+
+/// If this environment variable is set (and `run` is told to consult the
+/// environment), its value is used as the seed instead of a random one. This
+/// is how a failure reported by `run` can be reproduced.
+pub const SEED_ENV_VAR: &str = "BDRCK_PROP_SEED";
+
+/// The maximum number of successful shrinking steps `check` will take, to
+/// bound the time spent on shrinking pathological values.
+const MAX_SHRINK_STEPS: usize = 1000;
+
+/// A Gen generates random values for a property test, and knows how to shrink
+/// a failing value into "smaller" candidates.
+///
+/// Plain closures of the form `Fn(&mut Rng) -> T` are generators too, but they
+/// can't shrink the values they produce.
+pub trait Gen {
+    /// The type of value this generator produces.
+    type Value: Clone + fmt::Debug;
+
+    /// Generate a new random value.
+    fn generate(&self, rng: &mut Rng) -> Self::Value;
+
+    /// Return candidate values which are "smaller" than the given one, in
+    /// order of preference (the most aggressive shrink first). By default,
+    /// values can't be shrunk at all.
+    fn shrink(&self, _value: &Self::Value) -> Vec<Self::Value> {
+        Vec::new()
+    }
+}
+
+impl<T: Clone + fmt::Debug, F: Fn(&mut Rng) -> T> Gen for F {
+    type Value = T;
+
+    fn generate(&self, rng: &mut Rng) -> T {
+        self(rng)
+    }
+}
+
+/// Failure describes a counterexample found by `check`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Failure<T> {
+    /// The seed the run was started with. Passing this to `check` again (or
+    /// setting `SEED_ENV_VAR` for `run`) reproduces this failure exactly.
+    pub seed: u64,
+    /// The (zero-based) iteration on which the property first failed.
+    pub iteration: usize,
+    /// The value originally generated which made the property fail.
+    pub original: T,
+    /// The smallest value found by shrinking `original` which still makes the
+    /// property fail.
+    pub shrunk: T,
+    /// The number of successful shrinking steps taken to get from `original`
+    /// to `shrunk`.
+    pub shrink_steps: usize,
+    /// The message the property returned for `shrunk`.
+    pub message: String,
+}
+
+impl<T: fmt::Debug> fmt::Display for Failure<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "property failed on iteration {} (reproduce with {}={}): {}\n\
+             \toriginal: {:?}\n\
+             \tshrunk ({} steps): {:?}",
+            self.iteration,
+            SEED_ENV_VAR,
+            self.seed,
+            self.message,
+            self.original,
+            self.shrink_steps,
+            self.shrunk
+        )
+    }
+}
+
+/// Check the given property against `iterations` values produced by `gen`,
+/// using an RNG seeded with `seed`. If the property fails, the failing value
+/// is shrunk as far as possible and returned.
+pub fn check<G, P>(iterations: usize, seed: u64, gen: G, prop: P) -> Result<(), Failure<G::Value>>
+where
+    G: Gen,
+    P: Fn(&G::Value) -> Result<(), String>,
+{
+    let mut rng = Rng::seed_from_u64(seed);
+    for iteration in 0..iterations {
+        let original = gen.generate(&mut rng);
+        let mut message = match prop(&original) {
+            Ok(_) => continue,
+            Err(message) => message,
+        };
+
+        let mut shrunk = original.clone();
+        let mut shrink_steps = 0;
+        'shrinking: while shrink_steps < MAX_SHRINK_STEPS {
+            for candidate in gen.shrink(&shrunk) {
+                if let Err(m) = prop(&candidate) {
+                    shrunk = candidate;
+                    message = m;
+                    shrink_steps += 1;
+                    continue 'shrinking;
+                }
+            }
+            break;
+        }
+
+        return Err(Failure {
+            seed,
+            iteration,
+            original,
+            shrunk,
+            shrink_steps,
+            message,
+        });
+    }
+    Ok(())
+}
+
+/// Run a property test, panicking with a description of the (shrunk)
+/// counterexample if it fails. The seed is random, unless `seed_from_env` is
+/// true and `SEED_ENV_VAR` is set, in which case that seed is used instead.
+/// The panic message includes the seed, so failures can be reproduced.
+pub fn run<G, P>(iterations: usize, seed_from_env: bool, gen: G, prop: P)
+where
+    G: Gen,
+    P: Fn(&G::Value) -> Result<(), String>,
+{
+    let seed = match env::var(SEED_ENV_VAR) {
+        Ok(seed) if seed_from_env => seed
+            .parse()
+            .unwrap_or_else(|_| panic!("invalid {} '{}'", SEED_ENV_VAR, seed)),
+        _ => rand::random(),
+    };
+    if let Err(failure) = check(iterations, seed, gen, prop) {
+        panic!("{}", failure);
+    }
+}
@@ -12,17 +12,20 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+#[cfg(feature = "crypto")]
+use crate::crypto::keystore::FileStorage;
 use crate::error::*;
-use crate::fs::{create_file, create_symlink};
-use rand::distributions::Alphanumeric;
-use rand::{thread_rng, Rng};
+use crate::fs::{
+    atomic_write_with, create_symlink, create_unique, list_dir_with, FsOps, ListDir, ListOptions,
+    RealFs, TempFile,
+};
+use rand::thread_rng;
+use std::collections::HashMap;
 use std::env;
 use std::fs;
-use std::io;
+use std::io::{self, ErrorKind};
 use std::path::{Path, PathBuf};
-
-const TEMP_DIR_NAME_RAND_CHARS: usize = 32;
-const TEMP_DIR_RAND_RETRIES: usize = 1024;
+use std::sync::{Arc, Mutex};
 
 /// A directory within the system's standard temp directory that is
 /// automatically deleted when it goes out of scope. The directory is created
@@ -48,29 +51,10 @@ impl Dir {
     /// should generally be something application-specific, so if the temporary
     /// directory is somehow left over its origin can be identified.
     fn new_in<P: AsRef<Path>>(temp_dir: P, prefix: &str) -> Result<Dir> {
-        let mut rng = thread_rng();
-        for _ in 0..TEMP_DIR_RAND_RETRIES {
-            let suffix: String = (&mut rng)
-                .sample_iter(&Alphanumeric)
-                .map(char::from)
-                .take(TEMP_DIR_NAME_RAND_CHARS)
-                .collect();
-            let name = if prefix.is_empty() {
-                suffix
-            } else {
-                format!("{}-{}", prefix, suffix)
-            };
-            let path = temp_dir.as_ref().join(&name);
-            match fs::create_dir(&path) {
-                Ok(_) => return Ok(Dir { path: path }),
-                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {}
-                Err(e) => return Err(e.into()),
-            }
-        }
-        return Err(Error::Io(::std::io::Error::new(
-            ::std::io::ErrorKind::AlreadyExists,
-            "Failed to find unique random temporary directory name",
-        )));
+        let (path, _) = create_unique(&mut thread_rng(), temp_dir.as_ref(), prefix, |p| {
+            fs::create_dir(p)
+        })?;
+        Ok(Dir { path: path })
     }
 
     /// Return the path to this temporary directory.
@@ -111,10 +95,12 @@ impl Drop for Dir {
 }
 
 /// A file within the system's standard temp directory that is automatically
-/// deleted when it goes out of scope.
+/// deleted when it goes out of scope. This is a thin wrapper around
+/// `fs::TempFile`, which does the actual (exclusive) creation and cleanup.
 pub struct File {
+    // Declared first, so the file is removed before its directory.
+    file: TempFile,
     _dir: Option<Dir>,
-    path: PathBuf,
 }
 
 impl File {
@@ -122,13 +108,10 @@ impl File {
     /// directory.
     pub fn new_file() -> Result<File> {
         let dir = Dir::new("bdrck")?;
-        let path = dir.sub_path("tempfile")?;
-        let ret = File {
+        Ok(File {
+            file: TempFile::new_at(dir.sub_path("tempfile")?.as_path())?,
             _dir: Some(dir),
-            path: path,
-        };
-        create_file(ret.path.as_path())?;
-        Ok(ret)
+        })
     }
 
     /// Create a new temporary symlink within the standard system temporary
@@ -136,48 +119,41 @@ impl File {
     pub fn new_symlink<T: AsRef<Path>>(target: T) -> Result<File> {
         let dir = Dir::new("bdrck")?;
         let path = dir.sub_path("tempfile")?;
-        let ret = File {
+        create_symlink(target, path.as_path())?;
+        Ok(File {
+            file: TempFile::from_existing(path),
             _dir: Some(dir),
-            path: path,
-        };
-        create_symlink(target, ret.path.as_path())?;
-        Ok(ret)
+        })
     }
 
-    /// Create a new temporary file at the specified path.
+    /// Create a new temporary file at the specified path. It's an error if
+    /// something already exists there.
     pub fn new_file_at<P: AsRef<Path>>(path: P) -> Result<File> {
-        let ret = File {
-            _dir: None,
-            path: path.as_ref().to_path_buf(),
-        };
         if let Some(parent) = path.as_ref().parent() {
             fs::create_dir_all(parent)?;
         }
-        create_file(ret.path.as_path())?;
-        Ok(ret)
+        Ok(File {
+            file: TempFile::new_at(path.as_ref())?,
+            _dir: None,
+        })
     }
 
     /// Create a new temporary symlink at the specified path, pointing at the
     /// given target.
     pub fn new_symlink_at<T: AsRef<Path>, S: AsRef<Path>>(target: T, symlink: S) -> Result<File> {
-        let ret = File {
-            _dir: None,
-            path: symlink.as_ref().to_path_buf(),
-        };
         if let Some(parent) = symlink.as_ref().parent() {
             fs::create_dir_all(parent)?;
         }
-        create_symlink(target, symlink)?;
-        Ok(ret)
+        create_symlink(target, symlink.as_ref())?;
+        Ok(File {
+            file: TempFile::from_existing(symlink.as_ref().to_path_buf()),
+            _dir: None,
+        })
     }
 
     /// Return the path to this temporary file.
     pub fn path(&self) -> &Path {
-        self.path.as_path()
-    }
-
-    fn close_impl(&self) -> Result<()> {
-        Ok(fs::remove_file(self.path.as_path())?)
+        self.file.path()
     }
 
     /// "Close" this temporary file by deleting it. This is called automatically
@@ -185,13 +161,235 @@ impl File {
     /// want to dispose of this instance without just letting it go out of
     /// scope.
     pub fn close(self) -> Result<()> {
-        self.close_impl()
+        self.file.close()
     }
 }
 
-impl Drop for File {
-    #[allow(unused_must_use)]
-    fn drop(&mut self) {
-        self.close_impl();
+/// Operation identifies one of the filesystem operations a `FaultyFs` can be
+/// scripted to fail.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Operation {
+    /// Creating (opening) a new file.
+    Open,
+    /// Writing data to a file.
+    Write,
+    /// Flushing a file's contents to disk.
+    Fsync,
+    /// Renaming a file.
+    Rename,
+    /// Removing a file.
+    Remove,
+    /// Fetching a file's metadata.
+    Stat,
+}
+
+enum Trigger {
+    /// Fail the nth (1-based) call of an operation.
+    Nth(usize),
+    /// Fail once the total number of bytes written would exceed this.
+    AfterBytes(u64),
+}
+
+struct Fault {
+    operation: Operation,
+    trigger: Trigger,
+    kind: ErrorKind,
+}
+
+#[derive(Default)]
+struct FaultState {
+    faults: Vec<Fault>,
+    calls: HashMap<Operation, usize>,
+    bytes_written: u64,
+}
+
+#[derive(Default)]
+struct FaultyOps {
+    state: Mutex<FaultState>,
+}
+
+fn injected(operation: Operation, kind: ErrorKind) -> io::Error {
+    io::Error::new(kind, format!("injected {:?} failure", operation))
+}
+
+impl FaultyOps {
+    /// Record a call of the given operation, and return an error if it is
+    /// scripted to fail.
+    fn call(&self, operation: Operation) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let calls = state.calls.entry(operation).or_default();
+        *calls += 1;
+        let n = *calls;
+        match state.faults.iter().find(|f| {
+            f.operation == operation && matches!(f.trigger, Trigger::Nth(nth) if nth == n)
+        }) {
+            Some(fault) => Err(injected(operation, fault.kind)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl FsOps for FaultyOps {
+    fn create_new(&self, path: &Path) -> io::Result<fs::File> {
+        self.call(Operation::Open)?;
+        RealFs.create_new(path)
+    }
+
+    fn write_all(&self, file: &mut fs::File, data: &[u8]) -> io::Result<()> {
+        self.call(Operation::Write)?;
+
+        let mut state = self.state.lock().unwrap();
+        let limit = state
+            .faults
+            .iter()
+            .filter_map(|f| match f.trigger {
+                Trigger::AfterBytes(limit) => Some((limit, f.kind)),
+                Trigger::Nth(_) => None,
+            })
+            .min_by_key(|(limit, _)| *limit);
+        if let Some((limit, kind)) = limit {
+            let remaining = limit.saturating_sub(state.bytes_written) as usize;
+            if data.len() > remaining {
+                // Like a full disk, write as much as fits before failing.
+                RealFs.write_all(file, &data[..remaining])?;
+                state.bytes_written += remaining as u64;
+                return Err(injected(Operation::Write, kind));
+            }
+        }
+        RealFs.write_all(file, data)?;
+        state.bytes_written += data.len() as u64;
+        Ok(())
+    }
+
+    fn fsync(&self, file: &fs::File) -> io::Result<()> {
+        self.call(Operation::Fsync)?;
+        RealFs.fsync(file)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.call(Operation::Rename)?;
+        RealFs.rename(from, to)
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        self.call(Operation::Remove)?;
+        RealFs.remove(path)
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<fs::Metadata> {
+        self.call(Operation::Stat)?;
+        RealFs.symlink_metadata(path)
+    }
+}
+
+/// FaultyFs is a temporary directory, along with versions of the `fs`
+/// module's higher-level helpers (`TempFile`, `atomic_write`, etc.) which
+/// operate within it and whose individual filesystem operations can be
+/// scripted to fail. This makes it possible to test how code behaves when
+/// e.g. the disk fills up partway through a write, or a rename fails.
+///
+/// Operations which aren't scripted to fail are passed through to the real
+/// filesystem.
+pub struct FaultyFs {
+    dir: Dir,
+    ops: Arc<FaultyOps>,
+}
+
+impl FaultyFs {
+    /// Create a new FaultyFs, in a new temporary directory with the given
+    /// prefix in its name (see `Dir::new`).
+    pub fn new(prefix: &str) -> Result<FaultyFs> {
+        Ok(FaultyFs {
+            dir: Dir::new(prefix)?,
+            ops: Arc::new(FaultyOps::default()),
+        })
+    }
+
+    /// Return the path to this FaultyFs's temporary directory.
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// A convenience function which adds the given relative path to this
+    /// FaultyFs's temporary directory's absolute path.
+    pub fn sub_path<P: AsRef<Path>>(&self, path: P) -> Result<PathBuf> {
+        self.dir.sub_path(path)
+    }
+
+    /// Make the nth (starting from 1) call of the given operation fail with
+    /// the given kind of error. Other calls are unaffected.
+    pub fn fail_nth(&self, operation: Operation, n: usize, kind: ErrorKind) -> &Self {
+        self.ops.state.lock().unwrap().faults.push(Fault {
+            operation,
+            trigger: Trigger::Nth(n),
+            kind,
+        });
+        self
+    }
+
+    /// Make writes fail with the given kind of error once, in total, the given
+    /// number of bytes have been written. The write which crosses the limit
+    /// writes as much as fits before failing, just like a real write to a
+    /// full disk would.
+    ///
+    /// Only `Operation::Write` transfers data, so it is the only operation
+    /// this can be used with.
+    pub fn fail_after_bytes(&self, operation: Operation, bytes: u64, kind: ErrorKind) -> &Self {
+        assert_eq!(
+            Operation::Write,
+            operation,
+            "only writes can fail after a number of bytes"
+        );
+        self.ops.state.lock().unwrap().faults.push(Fault {
+            operation,
+            trigger: Trigger::AfterBytes(bytes),
+            kind,
+        });
+        self
+    }
+
+    /// Return how many times the given operation has been called (including
+    /// calls which failed).
+    pub fn calls(&self, operation: Operation) -> usize {
+        self.ops
+            .state
+            .lock()
+            .unwrap()
+            .calls
+            .get(&operation)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Return the total number of bytes which have been written.
+    pub fn bytes_written(&self) -> u64 {
+        self.ops.state.lock().unwrap().bytes_written
+    }
+
+    /// Like `fs::TempFile::new_in`, create a new temporary file in the given
+    /// directory (which should be within this FaultyFs's directory).
+    pub fn temp_file(&self, dir: &Path, prefix: &str) -> Result<TempFile> {
+        TempFile::new_in_with_ops(self.ops.clone(), dir, prefix)
+    }
+
+    /// Like `fs::atomic_write`, atomically replace the contents of the file at
+    /// the given path.
+    pub fn atomic_write<P: AsRef<Path>>(&self, path: P, data: &[u8]) -> Result<()> {
+        let ops: Arc<dyn FsOps> = self.ops.clone();
+        atomic_write_with(&ops, path.as_ref(), data)
+    }
+
+    /// Like `fs::list_dir`, list the entries in the given directory. Any
+    /// metadata the listing fetches counts as an `Operation::Stat`.
+    pub fn list_dir<P: AsRef<Path>>(&self, path: P, options: ListOptions) -> Result<ListDir> {
+        let ops: Arc<dyn FsOps> = self.ops.clone();
+        list_dir_with(&ops, path.as_ref(), options)
+    }
+
+    /// Return a `FileStorage` for a KeyStore, which stores it in the file at
+    /// the given path.
+    #[cfg(feature = "crypto")]
+    pub fn file_storage<P: AsRef<Path>>(&self, path: P) -> FileStorage {
+        FileStorage::new(path).with_fs_ops(self.ops.clone())
     }
 }
@@ -16,14 +16,19 @@ use crate::cli::*;
 use crate::error::*;
 use std::collections::{HashSet, VecDeque};
 use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
 
 // The write buffer size we preallocate, per instance of `TestStreamBuffers`.
 const TEST_WRITE_BUFFER_SIZE_BYTES: usize = 1024 * 100;
 
+// The fake (columns, rows) window size test streams report by default.
+const TEST_WINDOW_SIZE: (u16, u16) = (132, 43);
+
 /// This structure holds some fake terminal attributes, which the `cli` module
 /// can modify via `AbstractStream`, and which we can then inspect in our test.
 #[derive(Clone, Debug, Eq, PartialEq)]
-struct TestTerminalAttributes {
+pub(crate) struct TestTerminalAttributes {
     on: HashSet<TerminalFlag>,
     off: HashSet<TerminalFlag>,
 }
@@ -140,10 +145,11 @@ impl Write for TestStreamWriter {
 /// An important consequence of this is that it is not safe to use these across
 /// threads; doing so results in undefined behavior (crashes or overwritten
 /// data).
-struct TestStream {
+pub(crate) struct TestStream {
     isatty: bool,
     support_read: bool,
     support_write: bool,
+    window_size: (u16, u16),
     ctx: *mut TestContextPtrs,
 }
 
@@ -186,6 +192,10 @@ impl AbstractStream for TestStream {
             true => Some(Box::new(TestStreamWriter { ctx: self.ctx })),
         }
     }
+
+    fn window_size(&self) -> IoResult<(u16, u16)> {
+        Ok(self.window_size)
+    }
 }
 
 fn attributes_are_default(attributes: &VecDeque<TestTerminalAttributes>) -> bool {
@@ -197,7 +207,7 @@ fn attributes_are_default(attributes: &VecDeque<TestTerminalAttributes>) -> bool
 /// provides both `Read` and `Write` streams. Generally speaking, each test
 /// will create exactly one of these, and use `as_stream` to get
 /// `AbstractStream` instances to pass into the `cli` API.
-struct TestContext {
+pub(crate) struct TestContext {
     read_attributes_over_time: Box<VecDeque<TestTerminalAttributes>>,
     write_attributes_over_time: Box<VecDeque<TestTerminalAttributes>>,
     // This field is used via a pointer into it, but because we're doing
@@ -243,7 +253,12 @@ impl TestContext {
             && attributes_are_default(&self.write_attributes_over_time)
     }
 
-    fn as_stream(&mut self, isatty: bool, support_read: bool, support_write: bool) -> TestStream {
+    pub(crate) fn as_stream(
+        &mut self,
+        isatty: bool,
+        support_read: bool,
+        support_write: bool,
+    ) -> TestStream {
         if support_read && support_write {
             panic!("Test streams must be either read streams or write streams.");
         }
@@ -251,11 +266,12 @@ impl TestContext {
             support_read: support_read,
             support_write: support_write,
             isatty: isatty,
+            window_size: TEST_WINDOW_SIZE,
             ctx: self.ctx.as_mut(),
         }
     }
 
-    fn write_buffer_as_str(&self) -> Result<&str> {
+    pub(crate) fn write_buffer_as_str(&self) -> Result<&str> {
         let len = self.write_buffer.iter().take_while(|&&b| b != 0).count();
         Ok(std::str::from_utf8(&self.write_buffer[0..len])?)
     }
@@ -266,7 +282,9 @@ impl TestContext {
 /// instead.
 ///
 /// Returns a tuple of (context, input stream, output stream).
-fn create_normal_test_context(read_input: &str) -> (TestContext, TestStream, TestStream) {
+pub(crate) fn create_normal_test_context(
+    read_input: &str,
+) -> (TestContext, TestStream, TestStream) {
     let mut ctx = TestContext::new(read_input);
     let is = ctx.as_stream(
         /*isatty=*/ true, /*support_read=*/ true, /*support_write=*/ false,
@@ -700,3 +718,1523 @@ fn test_continue_confirmation_bad_input() {
         ctx.write_buffer_as_str().unwrap()
     );
 }
+
+#[test]
+fn test_terminal_width_not_a_tty() {
+    crate::init().unwrap();
+
+    // Whatever stdin is during tests, we should always get some sane width.
+    let width = terminal_width(&Stream::Stdin);
+    assert!(width > 0);
+    if !Stream::Stdin.isatty() {
+        assert_eq!(DEFAULT_TERMINAL_WIDTH, width);
+    }
+}
+
+#[test]
+fn test_terminal_size() {
+    crate::init().unwrap();
+
+    let mut ctx = TestContext::new("");
+    let mut os = ctx.as_stream(
+        /*isatty=*/ true, /*support_read=*/ false, /*support_write=*/ true,
+    );
+    assert_eq!(TEST_WINDOW_SIZE, terminal_size(&os).unwrap());
+
+    os.window_size = (80, 24);
+    assert_eq!((80, 24), terminal_size(&os).unwrap());
+
+    // A terminal which doesn't report its size is an error.
+    os.window_size = (0, 0);
+    assert!(terminal_size(&os).is_err());
+}
+
+#[test]
+fn test_terminal_size_not_a_tty() {
+    crate::init().unwrap();
+
+    let mut ctx = TestContext::new("");
+    let os = ctx.as_stream(
+        /*isatty=*/ false, /*support_read=*/ false, /*support_write=*/ true,
+    );
+    assert!(terminal_size(&os).is_err());
+}
+
+// Tests which install / uninstall the SIGWINCH handler can't run concurrently,
+// or they would observe each other's changes.
+static SIGWINCH_TEST_LOCK: Mutex<()> = Mutex::new(());
+
+fn current_sigwinch_handler() -> libc::sighandler_t {
+    let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+    assert_eq!(0, unsafe {
+        libc::sigaction(libc::SIGWINCH, std::ptr::null(), &mut action)
+    });
+    action.sa_sigaction
+}
+
+#[test]
+fn test_resize_watcher_check() {
+    crate::init().unwrap();
+    let _lock = SIGWINCH_TEST_LOCK.lock().unwrap();
+
+    let mut ctx = TestContext::new("");
+    let os = ctx.as_stream(
+        /*isatty=*/ true, /*support_read=*/ false, /*support_write=*/ true,
+    );
+    let watcher = ResizeWatcher::new(os).unwrap();
+    assert_eq!(TEST_WINDOW_SIZE, watcher.size().unwrap());
+    assert!(watcher.check().is_none());
+
+    // Simulate a resize by invoking the handler directly.
+    handle_sigwinch(libc::SIGWINCH);
+    assert_eq!(Some(TEST_WINDOW_SIZE), watcher.check());
+    // The notification should only be reported once.
+    assert!(watcher.check().is_none());
+}
+
+#[test]
+fn test_resize_watcher_subscribe() {
+    crate::init().unwrap();
+    let _lock = SIGWINCH_TEST_LOCK.lock().unwrap();
+
+    let mut ctx = TestContext::new("");
+    let os = ctx.as_stream(
+        /*isatty=*/ true, /*support_read=*/ false, /*support_write=*/ true,
+    );
+    // TestStream isn't Send, since it holds raw pointers into the test context.
+    // Reading the fake window size doesn't touch those, so wrap it for this test.
+    struct SendStream(TestStream);
+    unsafe impl Send for SendStream {}
+    impl AbstractStream for SendStream {
+        type Attributes = TestTerminalAttributes;
+        fn isatty(&self) -> bool {
+            self.0.isatty()
+        }
+        fn get_attributes(&self) -> IoResult<Self::Attributes> {
+            self.0.get_attributes()
+        }
+        fn set_attributes(&mut self, attributes: &Self::Attributes) -> IoResult<()> {
+            self.0.set_attributes(attributes)
+        }
+        fn as_reader(&self) -> Option<Box<dyn Read>> {
+            self.0.as_reader()
+        }
+        fn as_writer(&self) -> Option<Box<dyn Write>> {
+            self.0.as_writer()
+        }
+        fn window_size(&self) -> IoResult<(u16, u16)> {
+            self.0.window_size()
+        }
+    }
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let subscription = ResizeWatcher::new(SendStream(os))
+        .unwrap()
+        .subscribe(move |size| tx.send(size).unwrap());
+    handle_sigwinch(libc::SIGWINCH);
+    assert_eq!(
+        TEST_WINDOW_SIZE,
+        rx.recv_timeout(std::time::Duration::from_secs(10)).unwrap()
+    );
+    drop(subscription);
+}
+
+#[test]
+fn test_resize_watcher_idempotent_install() {
+    crate::init().unwrap();
+    let _lock = SIGWINCH_TEST_LOCK.lock().unwrap();
+
+    let mut ctx = TestContext::new("");
+    let original = current_sigwinch_handler();
+    let handler: extern "C" fn(libc::c_int) = handle_sigwinch;
+    let ours = handler as libc::sighandler_t;
+    assert_ne!(ours, original);
+
+    let a = ResizeWatcher::new(ctx.as_stream(
+        /*isatty=*/ true, /*support_read=*/ false, /*support_write=*/ true,
+    ))
+    .unwrap();
+    assert_eq!(ours, current_sigwinch_handler());
+    let b = ResizeWatcher::new(ctx.as_stream(
+        /*isatty=*/ true, /*support_read=*/ false, /*support_write=*/ true,
+    ))
+    .unwrap();
+    assert_eq!(ours, current_sigwinch_handler());
+
+    // The handler should stay installed until the last watcher is dropped,
+    // at which point the original handler is restored.
+    drop(a);
+    assert_eq!(ours, current_sigwinch_handler());
+    drop(b);
+    assert_eq!(original, current_sigwinch_handler());
+}
+
+static WRAP_TEST_TEXT: &str = "Lorem ipsum dolor sit amet, consectetur adipiscing elit, sed do eiusmod tempor incididunt ut labore et dolore magna aliqua.\n\nUt enim ad minim veniam, quis nostrud exercitation.";
+
+#[test]
+fn test_wrap_text_width_60() {
+    crate::init().unwrap();
+
+    assert_eq!(
+        vec![
+            "Lorem ipsum dolor sit amet, consectetur adipiscing elit, sed",
+            "do eiusmod tempor incididunt ut labore et dolore magna",
+            "aliqua.",
+            "",
+            "Ut enim ad minim veniam, quis nostrud exercitation.",
+        ],
+        wrap_text(WRAP_TEST_TEXT, 60)
+    );
+}
+
+#[test]
+fn test_wrap_text_width_80() {
+    crate::init().unwrap();
+
+    assert_eq!(
+        vec![
+            "Lorem ipsum dolor sit amet, consectetur adipiscing elit, sed do eiusmod tempor",
+            "incididunt ut labore et dolore magna aliqua.",
+            "",
+            "Ut enim ad minim veniam, quis nostrud exercitation.",
+        ],
+        wrap_text(WRAP_TEST_TEXT, 80)
+    );
+}
+
+#[test]
+fn test_wrap_text_width_120() {
+    crate::init().unwrap();
+
+    assert_eq!(
+        vec![
+            "Lorem ipsum dolor sit amet, consectetur adipiscing elit, sed do eiusmod tempor incididunt ut labore et dolore magna",
+            "aliqua.",
+            "",
+            "Ut enim ad minim veniam, quis nostrud exercitation.",
+        ],
+        wrap_text(WRAP_TEST_TEXT, 120)
+    );
+}
+
+#[test]
+fn test_wrap_text_long_word() {
+    crate::init().unwrap();
+
+    assert_eq!(
+        vec!["foo", "abcdefghij", "klmnopqrst", "uvwxyz bar"],
+        wrap_text("foo abcdefghijklmnopqrstuvwxyz bar", 10)
+    );
+    assert_eq!(vec!["a", "b", "c"], wrap_text("abc", 0));
+    assert!(wrap_text("   ", 10).is_empty());
+}
+
+#[test]
+fn test_wrap_text_ansi() {
+    crate::init().unwrap();
+
+    // Escape sequences take up no space.
+    let red = |s: &str| format!("\x1b[31m{}\x1b[0m", s);
+    let text = format!("{} {} {}", red("foo"), red("bar"), red("baz"));
+    assert_eq!(
+        vec![format!("{} {}", red("foo"), red("bar")), red("baz")],
+        wrap_text(text.as_str(), 7)
+    );
+
+    // Long words are broken up by width, without splitting a hyperlink's
+    // escape sequences (even though the URL is longer than a line).
+    let link = "\x1b]8;;https://example.com/some/long/path\x1b\\abcdefgh\x1b]8;;\x1b\\";
+    let lines = wrap_text(link, 4);
+    assert_eq!(
+        vec![
+            "\x1b]8;;https://example.com/some/long/path\x1b\\abcd",
+            "efgh\x1b]8;;\x1b\\",
+        ],
+        lines
+    );
+    assert!(lines.iter().all(|line| display_width(line) <= 4));
+
+    // Wide characters count as two columns.
+    assert_eq!(vec!["日本", "語"], wrap_text("日本語", 4));
+}
+
+/// Run `edit_text_with` with the given "editor" function, which should
+/// rewrite the file it's given programmatically.
+fn edit_text_test<E: Fn(&Path) -> Result<()>>(
+    initial: &str,
+    options: EditOptions<'_>,
+    editor: E,
+) -> Result<Option<String>> {
+    let (_ctx, is, os) = create_normal_test_context("");
+    edit_text_with(&is, &os, &editor, initial, options)
+}
+
+#[test]
+fn test_edit_text_strips_comments() {
+    crate::init().unwrap();
+
+    let options = EditOptions {
+        comment_prefix: Some("#"),
+        ..Default::default()
+    };
+    let edited = edit_text_test("\n# Enter a message.\n", options, |path| {
+        assert_eq!(
+            "\n# Enter a message.\n",
+            std::fs::read_to_string(path).unwrap()
+        );
+        std::fs::write(path, "hello\n# Enter a message.\n\nworld\n\n")?;
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(Some("hello\n\nworld".to_owned()), edited);
+}
+
+#[test]
+fn test_edit_text_abort() {
+    crate::init().unwrap();
+
+    let options = EditOptions {
+        comment_prefix: Some("#"),
+        require_change: true,
+        ..Default::default()
+    };
+    // Leaving only comments (or nothing) aborts.
+    assert_eq!(
+        None,
+        edit_text_test("# Enter a message.\n", options, |path| {
+            std::fs::write(path, "# still nothing\n  \n")?;
+            Ok(())
+        })
+        .unwrap()
+    );
+    // With `require_change`, so does leaving the initial text unmodified.
+    assert_eq!(
+        None,
+        edit_text_test("template\n# Enter a message.\n", options, |_| Ok(())).unwrap()
+    );
+    assert_eq!(
+        Some("template".to_owned()),
+        edit_text_test(
+            "template\n# Enter a message.\n",
+            EditOptions {
+                require_change: false,
+                ..options
+            },
+            |_| Ok(())
+        )
+        .unwrap()
+    );
+}
+
+#[test]
+fn test_edit_text_editor_failure() {
+    crate::init().unwrap();
+
+    std::env::set_var("VISUAL", "exit 3; true");
+    let (_ctx, is, os) = create_normal_test_context("");
+    let result = edit_text_with(&is, &os, &SystemEditor, "", EditOptions::default());
+    std::env::remove_var("VISUAL");
+    match result {
+        Err(Error::ProcessFailed { status, .. }) => assert_eq!(Some(3), status.code()),
+        _ => panic!("expected the editor to fail"),
+    }
+}
+
+#[test]
+fn test_edit_text_file() {
+    crate::init().unwrap();
+
+    let options = EditOptions {
+        file_extension: Some("md"),
+        ..Default::default()
+    };
+    let edited_path = std::cell::RefCell::new(None);
+    let edited = edit_text_test("", options, |path| {
+        *edited_path.borrow_mut() = Some(path.to_path_buf());
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(Some("md"), path.extension().and_then(|e| e.to_str()));
+        let mode = std::fs::metadata(path)?.permissions().mode();
+        assert_eq!(0o600, mode & 0o777);
+        std::fs::write(path, "# not a comment\n")?;
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(Some("# not a comment".to_owned()), edited);
+    // The temporary file should have been cleaned up.
+    assert!(!edited_path.into_inner().unwrap().exists());
+}
+
+#[test]
+fn test_edit_text_requires_tty() {
+    crate::init().unwrap();
+
+    let mut ctx = TestContext::new("");
+    let is = ctx.as_stream(
+        /*isatty=*/ false, /*support_read=*/ true, /*support_write=*/ false,
+    );
+    let os = ctx.as_stream(
+        /*isatty=*/ true, /*support_read=*/ false, /*support_write=*/ true,
+    );
+    let result = edit_text_with(
+        &is,
+        &os,
+        &|_: &Path| -> Result<()> { panic!("the editor shouldn't be launched") },
+        "",
+        EditOptions::default(),
+    );
+    assert!(matches!(result, Err(Error::Precondition(_))));
+}
+
+#[test]
+fn test_write_error() {
+    crate::init().unwrap();
+
+    let error = Error::NotFound("foo".to_string());
+
+    let mut text = Vec::new();
+    write_error(&mut text, &error, ErrorFormat::Text).unwrap();
+    assert_eq!("error: not found: foo\n", String::from_utf8(text).unwrap());
+
+    let mut json = Vec::new();
+    write_error(&mut json, &error, ErrorFormat::Json).unwrap();
+    let json = String::from_utf8(json).unwrap();
+    assert!(json.ends_with('\n'));
+    assert_eq!(1, json.lines().count());
+    let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+    assert_eq!("NOT_FOUND", value["code"]);
+    assert_eq!("USAGE", value["category"]);
+}
+
+fn render_table_to_test_stream(table: &Table, isatty: bool, width: u16) -> String {
+    let mut ctx = TestContext::new("");
+    let mut os = ctx.as_stream(
+        isatty, /*support_read=*/ false, /*support_write=*/ true,
+    );
+    os.window_size = (width, 24);
+    table.render_to(&os).unwrap();
+    ctx.write_buffer_as_str().unwrap().to_owned()
+}
+
+#[test]
+fn test_table_alignment() {
+    crate::init().unwrap();
+
+    let mut table = Table::new()
+        .header(["NAME", "SIZE", "KIND"])
+        .align(1, Alignment::Right)
+        .align(2, Alignment::Center);
+    table.add_row(["a", "1", "f"]);
+    table.add_row(["bbb", "1234", "dir"]);
+
+    assert_eq!(
+        "NAME  SIZE  KIND\n\
+         ----  ----  ----\n\
+         a        1   f\n\
+         bbb   1234  dir\n",
+        render_table_to_test_stream(&table, /*isatty=*/ true, 80)
+    );
+}
+
+#[test]
+fn test_table_truncation() {
+    crate::init().unwrap();
+
+    let mut table = Table::new()
+        .truncation(1, Truncation::Middle)
+        .max_width(2, 5);
+    table.add_row(["1", "/very/long/path/to/some/file.txt", "abcdefgh"]);
+    table.add_row(["2", "/short", "abc"]);
+
+    assert_eq!(
+        "1  /very/long…/file.txt  abcd…\n\
+         2  /short                abc\n",
+        render_table_to_test_stream(&table, /*isatty=*/ true, 30)
+    );
+}
+
+#[test]
+fn test_table_wide_characters() {
+    crate::init().unwrap();
+
+    assert_eq!(6, display_width("日本語"));
+
+    let mut table = Table::new().header(["名前", "x"]).underline_header(false);
+    table.add_row(["日本語", "1"]);
+    table.add_row(["ab", "2"]);
+
+    assert_eq!(
+        "名前    x\n\
+         日本語  1\n\
+         ab      2\n",
+        table.render(80)
+    );
+
+    // Wide characters which don't fit are dropped whole, not split.
+    let mut table = Table::new().max_width(0, 4);
+    table.add_row(["日本語"]);
+    assert_eq!("日…\n", table.render(80));
+}
+
+#[test]
+fn test_table_non_tty() {
+    crate::init().unwrap();
+
+    let mut table = Table::new().header(["NAME", "VALUE"]).max_width(1, 3);
+    table.add_row(["a b", "some\tlong\nvalue"]);
+
+    // No padding, truncation, or underline; embedded tabs / newlines are
+    // replaced so each row stays on one line.
+    assert_eq!(
+        "NAME\tVALUE\na b\tsome long value\n",
+        render_table_to_test_stream(&table, /*isatty=*/ false, 10)
+    );
+
+    let table = table.plain_separator(" ");
+    assert_eq!(
+        "NAME VALUE\na b some long value\n",
+        render_table_to_test_stream(&table, /*isatty=*/ false, 10)
+    );
+}
+
+#[test]
+fn test_link_rendering() {
+    crate::init().unwrap();
+
+    let link = Link::new("https://example.com/", "example");
+    assert_eq!(
+        "\x1b]8;;https://example.com/\x1b\\example\x1b]8;;\x1b\\",
+        link.render(true)
+    );
+    assert_eq!("example (https://example.com/)", link.render(false));
+    assert_eq!(
+        "https://example.com/",
+        Link::new("https://example.com/", "https://example.com/").to_string()
+    );
+
+    let mut ctx = TestContext::new("");
+    let tty = ctx.as_stream(
+        /*isatty=*/ true, /*support_read=*/ false, /*support_write=*/ true,
+    );
+    let pipe = ctx.as_stream(
+        /*isatty=*/ false, /*support_read=*/ false, /*support_write=*/ true,
+    );
+    assert_eq!(link.render(true), link.render_for(&tty, Hyperlinks::Always));
+    assert_eq!(
+        link.render(true),
+        link.render_for(&pipe, Hyperlinks::Always)
+    );
+    assert_eq!(link.render(false), link.render_for(&tty, Hyperlinks::Never));
+    // Regardless of the terminal, non-TTYs never get escape sequences.
+    assert_eq!(link.render(false), link.render_for(&pipe, Hyperlinks::Auto));
+}
+
+#[test]
+fn test_path_link() {
+    crate::init().unwrap();
+
+    let link = path_link(Path::new("/tmp/a b/ü.txt")).unwrap();
+    assert_eq!("file:///tmp/a%20b/%C3%BC.txt", link.url());
+    assert_eq!("/tmp/a b/ü.txt", link.text());
+
+    let link = path_link(Path::new("foo.txt")).unwrap();
+    assert!(link.url().starts_with("file:///"));
+    assert!(link.url().ends_with("/foo.txt"));
+    assert_eq!("foo.txt", link.text());
+}
+
+#[test]
+fn test_table_with_links() {
+    crate::init().unwrap();
+
+    let docs = Link::new("https://example.com/docs", "docs");
+    let mut linked = Table::new().header(["NAME", "URL"]).max_width(0, 6);
+    linked.add_row([docs.render(true), "x".to_string()]);
+    linked.add_row(["long name".to_string(), "y".to_string()]);
+    let mut plain = Table::new().header(["NAME", "URL"]).max_width(0, 6);
+    plain.add_row(["docs", "x"]);
+    plain.add_row(["long name", "y"]);
+
+    // Escape sequences take up no space, so the columns line up exactly as
+    // they would without the hyperlink.
+    assert_eq!(4, display_width(&docs.render(true)));
+    let rendered = linked.render(80);
+    assert_eq!(plain.render(80), strip_ansi(&rendered));
+    assert!(rendered.contains(&docs.render(true)));
+
+    // Truncating a hyperlink keeps it intact (and closed).
+    let long = Link::new("https://example.com/", "a very long link");
+    let mut table = Table::new().max_width(0, 6);
+    table.add_row([long.render(true)]);
+    assert_eq!(
+        "\x1b]8;;https://example.com/\x1b\\a ver…\x1b]8;;\x1b\\\n",
+        table.render(80)
+    );
+}
+
+#[test]
+fn test_default_messages() {
+    crate::init().unwrap();
+
+    // These are the strings this module has always displayed; the default
+    // catalog must not change them.
+    let messages = DefaultMessages;
+    assert_eq!("Confirm: ", messages.confirm_prompt());
+    assert_eq!(
+        "The values did not match. Please try again.",
+        messages.confirm_mismatch()
+    );
+    assert_eq!(
+        "Deleting foo. Continue? [Yes/No] ",
+        messages.continue_prompt("Deleting foo. ")
+    );
+    assert_eq!("Invalid response 'x'.", messages.invalid_response("x"));
+    assert_eq!(vec!["y", "yes"], messages.affirmative_answers());
+    assert_eq!(vec!["n", "no"], messages.negative_answers());
+    assert_eq!("back", messages.wizard_back());
+    assert_eq!("cancel", messages.wizard_cancel());
+    assert_eq!(
+        "Proceed with these settings? [Yes/No/back] ",
+        messages.wizard_summary_prompt()
+    );
+}
+
+struct PigLatinMessages;
+
+impl Messages for PigLatinMessages {
+    fn continue_prompt(&self, description: &str) -> String {
+        format!("{}Ontinuecay? [Esyay/Onay] ", description)
+    }
+
+    fn invalid_response(&self, response: &str) -> String {
+        format!("Invaliday esponseray '{}'.", response)
+    }
+
+    fn affirmative_answers(&self) -> Vec<String> {
+        vec!["esyay".to_string()]
+    }
+
+    fn negative_answers(&self) -> Vec<String> {
+        vec!["onay".to_string()]
+    }
+}
+
+#[test]
+fn test_continue_confirmation_custom_messages() {
+    crate::init().unwrap();
+
+    let (ctx, is, os) = create_normal_test_context("yes\nEsyay\n");
+    let result =
+        continue_confirmation_with_messages(is, os, TEST_CONTINUE_DESCRIPTION, &PigLatinMessages)
+            .unwrap();
+
+    // The default "yes" is no longer accepted.
+    assert!(result);
+    assert!(ctx.has_default_attributes());
+    let prompt = format!("{}Ontinuecay? [Esyay/Onay] ", TEST_CONTINUE_DESCRIPTION);
+    assert_eq!(
+        format!("{}Invaliday esponseray 'yes'.\n{}", prompt, prompt),
+        ctx.write_buffer_as_str().unwrap()
+    );
+
+    let (_ctx, is, os) = create_normal_test_context("onay\n");
+    assert!(!continue_confirmation_with_messages(
+        is,
+        os,
+        TEST_CONTINUE_DESCRIPTION,
+        &PigLatinMessages
+    )
+    .unwrap());
+}
+
+#[test]
+fn test_password_strength() {
+    crate::init().unwrap();
+
+    let cases: &[(&str, PasswordScore)] = &[
+        ("", PasswordScore::VeryWeak),
+        ("aaaaaaaa", PasswordScore::VeryWeak),
+        ("abcdefgh", PasswordScore::VeryWeak),
+        ("asdfghjk", PasswordScore::VeryWeak),
+        ("abcabcabcabc", PasswordScore::VeryWeak),
+        ("password", PasswordScore::VeryWeak),
+        ("Password123!", PasswordScore::VeryWeak),
+        ("Tr0ub4dor", PasswordScore::Fair),
+        ("Tr0ub4dor&3", PasswordScore::Good),
+        ("xK9#mQ2$vL7!pR4&", PasswordScore::Strong),
+        ("correct horse battery staple", PasswordScore::Strong),
+    ];
+    for &(candidate, expected) in cases {
+        let report = password_strength(candidate);
+        assert_eq!(expected, report.score, "{:?}", report);
+    }
+    let report = password_strength("xK9#mQ2$vL7!pR4&");
+    assert!(!format!("{:?}", report).contains("xK9#"));
+
+    let a = password_strength("xK9#mQ2$");
+    let b = password_strength("xK9#mQ2$vL7!pR4&");
+    assert!(a.entropy_bits < b.entropy_bits);
+    assert!(b.suggestions.is_empty());
+
+    let suggestions = password_strength("aaaaaaaa").suggestions;
+    assert!(suggestions.iter().any(|s| s.contains("repeated")));
+    let suggestions = password_strength("1234abcd").suggestions;
+    assert!(suggestions.iter().any(|s| s.contains("sequences")));
+    let suggestions = password_strength("qwerasdf").suggestions;
+    assert!(suggestions.iter().any(|s| s.contains("keyboard")));
+    let suggestions = password_strength("dragon").suggestions;
+    assert!(suggestions.iter().any(|s| s.contains("commonly used")));
+}
+
+#[cfg(feature = "crypto")]
+#[test]
+fn test_prompt_for_new_password() {
+    crate::init().unwrap();
+
+    let weak = "dragon";
+    let strong = "xK9#mQ2$vL7!pR4&";
+    let (ctx, is, os) =
+        create_normal_test_context(format!("{w}\n{w}\n{s}\n{s}\n", w = weak, s = strong).as_str());
+    let secret = prompt_for_new_password(is, os, TEST_PROMPT, PasswordPolicy::default()).unwrap();
+
+    assert_eq!(strong.as_bytes(), unsafe { secret.as_slice() });
+    let mut expected = format!(
+        "{}Confirm: This password is too weak.\n  Use at least 8 characters.\n",
+        TEST_PROMPT
+    );
+    for suggestion in password_strength(weak).suggestions {
+        expected.push_str(format!("  {}\n", suggestion).as_str());
+    }
+    expected.push_str(format!("{}Confirm: ", TEST_PROMPT).as_str());
+    let written = ctx.write_buffer_as_str().unwrap();
+    assert_eq!(expected, written);
+    assert!(!written.contains(weak));
+    assert!(!written.contains(strong));
+}
+
+#[cfg(feature = "crypto")]
+#[test]
+fn test_prompt_for_secret_cached() {
+    use crate::crypto::secret::{SecretCache, SecretCacheOptions};
+    use crate::testing::clock::MockClock;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    crate::init().unwrap();
+
+    let clock = Arc::new(MockClock::default());
+    let cache = SecretCache::with_clock(SecretCacheOptions::default(), clock.clone());
+    let ttl = Duration::from_secs(300);
+
+    let (ctx, is, os) = create_normal_test_context("hunter2\n");
+    let secret = prompt_for_secret_cached(is, os, TEST_PROMPT, &cache, "passphrase", ttl).unwrap();
+    assert_eq!(b"hunter2", unsafe { secret.as_slice() });
+    assert_eq!(TEST_PROMPT, ctx.write_buffer_as_str().unwrap());
+
+    // While the secret is cached, the user isn't prompted at all.
+    let (ctx, is, os) = create_normal_test_context("");
+    let secret = prompt_for_secret_cached(is, os, TEST_PROMPT, &cache, "passphrase", ttl).unwrap();
+    assert_eq!(b"hunter2", unsafe { secret.as_slice() });
+    assert_eq!("", ctx.write_buffer_as_str().unwrap());
+    assert!(ctx.has_default_attributes());
+
+    // Once it expires, they're prompted again.
+    clock.advance(ttl);
+    let (ctx, is, os) = create_normal_test_context("hunter3\n");
+    let secret = prompt_for_secret_cached(is, os, TEST_PROMPT, &cache, "passphrase", ttl).unwrap();
+    assert_eq!(b"hunter3", unsafe { secret.as_slice() });
+    assert_eq!(TEST_PROMPT, ctx.write_buffer_as_str().unwrap());
+}
+
+#[cfg(feature = "crypto")]
+#[test]
+fn test_prompt_for_new_password_policy() {
+    crate::init().unwrap();
+
+    // A relaxed policy accepts what the default one wouldn't.
+    let (ctx, is, os) = create_normal_test_context("Tr0ub4dor\nTr0ub4dor\n");
+    let policy = PasswordPolicy {
+        min_score: PasswordScore::Fair,
+        min_length: 6,
+    };
+    let secret = prompt_for_new_password(is, os, TEST_PROMPT, policy).unwrap();
+    assert_eq!(b"Tr0ub4dor", unsafe { secret.as_slice() });
+    assert_eq!(
+        format!("{}Confirm: ", TEST_PROMPT),
+        ctx.write_buffer_as_str().unwrap()
+    );
+
+    // A stricter one rejects it, even though it's long enough.
+    let (ctx, is, os) =
+        create_normal_test_context("Tr0ub4dor\nTr0ub4dor\nTr0ub4dor&3\nTr0ub4dor&3\n");
+    let policy = PasswordPolicy {
+        min_score: PasswordScore::Good,
+        min_length: 6,
+    };
+    let secret = prompt_for_new_password(is, os, TEST_PROMPT, policy).unwrap();
+    assert_eq!(b"Tr0ub4dor&3", unsafe { secret.as_slice() });
+    let written = ctx.write_buffer_as_str().unwrap();
+    assert!(written.contains("This password is too weak.\n"));
+    assert!(!written.contains("Use at least"));
+    assert!(!written.contains("Tr0ub4dor"));
+}
+
+#[test]
+fn test_verbosity_levels() {
+    crate::init().unwrap();
+
+    for (quiet, verbose, expected) in [
+        (0, 0, VerbosityLevel::Normal),
+        (1, 0, VerbosityLevel::Quiet),
+        (3, 0, VerbosityLevel::Quiet),
+        (0, 1, VerbosityLevel::Verbose),
+        (0, 2, VerbosityLevel::Debug),
+        (0, 5, VerbosityLevel::Debug),
+        // Conflicting flags cancel each other out.
+        (1, 1, VerbosityLevel::Normal),
+        (1, 2, VerbosityLevel::Verbose),
+        (2, 1, VerbosityLevel::Quiet),
+    ] {
+        assert_eq!(
+            expected,
+            Verbosity::new(quiet, verbose).level(),
+            "-q x{} -v x{}",
+            quiet,
+            verbose
+        );
+    }
+    assert_eq!(VerbosityLevel::Normal, Verbosity::default().level());
+}
+
+#[test]
+fn test_verbosity_log_filter() {
+    use tracing::level_filters::LevelFilter;
+
+    crate::init().unwrap();
+
+    for (level, expected) in [
+        (VerbosityLevel::Quiet, LevelFilter::ERROR),
+        (VerbosityLevel::Normal, LevelFilter::WARN),
+        (VerbosityLevel::Verbose, LevelFilter::INFO),
+        (VerbosityLevel::Debug, LevelFilter::DEBUG),
+    ] {
+        assert_eq!(expected, Verbosity::from_level(level).to_log_filter());
+    }
+}
+
+#[test]
+fn test_emit_respects_verbosity() {
+    crate::init().unwrap();
+
+    let mut ctx = TestContext::new("");
+    let os = ctx.as_stream(
+        /*isatty=*/ true, /*support_read=*/ false, /*support_write=*/ true,
+    );
+    let quiet = Verbosity::new(1, 0);
+    emit(&os, &quiet, VerbosityLevel::Quiet, format_args!("result")).unwrap();
+    emit(&os, &quiet, VerbosityLevel::Normal, format_args!("chatter")).unwrap();
+    emit_progress(&os, &quiet, format_args!("50%")).unwrap();
+    let verbose = Verbosity::new(0, 1);
+    emit(
+        &os,
+        &verbose,
+        VerbosityLevel::Verbose,
+        format_args!("details {}", 42),
+    )
+    .unwrap();
+    emit(
+        &os,
+        &verbose,
+        VerbosityLevel::Debug,
+        format_args!("internals"),
+    )
+    .unwrap();
+    assert_eq!("result\ndetails 42\n", ctx.write_buffer_as_str().unwrap());
+}
+
+#[test]
+fn test_emit_degrades_without_tty() {
+    crate::init().unwrap();
+
+    let verbosity = Verbosity::default();
+    assert!(verbosity.should_print_progress(true));
+    assert!(!verbosity.should_print_progress(false));
+    assert!(!Verbosity::new(1, 0).should_print_progress(true));
+
+    let colored = format_args!(
+        "\x1b[1;31merror\x1b[0m: \x1b]8;;https://example.com\x07link\x1b]8;;\x1b\\ done"
+    );
+
+    let mut ctx = TestContext::new("");
+    let os = ctx.as_stream(
+        /*isatty=*/ false, /*support_read=*/ false, /*support_write=*/ true,
+    );
+    emit_progress(&os, &verbosity, format_args!("50%")).unwrap();
+    emit(&os, &verbosity, VerbosityLevel::Normal, colored).unwrap();
+    assert_eq!("error: link done\n", ctx.write_buffer_as_str().unwrap());
+
+    let mut ctx = TestContext::new("");
+    let os = ctx.as_stream(
+        /*isatty=*/ true, /*support_read=*/ false, /*support_write=*/ true,
+    );
+    emit_progress(&os, &verbosity, format_args!("50%")).unwrap();
+    emit(&os, &verbosity, VerbosityLevel::Normal, colored).unwrap();
+    assert_eq!(
+        format!("\r\x1b[2K50%{}\n", colored),
+        ctx.write_buffer_as_str().unwrap()
+    );
+}
+
+#[test]
+fn test_wizard_straight_through() {
+    crate::init().unwrap();
+
+    let wizard = Wizard::new()
+        .step(WizardStep::new("dir", "Directory: ").default_value("/tmp"))
+        .step(WizardStep::new("mode", "Mode: ").select(&["fast", "safe"]))
+        .step(WizardStep::new("enabled", "Enabled: "));
+
+    let (ctx, is, os) = create_normal_test_context("\n2\nyes\n");
+    let answers = wizard.run(is, os).unwrap().unwrap();
+
+    assert_eq!(Some("/tmp"), answers.get("dir"));
+    assert_eq!(Some("safe"), answers.get("mode"));
+    assert!(answers.get_bool("enabled").unwrap());
+    assert!(answers.get("missing").is_none());
+    assert_eq!(
+        vec![("dir", "/tmp"), ("mode", "safe"), ("enabled", "yes")],
+        answers.iter().collect::<Vec<_>>()
+    );
+    assert!(ctx.has_default_attributes());
+    assert_eq!(
+        "Directory: [/tmp]   1) fast\n  2) safe\nMode: Enabled: ",
+        ctx.write_buffer_as_str().unwrap()
+    );
+}
+
+#[test]
+fn test_wizard_confirm_mismatch() {
+    crate::init().unwrap();
+
+    let wizard = Wizard::new().step(WizardStep::new("name", "Name: ").confirm());
+
+    let (ctx, is, os) = create_normal_test_context("foo\nbar\nfoo\nfoo\n");
+    let answers = wizard.run(is, os).unwrap().unwrap();
+
+    assert_eq!(Some("foo"), answers.get("name"));
+    assert_eq!(
+        "Name: Confirm: The values did not match. Please try again.\nName: Confirm: ",
+        ctx.write_buffer_as_str().unwrap()
+    );
+}
+
+#[test]
+fn test_wizard_back() {
+    crate::init().unwrap();
+
+    let wizard = Wizard::new()
+        .step(WizardStep::new("first", "First: "))
+        .step(WizardStep::new("second", "Second: "))
+        .with_summary();
+
+    // Go back from the second step to change the first answer, then go back
+    // from the summary and accept the previous second answer as-is.
+    let (ctx, is, os) = create_normal_test_context("a\nBACK\nb\nc\nback\n\nyes\n");
+    let answers = wizard.run(is, os).unwrap().unwrap();
+
+    assert_eq!(Some("b"), answers.get("first"));
+    assert_eq!(Some("c"), answers.get("second"));
+    let summary = "  first: b\n  second: c\nProceed with these settings? [Yes/No/back] ";
+    assert_eq!(
+        format!(
+            "First: Second: First: [a] Second: {}Second: [c] {}",
+            summary, summary
+        ),
+        ctx.write_buffer_as_str().unwrap()
+    );
+
+    // Declining at the summary returns nothing.
+    let (_ctx, is, os) = create_normal_test_context("a\nc\nno\n");
+    assert!(wizard.run(is, os).unwrap().is_none());
+}
+
+#[test]
+fn test_wizard_cancel() {
+    crate::init().unwrap();
+
+    let wizard = Wizard::new()
+        .step(WizardStep::new("first", "First: "))
+        .step(WizardStep::new("second", "Second: "))
+        .step(WizardStep::new("third", "Third: "));
+
+    // Note that the input after "cancel" is never read.
+    let (ctx, is, os) = create_normal_test_context("a\ncancel\nc\n");
+    assert!(wizard.run(is, os).unwrap().is_none());
+    assert_eq!("First: Second: ", ctx.write_buffer_as_str().unwrap());
+}
+
+#[test]
+fn test_wizard_sensitive() {
+    crate::init().unwrap();
+
+    let wizard = Wizard::new()
+        .step(WizardStep::new("user", "User: "))
+        .step(WizardStep::new("password", "Password: ").sensitive())
+        .with_summary();
+
+    let (ctx, is, os) = create_normal_test_context("bob\nhunter2\nback\nback\n\nhunter3\ny\n");
+    let answers = wizard.run(is, os).unwrap().unwrap();
+
+    assert_eq!(Some("bob"), answers.get("user"));
+    assert_eq!(Some("hunter3"), answers.get("password"));
+
+    // Each password prompt disabled echo, and restored it afterwards.
+    let disabled = TestTerminalAttributes::new_specific_state(
+        /*enabled=*/ &[TerminalFlag::EchoNewlines],
+        /*disabled=*/ &[TerminalFlag::Echo],
+    );
+    let expected_read_attributes_over_time: VecDeque<TestTerminalAttributes> = vec![
+        TestTerminalAttributes::default(),
+        disabled.clone(),
+        TestTerminalAttributes::default(),
+        disabled.clone(),
+        TestTerminalAttributes::default(),
+        disabled,
+        TestTerminalAttributes::default(),
+    ]
+    .into();
+    assert_eq!(
+        expected_read_attributes_over_time,
+        *ctx.read_attributes_over_time
+    );
+
+    // The previous password is neither pre-filled nor displayed anywhere.
+    let output = ctx.write_buffer_as_str().unwrap();
+    assert!(!output.contains("hunter"));
+    let summary = "  user: bob\n  password: ********\nProceed with these settings? [Yes/No/back] ";
+    assert_eq!(
+        format!(
+            "User: Password: {}Password: User: [bob] Password: {}",
+            summary, summary
+        ),
+        output
+    );
+}
+
+#[test]
+fn test_wizard_validator() {
+    crate::init().unwrap();
+
+    let wizard = Wizard::new().step(WizardStep::new("port", "Port: ").validator(|answer| {
+        answer
+            .parse::<u16>()
+            .map(|_| ())
+            .map_err(|_| "Not a port.".to_string())
+    }));
+
+    let (ctx, is, os) = create_normal_test_context("http\n8080\n");
+    let answers = wizard.run(is, os).unwrap().unwrap();
+
+    assert_eq!(8080, answers.get_as::<u16>("port").unwrap());
+    assert!(answers.get_as::<u16>("missing").is_err());
+    assert!(answers.get_bool("port").is_err());
+    assert_eq!(
+        "Port: Not a port.\nPort: ",
+        ctx.write_buffer_as_str().unwrap()
+    );
+}
+
+/// A minimal input stream whose attributes can be restored from any thread,
+/// and which calls `restore_terminal_attributes` when it's read from, as if
+/// e.g. a signal arrived while the user was typing.
+struct RestorableTestStream {
+    attributes: std::sync::Arc<Mutex<Vec<TestTerminalAttributes>>>,
+}
+
+impl AbstractStream for RestorableTestStream {
+    type Attributes = TestTerminalAttributes;
+
+    fn isatty(&self) -> bool {
+        true
+    }
+
+    fn get_attributes(&self) -> IoResult<Self::Attributes> {
+        Ok(self.attributes.lock().unwrap().last().unwrap().clone())
+    }
+
+    fn set_attributes(&mut self, attributes: &Self::Attributes) -> IoResult<()> {
+        self.attributes.lock().unwrap().push(attributes.clone());
+        Ok(())
+    }
+
+    fn as_reader(&self) -> Option<Box<dyn Read>> {
+        struct RestoringReader(bool);
+
+        impl Read for RestoringReader {
+            fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+                if self.0 {
+                    return Ok(0);
+                }
+                self.0 = true;
+                assert_eq!(1, restore_terminal_attributes());
+                buf[..7].copy_from_slice(b"hunter2");
+                buf[7] = b'\n';
+                Ok(8)
+            }
+        }
+
+        Some(Box::new(RestoringReader(false)))
+    }
+
+    fn as_writer(&self) -> Option<Box<dyn Write>> {
+        None
+    }
+
+    fn attribute_restorer(&self, attributes: &Self::Attributes) -> Option<AttributeRestorer> {
+        let stored = self.attributes.clone();
+        let attributes = attributes.clone();
+        Some(Box::new(move || {
+            stored.lock().unwrap().push(attributes.clone());
+            Ok(())
+        }))
+    }
+}
+
+#[test]
+fn test_restore_terminal_attributes_out_of_band() {
+    crate::init().unwrap();
+
+    let attributes = std::sync::Arc::new(Mutex::new(vec![TestTerminalAttributes::default()]));
+    let is = RestorableTestStream {
+        attributes: attributes.clone(),
+    };
+    let mut ctx = TestContext::new("");
+    let os = ctx.as_stream(
+        /*isatty=*/ true, /*support_read=*/ false, /*support_write=*/ true,
+    );
+
+    let result = prompt_for_string(is, os, TEST_PROMPT, /*is_sensitive=*/ true).unwrap();
+    assert_eq!("hunter2", result);
+
+    // Echo was disabled, restored out-of-band while reading, and then restored
+    // again (harmlessly) when the prompt finished.
+    let disabled = TestTerminalAttributes::new_specific_state(
+        /*enabled=*/ &[TerminalFlag::EchoNewlines],
+        /*disabled=*/ &[TerminalFlag::Echo],
+    );
+    assert_eq!(
+        vec![
+            TestTerminalAttributes::default(),
+            disabled,
+            TestTerminalAttributes::default(),
+            TestTerminalAttributes::default(),
+        ],
+        *attributes.lock().unwrap()
+    );
+    // The guard unregistered itself, so there's nothing left to restore.
+    assert_eq!(0, restore_terminal_attributes());
+}
+
+fn echo_disabled_attributes() -> TestTerminalAttributes {
+    TestTerminalAttributes::new_specific_state(
+        /*enabled=*/ &[TerminalFlag::EchoNewlines],
+        /*disabled=*/ &[TerminalFlag::Echo],
+    )
+}
+
+#[test]
+fn test_run_with_terminal_restores() {
+    crate::init().unwrap();
+
+    let mut ctx = TestContext::new("");
+    let mut streams = vec![ctx.as_stream(
+        /*isatty=*/ true, /*support_read=*/ false, /*support_write=*/ true,
+    )];
+    let result = run_with_terminal_streams(streams.as_mut_slice(), || {
+        // E.g. a subprocess which disables echo, and then exits without
+        // restoring it.
+        let mut os = ctx.as_stream(
+            /*isatty=*/ true, /*support_read=*/ false, /*support_write=*/ true,
+        );
+        os.set_attributes(&echo_disabled_attributes()).unwrap();
+        42
+    })
+    .unwrap();
+    assert_eq!(42, result);
+    assert_eq!(
+        vec![
+            TestTerminalAttributes::default(),
+            echo_disabled_attributes(),
+            TestTerminalAttributes::default(),
+        ],
+        Vec::from(*ctx.write_attributes_over_time.clone())
+    );
+}
+
+#[test]
+fn test_run_with_terminal_restores_after_panic() {
+    crate::init().unwrap();
+
+    let mut ctx = TestContext::new("");
+    let mut streams = vec![ctx.as_stream(
+        /*isatty=*/ true, /*support_read=*/ false, /*support_write=*/ true,
+    )];
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        run_with_terminal_streams(streams.as_mut_slice(), || {
+            let mut os = ctx.as_stream(
+                /*isatty=*/ true, /*support_read=*/ false, /*support_write=*/ true,
+            );
+            os.set_attributes(&echo_disabled_attributes()).unwrap();
+            panic!("the subprocess misbehaved");
+        })
+    }));
+    // The panic is resumed, but only after the attributes were restored.
+    assert!(result.is_err());
+    assert_eq!(
+        vec![
+            TestTerminalAttributes::default(),
+            echo_disabled_attributes(),
+            TestTerminalAttributes::default(),
+        ],
+        Vec::from(*ctx.write_attributes_over_time.clone())
+    );
+}
+
+#[test]
+fn test_terminal_state_guard_nesting() {
+    crate::init().unwrap();
+
+    let mut ctx = TestContext::new("");
+    let mut outer_stream = ctx.as_stream(
+        /*isatty=*/ true, /*support_read=*/ false, /*support_write=*/ true,
+    );
+    let mut inner_stream = ctx.as_stream(
+        /*isatty=*/ true, /*support_read=*/ false, /*support_write=*/ true,
+    );
+    let no_newlines = TestTerminalAttributes::new_specific_state(
+        /*enabled=*/ &[],
+        /*disabled=*/ &[TerminalFlag::EchoNewlines],
+    );
+
+    {
+        let mut outer = TerminalStateGuard::capture(&mut outer_stream).unwrap();
+        outer.stream().set_attributes(&no_newlines).unwrap();
+        {
+            let mut inner = TerminalStateGuard::capture(&mut inner_stream).unwrap();
+            assert_eq!(&no_newlines, inner.attributes());
+            inner
+                .stream()
+                .set_attributes(&echo_disabled_attributes())
+                .unwrap();
+        }
+        // The inner guard restored the state it captured, not the original.
+        assert_eq!(Some(&no_newlines), ctx.write_attributes_over_time.back());
+        outer.restore().unwrap();
+    }
+
+    assert_eq!(
+        vec![
+            TestTerminalAttributes::default(),
+            no_newlines.clone(),
+            echo_disabled_attributes(),
+            no_newlines,
+            TestTerminalAttributes::default(),
+        ],
+        Vec::from(*ctx.write_attributes_over_time.clone())
+    );
+}
+
+/// Run a small command which emits some text, a value, and a table through an
+/// `OutputSink` in the given mode, and return what it wrote.
+fn run_output_command(mode: &str, text_policy: TextPolicy) -> Result<String> {
+    let mut ctx = TestContext::new("");
+    let os = ctx.as_stream(
+        /*isatty=*/ false, /*support_read=*/ false, /*support_write=*/ true,
+    );
+    let mut sink = OutputSink::new(mode.parse()?, os)?.with_text_policy(text_policy);
+    sink.emit_text(format_args!("listing {} items", 2))?;
+    let mut table = Table::new().header(["name", "size"]);
+    table.add_row(["foo", "1"]);
+    table.add_row(["bar"]);
+    sink.emit_table(&table)?;
+    sink.finish()?;
+    Ok(ctx.write_buffer_as_str()?.to_string())
+}
+
+#[test]
+fn test_output_mode_parse() {
+    crate::init().unwrap();
+
+    for &mode in OutputMode::ALL {
+        assert_eq!(mode, mode.to_string().parse::<OutputMode>().unwrap());
+    }
+    assert_eq!(OutputMode::Text, OutputMode::default());
+    assert_eq!(ErrorFormat::Json, OutputMode::JsonPretty.error_format());
+
+    let error = "yaml".parse::<OutputMode>().unwrap_err();
+    assert_eq!(
+        "invalid argument: invalid output mode 'yaml' (expected one of: text, json, json-pretty)",
+        error.to_string()
+    );
+}
+
+#[test]
+fn test_output_sink_command_text() {
+    crate::init().unwrap();
+
+    assert_eq!(
+        "listing 2 items\nname\tsize\nfoo\t1\nbar\n",
+        run_output_command("text", TextPolicy::Error).unwrap()
+    );
+}
+
+#[test]
+fn test_output_sink_command_json() {
+    crate::init().unwrap();
+
+    // By default, stray text is an error in the JSON modes.
+    match run_output_command("json", TextPolicy::Error) {
+        Err(Error::Precondition(_)) => {}
+        other => panic!("expected a precondition error, got {:?}", other),
+    }
+
+    assert_eq!(
+        "{\"messages\":[\"listing 2 items\"],\"result\":[{\"name\":\"foo\",\"size\":\"1\"},{\"name\":\"bar\",\"size\":\"\"}]}\n",
+        run_output_command("json", TextPolicy::Collect).unwrap()
+    );
+    assert_eq!(
+        r#"{
+  "messages": [
+    "listing 2 items"
+  ],
+  "result": [
+    {
+      "name": "foo",
+      "size": "1"
+    },
+    {
+      "name": "bar",
+      "size": ""
+    }
+  ]
+}
+"#,
+        run_output_command("json-pretty", TextPolicy::Collect).unwrap()
+    );
+}
+
+#[test]
+fn test_output_sink_emit_value() {
+    crate::init().unwrap();
+
+    let value = serde_json::json!({"id": 7, "tags": ["a"]});
+    let cases = [
+        (
+            OutputMode::Text,
+            "{\n  \"id\": 7,\n  \"tags\": [\n    \"a\"\n  ]\n}\n",
+        ),
+        (OutputMode::Json, "{\"id\":7,\"tags\":[\"a\"]}\n"),
+        (
+            OutputMode::JsonPretty,
+            "{\n  \"id\": 7,\n  \"tags\": [\n    \"a\"\n  ]\n}\n",
+        ),
+    ];
+    for (mode, expected) in cases {
+        let mut ctx = TestContext::new("");
+        let os = ctx.as_stream(
+            /*isatty=*/ false, /*support_read=*/ false, /*support_write=*/ true,
+        );
+        let mut sink = OutputSink::new(mode, os).unwrap();
+        sink.emit_value(&value).unwrap();
+        if mode.is_json() {
+            // Only a single document can be emitted in JSON mode.
+            assert!(sink.emit_value(&value).is_err());
+        }
+        sink.finish().unwrap();
+        assert_eq!(expected, ctx.write_buffer_as_str().unwrap());
+    }
+}
+
+#[test]
+fn test_output_sink_emit_error() {
+    crate::init().unwrap();
+
+    let error = Error::NotFound("foo".to_string());
+
+    let mut ctx = TestContext::new("");
+    let os = ctx.as_stream(
+        /*isatty=*/ false, /*support_read=*/ false, /*support_write=*/ true,
+    );
+    let mut sink = OutputSink::new(OutputMode::Text, os).unwrap();
+    sink.emit_error(&error).unwrap();
+    sink.finish().unwrap();
+    assert_eq!(
+        "error: not found: foo\n",
+        ctx.write_buffer_as_str().unwrap()
+    );
+
+    let mut ctx = TestContext::new("");
+    let os = ctx.as_stream(
+        /*isatty=*/ false, /*support_read=*/ false, /*support_write=*/ true,
+    );
+    let mut sink = OutputSink::new(OutputMode::Json, os).unwrap();
+    sink.emit_value(&[1, 2]).unwrap();
+    sink.emit_error(&error).unwrap();
+    sink.finish().unwrap();
+    let value: serde_json::Value =
+        serde_json::from_str(ctx.write_buffer_as_str().unwrap()).unwrap();
+    assert_eq!("NOT_FOUND", value["error"]["code"]);
+}
+
+#[test]
+fn test_output_sink_json_strips_styling() {
+    crate::init().unwrap();
+
+    let mut ctx = TestContext::new("");
+    let os = ctx.as_stream(
+        /*isatty=*/ false, /*support_read=*/ false, /*support_write=*/ true,
+    );
+    let mut sink = OutputSink::new(OutputMode::Json, os)
+        .unwrap()
+        .with_text_policy(TextPolicy::Collect);
+    sink.emit_text(format_args!("\x1b[1mdone\x1b[0m")).unwrap();
+    let mut table = Table::new().header(["\x1b[1mname\x1b[0m", "link"]);
+    table.add_row([
+        "\x1b[31mfoo\x1b[0m",
+        "\x1b]8;;https://example.com\x1b\\example\x1b]8;;\x1b\\",
+    ]);
+    sink.emit_table(&table).unwrap();
+    sink.finish().unwrap();
+    assert_eq!(
+        "{\"messages\":[\"done\"],\"result\":[{\"link\":\"example\",\"name\":\"foo\"}]}\n",
+        ctx.write_buffer_as_str().unwrap()
+    );
+}
+
+#[test]
+fn test_output_sink_table_needs_header() {
+    crate::init().unwrap();
+
+    let mut ctx = TestContext::new("");
+    let os = ctx.as_stream(
+        /*isatty=*/ false, /*support_read=*/ false, /*support_write=*/ true,
+    );
+    let mut sink = OutputSink::new(OutputMode::Json, os).unwrap();
+    let mut table = Table::new();
+    table.add_row(["foo"]);
+    match sink.emit_table(&table) {
+        Err(Error::InvalidArgument(_)) => {}
+        other => panic!("expected an invalid argument error, got {:?}", other),
+    }
+}
+
+/// Signal handlers (and the record of which signals arrived) are global, so
+/// tests which raise signals must not run concurrently.
+static SIGNAL_TEST_LOCK: Mutex<()> = Mutex::new(());
+static EXITED_WITH_SIGNAL: std::sync::atomic::AtomicI32 = std::sync::atomic::AtomicI32::new(0);
+
+fn record_exit(signal: libc::c_int) {
+    EXITED_WITH_SIGNAL.store(signal, std::sync::atomic::Ordering::SeqCst);
+}
+
+fn wait_for<F: FnMut() -> bool>(mut condition: F) {
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+    while !condition() {
+        assert!(std::time::Instant::now() < deadline, "timed out waiting");
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    }
+}
+
+#[test]
+fn test_signal_exit_code() {
+    crate::init().unwrap();
+
+    assert_eq!(130, signal_exit_code(libc::SIGINT));
+    assert_eq!(143, signal_exit_code(libc::SIGTERM));
+    assert_eq!(None, SignalOutcome::Completed(()).exit_code());
+    assert_eq!(
+        Some(143),
+        SignalOutcome::Interrupted {
+            signal: libc::SIGTERM,
+            result: ()
+        }
+        .exit_code()
+    );
+}
+
+#[test]
+fn test_run_with_signals_cooperative() {
+    crate::init().unwrap();
+    let _lock = SIGNAL_TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    let options = SignalOptions {
+        grace_period: std::time::Duration::from_secs(60),
+    };
+    let outcome = run_with_signals(options, |token| {
+        assert!(!token.is_cancelled());
+        assert_eq!(0, unsafe { libc::raise(libc::SIGINT) });
+        // The callback has a chance to clean up, and return on its own.
+        wait_for(|| token.is_cancelled());
+        "cleaned up"
+    })
+    .unwrap();
+    assert_eq!(Some(130), outcome.exit_code());
+    assert_eq!(
+        SignalOutcome::Interrupted {
+            signal: libc::SIGINT,
+            result: "cleaned up"
+        },
+        outcome
+    );
+
+    // Signals from a previous run are forgotten.
+    let outcome = run_with_signals(options, |token| token.is_cancelled()).unwrap();
+    assert_eq!(SignalOutcome::Completed(false), outcome);
+}
+
+#[test]
+fn test_run_with_signals_exits() {
+    crate::init().unwrap();
+    let _lock = SIGNAL_TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let exited = || EXITED_WITH_SIGNAL.swap(0, std::sync::atomic::Ordering::SeqCst);
+    exited();
+
+    // A callback which doesn't return in time is cut off.
+    let options = SignalOptions {
+        grace_period: std::time::Duration::from_millis(50),
+    };
+    let mut exit_signal = 0;
+    let outcome = run_with_signals_impl(
+        options,
+        |_token| {
+            assert_eq!(0, unsafe { libc::raise(libc::SIGTERM) });
+            wait_for(|| {
+                exit_signal = exited();
+                exit_signal != 0
+            });
+        },
+        record_exit,
+    )
+    .unwrap();
+    assert_eq!(libc::SIGTERM, exit_signal);
+    assert_eq!(Some(143), outcome.exit_code());
+
+    // A second signal doesn't wait for the grace period.
+    let options = SignalOptions {
+        grace_period: std::time::Duration::from_secs(60),
+    };
+    let mut exit_signal = 0;
+    run_with_signals_impl(
+        options,
+        |_token| {
+            assert_eq!(0, unsafe { libc::raise(libc::SIGINT) });
+            assert_eq!(0, unsafe { libc::raise(libc::SIGINT) });
+            wait_for(|| {
+                exit_signal = exited();
+                exit_signal != 0
+            });
+        },
+        record_exit,
+    )
+    .unwrap();
+    assert_eq!(libc::SIGINT, exit_signal);
+}
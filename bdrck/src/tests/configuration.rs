@@ -13,17 +13,65 @@
 // limitations under the License.
 
 use crate::configuration;
+use crate::error::*;
 use crate::testing::temp;
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use std::fs;
 use std::path;
+use std::time::Duration;
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 struct TestConfiguration {
     foo: String,
 }
 
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+struct ServerConfiguration {
+    host: String,
+    port: u16,
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+struct NestedConfiguration {
+    server: ServerConfiguration,
+    name: Option<String>,
+}
+
+fn new_nested_configuration(
+    file: &temp::File,
+) -> configuration::Configuration<NestedConfiguration> {
+    // Remove the file: an empty file isn't a valid serialized configuration struct.
+    fs::remove_file(file.path()).unwrap();
+    configuration::Configuration::new(
+        configuration::Identifier {
+            application: "bdrck_config".to_owned(),
+            name: "nested".to_owned(),
+        },
+        NestedConfiguration {
+            server: ServerConfiguration {
+                host: "localhost".to_owned(),
+                port: 8080,
+            },
+            name: Some("foo".to_owned()),
+        },
+        Some(file.path()),
+    )
+    .unwrap()
+}
+
+// The length of the integrity header persisted configuration files start with.
+const ENVELOPE_HEADER_LEN: usize = 21;
+
+/// Read a persisted configuration file directly, without going through
+/// `Configuration`.
+fn read_persisted<T: serde::de::DeserializeOwned>(path: &path::Path) -> T {
+    let data = fs::read(path).unwrap();
+    assert!(data.starts_with(b"BDRCKCFG"));
+    rmp_serde::from_slice(&data[ENVELOPE_HEADER_LEN..]).unwrap()
+}
+
 static TEST_IDENTIFIER: Lazy<configuration::Identifier> = Lazy::new(|| configuration::Identifier {
     application: "bdrck_config".to_owned(),
     name: "test".to_owned(),
@@ -78,3 +126,2285 @@ fn test_persistence() {
         .unwrap();
     assert_eq!(default, configuration::get(&TEST_IDENTIFIER).ok().unwrap());
 }
+
+#[test]
+fn test_apply_patch_nested_merge() {
+    crate::init().unwrap();
+
+    let file = temp::File::new_file().unwrap();
+    let mut config = new_nested_configuration(&file);
+    config
+        .apply_patch(json!({"server": {"port": 9090}}))
+        .unwrap();
+    assert_eq!("localhost", config.get().server.host);
+    assert_eq!(9090, config.get().server.port);
+    assert_eq!(Some("foo".to_owned()), config.get().name);
+
+    // The patched configuration should have been persisted.
+    let expected = config.get().clone();
+    let config = configuration::Configuration::new(
+        configuration::Identifier {
+            application: "bdrck_config".to_owned(),
+            name: "nested".to_owned(),
+        },
+        expected.clone(),
+        Some(file.path()),
+    )
+    .unwrap();
+    assert_eq!(&expected, config.get());
+}
+
+#[test]
+fn test_apply_patch_null_clears_option() {
+    crate::init().unwrap();
+
+    let file = temp::File::new_file().unwrap();
+    let mut config = new_nested_configuration(&file);
+    config.apply_patch(json!({"name": null})).unwrap();
+    assert_eq!(None, config.get().name);
+    assert_eq!(8080, config.get().server.port);
+}
+
+#[test]
+fn test_apply_patch_type_mismatch() {
+    crate::init().unwrap();
+
+    let file = temp::File::new_file().unwrap();
+    let mut config = new_nested_configuration(&file);
+    let original = config.get().clone();
+    assert!(config
+        .apply_patch(json!({"server": {"port": "not a port"}}))
+        .is_err());
+    assert!(config.apply_patch(json!({"server": null})).is_err());
+    assert_eq!(&original, config.get());
+    // Nothing should have been persisted, either.
+    assert!(!file.path().exists());
+}
+
+#[test]
+fn test_diff_apply_round_trip() {
+    crate::init().unwrap();
+
+    let file = temp::File::new_file().unwrap();
+    let mut config = new_nested_configuration(&file);
+    let updated = NestedConfiguration {
+        server: ServerConfiguration {
+            host: "example.com".to_owned(),
+            port: 8080,
+        },
+        name: None,
+    };
+
+    let patch = config.diff(&updated).unwrap();
+    assert_eq!(
+        json!({"server": {"host": "example.com"}, "name": null}),
+        patch
+    );
+    config.apply_patch(patch).unwrap();
+    assert_eq!(&updated, config.get());
+    assert_eq!(json!({}), config.diff(&updated).unwrap());
+}
+
+fn new_test_configuration(
+    path: &path::Path,
+    mode: configuration::PersistMode,
+) -> configuration::Configuration<TestConfiguration> {
+    configuration::Configuration::new_with_mode(
+        TEST_IDENTIFIER.clone(),
+        TestConfiguration {
+            foo: "default".to_owned(),
+        },
+        Some(path),
+        mode,
+    )
+    .unwrap()
+}
+
+#[test]
+fn test_read_only() {
+    use std::os::unix::fs::PermissionsExt;
+
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let path = dir.sub_path("config.mp").unwrap();
+    {
+        let mut config = new_test_configuration(&path, configuration::PersistMode::Immediate);
+        config.set(TestConfiguration {
+            foo: "persisted".to_owned(),
+        });
+        config.persist().unwrap();
+    }
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o444)).unwrap();
+    let original = fs::read(&path).unwrap();
+
+    let mut config = configuration::Configuration::open_read_only(
+        &path,
+        TestConfiguration {
+            foo: "default".to_owned(),
+        },
+    )
+    .unwrap();
+    assert_eq!(configuration::PersistMode::ReadOnly, config.mode());
+    assert_eq!("persisted", config.get().foo);
+
+    let updated = TestConfiguration {
+        foo: "updated".to_owned(),
+    };
+    assert!(matches!(
+        config.try_set(updated),
+        Err(Error::ReadOnlyConfiguration(_))
+    ));
+    assert!(matches!(
+        config.try_reset(),
+        Err(Error::ReadOnlyConfiguration(_))
+    ));
+    assert!(matches!(
+        config.apply_patch(json!({"foo": "patched"})),
+        Err(Error::ReadOnlyConfiguration(_))
+    ));
+    assert!(matches!(
+        config.persist(),
+        Err(Error::ReadOnlyConfiguration(_))
+    ));
+    assert_eq!("persisted", config.get().foo);
+    assert!(!config.dirty());
+
+    // set and reset can't fail, so they leave the values untouched.
+    config.set(TestConfiguration {
+        foo: "in memory".to_owned(),
+    });
+    config.reset();
+    assert_eq!("persisted", config.get().foo);
+    assert!(!config.dirty());
+
+    // Even with persist-on-drop enabled, nothing is written.
+    config.persist_on_drop(true);
+    drop(config);
+    assert_eq!(original, fs::read(&path).unwrap());
+}
+
+#[test]
+fn test_configuration_is_sync() {
+    fn assert_sync<T: Sync>() {}
+
+    crate::init().unwrap();
+
+    // So it can be shared between threads, e.g. behind an Arc.
+    assert_sync::<configuration::Configuration<TestConfiguration>>();
+}
+
+#[test]
+fn test_explicit_persist_batches_writes() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let path = dir.sub_path("nested.mp").unwrap();
+    let mut config = configuration::Configuration::new_with_mode(
+        TEST_IDENTIFIER.clone(),
+        NestedConfiguration {
+            server: ServerConfiguration {
+                host: "localhost".to_owned(),
+                port: 8080,
+            },
+            name: None,
+        },
+        Some(path.as_path()),
+        configuration::PersistMode::Explicit,
+    )
+    .unwrap();
+    assert!(!config.dirty());
+
+    // None of these mutations should touch the disk.
+    config
+        .apply_patch(json!({"server": {"host": "example.com"}}))
+        .unwrap();
+    config
+        .apply_patch(json!({"server": {"port": 443}}))
+        .unwrap();
+    config.apply_patch(json!({"name": "foo"})).unwrap();
+    config.apply_patch(json!({"name": "bar"})).unwrap();
+    config.apply_patch(json!({"name": null})).unwrap();
+    assert!(config.dirty());
+    assert!(!path.exists());
+
+    config.persist().unwrap();
+    assert!(!config.dirty());
+    assert!(path.exists());
+
+    let reloaded = new_nested_configuration_at(&path);
+    assert_eq!("example.com", reloaded.get().server.host);
+    assert_eq!(443, reloaded.get().server.port);
+    assert_eq!(None, reloaded.get().name);
+}
+
+fn new_nested_configuration_at(
+    path: &path::Path,
+) -> configuration::Configuration<NestedConfiguration> {
+    configuration::Configuration::open_read_only(
+        path,
+        NestedConfiguration {
+            server: ServerConfiguration {
+                host: "localhost".to_owned(),
+                port: 8080,
+            },
+            name: None,
+        },
+    )
+    .unwrap()
+}
+
+#[test]
+fn test_immediate_mode_unchanged() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let path = dir.sub_path("config.mp").unwrap();
+    let mut config = new_test_configuration(&path, configuration::PersistMode::Immediate);
+
+    // Patches are still persisted immediately by default.
+    config.apply_patch(json!({"foo": "patched"})).unwrap();
+    assert!(!config.dirty());
+    assert!(path.exists());
+
+    // But other changes are only persisted explicitly.
+    config.set(TestConfiguration {
+        foo: "set".to_owned(),
+    });
+    assert!(config.dirty());
+    drop(config);
+    assert_eq!(
+        "patched",
+        new_test_configuration(&path, configuration::PersistMode::ReadOnly)
+            .get()
+            .foo
+    );
+}
+
+#[test]
+fn test_persist_on_drop() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let path = dir.sub_path("config.mp").unwrap();
+
+    {
+        let mut config = new_test_configuration(&path, configuration::PersistMode::Explicit);
+        config.set(TestConfiguration {
+            foo: "not persisted".to_owned(),
+        });
+    }
+    assert!(!path.exists());
+
+    {
+        let mut config = new_test_configuration(&path, configuration::PersistMode::Explicit);
+        config.persist_on_drop(true);
+        config.set(TestConfiguration {
+            foo: "persisted on drop".to_owned(),
+        });
+    }
+    assert_eq!(
+        "persisted on drop",
+        new_test_configuration(&path, configuration::PersistMode::ReadOnly)
+            .get()
+            .foo
+    );
+}
+
+#[test]
+fn test_export_import_across_formats() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let mut original = new_test_configuration(
+        dir.sub_path("original.mp").unwrap().as_path(),
+        configuration::PersistMode::Immediate,
+    );
+    original.set(TestConfiguration {
+        foo: "exported".to_owned(),
+    });
+    original.persist().unwrap();
+
+    for &format in &[
+        configuration::PersistenceFormat::Json,
+        configuration::PersistenceFormat::MessagePack,
+    ] {
+        let mut exported = Vec::new();
+        original.export_to(&mut exported, format).unwrap();
+
+        let path = dir.sub_path(format!("{:?}.mp", format)).unwrap();
+        let mut imported = new_test_configuration(&path, configuration::PersistMode::Immediate);
+        assert_eq!("default", imported.get().foo);
+        imported.import_from(exported.as_slice(), format).unwrap();
+        assert_eq!(original.get(), imported.get());
+
+        // The imported values should have been persisted, too.
+        let reloaded = new_test_configuration(&path, configuration::PersistMode::ReadOnly);
+        assert_eq!(original.get(), reloaded.get());
+    }
+}
+
+#[test]
+fn test_import_persisted_file() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let original_path = dir.sub_path("original.mp").unwrap();
+    let mut original =
+        new_test_configuration(&original_path, configuration::PersistMode::Immediate);
+    original.set(TestConfiguration {
+        foo: "persisted".to_owned(),
+    });
+    original.persist().unwrap();
+
+    // A persisted file has an integrity header, which must be stripped.
+    let path = dir.sub_path("imported.mp").unwrap();
+    let mut imported = new_test_configuration(&path, configuration::PersistMode::Immediate);
+    imported
+        .import_from(
+            fs::File::open(&original_path).unwrap(),
+            configuration::PersistenceFormat::MessagePack,
+        )
+        .unwrap();
+    assert_eq!(original.get(), imported.get());
+    let reloaded = new_test_configuration(&path, configuration::PersistMode::ReadOnly);
+    assert_eq!(original.get(), reloaded.get());
+
+    // ... and checked.
+    let mut corrupted = fs::read(&original_path).unwrap();
+    let last = corrupted.len() - 1;
+    corrupted[last] ^= 0xff;
+    match imported.import_from(
+        corrupted.as_slice(),
+        configuration::PersistenceFormat::MessagePack,
+    ) {
+        Err(Error::ConfigCorrupted { .. }) => {}
+        r => panic!("expected a corrupted error, got {:?}", r),
+    }
+    assert_eq!(original.get(), imported.get());
+}
+
+#[test]
+fn test_import_invalid_payload() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let path = dir.sub_path("config.mp").unwrap();
+    let mut config = new_test_configuration(&path, configuration::PersistMode::Immediate);
+    config.set(TestConfiguration {
+        foo: "existing".to_owned(),
+    });
+    config.persist().unwrap();
+
+    let payload = br#"{"bar": 123}"#;
+    assert!(config
+        .import_from(&payload[..], configuration::PersistenceFormat::Json)
+        .is_err());
+    assert_eq!("existing", config.get().foo);
+    let reloaded = new_test_configuration(&path, configuration::PersistMode::ReadOnly);
+    assert_eq!("existing", reloaded.get().foo);
+}
+
+#[test]
+fn test_import_patch() {
+    crate::init().unwrap();
+
+    let file = temp::File::new_file().unwrap();
+    let mut config = new_nested_configuration(&file);
+    let payload = br#"{"server": {"port": 443}}"#;
+    config
+        .import_patch_from(&payload[..], configuration::PersistenceFormat::Json)
+        .unwrap();
+    assert_eq!("localhost", config.get().server.host);
+    assert_eq!(443, config.get().server.port);
+    assert_eq!(Some("foo".to_owned()), config.get().name);
+}
+
+#[test]
+fn test_json_export_is_deterministic() {
+    crate::init().unwrap();
+
+    let file = temp::File::new_file().unwrap();
+    let config = new_nested_configuration(&file);
+    let mut first = Vec::new();
+    config
+        .export_to(&mut first, configuration::PersistenceFormat::Json)
+        .unwrap();
+    let mut second = Vec::new();
+    config
+        .export_to(&mut second, configuration::PersistenceFormat::Json)
+        .unwrap();
+    assert_eq!(first, second);
+
+    // Keys should be sorted, regardless of struct field order.
+    let exported = std::str::from_utf8(&first).unwrap();
+    assert!(exported.find("\"name\"").unwrap() < exported.find("\"server\"").unwrap());
+}
+
+#[test]
+fn test_env_overrides() {
+    crate::init().unwrap();
+
+    std::env::set_var("BDRCK_CFGENV_SERVER__PORT", "9090");
+    std::env::set_var("BDRCK_CFGENV_name", "from env");
+
+    let file = temp::File::new_file().unwrap();
+    let mut config = new_nested_configuration(&file)
+        .with_env_prefix("bdrck_cfgenv")
+        .unwrap();
+    assert_eq!("localhost", config.get().server.host);
+    assert_eq!(9090, config.get().server.port);
+    assert_eq!(Some("from env".to_owned()), config.get().name);
+
+    // Overrides are re-applied on top of any changes to the underlying values.
+    config
+        .apply_patch(json!({"server": {"host": "example.com"}}))
+        .unwrap();
+    assert_eq!("example.com", config.get().server.host);
+    assert_eq!(9090, config.get().server.port);
+
+    // The overrides should never be persisted.
+    config.persist().unwrap();
+    std::env::remove_var("BDRCK_CFGENV_SERVER__PORT");
+    std::env::remove_var("BDRCK_CFGENV_name");
+    let mut exported = Vec::new();
+    config
+        .export_to(&mut exported, configuration::PersistenceFormat::Json)
+        .unwrap();
+    let exported: serde_json::Value = serde_json::from_slice(&exported).unwrap();
+    assert_eq!(
+        json!({"server": {"host": "example.com", "port": 8080}, "name": "foo"}),
+        exported
+    );
+    let reloaded: NestedConfiguration = read_persisted(file.path());
+    assert_eq!(8080, reloaded.server.port);
+    assert_eq!(Some("foo".to_owned()), reloaded.name);
+}
+
+#[test]
+fn test_env_override_wrong_type() {
+    crate::init().unwrap();
+
+    std::env::set_var("BDRCK_CFGENVTYPE_SERVER__PORT", "not a port");
+    let file = temp::File::new_file().unwrap();
+    let result = new_nested_configuration(&file).with_env_prefix("BDRCK_CFGENVTYPE");
+    std::env::remove_var("BDRCK_CFGENVTYPE_SERVER__PORT");
+
+    match result {
+        Err(Error::InvalidArgument(message)) => {
+            assert!(message.contains("BDRCK_CFGENVTYPE_SERVER__PORT"))
+        }
+        _ => panic!("expected an InvalidArgument error"),
+    }
+}
+
+#[test]
+fn test_env_override_unknown_field() {
+    crate::init().unwrap();
+
+    std::env::set_var("BDRCK_CFGENVUNKNOWN_SERVER__BOGUS", "1");
+
+    let file = temp::File::new_file().unwrap();
+    let config = new_nested_configuration(&file)
+        .with_env_prefix("BDRCK_CFGENVUNKNOWN")
+        .unwrap();
+    assert_eq!(8080, config.get().server.port);
+
+    let file = temp::File::new_file().unwrap();
+    let result = new_nested_configuration(&file)
+        .with_env_overrides(configuration::EnvOverrides::new("BDRCK_CFGENVUNKNOWN").strict(true));
+    std::env::remove_var("BDRCK_CFGENVUNKNOWN_SERVER__BOGUS");
+    assert!(result.is_err());
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+#[test]
+fn test_xdg_fallbacks() {
+    use crate::configuration::paths::{resolve_with, BaseDirectory};
+    use std::ffi::OsString;
+
+    crate::init().unwrap();
+
+    let home_only = |name: &str| match name {
+        "HOME" => Some(OsString::from("/home/bdrck")),
+        _ => None,
+    };
+    for (kind, expected) in [
+        (BaseDirectory::Config, "/home/bdrck/.config/app"),
+        (BaseDirectory::Data, "/home/bdrck/.local/share/app"),
+        (BaseDirectory::Cache, "/home/bdrck/.cache/app"),
+        (BaseDirectory::State, "/home/bdrck/.local/state/app"),
+    ] {
+        assert_eq!(
+            path::PathBuf::from(expected),
+            resolve_with(kind, "app", false, home_only).unwrap()
+        );
+    }
+
+    // An absolute XDG variable wins, but a relative one must be ignored.
+    let with_xdg = |value: &'static str| {
+        move |name: &str| match name {
+            "HOME" => Some(OsString::from("/home/bdrck")),
+            "XDG_DATA_HOME" => Some(OsString::from(value)),
+            _ => None,
+        }
+    };
+    assert_eq!(
+        path::PathBuf::from("/srv/data/app"),
+        resolve_with(BaseDirectory::Data, "app", false, with_xdg("/srv/data")).unwrap()
+    );
+    assert_eq!(
+        path::PathBuf::from("/home/bdrck/.local/share/app"),
+        resolve_with(BaseDirectory::Data, "app", false, with_xdg("relative")).unwrap()
+    );
+
+    match resolve_with(BaseDirectory::Cache, "app", false, |_: &str| None) {
+        Err(Error::NotFound(_)) => {}
+        _ => panic!("expected a NotFound error"),
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_xdg_ensure_creates_private_directory() {
+    use crate::configuration::paths::{resolve_with, BaseDirectory};
+    use std::os::unix::fs::PermissionsExt;
+
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let state_home = dir.path().join("state");
+    let lookup = |name: &str| match name {
+        "XDG_STATE_HOME" => Some(state_home.clone().into_os_string()),
+        _ => None,
+    };
+
+    let path = resolve_with(BaseDirectory::State, "app", false, lookup).unwrap();
+    assert!(!path.exists());
+
+    let path = resolve_with(BaseDirectory::State, "app", true, lookup).unwrap();
+    assert_eq!(state_home.join("app"), path);
+    assert!(path.is_dir());
+    assert_eq!(
+        0o700,
+        fs::metadata(&path).unwrap().permissions().mode() & 0o777
+    );
+}
+
+#[cfg(not(windows))]
+#[test]
+fn test_open_default() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    std::env::set_var("XDG_CONFIG_HOME", dir.path());
+    let result = configuration::Configuration::open_default(
+        "bdrck_open_default",
+        "settings.mp",
+        TestConfiguration {
+            foo: "bar".to_owned(),
+        },
+    );
+    std::env::remove_var("XDG_CONFIG_HOME");
+
+    let mut config = result.unwrap();
+    config.set(TestConfiguration {
+        foo: "baz".to_owned(),
+    });
+    config.persist().unwrap();
+    assert!(dir
+        .path()
+        .join("bdrck_open_default")
+        .join("settings.mp")
+        .is_file());
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+struct CorrelatedConfiguration {
+    a: u64,
+    // The writer always keeps this equal to `a * 2`.
+    b: u64,
+}
+
+#[test]
+fn test_shared_configuration_snapshots_are_consistent() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let config = configuration::Configuration::new_with_mode(
+        configuration::Identifier {
+            application: "bdrck_config".to_owned(),
+            name: "shared".to_owned(),
+        },
+        CorrelatedConfiguration { a: 0, b: 0 },
+        Some(&dir.path().join("shared.mp")),
+        configuration::PersistMode::Explicit,
+    )
+    .unwrap();
+    let shared = Arc::new(configuration::SharedConfiguration::new(config));
+
+    let held = shared.snapshot();
+    let done = Arc::new(AtomicBool::new(false));
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let shared = shared.clone();
+            let done = done.clone();
+            std::thread::spawn(move || loop {
+                let finished = done.load(Ordering::SeqCst);
+                let snapshot = shared.snapshot();
+                assert_eq!(snapshot.a * 2, snapshot.b);
+                shared.read(|c| assert_eq!(c.a * 2, c.b));
+                if finished {
+                    break;
+                }
+            })
+        })
+        .collect();
+
+    for _ in 0..1000 {
+        shared
+            .modify(|c| {
+                c.a += 1;
+                c.b = c.a * 2;
+            })
+            .unwrap();
+    }
+    done.store(true, Ordering::SeqCst);
+    for reader in readers {
+        reader.join().unwrap();
+    }
+
+    // Snapshots taken before a swap are unaffected by it.
+    assert_eq!(CorrelatedConfiguration { a: 0, b: 0 }, *held);
+    assert_eq!(
+        CorrelatedConfiguration { a: 1000, b: 2000 },
+        *shared.snapshot()
+    );
+
+    shared.apply(|c| c.persist()).unwrap();
+    let reopened = configuration::Configuration::open_read_only(
+        &dir.path().join("shared.mp"),
+        CorrelatedConfiguration { a: 0, b: 0 },
+    )
+    .unwrap();
+    assert_eq!(1000, reopened.get().a);
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+struct PeerConfiguration {
+    host: String,
+    port: u16,
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(default)]
+struct DefaultedServerConfiguration {
+    host: String,
+    port: u16,
+}
+
+impl Default for DefaultedServerConfiguration {
+    fn default() -> Self {
+        DefaultedServerConfiguration {
+            host: "localhost".to_owned(),
+            port: 80,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(default)]
+struct StrictConfiguration {
+    server: Option<DefaultedServerConfiguration>,
+    max_connections: u32,
+    peers: Vec<PeerConfiguration>,
+    // Accepted on input for compatibility, but never written back out.
+    #[serde(skip_serializing)]
+    legacy: Option<serde_json::Value>,
+}
+
+fn new_strict_configuration(
+    dir: &temp::Dir,
+    unknown_fields: configuration::UnknownFields,
+) -> configuration::Configuration<StrictConfiguration> {
+    configuration::Configuration::new_with_mode(
+        configuration::Identifier {
+            application: "bdrck_config".to_owned(),
+            name: "strict".to_owned(),
+        },
+        StrictConfiguration::default(),
+        Some(&dir.path().join("strict.mp")),
+        configuration::PersistMode::Explicit,
+    )
+    .unwrap()
+    .with_unknown_fields(unknown_fields)
+}
+
+const STRICT_TEST_INPUT: &str = r#"{
+    "server": {"host": "localhost", "prot": 8080},
+    "max_conections": 10,
+    "peers": [
+        {"host": "a", "port": 1},
+        {"hots": "b", "host": "b", "port": 2}
+    ],
+    "legacy": {"anything": {"goes": true}},
+    "zzz": 1
+}"#;
+
+#[test]
+fn test_unknown_fields_found() {
+    crate::init().unwrap();
+
+    let value: serde_json::Value = serde_json::from_str(STRICT_TEST_INPUT).unwrap();
+    let parsed: StrictConfiguration = serde_json::from_value(value.clone()).unwrap();
+    let reference = serde_json::to_value(&parsed).unwrap();
+
+    let unknown_fields = configuration::UnknownFields::new(configuration::UnknownFieldPolicy::Deny);
+    let found: Vec<String> = unknown_fields
+        .find(&value, &reference)
+        .iter()
+        .map(|f| f.to_string())
+        .collect();
+    assert_eq!(
+        vec![
+            "legacy",
+            "max_conections (did you mean 'max_connections'?)",
+            "peers[1].hots (did you mean 'host'?)",
+            "server.prot (did you mean 'port'?)",
+            "zzz",
+        ],
+        found
+    );
+
+    // Allowing a section skips it (and anything inside it) entirely.
+    let unknown_fields = unknown_fields.allow("legacy").allow("peers[1]");
+    let found: Vec<String> = unknown_fields
+        .find(&value, &reference)
+        .into_iter()
+        .map(|f| f.path)
+        .collect();
+    assert_eq!(vec!["max_conections", "server.prot", "zzz"], found);
+}
+
+#[test]
+fn test_unknown_fields_policy() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+
+    // By default, unknown fields are ignored, just like serde does.
+    let mut config = new_strict_configuration(&dir, configuration::UnknownFields::default());
+    config
+        .import_from(
+            STRICT_TEST_INPUT.as_bytes(),
+            configuration::PersistenceFormat::Json,
+        )
+        .unwrap();
+    assert_eq!(2, config.get().peers.len());
+
+    // Warnings don't stop the import.
+    let mut config = new_strict_configuration(
+        &dir,
+        configuration::UnknownFields::new(configuration::UnknownFieldPolicy::Warn),
+    );
+    config
+        .import_from(
+            STRICT_TEST_INPUT.as_bytes(),
+            configuration::PersistenceFormat::Json,
+        )
+        .unwrap();
+    assert_eq!(2, config.get().peers.len());
+
+    // But denying them does, and leaves the configuration untouched.
+    let mut config = new_strict_configuration(
+        &dir,
+        configuration::UnknownFields::new(configuration::UnknownFieldPolicy::Deny).allow("legacy"),
+    );
+    match config.import_from(
+        STRICT_TEST_INPUT.as_bytes(),
+        configuration::PersistenceFormat::Json,
+    ) {
+        Err(Error::ConfigUnknownFields(fields)) => {
+            assert_eq!(4, fields.len());
+            assert_eq!("max_conections", fields[0].path);
+            assert_eq!(Some("max_connections".to_owned()), fields[0].suggestion);
+        }
+        other => panic!("expected unknown fields, got {:?}", other),
+    }
+    assert!(config.get().peers.is_empty());
+
+    // Patches are checked against the current values.
+    let patch = br#"{"max_connections": 5, "sever": {"host": "x", "port": 1}}"#;
+    match config.import_patch_from(&patch[..], configuration::PersistenceFormat::Json) {
+        Err(e) => {
+            assert_eq!(ErrorCode::ConfigUnknownFields, e.code());
+            assert!(e.to_string().contains("sever (did you mean 'server'?)"));
+        }
+        Ok(_) => panic!("expected unknown fields"),
+    }
+    assert_eq!(0, config.get().max_connections);
+    let patch = br#"{"max_connections": 5}"#;
+    config
+        .import_patch_from(&patch[..], configuration::PersistenceFormat::Json)
+        .unwrap();
+    assert_eq!(5, config.get().max_connections);
+}
+
+fn fixture_identifier(name: &str) -> configuration::Identifier {
+    configuration::Identifier {
+        application: "bdrck_config_fixture".to_owned(),
+        name: name.to_owned(),
+    }
+}
+
+fn new_nested_singleton(id: &configuration::Identifier, file: &temp::File) {
+    fs::remove_file(file.path()).unwrap();
+    configuration::new(
+        id.clone(),
+        NestedConfiguration {
+            server: ServerConfiguration {
+                host: "localhost".to_owned(),
+                port: 8080,
+            },
+            name: Some("foo".to_owned()),
+        },
+        Some(file.path()),
+    )
+    .unwrap();
+}
+
+#[test]
+fn test_fixture_restores_previous() {
+    crate::init().unwrap();
+
+    let id = fixture_identifier("restore");
+    let file = temp::File::new_file().unwrap();
+    new_nested_singleton(&id, &file);
+
+    let fixture = NestedConfiguration {
+        server: ServerConfiguration {
+            host: "example.com".to_owned(),
+            port: 443,
+        },
+        name: None,
+    };
+    let seen = configuration::testing::with_fixture(&id, fixture.clone(), || {
+        configuration::get::<NestedConfiguration>(&id).unwrap()
+    })
+    .unwrap();
+    assert_eq!(fixture, seen);
+
+    // Modifying and persisting the fixture shouldn't touch the real file.
+    {
+        let _guard = configuration::testing::fixture(&id, fixture.clone()).unwrap();
+        configuration::apply_patch::<NestedConfiguration>(&id, json!({"name": "bar"})).unwrap();
+        configuration::persist::<NestedConfiguration>(&id).unwrap();
+        assert_eq!(
+            Some("bar".to_owned()),
+            configuration::get::<NestedConfiguration>(&id).unwrap().name
+        );
+    }
+    assert!(!file.path().exists());
+
+    let restored = configuration::get::<NestedConfiguration>(&id).unwrap();
+    assert_eq!(8080, restored.server.port);
+    assert_eq!(Some("foo".to_owned()), restored.name);
+    configuration::remove::<NestedConfiguration>(&id).unwrap();
+
+    // Without a previous registration, the fixture is simply removed.
+    configuration::testing::with_fixture(&id, fixture, || ()).unwrap();
+    assert!(configuration::get::<NestedConfiguration>(&id).is_err());
+}
+
+#[test]
+fn test_fixture_restores_after_panic() {
+    crate::init().unwrap();
+
+    let id = fixture_identifier("panic");
+    let file = temp::File::new_file().unwrap();
+    new_nested_singleton(&id, &file);
+
+    let result = std::panic::catch_unwind(|| {
+        configuration::testing::with_fixture(
+            &id,
+            NestedConfiguration {
+                server: ServerConfiguration {
+                    host: "example.com".to_owned(),
+                    port: 443,
+                },
+                name: None,
+            },
+            || panic!("test panic"),
+        )
+    });
+    assert!(result.is_err());
+
+    assert_eq!(
+        8080,
+        configuration::get::<NestedConfiguration>(&id)
+            .unwrap()
+            .server
+            .port
+    );
+    // The fixture should have been released, too.
+    let _guard =
+        configuration::testing::override_field::<NestedConfiguration>(&id, "name", json!(null))
+            .unwrap();
+}
+
+#[test]
+fn test_fixture_override_field() {
+    crate::init().unwrap();
+
+    let id = fixture_identifier("override");
+    let file = temp::File::new_file().unwrap();
+    new_nested_singleton(&id, &file);
+    // Overrides apply on top of the defaults, not the current values.
+    configuration::apply_patch::<NestedConfiguration>(&id, json!({"name": "bar"})).unwrap();
+
+    {
+        let _guard = configuration::testing::override_field::<NestedConfiguration>(
+            &id,
+            "server.port",
+            json!(1),
+        )
+        .unwrap();
+        let config = configuration::get::<NestedConfiguration>(&id).unwrap();
+        assert_eq!(1, config.server.port);
+        assert_eq!("localhost", config.server.host);
+        assert_eq!(Some("foo".to_owned()), config.name);
+    }
+    assert_eq!(
+        Some("bar".to_owned()),
+        configuration::get::<NestedConfiguration>(&id).unwrap().name
+    );
+
+    assert!(
+        configuration::testing::override_field::<NestedConfiguration>(
+            &id,
+            "server.bogus",
+            json!(1)
+        )
+        .is_err()
+    );
+    assert!(
+        configuration::testing::override_field::<NestedConfiguration>(
+            &id,
+            "server.port",
+            json!("not a port")
+        )
+        .is_err()
+    );
+    assert!(
+        configuration::testing::override_field::<NestedConfiguration>(
+            &fixture_identifier("unregistered"),
+            "server.port",
+            json!(1)
+        )
+        .is_err()
+    );
+}
+
+#[test]
+fn test_fixture_override_field_waits_for_other_threads() {
+    use std::sync::mpsc;
+    use std::thread;
+
+    crate::init().unwrap();
+
+    let id = fixture_identifier("override_wait");
+    let file = temp::File::new_file().unwrap();
+    new_nested_singleton(&id, &file);
+
+    // Another thread holds a fixture with different values for a while.
+    let (installed_tx, installed_rx) = mpsc::channel();
+    let other_id = id.clone();
+    let other = thread::spawn(move || {
+        let _guard = configuration::testing::fixture(
+            &other_id,
+            NestedConfiguration {
+                server: ServerConfiguration {
+                    host: "other".to_owned(),
+                    port: 1,
+                },
+                name: None,
+            },
+        )
+        .unwrap();
+        installed_tx.send(()).unwrap();
+        thread::sleep(Duration::from_millis(100));
+    });
+    installed_rx.recv().unwrap();
+
+    // The override must be based on the real defaults, not the other fixture.
+    let _guard =
+        configuration::testing::override_field::<NestedConfiguration>(&id, "name", json!("x"))
+            .unwrap();
+    let config = configuration::get::<NestedConfiguration>(&id).unwrap();
+    assert_eq!(8080, config.server.port);
+    assert_eq!("localhost", config.server.host);
+    assert_eq!(Some("x".to_owned()), config.name);
+    other.join().unwrap();
+}
+
+#[test]
+fn test_fixture_nesting_conflict() {
+    crate::init().unwrap();
+
+    let id = fixture_identifier("nesting");
+    let value = TestConfiguration {
+        foo: "outer".to_owned(),
+    };
+    let _guard = configuration::testing::fixture(&id, value.clone()).unwrap();
+    match configuration::testing::with_fixture(&id, value, || ()) {
+        Err(Error::Precondition(_)) => {}
+        r => panic!("expected nested fixture to fail, got {:?}", r),
+    }
+    assert_eq!(
+        "outer",
+        configuration::get::<TestConfiguration>(&id).unwrap().foo
+    );
+
+    // A fixture for a different identifier is fine.
+    configuration::testing::with_fixture(
+        &fixture_identifier("nesting_other"),
+        TestConfiguration {
+            foo: "other".to_owned(),
+        },
+        || (),
+    )
+    .unwrap();
+}
+
+fn default_nested_configuration() -> NestedConfiguration {
+    NestedConfiguration {
+        server: ServerConfiguration {
+            host: "localhost".to_owned(),
+            port: 8080,
+        },
+        name: Some("foo".to_owned()),
+    }
+}
+
+#[test]
+fn test_overrides_dir_ordering() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let main_path = dir.sub_path("main.cfg").unwrap();
+    let overrides_path = dir.sub_path("conf.d").unwrap();
+    fs::create_dir(&overrides_path).unwrap();
+    // Written out of order, to make sure we sort them.
+    fs::write(
+        overrides_path.join("20-bar.json"),
+        r#"{"server": {"port": 2000}}"#,
+    )
+    .unwrap();
+    fs::write(
+        overrides_path.join("10-foo.json"),
+        r#"{"server": {"host": "example.com", "port": 1000}}"#,
+    )
+    .unwrap();
+    // Files without the right extension are ignored.
+    fs::write(overrides_path.join("30-baz.json~"), r#"{"name": "baz"}"#).unwrap();
+
+    let mut config = configuration::Configuration::open_with_overrides(
+        &main_path,
+        configuration::OverridesDir::new(&overrides_path),
+        default_nested_configuration(),
+    )
+    .unwrap();
+    assert_eq!("example.com", config.get().server.host);
+    assert_eq!(2000, config.get().server.port);
+    assert_eq!(Some("foo".to_owned()), config.get().name);
+
+    // Only the main file is ever written, and it doesn't include snippets.
+    config.apply_patch(json!({"name": "main"})).unwrap();
+    assert_eq!(2000, config.get().server.port);
+    assert_eq!(Some("main".to_owned()), config.get().name);
+    let reloaded: NestedConfiguration = read_persisted(&main_path);
+    assert_eq!(8080, reloaded.server.port);
+    assert_eq!(Some("main".to_owned()), reloaded.name);
+    assert_eq!(3, fs::read_dir(&overrides_path).unwrap().count());
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+struct CountedConfiguration {
+    counts: std::collections::BTreeMap<String, u32>,
+    name: Option<String>,
+}
+
+#[test]
+fn test_set_keeps_snippets_when_env_overrides_fail() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let main_path = dir.sub_path("main.cfg").unwrap();
+    let overrides_path = dir.sub_path("conf.d").unwrap();
+    fs::create_dir(&overrides_path).unwrap();
+    fs::write(
+        overrides_path.join("10-name.json"),
+        r#"{"name": "snippet"}"#,
+    )
+    .unwrap();
+
+    // This doesn't match any field (and so is ignored) until "a" is added.
+    std::env::set_var("BDRCK_CFGSETLAYER_COUNTS__A", "lots");
+    let mut config = configuration::Configuration::open_with_overrides(
+        &main_path,
+        configuration::OverridesDir::new(&overrides_path),
+        CountedConfiguration {
+            counts: Default::default(),
+            name: None,
+        },
+    )
+    .unwrap()
+    .with_env_prefix("BDRCK_CFGSETLAYER")
+    .unwrap();
+    assert_eq!(Some("snippet"), config.get().name.as_deref());
+
+    config.set(CountedConfiguration {
+        counts: [("a".to_owned(), 1)].into(),
+        name: None,
+    });
+    std::env::remove_var("BDRCK_CFGSETLAYER_COUNTS__A");
+    assert_eq!(Some(&1), config.get().counts.get("a"));
+    assert_eq!(Some("snippet"), config.get().name.as_deref());
+}
+
+#[test]
+fn test_overrides_dir_null_clears() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let main_path = dir.sub_path("main.cfg").unwrap();
+    let overrides_path = dir.sub_path("conf.d").unwrap();
+    fs::create_dir(&overrides_path).unwrap();
+    fs::write(overrides_path.join("10-name.json"), r#"{"name": "bar"}"#).unwrap();
+    fs::write(overrides_path.join("20-clear.json"), r#"{"name": null}"#).unwrap();
+
+    let config = configuration::Configuration::open_with_overrides(
+        &main_path,
+        configuration::OverridesDir::new(&overrides_path),
+        default_nested_configuration(),
+    )
+    .unwrap();
+    assert_eq!(None, config.get().name);
+    assert_eq!(
+        configuration::ValueOrigin::Snippet(overrides_path.join("20-clear.json")),
+        config.explain_value("name").unwrap()
+    );
+}
+
+#[test]
+fn test_overrides_dir_malformed_snippet() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let main_path = dir.sub_path("main.cfg").unwrap();
+    let overrides_path = dir.sub_path("conf.d").unwrap();
+    fs::create_dir(&overrides_path).unwrap();
+    fs::write(
+        overrides_path.join("10-good.json"),
+        r#"{"server": {"port": 1000}}"#,
+    )
+    .unwrap();
+    fs::write(overrides_path.join("20-bad.json"), r#"{"server": "#).unwrap();
+    fs::write(
+        overrides_path.join("30-wrong-type.json"),
+        r#"{"server": {"port": "high"}}"#,
+    )
+    .unwrap();
+    fs::write(overrides_path.join("40-good.json"), r#"{"name": "bar"}"#).unwrap();
+
+    // In lenient mode, the invalid snippets are skipped.
+    let config = configuration::Configuration::open_with_overrides(
+        &main_path,
+        configuration::OverridesDir::new(&overrides_path),
+        default_nested_configuration(),
+    )
+    .unwrap();
+    assert_eq!(1000, config.get().server.port);
+    assert_eq!(Some("bar".to_owned()), config.get().name);
+    drop(config);
+
+    // In strict mode, the error names the first invalid snippet.
+    match configuration::Configuration::open_with_overrides(
+        &main_path,
+        configuration::OverridesDir::new(&overrides_path).strict(true),
+        default_nested_configuration(),
+    ) {
+        Err(Error::InvalidArgument(message)) => assert!(message.contains("20-bad.json")),
+        r => panic!("expected an invalid snippet error, got {:?}", r.map(|_| ())),
+    }
+    fs::remove_file(overrides_path.join("20-bad.json")).unwrap();
+    match configuration::Configuration::open_with_overrides(
+        &main_path,
+        configuration::OverridesDir::new(&overrides_path).strict(true),
+        default_nested_configuration(),
+    ) {
+        Err(Error::InvalidArgument(message)) => assert!(message.contains("30-wrong-type.json")),
+        r => panic!("expected an invalid snippet error, got {:?}", r.map(|_| ())),
+    }
+}
+
+#[test]
+fn test_overrides_dir_explain_value() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let main_path = dir.sub_path("main.cfg").unwrap();
+    let overrides_path = dir.sub_path("conf.d").unwrap();
+    fs::create_dir(&overrides_path).unwrap();
+    fs::write(
+        overrides_path.join("10-port.json"),
+        r#"{"server": {"port": 1000}}"#,
+    )
+    .unwrap();
+
+    let open = || {
+        configuration::Configuration::open_with_overrides(
+            &main_path,
+            configuration::OverridesDir::new(&overrides_path),
+            default_nested_configuration(),
+        )
+        .unwrap()
+    };
+
+    // Before the main file exists, unset values come from the defaults.
+    let config = open();
+    assert_eq!(
+        configuration::ValueOrigin::Default,
+        config.explain_value("server.host").unwrap()
+    );
+    drop(config);
+
+    let mut config = open();
+    config
+        .apply_patch(json!({"server": {"host": "example.com"}}))
+        .unwrap();
+    drop(config);
+
+    // The main file exists now, but it only changed the host.
+    let config = open();
+    assert_eq!(
+        configuration::ValueOrigin::MainFile(main_path.clone()),
+        config.explain_value("server.host").unwrap()
+    );
+    assert_eq!(
+        configuration::ValueOrigin::Default,
+        config.explain_value("name").unwrap()
+    );
+    drop(config);
+
+    std::env::set_var("BDRCK_CFGDIR_NAME", "from env");
+    let config = open().with_env_prefix("bdrck_cfgdir").unwrap();
+    assert_eq!("example.com", config.get().server.host);
+    assert_eq!(1000, config.get().server.port);
+    assert_eq!(Some("from env".to_owned()), config.get().name);
+
+    assert_eq!(
+        configuration::ValueOrigin::MainFile(main_path.clone()),
+        config.explain_value("server.host").unwrap()
+    );
+    assert_eq!(
+        configuration::ValueOrigin::Snippet(overrides_path.join("10-port.json")),
+        config.explain_value("server.port").unwrap()
+    );
+    assert_eq!(
+        configuration::ValueOrigin::Environment("BDRCK_CFGDIR_NAME".to_owned()),
+        config.explain_value("name").unwrap()
+    );
+    std::env::remove_var("BDRCK_CFGDIR_NAME");
+    match config.explain_value("server.nope") {
+        Err(Error::NotFound(_)) => {}
+        r => panic!("expected NotFound, got {:?}", r),
+    }
+}
+
+fn small_limits() -> configuration::ConfigLimits {
+    configuration::ConfigLimits {
+        max_input_bytes: 1024,
+        max_depth: 8,
+        max_nodes: 100,
+        max_string_bytes: 64,
+    }
+}
+
+#[test]
+fn test_config_limits_input_size() {
+    crate::init().unwrap();
+
+    let file = temp::File::new_file().unwrap();
+    let mut config = new_nested_configuration(&file).with_limits(small_limits());
+
+    // An endless input must be rejected without reading all of it.
+    match config.import_from(
+        std::io::repeat(b' '),
+        configuration::PersistenceFormat::Json,
+    ) {
+        Err(Error::ConfigTooLarge { limit }) => assert_eq!(1024, limit),
+        r => panic!("expected a too large error, got {:?}", r),
+    }
+    assert_eq!(&default_nested_configuration(), config.get());
+
+    // The same goes for snippets.
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let main_path = dir.sub_path("main.cfg").unwrap();
+    let overrides_path = dir.sub_path("conf.d").unwrap();
+    fs::create_dir(&overrides_path).unwrap();
+    fs::write(
+        overrides_path.join("10-big.json"),
+        format!(r#"{{"name": "{}"}}"#, "x".repeat(2048)),
+    )
+    .unwrap();
+    let config = configuration::Configuration::open_with_overrides(
+        &main_path,
+        configuration::OverridesDir::new(&overrides_path).limits(small_limits()),
+        default_nested_configuration(),
+    )
+    .unwrap();
+    assert_eq!(Some("foo".to_owned()), config.get().name);
+    drop(config);
+    match configuration::Configuration::open_with_overrides(
+        &main_path,
+        configuration::OverridesDir::new(&overrides_path)
+            .limits(small_limits())
+            .strict(true),
+        default_nested_configuration(),
+    ) {
+        Err(Error::InvalidArgument(message)) => {
+            assert!(message.contains("10-big.json"));
+            assert!(message.contains("maximum size"));
+        }
+        r => panic!("expected an invalid snippet error, got {:?}", r.map(|_| ())),
+    }
+}
+
+#[test]
+fn test_config_limits_persisted_file() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let path = dir.sub_path("main.cfg").unwrap();
+    let open = |limits| {
+        configuration::Configuration::open_path_with_limits(
+            &path,
+            default_nested_configuration(),
+            configuration::PersistMode::Immediate,
+            limits,
+        )
+    };
+
+    let mut config = open(configuration::ConfigLimits::default()).unwrap();
+    let mut value = config.get().clone();
+    value.name = Some("x".repeat(2048));
+    config.set(value);
+    config.persist().unwrap();
+    drop(config);
+
+    // The limits apply to the persisted file too, not just to later input.
+    match open(small_limits()) {
+        Err(Error::ConfigTooLarge { limit }) => assert_eq!(1024, limit),
+        r => panic!("expected a too large error, got {:?}", r.map(|_| ())),
+    }
+    let config = open(configuration::ConfigLimits::default()).unwrap();
+    assert_eq!(Some("x".repeat(2048)), config.get().name);
+}
+
+#[test]
+fn test_config_limits_depth() {
+    crate::init().unwrap();
+
+    let limits = configuration::ConfigLimits {
+        max_input_bytes: configuration::DEFAULT_MAX_INPUT_BYTES,
+        ..small_limits()
+    };
+    let nested = |depth: usize| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+    limits
+        .check(nested(8).as_bytes(), configuration::PersistenceFormat::Json)
+        .unwrap();
+    match limits.check(nested(9).as_bytes(), configuration::PersistenceFormat::Json) {
+        Err(Error::ConfigTooDeep { depth }) => assert_eq!(8, depth),
+        r => panic!("expected a too deep error, got {:?}", r),
+    }
+    // Far deeper than serde_json's own recursion limit.
+    match limits.check(
+        nested(10_000).as_bytes(),
+        configuration::PersistenceFormat::Json,
+    ) {
+        Err(Error::ConfigTooDeep { depth }) => assert_eq!(8, depth),
+        r => panic!("expected a too deep error, got {:?}", r),
+    }
+
+    // Patches are checked too, and are never partially applied.
+    let file = temp::File::new_file().unwrap();
+    let mut config = new_nested_configuration(&file).with_limits(limits);
+    let mut patch = json!("deep");
+    for _ in 0..9 {
+        patch = json!({ "x": patch });
+    }
+    let patch = json!({"name": "bar", "server": patch});
+    match config.apply_patch(patch.clone()) {
+        Err(Error::ConfigTooDeep { depth }) => assert_eq!(8, depth),
+        r => panic!("expected a too deep error, got {:?}", r),
+    }
+    match config.import_patch_from(
+        patch.to_string().as_bytes(),
+        configuration::PersistenceFormat::Json,
+    ) {
+        Err(Error::ConfigTooDeep { depth }) => assert_eq!(8, depth),
+        r => panic!("expected a too deep error, got {:?}", r),
+    }
+    assert_eq!(&default_nested_configuration(), config.get());
+}
+
+#[test]
+fn test_config_limits_node_count() {
+    crate::init().unwrap();
+
+    let limits = configuration::ConfigLimits {
+        max_input_bytes: configuration::DEFAULT_MAX_INPUT_BYTES,
+        ..small_limits()
+    };
+    let wide: serde_json::Map<String, serde_json::Value> =
+        (0..1000).map(|i| (format!("k{}", i), json!(i))).collect();
+    let wide = serde_json::Value::Object(wide);
+    match limits.check(
+        wide.to_string().as_bytes(),
+        configuration::PersistenceFormat::Json,
+    ) {
+        Err(Error::ConfigTooComplex { nodes }) => assert_eq!(100, nodes),
+        r => panic!("expected a too complex error, got {:?}", r),
+    }
+    match limits.check_value(&wide) {
+        Err(Error::ConfigTooComplex { nodes }) => assert_eq!(100, nodes),
+        r => panic!("expected a too complex error, got {:?}", r),
+    }
+
+    // MessagePack input is held to the same limits.
+    let wide: Vec<u32> = (0..1000).collect();
+    match limits.check(
+        rmp_serde::to_vec(&wide).unwrap().as_slice(),
+        configuration::PersistenceFormat::MessagePack,
+    ) {
+        Err(Error::ConfigTooComplex { nodes }) => assert_eq!(100, nodes),
+        r => panic!("expected a too complex error, got {:?}", r),
+    }
+    // Including strings.
+    match limits.check(
+        rmp_serde::to_vec(&"x".repeat(65)).unwrap().as_slice(),
+        configuration::PersistenceFormat::MessagePack,
+    ) {
+        Err(Error::ConfigStringTooLong { limit }) => assert_eq!(64, limit),
+        r => panic!("expected a string too long error, got {:?}", r),
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+struct RealisticConfiguration {
+    servers: Vec<ServerConfiguration>,
+    aliases: std::collections::BTreeMap<String, String>,
+    motd: Option<String>,
+}
+
+#[test]
+fn test_config_limits_defaults() {
+    crate::init().unwrap();
+
+    let realistic = RealisticConfiguration {
+        servers: (0..100)
+            .map(|i| ServerConfiguration {
+                host: format!("server{}.example.com", i),
+                port: 8000 + i,
+            })
+            .collect(),
+        aliases: (0..100)
+            .map(|i| (format!("alias{}", i), format!("server{}", i)))
+            .collect(),
+        motd: Some("Welcome! ".repeat(1000)),
+    };
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let path = dir.sub_path("realistic.mp").unwrap();
+    let config = configuration::Configuration::open_read_only(
+        &path,
+        RealisticConfiguration {
+            servers: vec![],
+            aliases: Default::default(),
+            motd: None,
+        },
+    )
+    .unwrap();
+
+    for &format in &[
+        configuration::PersistenceFormat::Json,
+        configuration::PersistenceFormat::MessagePack,
+    ] {
+        let mut exported = Vec::new();
+        configuration::Configuration::in_memory(realistic.clone())
+            .export_to(&mut exported, format)
+            .unwrap();
+        configuration::ConfigLimits::default()
+            .check(exported.as_slice(), format)
+            .unwrap();
+
+        let mut imported = configuration::Configuration::in_memory(config.get().clone());
+        imported.import_from(exported.as_slice(), format).unwrap();
+        assert_eq!(&realistic, imported.get());
+    }
+
+    // Files on disk are loaded with the default limits, too.
+    fs::write(&path, rmp_serde::to_vec(&realistic).unwrap()).unwrap();
+    let config = configuration::Configuration::open_read_only(&path, realistic.clone()).unwrap();
+    assert_eq!(&realistic, config.get());
+}
+
+fn new_shared_nested_configuration() -> configuration::SharedConfiguration<NestedConfiguration> {
+    configuration::SharedConfiguration::new(configuration::Configuration::in_memory(
+        default_nested_configuration(),
+    ))
+}
+
+#[test]
+fn test_shared_configuration_generation() {
+    crate::init().unwrap();
+
+    let shared = new_shared_nested_configuration();
+    assert_eq!(0, shared.generation());
+    let subscription = shared.subscribe();
+    assert!(!subscription.changed_since(0));
+
+    shared.modify(|c| c.server.port = 1000).unwrap();
+    assert_eq!(1, shared.generation());
+    shared
+        .apply(|c| c.apply_patch(json!({"name": "bar"})))
+        .unwrap();
+    assert_eq!(2, shared.generation());
+    // Mutations which don't actually change anything aren't counted.
+    shared.modify(|c| c.server.port = 1000).unwrap();
+    shared.apply(|c| c.persist()).unwrap();
+    assert_eq!(2, shared.generation());
+
+    assert!(subscription.changed_since(0));
+    assert!(subscription.changed_since(1));
+    assert!(!subscription.changed_since(2));
+}
+
+#[test]
+fn test_shared_configuration_subscribe_field() {
+    crate::init().unwrap();
+
+    let shared = new_shared_nested_configuration();
+    assert!(shared.subscribe_field("server.nope").is_err());
+    let mut port = shared.subscribe_field("server.port").unwrap();
+    let mut all = shared.subscribe();
+
+    // Unrelated changes are ignored.
+    shared.modify(|c| c.name = None).unwrap();
+    shared
+        .modify(|c| c.server.host = "example.com".to_owned())
+        .unwrap();
+    assert_eq!(Some(2), all.wait_for_change(Duration::ZERO));
+    assert!(!port.changed_since(0));
+    assert_eq!(None, port.wait_for_change(Duration::ZERO));
+
+    shared.modify(|c| c.server.port = 443).unwrap();
+    assert!(port.changed_since(0));
+    assert_eq!(Some(3), port.wait_for_change(Duration::ZERO));
+    assert_eq!(3, port.generation());
+    assert_eq!(None, port.wait_for_change(Duration::ZERO));
+    assert_eq!(Some(3), all.wait_for_change(Duration::ZERO));
+
+    // Subscriptions don't keep the configuration alive.
+    drop(shared);
+    assert!(!port.changed_since(0));
+    assert_eq!(None, port.wait_for_change(Duration::from_secs(5)));
+}
+
+#[test]
+fn test_shared_configuration_wait_for_change() {
+    crate::init().unwrap();
+
+    let shared = std::sync::Arc::new(new_shared_nested_configuration());
+    let mut subscription = shared.subscribe_field("server.port").unwrap();
+    let writer = {
+        let shared = shared.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            shared.modify(|c| c.server.port = 443).unwrap();
+        })
+    };
+    assert_eq!(
+        Some(1),
+        subscription.wait_for_change(Duration::from_secs(30))
+    );
+    assert_eq!(443, shared.snapshot().server.port);
+    writer.join().unwrap();
+}
+
+#[test]
+fn test_shared_configuration_no_missed_changes() {
+    crate::init().unwrap();
+
+    let shared = std::sync::Arc::new(new_shared_nested_configuration());
+    for i in 1..=200 {
+        let writer = {
+            let shared = shared.clone();
+            std::thread::spawn(move || shared.modify(|c| c.server.port = i).unwrap())
+        };
+        // Whether or not the change is already visible, it must either be
+        // reflected in the snapshot, or be reported by the subscription.
+        let mut subscription = shared.subscribe();
+        if shared.snapshot().server.port != i {
+            assert!(subscription
+                .wait_for_change(Duration::from_secs(30))
+                .is_some());
+            assert_eq!(i, shared.snapshot().server.port);
+        }
+        writer.join().unwrap();
+    }
+    assert_eq!(200, shared.generation());
+}
+
+fn open_test_configuration(
+    path: &path::Path,
+) -> Result<configuration::Configuration<TestConfiguration>> {
+    configuration::Configuration::new(
+        TEST_IDENTIFIER.clone(),
+        TestConfiguration {
+            foo: "default".to_owned(),
+        },
+        Some(path),
+    )
+}
+
+#[test]
+fn test_persisted_checksum() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let path = dir.sub_path("config.mp").unwrap();
+    {
+        let mut config = open_test_configuration(&path).unwrap();
+        config.set(TestConfiguration {
+            foo: "persisted".to_owned(),
+        });
+        config.persist().unwrap();
+    }
+    let original = fs::read(&path).unwrap();
+    assert_eq!(
+        "persisted",
+        open_test_configuration(&path).unwrap().get().foo
+    );
+
+    // A truncated file is reported as corrupted, with the lengths involved.
+    fs::write(&path, &original[..original.len() - 3]).unwrap();
+    match open_test_configuration(&path) {
+        Err(Error::ConfigCorrupted {
+            expected_len,
+            actual_len,
+            ..
+        }) => {
+            assert_eq!(original.len() as u64, expected_len);
+            assert_eq!(original.len() as u64 - 3, actual_len);
+        }
+        r => panic!(
+            "expected a corrupted configuration error, got {:?}",
+            r.map(|_| ())
+        ),
+    }
+
+    // So is a file with a single damaged byte.
+    let mut damaged = original.clone();
+    *damaged.last_mut().unwrap() ^= 0x01;
+    fs::write(&path, &damaged).unwrap();
+    assert_eq!(
+        ErrorCode::ConfigCorrupted,
+        open_test_configuration(&path).err().unwrap().code()
+    );
+}
+
+#[test]
+fn test_persisted_without_checksum() {
+    crate::init().unwrap();
+
+    // Files written by older versions have no integrity header, and should
+    // still load.
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let path = dir.sub_path("config.mp").unwrap();
+    let legacy = TestConfiguration {
+        foo: "legacy".to_owned(),
+    };
+    fs::write(&path, rmp_serde::to_vec(&legacy).unwrap()).unwrap();
+    {
+        let config = open_test_configuration(&path).unwrap();
+        assert_eq!(legacy, *config.get());
+        // Writing it again adds the header.
+        config.persist().unwrap();
+    }
+    assert_eq!(legacy, read_persisted::<TestConfiguration>(&path));
+}
+
+#[test]
+fn test_persisted_incompatible() {
+    crate::init().unwrap();
+
+    // An intact file which just doesn't match the configuration struct is
+    // reported as a deserialization error, not as corruption.
+    let file = temp::File::new_file().unwrap();
+    new_nested_configuration(&file).persist().unwrap();
+    let error = open_test_configuration(file.path()).err().unwrap();
+    assert_eq!(ErrorCode::MsgDecode, error.code());
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+struct SecretConfiguration {
+    user: String,
+    api_token: configuration::redact::Redacted<String>,
+    server: SecretServerConfiguration,
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+struct SecretServerConfiguration {
+    host: String,
+    port: configuration::redact::Redacted<u16>,
+    // Not wrapped in Redacted, so only its name gives it away.
+    db_password: Option<String>,
+    proxy_secret: Option<String>,
+}
+
+fn new_secret_configuration() -> SecretConfiguration {
+    SecretConfiguration {
+        user: "bdrck".to_owned(),
+        api_token: "hunter2".to_owned().into(),
+        server: SecretServerConfiguration {
+            host: "localhost".to_owned(),
+            port: 8080.into(),
+            db_password: Some("swordfish".to_owned()),
+            proxy_secret: None,
+        },
+    }
+}
+
+#[test]
+fn test_redacted_debug() {
+    crate::init().unwrap();
+
+    let debug = format!("{:?}", new_secret_configuration());
+    assert!(!debug.contains("hunter2"));
+    assert!(!debug.contains("8080"));
+    assert!(debug.contains("api_token: ********"));
+    assert!(debug.contains("port: ********"));
+    assert_eq!("********", new_secret_configuration().api_token.to_string());
+    assert_eq!("hunter2", new_secret_configuration().api_token.expose());
+}
+
+#[test]
+fn test_redacted_value() {
+    crate::init().unwrap();
+
+    let config = configuration::Configuration::in_memory(new_secret_configuration());
+    assert_eq!(
+        json!({
+            "user": "bdrck",
+            "api_token": "********",
+            "server": {
+                "host": "localhost",
+                "port": "********",
+                "db_password": "********",
+                // Unset fields are left alone, even if their name matches.
+                "proxy_secret": null,
+            },
+        }),
+        config.to_redacted_value().unwrap()
+    );
+
+    // Name patterns are configurable; Redacted values are always redacted.
+    let config = configuration::Configuration::in_memory(new_secret_configuration())
+        .with_redacted_fields(&["HOST"]);
+    assert_eq!(
+        json!({
+            "user": "bdrck",
+            "api_token": "********",
+            "server": {
+                "host": "********",
+                "port": "********",
+                "db_password": "swordfish",
+                "proxy_secret": null,
+            },
+        }),
+        config.to_redacted_value().unwrap()
+    );
+
+    assert!(configuration::redact::field_name_matches(
+        "*token*",
+        "API_TOKEN"
+    ));
+    assert!(configuration::redact::field_name_matches(
+        "db_*",
+        "db_password"
+    ));
+    assert!(configuration::redact::field_name_matches("*_*_*", "a_b_c"));
+    assert!(!configuration::redact::field_name_matches("*_*_*", "a_b"));
+    assert!(!configuration::redact::field_name_matches(
+        "token",
+        "api_token"
+    ));
+}
+
+#[test]
+fn test_redacted_persistence_round_trip() {
+    crate::init().unwrap();
+
+    let file = temp::File::new_file().unwrap();
+    fs::remove_file(file.path()).unwrap();
+    let id = configuration::Identifier {
+        application: "bdrck_config".to_owned(),
+        name: "secret".to_owned(),
+    };
+    let mut config = configuration::Configuration::new(
+        id.clone(),
+        new_secret_configuration(),
+        Some(file.path()),
+    )
+    .unwrap();
+    let mut modified = new_secret_configuration();
+    modified.api_token = "correct horse".to_owned().into();
+    config.set(modified.clone());
+    config.persist().unwrap();
+
+    let mut exported = Vec::new();
+    config
+        .export_to(&mut exported, configuration::PersistenceFormat::Json)
+        .unwrap();
+    assert!(String::from_utf8(exported.clone())
+        .unwrap()
+        .contains("correct horse"));
+    drop(config);
+
+    let mut config =
+        configuration::Configuration::new(id, new_secret_configuration(), Some(file.path()))
+            .unwrap();
+    assert_eq!(&modified, config.get());
+    config.reset();
+    config
+        .import_from(exported.as_slice(), configuration::PersistenceFormat::Json)
+        .unwrap();
+    assert_eq!(&modified, config.get());
+}
+
+#[test]
+fn test_redacted_get_value() {
+    crate::init().unwrap();
+
+    let config = configuration::Configuration::in_memory(new_secret_configuration());
+    assert_eq!(json!("bdrck"), config.get_value("user", false).unwrap());
+    assert_eq!(
+        json!("********"),
+        config.get_value("api_token", false).unwrap()
+    );
+    assert_eq!(
+        json!("hunter2"),
+        config.get_value("api_token", true).unwrap()
+    );
+    assert_eq!(
+        json!("********"),
+        config.get_value("server.port", false).unwrap()
+    );
+    assert_eq!(json!(8080), config.get_value("Server.Port", true).unwrap());
+    assert_eq!(
+        json!("********"),
+        config.get_value("server.db_password", false).unwrap()
+    );
+    assert_eq!(
+        json!("swordfish"),
+        config.get_value("server.db_password", true).unwrap()
+    );
+    assert_eq!(
+        json!({
+            "host": "localhost",
+            "port": "********",
+            "db_password": "********",
+            "proxy_secret": null,
+        }),
+        config.get_value("server", false).unwrap()
+    );
+    assert!(matches!(
+        config.get_value("server.bogus", true),
+        Err(Error::NotFound(_))
+    ));
+}
+
+fn new_file_secret_configuration(
+    file: &temp::File,
+) -> configuration::Configuration<SecretConfiguration> {
+    fs::remove_file(file.path()).unwrap();
+    new_file_secret_configuration_at(file.path())
+}
+
+fn new_file_secret_configuration_at(
+    path: &path::Path,
+) -> configuration::Configuration<SecretConfiguration> {
+    configuration::Configuration::new(
+        configuration::Identifier {
+            application: "bdrck_config".to_owned(),
+            name: "secret".to_owned(),
+        },
+        new_secret_configuration(),
+        Some(path),
+    )
+    .unwrap()
+}
+
+/// Run the `config` subcommand given by `args` against the given
+/// configuration, with output in the given mode, and return what it wrote.
+fn run_config_command<E: crate::cli::Editor>(
+    config: &mut configuration::Configuration<SecretConfiguration>,
+    mode: &str,
+    args: &[&str],
+    editor: &E,
+) -> Result<String> {
+    use crate::cli::OutputSink;
+    use crate::configuration::cli::ConfigCommand;
+    use crate::tests::cli::create_normal_test_context;
+
+    let (mut ctx, is, os) = create_normal_test_context("");
+    let mut sink = OutputSink::new(
+        mode.parse()?,
+        ctx.as_stream(
+            /*isatty=*/ true, /*support_read=*/ false, /*support_write=*/ true,
+        ),
+    )?;
+    ConfigCommand::parse(args)?.run_with_editor(config, &mut sink, &is, &os, editor)?;
+    sink.finish()?;
+    Ok(ctx.write_buffer_as_str()?.to_owned())
+}
+
+fn no_editor(_: &path::Path) -> Result<()> {
+    panic!("the editor should not be launched");
+}
+
+#[test]
+fn test_config_command_parse() {
+    use crate::configuration::cli::ConfigCommand;
+
+    crate::init().unwrap();
+
+    assert_eq!(
+        ConfigCommand::Get {
+            path: "server.port".to_owned(),
+            show_secrets: true,
+        },
+        ConfigCommand::parse(&["get", "--show-secrets", "server.port"]).unwrap()
+    );
+    assert_eq!(
+        ConfigCommand::Set {
+            path: "server.db_password".to_owned(),
+            value: "-1 two".to_owned(),
+        },
+        ConfigCommand::parse(&["set", "server.db_password", "-1 two"]).unwrap()
+    );
+    assert_eq!(
+        ConfigCommand::List {
+            show_secrets: false
+        },
+        ConfigCommand::parse(&["list"]).unwrap()
+    );
+    assert_eq!(
+        ConfigCommand::Path,
+        ConfigCommand::parse(&["path"]).unwrap()
+    );
+    assert_eq!(
+        ConfigCommand::Edit,
+        ConfigCommand::parse(&["edit"]).unwrap()
+    );
+
+    for args in [
+        &[][..],
+        &["bogus"][..],
+        &["get"][..],
+        &["set", "user"][..],
+        &["unset", "user", "extra"][..],
+        &["set", "--show-secrets", "user", "bdrck"][..],
+        &["list", "--verbose"][..],
+    ] {
+        assert!(
+            matches!(ConfigCommand::parse(args), Err(Error::InvalidArgument(_))),
+            "expected {:?} to be rejected",
+            args
+        );
+    }
+
+    let help = ConfigCommand::help("settings");
+    assert!(help.starts_with("Usage: settings <command>"));
+    for command in ["get", "set", "unset", "list", "path", "edit"] {
+        assert!(help.contains(&format!("\n  {} ", command)));
+    }
+}
+
+#[test]
+fn test_config_command_get_set_unset() {
+    crate::init().unwrap();
+
+    let file = temp::File::new_file().unwrap();
+    let mut config = new_file_secret_configuration(&file);
+
+    assert_eq!(
+        "bdrck\n",
+        run_config_command(&mut config, "text", &["get", "USER"], &no_editor).unwrap()
+    );
+    assert_eq!(
+        "********\n",
+        run_config_command(&mut config, "text", &["get", "server.port"], &no_editor).unwrap()
+    );
+    assert_eq!(
+        "8080\n",
+        run_config_command(
+            &mut config,
+            "text",
+            &["get", "--show-secrets", "server.port"],
+            &no_editor
+        )
+        .unwrap()
+    );
+    assert_eq!(
+        "\"bdrck\"\n",
+        run_config_command(&mut config, "json", &["get", "user"], &no_editor).unwrap()
+    );
+    assert!(matches!(
+        run_config_command(&mut config, "text", &["get", "server.bogus"], &no_editor),
+        Err(Error::NotFound(_))
+    ));
+
+    // Values are parsed according to the field's type.
+    assert_eq!(
+        "",
+        run_config_command(
+            &mut config,
+            "text",
+            &["set", "server.port", "9090"],
+            &no_editor
+        )
+        .unwrap()
+    );
+    assert_eq!(
+        "",
+        run_config_command(
+            &mut config,
+            "text",
+            &["set", "Server.Host", "example.com"],
+            &no_editor
+        )
+        .unwrap()
+    );
+    assert_eq!(9090, *config.get().server.port.expose());
+    assert_eq!("example.com", config.get().server.host);
+    assert!(matches!(
+        run_config_command(
+            &mut config,
+            "text",
+            &["set", "server.port", "lots"],
+            &no_editor
+        ),
+        Err(Error::InvalidArgument(_))
+    ));
+    assert!(matches!(
+        run_config_command(&mut config, "text", &["set", "bogus", "1"], &no_editor),
+        Err(Error::NotFound(_))
+    ));
+    assert_eq!(9090, *config.get().server.port.expose());
+
+    // A null field's type is guessed, but the guess can be wrong.
+    run_config_command(
+        &mut config,
+        "text",
+        &["set", "server.proxy_secret", "123"],
+        &no_editor,
+    )
+    .unwrap();
+    assert_eq!(Some("123"), config.get().server.proxy_secret.as_deref());
+    run_config_command(
+        &mut config,
+        "text",
+        &["unset", "server.proxy_secret"],
+        &no_editor,
+    )
+    .unwrap();
+    assert_eq!(None, config.get().server.proxy_secret);
+
+    // Changes were persisted.
+    let mut reopened = new_file_secret_configuration_at(file.path());
+    assert_eq!(config.get(), reopened.get());
+
+    run_config_command(&mut reopened, "text", &["unset", "server.port"], &no_editor).unwrap();
+    assert_eq!(8080, *reopened.get().server.port.expose());
+    assert_eq!("example.com", reopened.get().server.host);
+    let reopened = new_file_secret_configuration_at(file.path());
+    assert_eq!(8080, *reopened.get().server.port.expose());
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+struct LabeledConfiguration {
+    labels: std::collections::BTreeMap<String, String>,
+    server: Option<ServerConfiguration>,
+    retries: Option<u32>,
+}
+
+#[test]
+fn test_unset_value_missing_from_default() {
+    crate::init().unwrap();
+
+    let default = LabeledConfiguration {
+        labels: [("env".to_owned(), "prod".to_owned())].into(),
+        server: None,
+        retries: None,
+    };
+    let mut config = configuration::Configuration::in_memory(default.clone());
+    config
+        .apply_patch(json!({
+            "labels": {"env": "dev", "team": "storage"},
+            "server": {"host": "example.com", "port": 9090},
+        }))
+        .unwrap();
+
+    // A map key which isn't in the default is removed.
+    config.unset_value("labels.team").unwrap();
+    assert_eq!(
+        std::collections::BTreeMap::from([("env".to_owned(), "dev".to_owned())]),
+        config.get().labels
+    );
+    config.unset_value("labels.env").unwrap();
+    assert_eq!(default.labels, config.get().labels);
+
+    // A field of an Option which is None by default reverts the Option.
+    config.unset_value("server.port").unwrap();
+    assert_eq!(None, config.get().server);
+
+    // A null field is set to whatever the JSON parses to, if that's valid.
+    config.set_value("retries", "3").unwrap();
+    assert_eq!(Some(3), config.get().retries);
+    config.unset_value("retries").unwrap();
+    assert_eq!(None, config.get().retries);
+    assert!(matches!(
+        config.set_value("retries", "lots"),
+        Err(Error::Json(_))
+    ));
+    assert!(matches!(
+        config.unset_value("labels.team.bogus"),
+        Err(Error::NotFound(_))
+    ));
+    assert_eq!(&default, config.get());
+}
+
+#[test]
+fn test_config_command_list_and_path() {
+    crate::init().unwrap();
+
+    let file = temp::File::new_file().unwrap();
+    let mut config = new_file_secret_configuration(&file);
+
+    let listed: serde_json::Value = serde_json::from_str(
+        run_config_command(&mut config, "json", &["list"], &no_editor)
+            .unwrap()
+            .as_str(),
+    )
+    .unwrap();
+    assert_eq!(config.to_redacted_value().unwrap(), listed);
+    let listed =
+        run_config_command(&mut config, "text", &["list", "--show-secrets"], &no_editor).unwrap();
+    assert!(listed.contains("hunter2"));
+
+    assert_eq!(
+        format!("{}\n", file.path().display()),
+        run_config_command(&mut config, "text", &["path"], &no_editor).unwrap()
+    );
+    assert_eq!(
+        json!(file.path().display().to_string()),
+        serde_json::from_str::<serde_json::Value>(
+            run_config_command(&mut config, "json", &["path"], &no_editor)
+                .unwrap()
+                .as_str()
+        )
+        .unwrap()
+    );
+
+    let mut config = configuration::Configuration::in_memory(new_secret_configuration());
+    assert!(matches!(
+        run_config_command(&mut config, "text", &["path"], &no_editor),
+        Err(Error::Precondition(_))
+    ));
+}
+
+#[test]
+fn test_config_command_edit() {
+    crate::init().unwrap();
+
+    let file = temp::File::new_file().unwrap();
+    let mut config = new_file_secret_configuration(&file);
+
+    let replace = |from: &'static str, to: &'static str| {
+        move |path: &path::Path| -> Result<()> {
+            let contents = fs::read_to_string(path)?;
+            // Secrets are editable, so they must be shown unredacted.
+            assert!(contents.contains("hunter2"));
+            fs::write(path, contents.replace(from, to))?;
+            Ok(())
+        }
+    };
+
+    // An edit which makes the configuration invalid is rejected, and nothing
+    // is changed.
+    let error = run_config_command(
+        &mut config,
+        "text",
+        &["edit"],
+        &replace("8080", "\"eighty\""),
+    )
+    .unwrap_err();
+    assert!(matches!(error, Error::InvalidArgument(_)));
+    assert!(error.to_string().contains("not saved"));
+    assert_eq!(&new_secret_configuration(), config.get());
+    assert_eq!(
+        &new_secret_configuration(),
+        new_file_secret_configuration_at(file.path()).get()
+    );
+
+    // Leaving the file unchanged is a no-op.
+    run_config_command(&mut config, "text", &["edit"], &replace("", "")).unwrap();
+    assert!(!config.dirty());
+
+    run_config_command(
+        &mut config,
+        "text",
+        &["edit"],
+        &replace("localhost", "example.com"),
+    )
+    .unwrap();
+    assert_eq!("example.com", config.get().server.host);
+    assert_eq!(
+        "example.com",
+        new_file_secret_configuration_at(file.path())
+            .get()
+            .server
+            .host
+    );
+
+    // A configuration which can't be saved can't be edited.
+    let mut read_only = configuration::Configuration::new_with_mode(
+        configuration::Identifier {
+            application: "bdrck_config".to_owned(),
+            name: "secret".to_owned(),
+        },
+        new_secret_configuration(),
+        Some(file.path()),
+        configuration::PersistMode::ReadOnly,
+    )
+    .unwrap();
+    assert!(matches!(
+        run_config_command(&mut read_only, "text", &["edit"], &no_editor),
+        Err(Error::ReadOnlyConfiguration(_))
+    ));
+}
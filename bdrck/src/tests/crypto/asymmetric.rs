@@ -0,0 +1,123 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::crypto::asymmetric::*;
+use crate::crypto::key::{AbstractKey, Key};
+use crate::crypto::keystore::KeyStore;
+
+#[test]
+fn test_seal_open_round_trip() {
+    crate::init().unwrap();
+
+    let keypair = KeyPair::generate().unwrap();
+    let plaintext = b"this is some test data";
+    let ciphertext = seal(keypair.public_key(), plaintext).unwrap();
+    assert_eq!(plaintext.len() + SEAL_BYTES, ciphertext.len());
+    assert_eq!(plaintext.to_vec(), open(&keypair, &ciphertext).unwrap());
+
+    // Sealing is randomized, so sealing the same plaintext twice should differ.
+    assert_ne!(ciphertext, seal(keypair.public_key(), plaintext).unwrap());
+}
+
+#[test]
+fn test_open_wrong_keypair() {
+    crate::init().unwrap();
+
+    let keypair = KeyPair::generate().unwrap();
+    let other = KeyPair::generate().unwrap();
+    let ciphertext = seal(keypair.public_key(), b"this is some test data").unwrap();
+    assert!(open(&other, &ciphertext).is_err());
+
+    // Tampering with the ciphertext should also be detected.
+    let mut tampered = ciphertext.clone();
+    let last = tampered.len() - 1;
+    tampered[last] ^= 1;
+    assert!(open(&keypair, &tampered).is_err());
+    assert!(open(&keypair, &ciphertext[..SEAL_BYTES - 1]).is_err());
+}
+
+#[test]
+fn test_keypair_keystore_cycle() {
+    crate::init().unwrap();
+
+    let keypair = KeyPair::generate().unwrap();
+    let software_key = Key::new_random().unwrap();
+
+    let mut keystore = KeyStore::new().unwrap();
+    let master_digest = keystore.get_master_key().unwrap().get_digest();
+    assert!(keystore.add_key(&keypair).unwrap());
+    assert!(!keystore.add_key(&keypair).unwrap());
+    assert!(keystore.add_key(&software_key).unwrap());
+
+    // Round-trip the keypair through its serialized secret key, as if it had
+    // been stored offline in the meantime.
+    let keypair = KeyPair::deserialize(keypair.serialize().unwrap()).unwrap();
+
+    let data = keystore.to_vec().unwrap();
+    let mut keystore = KeyStore::load_slice(data.as_slice()).unwrap();
+    keystore.open(&keypair).unwrap();
+    assert_eq!(
+        master_digest,
+        keystore.get_master_key().unwrap().get_digest()
+    );
+
+    let data = keystore.to_vec().unwrap();
+    let mut keystore = KeyStore::load_slice(data.as_slice()).unwrap();
+    assert!(keystore.open(&KeyPair::generate().unwrap()).is_err());
+}
+
+#[test]
+fn test_public_key_serialization_round_trip() {
+    crate::init().unwrap();
+
+    let keypair = KeyPair::generate().unwrap();
+    let public_key = keypair.public_key().clone();
+
+    let serialized = rmp_serde::to_vec(&public_key).unwrap();
+    let deserialized: PublicKey = rmp_serde::from_slice(&serialized).unwrap();
+    assert_eq!(public_key, deserialized);
+    assert_eq!(
+        public_key,
+        PublicKey::from_slice(public_key.as_bytes()).unwrap()
+    );
+    assert!(PublicKey::from_slice(&public_key.as_bytes()[1..]).is_err());
+
+    // The fingerprint is stable, and matches the public key's digest.
+    assert_eq!(public_key.fingerprint(), deserialized.fingerprint());
+    assert_eq!(keypair.get_digest().fingerprint(), public_key.fingerprint());
+    assert_eq!(16, public_key.fingerprint().len());
+    assert_eq!(public_key.fingerprint(), public_key.to_string());
+
+    // A key pair reconstructed from its secret key has the same public key.
+    let restored = KeyPair::from_secret_key(keypair.serialize().unwrap()).unwrap();
+    assert_eq!(&public_key, restored.public_key());
+
+    // A message sealed to the deserialized public key can still be opened.
+    let ciphertext = seal(&deserialized, b"foobar").unwrap();
+    assert_eq!(b"foobar".to_vec(), open(&keypair, &ciphertext).unwrap());
+}
+
+#[test]
+fn test_keypair_debug_redacted() {
+    crate::init().unwrap();
+
+    let keypair = KeyPair::generate().unwrap();
+    let secret = keypair.serialize().unwrap();
+    let debug = format!("{:?}", keypair);
+    assert!(debug.contains("<redacted>"));
+    assert!(debug.contains(&keypair.public_key().fingerprint()));
+
+    let secret_hex = data_encoding::HEXLOWER.encode(unsafe { secret.as_slice() });
+    assert!(!debug.contains(&secret_hex));
+    assert!(!debug.contains(&format!("{:?}", unsafe { secret.as_slice() })));
+}
@@ -0,0 +1,211 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::crypto::audit::*;
+use crate::crypto::key::*;
+use crate::crypto::keystore::*;
+use crate::testing::temp;
+use std::fs;
+use std::path::Path;
+
+/// Split the given audit log file into its raw (length-prefixed) records.
+fn read_raw_records(path: &Path) -> Vec<Vec<u8>> {
+    let data = fs::read(path).unwrap();
+    let mut records = Vec::new();
+    let mut remaining = data.as_slice();
+    while !remaining.is_empty() {
+        let len = u32::from_be_bytes([remaining[0], remaining[1], remaining[2], remaining[3]]);
+        let (record, rest) = remaining.split_at(4 + len as usize);
+        records.push(record.to_vec());
+        remaining = rest;
+    }
+    records
+}
+
+fn new_audited_key_store(dir: &temp::Dir) -> ManagedKeyStore<FileStorage> {
+    let path = dir.sub_path("keystore").unwrap();
+    ManagedKeyStore::new(FileStorage::new(path.as_path()), false)
+        .unwrap()
+        .with_audit_log(AuditLog::next_to(path.as_path()))
+}
+
+#[test]
+fn test_audit_log_chain_verifies() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let key_a = Key::new_random().unwrap();
+    let key_b = Key::new_random().unwrap();
+
+    {
+        let mut keystore = new_audited_key_store(&dir);
+        assert!(keystore.add_key(&key_a).unwrap());
+        assert!(keystore.add_key(&key_b).unwrap());
+        assert!(!keystore.add_key(&key_b).unwrap());
+        assert!(keystore.remove_key(&key_b).unwrap());
+        keystore.flush().unwrap();
+    }
+
+    let mut keystore = new_audited_key_store(&dir);
+    keystore.open(&key_a).unwrap();
+
+    let report = keystore.verify_audit_log().unwrap();
+    assert!(report.is_intact(), "{:?}", report);
+    assert!(report.macs_verified);
+    assert!(report.unauthenticated.is_empty());
+    let summary: Vec<(AuditOperation, AuditOutcome)> = report
+        .entries
+        .iter()
+        .map(|e| (e.operation, e.outcome.clone()))
+        .collect();
+    assert_eq!(
+        vec![
+            (AuditOperation::AddKey, AuditOutcome::Success),
+            (AuditOperation::AddKey, AuditOutcome::Success),
+            (AuditOperation::AddKey, AuditOutcome::Unchanged),
+            (AuditOperation::RemoveKey, AuditOutcome::Success),
+            (AuditOperation::Open, AuditOutcome::Success),
+        ],
+        summary
+    );
+    assert_ne!(
+        report.entries[0].wrapping_key,
+        report.entries[1].wrapping_key
+    );
+    assert_eq!(
+        report.entries[0].wrapping_key,
+        report.entries[4].wrapping_key
+    );
+}
+
+#[test]
+fn test_audit_log_detects_deleted_entry() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let key_a = Key::new_random().unwrap();
+    let key_b = Key::new_random().unwrap();
+    let key_c = Key::new_random().unwrap();
+
+    let mut keystore = new_audited_key_store(&dir);
+    keystore.add_key(&key_a).unwrap();
+    keystore.add_key(&key_b).unwrap();
+    keystore.add_key(&key_c).unwrap();
+    keystore.flush().unwrap();
+    assert!(keystore.verify_audit_log().unwrap().is_intact());
+
+    let path = keystore.audit_log().unwrap().path().to_path_buf();
+    let mut records = read_raw_records(path.as_path());
+    assert_eq!(3, records.len());
+    records.remove(1);
+    fs::write(path.as_path(), records.concat()).unwrap();
+
+    let report = keystore.verify_audit_log().unwrap();
+    assert!(!report.is_intact());
+    assert_eq!(vec![1], report.breaks);
+    assert_eq!(2, report.entries.len());
+}
+
+#[test]
+fn test_audit_log_records_unauthenticated_open_failures() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let key = Key::new_random().unwrap();
+    let wrong_key = Key::new_random().unwrap();
+
+    {
+        let mut keystore = new_audited_key_store(&dir);
+        keystore.add_key(&key).unwrap();
+        keystore.flush().unwrap();
+    }
+
+    let mut keystore = new_audited_key_store(&dir);
+    assert!(keystore.open(&wrong_key).is_err());
+
+    // While locked, MACs can't be checked, but the chain can.
+    let report = keystore.verify_audit_log().unwrap();
+    assert!(report.is_intact());
+    assert!(!report.macs_verified);
+    assert_eq!(vec![1], report.unauthenticated);
+    assert_eq!(AuditOperation::Open, report.entries[1].operation);
+    match report.entries[1].outcome {
+        AuditOutcome::Failure(_) => {}
+        ref other => panic!("expected a failure outcome, got {:?}", other),
+    }
+
+    keystore.open(&key).unwrap();
+    let report = keystore.verify_audit_log().unwrap();
+    assert!(report.is_intact());
+    assert!(report.macs_verified);
+    assert_eq!(vec![1], report.unauthenticated);
+    assert!(report.entries[2].is_authenticated());
+    assert_eq!(3, report.entries.len());
+}
+
+#[test]
+fn test_audit_log_detects_modified_entry() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let key = Key::new_random().unwrap();
+    let mut keystore = new_audited_key_store(&dir);
+    keystore.add_key(&key).unwrap();
+    keystore.flush().unwrap();
+
+    // Change the only entry's key fingerprint, keeping its (now invalid) MAC.
+    let path = keystore.audit_log().unwrap().path().to_path_buf();
+    let fingerprint = keystore.verify_audit_log().unwrap().entries[0]
+        .wrapping_key
+        .clone();
+    let mut tampered = fs::read(path.as_path()).unwrap();
+    let idx = tampered
+        .windows(fingerprint.len())
+        .position(|w| w == fingerprint.as_bytes())
+        .unwrap();
+    tampered[idx] = match tampered[idx] {
+        b'0' => b'1',
+        _ => b'0',
+    };
+    fs::write(path.as_path(), tampered).unwrap();
+
+    let report = keystore.verify_audit_log().unwrap();
+    assert!(!report.is_intact());
+    assert_eq!(vec![0], report.invalid_macs);
+}
+
+#[test]
+fn test_no_audit_log_by_default() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let path = dir.sub_path("keystore").unwrap();
+    let key = Key::new_random().unwrap();
+    {
+        let mut keystore = ManagedKeyStore::new(FileStorage::new(path.as_path()), false).unwrap();
+        keystore.add_key(&key).unwrap();
+        keystore.flush().unwrap();
+        assert!(keystore.audit_log().is_none());
+        assert!(keystore.verify_audit_log().is_err());
+    }
+    let mut keystore = ManagedKeyStore::new(FileStorage::new(path.as_path()), false).unwrap();
+    assert!(keystore.open(&Key::new_random().unwrap()).is_err());
+    keystore.open(&key).unwrap();
+
+    let entries: Vec<_> = fs::read_dir(dir.path())
+        .unwrap()
+        .map(|e| e.unwrap().file_name())
+        .collect();
+    assert_eq!(vec![std::ffi::OsString::from("keystore")], entries);
+    assert!(!AuditLog::next_to(path.as_path()).path().exists());
+}
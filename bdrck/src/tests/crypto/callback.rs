@@ -0,0 +1,130 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::crypto::callback::*;
+use crate::crypto::digest::Digest;
+use crate::crypto::key::{AbstractKey, Key};
+use crate::crypto::keystore::KeyStore;
+use crate::crypto::secret::Secret;
+use crate::error::*;
+use crate::testing::crypto::MockHardwareKey;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Construct a CallbackKey which is backed by an ordinary software key.
+fn new_software_callback_key() -> CallbackKey {
+    let key = Key::new_random().unwrap();
+    let key_data = key.serialize().unwrap();
+    CallbackKey::new(
+        key.get_digest(),
+        Box::new(move |challenge| {
+            let mut data = unsafe { key_data.as_slice() }.to_vec();
+            data.extend_from_slice(challenge);
+            Ok(Digest::from_bytes(data.as_slice()).as_slice().to_vec())
+        }),
+    )
+}
+
+#[test]
+fn test_callback_key_keystore_cycle() {
+    crate::init().unwrap();
+
+    let software_key = Key::new_random().unwrap();
+    let callback_key = new_software_callback_key();
+
+    let mut keystore = KeyStore::new().unwrap();
+    let master_digest = keystore.get_master_key().unwrap().get_digest();
+    assert!(keystore.add_key(&callback_key).unwrap());
+    assert!(!keystore.add_key(&callback_key).unwrap());
+    assert!(keystore.add_key(&software_key).unwrap());
+
+    let data = keystore.to_vec().unwrap();
+    let mut keystore = KeyStore::load_slice(data.as_slice()).unwrap();
+    keystore.open(&callback_key).unwrap();
+    assert_eq!(
+        master_digest,
+        keystore.get_master_key().unwrap().get_digest()
+    );
+
+    // Remove the callback key; it should no longer be able to open the store.
+    assert!(keystore.remove_key(&callback_key).unwrap());
+    let data = keystore.to_vec().unwrap();
+    let mut keystore = KeyStore::load_slice(data.as_slice()).unwrap();
+    assert!(keystore.open(&callback_key).is_err());
+    keystore.open(&software_key).unwrap();
+}
+
+#[test]
+fn test_callback_key_wrong_response() {
+    crate::init().unwrap();
+
+    let callback_key = new_software_callback_key();
+    let impostor = CallbackKey::new(
+        callback_key.get_digest(),
+        Box::new(|challenge| Ok(challenge.to_vec())),
+    );
+
+    let plaintext = Secret::with_len(16).unwrap();
+    let (nonce, ciphertext) = callback_key.encrypt(&plaintext, None).unwrap();
+    assert!(callback_key.decrypt(nonce.as_ref(), &ciphertext).is_ok());
+    assert!(impostor.decrypt(nonce.as_ref(), &ciphertext).is_err());
+}
+
+#[test]
+fn test_callback_key_unsupported_operations() {
+    crate::init().unwrap();
+
+    let callback_key = new_software_callback_key();
+    match callback_key.serialize() {
+        Err(Error::Unsupported(_)) => {}
+        _ => panic!("expected CallbackKey::serialize to be unsupported"),
+    }
+    match CallbackKey::deserialize(Secret::new()) {
+        Err(Error::Unsupported(_)) => {}
+        _ => panic!("expected CallbackKey::deserialize to be unsupported"),
+    }
+}
+
+#[test]
+fn test_mock_hardware_key() {
+    crate::init().unwrap();
+
+    let key = MockHardwareKey::new(Duration::from_millis(1)).unwrap();
+    let observed = Arc::new(AtomicU64::new(0));
+    let observed_clone = observed.clone();
+    key.set_on_prompt(Box::new(move || {
+        observed_clone.fetch_add(1, Ordering::SeqCst);
+    }));
+
+    let mut keystore = KeyStore::new().unwrap();
+    keystore.add_key(&key).unwrap();
+    assert_eq!(1, key.prompt_count());
+    let data = keystore.to_vec().unwrap();
+
+    // The simulated user doesn't touch their key, so opening fails.
+    key.set_present(false);
+    let mut keystore = KeyStore::load_slice(data.as_slice()).unwrap();
+    assert!(keystore.open(&key).is_err());
+    assert_eq!(2, key.prompt_count());
+
+    key.set_present(true);
+    keystore.open(&key).unwrap();
+    assert_eq!(3, key.prompt_count());
+    assert_eq!(3, observed.load(Ordering::SeqCst));
+
+    // Computing the digest must not prompt.
+    key.get_digest();
+    assert_eq!(3, key.prompt_count());
+}
@@ -0,0 +1,285 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::crypto::digest::*;
+use crate::testing::temp;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+#[test]
+fn test_digest_builder_matches_from_bytes() {
+    crate::init().unwrap();
+
+    let data: Vec<u8> = (0..10000).map(|i| (i % 251) as u8).collect();
+    let mut builder = DigestBuilder::new();
+    for chunk in data.chunks(333) {
+        builder.update(chunk);
+    }
+    assert_eq!(Digest::from_bytes(data.as_slice()), builder.finish());
+
+    // An empty builder should match the digest of empty input.
+    assert_eq!(Digest::from_bytes(&[]), DigestBuilder::new().finish());
+}
+
+fn create_fixture_tree(dir: &temp::Dir) {
+    fs::create_dir_all(dir.sub_path("a/b/c").unwrap()).unwrap();
+    fs::create_dir_all(dir.sub_path("empty").unwrap()).unwrap();
+    fs::write(dir.sub_path("top.txt").unwrap(), b"top").unwrap();
+    fs::write(dir.sub_path("a/one.txt").unwrap(), b"one").unwrap();
+    fs::write(dir.sub_path("a/b/two.txt").unwrap(), b"two").unwrap();
+    fs::write(dir.sub_path("a/b/c/empty.txt").unwrap(), b"").unwrap();
+    for i in 0..32 {
+        let data: Vec<u8> = (0..(i * 1000)).map(|j| (j % 251) as u8).collect();
+        fs::write(dir.sub_path(format!("a/b/c/{}.bin", i)).unwrap(), data).unwrap();
+    }
+}
+
+#[test]
+fn test_digest_tree_parallel_matches_serial() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    create_fixture_tree(&dir);
+
+    let serial = digest_tree(dir.path()).unwrap();
+    assert!(serial.errors().is_empty());
+    assert_eq!(36, serial.files().len());
+    assert_eq!(
+        &digest_file(dir.sub_path("a/b/two.txt").unwrap()).unwrap(),
+        serial.files().get(Path::new("a/b/two.txt")).unwrap()
+    );
+
+    for threads in [None, Some(1), Some(3), Some(16)] {
+        let parallel = digest_tree_parallel(dir.path(), threads).unwrap();
+        assert_eq!(serial, parallel);
+        assert_eq!(serial.digest(), parallel.digest());
+    }
+
+    // Changing any file's contents or name should change the tree's digest.
+    fs::write(dir.sub_path("a/one.txt").unwrap(), b"changed").unwrap();
+    let changed = digest_tree(dir.path()).unwrap();
+    assert_ne!(serial.digest(), changed.digest());
+    fs::write(dir.sub_path("a/one.txt").unwrap(), b"one").unwrap();
+    fs::rename(
+        dir.sub_path("top.txt").unwrap(),
+        dir.sub_path("renamed.txt").unwrap(),
+    )
+    .unwrap();
+    assert_ne!(serial.digest(), digest_tree(dir.path()).unwrap().digest());
+}
+
+#[test]
+fn test_digest_tree_parallel_progress() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    create_fixture_tree(&dir);
+
+    let mut calls = Vec::new();
+    digest_tree_parallel_with_progress(dir.path(), Some(4), |done, discovered| {
+        calls.push((done, discovered))
+    })
+    .unwrap();
+    assert_eq!(36, calls.len());
+    assert_eq!(Some(&(36, 36)), calls.last());
+    for (i, (done, discovered)) in calls.iter().enumerate() {
+        assert_eq!(i + 1, *done);
+        assert!(done <= discovered);
+    }
+}
+
+#[test]
+fn test_digest_tree_errors() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    create_fixture_tree(&dir);
+    // A dangling symlink can't be hashed, even by root.
+    std::os::unix::fs::symlink("nonexistent", dir.sub_path("a/dangling").unwrap()).unwrap();
+    // Neither can an unreadable file, unless we're root.
+    let unreadable = dir.sub_path("a/b/unreadable.txt").unwrap();
+    fs::write(&unreadable, b"secret").unwrap();
+    fs::set_permissions(&unreadable, fs::Permissions::from_mode(0o000)).unwrap();
+    let is_root = unsafe { libc::geteuid() } == 0;
+
+    for manifest in [
+        digest_tree(dir.path()).unwrap(),
+        digest_tree_parallel(dir.path(), Some(4)).unwrap(),
+    ] {
+        assert!(manifest.errors().contains_key(Path::new("a/dangling")));
+        assert_eq!(
+            !is_root,
+            manifest
+                .errors()
+                .contains_key(Path::new("a/b/unreadable.txt"))
+        );
+        assert_eq!(if is_root { 1 } else { 2 }, manifest.errors().len());
+        // Everything else should still have been hashed.
+        assert_eq!(if is_root { 37 } else { 36 }, manifest.files().len());
+    }
+
+    assert!(digest_tree(dir.sub_path("top.txt").unwrap()).is_err());
+}
+
+// Some deterministic, but not repetitive, test data.
+fn merkle_test_data(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+}
+
+#[test]
+fn test_merkle_tree_root_stability() {
+    crate::init().unwrap();
+
+    let data = merkle_test_data(10 * 1024 + 17);
+    let a = MerkleTree::from_reader(data.as_slice(), 1024).unwrap();
+    let b = MerkleTree::from_reader(data.as_slice(), 1024).unwrap();
+    assert_eq!(11, a.leaf_count());
+    assert_eq!(a.root(), b.root());
+    assert!(a.diff(&b).unwrap().is_empty());
+
+    // The chunk size changes the tree, so different sizes can't be compared.
+    let c = MerkleTree::from_reader(data.as_slice(), 2048).unwrap();
+    assert_eq!(6, c.leaf_count());
+    assert_ne!(a.root(), c.root());
+    assert!(a.diff(&c).is_err());
+    assert!(MerkleTree::from_reader(data.as_slice(), 0).is_err());
+}
+
+#[test]
+fn test_merkle_proof() {
+    crate::init().unwrap();
+
+    // 11 leaves, so several levels have an odd number of nodes.
+    let data = merkle_test_data(10 * 1024 + 17);
+    let tree = MerkleTree::from_reader(data.as_slice(), 1024).unwrap();
+    let root = tree.root();
+    for (i, chunk) in data.chunks(1024).enumerate() {
+        let leaf = MerkleTree::leaf_digest(chunk);
+        assert_eq!(Some(&leaf), tree.leaf(i));
+        let proof = tree.proof(i).unwrap();
+        assert!(proof.verify(&root, tree.leaf_count(), &leaf, i));
+        // The proof is only valid for this leaf, at this index.
+        assert!(!proof.verify(&root, tree.leaf_count(), &leaf, (i + 1) % tree.leaf_count()));
+        assert!(!proof.verify(
+            &root,
+            tree.leaf_count(),
+            &MerkleTree::leaf_digest(b"tampered"),
+            i
+        ));
+    }
+    assert!(tree.proof(tree.leaf_count()).is_err());
+
+    // A tampered chunk produces a different root, so old proofs fail.
+    let mut tampered = data.clone();
+    tampered[3000] ^= 0xff;
+    let tampered_tree = MerkleTree::from_reader(tampered.as_slice(), 1024).unwrap();
+    assert!(!tree
+        .proof(2)
+        .unwrap()
+        .verify(&tampered_tree.root(), 11, tree.leaf(2).unwrap(), 2));
+}
+
+#[test]
+fn test_merkle_proof_forged_leaf_count() {
+    crate::init().unwrap();
+
+    // With leaves [a, b, c], the proof for c is just [H(a, b)]. If the proof
+    // claimed the tree had only 2 leaves, that same sibling would "prove" c
+    // is at index 1, so the leaf count must not come from the proof.
+    let data = merkle_test_data(3 * 1024);
+    let tree = MerkleTree::from_reader(data.as_slice(), 1024).unwrap();
+    let root = tree.root();
+    let c = tree.leaf(2).unwrap();
+    #[derive(Deserialize, Serialize)]
+    struct UntrustedProof {
+        #[serde(default)]
+        leaf_count: usize,
+        siblings: Vec<Digest>,
+    }
+    let proof = rmp_serde::to_vec_named(&tree.proof(2).unwrap()).unwrap();
+    let mut forged: UntrustedProof = rmp_serde::from_slice(proof.as_slice()).unwrap();
+    forged.leaf_count = 2;
+    let forged = rmp_serde::to_vec_named(&forged).unwrap();
+    let forged: MerkleProof = rmp_serde::from_slice(forged.as_slice()).unwrap();
+
+    assert!(forged.verify(&root, 3, c, 2));
+    assert!(!forged.verify(&root, 3, c, 1));
+}
+
+#[test]
+fn test_merkle_tree_diff() {
+    crate::init().unwrap();
+
+    let data = merkle_test_data(8 * 1024);
+    let mut modified = data.clone();
+    modified[5 * 1024 + 100] ^= 0x01;
+    let a = MerkleTree::from_reader(data.as_slice(), 1024).unwrap();
+    let b = MerkleTree::from_reader(modified.as_slice(), 1024).unwrap();
+    assert_ne!(a.root(), b.root());
+    assert_eq!(vec![5], a.diff(&b).unwrap());
+
+    // Appended chunks are all reported as different.
+    modified.extend_from_slice(&[0; 1500]);
+    let c = MerkleTree::from_reader(modified.as_slice(), 1024).unwrap();
+    assert_eq!(vec![5, 8, 9], a.diff(&c).unwrap());
+}
+
+#[test]
+fn test_merkle_tree_edge_cases() {
+    crate::init().unwrap();
+
+    // Empty input has a single (empty) leaf.
+    let empty = MerkleTree::from_reader(&b""[..], DEFAULT_MERKLE_CHUNK_BYTES).unwrap();
+    assert_eq!(1, empty.leaf_count());
+    assert_eq!(MerkleTree::leaf_digest(b""), empty.root());
+    assert!(empty
+        .proof(0)
+        .unwrap()
+        .verify(&empty.root(), 1, &MerkleTree::leaf_digest(b""), 0));
+
+    // Input which fits in a single chunk (even exactly) has a single leaf.
+    let empty = MerkleTree::from_reader(&b""[..], 1024).unwrap();
+    for data in [&b"foobar"[..], &[0xab; 1024][..]] {
+        let tree = MerkleTree::from_reader(data, 1024).unwrap();
+        assert_eq!(1, tree.leaf_count());
+        assert_eq!(MerkleTree::leaf_digest(data), tree.root());
+        assert_eq!(vec![0], tree.diff(&empty).unwrap());
+    }
+
+    // The domain separation means the root isn't just a plain digest.
+    assert_ne!(
+        Digest::from_bytes(b"foobar"),
+        MerkleTree::leaf_digest(b"foobar")
+    );
+}
+
+#[test]
+fn test_merkle_tree_serde_round_trip() {
+    crate::init().unwrap();
+
+    let data = merkle_test_data(5 * 1024 + 1);
+    let tree = MerkleTree::from_reader(data.as_slice(), 1024).unwrap();
+    let serialized = rmp_serde::to_vec(&tree).unwrap();
+    let deserialized: MerkleTree = rmp_serde::from_slice(serialized.as_slice()).unwrap();
+    assert_eq!(tree, deserialized);
+    assert_eq!(tree.root(), deserialized.root());
+    assert_eq!(1024, deserialized.chunk_size());
+
+    let proof = tree.proof(5).unwrap();
+    let serialized = rmp_serde::to_vec(&proof).unwrap();
+    let deserialized: MerkleProof = rmp_serde::from_slice(serialized.as_slice()).unwrap();
+    assert!(deserialized.verify(&tree.root(), 6, tree.leaf(5).unwrap(), 5));
+}
@@ -109,3 +109,90 @@ fn test_decrypting_with_wrong_key_fails() {
     let decrypted_result = wrong_key.decrypt(nonce.as_ref(), ciphertext.as_slice());
     assert!(decrypted_result.is_err());
 }
+
+fn key_from_bytes(bytes: &[u8]) -> Key {
+    let mut s = Secret::with_len(bytes.len()).unwrap();
+    unsafe { s.as_mut_slice() }.copy_from_slice(bytes);
+    Key::from_raw(s).unwrap()
+}
+
+#[test]
+fn test_derive_subkey_is_deterministic() {
+    crate::init().unwrap();
+
+    let key = Key::new_random().unwrap();
+    let a = key.derive_subkey("dbfields", 1).unwrap();
+    let b = key.derive_subkey("dbfields", 1).unwrap();
+    assert_eq!(a.get_digest(), b.get_digest());
+    assert_ne!(key.get_digest(), a.get_digest());
+
+    // A different parent yields a different sub-key.
+    let other = Key::new_random().unwrap();
+    assert_ne!(
+        a.get_digest(),
+        other.derive_subkey("dbfields", 1).unwrap().get_digest()
+    );
+}
+
+#[test]
+fn test_derive_subkey_independence() {
+    crate::init().unwrap();
+
+    let key = Key::new_random().unwrap();
+    let a = key.derive_subkey("blobs", 0).unwrap();
+    let siblings = [
+        key.derive_subkey("blobs", 1).unwrap(),
+        key.derive_subkey("tokens", 0).unwrap(),
+        key.derive_subkey("blob", 0).unwrap(),
+    ];
+
+    let plaintext = random_secret(64);
+    let (nonce, ciphertext) = a.encrypt(&plaintext, None).unwrap();
+    for sibling in siblings.iter() {
+        assert_ne!(a.get_digest(), sibling.get_digest());
+        assert!(sibling
+            .decrypt(nonce.as_ref(), ciphertext.as_slice())
+            .is_err());
+    }
+    let decrypted = a.decrypt(nonce.as_ref(), ciphertext.as_slice()).unwrap();
+    assert_eq!(unsafe { plaintext.as_slice() }, unsafe {
+        decrypted.as_slice()
+    });
+}
+
+#[test]
+fn test_derive_subkey_context_length() {
+    crate::init().unwrap();
+
+    let key = Key::new_random().unwrap();
+    assert!(key.derive_subkey("", 0).is_ok());
+    assert!(key.derive_subkey("12345678", 0).is_ok());
+    // Longer contexts aren't truncated, which would make these two equivalent.
+    assert!(key.derive_subkey("123456789", 0).is_err());
+    assert!(key.derive_subkey("12345678a", 0).is_err());
+    // Nor are contexts which only differ by padding allowed to collide.
+    assert!(key.derive_subkey("1234\0", 0).is_err());
+    assert_ne!(
+        key.derive_subkey("1234", 0).unwrap().get_digest(),
+        key.derive_subkey("12345", 0).unwrap().get_digest()
+    );
+    // Multi-byte characters count by bytes, not characters.
+    assert!(key.derive_subkey("ééééé", 0).is_err());
+    // The "bdrck" prefix is reserved for internal use.
+    assert!(key.derive_subkey("bdrckmac", 1).is_err());
+}
+
+#[test]
+fn test_derive_subkey_golden() {
+    crate::init().unwrap();
+
+    let parent: Vec<u8> = (0..KEY_BYTES as u8).collect();
+    let key = key_from_bytes(parent.as_slice());
+    let subkey = key.derive_subkey("golden", 42).unwrap();
+    // This is BLAKE2b keyed with the parent, with the index as the salt and
+    // the context as the personalization, per libsodium's crypto_kdf.
+    assert_eq!(
+        "95934f615c59256d2d32b5881c37c027b2ca76c2727e38128fd7256439c206d4",
+        data_encoding::HEXLOWER.encode(unsafe { subkey.as_secret().as_slice() })
+    );
+}
@@ -16,8 +16,49 @@ use crate::crypto::digest::*;
 use crate::crypto::key::*;
 use crate::crypto::keystore::*;
 use crate::crypto::secret::Secret;
+use crate::crypto::wrap::WrappedKey;
+use crate::error::*;
+use crate::testing::clock::MockClock;
+use crate::testing::crypto::golden_key_store;
+use crate::testing::logging::capture;
 use crate::testing::temp;
+use serde::{Deserialize, Serialize};
 use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::Level;
+
+/// This mirrors the serialized format of a `KeyStore`, so tests can tamper
+/// with its contents.
+#[derive(Deserialize, Serialize)]
+struct RawKeyStore {
+    token_nonce: Option<Nonce>,
+    token: Vec<u8>,
+    wrapped_keys: Vec<WrappedKey>,
+    #[serde(default)]
+    mac: Option<Vec<u8>>,
+}
+
+/// Like `RawKeyStore`, but for KeyStores which also have key metadata.
+#[derive(Deserialize)]
+struct RawKeyStoreWithMetadata {
+    token_nonce: Option<Nonce>,
+    token: Vec<u8>,
+    wrapped_keys: Vec<WrappedKey>,
+    mac: Option<Vec<u8>>,
+    #[allow(dead_code)]
+    key_metadata: serde::de::IgnoredAny,
+}
+
+/// This mirrors the serialized format of a `WrappedKey`, so tests can tamper
+/// with its contents.
+#[derive(Deserialize, Serialize)]
+struct RawWrappedKey {
+    data: Vec<u8>,
+    nonce: Option<Nonce>,
+    wrapping_digest: Digest,
+    aad: bool,
+}
 
 fn new_password(password: &str) -> Secret {
     let bytes = password.as_bytes();
@@ -162,12 +203,800 @@ fn test_unpersistable() {
     fs::remove_file(file.path()).unwrap();
     assert!(!file.path().exists());
 
-    {
+    let logs = capture(|| {
         // Construct a new key store, but don't add any keys.
         let keystore = DiskKeyStore::new(file.path(), false).unwrap();
         assert!(!keystore.is_persistable());
-    }
+    });
+    // Failing to persist it on drop is logged, since there's no other way to
+    // report it.
+    logs.assert_contains(Level::ERROR, "cannot be persisted");
 
     // Since the key store was not persistable, the file should still not exist.
     assert!(!file.path().exists());
 }
+
+#[test]
+fn test_legacy_wrapped_key_opens() {
+    crate::init().unwrap();
+
+    let key = Key::new_random().unwrap();
+    let mut keystore = KeyStore::new().unwrap();
+    let master_digest = keystore.get_master_key().unwrap().get_digest();
+    // Serialize a wrapped key without associated data, and then strip the
+    // trailing `aad` field (a 4-element array becomes a 3-element array), to
+    // get the format older versions produced.
+    let mut legacy =
+        rmp_serde::to_vec(&WrappedKey::wrap(keystore.get_master_key().unwrap(), &key).unwrap())
+            .unwrap();
+    assert_eq!(0x94, legacy[0]);
+    assert_eq!(Some(0xc2), legacy.pop());
+    legacy[0] = 0x93;
+    // Add some other key, just so we can get at the serialized token.
+    keystore.add_key(&Key::new_random().unwrap()).unwrap();
+    let raw: RawKeyStore = rmp_serde::from_slice(keystore.to_vec().unwrap().as_slice()).unwrap();
+
+    // Construct a serialized KeyStore whose only entry is in the legacy format.
+    let mut data = vec![0x93];
+    data.extend(rmp_serde::to_vec(&raw.token_nonce).unwrap());
+    data.extend(rmp_serde::to_vec(&raw.token).unwrap());
+    data.push(0x91);
+    data.extend(legacy);
+
+    let mut keystore = KeyStore::load_slice(data.as_slice()).unwrap();
+    assert!(!keystore.had_integrity_mac());
+    assert!(!keystore.iter_wrapped_keys().next().unwrap().has_aad());
+    keystore.open(&key).unwrap();
+    assert_eq!(
+        master_digest,
+        keystore.get_master_key().unwrap().get_digest()
+    );
+}
+
+#[test]
+fn test_transplanted_key_fails() {
+    crate::init().unwrap();
+
+    let key = Key::new_random().unwrap();
+    let mut keystore = KeyStore::new().unwrap();
+    keystore.add_key(&key).unwrap();
+    let raw: RawKeyStore = rmp_serde::from_slice(keystore.to_vec().unwrap().as_slice()).unwrap();
+
+    // Construct a different KeyStore which shares the same master key, but has
+    // a different token, and copy the wrapped key into it.
+    let master_key = keystore.get_master_key().unwrap();
+    let token = master_key
+        .decrypt(raw.token_nonce.as_ref(), raw.token.as_slice())
+        .unwrap();
+    let (token_nonce, token) = master_key.encrypt(&token, None).unwrap();
+    let other = RawKeyStore {
+        token_nonce,
+        token,
+        wrapped_keys: raw.wrapped_keys,
+        mac: None,
+    };
+
+    let mut other = KeyStore::load_slice(rmp_serde::to_vec(&other).unwrap().as_slice()).unwrap();
+    assert!(other.open(&key).is_err());
+}
+
+#[test]
+fn test_integrity_mac_round_trip() {
+    crate::init().unwrap();
+
+    let key = Key::new_random().unwrap();
+    let mut keystore = KeyStore::new().unwrap();
+    assert!(!keystore.had_integrity_mac());
+    keystore.add_key(&key).unwrap();
+    let master_digest = keystore.get_master_key().unwrap().get_digest();
+
+    let mut keystore = KeyStore::load_slice(keystore.to_vec().unwrap().as_slice()).unwrap();
+    assert!(keystore.had_integrity_mac());
+    keystore.open(&key).unwrap();
+    assert_eq!(
+        master_digest,
+        keystore.get_master_key().unwrap().get_digest()
+    );
+}
+
+#[test]
+fn test_tampered_wrapped_key_detected() {
+    crate::init().unwrap();
+
+    let key = Key::new_random().unwrap();
+    let other_key = Key::new_random().unwrap();
+    let mut keystore = KeyStore::new().unwrap();
+    keystore.add_key(&key).unwrap();
+    keystore.add_key(&other_key).unwrap();
+    let mut raw: RawKeyStore =
+        rmp_serde::from_slice(keystore.to_vec().unwrap().as_slice()).unwrap();
+    assert!(raw.mac.is_some());
+
+    // Flip a single bit in the *other* key's wrapped data, so opening with the
+    // first key still recovers the master key.
+    let other_wrapped = raw.wrapped_keys.pop().unwrap();
+    let mut other_wrapped: RawWrappedKey =
+        rmp_serde::from_slice(rmp_serde::to_vec(&other_wrapped).unwrap().as_slice()).unwrap();
+    other_wrapped.data[0] ^= 0x01;
+    raw.wrapped_keys.push(
+        rmp_serde::from_slice(rmp_serde::to_vec(&other_wrapped).unwrap().as_slice()).unwrap(),
+    );
+
+    let mut tampered = KeyStore::load_slice(rmp_serde::to_vec(&raw).unwrap().as_slice()).unwrap();
+    match tampered.open(&key) {
+        Err(Error::KeyStoreTampered(_)) => {}
+        r => panic!("expected KeyStoreTampered error, got {:?}", r),
+    }
+    assert!(!tampered.is_open());
+}
+
+#[test]
+fn test_stripped_wrapped_key_detected() {
+    crate::init().unwrap();
+
+    let key = Key::new_random().unwrap();
+    let mut keystore = KeyStore::new().unwrap();
+    keystore.add_key(&key).unwrap();
+    keystore.add_key(&Key::new_random().unwrap()).unwrap();
+    let mut raw: RawKeyStore =
+        rmp_serde::from_slice(keystore.to_vec().unwrap().as_slice()).unwrap();
+    raw.wrapped_keys.pop();
+
+    let mut tampered = KeyStore::load_slice(rmp_serde::to_vec(&raw).unwrap().as_slice()).unwrap();
+    match tampered.open(&key) {
+        Err(Error::KeyStoreTampered(_)) => {}
+        r => panic!("expected KeyStoreTampered error, got {:?}", r),
+    }
+
+    // A wrong key is still reported as such, not as tampering.
+    match tampered.open(&Key::new_random().unwrap()) {
+        Err(Error::InvalidArgument(_)) => {}
+        r => panic!("expected InvalidArgument error, got {:?}", r),
+    }
+}
+
+#[test]
+fn test_keystore_without_mac_opens() {
+    crate::init().unwrap();
+
+    let key = Key::new_random().unwrap();
+    let mut keystore = KeyStore::new().unwrap();
+    keystore.add_key(&key).unwrap();
+    let mut raw: RawKeyStore =
+        rmp_serde::from_slice(keystore.to_vec().unwrap().as_slice()).unwrap();
+    raw.mac = None;
+
+    let mut keystore = KeyStore::load_slice(rmp_serde::to_vec(&raw).unwrap().as_slice()).unwrap();
+    assert!(!keystore.had_integrity_mac());
+    keystore.open(&key).unwrap();
+
+    // Once re-persisted while open, it gains a MAC.
+    let keystore = KeyStore::load_slice(keystore.to_vec().unwrap().as_slice()).unwrap();
+    assert!(keystore.had_integrity_mac());
+}
+
+#[test]
+fn test_persist_while_locked() {
+    crate::init().unwrap();
+
+    let file = temp::File::new_file().unwrap();
+    let key = Key::new_random().unwrap();
+    let other_key = Key::new_random().unwrap();
+
+    {
+        let mut keystore = DiskKeyStore::new(file.path(), false).unwrap();
+        keystore.add_key(&key).unwrap();
+        keystore.add_key(&other_key).unwrap();
+    }
+
+    // Load and persist again without ever opening; the MAC is kept.
+    {
+        let keystore = DiskKeyStore::new(file.path(), false).unwrap();
+        assert!(keystore.had_integrity_mac());
+    }
+
+    {
+        let mut keystore = DiskKeyStore::new(file.path(), false).unwrap();
+        assert!(keystore.had_integrity_mac());
+        keystore.open(&key).unwrap();
+    }
+
+    // Modifying the KeyStore while locked drops the MAC, rather than leaving
+    // a MAC which no longer matches.
+    {
+        let mut keystore = DiskKeyStore::new(file.path(), false).unwrap();
+        assert!(keystore.remove_key(&other_key).unwrap());
+    }
+
+    {
+        let mut keystore = DiskKeyStore::new(file.path(), false).unwrap();
+        assert!(!keystore.had_integrity_mac());
+        keystore.open(&key).unwrap();
+    }
+
+    let keystore = KeyStore::load_slice(fs::read(file.path()).unwrap().as_slice()).unwrap();
+    assert!(keystore.had_integrity_mac());
+}
+
+#[test]
+fn test_managed_keystore_memory_round_trip() {
+    crate::init().unwrap();
+
+    let key = Key::new_random().unwrap();
+    let master_digest: Digest;
+    let data: Vec<u8>;
+
+    {
+        let mut keystore = ManagedKeyStore::new(MemoryStorage::new(), false).unwrap();
+        // A brand new KeyStore needs to be persisted...
+        assert!(keystore.is_dirty());
+        // ... but not before it has at least one key.
+        assert!(keystore.flush().is_err());
+        assert_eq!(0, keystore.storage().store_count());
+
+        assert!(keystore.add_key(&key).unwrap());
+        master_digest = keystore.get_master_key().unwrap().get_digest();
+        keystore.flush().unwrap();
+        assert!(!keystore.is_dirty());
+        assert_eq!(1, keystore.storage().store_count());
+        data = keystore.storage().data().unwrap();
+    }
+
+    let mut keystore = ManagedKeyStore::new(MemoryStorage::with_data(data), false).unwrap();
+    assert!(!keystore.is_dirty());
+    keystore.open(&key).unwrap();
+    assert_eq!(
+        master_digest,
+        keystore.get_master_key().unwrap().get_digest()
+    );
+}
+
+#[test]
+fn test_managed_keystore_skips_redundant_stores() {
+    crate::init().unwrap();
+
+    let key_a = Key::new_random().unwrap();
+    let key_b = Key::new_random().unwrap();
+    let mut keystore = ManagedKeyStore::new(MemoryStorage::new(), false).unwrap();
+    keystore.add_key(&key_a).unwrap();
+    keystore.flush().unwrap();
+    assert_eq!(1, keystore.storage().store_count());
+
+    // Nothing changed, so flushing again shouldn't store anything.
+    keystore.flush().unwrap();
+    assert_eq!(1, keystore.storage().store_count());
+
+    // Neither adding a duplicate key nor removing a missing one is a change.
+    assert!(!keystore.add_key(&key_a).unwrap());
+    assert!(!keystore.remove_key(&key_b).unwrap());
+    assert!(!keystore.is_dirty());
+    keystore.flush().unwrap();
+    assert_eq!(1, keystore.storage().store_count());
+
+    assert!(keystore.add_key(&key_b).unwrap());
+    assert!(keystore.is_dirty());
+    keystore.flush().unwrap();
+    assert_eq!(2, keystore.storage().store_count());
+
+    assert!(keystore.remove_key(&key_a).unwrap());
+    assert!(keystore.is_dirty());
+    keystore.flush().unwrap();
+    assert_eq!(3, keystore.storage().store_count());
+}
+
+#[test]
+fn test_managed_keystore_file_storage_compatible() {
+    crate::init().unwrap();
+
+    let file = temp::File::new_file().unwrap();
+    let key_a = Key::new_random().unwrap();
+    let key_b = Key::new_random().unwrap();
+    let master_digest: Digest;
+
+    {
+        let mut keystore = DiskKeyStore::new(file.path(), false).unwrap();
+        keystore.add_key(&key_a).unwrap();
+        master_digest = keystore.get_master_key().unwrap().get_digest();
+    }
+
+    // A file written by DiskKeyStore should be usable via FileStorage...
+    {
+        let mut keystore = ManagedKeyStore::new(FileStorage::new(file.path()), false).unwrap();
+        assert!(!keystore.is_dirty());
+        keystore.open(&key_a).unwrap();
+        assert_eq!(
+            master_digest,
+            keystore.get_master_key().unwrap().get_digest()
+        );
+        keystore.add_key(&key_b).unwrap();
+        keystore.flush().unwrap();
+    }
+
+    // ... and vice versa.
+    let mut keystore = DiskKeyStore::new(file.path(), false).unwrap();
+    keystore.open(&key_b).unwrap();
+    assert_eq!(
+        master_digest,
+        keystore.get_master_key().unwrap().get_digest()
+    );
+}
+
+#[test]
+fn test_golden_key_store_is_reproducible() {
+    crate::init().unwrap();
+
+    let (key, keystore) = golden_key_store(1).unwrap();
+    let (_, again) = golden_key_store(1).unwrap();
+    let (_, other) = golden_key_store(2).unwrap();
+
+    let data = keystore.to_vec().unwrap();
+    assert_eq!(data, again.to_vec().unwrap());
+    assert_ne!(data, other.to_vec().unwrap());
+
+    // The deterministic KeyStore should still be a perfectly normal KeyStore.
+    let mut loaded = KeyStore::load_slice(data.as_slice()).unwrap();
+    loaded.open(&key).unwrap();
+    assert!(loaded.had_integrity_mac());
+    assert_eq!(
+        keystore.get_master_key().unwrap().get_digest(),
+        loaded.get_master_key().unwrap().get_digest()
+    );
+}
+
+/// The golden fixture checked in next to this file can be regenerated (e.g.
+/// after an intentional change to the serialization format) by running this
+/// test with `BDRCK_REGENERATE_FIXTURES` set.
+#[test]
+fn test_golden_key_store_fixture() {
+    crate::init().unwrap();
+
+    let fixture: PathBuf = [
+        env!("CARGO_MANIFEST_DIR"),
+        "src",
+        "tests",
+        "crypto",
+        "testdata",
+        "keystore_seed_1.bin",
+    ]
+    .iter()
+    .collect();
+    let (key, keystore) = golden_key_store(1).unwrap();
+    let data = keystore.to_vec().unwrap();
+    if std::env::var_os("BDRCK_REGENERATE_FIXTURES").is_some() {
+        fs::write(&fixture, data.as_slice()).unwrap();
+    }
+
+    let expected = fs::read(&fixture).unwrap();
+    assert_eq!(expected, data);
+    let mut loaded = KeyStore::load_slice(expected.as_slice()).unwrap();
+    loaded.open(&key).unwrap();
+}
+
+fn new_expiring_key_store(key: &Key, expires_at: SystemTime) -> Vec<u8> {
+    let mut keystore = KeyStore::new().unwrap();
+    assert!(keystore
+        .add_key_with_options(
+            key,
+            KeyOptions {
+                label: Some("laptop".to_string()),
+                expires_at: Some(expires_at),
+                usage: KeyUsage::WrapOnly,
+            },
+        )
+        .unwrap());
+    keystore.to_vec().unwrap()
+}
+
+#[test]
+fn test_expired_key_refused() {
+    crate::init().unwrap();
+
+    let key = Key::new_random().unwrap();
+    let expires_at = UNIX_EPOCH + Duration::from_secs(1_000_000);
+    let data = new_expiring_key_store(&key, expires_at);
+    let clock = MockClock::new(expires_at);
+
+    // A wrong key is still reported as such, not as expired.
+    let mut keystore = KeyStore::load_slice(data.as_slice()).unwrap();
+    match keystore.open_with_clock(&Key::new_random().unwrap(), &clock) {
+        Err(Error::InvalidArgument(_)) => {}
+        r => panic!("expected InvalidArgument, got {:?}", r.map(|_| ())),
+    }
+
+    match keystore.open_with_clock(&key, &clock) {
+        Err(Error::KeyExpired { expired_at, label }) => {
+            assert_eq!(expires_at, expired_at);
+            assert_eq!("laptop", label);
+        }
+        r => panic!("expected KeyExpired, got {:?}", r.map(|_| ())),
+    }
+    assert!(!keystore.is_open());
+
+    // The escape hatch still lets the user in, e.g. to rotate the key.
+    keystore.open_allow_expired(&key).unwrap();
+    assert!(keystore.is_open());
+}
+
+#[test]
+fn test_unexpired_key_opens() {
+    crate::init().unwrap();
+
+    let key = Key::new_random().unwrap();
+    let expires_at = UNIX_EPOCH + Duration::from_secs(1_000_000);
+    let data = new_expiring_key_store(&key, expires_at);
+
+    let clock = MockClock::new(expires_at - Duration::from_secs(1));
+    let mut keystore = KeyStore::load_slice(data.as_slice()).unwrap();
+    keystore.open_with_clock(&key, &clock).unwrap();
+    // The metadata is covered by the integrity MAC.
+    assert!(keystore.had_integrity_mac());
+
+    // Keys without an expiry never expire.
+    let other = Key::new_random().unwrap();
+    let mut keystore = KeyStore::new().unwrap();
+    keystore.add_key(&other).unwrap();
+    let mut keystore = KeyStore::load_slice(keystore.to_vec().unwrap().as_slice()).unwrap();
+    clock.advance(Duration::from_secs(u32::MAX as u64));
+    keystore.open_with_clock(&other, &clock).unwrap();
+}
+
+#[test]
+fn test_stripped_key_metadata_detected() {
+    crate::init().unwrap();
+
+    let key = Key::new_random().unwrap();
+    let data = new_expiring_key_store(&key, UNIX_EPOCH + Duration::from_secs(1));
+
+    // Drop the key metadata (the last serialized field), and with it the
+    // expiry. This must not let an expired key through.
+    let raw: RawKeyStoreWithMetadata = rmp_serde::from_slice(data.as_slice()).unwrap();
+    let stripped = rmp_serde::to_vec(&RawKeyStore {
+        token_nonce: raw.token_nonce,
+        token: raw.token,
+        wrapped_keys: raw.wrapped_keys,
+        mac: raw.mac,
+    })
+    .unwrap();
+    let mut keystore = KeyStore::load_slice(stripped.as_slice()).unwrap();
+    match keystore.open(&key) {
+        Err(Error::KeyStoreTampered(_)) => {}
+        r => panic!("expected KeyStoreTampered, got {:?}", r.map(|_| ())),
+    }
+}
+
+#[test]
+fn test_list_keys() {
+    crate::init().unwrap();
+
+    // Legacy KeyStores (without any metadata) still load, and their keys are
+    // listed with default metadata.
+    let (golden_key, golden) = golden_key_store(1).unwrap();
+    let mut keystore = KeyStore::load_slice(golden.to_vec().unwrap().as_slice()).unwrap();
+    assert_eq!(
+        vec![KeyInfo {
+            wrapping_digest: golden_key.get_digest(),
+            label: None,
+            expires_at: None,
+            usage: KeyUsage::General,
+            rotated_at: None,
+        }],
+        keystore.list_keys()
+    );
+
+    keystore.open(&golden_key).unwrap();
+    let key = Key::new_random().unwrap();
+    let expires_at = UNIX_EPOCH + Duration::from_secs(1_000_000);
+    keystore
+        .add_key_with_options(
+            &key,
+            KeyOptions {
+                label: Some("backup".to_string()),
+                expires_at: Some(expires_at),
+                usage: KeyUsage::WrapOnly,
+            },
+        )
+        .unwrap();
+
+    // List the keys of an unopened copy, like a CLI would.
+    let keystore = KeyStore::load_slice(keystore.to_vec().unwrap().as_slice()).unwrap();
+    let keys = keystore.list_keys();
+    assert_eq!(2, keys.len());
+    assert_eq!(None, keys[0].expires_at);
+    assert_eq!(
+        KeyInfo {
+            wrapping_digest: key.get_digest(),
+            label: Some("backup".to_string()),
+            expires_at: Some(expires_at),
+            usage: KeyUsage::WrapOnly,
+            rotated_at: None,
+        },
+        keys[1]
+    );
+    assert!(!keys[1].is_expired_at(expires_at - Duration::from_secs(1)));
+    assert!(keys[1].is_expired_at(expires_at));
+}
+
+#[test]
+fn test_keystore_derive() {
+    crate::init().unwrap();
+
+    let wrap_key = Key::new_random().unwrap();
+    let mut keystore = KeyStore::new().unwrap();
+    assert!(keystore.add_key(&wrap_key).unwrap());
+    let derived = keystore.derive("tokens", 3).unwrap().get_digest();
+    assert_eq!(derived, keystore.derive("tokens", 3).unwrap().get_digest());
+
+    // Derived keys survive a save / load, and don't depend on the wrapping key.
+    let mut loaded = KeyStore::load_slice(keystore.to_vec().unwrap().as_slice()).unwrap();
+    assert!(loaded.derive("tokens", 3).is_err());
+    loaded.open(&wrap_key).unwrap();
+    let other_key = Key::new_random().unwrap();
+    assert!(loaded.add_key(&other_key).unwrap());
+    assert!(loaded.remove_key(&wrap_key).unwrap());
+    assert_eq!(derived, loaded.derive("tokens", 3).unwrap().get_digest());
+    assert_ne!(derived, loaded.derive("tokens", 4).unwrap().get_digest());
+}
+
+#[test]
+fn test_replace_key() {
+    crate::init().unwrap();
+
+    let salt = Salt::default();
+    let old_key = new_password_key("old password", &salt);
+    let new_key = new_password_key("new password", &salt);
+    let other_key = Key::new_random().unwrap();
+
+    let mut keystore = KeyStore::new().unwrap();
+    let master_digest = keystore.get_master_key().unwrap().get_digest();
+    assert!(keystore.add_key(&old_key).unwrap());
+    assert!(keystore.add_key(&other_key).unwrap());
+
+    // Replace the key on an unopened copy; it should be opened along the way.
+    let mut keystore = KeyStore::load_slice(keystore.to_vec().unwrap().as_slice()).unwrap();
+    keystore.replace_key(&old_key, &new_key).unwrap();
+    assert!(keystore.is_open());
+    assert_eq!(2, keystore.iter_wrapped_keys().count());
+    let data = keystore.to_vec().unwrap();
+
+    let mut keystore = KeyStore::load_slice(data.as_slice()).unwrap();
+    assert!(keystore.open(&old_key).is_err());
+    keystore.open(&new_key).unwrap();
+    assert_eq!(
+        master_digest,
+        keystore.get_master_key().unwrap().get_digest()
+    );
+
+    let mut keystore = KeyStore::load_slice(data.as_slice()).unwrap();
+    keystore.open(&other_key).unwrap();
+
+    // Replacing a key with itself, or with a key which is already present,
+    // makes no sense.
+    assert!(keystore.replace_key(&new_key, &new_key).is_err());
+    assert!(keystore.replace_key(&new_key, &other_key).is_err());
+}
+
+#[test]
+fn test_replace_key_wrong_old_key() {
+    crate::init().unwrap();
+
+    let key = Key::new_random().unwrap();
+    let wrong_key = Key::new_random().unwrap();
+    let new_key = Key::new_random().unwrap();
+
+    let mut keystore = KeyStore::new().unwrap();
+    assert!(keystore.add_key(&key).unwrap());
+    let data = keystore.to_vec().unwrap();
+
+    let mut keystore = KeyStore::load_slice(data.as_slice()).unwrap();
+    match keystore.replace_key(&wrong_key, &new_key) {
+        Err(Error::InvalidArgument(_)) => {}
+        r => panic!("expected InvalidArgument, got {:?}", r),
+    }
+    assert!(!keystore.is_open());
+    assert_eq!(data, keystore.to_vec().unwrap());
+
+    keystore.open(&key).unwrap();
+    assert!(keystore.replace_key(&wrong_key, &new_key).is_err());
+    assert_eq!(data, keystore.to_vec().unwrap());
+}
+
+#[test]
+fn test_replace_key_carries_metadata() {
+    crate::init().unwrap();
+
+    let old_key = Key::new_random().unwrap();
+    let new_key = Key::new_random().unwrap();
+    let expires_at = UNIX_EPOCH + Duration::from_secs(4_000_000_000);
+
+    let mut keystore = KeyStore::new().unwrap();
+    keystore
+        .add_key_with_options(
+            &old_key,
+            KeyOptions {
+                label: Some("password".to_string()),
+                expires_at: Some(expires_at),
+                usage: KeyUsage::WrapOnly,
+            },
+        )
+        .unwrap();
+    assert_eq!(None, keystore.list_keys()[0].rotated_at);
+
+    let rotated_at = UNIX_EPOCH + Duration::from_secs(3_000_000_000);
+    keystore
+        .replace_key_with_clock(&old_key, &new_key, &MockClock::new(rotated_at))
+        .unwrap();
+    let keystore = KeyStore::load_slice(keystore.to_vec().unwrap().as_slice()).unwrap();
+    let keys = keystore.list_keys();
+    assert_eq!(1, keys.len());
+    assert_eq!(new_key.get_digest(), keys[0].wrapping_digest);
+    assert_eq!(Some("password".to_string()), keys[0].label);
+    assert_eq!(Some(expires_at), keys[0].expires_at);
+    assert_eq!(KeyUsage::WrapOnly, keys[0].usage);
+    assert_eq!(Some(rotated_at), keys[0].rotated_at);
+
+    // The metadata is still covered by the MAC.
+    let mut keystore = keystore;
+    keystore.open(&new_key).unwrap();
+}
+
+#[test]
+fn test_managed_replace_only_key() {
+    crate::init().unwrap();
+
+    let file = temp::File::new_file().unwrap();
+    let old_key = Key::new_random().unwrap();
+    let new_key = Key::new_random().unwrap();
+
+    {
+        let mut keystore = ManagedKeyStore::new(FileStorage::new(file.path()), false).unwrap();
+        assert!(keystore.add_key(&old_key).unwrap());
+        keystore.flush().unwrap();
+    }
+
+    {
+        let mut keystore = ManagedKeyStore::new(FileStorage::new(file.path()), false).unwrap();
+        keystore.replace_key(&old_key, &new_key).unwrap();
+        assert!(keystore.is_dirty());
+        keystore.flush().unwrap();
+    }
+
+    let mut keystore = ManagedKeyStore::new(FileStorage::new(file.path()), false).unwrap();
+    assert!(keystore.open(&old_key).is_err());
+    keystore.open(&new_key).unwrap();
+    assert_eq!(1, keystore.iter_wrapped_keys().count());
+}
+
+#[test]
+fn test_file_storage_failed_persist_keeps_original() {
+    use crate::testing::temp::{FaultyFs, Operation};
+    use std::io::ErrorKind;
+
+    crate::init().unwrap();
+
+    let ffs = FaultyFs::new("bdrck").unwrap();
+    let path = ffs.sub_path("keystore").unwrap();
+    let old_key = Key::new_random().unwrap();
+    let new_key = Key::new_random().unwrap();
+    {
+        let mut keystore = ManagedKeyStore::new(ffs.file_storage(&path), false).unwrap();
+        assert!(keystore.add_key(&old_key).unwrap());
+        keystore.flush().unwrap();
+    }
+
+    // The disk fills up partway through persisting the change.
+    ffs.fail_after_bytes(
+        Operation::Write,
+        ffs.bytes_written() + 16,
+        ErrorKind::StorageFull,
+    );
+    {
+        let mut keystore = ManagedKeyStore::new(ffs.file_storage(&path), false).unwrap();
+        keystore.replace_key(&old_key, &new_key).unwrap();
+        assert!(keystore.flush().is_err());
+        assert!(keystore.is_dirty());
+    }
+
+    // The previously persisted KeyStore is still intact.
+    let mut keystore = ManagedKeyStore::new(FileStorage::new(&path), false).unwrap();
+    assert!(keystore.open(&new_key).is_err());
+    keystore.open(&old_key).unwrap();
+}
+
+fn assert_read_only<T: std::fmt::Debug>(result: Result<T>) {
+    assert_eq!(ErrorCode::ReadOnlyKeyStore, result.unwrap_err().code());
+}
+
+#[test]
+fn test_read_only_keystore() {
+    crate::init().unwrap();
+
+    let file = temp::File::new_file().unwrap();
+    let key = Key::new_random().unwrap();
+    let other_key = Key::new_random().unwrap();
+    let (id, master_digest) = {
+        let mut keystore = ManagedKeyStore::new(FileStorage::new(file.path()), false).unwrap();
+        assert!(keystore.add_key(&key).unwrap());
+        keystore.flush().unwrap();
+        (
+            keystore.get_id(),
+            keystore.get_master_key().unwrap().get_digest(),
+        )
+    };
+    let original = fs::read(file.path()).unwrap();
+    let original_mtime = fs::metadata(file.path()).unwrap().modified().unwrap();
+
+    {
+        let mut keystore = ReadOnlyKeyStore::load_path(file.path()).unwrap();
+        assert!(keystore.is_read_only());
+        assert_eq!(id, keystore.get_id());
+        keystore.open(&key).unwrap();
+        assert!(keystore.had_integrity_mac());
+        assert_eq!(
+            master_digest,
+            keystore.get_master_key().unwrap().get_digest()
+        );
+        assert_eq!(1, keystore.iter_wrapped_keys().count());
+        assert_eq!(1, keystore.list_keys().len());
+    }
+
+    {
+        let mut keystore = DiskKeyStore::open_read_only(file.path()).unwrap();
+        keystore.open(&key).unwrap();
+        assert_read_only(keystore.add_key(&other_key));
+        assert_read_only(keystore.add_key_with_options(&other_key, KeyOptions::default()));
+        assert_read_only(keystore.remove_key(&key));
+        assert_read_only(keystore.replace_key(&key, &other_key));
+        assert_eq!(1, keystore.list_keys().len());
+    }
+
+    {
+        let mut keystore = ManagedKeyStore::open_read_only(FileStorage::new(file.path())).unwrap();
+        keystore.open(&key).unwrap();
+        assert_read_only(keystore.add_key(&other_key));
+        assert_read_only(keystore.remove_key(&key));
+        assert_read_only(keystore.replace_key(&key, &other_key));
+        assert!(!keystore.is_dirty());
+        keystore.flush().unwrap();
+    }
+
+    // Nothing above (including dropping the DiskKeyStore) wrote to the file.
+    assert_eq!(original, fs::read(file.path()).unwrap());
+    assert_eq!(
+        original_mtime,
+        fs::metadata(file.path()).unwrap().modified().unwrap()
+    );
+}
+
+#[test]
+fn test_read_only_keystore_missing() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let path = dir.sub_path("keystore").unwrap();
+    assert!(ReadOnlyKeyStore::load_path(&path).is_err());
+    assert!(DiskKeyStore::open_read_only(&path).is_err());
+    assert!(ManagedKeyStore::open_read_only(MemoryStorage::new()).is_err());
+    // Unlike `DiskKeyStore::new`, a read-only open never creates the file.
+    assert!(!path.exists());
+}
+
+#[test]
+fn test_read_only_keystore_without_write_permission() {
+    use std::os::unix::fs::PermissionsExt;
+
+    crate::init().unwrap();
+
+    let file = temp::File::new_file().unwrap();
+    let key = Key::new_random().unwrap();
+    {
+        let mut keystore = DiskKeyStore::new(file.path(), false).unwrap();
+        keystore.add_key(&key).unwrap();
+    }
+    fs::set_permissions(file.path(), fs::Permissions::from_mode(0o400)).unwrap();
+
+    let mut keystore = DiskKeyStore::open_read_only(file.path()).unwrap();
+    keystore.open(&key).unwrap();
+    drop(keystore);
+    let mut keystore = ReadOnlyKeyStore::load_path(file.path()).unwrap();
+    keystore.open(&key).unwrap();
+}
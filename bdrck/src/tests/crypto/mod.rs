@@ -12,11 +12,25 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+#[cfg(test)]
+mod asymmetric;
+#[cfg(test)]
+mod audit;
+#[cfg(test)]
+mod callback;
+#[cfg(test)]
+mod digest;
 #[cfg(test)]
 mod key;
 #[cfg(test)]
 mod keystore;
 #[cfg(test)]
+mod password;
+#[cfg(test)]
 mod secret;
 #[cfg(test)]
+mod stream;
+#[cfg(test)]
+mod totp;
+#[cfg(test)]
 mod wrap;
@@ -0,0 +1,185 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::crypto::password::*;
+use crate::crypto::secret::Secret;
+use crate::crypto::util::constant_time_eq;
+use std::ffi::CString;
+
+// Cheap parameters, so the tests run quickly. These should never be used for real passwords.
+const TEST_ARGON2ID_PARAMS: PasswordHashParams = PasswordHashParams::Argon2id {
+    memory_kib: 8,
+    iterations: 1,
+};
+const TEST_SCRYPT_PARAMS: PasswordHashParams = PasswordHashParams::Scrypt {
+    log_n: 10,
+    r: 8,
+    p: 1,
+};
+
+const GOLDEN_PASSWORD: &[u8] = b"correct horse battery staple";
+// This was computed with Python's (OpenSSL's) independent scrypt implementation.
+const GOLDEN_SCRYPT: &str =
+    "$scrypt$ln=10,r=8,p=1$AAECAwQFBgcICQoLDA0ODw$mp90zEQd5XGhjEv4WArVH4Z0XRSzkGWtJK2S/AXJlRU";
+// This is also checked against libsodium's own crypto_pwhash_str_verify below.
+const GOLDEN_ARGON2ID: &str =
+    "$argon2id$v=19$m=8,t=1,p=1$EkSEd7KvUbL5p8KUWeRnBg$G6KKRgsiAg/d+ZW0ai7Kjcs9/wBinxuMsKbw6Z2X8sk";
+
+fn secret(bytes: &[u8]) -> Secret {
+    let mut s = Secret::with_len(bytes.len()).unwrap();
+    unsafe { s.as_mut_slice() }.copy_from_slice(bytes);
+    s
+}
+
+#[test]
+fn test_hash_verify_round_trip() {
+    crate::init().unwrap();
+
+    let password = secret(b"hunter2");
+    let wrong = secret(b"hunter3");
+    for params in [TEST_ARGON2ID_PARAMS, TEST_SCRYPT_PARAMS] {
+        let encoded = hash(&password, params).unwrap();
+        assert!(verify(&password, &encoded).unwrap());
+        assert!(!verify(&wrong, &encoded).unwrap());
+        assert!(!verify(&Secret::new(), &encoded).unwrap());
+
+        // Each hash uses a fresh salt.
+        assert_ne!(encoded, hash(&password, params).unwrap());
+    }
+}
+
+#[test]
+fn test_golden_vectors() {
+    crate::init().unwrap();
+
+    let password = secret(GOLDEN_PASSWORD);
+    for golden in [GOLDEN_SCRYPT, GOLDEN_ARGON2ID] {
+        assert!(verify(&password, golden).unwrap());
+        assert!(!verify(&secret(b"incorrect horse battery staple"), golden).unwrap());
+    }
+
+    // Our Argon2id strings should be interchangeable with libsodium's own.
+    let encoded = CString::new(GOLDEN_ARGON2ID).unwrap();
+    assert_eq!(0, unsafe {
+        halite_sys::crypto_pwhash_str_verify(
+            encoded.as_ptr(),
+            GOLDEN_PASSWORD.as_ptr() as *const libc::c_char,
+            GOLDEN_PASSWORD.len() as libc::c_ulonglong,
+        )
+    });
+}
+
+#[test]
+fn test_needs_rehash() {
+    crate::init().unwrap();
+
+    let encoded = hash(&secret(b"hunter2"), TEST_ARGON2ID_PARAMS).unwrap();
+    assert!(!needs_rehash(&encoded, &TEST_ARGON2ID_PARAMS).unwrap());
+    assert!(needs_rehash(&encoded, &PasswordHashParams::default()).unwrap());
+    assert!(needs_rehash(
+        &encoded,
+        &PasswordHashParams::Argon2id {
+            memory_kib: 8,
+            iterations: 2,
+        }
+    )
+    .unwrap());
+    // Stronger-than-desired parameters don't need to be rehashed.
+    assert!(!needs_rehash(
+        &encoded,
+        &PasswordHashParams::Argon2id {
+            memory_kib: 8,
+            iterations: 1,
+        }
+    )
+    .unwrap());
+    // Switching algorithms always requires a rehash.
+    assert!(needs_rehash(&encoded, &TEST_SCRYPT_PARAMS).unwrap());
+
+    assert!(!needs_rehash(GOLDEN_SCRYPT, &TEST_SCRYPT_PARAMS).unwrap());
+    assert!(needs_rehash(
+        GOLDEN_SCRYPT,
+        &PasswordHashParams::Scrypt {
+            log_n: 14,
+            r: 8,
+            p: 1,
+        }
+    )
+    .unwrap());
+}
+
+#[test]
+fn test_malformed_encoded_hashes() {
+    crate::init().unwrap();
+
+    let password = secret(GOLDEN_PASSWORD);
+    let unknown = verify(
+        &password,
+        "$bcrypt$ln=10,r=8,p=1$AAECAwQFBgcICQoLDA0ODw$mp90zEQd5XGhjEv4WArVH4Z0XRSzkGWtJK2S/AXJlRU",
+    )
+    .unwrap_err();
+    assert!(unknown
+        .to_string()
+        .contains("unsupported password hash algorithm"));
+
+    for encoded in [
+        "",
+        "$",
+        "scrypt$ln=10,r=8,p=1$AAECAwQFBgcICQoLDA0ODw$mp90zEQd5XGhjEv4WArVH4Z0XRSzkGWtJK2S/AXJlRU",
+        // Missing hash.
+        "$scrypt$ln=10,r=8,p=1$AAECAwQFBgcICQoLDA0ODw",
+        // Trailing field.
+        "$scrypt$ln=10,r=8,p=1$AAECAwQFBgcICQoLDA0ODw$mp90zEQd5XGhjEv4WArVH4Z0XRSzkGWtJK2S/AXJlRU$",
+        // Parameters out of order, missing, or non-canonical.
+        "$scrypt$r=8,ln=10,p=1$AAECAwQFBgcICQoLDA0ODw$mp90zEQd5XGhjEv4WArVH4Z0XRSzkGWtJK2S/AXJlRU",
+        "$scrypt$ln=10,r=8$AAECAwQFBgcICQoLDA0ODw$mp90zEQd5XGhjEv4WArVH4Z0XRSzkGWtJK2S/AXJlRU",
+        "$scrypt$ln=010,r=8,p=1$AAECAwQFBgcICQoLDA0ODw$mp90zEQd5XGhjEv4WArVH4Z0XRSzkGWtJK2S/AXJlRU",
+        "$scrypt$ln=+10,r=8,p=1$AAECAwQFBgcICQoLDA0ODw$mp90zEQd5XGhjEv4WArVH4Z0XRSzkGWtJK2S/AXJlRU",
+        "$scrypt$ln=64,r=8,p=1$AAECAwQFBgcICQoLDA0ODw$mp90zEQd5XGhjEv4WArVH4Z0XRSzkGWtJK2S/AXJlRU",
+        // Invalid or padded base64.
+        "$scrypt$ln=10,r=8,p=1$AAECAwQFBgcICQoLDA0ODw==$mp90zEQd5XGhjEv4WArVH4Z0XRSzkGWtJK2S/AXJlRU",
+        "$scrypt$ln=10,r=8,p=1$AAECAwQFBgcICQoLDA0ODw$!!!",
+        // Salt too short.
+        "$scrypt$ln=10,r=8,p=1$AAECAw$mp90zEQd5XGhjEv4WArVH4Z0XRSzkGWtJK2S/AXJlRU",
+        // Unsupported Argon2 version.
+        "$argon2id$v=16$m=8,t=1,p=1$AAECAwQFBgcICQoLDA0ODw$mp90zEQd5XGhjEv4WArVH4Z0XRSzkGWtJK2S/AXJlRU",
+    ] {
+        assert!(verify(&password, encoded).is_err(), "{}", encoded);
+        assert!(
+            needs_rehash(encoded, &PasswordHashParams::default()).is_err(),
+            "{}",
+            encoded
+        );
+    }
+
+    assert!(hash(
+        &password,
+        PasswordHashParams::Argon2id {
+            memory_kib: 1,
+            iterations: 1,
+        }
+    )
+    .is_err());
+}
+
+#[test]
+fn test_constant_time_eq() {
+    crate::init().unwrap();
+
+    assert!(constant_time_eq(b"", b""));
+    assert!(constant_time_eq(b"abcdef", b"abcdef"));
+    assert!(!constant_time_eq(b"abcdef", b"abcdeg"));
+    assert!(!constant_time_eq(b"abcdef", b"bbcdef"));
+    assert!(!constant_time_eq(b"abcdef", b"abcde"));
+}
@@ -12,7 +12,16 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::crypto::digest::Digest;
 use crate::crypto::secret::*;
+use crate::error::*;
+use crate::testing::clock::MockClock;
+use crate::testing::temp;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 
 #[test]
 fn test_empty() {
@@ -87,3 +96,265 @@ fn test_resize() {
     assert_eq!(data.len(), s.len());
     assert_eq!(data.as_slice(), unsafe { s.as_slice() });
 }
+
+fn write_secret_file(path: &Path, contents: &[u8], mode: u32) {
+    fs::write(path, contents).unwrap();
+    fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+}
+
+#[test]
+fn test_from_file_permissions() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let path = dir.sub_path("token").unwrap();
+
+    for &mode in &[0o640, 0o604, 0o644] {
+        write_secret_file(&path, b"hunter2", mode);
+        assert!(Secret::from_file(&path).is_err());
+
+        let s = Secret::from_file_with_options(
+            &path,
+            &LoadOptions {
+                allow_insecure_permissions: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(b"hunter2", unsafe { s.as_slice() });
+    }
+
+    write_secret_file(&path, b"hunter2", 0o600);
+    let s = Secret::from_file(&path).unwrap();
+    assert_eq!(b"hunter2", unsafe { s.as_slice() });
+}
+
+#[test]
+fn test_from_file_strips_one_newline() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let path = dir.sub_path("token").unwrap();
+
+    for &(contents, expected) in &[
+        (&b"hunter2"[..], &b"hunter2"[..]),
+        (b"hunter2\n", b"hunter2"),
+        (b"hunter2\r\n", b"hunter2"),
+        (b"hunter2\n\n", b"hunter2\n"),
+        (b"\n", b""),
+    ] {
+        write_secret_file(&path, contents, 0o600);
+        let s = Secret::from_file(&path).unwrap();
+        assert_eq!(expected, unsafe { s.as_slice() });
+    }
+}
+
+#[test]
+fn test_from_env() {
+    crate::init().unwrap();
+
+    const VAR: &str = "BDRCK_TEST_FROM_ENV";
+
+    std::env::set_var(VAR, "hunter2");
+    let s = Secret::from_env_with_options(
+        VAR,
+        &LoadOptions {
+            keep_env: true,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    assert_eq!(b"hunter2", unsafe { s.as_slice() });
+    assert_eq!("hunter2", std::env::var(VAR).unwrap());
+
+    // By default, the variable should be removed after being read.
+    let s = Secret::from_env(VAR).unwrap();
+    assert_eq!(b"hunter2", unsafe { s.as_slice() });
+    assert!(std::env::var_os(VAR).is_none());
+    assert!(Secret::from_env(VAR).is_err());
+}
+
+#[test]
+fn test_from_file_or_env() {
+    crate::init().unwrap();
+
+    const FILE_VAR: &str = "BDRCK_TEST_FROM_FILE_OR_ENV_FILE";
+    const ENV_VAR: &str = "BDRCK_TEST_FROM_FILE_OR_ENV";
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let path = dir.sub_path("token").unwrap();
+    write_secret_file(&path, b"from file\n", 0o600);
+
+    // With neither variable set, this is an error.
+    assert!(Secret::from_file_or_env(FILE_VAR, ENV_VAR).is_err());
+
+    // With only the plain variable set, it's used.
+    std::env::set_var(ENV_VAR, "from env");
+    let s = Secret::from_file_or_env(FILE_VAR, ENV_VAR).unwrap();
+    assert_eq!(b"from env", unsafe { s.as_slice() });
+
+    // If both are set, the file takes precedence.
+    std::env::set_var(ENV_VAR, "from env");
+    std::env::set_var(FILE_VAR, &path);
+    let s = Secret::from_file_or_env(FILE_VAR, ENV_VAR).unwrap();
+    assert_eq!(b"from file", unsafe { s.as_slice() });
+    std::env::remove_var(FILE_VAR);
+    std::env::remove_var(ENV_VAR);
+}
+
+fn new_test_cache(options: SecretCacheOptions) -> (Arc<MockClock>, SecretCache) {
+    let clock = Arc::new(MockClock::default());
+    let cache = SecretCache::with_clock(options, clock.clone());
+    (clock, cache)
+}
+
+fn secret_str(secret: &Secret) -> &str {
+    unsafe { std::str::from_utf8(secret.as_slice()).unwrap() }
+}
+
+#[test]
+fn test_secret_cache_expiry() {
+    crate::init().unwrap();
+
+    let (clock, cache) = new_test_cache(SecretCacheOptions::default());
+    let secret = Secret::from_slice(b"hunter2").unwrap();
+    let fingerprint = Digest::from_secret(&secret).fingerprint();
+    cache.put("passphrase", secret, Duration::from_secs(60));
+
+    clock.advance(Duration::from_secs(59));
+    assert_eq!(
+        "hunter2",
+        secret_str(&cache.get("passphrase").unwrap().unwrap())
+    );
+    assert_eq!(Some(fingerprint), cache.fingerprint("passphrase"));
+    // Without refresh_on_get, using the secret doesn't extend its TTL.
+    clock.advance(Duration::from_secs(1));
+    assert!(cache.get("passphrase").unwrap().is_none());
+    assert!(cache.fingerprint("passphrase").is_none());
+    assert!(cache.is_empty());
+}
+
+#[test]
+fn test_secret_cache_refresh_on_get() {
+    crate::init().unwrap();
+
+    let (clock, cache) = new_test_cache(SecretCacheOptions {
+        refresh_on_get: true,
+        ..Default::default()
+    });
+    cache.put(
+        "passphrase",
+        Secret::from_slice(b"hunter2").unwrap(),
+        Duration::from_secs(60),
+    );
+    cache.put(
+        "other",
+        Secret::from_slice(b"other").unwrap(),
+        Duration::from_secs(60),
+    );
+    for _ in 0..3 {
+        clock.advance(Duration::from_secs(45));
+        assert!(cache.get("passphrase").unwrap().is_some());
+    }
+    assert!(!cache.contains("other"));
+
+    // Expired entries are removed (and zeroed) eagerly too.
+    cache.put(
+        "other",
+        Secret::from_slice(b"other").unwrap(),
+        Duration::from_secs(1),
+    );
+    clock.advance(Duration::from_secs(1));
+    assert_eq!(1, cache.clear_expired());
+    assert_eq!(1, cache.len());
+    cache.clear();
+    assert!(cache.is_empty());
+}
+
+#[test]
+fn test_secret_cache_eviction_order() {
+    crate::init().unwrap();
+
+    let (_clock, cache) = new_test_cache(SecretCacheOptions {
+        max_entries: 2,
+        ..Default::default()
+    });
+    let ttl = Duration::from_secs(60);
+    cache.put("a", Secret::from_slice(b"a").unwrap(), ttl);
+    cache.put("b", Secret::from_slice(b"b").unwrap(), ttl);
+    // Using "a" makes "b" the least recently used entry.
+    assert!(cache.get("a").unwrap().is_some());
+    cache.put("c", Secret::from_slice(b"c").unwrap(), ttl);
+    assert!(cache.contains("a"));
+    assert!(!cache.contains("b"));
+    assert!(cache.contains("c"));
+
+    // Replacing an existing entry doesn't evict anything.
+    cache.put("c", Secret::from_slice(b"d").unwrap(), ttl);
+    assert_eq!(2, cache.len());
+    assert_eq!("d", secret_str(&cache.get("c").unwrap().unwrap()));
+    cache.put("e", Secret::from_slice(b"e").unwrap(), ttl);
+    assert!(!cache.contains("a"));
+}
+
+#[test]
+fn test_secret_cache_get_or_insert_with() {
+    crate::init().unwrap();
+
+    let (clock, cache) = new_test_cache(SecretCacheOptions::default());
+    let prompts = std::cell::Cell::new(0);
+    let prompt = || {
+        prompts.set(prompts.get() + 1);
+        Secret::from_slice(b"hunter2")
+    };
+    let ttl = Duration::from_secs(60);
+
+    for _ in 0..3 {
+        let secret = cache.get_or_insert_with("passphrase", ttl, prompt).unwrap();
+        assert_eq!("hunter2", secret_str(&secret));
+    }
+    assert_eq!(1, prompts.get());
+
+    clock.advance(ttl);
+    cache.get_or_insert_with("passphrase", ttl, prompt).unwrap();
+    assert_eq!(2, prompts.get());
+
+    // A failed prompt isn't cached.
+    cache.clear();
+    assert!(cache
+        .get_or_insert_with("passphrase", ttl, || Err(Error::Crypto(
+            "cancelled".to_string()
+        )))
+        .is_err());
+    assert!(!cache.contains("passphrase"));
+}
+
+#[test]
+fn test_secret_cache_sweeper() {
+    crate::init().unwrap();
+
+    let clock = Arc::new(MockClock::default());
+    let cache = Arc::new(SecretCache::with_clock(
+        SecretCacheOptions::default(),
+        clock.clone(),
+    ));
+    cache.put(
+        "passphrase",
+        Secret::from_slice(b"hunter2").unwrap(),
+        Duration::from_secs(1),
+    );
+    let sweeper = cache.start_sweeper(Duration::from_millis(1));
+    clock.advance(Duration::from_secs(1));
+
+    // Any access through the API would clear expired entries itself, so just
+    // give the sweeper plenty of time to run, and check there's nothing left
+    // for us to clear.
+    std::thread::sleep(Duration::from_millis(200));
+    assert_eq!(0, cache.clear_expired());
+    drop(sweeper);
+
+    // The sweeper doesn't keep the cache alive.
+    let sweeper = cache.start_sweeper(Duration::from_millis(1));
+    drop(cache);
+    drop(sweeper);
+}
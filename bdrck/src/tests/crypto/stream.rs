@@ -0,0 +1,449 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::crypto::key::Key;
+use crate::crypto::stream::*;
+use crate::crypto::util::randombytes_into;
+use crate::error::Result;
+use crate::testing::prop;
+use std::io::{Read, Write};
+
+/// The offset of the compression id within a stream's header.
+const COMPRESSION_ID_OFFSET: usize = 9;
+
+fn random_data(len: usize) -> Vec<u8> {
+    let mut data = vec![0; len];
+    randombytes_into(data.as_mut_slice());
+    data
+}
+
+fn compressible_data(len: usize) -> Vec<u8> {
+    b"the quick brown fox jumps over the lazy dog\n"
+        .iter()
+        .cycle()
+        .take(len)
+        .cloned()
+        .collect()
+}
+
+fn seal(key: &Key, data: &[u8], compression: Compression) -> Vec<u8> {
+    let mut writer = SealWriter::new(key, Vec::new(), compression).unwrap();
+    writer.write_all(data).unwrap();
+    writer.finish().unwrap()
+}
+
+fn open(key: &Key, sealed: &[u8]) -> Result<Vec<u8>> {
+    let mut reader = OpenReader::new(key, sealed)?;
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+#[test]
+fn test_seal_round_trip_without_compression() {
+    crate::init().unwrap();
+
+    let key = Key::new_random().unwrap();
+    for len in [
+        0,
+        1,
+        CHUNK_BYTES - 1,
+        CHUNK_BYTES,
+        CHUNK_BYTES + 1,
+        3 * CHUNK_BYTES + 7,
+    ] {
+        let data = random_data(len);
+        let sealed = seal(&key, data.as_slice(), Compression::None);
+        assert_eq!(data, open(&key, sealed.as_slice()).unwrap());
+    }
+
+    // Opening with the wrong key must fail.
+    let sealed = seal(&key, b"secret", Compression::None);
+    assert!(open(&Key::new_random().unwrap(), sealed.as_slice()).is_err());
+
+    prop::run(
+        32,
+        true,
+        prop::gen::vec(prop::gen::int(..=u8::MAX), 0..3 * CHUNK_BYTES),
+        |data| {
+            let sealed = seal(&key, data.as_slice(), Compression::None);
+            match open(&key, sealed.as_slice()).map_err(|e| e.to_string())? == *data {
+                true => Ok(()),
+                false => Err("sealed data didn't round trip".to_string()),
+            }
+        },
+    );
+}
+
+#[cfg(feature = "flate2")]
+#[test]
+fn test_seal_round_trip_with_deflate() {
+    crate::init().unwrap();
+
+    let key = Key::new_random().unwrap();
+    for data in [
+        Vec::new(),
+        random_data(3 * CHUNK_BYTES + 7),
+        compressible_data(4 * CHUNK_BYTES),
+    ] {
+        let sealed = seal(&key, data.as_slice(), Compression::Deflate);
+        let mut reader = OpenReader::new(&key, sealed.as_slice()).unwrap();
+        assert_eq!(Compression::Deflate, reader.compression());
+        let mut opened = Vec::new();
+        reader.read_to_end(&mut opened).unwrap();
+        assert_eq!(data, opened);
+    }
+}
+
+#[cfg(feature = "flate2")]
+#[test]
+fn test_deflate_compresses_compressible_input() {
+    crate::init().unwrap();
+
+    let key = Key::new_random().unwrap();
+    let data = compressible_data(1024 * 1024);
+    let plain = seal(&key, data.as_slice(), Compression::None);
+    let compressed = seal(&key, data.as_slice(), Compression::Deflate);
+    assert!(plain.len() > data.len());
+    assert!(
+        compressed.len() * 10 < data.len(),
+        "expected at least 10x compression, got {} -> {} bytes",
+        data.len(),
+        compressed.len()
+    );
+}
+
+#[cfg(not(feature = "flate2"))]
+#[test]
+fn test_deflate_requires_feature() {
+    crate::init().unwrap();
+
+    let key = Key::new_random().unwrap();
+    assert!(SealWriter::new(&key, Vec::new(), Compression::Deflate).is_err());
+}
+
+#[test]
+fn test_compression_header_tampering_is_detected() {
+    crate::init().unwrap();
+
+    let key = Key::new_random().unwrap();
+    let data = compressible_data(CHUNK_BYTES * 2);
+
+    // Claiming the plaintext is compressed must not get past authentication
+    // (or, without DEFLATE support, must be rejected outright).
+    let mut sealed = seal(&key, data.as_slice(), Compression::None);
+    sealed[COMPRESSION_ID_OFFSET] = 1;
+    assert!(open(&key, sealed.as_slice()).is_err());
+
+    // Unknown compression ids are refused.
+    sealed[COMPRESSION_ID_OFFSET] = 0xff;
+    assert!(OpenReader::new(&key, sealed.as_slice()).is_err());
+
+    // Restoring the original header makes the stream valid again.
+    sealed[COMPRESSION_ID_OFFSET] = 0;
+    assert_eq!(data, open(&key, sealed.as_slice()).unwrap());
+}
+
+#[test]
+fn test_truncation_and_trailing_data_are_detected() {
+    crate::init().unwrap();
+
+    let key = Key::new_random().unwrap();
+    let sealed = seal(
+        &key,
+        random_data(2 * CHUNK_BYTES + 1).as_slice(),
+        Compression::None,
+    );
+
+    // Dropping whole chunks, or part of one, must fail.
+    for len in [sealed.len() - 1, sealed.len() - 100, sealed.len() / 2, 40] {
+        assert!(open(&key, &sealed[..len]).is_err());
+    }
+
+    // A stream which was never finished is missing its final chunk.
+    let mut unfinished = Vec::new();
+    let mut writer = EncryptWriter::new(&key, &mut unfinished).unwrap();
+    writer
+        .write_all(random_data(2 * CHUNK_BYTES).as_slice())
+        .unwrap();
+    drop(writer);
+    assert!(open(&key, unfinished.as_slice()).is_err());
+
+    let mut extended = sealed.clone();
+    extended.push(0);
+    assert!(open(&key, extended.as_slice()).is_err());
+}
+
+#[test]
+fn test_uncompressed_interop_with_plain_stream() {
+    crate::init().unwrap();
+
+    let key = Key::new_random().unwrap();
+    let data = random_data(CHUNK_BYTES + 123);
+
+    let mut writer = EncryptWriter::new(&key, Vec::new()).unwrap();
+    writer.write_all(data.as_slice()).unwrap();
+    let encrypted = writer.finish().unwrap();
+    assert_eq!(data, open(&key, encrypted.as_slice()).unwrap());
+
+    let sealed = seal(&key, data.as_slice(), Compression::None);
+    assert_eq!(encrypted.len(), sealed.len());
+    let mut reader = DecryptReader::new(&key, sealed.as_slice()).unwrap();
+    let mut decrypted = Vec::new();
+    reader.read_to_end(&mut decrypted).unwrap();
+    assert_eq!(data, decrypted);
+}
+
+#[cfg(feature = "fs")]
+#[test]
+fn test_seal_and_open_file() {
+    use crate::testing::temp;
+
+    crate::init().unwrap();
+
+    let key = Key::new_random().unwrap();
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let src = dir.sub_path("plain").unwrap();
+    let sealed = dir.sub_path("sealed").unwrap();
+    let opened = dir.sub_path("opened").unwrap();
+    let data = random_data(CHUNK_BYTES * 2 + 5);
+    std::fs::write(src.as_path(), data.as_slice()).unwrap();
+
+    seal_file(&key, src.as_path(), sealed.as_path(), Compression::None).unwrap();
+    assert_ne!(data, std::fs::read(sealed.as_path()).unwrap());
+    open_file(&key, sealed.as_path(), opened.as_path()).unwrap();
+    assert_eq!(data, std::fs::read(opened.as_path()).unwrap());
+
+    // A failed open must leave the destination untouched.
+    std::fs::write(opened.as_path(), b"untouched").unwrap();
+    let wrong_key = Key::new_random().unwrap();
+    assert!(open_file(&wrong_key, sealed.as_path(), opened.as_path()).is_err());
+    assert_eq!(
+        b"untouched".to_vec(),
+        std::fs::read(opened.as_path()).unwrap()
+    );
+}
+
+#[test]
+fn test_resume_is_byte_identical() {
+    crate::init().unwrap();
+
+    let key = Key::new_random().unwrap();
+    let data = random_data(CHUNK_BYTES * 3 + 100);
+
+    // Export a state part way through an otherwise uninterrupted run.
+    let mut writer = EncryptWriter::new(&key, Vec::new()).unwrap();
+    writer.write_all(&data[..CHUNK_BYTES * 2 + 7]).unwrap();
+    let state = writer.resume_state(&key).unwrap();
+    assert_eq!(2, state.chunks());
+    assert_eq!(state.ciphertext_offset(), writer.get_ref().len() as u64);
+    writer.write_all(&data[CHUNK_BYTES * 2 + 7..]).unwrap();
+    let uninterrupted = writer.finish().unwrap();
+
+    // Resume from that state, as if the process had been killed there.
+    let partial = uninterrupted[..state.ciphertext_offset() as usize].to_vec();
+    let mut writer = EncryptWriter::resume(&key, &state, partial).unwrap();
+    writer
+        .write_all(&data[state.plaintext_offset() as usize..])
+        .unwrap();
+    let resumed = writer.finish().unwrap();
+    assert_eq!(uninterrupted, resumed);
+
+    let mut reader = DecryptReader::new(&key, resumed.as_slice()).unwrap();
+    let mut decrypted = Vec::new();
+    reader.read_to_end(&mut decrypted).unwrap();
+    assert_eq!(data, decrypted);
+}
+
+#[test]
+fn test_resume_rejects_mismatched_state() {
+    crate::init().unwrap();
+
+    let key = Key::new_random().unwrap();
+    let mut writer = EncryptWriter::new(&key, Vec::new()).unwrap();
+    writer.write_all(&random_data(CHUNK_BYTES + 1)).unwrap();
+    let state = writer.resume_state(&key).unwrap();
+
+    let wrong_key = Key::new_random().unwrap();
+    assert!(EncryptWriter::resume(&wrong_key, &state, Vec::new()).is_err());
+
+    // The chunk count is authenticated along with the secretstream state, so
+    // a state can't be used to resume from the wrong position.
+    let mut tampered = serde_json::to_value(&state).unwrap();
+    tampered["chunks"] = serde_json::json!(2);
+    let tampered: ResumeState = serde_json::from_value(tampered).unwrap();
+    assert_eq!(CHUNK_BYTES as u64 * 2, tampered.plaintext_offset());
+    assert!(EncryptWriter::resume(&key, &tampered, Vec::new()).is_err());
+
+    assert!(EncryptWriter::resume(&key, &state, Vec::new()).is_ok());
+}
+
+#[cfg(feature = "fs")]
+#[test]
+fn test_seal_file_resumable() {
+    use crate::error::Error;
+    use crate::testing::temp;
+
+    crate::init().unwrap();
+
+    let key = Key::new_random().unwrap();
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let src = dir.sub_path("plain").unwrap();
+    let sealed = dir.sub_path("sealed").unwrap();
+    let state = dir.sub_path("sealed.state").unwrap();
+    let data = random_data(CHUNK_BYTES * 10 + 5);
+    std::fs::write(src.as_path(), data.as_slice()).unwrap();
+
+    // Interrupt the first run at its second checkpoint.
+    let mut checkpoints = Vec::new();
+    let mut interrupt = |s: &ResumeState| {
+        checkpoints.push(s.chunks());
+        match checkpoints.len() {
+            2 => Err(Error::Internal("interrupted".to_string())),
+            _ => Ok(()),
+        }
+    };
+    let options = ResumeOptions {
+        checkpoint_chunks: 3,
+        on_checkpoint: Some(&mut interrupt),
+    };
+    assert!(seal_file_resumable_with(
+        &key,
+        src.as_path(),
+        sealed.as_path(),
+        state.as_path(),
+        options
+    )
+    .is_err());
+    assert_eq!(vec![3, 6], checkpoints);
+    assert!(state.as_path().exists());
+
+    // The second run picks up where the first left off.
+    let mut checkpoints = Vec::new();
+    let mut record = |s: &ResumeState| {
+        checkpoints.push(s.chunks());
+        Ok(())
+    };
+    let options = ResumeOptions {
+        checkpoint_chunks: 3,
+        on_checkpoint: Some(&mut record),
+    };
+    seal_file_resumable_with(
+        &key,
+        src.as_path(),
+        sealed.as_path(),
+        state.as_path(),
+        options,
+    )
+    .unwrap();
+    assert_eq!(vec![9], checkpoints);
+    assert!(!state.as_path().exists());
+
+    let opened = dir.sub_path("opened").unwrap();
+    open_file(&key, sealed.as_path(), opened.as_path()).unwrap();
+    assert_eq!(data, std::fs::read(opened.as_path()).unwrap());
+
+    // Tampering with a chunk written before the interruption is still
+    // detected.
+    let mut contents = std::fs::read(sealed.as_path()).unwrap();
+    contents[100] ^= 1;
+    std::fs::write(sealed.as_path(), contents).unwrap();
+    assert!(open_file(&key, sealed.as_path(), opened.as_path()).is_err());
+}
+
+#[cfg(feature = "fs")]
+#[test]
+fn test_seal_file_resumable_stale_state() {
+    use crate::error::Error;
+    use crate::testing::temp;
+
+    crate::init().unwrap();
+
+    let key = Key::new_random().unwrap();
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let src = dir.sub_path("plain").unwrap();
+    let sealed = dir.sub_path("sealed").unwrap();
+    let state = dir.sub_path("sealed.state").unwrap();
+    std::fs::write(src.as_path(), random_data(CHUNK_BYTES * 4)).unwrap();
+
+    let interrupted_run = || {
+        let mut interrupt = |_: &ResumeState| Err(Error::Internal("interrupted".to_string()));
+        let options = ResumeOptions {
+            checkpoint_chunks: 2,
+            on_checkpoint: Some(&mut interrupt),
+        };
+        let _ = std::fs::remove_file(state.as_path());
+        assert!(seal_file_resumable_with(
+            &key,
+            src.as_path(),
+            sealed.as_path(),
+            state.as_path(),
+            options
+        )
+        .is_err());
+    };
+
+    // Modified partial output.
+    interrupted_run();
+    let mut contents = std::fs::read(sealed.as_path()).unwrap();
+    contents[50] ^= 1;
+    std::fs::write(sealed.as_path(), contents).unwrap();
+    match seal_file_resumable(&key, src.as_path(), sealed.as_path(), state.as_path()) {
+        Err(Error::InvalidArgument(_)) => {}
+        other => panic!("expected a stale state error, got {:?}", other),
+    }
+
+    // Missing partial output.
+    interrupted_run();
+    std::fs::remove_file(sealed.as_path()).unwrap();
+    assert!(seal_file_resumable(&key, src.as_path(), sealed.as_path(), state.as_path()).is_err());
+
+    // A source which has changed.
+    interrupted_run();
+    std::fs::write(src.as_path(), random_data(CHUNK_BYTES * 5)).unwrap();
+    assert!(seal_file_resumable(&key, src.as_path(), sealed.as_path(), state.as_path()).is_err());
+
+    // A source whose already-encrypted plaintext was modified in place, with
+    // its length, inode, and modification time all unchanged.
+    std::fs::write(src.as_path(), random_data(CHUNK_BYTES * 4)).unwrap();
+    interrupted_run();
+    {
+        use std::io::{Seek, SeekFrom, Write};
+        let modified = std::fs::metadata(src.as_path())
+            .unwrap()
+            .modified()
+            .unwrap();
+        let mut f = std::fs::OpenOptions::new()
+            .write(true)
+            .open(src.as_path())
+            .unwrap();
+        f.seek(SeekFrom::Start(10)).unwrap();
+        f.write_all(&random_data(16)).unwrap();
+        f.set_modified(modified).unwrap();
+    }
+    match seal_file_resumable(&key, src.as_path(), sealed.as_path(), state.as_path()) {
+        Err(Error::InvalidArgument(_)) => {}
+        other => panic!("expected a stale state error, got {:?}", other),
+    }
+
+    // The wrong key.
+    std::fs::write(src.as_path(), random_data(CHUNK_BYTES * 4)).unwrap();
+    interrupted_run();
+    let wrong_key = Key::new_random().unwrap();
+    assert!(
+        seal_file_resumable(&wrong_key, src.as_path(), sealed.as_path(), state.as_path()).is_err()
+    );
+
+    // Since the state file is left alone, the real key can still resume.
+    seal_file_resumable(&key, src.as_path(), sealed.as_path(), state.as_path()).unwrap();
+}
@@ -0,0 +1,226 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::crypto::totp::*;
+use crate::error::*;
+use crate::testing::clock::MockClock;
+use data_encoding::BASE32;
+use std::time::{Duration, SystemTime};
+
+fn at(secs: u64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+fn rfc_key(algorithm: TotpAlgorithm) -> TotpKey {
+    // RFC 6238's reference implementation repeats this seed to the natural
+    // key length of each algorithm.
+    let len = match algorithm {
+        TotpAlgorithm::Sha1 => 20,
+        TotpAlgorithm::Sha256 => 32,
+        TotpAlgorithm::Sha512 => 64,
+    };
+    let seed: Vec<u8> = b"1234567890".iter().copied().cycle().take(len).collect();
+    TotpKey::from_base32(&BASE32.encode(&seed)).unwrap()
+}
+
+#[test]
+fn test_rfc6238_vectors() {
+    crate::init().unwrap();
+
+    // From RFC 6238, Appendix B.
+    let vectors: &[(u64, &str, &str, &str)] = &[
+        (59, "94287082", "46119246", "90693936"),
+        (1111111109, "07081804", "68084774", "25091201"),
+        (1111111111, "14050471", "67062674", "99943326"),
+        (1234567890, "89005924", "91819424", "93441116"),
+        (2000000000, "69279037", "90698825", "38618901"),
+        (20000000000, "65353130", "77737706", "47863826"),
+    ];
+    for algorithm in [
+        TotpAlgorithm::Sha1,
+        TotpAlgorithm::Sha256,
+        TotpAlgorithm::Sha512,
+    ] {
+        let key = rfc_key(algorithm);
+        let params = TotpParams {
+            digits: 8,
+            algorithm,
+            ..Default::default()
+        };
+        for &(time, sha1, sha256, sha512) in vectors {
+            let expected = match algorithm {
+                TotpAlgorithm::Sha1 => sha1,
+                TotpAlgorithm::Sha256 => sha256,
+                TotpAlgorithm::Sha512 => sha512,
+            };
+            assert_eq!(
+                expected,
+                key.code_at(at(time), &params).unwrap(),
+                "{} at {}",
+                algorithm,
+                time
+            );
+        }
+    }
+}
+
+#[test]
+fn test_current_code_and_skew() {
+    crate::init().unwrap();
+
+    let key = rfc_key(TotpAlgorithm::Sha1);
+    let params = TotpParams::default();
+    let clock = MockClock::new(at(1111111111));
+    let code = key.current_code(&clock, &params).unwrap();
+    assert_eq!("050471", code);
+    assert!(key.verify(&code, &clock, &params, 0).unwrap());
+
+    // 1111111111 is 1 second into its time step; move to the last second of
+    // the step after next.
+    clock.advance(Duration::from_secs(88));
+    assert!(!key.verify(&code, &clock, &params, 0).unwrap());
+    assert!(!key.verify(&code, &clock, &params, 1).unwrap());
+    assert!(key.verify(&code, &clock, &params, 2).unwrap());
+
+    // The window extends the same distance into the future, too.
+    clock.set(at(1111111111 - 60));
+    assert!(!key.verify(&code, &clock, &params, 1).unwrap());
+    assert!(key.verify(&code, &clock, &params, 2).unwrap());
+
+    // Near the epoch, the window is simply cut off.
+    let first = key.code_at(at(0), &params).unwrap();
+    clock.set(at(0));
+    assert!(key.verify(&first, &clock, &params, 5).unwrap());
+}
+
+#[test]
+fn test_verify_rejects_malformed_codes() {
+    crate::init().unwrap();
+
+    let key = rfc_key(TotpAlgorithm::Sha1);
+    let params = TotpParams::default();
+    let clock = MockClock::new(at(59));
+    let code = key.current_code(&clock, &params).unwrap();
+    assert_eq!("287082", code);
+
+    // Codes with the wrong length (including the full 8-digit code, which
+    // ends with the right 6 digits) are rejected, not truncated or padded.
+    for candidate in ["", "28708", "2870820", "94287082", " 287082", "287082\n"] {
+        assert!(!key.verify(candidate, &clock, &params, 1).unwrap());
+    }
+
+    let invalid = TotpParams {
+        digits: 10,
+        ..Default::default()
+    };
+    assert_eq!(
+        ErrorCode::InvalidArgument,
+        key.verify(&code, &clock, &invalid, 1).unwrap_err().code()
+    );
+    let invalid = TotpParams {
+        period: Duration::from_millis(1500),
+        ..Default::default()
+    };
+    assert_eq!(
+        ErrorCode::InvalidArgument,
+        key.code_at(at(59), &invalid).unwrap_err().code()
+    );
+}
+
+#[test]
+fn test_base32_tolerance() {
+    crate::init().unwrap();
+
+    let canonical = TotpKey::from_base32("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ").unwrap();
+    let params = TotpParams::default();
+    let expected = canonical.code_at(at(59), &params).unwrap();
+    for encoded in [
+        "gezdgnbvgy3tqojqgezdgnbvgy3tqojq",
+        "GEZD GNBV GY3T QOJQ GEZD GNBV GY3T QOJQ",
+        "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ======",
+    ] {
+        let key = TotpKey::from_base32(encoded).unwrap();
+        assert_eq!(expected, key.code_at(at(59), &params).unwrap());
+    }
+    // Shorter keys with padding decode too.
+    assert_eq!(
+        "JBSWY3DPEE",
+        TotpKey::from_base32("jbswy3dpee======")
+            .unwrap()
+            .to_base32()
+    );
+
+    for invalid in ["", "====", "GEZDGNBV1", "GEZDGNBVG"] {
+        assert_eq!(
+            ErrorCode::InvalidArgument,
+            TotpKey::from_base32(invalid).err().unwrap().code()
+        );
+    }
+}
+
+#[test]
+fn test_otpauth_uri_round_trip() {
+    crate::init().unwrap();
+
+    let parsed = TotpUri::parse(
+        "otpauth://totp/Example%20Co:alice%40example.com?secret=JBSWY3DPEHPK3PXP&issuer=Example%20Co&algorithm=SHA256&digits=8&period=60&image=ignored",
+    )
+    .unwrap();
+    assert_eq!("Example Co:alice@example.com", parsed.label);
+    assert_eq!(Some("Example Co"), parsed.issuer.as_deref());
+    assert_eq!("JBSWY3DPEHPK3PXP", parsed.key.to_base32());
+    assert_eq!(
+        TotpParams {
+            digits: 8,
+            period: Duration::from_secs(60),
+            algorithm: TotpAlgorithm::Sha256,
+        },
+        parsed.params
+    );
+
+    let uri = parsed.to_uri();
+    assert_eq!(
+        "otpauth://totp/Example%20Co%3Aalice%40example.com?secret=JBSWY3DPEHPK3PXP&issuer=Example%20Co&algorithm=SHA256&digits=8&period=60",
+        uri
+    );
+    let reparsed = TotpUri::parse(&uri).unwrap();
+    assert_eq!(parsed.label, reparsed.label);
+    assert_eq!(parsed.issuer, reparsed.issuer);
+    assert_eq!(parsed.params, reparsed.params);
+    assert_eq!(
+        parsed.key.code_at(at(59), &parsed.params).unwrap(),
+        reparsed.key.code_at(at(59), &reparsed.params).unwrap()
+    );
+
+    // Missing parameters take their defaults, and the issuer can come from
+    // the label.
+    let minimal = TotpUri::parse("otpauth://totp/Example:bob?secret=JBSWY3DPEHPK3PXP").unwrap();
+    assert_eq!(Some("Example"), minimal.issuer.as_deref());
+    assert_eq!(TotpParams::default(), minimal.params);
+
+    for invalid in [
+        "otpauth://hotp/Example:bob?secret=JBSWY3DPEHPK3PXP&counter=1",
+        "otpauth://totp/Example:bob",
+        "otpauth://totp/Example:bob?secret=JBSWY3DPEHPK3PXP&algorithm=MD5",
+        "otpauth://totp/Example:bob?secret=JBSWY3DPEHPK3PXP&digits=4",
+        "otpauth://totp/Example:bob?secret=JBSWY3DPEHPK3PXP&period=0",
+        "otpauth://totp/Example%2:bob?secret=JBSWY3DPEHPK3PXP",
+    ] {
+        assert_eq!(
+            ErrorCode::InvalidArgument,
+            TotpUri::parse(invalid).err().unwrap().code(),
+            "{}",
+            invalid
+        );
+    }
+}
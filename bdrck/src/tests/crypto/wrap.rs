@@ -41,3 +41,33 @@ fn test_unwrapping_with_wrong_key_fails() {
     let wrapped = WrappedKey::wrap(&a, &b).unwrap();
     assert!(wrapped.unwrap::<Key, Key>(&wrong_key).is_err());
 }
+
+#[test]
+fn test_wrapping_roundtrip_with_aad() {
+    crate::init().unwrap();
+
+    let a = Key::new_random().unwrap();
+    let b = Key::new_random().unwrap();
+
+    let wrapped = WrappedKey::wrap_with_aad(&a, &b, b"foobar").unwrap();
+    assert!(wrapped.has_aad());
+    let unwrapped: Key = wrapped.unwrap_with_aad(&b, b"foobar").unwrap();
+    assert_eq!(a.get_digest(), unwrapped.get_digest());
+}
+
+#[test]
+fn test_unwrapping_with_wrong_aad_fails() {
+    crate::init().unwrap();
+
+    let a = Key::new_random().unwrap();
+    let b = Key::new_random().unwrap();
+
+    let wrapped = WrappedKey::wrap_with_aad(&a, &b, b"foobar").unwrap();
+    assert!(wrapped.unwrap_with_aad::<Key, Key>(&b, b"barbaz").is_err());
+    assert!(wrapped.unwrap_with_aad::<Key, Key>(&b, b"").is_err());
+    assert!(wrapped.unwrap::<Key, Key>(&b).is_err());
+
+    let wrapped = WrappedKey::wrap(&a, &b).unwrap();
+    assert!(!wrapped.has_aad());
+    assert!(wrapped.unwrap_with_aad::<Key, Key>(&b, b"foobar").is_err());
+}
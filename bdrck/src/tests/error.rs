@@ -0,0 +1,390 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::error::*;
+use crate::testing::clock::{Clock, MockClock};
+use serde_json::json;
+use std::cell::Cell;
+use std::io;
+use std::time::Duration;
+
+#[test]
+fn test_error_codes_are_stable() {
+    crate::init().unwrap();
+
+    // These strings are part of our public interface. If this test fails
+    // because a code was renamed or removed, that's a breaking change: add a
+    // new code instead.
+    const GOLDEN: &[&str] = &[
+        "AUTHENTICATION_FAILED",
+        "CONFIG_UNKNOWN_FIELDS",
+        "CONFIG_TOO_LARGE",
+        "CONFIG_TOO_DEEP",
+        "CONFIG_TOO_COMPLEX",
+        "CONFIG_STRING_TOO_LONG",
+        "CONFIG_CORRUPTED",
+        "CRYPTO",
+        "DIGEST_MISMATCH",
+        "ENV_VAR",
+        "INVALID_UTF8",
+        "HEX_DECODE",
+        "HTTP",
+        "HTTP_CONTENT_TYPE",
+        "HTTP_RETRY",
+        "HTTP_STATUS",
+        "INPUT_TOO_BIG",
+        "INTERNAL",
+        "INVALID_ARGUMENT",
+        "IO",
+        "IO_NOT_FOUND",
+        "IO_PERMISSION_DENIED",
+        "IO_ALREADY_EXISTS",
+        "IO_TIMED_OUT",
+        "IO_UNEXPECTED_EOF",
+        "IO_INVALID_DATA",
+        "IO_CONNECTION",
+        "JSON",
+        "KEY_EXPIRED",
+        "KEY_STORE_TAMPERED",
+        "MSG_DECODE",
+        "MSG_ENCODE",
+        "NET_TIMEOUT",
+        "NOT_FOUND",
+        "NUL",
+        "PARSE_INT",
+        "PARSE_IP_ADDR",
+        "PRECONDITION",
+        "PROCESS_FAILED",
+        "PROCESS_TIMEOUT",
+        "READ_ONLY_CONFIGURATION",
+        "READ_ONLY_KEY_STORE",
+        "REGEX",
+        "SOCKET_OPTION",
+        "STRING_PARSE",
+        "UNSUPPORTED",
+        "URL",
+    ];
+    let codes: Vec<&str> = ErrorCode::ALL.iter().map(|c| c.as_str()).collect();
+    assert_eq!(GOLDEN, codes.as_slice());
+
+    // The serde representation must agree with `as_str`.
+    for code in ErrorCode::ALL {
+        assert_eq!(json!(code.as_str()), serde_json::to_value(code).unwrap());
+    }
+}
+
+#[test]
+fn test_error_variant_codes() {
+    use std::os::unix::process::ExitStatusExt;
+
+    crate::init().unwrap();
+
+    // Hidden from clippy, which otherwise rejects this (deliberately) invalid regex.
+    let unclosed_group = std::hint::black_box("(");
+    let errors: Vec<(Error, ErrorCode, ErrorCategory)> = vec![
+        (
+            Error::Crypto("x".to_string()),
+            ErrorCode::Crypto,
+            ErrorCategory::Crypto,
+        ),
+        (
+            std::env::var("BDRCK_ERROR_CODE_TEST_UNSET")
+                .unwrap_err()
+                .into(),
+            ErrorCode::EnvVar,
+            ErrorCategory::Usage,
+        ),
+        (
+            String::from_utf8(vec![0xff]).unwrap_err().into(),
+            ErrorCode::InvalidUtf8,
+            ErrorCategory::Usage,
+        ),
+        (
+            Error::HttpRetry("x".to_string()),
+            ErrorCode::HttpRetry,
+            ErrorCategory::Http,
+        ),
+        (
+            Error::InvalidArgument("x".to_string()),
+            ErrorCode::InvalidArgument,
+            ErrorCategory::Usage,
+        ),
+        (
+            serde_json::from_str::<u32>("x").unwrap_err().into(),
+            ErrorCode::Json,
+            ErrorCategory::Internal,
+        ),
+        (
+            rmp_serde::from_slice::<u32>(&[0xc1]).unwrap_err().into(),
+            ErrorCode::MsgDecode,
+            ErrorCategory::Internal,
+        ),
+        (
+            Error::NotFound("x".to_string()),
+            ErrorCode::NotFound,
+            ErrorCategory::Usage,
+        ),
+        (
+            "x".parse::<u32>().unwrap_err().into(),
+            ErrorCode::ParseInt,
+            ErrorCategory::Usage,
+        ),
+        (
+            Error::ProcessFailed {
+                status: std::process::ExitStatus::from_raw(256),
+                stderr: String::new(),
+            },
+            ErrorCode::ProcessFailed,
+            ErrorCategory::Io,
+        ),
+        (
+            regex::Regex::new(unclosed_group).unwrap_err().into(),
+            ErrorCode::Regex,
+            ErrorCategory::Internal,
+        ),
+        (
+            url::Url::parse("").unwrap_err().into(),
+            ErrorCode::Url,
+            ErrorCategory::Usage,
+        ),
+    ];
+
+    for (error, code, category) in errors {
+        assert_eq!(code, error.code(), "{:?}", error);
+        assert_eq!(category, error.category(), "{:?}", error);
+    }
+}
+
+#[test]
+fn test_io_error_kind_codes() {
+    crate::init().unwrap();
+
+    for (kind, code) in [
+        (io::ErrorKind::NotFound, ErrorCode::IoNotFound),
+        (
+            io::ErrorKind::PermissionDenied,
+            ErrorCode::IoPermissionDenied,
+        ),
+        (io::ErrorKind::AlreadyExists, ErrorCode::IoAlreadyExists),
+        (io::ErrorKind::TimedOut, ErrorCode::IoTimedOut),
+        (io::ErrorKind::WouldBlock, ErrorCode::IoTimedOut),
+        (io::ErrorKind::UnexpectedEof, ErrorCode::IoUnexpectedEof),
+        (io::ErrorKind::InvalidData, ErrorCode::IoInvalidData),
+        (io::ErrorKind::ConnectionRefused, ErrorCode::IoConnection),
+        (io::ErrorKind::BrokenPipe, ErrorCode::IoConnection),
+        (io::ErrorKind::Other, ErrorCode::Io),
+    ] {
+        let error: Error = io::Error::new(kind, "x").into();
+        assert_eq!(code, error.code());
+        assert_eq!(ErrorCategory::Io, error.category());
+    }
+}
+
+#[test]
+fn test_error_to_json() {
+    crate::init().unwrap();
+
+    let error = Error::InvalidArgument("bad flag".to_string());
+    assert_eq!(
+        json!({
+            "code": "INVALID_ARGUMENT",
+            "category": "USAGE",
+            "message": "invalid argument: bad flag",
+            "context": [],
+        }),
+        error.to_json()
+    );
+
+    let error: Error = io::Error::new(io::ErrorKind::NotFound, "no such file").into();
+    assert_eq!(
+        json!({
+            "code": "IO_NOT_FOUND",
+            "category": "IO",
+            "message": "no such file",
+            "context": [],
+        }),
+        error.to_json()
+    );
+}
+
+#[test]
+fn test_error_retryability() {
+    crate::init().unwrap();
+
+    let status = |status: u16| Error::HttpStatus {
+        status,
+        body_prefix: String::new(),
+    };
+    for (error, retryability) in [
+        (status(503), Retryability::Transient),
+        (status(429), Retryability::Transient),
+        (status(408), Retryability::Transient),
+        (status(404), Retryability::Permanent),
+        (status(400), Retryability::Permanent),
+        (Error::HttpRetry("x".to_string()), Retryability::Transient),
+        (Error::NetTimeout("x".to_string()), Retryability::Transient),
+        (
+            Error::ProcessTimeout("x".to_string()),
+            Retryability::Transient,
+        ),
+        (
+            Error::InvalidArgument("x".to_string()),
+            Retryability::Permanent,
+        ),
+        (
+            Error::Precondition("x".to_string()),
+            Retryability::Permanent,
+        ),
+        (Error::NotFound("x".to_string()), Retryability::Permanent),
+        (Error::Unsupported("x".to_string()), Retryability::Permanent),
+        (
+            Error::AuthenticationFailed {
+                status: 401,
+                body_prefix: String::new(),
+            },
+            Retryability::Permanent,
+        ),
+        (
+            serde_json::from_str::<u32>("x").unwrap_err().into(),
+            Retryability::Permanent,
+        ),
+        (Error::Internal("x".to_string()), Retryability::Unknown),
+    ] {
+        assert_eq!(retryability, error.retryability(), "{:?}", error);
+    }
+
+    for (kind, retryability) in [
+        (io::ErrorKind::Interrupted, Retryability::Transient),
+        (io::ErrorKind::WouldBlock, Retryability::Transient),
+        (io::ErrorKind::TimedOut, Retryability::Transient),
+        (io::ErrorKind::ConnectionReset, Retryability::Transient),
+        (io::ErrorKind::ConnectionRefused, Retryability::Transient),
+        (io::ErrorKind::NotFound, Retryability::Permanent),
+        (io::ErrorKind::PermissionDenied, Retryability::Permanent),
+        (io::ErrorKind::InvalidData, Retryability::Permanent),
+        (io::ErrorKind::Other, Retryability::Unknown),
+    ] {
+        let error: Error = io::Error::new(kind, "x").into();
+        assert_eq!(retryability, error.retryability(), "{:?}", kind);
+    }
+}
+
+#[test]
+fn test_error_retryability_override() {
+    crate::init().unwrap();
+
+    let error = Error::NotFound("x".to_string()).as_transient();
+    assert_eq!(Retryability::Transient, error.retryability());
+    // Apart from its retryability, the error is unchanged.
+    assert_eq!(ErrorCode::NotFound, error.code());
+    assert_eq!("not found: x", error.to_string());
+    assert!(error.context().is_empty());
+
+    // Markers replace each other, rather than nesting.
+    let error = error.as_permanent();
+    assert_eq!(Retryability::Permanent, error.retryability());
+    match error {
+        Error::WithRetryability { source, .. } => {
+            assert!(matches!(*source, Error::NotFound(_)))
+        }
+        e => panic!("unexpected error {:?}", e),
+    }
+}
+
+fn no_jitter_policy() -> RetryPolicy {
+    RetryPolicy {
+        jitter: Duration::ZERO,
+        ..RetryPolicy::default()
+    }
+}
+
+#[test]
+fn test_retry_transient() {
+    crate::init().unwrap();
+
+    let clock = MockClock::default();
+    let start = clock.now_instant();
+    let attempts = Cell::new(0);
+    let result: Result<()> = retry_with_clock(&no_jitter_policy(), &clock, || {
+        attempts.set(attempts.get() + 1);
+        Err(Error::NetTimeout("x".to_string()))
+    });
+    assert_eq!(ErrorCode::NetTimeout, result.unwrap_err().code());
+    assert_eq!(4, attempts.get());
+    assert_eq!(
+        Duration::from_millis(100 + 200 + 400),
+        clock.now_instant() - start
+    );
+
+    // An operation which eventually succeeds stops being retried.
+    attempts.set(0);
+    let result = retry_with_clock(&no_jitter_policy(), &clock, || {
+        attempts.set(attempts.get() + 1);
+        match attempts.get() {
+            1 => Err(io::Error::from(io::ErrorKind::ConnectionReset).into()),
+            n => Ok(n),
+        }
+    });
+    assert_eq!(2, result.unwrap());
+}
+
+#[test]
+fn test_retry_permanent() {
+    crate::init().unwrap();
+
+    let clock = MockClock::default();
+    let start = clock.now_instant();
+    let attempts = Cell::new(0);
+    let result: Result<()> = retry_with_clock(&no_jitter_policy(), &clock, || {
+        attempts.set(attempts.get() + 1);
+        Err(Error::InvalidArgument("x".to_string()))
+    });
+    assert_eq!(ErrorCode::InvalidArgument, result.unwrap_err().code());
+    assert_eq!(1, attempts.get());
+    assert_eq!(Duration::ZERO, clock.now_instant() - start);
+
+    // Marking an otherwise transient error as permanent stops retries too.
+    attempts.set(0);
+    let result: Result<()> = retry_with_clock(&no_jitter_policy(), &clock, || {
+        attempts.set(attempts.get() + 1);
+        Err(Error::NetTimeout("x".to_string()).as_permanent())
+    });
+    assert!(result.is_err());
+    assert_eq!(1, attempts.get());
+}
+
+#[test]
+fn test_retry_policy_delay() {
+    crate::init().unwrap();
+
+    let mut rng = rand::thread_rng();
+    let policy = RetryPolicy {
+        max_delay: Duration::from_millis(300),
+        ..no_jitter_policy()
+    };
+    assert_eq!(Duration::from_millis(100), policy.delay(1, &mut rng));
+    assert_eq!(Duration::from_millis(200), policy.delay(2, &mut rng));
+    assert_eq!(Duration::from_millis(300), policy.delay(3, &mut rng));
+    assert_eq!(Duration::from_millis(300), policy.delay(1000, &mut rng));
+
+    let delay = RetryPolicy::default().delay(1, &mut rng);
+    assert!(delay >= Duration::from_millis(100) && delay < Duration::from_millis(110));
+
+    // Adding jitter to an unbounded delay saturates instead of overflowing.
+    let policy = RetryPolicy {
+        max_delay: Duration::MAX,
+        jitter: Duration::from_secs(1),
+        ..RetryPolicy::default()
+    };
+    assert_eq!(Duration::MAX, policy.delay(1000, &mut rng));
+}
@@ -12,11 +12,14 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::error::*;
 use crate::fs::*;
-use crate::testing::temp;
+use crate::testing::temp::{FaultyFs, Operation};
+use crate::testing::{prop, temp};
 use std::fs::{self, File};
 use std::io::{Read, Write};
 use std::path::PathBuf;
+use std::time::Duration;
 
 #[test]
 fn test_path_bytes_round_trip() {
@@ -26,6 +29,20 @@ fn test_path_bytes_round_trip() {
     let bytes = path_to_bytes(expected_path.as_path()).unwrap();
     let path = path_from_bytes(bytes).unwrap();
     assert_eq!(expected_path, path);
+
+    // Any byte string (valid UTF-8 or not) must survive the round trip.
+    prop::run(
+        256,
+        true,
+        prop::gen::vec(prop::gen::int(1..=u8::MAX), 0..64),
+        |bytes| {
+            let path = path_from_bytes(bytes.clone()).map_err(|e| e.to_string())?;
+            match path_to_bytes(&path).map_err(|e| e.to_string())? == *bytes {
+                true => Ok(()),
+                false => Err(format!("{} didn't round trip", path.display())),
+            }
+        },
+    );
 }
 
 #[test]
@@ -88,3 +105,1640 @@ fn test_set_permissions_mode() {
         fs::metadata(temp_file.path()).unwrap().permissions().mode() & 0x1FF
     );
 }
+
+#[test]
+fn test_symbolic_mode_parsing() {
+    crate::init().unwrap();
+
+    // (initial mode, is_dir, expression, expected mode)
+    let cases: &[(u32, bool, &str, u32)] = &[
+        (0o644, false, "u+x", 0o744),
+        (0o777, false, "go-w", 0o755),
+        (0o600, false, "u+rwX,go-w", 0o600),
+        (0o700, false, "u+rwX,go+rX", 0o755),
+        (0o644, false, "a+X", 0o644),
+        (0o744, false, "a+X", 0o755),
+        (0o644, true, "a+X", 0o755),
+        (0o644, true, "+X", 0o755),
+        (0o777, false, "u=rw,go=r", 0o644),
+        (0o755, false, "g=", 0o705),
+        (0o4755, false, "u=rwx", 0o755),
+        (0o644, false, "u+s,g+s", 0o6644),
+        (0o755, true, "+t", 0o1755),
+        (0o1777, true, "o-t", 0o777),
+        (0o640, false, "o+r-w,g=rw", 0o664),
+        (0o000, false, "ugo+r", 0o444),
+    ];
+    for &(initial, is_dir, expr, expected) in cases {
+        let mode: Mode = expr.parse().unwrap();
+        assert_eq!(
+            expected,
+            mode.apply(initial, is_dir),
+            "{:o} {} on {}",
+            initial,
+            expr,
+            if is_dir { "directory" } else { "file" }
+        );
+    }
+
+    for expr in &["", "u", "z+r", "u+r,", "u+q", "08", "77777"] {
+        assert!(expr.parse::<Mode>().is_err(), "{}", expr);
+    }
+}
+
+#[test]
+fn test_mode_formatting() {
+    crate::init().unwrap();
+
+    let cases: &[(&str, &str)] = &[
+        ("0640", "rw-r-----"),
+        ("755", "rwxr-xr-x"),
+        ("0", "---------"),
+        ("4755", "rwsr-xr-x"),
+        ("2644", "rw-r-Sr--"),
+        ("1777", "rwxrwxrwt"),
+        ("u+rwX,go-w", "u+rwX,go-w"),
+        ("ugo=r", "a=r"),
+    ];
+    for &(expr, expected) in cases {
+        assert_eq!(expected, expr.parse::<Mode>().unwrap().to_string());
+    }
+}
+
+#[test]
+fn test_get_and_set_mode() {
+    crate::init().unwrap();
+
+    let temp_file = temp::File::new_file().unwrap();
+    set_mode(temp_file.path(), &"0640".parse().unwrap()).unwrap();
+    assert_eq!(Mode::Absolute(0o640), get_mode(temp_file.path()).unwrap());
+
+    set_mode(temp_file.path(), &"u+x,o+r".parse().unwrap()).unwrap();
+    let mode = get_mode(temp_file.path()).unwrap();
+    assert_eq!(Mode::Absolute(0o744), mode);
+    assert_eq!("rwxr--r--", mode.to_string());
+
+    // Round-trip the absolute mode through its octal representation.
+    let octal = match mode {
+        Mode::Absolute(mode) => format!("{:04o}", mode),
+        _ => unreachable!(),
+    };
+    assert_eq!("0744", octal);
+    assert_eq!(mode, octal.parse().unwrap());
+}
+
+#[test]
+fn test_chown_by_name_unknown_user() {
+    crate::init().unwrap();
+
+    let temp_file = temp::File::new_file().unwrap();
+    match chown_by_name(temp_file.path(), Some("bdrck-no-such-user"), None) {
+        Err(Error::NotFound(_)) => {}
+        r => panic!("expected NotFound error, got {:?}", r),
+    }
+    match chown_by_name(temp_file.path(), None, Some("bdrck-no-such-group")) {
+        Err(Error::NotFound(_)) => {}
+        r => panic!("expected NotFound error, got {:?}", r),
+    }
+    // Changing nothing should always succeed.
+    chown_by_name(temp_file.path(), None, None).unwrap();
+}
+
+#[test]
+fn test_temp_file_collision_retry() {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+
+    // Pre-create a file with the name the seeded RNG would produce first.
+    let first_name = random_name(&mut StdRng::seed_from_u64(1234), "test");
+    let existing = dir.path().join(first_name);
+    create_file(&existing).unwrap();
+
+    let tf =
+        TempFile::new_in_with_rng(&mut StdRng::seed_from_u64(1234), dir.path(), "test").unwrap();
+    assert_ne!(existing, tf.path());
+    assert!(tf.path().exists());
+    assert!(existing.exists());
+    assert_eq!(dir.path(), tf.path().parent().unwrap());
+    assert!(tf
+        .path()
+        .file_name()
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .starts_with("test-"));
+}
+
+#[test]
+fn test_temp_file_permissions() {
+    use std::os::unix::fs::PermissionsExt;
+
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let tf = TempFile::new_in(dir.path(), "test").unwrap();
+    assert_eq!(
+        0o600,
+        fs::metadata(tf.path()).unwrap().permissions().mode() & 0o777
+    );
+}
+
+#[test]
+fn test_temp_file_persist() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let dest = dir.path().join("dest");
+    fs::write(&dest, b"old contents").unwrap();
+
+    let mut tf = TempFile::new_in(dir.path(), "test").unwrap();
+    let path = tf.path().to_path_buf();
+    tf.as_file_mut().write_all(b"new contents").unwrap();
+    tf.persist(&dest).unwrap();
+
+    assert!(!path.exists());
+    assert_eq!(b"new contents".to_vec(), fs::read(&dest).unwrap());
+}
+
+#[test]
+fn test_temp_file_persist_cross_device() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let dest = dir.path().join("dest");
+
+    let mut tf = TempFile::new_in(dir.path(), "test").unwrap();
+    let path = tf.path().to_path_buf();
+    tf.as_file_mut().write_all(b"new contents").unwrap();
+    tf.persist_with(&dest, |_, _| {
+        Err(std::io::Error::from_raw_os_error(libc::EXDEV))
+    })
+    .unwrap();
+
+    assert!(!path.exists());
+    assert_eq!(b"new contents".to_vec(), fs::read(&dest).unwrap());
+    // Only the destination should be left; no stray temporary copies.
+    assert_eq!(1, fs::read_dir(dir.path()).unwrap().count());
+}
+
+#[test]
+fn test_temp_file_persist_other_error() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let dest = dir.path().join("dest");
+
+    let tf = TempFile::new_in(dir.path(), "test").unwrap();
+    let path = tf.path().to_path_buf();
+    assert!(tf
+        .persist_with(&dest, |_, _| Err(std::io::Error::from_raw_os_error(
+            libc::EACCES
+        )))
+        .is_err());
+
+    // The temporary file should have been cleaned up, and nothing written.
+    assert!(!path.exists());
+    assert!(!dest.exists());
+}
+
+#[test]
+fn test_temp_file_cleanup() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+
+    let tf = TempFile::new_in(dir.path(), "test").unwrap();
+    let path = tf.path().to_path_buf();
+    assert!(path.exists());
+    drop(tf);
+    assert!(!path.exists());
+
+    let tf = TempFile::new_in(dir.path(), "test").unwrap();
+    let path = tf.path().to_path_buf();
+    tf.close().unwrap();
+    assert!(!path.exists());
+}
+
+/// Return the names of the entries in the given directory, sorted.
+fn dir_entries(dir: &std::path::Path) -> Vec<String> {
+    let mut entries: Vec<String> = fs::read_dir(dir)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    entries.sort();
+    entries
+}
+
+#[test]
+fn test_atomic_write() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let path = dir.sub_path("data").unwrap();
+    atomic_write(&path, b"first").unwrap();
+    assert_eq!(b"first".to_vec(), fs::read(&path).unwrap());
+    atomic_write(&path, b"second").unwrap();
+    assert_eq!(b"second".to_vec(), fs::read(&path).unwrap());
+    assert_eq!(vec!["data".to_string()], dir_entries(dir.path()));
+}
+
+/// A named way of scripting a FaultyFs to fail, and the error code it results
+/// in.
+type FaultCase = (&'static str, fn(&FaultyFs), ErrorCode);
+
+#[test]
+fn test_atomic_write_failures_leave_original_intact() {
+    use std::io::ErrorKind;
+
+    crate::init().unwrap();
+
+    let data = vec![0xab_u8; 10000];
+    let cases: &[FaultCase] = &[
+        (
+            "rename",
+            |ffs| {
+                let n = ffs.calls(Operation::Rename) + 1;
+                ffs.fail_nth(Operation::Rename, n, ErrorKind::PermissionDenied);
+            },
+            ErrorCode::IoPermissionDenied,
+        ),
+        (
+            "full disk",
+            |ffs| {
+                let limit = ffs.bytes_written() + 4096;
+                ffs.fail_after_bytes(Operation::Write, limit, ErrorKind::StorageFull);
+            },
+            ErrorCode::Io,
+        ),
+        (
+            "fsync",
+            |ffs| {
+                let n = ffs.calls(Operation::Fsync) + 1;
+                ffs.fail_nth(Operation::Fsync, n, ErrorKind::Other);
+            },
+            ErrorCode::Io,
+        ),
+        (
+            "open",
+            |ffs| {
+                let n = ffs.calls(Operation::Open) + 1;
+                ffs.fail_nth(Operation::Open, n, ErrorKind::PermissionDenied);
+            },
+            ErrorCode::IoPermissionDenied,
+        ),
+    ];
+    for (name, script, code) in cases {
+        let ffs = FaultyFs::new("bdrck").unwrap();
+        let path = ffs.sub_path("data").unwrap();
+        ffs.atomic_write(&path, b"original").unwrap();
+
+        script(&ffs);
+        let err = ffs.atomic_write(&path, data.as_slice()).unwrap_err();
+        assert_eq!(*code, err.code(), "{}", name);
+
+        // The original is untouched, and the temporary file is cleaned up.
+        assert_eq!(b"original".to_vec(), fs::read(&path).unwrap(), "{}", name);
+        assert_eq!(
+            vec!["data".to_string()],
+            dir_entries(ffs.path()),
+            "{}",
+            name
+        );
+    }
+}
+
+/// Clone a test file (with an unusual mode) using the given policy, returning
+/// the method used, or None if the policy isn't supported here.
+fn clone_test_file(dir: &temp::Dir, policy: ClonePolicy) -> Option<(PathBuf, CloneMethod)> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let src = dir.sub_path("src").unwrap();
+    let dst = dir.sub_path("dst").unwrap();
+    let _ = fs::remove_file(&dst);
+    if !src.exists() {
+        fs::write(&src, b"some data to clone").unwrap();
+        set_permissions_mode(&src, 0o640).unwrap();
+    }
+
+    let method = match clone_file(&src, &dst, policy) {
+        Err(Error::Unsupported(_)) => return None,
+        r => r.unwrap(),
+    };
+    assert_eq!(b"some data to clone".to_vec(), fs::read(&dst).unwrap());
+    assert_eq!(
+        0o640,
+        fs::metadata(&dst).unwrap().permissions().mode() & 0o777
+    );
+    Some((dst, method))
+}
+
+#[test]
+fn test_clone_file_copy() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let policy = ClonePolicy {
+        allow_reflink: false,
+        allow_hardlink: false,
+        allow_copy: true,
+        preserve_sparseness: false,
+    };
+    let (dst, method) = clone_test_file(&dir, policy).unwrap();
+    assert_eq!(CloneMethod::Copy, method);
+
+    // The copy should be independent of the original.
+    fs::write(&dst, b"modified").unwrap();
+    assert_eq!(
+        b"some data to clone".to_vec(),
+        fs::read(dir.sub_path("src").unwrap()).unwrap()
+    );
+
+    // The destination must not already exist.
+    assert!(clone_file(dir.sub_path("src").unwrap(), &dst, policy).is_err());
+}
+
+#[test]
+fn test_clone_file_hard_link() {
+    use std::os::unix::fs::MetadataExt;
+
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let policy = ClonePolicy {
+        allow_reflink: false,
+        allow_hardlink: true,
+        allow_copy: false,
+        preserve_sparseness: false,
+    };
+    let (dst, method) = clone_test_file(&dir, policy).unwrap();
+    assert_eq!(CloneMethod::HardLink, method);
+    let src = fs::metadata(dir.sub_path("src").unwrap()).unwrap();
+    let dst = fs::metadata(&dst).unwrap();
+    assert_eq!((src.dev(), src.ino()), (dst.dev(), dst.ino()));
+}
+
+#[test]
+fn test_clone_file_reflink() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let policy = ClonePolicy {
+        allow_reflink: true,
+        allow_hardlink: false,
+        allow_copy: false,
+        preserve_sparseness: false,
+    };
+    // Whether or not reflinks work depends on the filesystem we're on. If
+    // they don't, there's nothing to test.
+    if let Some((_, method)) = clone_test_file(&dir, policy) {
+        assert_eq!(CloneMethod::Reflink, method);
+    }
+
+    // With the default policy, we should always end up with *some* copy.
+    let (_, method) = clone_test_file(&dir, ClonePolicy::default()).unwrap();
+    assert_ne!(CloneMethod::HardLink, method);
+}
+
+const SPARSE_BLOCK: u64 = 64 * 1024;
+
+/// Create a sparse test file: a block of data, a hole, another block of data,
+/// and then a trailing hole.
+fn create_sparse_test_file(path: &std::path::Path) -> File {
+    use std::io::{Seek, SeekFrom};
+
+    let mut f = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .unwrap();
+    f.write_all(&vec![b'a'; SPARSE_BLOCK as usize]).unwrap();
+    f.seek(SeekFrom::Start(4 * SPARSE_BLOCK)).unwrap();
+    f.write_all(&vec![b'b'; SPARSE_BLOCK as usize]).unwrap();
+    f.set_len(8 * SPARSE_BLOCK).unwrap();
+    f.sync_all().unwrap();
+    f
+}
+
+#[test]
+fn test_sparse_extents_and_copy() {
+    use std::os::unix::fs::MetadataExt;
+
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let src = dir.sub_path("src").unwrap();
+    let f = create_sparse_test_file(&src);
+    let src_extents = match extents(&f) {
+        Err(Error::Unsupported(_)) => return,
+        r => r.unwrap(),
+    };
+    // Some filesystems don't track holes, in which case there's nothing
+    // interesting to test.
+    if src_extents.len() == 1 {
+        return;
+    }
+    assert_eq!(
+        vec![
+            Extent {
+                offset: 0,
+                len: SPARSE_BLOCK,
+                kind: ExtentKind::Data
+            },
+            Extent {
+                offset: SPARSE_BLOCK,
+                len: 3 * SPARSE_BLOCK,
+                kind: ExtentKind::Hole
+            },
+            Extent {
+                offset: 4 * SPARSE_BLOCK,
+                len: SPARSE_BLOCK,
+                kind: ExtentKind::Data
+            },
+            Extent {
+                offset: 5 * SPARSE_BLOCK,
+                len: 3 * SPARSE_BLOCK,
+                kind: ExtentKind::Hole
+            },
+        ],
+        src_extents
+    );
+
+    let dst = dir.sub_path("dst").unwrap();
+    let stats = copy_sparse(&src, &dst).unwrap();
+    assert_eq!(
+        SparseCopyStats {
+            data_bytes: 2 * SPARSE_BLOCK,
+            hole_bytes: 6 * SPARSE_BLOCK,
+            sparse: true,
+        },
+        stats
+    );
+    assert_eq!(fs::read(&src).unwrap(), fs::read(&dst).unwrap());
+    let (src_meta, dst_meta) = (fs::metadata(&src).unwrap(), fs::metadata(&dst).unwrap());
+    assert_eq!(src_meta.len(), dst_meta.len());
+    assert!(dst_meta.blocks() <= src_meta.blocks());
+    assert_eq!(src_extents, extents(&File::open(&dst).unwrap()).unwrap());
+
+    // Copying a file onto itself (even via another link) would truncate it.
+    let link = dir.sub_path("link").unwrap();
+    fs::hard_link(&src, &link).unwrap();
+    let contents = fs::read(&src).unwrap();
+    for dst in [&src, &link] {
+        match copy_sparse(&src, dst) {
+            Err(Error::InvalidArgument(_)) => {}
+            r => panic!("expected an invalid argument error, got {:?}", r),
+        }
+        assert_eq!(contents, fs::read(&src).unwrap());
+    }
+
+    // clone_file can preserve sparseness too.
+    let cloned = dir.sub_path("cloned").unwrap();
+    let policy = ClonePolicy {
+        allow_reflink: false,
+        allow_hardlink: false,
+        allow_copy: true,
+        preserve_sparseness: true,
+    };
+    assert_eq!(
+        CloneMethod::Copy,
+        clone_file(&src, &cloned, policy).unwrap()
+    );
+    assert_eq!(fs::read(&src).unwrap(), fs::read(&cloned).unwrap());
+    assert!(fs::metadata(&cloned).unwrap().blocks() <= src_meta.blocks());
+}
+
+#[test]
+fn test_punch_hole() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let path = dir.sub_path("file").unwrap();
+    let mut f = File::create(&path).unwrap();
+    f.write_all(&vec![b'c'; 3 * SPARSE_BLOCK as usize]).unwrap();
+    f.sync_all().unwrap();
+
+    match punch_hole(&f, SPARSE_BLOCK, SPARSE_BLOCK) {
+        Err(Error::Unsupported(_)) => return,
+        r => r.unwrap(),
+    }
+    let contents = fs::read(&path).unwrap();
+    assert_eq!(3 * SPARSE_BLOCK, contents.len() as u64);
+    let (a, rest) = contents.split_at(SPARSE_BLOCK as usize);
+    let (hole, c) = rest.split_at(SPARSE_BLOCK as usize);
+    assert!(a.iter().chain(c.iter()).all(|&b| b == b'c'));
+    assert!(hole.iter().all(|&b| b == 0));
+
+    let punched = extents(&File::open(&path).unwrap()).unwrap();
+    assert!(punched.contains(&Extent {
+        offset: SPARSE_BLOCK,
+        len: SPARSE_BLOCK,
+        kind: ExtentKind::Hole,
+    }));
+}
+
+fn new_test_watcher() -> watch::Watcher<&'static str> {
+    watch::Watcher::new(watch::WatchOptions {
+        poll_interval: Duration::from_millis(20),
+        ..Default::default()
+    })
+}
+
+fn expect_event(
+    watcher: &mut watch::Watcher<&'static str>,
+    path: &std::path::Path,
+    kind: watch::EventKind,
+) {
+    assert_eq!(
+        Some(watch::Event {
+            tag: "test",
+            path: path.to_path_buf(),
+            kind,
+        }),
+        watcher.next_event(Duration::from_secs(5))
+    );
+}
+
+#[test]
+fn test_watch_file_lifecycle() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let path = dir.path().join("config");
+    let mut watcher = new_test_watcher();
+    // The file doesn't exist yet.
+    watcher.watch_path(&path, "test").unwrap();
+    assert_eq!(None, watcher.next_event(Duration::from_millis(50)));
+
+    fs::write(&path, "a").unwrap();
+    expect_event(&mut watcher, &path, watch::EventKind::Created);
+
+    fs::write(&path, "bb").unwrap();
+    expect_event(&mut watcher, &path, watch::EventKind::Modified);
+
+    fs::remove_file(&path).unwrap();
+    expect_event(&mut watcher, &path, watch::EventKind::Removed);
+
+    fs::write(&path, "ccc").unwrap();
+    expect_event(&mut watcher, &path, watch::EventKind::Created);
+    assert_eq!(None, watcher.next_event(Duration::from_millis(50)));
+}
+
+#[test]
+fn test_watch_directory() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    fs::write(dir.path().join("existing"), "a").unwrap();
+    let mut watcher = new_test_watcher();
+    watcher.watch_path(dir.path(), "test").unwrap();
+
+    let added = dir.path().join("added");
+    fs::write(&added, "b").unwrap();
+    expect_event(&mut watcher, &added, watch::EventKind::Created);
+
+    fs::remove_file(dir.path().join("existing")).unwrap();
+    expect_event(
+        &mut watcher,
+        &dir.path().join("existing"),
+        watch::EventKind::Removed,
+    );
+}
+
+#[test]
+fn test_watch_coalesces_changes() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let path = dir.path().join("config");
+    fs::write(&path, "").unwrap();
+    let mut watcher = new_test_watcher();
+    watcher.watch_path(&path, "test").unwrap();
+
+    for contents in ["a", "bb", "ccc"] {
+        fs::write(&path, contents).unwrap();
+    }
+    expect_event(&mut watcher, &path, watch::EventKind::Modified);
+    assert_eq!(None, watcher.next_event(Duration::from_millis(100)));
+}
+
+#[test]
+fn test_watch_subscribe() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let path = dir.path().join("config");
+    let mut watcher = new_test_watcher();
+    watcher.watch_path(&path, "test").unwrap();
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let subscription = watcher.subscribe(move |event| tx.send(event).unwrap());
+    fs::write(&path, "a").unwrap();
+    let event = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(watch::EventKind::Created, event.kind);
+    // Dropping the subscription stops (and joins) the thread.
+    drop(subscription);
+}
+
+// Create a small tree under the given directory: two files (of 3 and 5 bytes),
+// and a nested directory, with one more file (of 7 bytes) inside it.
+fn create_remove_test_tree(dir: &std::path::Path) -> PathBuf {
+    let root = dir.join("tree");
+    fs::create_dir_all(root.join("nested")).unwrap();
+    fs::write(root.join("a"), "aaa").unwrap();
+    fs::write(root.join("b"), "bbbbb").unwrap();
+    fs::write(root.join("nested").join("c"), "ccccccc").unwrap();
+    root
+}
+
+#[test]
+fn test_remove_tree_dry_run() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let root = create_remove_test_tree(dir.path());
+    let mut reported = Vec::new();
+    let mut on_entry = |path: &std::path::Path, kind: EntryKind| {
+        reported.push((path.strip_prefix(dir.path()).unwrap().to_path_buf(), kind))
+    };
+    let stats = remove_tree(
+        &root,
+        RemoveOptions {
+            dry_run: true,
+            on_entry: Some(&mut on_entry),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    assert!(stats.is_complete());
+    assert_eq!(3, stats.files);
+    assert_eq!(2, stats.directories);
+    assert_eq!(0, stats.symlinks);
+    assert_eq!(15, stats.bytes);
+    // Directories are reported after their contents.
+    reported.sort();
+    assert_eq!(
+        vec![
+            (PathBuf::from("tree"), EntryKind::Directory),
+            (PathBuf::from("tree/a"), EntryKind::File),
+            (PathBuf::from("tree/b"), EntryKind::File),
+            (PathBuf::from("tree/nested"), EntryKind::Directory),
+            (PathBuf::from("tree/nested/c"), EntryKind::File),
+        ],
+        reported
+    );
+    assert!(root.join("nested").join("c").exists());
+}
+
+#[test]
+fn test_remove_tree_symlinks_not_followed() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let root = create_remove_test_tree(dir.path());
+    let outside = dir.path().join("outside");
+    fs::create_dir(&outside).unwrap();
+    fs::write(outside.join("keep"), "keep").unwrap();
+    create_symlink(&outside, root.join("link")).unwrap();
+
+    let stats = remove_tree(&root, RemoveOptions::default()).unwrap();
+    assert!(stats.is_complete());
+    assert_eq!(1, stats.symlinks);
+    assert_eq!(3, stats.files);
+    assert!(!root.exists());
+    assert_eq!("keep", fs::read_to_string(outside.join("keep")).unwrap());
+
+    // keep_root empties, but doesn't remove, the directory.
+    let stats = remove_tree(
+        &outside,
+        RemoveOptions {
+            keep_root: true,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    assert_eq!(1, stats.files);
+    assert_eq!(0, stats.directories);
+    assert_eq!(0, fs::read_dir(&outside).unwrap().count());
+}
+
+#[test]
+fn test_remove_tree_read_only() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let root = create_remove_test_tree(dir.path());
+    set_permissions_mode(root.join("nested").join("c"), 0o400).unwrap();
+    set_permissions_mode(root.join("nested"), 0o500).unwrap();
+
+    let stats = remove_tree(&root, RemoveOptions::default()).unwrap();
+    assert!(stats.is_complete(), "{:?}", stats.failures);
+    assert_eq!(3, stats.files);
+    assert!(!root.exists());
+}
+
+#[test]
+fn test_remove_tree_directory_swapped_for_symlink() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let root = create_remove_test_tree(dir.path());
+    fs::write(root.join("nested").join("d"), "d").unwrap();
+    let outside = dir.path().join("outside");
+    fs::create_dir(&outside).unwrap();
+    fs::write(outside.join("c"), "c").unwrap();
+    fs::write(outside.join("d"), "d").unwrap();
+
+    // As soon as the first entry in "nested" is removed, swap "nested" for a
+    // symlink to "outside". The rest of its entries must still be removed
+    // from the original directory, not through the symlink.
+    let nested = root.join("nested");
+    let moved = dir.path().join("moved");
+    let mut swapped = false;
+    let mut on_entry = |path: &std::path::Path, _: EntryKind| {
+        if !swapped && path.parent() == Some(nested.as_path()) {
+            fs::rename(&nested, &moved).unwrap();
+            create_symlink(&outside, &nested).unwrap();
+            swapped = true;
+        }
+    };
+    remove_tree(
+        &root,
+        RemoveOptions {
+            on_entry: Some(&mut on_entry),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    assert!(outside.join("c").exists());
+    assert!(outside.join("d").exists());
+    assert_eq!(0, fs::read_dir(&moved).unwrap().count());
+}
+
+#[test]
+fn test_remove_tree_must_be_under() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let root = create_remove_test_tree(dir.path());
+    let allowed = root.join("nested");
+
+    let result = remove_tree(
+        root.join("nested").join("..").join("a"),
+        RemoveOptions {
+            must_be_under: Some(&allowed),
+            ..Default::default()
+        },
+    );
+    assert!(matches!(result, Err(Error::Precondition(_))));
+    assert!(root.join("a").exists());
+
+    // The allowed directory itself isn't inside of itself.
+    for keep_root in [false, true] {
+        let result = remove_tree(
+            root.join("nested").join(".").join(""),
+            RemoveOptions {
+                must_be_under: Some(&allowed),
+                keep_root,
+                ..Default::default()
+            },
+        );
+        assert!(matches!(result, Err(Error::Precondition(_))));
+        assert!(allowed.exists());
+    }
+
+    // A symlink inside the allowed directory is fine, wherever it points.
+    create_symlink(root.join("a"), allowed.join("link")).unwrap();
+    let stats = remove_tree(
+        allowed.join("link"),
+        RemoveOptions {
+            must_be_under: Some(&allowed),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    assert_eq!(1, stats.symlinks);
+    assert!(root.join("a").exists());
+}
+
+#[test]
+fn test_remove_tree_partial_failure() {
+    crate::init().unwrap();
+
+    // root can read any directory, so there's nothing to fail.
+    let is_root = unsafe { libc::geteuid() } == 0;
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let root = create_remove_test_tree(dir.path());
+    fs::create_dir(root.join("unreadable")).unwrap();
+    fs::write(root.join("unreadable").join("d"), "d").unwrap();
+    set_permissions_mode(root.join("unreadable"), 0o300).unwrap();
+
+    let stats = remove_tree(&root, RemoveOptions::default()).unwrap();
+    set_permissions_mode(root.join("unreadable"), 0o700).ok();
+
+    if is_root {
+        assert!(stats.is_complete());
+        assert_eq!(4, stats.files);
+        assert!(!root.exists());
+    } else {
+        // Everything else is still removed; only the unreadable directory
+        // (and so the root) remain.
+        assert_eq!(1, stats.failures.len());
+        assert_eq!(root.join("unreadable"), stats.failures[0].0);
+        assert_eq!(3, stats.files);
+        assert_eq!(1, stats.directories);
+        assert_eq!(
+            vec![root.join("unreadable")],
+            fs::read_dir(&root)
+                .unwrap()
+                .map(|e| e.unwrap().path())
+                .collect::<Vec<_>>()
+        );
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+#[test]
+fn test_xattr_round_trip() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let path = dir.sub_path("file").unwrap();
+    fs::write(&path, b"contents").unwrap();
+
+    // Not every filesystem supports extended attributes, so skip if the temp
+    // directory's doesn't.
+    match xattr::set(&path, "user.bdrck", b"foo\0bar", xattr::SetFlags::Create) {
+        Err(Error::Unsupported(_)) => return,
+        r => r.unwrap(),
+    }
+
+    assert_eq!(
+        b"foo\0bar".to_vec(),
+        xattr::get(&path, "user.bdrck").unwrap().unwrap()
+    );
+    assert!(xattr::list(&path)
+        .unwrap()
+        .contains(&"user.bdrck".to_string()));
+
+    // Create should refuse to clobber an existing attribute.
+    assert!(xattr::set(&path, "user.bdrck", b"baz", xattr::SetFlags::Create).is_err());
+    xattr::set(&path, "user.bdrck", b"baz", xattr::SetFlags::Replace).unwrap();
+    assert_eq!(
+        b"baz".to_vec(),
+        xattr::get(&path, "user.bdrck").unwrap().unwrap()
+    );
+
+    xattr::remove(&path, "user.bdrck").unwrap();
+    assert!(xattr::get(&path, "user.bdrck").unwrap().is_none());
+    assert!(!xattr::list(&path)
+        .unwrap()
+        .contains(&"user.bdrck".to_string()));
+    // Replace should refuse to create a missing attribute.
+    assert!(xattr::set(&path, "user.bdrck", b"baz", xattr::SetFlags::Replace).is_err());
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+#[test]
+fn test_xattr_missing_file() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let path = dir.sub_path("missing").unwrap();
+    assert!(xattr::get(&path, "user.bdrck").is_err());
+    assert!(xattr::list(&path).is_err());
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+#[test]
+fn test_xattr_symlink_not_followed() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let path = dir.sub_path("file").unwrap();
+    let link = dir.sub_path("link").unwrap();
+    fs::write(&path, b"contents").unwrap();
+    std::os::unix::fs::symlink(&path, &link).unwrap();
+
+    match xattr::set(&path, "user.bdrck", b"foo", xattr::SetFlags::Either) {
+        Err(Error::Unsupported(_)) => return,
+        r => r.unwrap(),
+    }
+
+    assert_eq!(
+        b"foo".to_vec(),
+        xattr::get(&link, "user.bdrck").unwrap().unwrap()
+    );
+    // The symlink itself has no such attribute.
+    assert!(xattr::lget(&link, "user.bdrck").unwrap().is_none());
+    assert!(!xattr::llist(&link)
+        .unwrap()
+        .contains(&"user.bdrck".to_string()));
+}
+
+/// Create a directory for listing tests, containing files "a" (10 bytes), "b"
+/// (30 bytes), and "c" (20 bytes), modified in the order c, a, b, as well as a
+/// directory "d", a hidden file ".e", and a symlink "f" to "a".
+fn create_list_test_dir(path: &std::path::Path) {
+    for (name, len, mtime) in [("a", 10, 2000), ("b", 30, 3000), ("c", 20, 1000)] {
+        let file = File::create(path.join(name)).unwrap();
+        file.set_len(len).unwrap();
+        file.set_modified(std::time::UNIX_EPOCH + Duration::from_secs(mtime))
+            .unwrap();
+    }
+    fs::create_dir(path.join("d")).unwrap();
+    fs::write(path.join(".e"), "hidden").unwrap();
+    create_symlink("a", path.join("f")).unwrap();
+}
+
+fn list_names(path: &std::path::Path, options: ListOptions) -> Vec<String> {
+    list_dir(path, options)
+        .unwrap()
+        .map(|entry| entry.unwrap().name().to_string_lossy().into_owned())
+        .collect()
+}
+
+#[test]
+fn test_list_dir_order() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    create_list_test_dir(dir.path());
+
+    assert_eq!(
+        vec!["a", "b", "c", "d", "f"],
+        list_names(
+            dir.path(),
+            ListOptions {
+                order: ListOrder::Name,
+                ..Default::default()
+            }
+        )
+    );
+    let mut unsorted = list_names(dir.path(), ListOptions::default());
+    unsorted.sort();
+    assert_eq!(vec!["a", "b", "c", "d", "f"], unsorted);
+
+    let files_by = |order| {
+        list_names(
+            dir.path(),
+            ListOptions {
+                order,
+                file_type_filter: Some(EntryKind::File),
+                ..Default::default()
+            },
+        )
+    };
+    assert_eq!(vec!["c", "a", "b"], files_by(ListOrder::Mtime));
+    assert_eq!(vec!["a", "c", "b"], files_by(ListOrder::Size));
+
+    let entries: Vec<DirEntryInfo> = list_dir(
+        dir.path(),
+        ListOptions {
+            order: ListOrder::Name,
+            include_hidden: true,
+            ..Default::default()
+        },
+    )
+    .unwrap()
+    .collect::<Result<_>>()
+    .unwrap();
+    let kinds: Vec<(String, EntryKind)> = entries
+        .iter()
+        .map(|e| (e.name().to_string_lossy().into_owned(), e.kind()))
+        .collect();
+    assert_eq!(
+        vec![
+            (".e".to_owned(), EntryKind::File),
+            ("a".to_owned(), EntryKind::File),
+            ("b".to_owned(), EntryKind::File),
+            ("c".to_owned(), EntryKind::File),
+            ("d".to_owned(), EntryKind::Directory),
+            ("f".to_owned(), EntryKind::Symlink),
+        ],
+        kinds
+    );
+    assert_eq!(dir.path().join("b"), entries[2].path());
+    assert_eq!(30, entries[2].size().unwrap());
+    assert_eq!(
+        std::time::UNIX_EPOCH + Duration::from_secs(3000),
+        entries[2].modified().unwrap()
+    );
+    // Symlinks aren't followed.
+    assert!(entries[5].metadata().unwrap().file_type().is_symlink());
+
+    assert_eq!(6, count_entries(dir.path()).unwrap());
+    assert!(list_dir(dir.path().join("missing"), ListOptions::default()).is_err());
+}
+
+#[test]
+fn test_list_dir_lazy_stat() {
+    crate::init().unwrap();
+
+    let listed = |faulty: &FaultyFs, options: ListOptions| -> Vec<DirEntryInfo> {
+        faulty
+            .list_dir(faulty.path(), options)
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap()
+    };
+
+    let faulty = FaultyFs::new("bdrck").unwrap();
+    create_list_test_dir(faulty.path());
+
+    // Neither listing, filtering by type, nor sorting by name needs metadata.
+    let entries = listed(&faulty, ListOptions::default());
+    assert_eq!(5, entries.len());
+    listed(
+        &faulty,
+        ListOptions {
+            order: ListOrder::Name,
+            file_type_filter: Some(EntryKind::Directory),
+            ..Default::default()
+        },
+    );
+    assert_eq!(0, faulty.calls(Operation::Stat));
+
+    // Metadata is fetched on demand, and then cached.
+    entries[0].size().unwrap();
+    entries[0].modified().unwrap();
+    assert_eq!(1, faulty.calls(Operation::Stat));
+
+    // Sorting by metadata, or asking for it eagerly, fetches it once per
+    // entry, and not again afterwards.
+    let entries = listed(
+        &faulty,
+        ListOptions {
+            order: ListOrder::Size,
+            ..Default::default()
+        },
+    );
+    assert_eq!(6, faulty.calls(Operation::Stat));
+    for entry in entries.iter() {
+        entry.metadata().unwrap();
+    }
+    assert_eq!(6, faulty.calls(Operation::Stat));
+    listed(
+        &faulty,
+        ListOptions {
+            stat_mode: StatMode::Eager,
+            ..Default::default()
+        },
+    );
+    assert_eq!(11, faulty.calls(Operation::Stat));
+
+    // A failure fetching one entry's metadata doesn't end the listing.
+    faulty.fail_nth(
+        Operation::Stat,
+        faulty.calls(Operation::Stat) + 2,
+        std::io::ErrorKind::PermissionDenied,
+    );
+    let results: Vec<Result<DirEntryInfo>> = faulty
+        .list_dir(
+            faulty.path(),
+            ListOptions {
+                stat_mode: StatMode::Eager,
+                ..Default::default()
+            },
+        )
+        .unwrap()
+        .collect();
+    assert_eq!(5, results.len());
+    assert_eq!(1, results.iter().filter(|r| r.is_err()).count());
+}
+
+#[test]
+fn test_list_dir_entry_removed_mid_iteration() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let create_files = || {
+        for i in 0..100 {
+            fs::write(dir.path().join(format!("file{}", i)), "x").unwrap();
+        }
+    };
+    let remove_all_except = |keep: &std::path::Path| {
+        for entry in fs::read_dir(dir.path()).unwrap() {
+            let path = entry.unwrap().path();
+            if path != keep {
+                fs::remove_file(path).unwrap();
+            }
+        }
+    };
+
+    // Entries removed after the listing started are skipped when metadata is
+    // fetched eagerly...
+    create_files();
+    let mut entries = list_dir(
+        dir.path(),
+        ListOptions {
+            stat_mode: StatMode::Eager,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let first = entries.next().unwrap().unwrap();
+    remove_all_except(first.path());
+    assert!(entries.collect::<Result<Vec<_>>>().unwrap().is_empty());
+
+    // ... and otherwise may still be listed, but their metadata is
+    // (naturally) unavailable.
+    create_files();
+    let mut entries = list_dir(dir.path(), ListOptions::default()).unwrap();
+    let first = entries.next().unwrap().unwrap();
+    remove_all_except(first.path());
+    for entry in entries {
+        assert!(entry.unwrap().metadata().is_err());
+    }
+    assert!(first.metadata().is_ok());
+}
+
+#[cfg(not(target_os = "windows"))]
+#[test]
+fn test_space() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let info = space(dir.path()).unwrap();
+    assert!(info.total_bytes > 0);
+    assert!(info.free_bytes <= info.total_bytes);
+    assert!(info.available_bytes <= info.free_bytes);
+
+    // A path which doesn't exist yet should resolve to the same filesystem.
+    let missing = dir.sub_path("foo/bar/baz").unwrap();
+    assert!(!missing.exists());
+    let missing_info = space(&missing).unwrap();
+    assert_eq!(info.total_bytes, missing_info.total_bytes);
+    assert_eq!(info.inodes_total, missing_info.inodes_total);
+    assert!(space("relative/path/which/does/not/exist").is_ok());
+}
+
+#[cfg(not(target_os = "windows"))]
+#[test]
+fn test_check_space() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    check_space(dir.path(), 0).unwrap();
+    match check_space(dir.path(), u64::MAX) {
+        Err(Error::Precondition(_)) => {}
+        r => panic!("expected an enormous floor to fail, got {:?}", r),
+    }
+}
+
+#[test]
+fn test_fs_type_from_magic() {
+    crate::init().unwrap();
+
+    assert_eq!(FsType::Tmpfs, FsType::from_magic(0x01021994));
+    assert_eq!(FsType::Ext4, FsType::from_magic(0xef53));
+    assert_eq!("tmpfs", FsType::from_magic(0x01021994).name());
+    assert!(FsType::from_magic(0x6969).is_network());
+    assert!(!FsType::Ext4.is_network());
+    assert!(FsType::Ext4.supports_xattr_hint());
+    assert_eq!(FsType::Unknown(0x1234), FsType::from_magic(0x1234));
+    assert_eq!("unknown (0x1234)", FsType::Unknown(0x1234).to_string());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_filesystem_info() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let info = filesystem_info(dir.path()).unwrap();
+    assert!(info.block_size > 0);
+    assert_eq!(info.fs_type.is_network(), info.is_network);
+    assert_eq!(
+        filesystem_info(dir.sub_path("missing/child").unwrap()).unwrap(),
+        info
+    );
+}
+
+#[test]
+fn test_named_lock_exclusive() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let path = dir.sub_path("task.lock").unwrap();
+    let guard = lock::NamedLock::acquire(&path, lock::LockOptions::default()).unwrap();
+    let info = lock::LockInfo::read(&path).unwrap();
+    assert_eq!(guard.info(), &info);
+    assert_eq!(std::process::id(), info.pid);
+    assert!(!info.is_stale(None));
+
+    match lock::NamedLock::acquire(&path, lock::LockOptions::default()) {
+        Err(Error::Io(e)) => assert_eq!(std::io::ErrorKind::WouldBlock, e.kind()),
+        r => panic!("expected the lock to be held, got {:?}", r),
+    }
+    match lock::NamedLock::acquire(
+        &path,
+        lock::LockOptions {
+            blocking: true,
+            timeout: Some(Duration::from_millis(50)),
+            poll_interval: Duration::from_millis(10),
+            ..Default::default()
+        },
+    ) {
+        Err(Error::Io(e)) => assert_eq!(std::io::ErrorKind::TimedOut, e.kind()),
+        r => panic!("expected the lock to time out, got {:?}", r),
+    }
+
+    drop(guard);
+    assert!(!path.exists());
+    let _guard = lock::NamedLock::acquire(&path, lock::LockOptions::default()).unwrap();
+}
+
+#[test]
+fn test_named_lock_stale_broken_once() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let path = dir.sub_path("task.lock").unwrap();
+    let guard = lock::NamedLock::acquire(&path, lock::LockOptions::default()).unwrap();
+    let mut info = guard.info().clone();
+    std::mem::forget(guard);
+    // Not a stale lock, so it shouldn't be broken.
+    assert!(!lock::NamedLock::break_stale(&path, None).unwrap());
+
+    // Pretend the holder died.
+    info.pid = i32::MAX as u32;
+    fs::write(&path, info.to_string()).unwrap();
+    assert!(lock::LockInfo::read(&path).unwrap().is_dead());
+
+    let barrier = std::sync::Arc::new(std::sync::Barrier::new(2));
+    let breakers: Vec<_> = (0..2)
+        .map(|_| {
+            let barrier = barrier.clone();
+            let path = path.clone();
+            std::thread::spawn(move || {
+                barrier.wait();
+                lock::NamedLock::break_stale(&path, None).unwrap()
+            })
+        })
+        .collect();
+    let broken = breakers
+        .into_iter()
+        .map(|b| b.join().unwrap())
+        .filter(|&broken| broken)
+        .count();
+    assert_eq!(1, broken);
+    assert!(!path.exists());
+    // Nothing else should be left behind.
+    assert_eq!(0, fs::read_dir(dir.path()).unwrap().count());
+
+    // Locks which are too old are stale as well.
+    let guard = lock::NamedLock::acquire(&path, lock::LockOptions::default()).unwrap();
+    let mut info = guard.info().clone();
+    std::mem::forget(guard);
+    info.started = std::time::UNIX_EPOCH;
+    fs::write(&path, info.to_string()).unwrap();
+    let guard = lock::NamedLock::acquire(
+        &path,
+        lock::LockOptions {
+            stale_after: Some(Duration::from_secs(3600)),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    assert_eq!(guard.info(), &lock::LockInfo::read(&path).unwrap());
+}
+
+#[test]
+fn test_named_lock_released_on_panic() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let path = dir.sub_path("task.lock").unwrap();
+    let thread_path = path.clone();
+    let result = std::thread::spawn(move || {
+        let _guard = lock::NamedLock::acquire(&thread_path, lock::LockOptions::default()).unwrap();
+        panic!("holder failed");
+    })
+    .join();
+    assert!(result.is_err());
+    assert!(!path.exists());
+    let _guard = lock::NamedLock::acquire(&path, lock::LockOptions::default()).unwrap();
+}
+
+#[test]
+fn test_display_contract_home() {
+    use std::path::Path;
+
+    crate::init().unwrap();
+
+    let home = Some(Path::new("/home/bdrck"));
+    assert_eq!(
+        "~/src/foo.rs",
+        display::contract_home_with(Path::new("/home/bdrck/src/foo.rs"), home)
+    );
+    assert_eq!(
+        "~",
+        display::contract_home_with(Path::new("/home/bdrck"), home)
+    );
+    // Only whole components are contracted.
+    assert_eq!(
+        "/home/bdrckfoo/bar",
+        display::contract_home_with(Path::new("/home/bdrckfoo/bar"), home)
+    );
+    assert_eq!(
+        "/etc/passwd",
+        display::contract_home_with(Path::new("/etc/passwd"), home)
+    );
+    assert_eq!(
+        "src/foo.rs",
+        display::contract_home_with(Path::new("src/foo.rs"), home)
+    );
+
+    // Without a home directory, nothing is contracted.
+    assert_eq!(
+        "/home/bdrck/src/foo.rs",
+        display::contract_home_with(Path::new("/home/bdrck/src/foo.rs"), None)
+    );
+    assert_eq!(
+        "/home/bdrck/src/foo.rs",
+        display::contract_home_with(Path::new("/home/bdrck/src/foo.rs"), Some(Path::new("/")))
+    );
+
+    // The real environment is consulted by default.
+    if let Some(home) = display::home_dir() {
+        assert_eq!("~/foo", display::contract_home(&home.join("foo")));
+    }
+}
+
+#[test]
+fn test_display_relative_if_shorter() {
+    use std::path::Path;
+
+    crate::init().unwrap();
+
+    let base = Path::new("/home/bdrck/src/project");
+    assert_eq!(
+        Path::new("src/main.rs"),
+        display::relative_if_shorter(Path::new("/home/bdrck/src/project/src/main.rs"), base)
+    );
+    assert_eq!(
+        Path::new("."),
+        display::relative_if_shorter(Path::new("/home/bdrck/src/project"), base)
+    );
+    assert_eq!(
+        Path::new("../other/lib.rs"),
+        display::relative_if_shorter(Path::new("/home/bdrck/src/other/lib.rs"), base)
+    );
+    // Climbing all the way out is longer than the absolute path.
+    assert_eq!(
+        Path::new("/etc/passwd"),
+        display::relative_if_shorter(Path::new("/etc/passwd"), base)
+    );
+    // Relative paths are left alone.
+    assert_eq!(
+        Path::new("foo/bar"),
+        display::relative_if_shorter(Path::new("foo/bar"), base)
+    );
+}
+
+#[test]
+fn test_display_truncate_middle() {
+    crate::init().unwrap();
+
+    let path = "/very/long/directory/structure/leaf.txt";
+    assert_eq!(path, display::truncate_middle(path, 100));
+    assert_eq!(path, display::truncate_middle(path, path.len()));
+    assert_eq!(
+        "/very/long/directory/…/leaf.txt",
+        display::truncate_middle(path, 31)
+    );
+    assert_eq!("/very/…/leaf.txt", display::truncate_middle(path, 16));
+    assert_eq!("…/leaf.txt", display::truncate_middle(path, 10));
+    // The leaf name itself doesn't fit, so it's truncated too.
+    assert_eq!("leaf…txt", display::truncate_middle(path, 8));
+    assert_eq!("l…t", display::truncate_middle(path, 3));
+    assert_eq!("…", display::truncate_middle(path, 1));
+    assert_eq!("", display::truncate_middle(path, 0));
+
+    // Wide characters occupy two columns each.
+    let wide = "/データ/ディレクトリ/ファイル.txt";
+    assert_eq!("/デ…/ファイル.txt", display::truncate_middle(wide, 18));
+    assert_eq!("/デ…/ファイル.txt", display::truncate_middle(wide, 17));
+    assert_eq!("/…/ファイル.txt", display::truncate_middle(wide, 16));
+    assert_eq!("ファ…txt", display::truncate_middle(wide, 8));
+    for width in 0..40 {
+        assert!(display::truncate_middle(wide, width).chars().count() <= width);
+    }
+}
+
+#[test]
+fn test_display_friendly() {
+    use std::path::Path;
+
+    crate::init().unwrap();
+
+    let home = Some(Path::new("/home/bdrck"));
+    let base = Some(Path::new("/home/bdrck/src/project"));
+    let friendly = |path: &str, width| display::friendly_with(Path::new(path), base, home, width);
+
+    assert_eq!(
+        "src/main.rs",
+        friendly("/home/bdrck/src/project/src/main.rs", 80)
+    );
+    assert_eq!(
+        "~/.config/foo.toml",
+        friendly("/home/bdrck/.config/foo.toml", 80)
+    );
+    assert_eq!("/etc/passwd", friendly("/etc/passwd", 80));
+    assert_eq!(
+        "~/.co…/foo.toml",
+        friendly("/home/bdrck/.config/foo.toml", 15)
+    );
+
+    // Applying friendly() to its own output doesn't change it any further.
+    for path in [
+        "/home/bdrck/src/project/src/main.rs",
+        "/home/bdrck/.config/some/deeply/nested/foo.toml",
+        "/var/lib/some/very/long/path/to/a/file.txt",
+        "/home/bdrck/データ/ファイル.txt",
+    ] {
+        for width in [0, 1, 5, 10, 20, 80] {
+            let once = friendly(path, width);
+            assert_eq!(once, friendly(&once, width));
+        }
+    }
+}
+
+#[test]
+fn test_cas_put_get_round_trip() {
+    use crate::crypto::digest::Digest;
+
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let store = cas::Store::open(dir.path()).unwrap();
+    let digest = store.put(&b"hello, world"[..]).unwrap();
+    assert_eq!(Digest::from_bytes(b"hello, world"), digest);
+    assert!(store.contains(&digest).unwrap());
+
+    let mut contents = Vec::new();
+    store
+        .get(&digest)
+        .unwrap()
+        .unwrap()
+        .read_to_end(&mut contents)
+        .unwrap();
+    assert_eq!(b"hello, world".to_vec(), contents);
+    assert_eq!(
+        vec![digest.clone()],
+        store.iter().unwrap().collect::<Vec<_>>()
+    );
+
+    // Objects are stored in a fan-out layout, named after their digest.
+    let path = store.object_path(&digest);
+    assert_eq!(
+        dir.path().join("objects"),
+        path.parent().unwrap().parent().unwrap()
+    );
+    assert_eq!(2, path.parent().unwrap().file_name().unwrap().len());
+
+    assert!(store.remove(&digest).unwrap());
+    assert!(!store.remove(&digest).unwrap());
+    assert!(!store.contains(&digest).unwrap());
+    assert!(store.get(&digest).unwrap().is_none());
+}
+
+#[test]
+fn test_cas_metadata() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    cas::Store::open(dir.path()).unwrap();
+    let metadata = dir.path().join("metadata");
+    assert!(fs::read_to_string(&metadata)
+        .unwrap()
+        .contains(cas::DIGEST_ALGORITHM));
+    // Reopening an existing store is fine.
+    cas::Store::open(dir.path()).unwrap();
+
+    fs::write(&metadata, "version 1\ndigest blake3\n").unwrap();
+    match cas::Store::open(dir.path()) {
+        Err(Error::Unsupported(_)) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("expected an error"),
+    }
+}
+
+#[test]
+fn test_cas_put_deduplicates() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let store = cas::Store::open(dir.path()).unwrap();
+    let digest = store.put(&b"some content"[..]).unwrap();
+    let path = store.object_path(&digest);
+    let old = std::time::UNIX_EPOCH + Duration::from_secs(1000);
+    File::options()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_modified(old)
+        .unwrap();
+
+    // Storing the same content again doesn't rewrite the object.
+    assert_eq!(digest, store.put(&b"some content"[..]).unwrap());
+    assert_eq!(old, fs::metadata(&path).unwrap().modified().unwrap());
+    // Nor does it leave any temporary files behind.
+    assert_eq!(0, fs::read_dir(dir.path().join("tmp")).unwrap().count());
+}
+
+#[test]
+fn test_cas_concurrent_puts() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let root = dir.path().to_path_buf();
+    let handles: Vec<_> = (0..8)
+        .map(|i| {
+            let root = root.clone();
+            std::thread::spawn(move || {
+                // Use a separate Store per thread, like separate processes
+                // would.
+                let store = cas::Store::open(&root).unwrap();
+                let shared = store.put(&vec![7; 100_000][..]).unwrap();
+                let unique = store.put(format!("thread {}", i).as_bytes()).unwrap();
+                (shared, unique)
+            })
+        })
+        .collect();
+    let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+
+    let store = cas::Store::open(&root).unwrap();
+    assert_eq!(9, store.iter().unwrap().count());
+    for (shared, _) in &results {
+        assert_eq!(results[0].0, *shared);
+    }
+    let mut contents = Vec::new();
+    store
+        .get(&results[0].0)
+        .unwrap()
+        .unwrap()
+        .read_to_end(&mut contents)
+        .unwrap();
+    assert_eq!(vec![7; 100_000], contents);
+}
+
+#[test]
+fn test_cas_gc() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let store = cas::Store::open(dir.path()).unwrap();
+    let live = store.put(&b"live"[..]).unwrap();
+    let dead = store.put(&b"dead"[..]).unwrap();
+    let also_dead = store.put(&b"also dead"[..]).unwrap();
+
+    let stats = store.gc(vec![live.clone()]).unwrap();
+    assert_eq!(
+        cas::GcStats {
+            retained: 1,
+            removed: 2,
+            bytes_freed: 13,
+        },
+        stats
+    );
+    assert!(store.contains(&live).unwrap());
+    assert!(!store.contains(&dead).unwrap());
+    assert!(!store.contains(&also_dead).unwrap());
+}
+
+#[test]
+fn test_cas_deep_verify() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let store = cas::Store::open(dir.path()).unwrap();
+    let digest = store.put(&b"precious data"[..]).unwrap();
+    let path = store.object_path(&digest);
+    let mut contents = fs::read(&path).unwrap();
+    contents[0] ^= 1;
+    fs::write(&path, contents).unwrap();
+
+    // A shallow check only looks at the size, so it doesn't notice.
+    assert!(!store.verify(&digest).unwrap());
+    assert!(store.get(&digest).unwrap().is_some());
+    store.put(&b"precious data"[..]).unwrap();
+    assert!(!store.verify(&digest).unwrap());
+
+    // With deep verification, the corruption is detected, and putting the
+    // content again repairs it.
+    let store = store.with_deep_verify(true);
+    assert!(store.get(&digest).is_err());
+    store.put(&b"precious data"[..]).unwrap();
+    assert!(store.verify(&digest).unwrap());
+    assert!(store.get(&digest).unwrap().is_some());
+}
@@ -0,0 +1,242 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::error::*;
+use crate::http::auth::*;
+use crate::http::client::AbstractClient;
+use crate::http::recording::*;
+use crate::http::types::{HeaderMap, HttpData, ResponseMetadata};
+use crate::testing::http::{ReplayMode, TestStubClient};
+use reqwest::header::{HeaderValue, AUTHORIZATION, CONTENT_TYPE};
+use reqwest::{Client, Request, Url};
+use std::collections::VecDeque;
+use std::sync::{Arc, Barrier, Mutex};
+use std::thread;
+
+fn new_request(token: Option<&str>) -> Request {
+    let mut builder = Client::new().get(Url::parse("https://example.com/items").unwrap());
+    if let Some(token) = token {
+        builder = builder.header(AUTHORIZATION, format!("Bearer {}", token));
+    }
+    builder.build().unwrap()
+}
+
+fn new_refresh_request() -> Request {
+    Client::new()
+        .post(Url::parse("https://example.com/token").unwrap())
+        .body("grant_type=refresh_token")
+        .build()
+        .unwrap()
+}
+
+fn new_entry(req: Request, status: u16, body: &str) -> RecordingEntry {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        CONTENT_TYPE.as_str().to_owned(),
+        vec![HttpData::Text("application/json".to_owned())],
+    );
+    RecordingEntry {
+        req: RecordedRequest::from(&req),
+        res: RecordedResponse::from(&(
+            ResponseMetadata {
+                status,
+                headers,
+                from_cache: false,
+                timings: None,
+            },
+            body.as_bytes().to_vec(),
+        )),
+        stream_id: None,
+    }
+}
+
+fn new_client(mode: ReplayMode, entries: Vec<RecordingEntry>) -> TestStubClient {
+    let client = TestStubClient::new_with_mode(mode);
+    client
+        .push_recording(
+            serde_json::to_vec(&Recording(entries.into_iter().collect::<VecDeque<_>>()))
+                .unwrap()
+                .as_slice(),
+        )
+        .unwrap();
+    client
+}
+
+fn new_provider(entries: Vec<RecordingEntry>) -> BearerTokenProvider<TestStubClient> {
+    BearerTokenProvider::new(new_client(ReplayMode::Ordered, entries), |client| {
+        Ok(client
+            .post(Url::parse("https://example.com/token").unwrap())
+            .body("grant_type=refresh_token")
+            .build()?)
+    })
+    .with_token("expired")
+    .unwrap()
+}
+
+#[test]
+fn test_expired_token_is_refreshed() {
+    crate::init().unwrap();
+
+    let client = AuthenticatingClient::new(
+        new_client(
+            ReplayMode::Ordered,
+            vec![
+                new_entry(new_request(Some("expired")), 401, "{}"),
+                new_entry(new_request(Some("fresh")), 200, "[1,2,3]"),
+                // Later requests use the new token right away.
+                new_entry(new_request(Some("fresh")), 200, "[4,5,6]"),
+            ],
+        ),
+        new_provider(vec![new_entry(
+            new_refresh_request(),
+            200,
+            r#"{"access_token": "fresh", "expires_in": 3600}"#,
+        )]),
+    );
+
+    let (metadata, body) = client.execute(new_request(None)).unwrap();
+    assert_eq!(200, metadata.get_status().unwrap().as_u16());
+    assert_eq!(b"[1,2,3]", body.as_slice());
+    let (_, body) = client.execute(new_request(None)).unwrap();
+    assert_eq!(b"[4,5,6]", body.as_slice());
+    assert_eq!(
+        Some(HeaderValue::from_static("Bearer fresh")),
+        client.provider().current().unwrap()
+    );
+}
+
+/// Wraps a provider, making the first `n` calls to `current` wait for each
+/// other, so concurrent requests are all sent with the same credential.
+struct BarrierProvider {
+    inner: BearerTokenProvider<TestStubClient>,
+    barrier: Barrier,
+    remaining: Mutex<usize>,
+}
+
+impl AuthProvider for BarrierProvider {
+    fn current(&self) -> Result<Option<HeaderValue>> {
+        let current = self.inner.current()?;
+        let wait = {
+            let mut remaining = self.remaining.lock().unwrap();
+            let wait = *remaining > 0;
+            *remaining = remaining.saturating_sub(1);
+            wait
+        };
+        if wait {
+            self.barrier.wait();
+        }
+        Ok(current)
+    }
+
+    fn refresh(&self) -> Result<HeaderValue> {
+        self.inner.refresh()
+    }
+}
+
+#[test]
+fn test_concurrent_requests_share_refresh() {
+    crate::init().unwrap();
+
+    // The refresh endpoint can only be replayed once, so a second refresh
+    // would fail.
+    let client = Arc::new(AuthenticatingClient::new(
+        new_client(
+            ReplayMode::Unordered,
+            vec![
+                new_entry(new_request(Some("expired")), 401, "{}"),
+                new_entry(new_request(Some("expired")), 401, "{}"),
+                new_entry(new_request(Some("fresh")), 200, "[]"),
+                new_entry(new_request(Some("fresh")), 200, "[]"),
+            ],
+        ),
+        BarrierProvider {
+            inner: new_provider(vec![new_entry(
+                new_refresh_request(),
+                200,
+                r#"{"access_token": "fresh"}"#,
+            )]),
+            barrier: Barrier::new(2),
+            remaining: Mutex::new(2),
+        },
+    ));
+
+    let threads: Vec<_> = (0..2)
+        .map(|_| {
+            let client = client.clone();
+            thread::spawn(move || {
+                let (metadata, _) = client.execute(new_request(None)).unwrap();
+                metadata.get_status().unwrap().as_u16()
+            })
+        })
+        .collect();
+    for thread in threads {
+        assert_eq!(200, thread.join().unwrap());
+    }
+}
+
+#[test]
+fn test_failed_refresh() {
+    crate::init().unwrap();
+
+    let client = AuthenticatingClient::new(
+        new_client(
+            ReplayMode::Ordered,
+            vec![new_entry(new_request(Some("expired")), 401, "{}")],
+        ),
+        new_provider(vec![new_entry(
+            new_refresh_request(),
+            400,
+            r#"{"error": "invalid_grant"}"#,
+        )]),
+    );
+
+    match client.execute(new_request(None)) {
+        Err(Error::AuthenticationFailed {
+            status,
+            body_prefix,
+        }) => {
+            assert_eq!(400, status);
+            assert!(body_prefix.contains("invalid_grant"));
+        }
+        r => panic!("expected an authentication error, got {:?}", r),
+    }
+    assert_eq!(
+        Some(HeaderValue::from_static("Bearer expired")),
+        client.provider().current().unwrap()
+    );
+}
+
+#[test]
+fn test_rejected_retry() {
+    crate::init().unwrap();
+
+    let client = AuthenticatingClient::new(
+        new_client(
+            ReplayMode::Ordered,
+            vec![
+                new_entry(new_request(Some("expired")), 401, "{}"),
+                new_entry(new_request(Some("fresh")), 403, r#"{"error": "denied"}"#),
+            ],
+        ),
+        new_provider(vec![new_entry(
+            new_refresh_request(),
+            200,
+            r#"{"access_token": "fresh"}"#,
+        )]),
+    );
+
+    match client.execute(new_request(None)) {
+        Err(Error::AuthenticationFailed { status, .. }) => assert_eq!(403, status),
+        r => panic!("expected an authentication error, got {:?}", r),
+    }
+}
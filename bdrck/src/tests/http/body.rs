@@ -0,0 +1,166 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::error::*;
+use crate::http::body::RequestBody;
+use crate::http::client::AbstractClient;
+use crate::http::recording::*;
+use crate::http::types::{HeaderMap, HttpData, ResponseMetadata};
+use crate::testing::http::TestStubClient;
+use reqwest::header::CONTENT_LENGTH;
+use reqwest::{Client, Method, Request, Url};
+use std::collections::VecDeque;
+use std::io::Cursor;
+use std::sync::{Arc, Mutex};
+
+const TEST_URL: &str = "https://example.com/upload";
+
+fn new_request() -> Request {
+    Client::new()
+        .put(Url::parse(TEST_URL).unwrap())
+        .build()
+        .unwrap()
+}
+
+fn new_body_data(len: usize, seed: u8) -> Vec<u8> {
+    (0..len)
+        .map(|i| (i as u8).wrapping_mul(31) ^ seed)
+        .collect()
+}
+
+fn record(data: &[u8], len: Option<u64>) -> RecordedRequest {
+    let mut request = new_request();
+    let mut capture = BodyCapture::default();
+    RequestBody::from_reader(Box::new(Cursor::new(data.to_vec())), len)
+        .consume(&mut request, Box::new(|d| capture.update(d)))
+        .unwrap();
+    let mut recorded = RecordedRequest::from(&request);
+    recorded.set_body_capture(capture);
+    recorded
+}
+
+fn new_recording(reqs: Vec<RecordedRequest>) -> Vec<u8> {
+    let mut entries = VecDeque::new();
+    for req in reqs {
+        entries.push_back(RecordingEntry {
+            req,
+            res: RecordedResponse::from(&(
+                ResponseMetadata {
+                    status: 200,
+                    headers: HeaderMap::new(),
+                    from_cache: false,
+                    timings: None,
+                },
+                b"ok".to_vec(),
+            )),
+            stream_id: None,
+        });
+    }
+    serde_json::to_vec(&Recording(entries)).unwrap()
+}
+
+#[test]
+fn test_small_streamed_body_recorded_in_full() {
+    crate::init().unwrap();
+
+    let recorded = record(b"hello, world!", None);
+    assert_eq!(Some("hello, world!".to_owned()), recorded.body);
+    assert!(recorded.body_digest.is_none());
+    // With an unknown length, chunked transfer is used instead.
+    assert!(!recorded.headers.contains_key(CONTENT_LENGTH.as_str()));
+}
+
+#[test]
+fn test_streamed_upload_matched_by_digest() {
+    crate::init().unwrap();
+
+    let len = RECORDED_BODY_DIGEST_THRESHOLD as usize * 3 + 17;
+    let data = new_body_data(len, 0);
+    let recorded = record(data.as_slice(), Some(len as u64));
+    assert!(recorded.body.is_none());
+    let digest = recorded.body_digest.clone().unwrap();
+    assert_eq!(len as u64, digest.len);
+    assert_eq!(64, digest.sha256.len());
+    assert_eq!(
+        vec![HttpData::Text(len.to_string())],
+        recorded.headers[CONTENT_LENGTH.as_str()]
+    );
+
+    // A different body of the same length must not match.
+    assert!(!recorded.matches(&record(new_body_data(len, 1).as_slice(), Some(len as u64))));
+
+    let client = TestStubClient::new();
+    client
+        .push_recording(new_recording(vec![recorded]).as_slice())
+        .unwrap();
+    let progress = Arc::new(Mutex::new(Vec::new()));
+    let progress_copy = progress.clone();
+    let body = RequestBody::from_reader(Box::new(Cursor::new(data)), Some(len as u64))
+        .with_progress(Box::new(move |sent| {
+            progress_copy.lock().unwrap().push(sent)
+        }));
+    let (metadata, res) = client.execute_body(new_request(), body).unwrap();
+    assert_eq!(200, metadata.get_status().unwrap().as_u16());
+    assert_eq!(b"ok".to_vec(), res);
+
+    let progress = progress.lock().unwrap();
+    assert!(progress.len() > 1);
+    assert_eq!(len as u64, *progress.last().unwrap());
+}
+
+#[test]
+fn test_retry_rejects_reader_body() {
+    crate::init().unwrap();
+
+    let client = TestStubClient::new();
+    let body = RequestBody::from_reader(Box::new(Cursor::new(b"foo".to_vec())), Some(3));
+    assert!(!body.is_replayable());
+    match client.execute_with_retries_body(
+        3,
+        false,
+        Method::PUT,
+        Url::parse(TEST_URL).unwrap(),
+        None,
+        Some(body),
+    ) {
+        Err(Error::InvalidArgument(msg)) => assert!(msg.contains("from_factory")),
+        r => panic!("expected InvalidArgument error, got {:?}", r.map(|_| ())),
+    }
+}
+
+#[test]
+fn test_retry_accepts_factory_body() {
+    crate::init().unwrap();
+
+    let client = TestStubClient::new();
+    client
+        .push_recording(new_recording(vec![record(b"foo", Some(3))]).as_slice())
+        .unwrap();
+    let body = RequestBody::from_factory(
+        Box::new(|| Ok(Box::new(Cursor::new(b"foo".to_vec())))),
+        Some(3),
+    );
+    assert!(body.is_replayable());
+    let (metadata, _) = client
+        .execute_with_retries_body(
+            3,
+            false,
+            Method::PUT,
+            Url::parse(TEST_URL).unwrap(),
+            None,
+            Some(body),
+        )
+        .unwrap();
+    assert_eq!(200, metadata.get_status().unwrap().as_u16());
+}
@@ -0,0 +1,155 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::http::cache::*;
+use crate::http::client::AbstractClient;
+use crate::http::recording::*;
+use crate::http::types::{HeaderMap, HttpData, ResponseMetadata};
+use crate::testing::http::TestStubClient;
+use crate::testing::temp;
+use reqwest::header::IF_NONE_MATCH;
+use reqwest::{Client, Request, Url};
+use std::collections::VecDeque;
+use std::fs;
+
+const TEST_BODY: &[u8] = b"{\"items\":[1,2,3]}";
+
+fn new_request(path: &str, etag: Option<&str>) -> Request {
+    let mut builder =
+        Client::new().get(Url::parse(&format!("https://example.com{}", path)).unwrap());
+    if let Some(etag) = etag {
+        builder = builder.header(IF_NONE_MATCH, etag);
+    }
+    builder.build().unwrap()
+}
+
+fn new_entry(req: Request, status: u16, etag: &str, body: &[u8]) -> RecordingEntry {
+    let mut headers = HeaderMap::new();
+    headers.insert("etag".to_owned(), vec![HttpData::Text(etag.to_owned())]);
+    RecordingEntry {
+        req: RecordedRequest::from(&req),
+        res: RecordedResponse::from(&(
+            ResponseMetadata {
+                status,
+                headers,
+                from_cache: false,
+                timings: None,
+            },
+            body.to_vec(),
+        )),
+        stream_id: None,
+    }
+}
+
+fn new_client(entries: Vec<RecordingEntry>) -> TestStubClient {
+    let client = TestStubClient::new();
+    client
+        .push_recording(
+            serde_json::to_vec(&Recording(entries.into_iter().collect::<VecDeque<_>>()))
+                .unwrap()
+                .as_slice(),
+        )
+        .unwrap();
+    client
+}
+
+fn count_entries(dir: &temp::Dir) -> usize {
+    fs::read_dir(dir.path())
+        .unwrap()
+        .filter(|e| e.as_ref().unwrap().path().extension().is_some())
+        .count()
+}
+
+#[test]
+fn test_cache_revalidation() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let client = CachingClient::new(
+        new_client(vec![
+            // The first request is unconditional, and populates the cache.
+            new_entry(new_request("/items", None), 200, "\"v1\"", TEST_BODY),
+            // The second should revalidate the cached response.
+            new_entry(new_request("/items", Some("\"v1\"")), 304, "\"v1\"", b""),
+        ]),
+        dir.path(),
+    )
+    .unwrap();
+
+    let (metadata, body) = client.execute(new_request("/items", None)).unwrap();
+    assert_eq!(200, metadata.get_status().unwrap().as_u16());
+    assert!(!metadata.is_from_cache());
+    assert_eq!(TEST_BODY, body.as_slice());
+    assert_eq!(1, count_entries(&dir));
+
+    let (metadata, body) = client.execute(new_request("/items", None)).unwrap();
+    assert_eq!(200, metadata.get_status().unwrap().as_u16());
+    assert!(metadata.is_from_cache());
+    assert_eq!(TEST_BODY, body.as_slice());
+}
+
+#[test]
+fn test_cache_clear() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let client = CachingClient::new(
+        new_client(vec![
+            new_entry(new_request("/items", None), 200, "\"v1\"", TEST_BODY),
+            // After clearing, the request should be unconditional again.
+            new_entry(new_request("/items", None), 200, "\"v2\"", TEST_BODY),
+        ]),
+        dir.path(),
+    )
+    .unwrap();
+
+    client.execute(new_request("/items", None)).unwrap();
+    assert_eq!(1, count_entries(&dir));
+    client.clear().unwrap();
+    assert_eq!(0, count_entries(&dir));
+    let (metadata, _) = client.execute(new_request("/items", None)).unwrap();
+    assert!(!metadata.is_from_cache());
+}
+
+#[test]
+fn test_cache_eviction() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let client = CachingClient::new(
+        new_client(vec![
+            new_entry(new_request("/a", None), 200, "\"a\"", TEST_BODY),
+            new_entry(new_request("/b", None), 200, "\"b\"", TEST_BODY),
+            new_entry(new_request("/c", None), 200, "\"c\"", TEST_BODY),
+            // "/a" was evicted, so this request is unconditional...
+            new_entry(new_request("/a", None), 200, "\"a\"", TEST_BODY),
+            // ... but "/c" is still cached.
+            new_entry(new_request("/c", Some("\"c\"")), 304, "\"c\"", b""),
+        ]),
+        dir.path(),
+    )
+    .unwrap()
+    .with_max_entries(2);
+
+    for path in &["/a", "/b", "/c"] {
+        client.execute(new_request(path, None)).unwrap();
+        // Make sure each entry gets a distinct modification time.
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        assert!(count_entries(&dir) <= 2);
+    }
+    let (metadata, _) = client.execute(new_request("/a", None)).unwrap();
+    assert!(!metadata.is_from_cache());
+    let (metadata, _) = client.execute(new_request("/c", None)).unwrap();
+    assert!(metadata.is_from_cache());
+}
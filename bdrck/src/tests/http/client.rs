@@ -15,6 +15,7 @@
 use crate::error::*;
 use crate::http::client::*;
 use crate::http::types::{HeaderMap, ResponseMetadata};
+use crate::testing::clock::{Clock, MockClock};
 use reqwest::Client as InnerClient;
 use reqwest::{Method, Request, RequestBuilder, Url};
 use std::cell::RefCell;
@@ -44,6 +45,8 @@ impl AbstractClient for RetriesTestClient {
             ResponseMetadata {
                 status: 503,
                 headers: HeaderMap::new(),
+                from_cache: false,
+                timings: None,
             },
             Vec::new(),
         ))
@@ -156,3 +159,28 @@ fn test_trait_object_works() {
         )
         .is_err());
 }
+
+#[test]
+fn test_execute_with_retries_clock() {
+    crate::init().unwrap();
+
+    let client = RetriesTestClient::new();
+    let clock = MockClock::default();
+    let start = clock.now_instant();
+    assert!(client
+        .execute_with_retries_clock(
+            &clock,
+            3,
+            false,
+            Method::GET,
+            "http://www.google.com/".parse().unwrap(),
+            None,
+            None
+        )
+        .is_err());
+    assert_eq!(4, client.requests.borrow().len());
+    // The client's own sleep hook should have been bypassed entirely, in favor
+    // of the clock (which doesn't really sleep).
+    assert!(client.sleeps.borrow().is_empty());
+    assert_eq!(Duration::from_millis(700), clock.now_instant() - start);
+}
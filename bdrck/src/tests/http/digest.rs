@@ -0,0 +1,173 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::crypto::digest::Digest;
+use crate::error::*;
+use crate::http::client::AbstractClient;
+use crate::http::recording::*;
+use crate::http::types::{HeaderMap, ResponseMetadata};
+use crate::testing::http::TestStubClient;
+use crate::testing::temp;
+use reqwest::{Client, Request, Url};
+use std::collections::VecDeque;
+use std::fs;
+
+const TEST_URL: &str = "https://example.com/release.tar.gz";
+const TEST_BODY: &[u8] = b"this is a release artifact";
+
+fn new_request() -> Request {
+    Client::new()
+        .get(Url::parse(TEST_URL).unwrap())
+        .build()
+        .unwrap()
+}
+
+fn new_client() -> TestStubClient {
+    let mut entries = VecDeque::new();
+    entries.push_back(RecordingEntry {
+        req: RecordedRequest::from(&new_request()),
+        res: RecordedResponse::from(&(
+            ResponseMetadata {
+                status: 200,
+                headers: HeaderMap::new(),
+                from_cache: false,
+                timings: None,
+            },
+            TEST_BODY.to_vec(),
+        )),
+        stream_id: None,
+    });
+    let client = TestStubClient::new();
+    client
+        .push_recording(serde_json::to_vec(&Recording(entries)).unwrap().as_slice())
+        .unwrap();
+    client
+}
+
+#[test]
+fn test_execute_verified() {
+    crate::init().unwrap();
+
+    let expected = Digest::from_bytes(TEST_BODY);
+    let (_, body) = new_client()
+        .execute_verified(new_request(), Some(&expected))
+        .unwrap();
+    assert_eq!(TEST_BODY, body.as_slice());
+
+    // Without a digest, nothing is verified.
+    let (_, body) = new_client().execute_verified(new_request(), None).unwrap();
+    assert_eq!(TEST_BODY, body.as_slice());
+}
+
+#[test]
+fn test_execute_verified_mismatch() {
+    crate::init().unwrap();
+
+    let expected = Digest::from_bytes(b"some other artifact");
+    match new_client().execute_verified(new_request(), Some(&expected)) {
+        Err(Error::DigestMismatch {
+            expected: e,
+            actual: a,
+        }) => {
+            assert_eq!(expected, *e);
+            assert_eq!(Digest::from_bytes(TEST_BODY), *a);
+        }
+        r => panic!("expected DigestMismatch error, got {:?}", r.map(|_| ())),
+    }
+}
+
+#[test]
+fn test_download_to() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let dest = dir.sub_path("release.tar.gz").unwrap();
+    let expected = Digest::from_bytes(TEST_BODY);
+    let metadata = new_client()
+        .download_to(new_request(), &dest, Some(&expected))
+        .unwrap();
+    assert_eq!(200, metadata.get_status().unwrap().as_u16());
+    assert_eq!(TEST_BODY, fs::read(&dest).unwrap().as_slice());
+    // Only the destination file should be left behind.
+    assert_eq!(1, fs::read_dir(dir.path()).unwrap().count());
+}
+
+#[test]
+fn test_download_to_mismatch() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let dest = dir.sub_path("release.tar.gz").unwrap();
+    let expected = Digest::from_bytes(b"some other artifact");
+    match new_client().download_to(new_request(), &dest, Some(&expected)) {
+        Err(Error::DigestMismatch { .. }) => {}
+        r => panic!("expected DigestMismatch error, got {:?}", r.map(|_| ())),
+    }
+    assert!(!dest.exists());
+    // The temporary file should have been cleaned up, too.
+    assert_eq!(0, fs::read_dir(dir.path()).unwrap().count());
+}
+
+#[test]
+fn test_download_to_large_recorded_body() {
+    crate::init().unwrap();
+
+    // Large bodies are recorded only as a digest, which is still verified.
+    let body: Vec<u8> = (0..RECORDED_BODY_DIGEST_THRESHOLD + 1)
+        .map(|i| (i % 251) as u8)
+        .collect();
+    let mut capture = ResponseCapture::default();
+    for chunk in body.chunks(4096) {
+        capture.update(chunk);
+    }
+    let res = capture.into_response(ResponseMetadata {
+        status: 200,
+        headers: HeaderMap::new(),
+        from_cache: false,
+        timings: None,
+    });
+    assert_eq!(
+        Some(body.len() as u64),
+        res.body_digest.as_ref().map(|d| d.len)
+    );
+    let mut entries = VecDeque::new();
+    entries.push_back(RecordingEntry {
+        req: RecordedRequest::from(&new_request()),
+        res,
+        stream_id: None,
+    });
+    let recording = Recording(entries).to_vec().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let dest = dir.sub_path("release.tar.gz").unwrap();
+    let client = TestStubClient::new();
+    client.push_recording(recording.as_slice()).unwrap();
+    client.push_recording(recording.as_slice()).unwrap();
+
+    let expected = Digest::from_bytes(body.as_slice());
+    client
+        .download_to(new_request(), &dest, Some(&expected))
+        .unwrap();
+    assert!(dest.exists());
+    fs::remove_file(&dest).unwrap();
+
+    let expected = Digest::from_bytes(b"some other artifact");
+    match client.download_to(new_request(), &dest, Some(&expected)) {
+        Err(Error::DigestMismatch { actual, .. }) => {
+            assert_eq!(Digest::from_bytes(body.as_slice()), *actual);
+        }
+        r => panic!("expected DigestMismatch error, got {:?}", r.map(|_| ())),
+    }
+    assert!(!dest.exists());
+}
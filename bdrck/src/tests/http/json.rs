@@ -0,0 +1,221 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::error::*;
+use crate::http::client::AbstractClient;
+use crate::http::recording::*;
+use crate::http::types::{ApiResult, HeaderMap, HttpData, ResponseMetadata};
+use crate::testing::http::TestStubClient;
+use reqwest::{Client, Url};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+
+const TEST_URL: &str = "https://example.com/api/widgets";
+
+#[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
+struct Widget {
+    name: String,
+    size: u32,
+}
+
+#[derive(Debug, Deserialize, Eq, PartialEq)]
+struct ApiError {
+    message: String,
+}
+
+fn json_request(body: Option<&str>) -> RecordedRequest {
+    let mut headers = HashMap::new();
+    headers.insert(
+        "accept".to_string(),
+        vec![HttpData::Text("application/json".to_string())],
+    );
+    if body.is_some() {
+        headers.insert(
+            "content-type".to_string(),
+            vec![HttpData::Text("application/json".to_string())],
+        );
+    }
+    RecordedRequest {
+        method: match body {
+            None => "GET",
+            Some(_) => "POST",
+        }
+        .to_string(),
+        url: TEST_URL.to_string(),
+        headers,
+        body: body.map(|b| b.to_string()),
+        body_digest: None,
+        proxy: None,
+    }
+}
+
+fn new_client(
+    req: RecordedRequest,
+    status: u16,
+    content_type: Option<&str>,
+    body: &str,
+) -> TestStubClient {
+    let mut headers = HeaderMap::new();
+    if let Some(content_type) = content_type {
+        headers.insert(
+            "content-type".to_string(),
+            vec![HttpData::Text(content_type.to_string())],
+        );
+    }
+    let mut entries = VecDeque::new();
+    entries.push_back(RecordingEntry {
+        req,
+        res: RecordedResponse::from(&(
+            ResponseMetadata {
+                status,
+                headers,
+                from_cache: false,
+                timings: None,
+            },
+            body.as_bytes().to_vec(),
+        )),
+        stream_id: None,
+    });
+    let client = TestStubClient::new();
+    client
+        .push_recording(serde_json::to_vec(&Recording(entries)).unwrap().as_slice())
+        .unwrap();
+    client
+}
+
+#[test]
+fn test_json_round_trip() {
+    crate::init().unwrap();
+
+    let widget = Widget {
+        name: "sprocket".to_string(),
+        size: 3,
+    };
+    let body = serde_json::to_string(&widget).unwrap();
+
+    let client = new_client(
+        json_request(None),
+        200,
+        Some("application/json; charset=utf-8"),
+        body.as_str(),
+    );
+    let fetched: Widget = client.get_json(Url::parse(TEST_URL).unwrap()).unwrap();
+    assert_eq!(widget, fetched);
+
+    let client = new_client(
+        json_request(Some(body.as_str())),
+        201,
+        Some("application/vnd.example+json"),
+        body.as_str(),
+    );
+    let created: Widget = client
+        .post_json(Url::parse(TEST_URL).unwrap(), &widget)
+        .unwrap();
+    assert_eq!(widget, created);
+}
+
+#[test]
+fn test_json_error_body() {
+    crate::init().unwrap();
+
+    let error_body = r#"{"message":"size must be positive"}"#;
+    let request = Client::new()
+        .get(Url::parse(TEST_URL).unwrap())
+        .header("accept", "application/json")
+        .build()
+        .unwrap();
+    let client = new_client(
+        json_request(None),
+        422,
+        Some("application/json"),
+        error_body,
+    );
+    let result: ApiResult<Widget, ApiError> = client.execute_json(request).unwrap();
+    assert_eq!(
+        ApiResult::Err {
+            status: 422,
+            error: ApiError {
+                message: "size must be positive".to_string(),
+            },
+        },
+        result
+    );
+
+    // Without an error body type, the status itself is the error.
+    let client = new_client(
+        json_request(None),
+        422,
+        Some("application/json"),
+        error_body,
+    );
+    match client.get_json::<Widget>(Url::parse(TEST_URL).unwrap()) {
+        Err(Error::HttpStatus {
+            status,
+            body_prefix,
+        }) => {
+            assert_eq!(422, status);
+            assert_eq!(error_body, body_prefix);
+        }
+        other => panic!("expected HttpStatus error, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_json_content_type_mismatch() {
+    crate::init().unwrap();
+
+    let html = format!("<html>{}</html>", "x".repeat(1000));
+    let client = new_client(json_request(None), 200, Some("text/html"), html.as_str());
+    match client.get_json::<Widget>(Url::parse(TEST_URL).unwrap()) {
+        Err(Error::HttpContentType {
+            content_type,
+            body_prefix,
+        }) => {
+            assert_eq!("text/html", content_type);
+            assert!(body_prefix.starts_with("<html>xxx"));
+            assert!(body_prefix.ends_with("..."));
+            assert!(body_prefix.len() < 100);
+        }
+        other => panic!("expected HttpContentType error, got {:?}", other),
+    }
+
+    // A missing content type is a mismatch too.
+    let client = new_client(json_request(None), 200, None, "{}");
+    assert_eq!(
+        ErrorCode::HttpContentType,
+        client
+            .get_json::<Widget>(Url::parse(TEST_URL).unwrap())
+            .unwrap_err()
+            .code()
+    );
+}
+
+#[test]
+fn test_json_malformed_body() {
+    crate::init().unwrap();
+
+    let client = new_client(
+        json_request(None),
+        200,
+        Some("application/json"),
+        "{\"name\": \"sprocket\",\n \"size\": }",
+    );
+    match client.get_json::<Widget>(Url::parse(TEST_URL).unwrap()) {
+        Err(Error::Json(e)) => {
+            assert_eq!(2, e.line());
+            assert_eq!(10, e.column());
+            assert!(e.to_string().contains("line 2 column 10"));
+        }
+        other => panic!("expected Json error, got {:?}", other),
+    }
+}
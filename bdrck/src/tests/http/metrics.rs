@@ -0,0 +1,216 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::http::client::AbstractClient;
+use crate::http::metrics::*;
+use crate::http::recording::*;
+use crate::http::types::{HeaderMap, RequestTimings, ResponseMetadata};
+use crate::testing::clock::MockClock;
+use crate::testing::http::TestStubClient;
+use reqwest::{Client, Method, Request, Url};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+
+fn new_request(method: Method, path: &str, body: Option<&str>) -> Request {
+    let url = Url::parse(&format!("https://example.com/{}", path)).unwrap();
+    let mut builder = Client::new().request(method, url);
+    if let Some(body) = body {
+        builder = builder.body(body.to_string());
+    }
+    builder.build().unwrap()
+}
+
+fn millis(ms: u64) -> Duration {
+    Duration::from_millis(ms)
+}
+
+fn total(ms: u64) -> Option<RequestTimings> {
+    Some(RequestTimings {
+        total: millis(ms),
+        ..Default::default()
+    })
+}
+
+/// Build a client which replays the given interactions, going through the
+/// on-disk recording format so timings are round-tripped too.
+fn replay_client(
+    interactions: Vec<(Request, u16, &str, Option<RequestTimings>)>,
+) -> MeteredClient<TestStubClient> {
+    let entries: VecDeque<RecordingEntry> = interactions
+        .iter()
+        .map(|(req, status, body, timings)| RecordingEntry {
+            req: RecordedRequest::from(req),
+            res: RecordedResponse::from(&(
+                ResponseMetadata {
+                    status: *status,
+                    headers: HeaderMap::new(),
+                    from_cache: false,
+                    timings: timings.clone(),
+                },
+                body.as_bytes().to_vec(),
+            )),
+            stream_id: None,
+        })
+        .collect();
+    let stub = TestStubClient::new();
+    stub.push_recording(Recording(entries).to_vec().unwrap().as_slice())
+        .unwrap();
+    MeteredClient::new(stub).with_clock(Arc::new(MockClock::default()))
+}
+
+fn scripted_session() -> Vec<(Request, u16, &'static str, Option<RequestTimings>)> {
+    vec![
+        (
+            new_request(Method::GET, "a", None),
+            200,
+            "first",
+            Some(RequestTimings {
+                dns: Some(millis(1)),
+                connect: Some(millis(5)),
+                tls: Some(millis(10)),
+                first_byte: Some(millis(30)),
+                total: millis(40),
+                connection_reused: Some(false),
+            }),
+        ),
+        (
+            new_request(Method::GET, "b", None),
+            200,
+            "second",
+            Some(RequestTimings {
+                first_byte: Some(millis(10)),
+                total: millis(20),
+                connection_reused: Some(true),
+                ..Default::default()
+            }),
+        ),
+        (
+            new_request(Method::POST, "c", Some("hello")),
+            404,
+            "",
+            total(30),
+        ),
+        // Without timings, the (mock) clock is used instead.
+        (new_request(Method::GET, "d", None), 503, "oops", None),
+    ]
+}
+
+fn run_session(client: &MeteredClient<TestStubClient>) {
+    for (req, _, _, timings) in scripted_session() {
+        let (metadata, _) = client.execute(req).unwrap();
+        assert_eq!(timings.as_ref(), metadata.get_timings());
+    }
+}
+
+#[test]
+fn test_metrics_after_replayed_session() {
+    crate::init().unwrap();
+
+    let client = replay_client(scripted_session());
+    assert_eq!(ClientMetrics::default(), client.metrics());
+    run_session(&client);
+
+    assert_eq!(
+        ClientMetrics {
+            requests: 4,
+            errors: 0,
+            responses_by_class: [0, 2, 0, 1, 1],
+            bytes_sent: 5,
+            bytes_received: 15,
+            connections_opened: 1,
+            connections_reused: 1,
+            tls_handshakes: 1,
+            latency_p50: Some(millis(20)),
+            latency_p95: Some(millis(40)),
+        },
+        client.metrics()
+    );
+}
+
+#[test]
+fn test_latency_percentiles() {
+    crate::init().unwrap();
+
+    let interactions = (1..=100)
+        .map(|ms| (new_request(Method::GET, "", None), 200, "", total(ms)))
+        .collect();
+    let client = replay_client(interactions);
+    for _ in 0..100 {
+        client.execute(new_request(Method::GET, "", None)).unwrap();
+    }
+    let metrics = client.metrics();
+    assert_eq!(Some(millis(50)), metrics.latency_p50);
+    assert_eq!(Some(millis(95)), metrics.latency_p95);
+
+    // Only the most recent samples are kept.
+    let interactions = (1..=100)
+        .map(|ms| (new_request(Method::GET, "", None), 200, "", total(ms)))
+        .collect();
+    let client = replay_client(interactions).with_max_samples(10);
+    for _ in 0..100 {
+        client.execute(new_request(Method::GET, "", None)).unwrap();
+    }
+    let metrics = client.metrics();
+    assert_eq!(100, metrics.requests);
+    assert_eq!(Some(millis(95)), metrics.latency_p50);
+    assert_eq!(Some(millis(100)), metrics.latency_p95);
+}
+
+#[test]
+fn test_reset_metrics() {
+    crate::init().unwrap();
+
+    let mut interactions = scripted_session();
+    interactions.extend(scripted_session());
+    let client = replay_client(interactions);
+
+    run_session(&client);
+    let before = client.metrics();
+    assert_eq!(4, before.requests);
+    assert_eq!(before, client.reset_metrics());
+    assert_eq!(ClientMetrics::default(), client.metrics());
+
+    // After a reset, counting starts over from scratch.
+    run_session(&client);
+    assert_eq!(before, client.metrics());
+}
+
+#[cfg(feature = "cli")]
+#[test]
+fn test_stats_table() {
+    crate::init().unwrap();
+
+    let client = replay_client(scripted_session());
+    run_session(&client);
+    let expected = concat!(
+        "metric               value\n",
+        "------------------  ------\n",
+        "requests                 4\n",
+        "errors                   0\n",
+        "responses 1xx            0\n",
+        "responses 2xx            2\n",
+        "responses 3xx            0\n",
+        "responses 4xx            1\n",
+        "responses 5xx            1\n",
+        "bytes sent               5\n",
+        "bytes received          15\n",
+        "connections opened       1\n",
+        "connections reused       1\n",
+        "TLS handshakes           1\n",
+        "latency p50         20.0ms\n",
+        "latency p95         40.0ms\n",
+    );
+    assert_eq!(expected, client.metrics().to_table().render(80));
+}
@@ -12,7 +12,35 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+#[cfg(debug_assertions)]
+#[cfg(test)]
+mod auth;
+#[cfg(debug_assertions)]
+#[cfg(test)]
+mod body;
+#[cfg(debug_assertions)]
+#[cfg(test)]
+mod cache;
 #[cfg(test)]
 mod client;
+#[cfg(debug_assertions)]
+#[cfg(test)]
+mod digest;
+#[cfg(debug_assertions)]
+#[cfg(test)]
+mod json;
+#[cfg(debug_assertions)]
+#[cfg(test)]
+mod metrics;
+#[cfg(test)]
+mod proxy;
+#[cfg(debug_assertions)]
+#[cfg(test)]
+mod recording;
+#[cfg(test)]
+mod types;
+#[cfg(unix)]
+#[cfg(test)]
+mod unix;
 #[cfg(test)]
 mod util;
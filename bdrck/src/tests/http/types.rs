@@ -0,0 +1,146 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::http::types::*;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use reqwest::{Method, Request, Url};
+
+#[derive(Debug, Eq, PartialEq)]
+struct ParsedPart {
+    headers: Vec<String>,
+    data: Vec<u8>,
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// A minimal multipart/form-data parser, to check the structure of bodies we
+/// generate.
+fn parse_multipart(body: &[u8], boundary: &str) -> Vec<ParsedPart> {
+    let delimiter = format!("--{}", boundary);
+    let mut rest = body;
+    let mut parts = Vec::new();
+    assert!(rest.starts_with(delimiter.as_bytes()));
+    loop {
+        rest = &rest[delimiter.len()..];
+        if rest == b"--\r\n" {
+            return parts;
+        }
+        assert!(rest.starts_with(b"\r\n"));
+        rest = &rest[2..];
+
+        let headers_end = find(rest, b"\r\n\r\n").unwrap();
+        let headers = std::str::from_utf8(&rest[..headers_end])
+            .unwrap()
+            .split("\r\n")
+            .map(|h| h.to_owned())
+            .collect();
+        rest = &rest[headers_end + 4..];
+
+        let next = find(rest, format!("\r\n{}", delimiter).as_bytes()).unwrap();
+        parts.push(ParsedPart {
+            headers,
+            data: rest[..next].to_vec(),
+        });
+        rest = &rest[next + 2..];
+    }
+}
+
+#[test]
+fn test_multipart_structure() {
+    crate::init().unwrap();
+
+    let multipart = MultipartBuilder::new()
+        .text_field("title", "My Upload")
+        .text_field("description", "line one\r\nline two")
+        .file_field_from_reader(
+            "file",
+            "data.bin",
+            "application/octet-stream",
+            &[0_u8, 1, 2, 255][..],
+        )
+        .unwrap()
+        .build();
+    assert_eq!(
+        format!("multipart/form-data; boundary={}", multipart.boundary()),
+        multipart.content_type()
+    );
+
+    let parts = parse_multipart(multipart.as_bytes(), multipart.boundary());
+    assert_eq!(
+        vec![
+            ParsedPart {
+                headers: vec!["Content-Disposition: form-data; name=\"title\"".to_owned()],
+                data: b"My Upload".to_vec(),
+            },
+            ParsedPart {
+                headers: vec!["Content-Disposition: form-data; name=\"description\"".to_owned()],
+                data: b"line one\r\nline two".to_vec(),
+            },
+            ParsedPart {
+                headers: vec![
+                    "Content-Disposition: form-data; name=\"file\"; filename=\"data.bin\""
+                        .to_owned(),
+                    "Content-Type: application/octet-stream".to_owned(),
+                ],
+                data: vec![0, 1, 2, 255],
+            },
+        ],
+        parts
+    );
+
+    let mut request = Request::new(Method::POST, Url::parse("http://example.com/").unwrap());
+    let content_type = multipart.content_type();
+    let len = multipart.as_bytes().len() as u64;
+    let body = multipart.apply(&mut request);
+    assert_eq!(
+        content_type,
+        request.headers()["content-type"].to_str().unwrap()
+    );
+    assert_eq!(Some(len), body.content_length());
+}
+
+#[test]
+fn test_multipart_escaping() {
+    crate::init().unwrap();
+
+    let multipart = MultipartBuilder::new()
+        .file_field("a\"b", "my \"file\"\r\n.txt", "text/plain", "contents")
+        .build();
+    let parts = parse_multipart(multipart.as_bytes(), multipart.boundary());
+    assert_eq!(1, parts.len());
+    assert_eq!(
+        "Content-Disposition: form-data; name=\"a%22b\"; filename=\"my %22file%22%0D%0A.txt\"",
+        parts[0].headers[0]
+    );
+}
+
+#[test]
+fn test_multipart_boundary_collision() {
+    crate::init().unwrap();
+
+    // Figure out which boundary a given seed would produce first, and then
+    // include it in the body, so the builder must pick a different one.
+    let colliding = random_multipart_boundary(&mut StdRng::seed_from_u64(1234));
+    let payload = format!("this payload contains --{} on purpose", colliding);
+    let multipart = MultipartBuilder::new()
+        .text_field("field", payload.as_str())
+        .build_with_rng(&mut StdRng::seed_from_u64(1234));
+    assert_ne!(colliding, multipart.boundary());
+
+    let parts = parse_multipart(multipart.as_bytes(), multipart.boundary());
+    assert_eq!(1, parts.len());
+    assert_eq!(payload.as_bytes(), parts[0].data.as_slice());
+}
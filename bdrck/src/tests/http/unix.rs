@@ -0,0 +1,273 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::error::*;
+use crate::http::client::{AbstractClient, Client};
+use crate::http::types::HttpData;
+use crate::testing::http::TestStubClient;
+use crate::testing::temp;
+use reqwest::Url;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Read a single request (head and body) from the given connection.
+fn read_request<R: BufRead>(reader: &mut R) -> String {
+    let mut head = String::new();
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap();
+            }
+        }
+        head.push_str(&line);
+        if line == "\r\n" {
+            break;
+        }
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).unwrap();
+    head + &String::from_utf8(body).unwrap()
+}
+
+/// Listen on the given socket, and answer one connection with each of the
+/// given canned responses, in order. The thread returns the raw requests it
+/// received.
+fn serve(socket: &Path, responses: &[&str]) -> JoinHandle<Vec<String>> {
+    let listener = UnixListener::bind(socket).unwrap();
+    let responses: Vec<String> = responses.iter().map(|r| r.to_string()).collect();
+    thread::spawn(move || {
+        let mut requests = Vec::new();
+        for response in responses {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(&stream);
+            requests.push(read_request(&mut reader));
+            (&stream).write_all(response.as_bytes()).unwrap();
+        }
+        requests
+    })
+}
+
+fn unix_url(socket: &Path, path: &str) -> Url {
+    Url::parse(&format!("unix://{}:{}", socket.display(), path)).unwrap()
+}
+
+fn header(headers: &crate::http::types::HeaderMap, name: &str) -> String {
+    headers.get(name).unwrap()[0]
+        .clone()
+        .try_into_string()
+        .unwrap()
+}
+
+#[test]
+fn test_unix_get_and_post() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let socket = dir.sub_path("api.sock").unwrap();
+    let server = serve(
+        &socket,
+        &[
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 15\r\n\r\n{\"status\":\"up\"}",
+            "HTTP/1.1 201 Created\r\nContent-Length: 7\r\nX-Id: 42\r\n\r\ncreated",
+        ],
+    );
+
+    // Address the socket directly in the URL.
+    let client = Client::new();
+    let request = client
+        .get(unix_url(&socket, "/v1/status?verbose=1"))
+        .build()
+        .unwrap();
+    let (metadata, body) = client.execute(request).unwrap();
+    assert_eq!(200, metadata.get_status().unwrap().as_u16());
+    assert_eq!(
+        "application/json",
+        header(metadata.get_headers(), "content-type")
+    );
+    assert_eq!(b"{\"status\":\"up\"}".to_vec(), body);
+
+    // Or, configure the client to send everything to the socket.
+    let client = Client::unix(&socket);
+    let request = client
+        .post(Url::parse("http://localhost/v1/things").unwrap())
+        .header("X-Request", "abc")
+        .body("hello, world")
+        .build()
+        .unwrap();
+    let (metadata, body) = client.execute(request).unwrap();
+    assert_eq!(201, metadata.get_status().unwrap().as_u16());
+    assert_eq!("42", header(metadata.get_headers(), "x-id"));
+    assert_eq!(b"created".to_vec(), body);
+
+    let requests = server.join().unwrap();
+    assert!(requests[0].starts_with("GET /v1/status?verbose=1 HTTP/1.1\r\n"));
+    assert!(requests[0].contains("host: localhost\r\n"));
+    assert!(requests[1].starts_with("POST /v1/things HTTP/1.1\r\n"));
+    assert!(requests[1].contains("x-request: abc\r\n"));
+    assert!(requests[1].contains("content-length: 12\r\n"));
+    assert!(requests[1].ends_with("\r\n\r\nhello, world"));
+}
+
+#[test]
+fn test_unix_chunked_response() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let socket = dir.sub_path("api.sock").unwrap();
+    let server = serve(
+        &socket,
+        &[
+            "HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5;name=value\r\nhello\r\n7\r\n, world\r\n0\r\nX-Trailer: ignored\r\n\r\n",
+            "HTTP/1.1 200 OK\r\n\r\nuntil the connection closes",
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nff\r\ntruncated",
+        ],
+    );
+
+    let client = Client::unix(&socket);
+    let get = |path: &str| {
+        let url = Url::parse("http://localhost").unwrap().join(path).unwrap();
+        client.execute(client.get(url).build().unwrap())
+    };
+
+    let (metadata, body) = get("/chunked").unwrap();
+    assert_eq!(200, metadata.get_status().unwrap().as_u16());
+    assert_eq!(b"hello, world".to_vec(), body);
+    assert!(metadata.get_headers().get("x-trailer").is_none());
+
+    let (_, body) = get("/eof").unwrap();
+    assert_eq!(b"until the connection closes".to_vec(), body);
+
+    let err = get("/truncated").unwrap_err();
+    assert_eq!(ErrorCode::IoUnexpectedEof, err.code());
+
+    server.join().unwrap();
+}
+
+#[test]
+fn test_unix_connection_errors() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let client = Client::new();
+    let get = |socket: &Path| client.execute(client.get(unix_url(socket, "/")).build().unwrap());
+
+    let missing = dir.sub_path("missing.sock").unwrap();
+    let err = get(&missing).unwrap_err();
+    assert_eq!(ErrorCode::IoNotFound, err.code());
+    assert!(err.to_string().contains("socket file does not exist"));
+
+    // Binding and then closing a listener leaves a socket file behind, which
+    // nothing is listening on.
+    let stale = dir.sub_path("stale.sock").unwrap();
+    drop(UnixListener::bind(&stale).unwrap());
+    let err = get(&stale).unwrap_err();
+    assert_eq!(ErrorCode::IoConnection, err.code());
+    assert!(err.to_string().contains("connection refused"));
+
+    // root can connect to any socket, so there's nothing to fail.
+    let is_root = unsafe { libc::geteuid() } == 0;
+    if !is_root {
+        let forbidden = dir.sub_path("forbidden.sock").unwrap();
+        let _listener = UnixListener::bind(&forbidden).unwrap();
+        fs::set_permissions(&forbidden, fs::Permissions::from_mode(0o000)).unwrap();
+        let err = get(&forbidden).unwrap_err();
+        assert_eq!(ErrorCode::IoPermissionDenied, err.code());
+        assert!(err.to_string().contains("permission denied"));
+    }
+
+    // A unix: URL must say which path to request.
+    let err = client
+        .execute(
+            client
+                .get(Url::parse("unix:///run/api.sock").unwrap())
+                .build()
+                .unwrap(),
+        )
+        .unwrap_err();
+    assert_eq!(ErrorCode::InvalidArgument, err.code());
+}
+
+#[test]
+fn test_unix_timeout() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let socket = dir.sub_path("api.sock").unwrap();
+    let listener = UnixListener::bind(&socket).unwrap();
+    let (done_tx, done_rx) = std::sync::mpsc::channel::<()>();
+    // Read the request, but never respond to it.
+    let server = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        read_request(&mut BufReader::new(&stream));
+        let _ = done_rx.recv();
+    });
+
+    let client = Client::unix(&socket);
+    let request = client
+        .get(Url::parse("http://localhost/slow").unwrap())
+        .timeout(Duration::from_millis(100))
+        .build()
+        .unwrap();
+    let start = Instant::now();
+    let err = client.execute(request).unwrap_err();
+    assert_eq!(ErrorCode::NetTimeout, err.code());
+    assert!(start.elapsed() < Duration::from_secs(10));
+
+    drop(done_tx);
+    server.join().unwrap();
+}
+
+#[cfg(debug_assertions)]
+#[test]
+fn test_unix_recording_replay() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let socket = dir.sub_path("api.sock").unwrap();
+    let recording = dir.sub_path("recording.json").unwrap();
+    let server = serve(
+        &socket,
+        &["HTTP/1.1 200 OK\r\nContent-Length: 2\r\nX-Id: 7\r\n\r\nok"],
+    );
+    let url = Url::parse("http://localhost/v1/things").unwrap();
+
+    {
+        let client = Client::new_with_recording(&recording).with_unix_socket(&socket);
+        let request = client.post(url.clone()).body("thing").build().unwrap();
+        client.execute(request).unwrap();
+    }
+    server.join().unwrap();
+    fs::remove_file(&socket).unwrap();
+
+    // The recording replays just like one made over TCP, without the socket.
+    let client = TestStubClient::new();
+    client
+        .push_recording(fs::read(&recording).unwrap().as_slice())
+        .unwrap();
+    let request = client.post(url).body("thing").build().unwrap();
+    let (metadata, body) = client.execute(request).unwrap();
+    assert_eq!(200, metadata.get_status().unwrap().as_u16());
+    assert_eq!(
+        &vec![HttpData::Text("7".to_string())],
+        metadata.get_headers().get("x-id").unwrap()
+    );
+    assert_eq!(b"ok".to_vec(), body);
+}
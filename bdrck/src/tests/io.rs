@@ -12,6 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::error::*;
 use crate::io::*;
 use crate::testing::temp;
 use std::fs;
@@ -36,3 +37,280 @@ fn test_read_at_most() {
         }
     }
 }
+
+fn read_lines(reader: LineReader<&[u8]>) -> Vec<std::result::Result<Line, String>> {
+    reader.map(|l| l.map_err(|e| e.to_string())).collect()
+}
+
+#[test]
+fn test_line_reader_line_endings() {
+    crate::init().unwrap();
+
+    let input = b"unix\nwindows\r\nbare\rcr\n\nno newline";
+    let lines: Vec<String> = LineReader::new(&input[..])
+        .map(|l| l.unwrap().as_str().unwrap().to_owned())
+        .collect();
+    assert_eq!(vec!["unix", "windows", "bare\rcr", "", "no newline"], lines);
+
+    assert_eq!(0, LineReader::new(&b""[..]).count());
+    assert_eq!(1, LineReader::new(&b"\r\n"[..]).count());
+}
+
+#[test]
+fn test_line_reader_max_line_len() {
+    crate::init().unwrap();
+
+    let mut input = vec![b'x'; 10 * 1024 * 1024];
+    input.extend_from_slice(b"\nshort\r\n");
+    input.extend_from_slice(b"exactly16bytes!!\r\n");
+    input.extend_from_slice(b"exactly17bytes!!!\n");
+    let mut reader = LineReader::new(std::io::BufReader::new(&input[..])).max_line_len(16);
+
+    match reader.next().unwrap() {
+        Err(Error::InputTooBig(message)) => assert!(message.contains("line 1")),
+        _ => panic!("expected an InputTooBig error"),
+    }
+    // The reader should recover at the next line.
+    assert_eq!(Some("short"), reader.next().unwrap().unwrap().as_str());
+    assert_eq!(2, reader.line_number());
+    assert_eq!(
+        Some("exactly16bytes!!"),
+        reader.next().unwrap().unwrap().as_str()
+    );
+    assert!(reader.next().unwrap().is_err());
+    assert!(reader.next().is_none());
+    assert_eq!(4, reader.line_number());
+}
+
+#[test]
+fn test_line_reader_utf8_modes() {
+    crate::init().unwrap();
+
+    let input = b"valid\ninv\xffalid\nvalid again\n";
+
+    let lines = read_lines(LineReader::new(&input[..]).utf8_mode(Utf8Mode::Strict));
+    assert_eq!(3, lines.len());
+    assert_eq!(Ok(Line::Text("valid".to_owned())), lines[0]);
+    assert!(lines[1].as_ref().unwrap_err().contains("line 2"));
+    assert_eq!(Ok(Line::Text("valid again".to_owned())), lines[2]);
+
+    let lines = read_lines(LineReader::new(&input[..]).utf8_mode(Utf8Mode::Lossy));
+    assert_eq!(Ok(Line::Text("inv\u{fffd}alid".to_owned())), lines[1]);
+
+    let lines = read_lines(LineReader::new(&input[..]).utf8_mode(Utf8Mode::Bytes));
+    assert_eq!(Ok(Line::Bytes(b"inv\xffalid".to_vec())), lines[1]);
+    assert_eq!(b"valid again", lines[2].as_ref().unwrap().as_bytes());
+}
+
+fn copy_test_data(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+/// A reader which returns at most a few bytes at a time, and which is
+/// interrupted before every successful read, to exercise retry handling.
+struct StutteringReader<'a> {
+    data: &'a [u8],
+    interrupt: bool,
+    reads: usize,
+}
+
+impl<'a> StutteringReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        StutteringReader {
+            data,
+            interrupt: true,
+            reads: 0,
+        }
+    }
+}
+
+impl<'a> std::io::Read for StutteringReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.interrupt = !self.interrupt;
+        if !self.interrupt {
+            return Err(std::io::ErrorKind::Interrupted.into());
+        }
+        self.reads += 1;
+        let n = buf.len().min(self.data.len()).min(3);
+        buf[..n].copy_from_slice(&self.data[..n]);
+        self.data = &self.data[n..];
+        Ok(n)
+    }
+}
+
+#[test]
+fn test_copy_ctl_limit() {
+    crate::init().unwrap();
+
+    let data = copy_test_data(100);
+    for buffer_size in [7, 100, DEFAULT_COPY_BUFFER_BYTES] {
+        // Exactly at the boundary, the copy is complete.
+        let mut out = Vec::new();
+        let outcome = copy_ctl(
+            &mut data.as_slice(),
+            &mut out,
+            CopyOptions {
+                max_bytes: Some(100),
+                buffer_size,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            CopyOutcome {
+                bytes_copied: 100,
+                completed: true,
+                cancelled: false,
+            },
+            outcome
+        );
+        assert_eq!(data, out);
+
+        // One byte short, it isn't.
+        let mut out = Vec::new();
+        let outcome = copy_ctl(
+            &mut StutteringReader::new(data.as_slice()),
+            &mut out,
+            CopyOptions {
+                max_bytes: Some(99),
+                buffer_size,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            CopyOutcome {
+                bytes_copied: 99,
+                completed: false,
+                cancelled: false,
+            },
+            outcome
+        );
+        assert_eq!(&data[..99], out.as_slice());
+    }
+}
+
+#[test]
+fn test_copy_ctl_cancel() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    crate::init().unwrap();
+
+    let data = copy_test_data(1000);
+    let cancel = AtomicBool::new(false);
+    let mut progress = |_: u64| cancel.store(true, Ordering::SeqCst);
+    let mut out = Vec::new();
+    let outcome = copy_ctl(
+        &mut data.as_slice(),
+        &mut out,
+        CopyOptions {
+            progress: Some(&mut progress),
+            progress_interval: 250,
+            cancel: Some(&cancel),
+            buffer_size: 100,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    // Cancellation is noticed before the next write after the flag is set.
+    assert_eq!(
+        CopyOutcome {
+            bytes_copied: 300,
+            completed: false,
+            cancelled: true,
+        },
+        outcome
+    );
+    assert_eq!(&data[..300], out.as_slice());
+}
+
+#[test]
+fn test_copy_ctl_progress() {
+    crate::init().unwrap();
+
+    let data = copy_test_data(1000);
+    let mut calls = Vec::new();
+    let mut progress = |n: u64| calls.push(n);
+    let mut reader = StutteringReader::new(data.as_slice());
+    let mut out = Vec::new();
+    let outcome = copy_ctl(
+        &mut reader,
+        &mut out,
+        CopyOptions {
+            progress: Some(&mut progress),
+            progress_interval: 10,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    assert!(outcome.completed);
+    assert_eq!(1000, outcome.bytes_copied);
+    assert_eq!(data, out);
+    // Short reads mean many reads, but progress is reported less often.
+    assert_eq!(335, reader.reads);
+    assert_eq!(1000, calls.iter().sum::<u64>());
+    assert!(calls[..calls.len() - 1].iter().all(|&n| n >= 10));
+    assert_eq!(84, calls.len());
+}
+
+#[test]
+fn test_copy_file_ctl_fast_path() {
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let src_path = dir.sub_path("src").unwrap();
+    let data = copy_test_data(3 * 1024 * 1024 + 17);
+    fs::write(&src_path, &data).unwrap();
+
+    for max_bytes in [None, Some(2 * 1024 * 1024), Some(data.len() as u64)] {
+        let mut outcomes = Vec::new();
+        for fast_path in [true, false] {
+            let dst_path = dir.sub_path(format!("dst-{}", fast_path)).unwrap();
+            let mut total = 0;
+            let mut progress = |n: u64| total += n;
+            let outcome = copy_file_ctl(
+                &mut fs::File::open(&src_path).unwrap(),
+                &mut fs::File::create(&dst_path).unwrap(),
+                CopyOptions {
+                    max_bytes,
+                    progress: Some(&mut progress),
+                    ..Default::default()
+                },
+                fast_path,
+            )
+            .unwrap();
+            assert_eq!(outcome.bytes_copied, total);
+            let expected_len = max_bytes.unwrap_or(data.len() as u64) as usize;
+            assert_eq!(
+                &data[..expected_len],
+                fs::read(&dst_path).unwrap().as_slice()
+            );
+            outcomes.push(outcome);
+        }
+        assert_eq!(outcomes[0], outcomes[1]);
+        assert_eq!(max_bytes != Some(2 * 1024 * 1024), outcomes[0].completed);
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_copy_file_ctl_fast_path_procfs() {
+    crate::init().unwrap();
+
+    // procfs files claim to be empty, and copy_file_range reports EOF for
+    // them immediately, but reading them produces data.
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let dst_path = dir.sub_path("dst").unwrap();
+    let expected = fs::read("/proc/version").unwrap();
+    assert!(!expected.is_empty());
+    let outcome = copy_file_ctl(
+        &mut fs::File::open("/proc/version").unwrap(),
+        &mut fs::File::create(&dst_path).unwrap(),
+        CopyOptions::default(),
+        /*fast_path=*/ true,
+    )
+    .unwrap();
+    assert!(outcome.completed);
+    assert_eq!(expected.len() as u64, outcome.bytes_copied);
+    assert_eq!(expected, fs::read(&dst_path).unwrap());
+}
@@ -19,6 +19,8 @@ mod configuration;
 #[cfg(test)]
 mod crypto;
 #[cfg(test)]
+mod error;
+#[cfg(test)]
 mod fs;
 #[cfg(test)]
 mod http;
@@ -27,6 +29,8 @@ mod io;
 #[cfg(test)]
 mod net;
 #[cfg(test)]
+mod proc;
+#[cfg(test)]
 mod testing;
 
 #[test]
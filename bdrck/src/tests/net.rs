@@ -12,8 +12,17 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::error::*;
+use crate::net::framed::{LineChannel, LineTerminator, ReadTimeout};
+use crate::net::metered::{self, MeteredStream, RateLimit, Totals};
 use crate::net::*;
-use std::net::IpAddr;
+use crate::testing::clock::{Clock, MockClock, SystemClock};
+use crate::testing::prop;
+use std::io::{self, Cursor, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 macro_rules! ip {
     ($e:expr) => {
@@ -66,6 +75,28 @@ fn test_hardware_addr_string_round_trip() {
     assert_eq!("0c:c4:7a:7f:b6:32", mac!("0c:c4:7a:7f:b6:32").to_string());
     assert_eq!("0c:c4:7a:7f:b6:32", mac!("0c-c4-7a-7f-b6-32").to_string());
     assert_eq!("0c:c4:7a:7f:b6:32", mac!("0cc4.7a7f.b632").to_string());
+
+    prop::run(
+        256,
+        true,
+        prop::gen::vec(prop::gen::int(..=u8::MAX), 6..=6),
+        |bytes| {
+            let s = bytes
+                .iter()
+                .map(|b| format!("{:02X}", b))
+                .collect::<Vec<_>>()
+                .join("-");
+            let parsed: HardwareAddr = s.parse().map_err(|e: Error| e.to_string())?;
+            let reparsed: HardwareAddr = parsed
+                .to_string()
+                .parse()
+                .map_err(|e: Error| e.to_string())?;
+            match parsed.as_bytes() == bytes.as_slice() && parsed == reparsed {
+                true => Ok(()),
+                false => Err(format!("{} didn't round trip", s)),
+            }
+        },
+    );
 }
 
 #[test]
@@ -97,6 +128,24 @@ fn test_ip_net_string_round_trip() {
     assert_eq!("10.0.0.0/24", net!("10.0.0.0/24").to_string());
     assert_eq!("10.0.0.0/14", net!("10.0.0.0/14").to_string());
     assert_eq!("10.0.0.0/14", net!("10.0.0.0/fffc0000").to_string());
+
+    prop::run(
+        256,
+        true,
+        prop::gen::pair(prop::gen::int(..=u32::MAX), prop::gen::int(0..=32_u8)),
+        |&(ip, prefix_len)| {
+            let s = format!("{}/{}", Ipv4Addr::from(ip), prefix_len);
+            let parsed: IpNet = s.parse().map_err(|e: Error| e.to_string())?;
+            let reparsed: IpNet = parsed
+                .to_string()
+                .parse()
+                .map_err(|e: Error| e.to_string())?;
+            match parsed == reparsed && parsed.get_one_bits() == prefix_len as usize {
+                true => Ok(()),
+                false => Err(format!("{} didn't round trip ({})", s, parsed)),
+            }
+        },
+    );
 }
 
 #[test]
@@ -196,3 +245,869 @@ fn test_ip_net_last_address() {
     assert_eq!(ip!("10.10.10.254"), net!("10.10.10.0/24").last());
     assert_eq!(ip!("10.10.255.254"), net!("10.10.0.0/16").last());
 }
+
+fn new_udp_responder() -> UdpSocket {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    socket
+}
+
+#[test]
+fn test_udp_query_retries() {
+    crate::init().unwrap();
+
+    let responder = new_udp_responder();
+    let addr = responder.local_addr().unwrap();
+    let handle = thread::spawn(move || {
+        let mut buf = [0_u8; 16];
+        // Ignore the first attempt, and answer the second one.
+        responder.recv_from(&mut buf).unwrap();
+        let (len, from) = responder.recv_from(&mut buf).unwrap();
+        assert_eq!(b"1:ping", &buf[..len]);
+        responder.send_to(b"1:pong", from).unwrap();
+    });
+
+    let (response, from) = udp_query(
+        None,
+        addr,
+        b"1:ping",
+        |res| res.starts_with(b"1:"),
+        &QueryOptions {
+            attempts: 3,
+            timeout: Duration::from_millis(100),
+            backoff: 1.0,
+        },
+    )
+    .unwrap();
+    assert_eq!(b"1:pong".to_vec(), response);
+    assert_eq!(addr, from);
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_udp_query_ignores_non_matching_responses() {
+    crate::init().unwrap();
+
+    let responder = new_udp_responder();
+    let addr = responder.local_addr().unwrap();
+    let handle = thread::spawn(move || {
+        let mut buf = [0_u8; 16];
+        let (_, from) = responder.recv_from(&mut buf).unwrap();
+        responder.send_to(b"2:pong", from).unwrap();
+        responder.send_to(b"1:pong", from).unwrap();
+    });
+
+    // Even with only a single attempt, the wrong datagram should be skipped.
+    let (response, _) = udp_query(
+        None,
+        addr,
+        b"1:ping",
+        |res| res.starts_with(b"1:"),
+        &QueryOptions {
+            attempts: 1,
+            timeout: Duration::from_secs(5),
+            backoff: 1.0,
+        },
+    )
+    .unwrap();
+    assert_eq!(b"1:pong".to_vec(), response);
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_udp_query_timeout() {
+    crate::init().unwrap();
+
+    let responder = new_udp_responder();
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let ret = udp_query(
+        Some(&socket),
+        responder.local_addr().unwrap(),
+        b"1:ping",
+        |_| true,
+        &QueryOptions {
+            attempts: 2,
+            timeout: Duration::from_millis(20),
+            backoff: 2.0,
+        },
+    );
+    match ret {
+        Err(Error::NetTimeout(_)) => {}
+        _ => panic!("expected a timeout, got {:?}", ret),
+    }
+    // The socket's original read timeout should have been restored.
+    assert_eq!(None, socket.read_timeout().unwrap());
+    // Both attempts should have actually been sent.
+    let mut buf = [0_u8; 16];
+    responder.recv_from(&mut buf).unwrap();
+    responder.recv_from(&mut buf).unwrap();
+}
+
+#[test]
+fn test_udp_query_invalid_backoff() {
+    crate::init().unwrap();
+
+    let responder = new_udp_responder();
+    let query = |timeout: Duration, backoff: f64| {
+        udp_query(
+            None,
+            responder.local_addr().unwrap(),
+            b"1:ping",
+            |_| true,
+            &QueryOptions {
+                attempts: 2,
+                timeout,
+                backoff,
+            },
+        )
+    };
+    for backoff in [-1.0, f64::NAN, f64::INFINITY] {
+        let ret = query(Duration::from_millis(1), backoff);
+        assert!(
+            matches!(ret, Err(Error::InvalidArgument(_))),
+            "expected an error for {}, got {:?}",
+            backoff,
+            ret
+        );
+    }
+    // Overflowing the timeout is an error too, rather than a panic.
+    let ret = query(Duration::from_millis(1), 1e300);
+    assert!(matches!(ret, Err(Error::InvalidArgument(_))), "{:?}", ret);
+    let ret = query(Duration::MAX, 1.0);
+    assert!(matches!(ret, Err(Error::InvalidArgument(_))), "{:?}", ret);
+}
+
+#[test]
+fn test_udp_query_all() {
+    crate::init().unwrap();
+
+    let responder = new_udp_responder();
+    let addr = responder.local_addr().unwrap();
+    let handle = thread::spawn(move || {
+        let mut buf = [0_u8; 16];
+        let (_, from) = responder.recv_from(&mut buf).unwrap();
+        responder.send_to(b"1:foo", from).unwrap();
+        responder.send_to(b"2:bar", from).unwrap();
+        responder.send_to(b"1:baz", from).unwrap();
+    });
+
+    let responses = udp_query_all(
+        None,
+        addr,
+        b"1:ping",
+        |res| res.starts_with(b"1:"),
+        Duration::from_millis(200),
+    )
+    .unwrap();
+    assert_eq!(
+        vec![b"1:foo".to_vec(), b"1:baz".to_vec()],
+        responses.into_iter().map(|(r, _)| r).collect::<Vec<_>>()
+    );
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_socket_config_apply() {
+    crate::init().unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let config = SocketConfig::default()
+        .keepalive(Some(KeepaliveParams {
+            idle: Duration::from_secs(30),
+            interval: Duration::from_secs(5),
+            count: 4,
+        }))
+        .nodelay(true)
+        .linger(Some(Duration::from_secs(2)))
+        .recv_buffer_size(64 * 1024)
+        .send_buffer_size(64 * 1024);
+    let stream = connect_with(&addr, &config, Duration::from_secs(5)).unwrap();
+
+    assert_ne!(
+        0,
+        get_socket_option(&stream, libc::SOL_SOCKET, libc::SO_KEEPALIVE).unwrap()
+    );
+    assert_ne!(
+        0,
+        get_socket_option(&stream, libc::IPPROTO_TCP, libc::TCP_NODELAY).unwrap()
+    );
+    assert_eq!(
+        4,
+        get_socket_option(&stream, libc::IPPROTO_TCP, libc::TCP_KEEPCNT).unwrap()
+    );
+    assert_eq!(
+        5,
+        get_socket_option(&stream, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL).unwrap()
+    );
+    // The OS may round buffer sizes (Linux doubles them, for bookkeeping
+    // overhead), so just check they're in the right ballpark.
+    let rcvbuf = get_socket_option(&stream, libc::SOL_SOCKET, libc::SO_RCVBUF).unwrap();
+    assert!((32 * 1024..=256 * 1024).contains(&rcvbuf));
+    let sndbuf = get_socket_option(&stream, libc::SOL_SOCKET, libc::SO_SNDBUF).unwrap();
+    assert!((32 * 1024..=256 * 1024).contains(&sndbuf));
+
+    // Options can be turned back off, too.
+    SocketConfig::default()
+        .keepalive(None)
+        .nodelay(false)
+        .apply_to(&stream)
+        .unwrap();
+    assert_eq!(
+        0,
+        get_socket_option(&stream, libc::SOL_SOCKET, libc::SO_KEEPALIVE).unwrap()
+    );
+    assert_eq!(
+        0,
+        get_socket_option(&stream, libc::IPPROTO_TCP, libc::TCP_NODELAY).unwrap()
+    );
+}
+
+#[test]
+fn test_socket_config_apply_to_listener() {
+    crate::init().unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    SocketConfig::default()
+        .reuse_addr(true)
+        .apply_to_listener(&listener)
+        .unwrap();
+    assert_ne!(
+        0,
+        get_socket_option(&listener, libc::SOL_SOCKET, libc::SO_REUSEADDR).unwrap()
+    );
+}
+
+#[test]
+fn test_socket_config_absurd_buffer_size() {
+    crate::init().unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    match SocketConfig::default()
+        .recv_buffer_size(usize::MAX)
+        .apply_to_listener(&listener)
+    {
+        Err(Error::SocketOption { option, .. }) => assert_eq!("SO_RCVBUF", option),
+        r => panic!("expected SocketOption error, got {:?}", r),
+    }
+}
+
+#[test]
+fn test_resolve_with_timeout_ordering() {
+    crate::init().unwrap();
+
+    let timeout = Duration::from_secs(10);
+    let system =
+        resolve_with_timeout("localhost", 80, timeout, AddressFamily::SystemOrder).unwrap();
+    assert!(system
+        .iter()
+        .all(|a| a.port() == 80 && a.ip().is_loopback()));
+
+    type AddrFilter = fn(&SocketAddr) -> bool;
+    let checks: [(AddressFamily, AddrFilter); 2] = [
+        (AddressFamily::V4, SocketAddr::is_ipv4),
+        (AddressFamily::V6, SocketAddr::is_ipv6),
+    ];
+    for (prefer, matches) in checks {
+        let addrs = resolve_with_timeout("localhost", 80, timeout, prefer).unwrap();
+        assert_eq!(system.len(), addrs.len());
+        // Preferred addresses should come first, if there are any.
+        let preferred = addrs.iter().take_while(|a| matches(a)).count();
+        assert_eq!(addrs.iter().filter(|a| matches(a)).count(), preferred);
+    }
+}
+
+#[test]
+fn test_resolve_with_timeout_times_out() {
+    crate::init().unwrap();
+
+    // In a network sandbox, resolution may fail before the timeout expires,
+    // which is fine; the important thing is that we never block for long.
+    let start = Instant::now();
+    let result = resolve_with_timeout(
+        "bdrck-test.invalid",
+        80,
+        Duration::from_millis(1),
+        AddressFamily::SystemOrder,
+    );
+    assert!(start.elapsed() < Duration::from_secs(1));
+    match result {
+        Err(Error::NetTimeout(_)) | Err(Error::Io(_)) => {}
+        _ => panic!("expected resolution to fail, got {:?}", result),
+    }
+}
+
+#[test]
+fn test_connect_happy() {
+    crate::init().unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let live = listener.local_addr().unwrap();
+    // Nothing is listening on this port anymore, so connecting is refused.
+    let refused = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+
+    let stream = connect_happy(&[refused, live], Duration::from_secs(5)).unwrap();
+    assert_eq!(live, stream.peer_addr().unwrap());
+    assert!(connect_happy(&[refused], Duration::from_secs(5)).is_err());
+    assert!(connect_happy(&[], Duration::from_secs(1)).is_err());
+}
+
+#[test]
+fn test_connect_happy_blackholed() {
+    crate::init().unwrap();
+
+    // This address is non-routable, so connecting to it either hangs or fails
+    // immediately. Some sandboxes intercept all connections though, in which
+    // case there's nothing to test.
+    let blackholed: SocketAddr = "10.255.255.1:9".parse().unwrap();
+    if TcpStream::connect_timeout(&blackholed, Duration::from_millis(100)).is_ok() {
+        return;
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let live = listener.local_addr().unwrap();
+    let start = Instant::now();
+    let stream = connect_happy(&[blackholed, live], Duration::from_secs(5)).unwrap();
+    assert_eq!(live, stream.peer_addr().unwrap());
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn test_prefix_len_from_mask() {
+    crate::init().unwrap();
+
+    assert_eq!(Some(0), prefix_len_from_mask(&[0, 0, 0, 0]));
+    assert_eq!(Some(8), prefix_len_from_mask(&[255, 0, 0, 0]));
+    assert_eq!(Some(20), prefix_len_from_mask(&[255, 255, 240, 0]));
+    assert_eq!(Some(24), prefix_len_from_mask(&[255, 255, 255, 0]));
+    assert_eq!(Some(32), prefix_len_from_mask(&[255, 255, 255, 255]));
+    assert_eq!(None, prefix_len_from_mask(&[255, 0, 255, 0]));
+    assert_eq!(None, prefix_len_from_mask(&[255, 255, 15, 0]));
+
+    let v6 = |s: &str| match ip!(s) {
+        IpAddr::V6(ip) => ip.octets(),
+        IpAddr::V4(_) => unreachable!(),
+    };
+    assert_eq!(Some(64), prefix_len_from_mask(&v6("ffff:ffff:ffff:ffff::")));
+    assert_eq!(Some(10), prefix_len_from_mask(&v6("ffc0::")));
+    assert_eq!(Some(128), prefix_len_from_mask(&[0xff; 16]));
+    assert_eq!(None, prefix_len_from_mask(&v6("ffff::ffff")));
+}
+
+#[cfg(not(target_os = "windows"))]
+#[test]
+fn test_loopback_interface() {
+    crate::init().unwrap();
+
+    let interfaces = interfaces().unwrap();
+    let loopback = interfaces
+        .iter()
+        .find(|i| i.flags.contains(InterfaceFlags::LOOPBACK))
+        .expect("expected a loopback interface");
+    assert!(loopback
+        .flags
+        .contains(InterfaceFlags::LOOPBACK | InterfaceFlags::UP));
+    assert!(loopback.index > 0);
+    assert_eq!(None, loopback.hardware_addr);
+
+    let v4 = loopback
+        .addrs
+        .iter()
+        .find(|a| a.addr == ip!("127.0.0.1"))
+        .expect("expected loopback to have 127.0.0.1");
+    assert_eq!(8, v4.prefix_len);
+    assert_eq!(AddressScope::Host, v4.scope);
+
+    // IPv6 may be disabled entirely, in which case there is no ::1.
+    if UdpSocket::bind("[::1]:0").is_ok() {
+        let v6 = loopback
+            .addrs
+            .iter()
+            .find(|a| a.addr == ip!("::1"))
+            .expect("expected loopback to have ::1");
+        assert_eq!(128, v6.prefix_len);
+        assert_eq!(AddressScope::Host, v6.scope);
+    }
+
+    let by_name = interface_by_name(loopback.name.as_str()).unwrap();
+    assert_eq!(loopback.name, by_name.name);
+    assert_eq!(loopback.index, by_name.index);
+}
+
+#[cfg(not(target_os = "windows"))]
+#[test]
+fn test_interface_by_name_not_found() {
+    crate::init().unwrap();
+
+    match interface_by_name("bdrck-nonexistent0") {
+        Err(Error::NotFound(_)) => {}
+        other => panic!("expected NotFound, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_parse_default_routes() {
+    crate::init().unwrap();
+
+    let ipv4 =
+        "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n\
+                eth1\t00000000\t010200C0\t0003\t0\t0\t600\t00000000\t0\t0\t0\n\
+                eth0\t00000000\t010200C0\t0003\t0\t0\t100\t00000000\t0\t0\t0\n\
+                eth0\t000200C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\t0\t0\t0\n";
+    assert_eq!(Some("eth0".to_string()), parse_ipv4_default_route(ipv4));
+    assert_eq!(None, parse_ipv4_default_route(""));
+
+    let ipv6 = "fe800000000000000000000000000000 40 00000000000000000000000000000000 00 00000000000000000000000000000000 00000100 00000002 00000000 00000001     eth0\n\
+                00000000000000000000000000000000 00 00000000000000000000000000000000 00 fd000000000000000000000000000001 00000400 00000001 00000000 00000003     wlan0\n\
+                00000000000000000000000000000000 00 00000000000000000000000000000000 00 00000000000000000000000000000000 ffffffff 00000001 00000000 00200200       lo\n";
+    assert_eq!(Some("wlan0".to_string()), parse_ipv6_default_route(ipv6));
+    assert_eq!(None, parse_ipv6_default_route(""));
+}
+
+#[test]
+fn test_line_channel_tcp_round_trip() {
+    crate::init().unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut channel = LineChannel::new(stream);
+        while let Ok(line) = channel.read_line(Duration::from_secs(5)) {
+            channel.write_line(line.to_uppercase().as_str()).unwrap();
+        }
+    });
+
+    let stream = TcpStream::connect(addr).unwrap();
+    let original_timeout = Some(Duration::from_secs(30));
+    stream.set_read_timeout(original_timeout).unwrap();
+    let mut channel = LineChannel::new(stream);
+    assert_eq!(
+        "HELLO",
+        channel.request("hello", Duration::from_secs(5)).unwrap()
+    );
+    assert_eq!(
+        "WORLD",
+        channel.request("world", Duration::from_secs(5)).unwrap()
+    );
+    // The stream's own timeout is left as we found it.
+    assert_eq!(original_timeout, channel.get_ref().read_timeout().unwrap());
+    assert!(channel.write_line("a\nb").is_err());
+
+    drop(channel);
+    server.join().unwrap();
+}
+
+#[test]
+fn test_line_channel_timeout() {
+    crate::init().unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    // Accept the connection, but never write anything to it.
+    let (_peer, _) = listener.accept().unwrap();
+
+    let mut channel = LineChannel::new(stream);
+    let start = Instant::now();
+    match channel.read_line(Duration::from_millis(100)) {
+        Err(Error::NetTimeout(_)) => {}
+        r => panic!("expected a timeout, got {:?}", r),
+    }
+    assert!(start.elapsed() >= Duration::from_millis(100));
+    assert_eq!(None, channel.get_ref().read_timeout().unwrap());
+}
+
+#[test]
+fn test_line_channel_oversize_and_eof() {
+    crate::init().unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (mut peer, _) = listener.accept().unwrap();
+    peer.write_all(b"short\r\n0123456789abcdef\r\n").unwrap();
+
+    let mut channel = LineChannel::new(stream).with_max_length(8);
+    assert_eq!("short", channel.read_line(Duration::from_secs(5)).unwrap());
+    match channel.read_line(Duration::from_secs(5)) {
+        Err(Error::InputTooBig(_)) => {}
+        r => panic!("expected an oversize line error, got {:?}", r),
+    }
+
+    // A connection closed mid-line is distinguishable from a timeout.
+    peer.write_all(b"partial").unwrap();
+    drop(peer);
+    match channel.read_line(Duration::from_secs(5)) {
+        Err(Error::Io(e)) => assert_eq!(io::ErrorKind::UnexpectedEof, e.kind()),
+        r => panic!("expected EOF, got {:?}", r),
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_unix_socket_round_trip() {
+    use crate::net::unix::{connect, UnixSocketListener};
+    use crate::testing::temp;
+    use std::os::unix::net::UnixListener;
+
+    crate::init().unwrap();
+
+    let dir = temp::Dir::new("bdrck").unwrap();
+    let path = dir.sub_path("control.sock").unwrap();
+
+    // Leave a stale socket file behind, as if a previous process crashed.
+    drop(UnixListener::bind(&path).unwrap());
+    assert!(path.exists());
+
+    let listener = UnixSocketListener::bind(&path).unwrap();
+
+    let server = thread::spawn(move || {
+        let mut channel =
+            LineChannel::new(listener.accept().unwrap()).with_terminator(LineTerminator::Lf);
+        let line = channel.read_line(Duration::from_secs(5)).unwrap();
+        channel
+            .write_line(format!("ack {}", line).as_str())
+            .unwrap();
+        listener
+    });
+
+    let mut channel = LineChannel::new(connect(&path).unwrap());
+    assert_eq!(
+        "ack status",
+        channel.request("status", Duration::from_secs(5)).unwrap()
+    );
+
+    let listener = server.join().unwrap();
+    // A second listener can't steal a socket which is in use.
+    assert!(UnixSocketListener::bind(&path).is_err());
+    drop(listener);
+    assert!(!path.exists());
+    match connect(&path) {
+        Err(Error::NotFound(_)) => {}
+        r => panic!("expected NotFound, got {:?}", r),
+    }
+}
+
+#[test]
+fn test_host_port_round_trip() {
+    crate::init().unwrap();
+
+    for (s, host, port) in [
+        ("example.com:443", "example.com", 443),
+        ("127.0.0.1:80", "127.0.0.1", 80),
+        ("[::1]:8080", "::1", 8080),
+    ] {
+        let parsed: HostPort = s.parse().unwrap();
+        assert_eq!(HostPort::new(host, port), parsed);
+        assert_eq!(s, parsed.to_string());
+    }
+    let addr: SocketAddr = "[::1]:8080".parse().unwrap();
+    assert_eq!(HostPort::new("::1", 8080), HostPort::from(addr));
+
+    for invalid in [
+        "example.com",
+        ":80",
+        "::1:8080",
+        "[::1:8080",
+        "host:port",
+        "host:65536",
+    ] {
+        match invalid.parse::<HostPort>() {
+            Err(Error::InvalidArgument(_)) => {}
+            r => panic!("expected {:?} to be invalid, got {:?}", invalid, r),
+        }
+    }
+}
+
+#[test]
+fn test_probe_open_port() {
+    crate::init().unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let target = listener.local_addr().unwrap();
+    let mut seen = Vec::new();
+    let report = probe::probe_with(
+        HostPort::new("127.0.0.1", target.port()),
+        &probe::ProbeOptions {
+            attempts: 3,
+            interval: Duration::from_millis(10),
+            ..Default::default()
+        },
+        &SystemClock,
+        |attempt| seen.push(attempt.sequence),
+    )
+    .unwrap();
+    assert_eq!(vec![0, 1, 2], seen);
+    assert_eq!(3, report.received());
+    assert_eq!(0.0, report.loss());
+    for attempt in report.attempts.iter() {
+        assert_eq!(probe::ProbeOutcome::Connected, attempt.outcome);
+        assert!(attempt.latency.unwrap() < Duration::from_secs(1));
+    }
+    assert!(report.min_latency() <= report.avg_latency());
+    assert!(report.avg_latency() <= report.max_latency());
+}
+
+#[test]
+fn test_probe_closed_port() {
+    crate::init().unwrap();
+
+    let options = probe::ProbeOptions {
+        attempts: 1,
+        ..Default::default()
+    };
+    let closed = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let report = probe::probe(closed.into(), &options).unwrap();
+    assert_eq!(probe::ProbeOutcome::Refused, report.attempts[0].outcome);
+    assert!(report.attempts[0].latency.is_some());
+
+    let closed = UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let report = probe::probe(
+        closed.into(),
+        &probe::ProbeOptions {
+            protocol: probe::ProbeProtocol::Udp,
+            ..options
+        },
+    )
+    .unwrap();
+    assert_eq!(
+        probe::ProbeOutcome::PortUnreachable,
+        report.attempts[0].outcome
+    );
+    assert!(report.attempts[0].outcome.is_reachable());
+
+    // An open UDP port which never answers is indistinguishable from a
+    // filtered one.
+    let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let report = probe::probe(
+        silent.local_addr().unwrap().into(),
+        &probe::ProbeOptions {
+            protocol: probe::ProbeProtocol::Udp,
+            timeout: Duration::from_millis(50),
+            ..options
+        },
+    )
+    .unwrap();
+    assert_eq!(probe::ProbeOutcome::TimedOut, report.attempts[0].outcome);
+    assert_eq!(None, report.attempts[0].latency);
+    assert_eq!(1.0, report.loss());
+}
+
+#[test]
+fn test_probe_report_aggregates() {
+    crate::init().unwrap();
+
+    let attempt = |sequence, outcome, latency_ms: Option<u64>| probe::ProbeAttempt {
+        sequence,
+        outcome,
+        latency: latency_ms.map(Duration::from_millis),
+    };
+    let report = probe::ProbeReport {
+        target: "localhost:80".parse().unwrap(),
+        addr: "127.0.0.1:80".parse().unwrap(),
+        protocol: probe::ProbeProtocol::Tcp,
+        attempts: vec![
+            attempt(0, probe::ProbeOutcome::Connected, Some(10)),
+            attempt(1, probe::ProbeOutcome::TimedOut, None),
+            attempt(2, probe::ProbeOutcome::Connected, Some(30)),
+            attempt(3, probe::ProbeOutcome::Refused, Some(20)),
+        ],
+    };
+    assert_eq!(3, report.received());
+    assert_eq!(0.25, report.loss());
+    assert_eq!(Some(Duration::from_millis(10)), report.min_latency());
+    assert_eq!(Some(Duration::from_millis(20)), report.avg_latency());
+    assert_eq!(Some(Duration::from_millis(30)), report.max_latency());
+
+    let empty = probe::ProbeReport {
+        attempts: vec![],
+        ..report
+    };
+    assert_eq!(0.0, empty.loss());
+    assert_eq!(None, empty.avg_latency());
+}
+
+#[test]
+fn test_probe_interval_pacing() {
+    crate::init().unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let clock = MockClock::default();
+    let start = clock.now_instant();
+    let report = probe::probe_with(
+        listener.local_addr().unwrap().into(),
+        &probe::ProbeOptions {
+            attempts: 4,
+            interval: Duration::from_secs(5),
+            ..Default::default()
+        },
+        &clock,
+        |_| {},
+    )
+    .unwrap();
+    assert_eq!(4, report.attempts.len());
+    // We wait between attempts, but not after the last one.
+    assert_eq!(Duration::from_secs(15), clock.now_instant() - start);
+}
+
+/// An in-memory stream, which reads from a fixed buffer and collects whatever
+/// is written to it.
+struct Duplex {
+    input: Cursor<Vec<u8>>,
+    output: Vec<u8>,
+}
+
+impl Duplex {
+    fn new(input: &[u8]) -> Self {
+        Duplex {
+            input: Cursor::new(input.to_vec()),
+            output: Vec::new(),
+        }
+    }
+}
+
+impl Read for Duplex {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for Duplex {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl ReadTimeout for Duplex {
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(None)
+    }
+
+    fn set_read_timeout(&self, _: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_metered_stream_accounting() {
+    crate::init().unwrap();
+
+    let before = metered::global_totals();
+    let mut stream = MeteredStream::new(Duplex::new(b"hello, world")).with_global_accounting();
+    let mut buf = [0_u8; 5];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(b"hello", &buf);
+    stream.write_all(b"foo").unwrap();
+    stream.flush().unwrap();
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest).unwrap();
+    assert_eq!(b", world", rest.as_slice());
+
+    assert_eq!(
+        Totals {
+            bytes_in: 12,
+            bytes_out: 3,
+        },
+        stream.totals()
+    );
+    // Other tests may be transferring data concurrently, so we can only check
+    // that our transfers were included.
+    let after = metered::global_totals();
+    assert!(after.bytes_in - before.bytes_in >= 12);
+    assert!(after.bytes_out - before.bytes_out >= 3);
+    assert_eq!(b"foo", stream.into_inner().output.as_slice());
+}
+
+#[test]
+fn test_metered_stream_moving_average() {
+    crate::init().unwrap();
+
+    let clock = Arc::new(MockClock::default());
+    let mut stream = MeteredStream::new(Duplex::new(&[0; 1000]))
+        .with_clock(clock.clone())
+        .with_window(Duration::from_secs(10));
+    assert_eq!(0.0, stream.rate_in());
+    assert_eq!(0.0, stream.rate_out());
+
+    stream.write_all(&[0; 1000]).unwrap();
+    assert_eq!(100.0, stream.rate_out());
+    clock.advance(Duration::from_secs(5));
+    stream.write_all(&[0; 500]).unwrap();
+    stream.read_exact(&mut [0; 200]).unwrap();
+    assert_eq!(150.0, stream.rate_out());
+    assert_eq!(20.0, stream.rate_in());
+
+    // Once a full window has passed, the first write falls out of the average.
+    clock.advance(Duration::from_secs(5));
+    assert_eq!(50.0, stream.rate_out());
+    clock.advance(Duration::from_millis(4999));
+    assert_eq!(50.0, stream.rate_out());
+    clock.advance(Duration::from_millis(1));
+    assert_eq!(0.0, stream.rate_out());
+    assert_eq!(0.0, stream.rate_in());
+
+    // The lifetime totals aren't affected.
+    assert_eq!(1500, stream.totals().bytes_out);
+    assert_eq!(200, stream.totals().bytes_in);
+}
+
+#[test]
+fn test_metered_stream_rate_limit() {
+    crate::init().unwrap();
+
+    let clock = Arc::new(MockClock::default());
+    let mut stream = MeteredStream::new(Duplex::new(&[7; 10_000]))
+        .with_clock(clock.clone())
+        .with_limit_in(RateLimit::new(1000))
+        .with_limit_out(RateLimit::new(2000).with_burst(500));
+
+    let start = clock.now_instant();
+    stream.write_all(&[0; 10_000]).unwrap();
+    // The first 500 bytes are allowed immediately, then the rest take 4.75s.
+    let elapsed = (clock.now_instant() - start).as_secs_f64();
+    assert!((elapsed - 4.75).abs() < 0.01, "took {}s", elapsed);
+
+    let start = clock.now_instant();
+    let mut buf = Vec::new();
+    stream.read_to_end(&mut buf).unwrap();
+    assert_eq!(vec![7; 10_000], buf);
+    let elapsed = (clock.now_instant() - start).as_secs_f64();
+    assert!((elapsed - 9.0).abs() < 0.01, "took {}s", elapsed);
+}
+
+#[test]
+fn test_metered_stream_small_limit_no_deadlock() {
+    crate::init().unwrap();
+
+    let clock = Arc::new(MockClock::default());
+    let stream = MeteredStream::new(Duplex::new(b"PONG\r\n"))
+        .with_clock(clock.clone())
+        .with_limit_in(RateLimit::new(2))
+        .with_limit_out(RateLimit::new(3));
+    // Both limits are much smaller than the lines exchanged, and than
+    // LineChannel's read buffer.
+    let mut channel = LineChannel::new(stream);
+    assert_eq!(
+        "PONG",
+        channel.request("PING", Duration::from_secs(60)).unwrap()
+    );
+    assert_eq!(
+        b"PING\r\n",
+        channel.into_inner().into_inner().output.as_slice()
+    );
+}
@@ -0,0 +1,170 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::error::*;
+use crate::proc::*;
+use std::time::{Duration, Instant};
+
+fn sh(script: &str) -> ProcCommand {
+    ProcCommand::new("/bin/sh").arg("-c").arg(script)
+}
+
+#[test]
+fn test_run_success() {
+    crate::init().unwrap();
+
+    let output = run(sh("echo hello; echo world >&2")).unwrap();
+    assert!(output.status.success());
+    assert_eq!(b"hello\n".to_vec(), output.stdout);
+    assert_eq!(b"world\n".to_vec(), output.stderr);
+}
+
+#[test]
+fn test_run_stdin() {
+    crate::init().unwrap();
+
+    let output = run(ProcCommand::new("/bin/cat").stdin(b"foo\nbar\n".to_vec())).unwrap();
+    assert_eq!(b"foo\nbar\n".to_vec(), output.stdout);
+}
+
+#[test]
+fn test_run_timeout() {
+    crate::init().unwrap();
+
+    let start = Instant::now();
+    // The background sleep is in the same process group, so it gets killed
+    // too; otherwise it would hold the output pipes open.
+    match run(sh("sleep 30 & sleep 30").timeout(Duration::from_millis(200))) {
+        Err(Error::ProcessTimeout(_)) => {}
+        r => panic!("expected ProcessTimeout error, got {:?}", r),
+    }
+    assert!(start.elapsed() < Duration::from_secs(10));
+}
+
+#[test]
+fn test_run_timeout_after_child_exits() {
+    crate::init().unwrap();
+
+    // The child exits right away, but leaves a background process holding
+    // its output pipes open. The timeout still applies to waiting for it.
+    let start = Instant::now();
+    match run(sh("sleep 30 & echo started").timeout(Duration::from_millis(200))) {
+        Err(Error::ProcessTimeout(_)) => {}
+        r => panic!("expected ProcessTimeout error, got {:?}", r),
+    }
+    let mut lines = Vec::new();
+    let ret = run_streaming(
+        sh("sleep 30 & echo started").timeout(Duration::from_millis(200)),
+        |_, line| lines.push(line.to_owned()),
+    );
+    match ret {
+        Err(Error::ProcessTimeout(_)) => {}
+        r => panic!("expected ProcessTimeout error, got {:?}", r),
+    }
+    assert_eq!(vec!["started"], lines);
+    assert!(start.elapsed() < Duration::from_secs(10));
+}
+
+#[test]
+fn test_run_failure_captures_stderr() {
+    crate::init().unwrap();
+
+    match run(sh("echo 'something went wrong' >&2; exit 3")) {
+        Err(Error::ProcessFailed { status, stderr }) => {
+            assert_eq!(Some(3), status.code());
+            assert_eq!("something went wrong\n", stderr);
+        }
+        r => panic!("expected ProcessFailed error, got {:?}", r),
+    }
+
+    // Without checking, the failure should be returned as normal output.
+    let output = run(sh("echo 'something went wrong' >&2; exit 3").check(false)).unwrap();
+    assert_eq!(Some(3), output.status.code());
+    assert_eq!(b"something went wrong\n".to_vec(), output.stderr);
+}
+
+#[test]
+fn test_run_large_output() {
+    crate::init().unwrap();
+
+    // This is much larger than any pipe buffer, so reading stdout and stderr
+    // sequentially would deadlock.
+    const OUTPUT_BYTES: usize = 4 * 1024 * 1024;
+    let output = run(sh(&format!(
+        "head -c {0} /dev/zero >&2; head -c {0} /dev/zero",
+        OUTPUT_BYTES
+    )))
+    .unwrap();
+    assert_eq!(OUTPUT_BYTES, output.stdout.len());
+    assert_eq!(OUTPUT_BYTES, output.stderr.len());
+}
+
+#[test]
+fn test_run_env_isolation() {
+    crate::init().unwrap();
+
+    std::env::set_var("BDRCK_PROC_TEST_INHERITED", "inherited");
+    let script = "echo \"$BDRCK_PROC_TEST_INHERITED:$BDRCK_PROC_TEST_SET\"";
+
+    let output = run(sh(script).env("BDRCK_PROC_TEST_SET", "set")).unwrap();
+    assert_eq!(b"inherited:set\n".to_vec(), output.stdout);
+
+    let output = run(sh(script).clear_env().env("BDRCK_PROC_TEST_SET", "set")).unwrap();
+    assert_eq!(b":set\n".to_vec(), output.stdout);
+}
+
+#[test]
+fn test_run_current_dir() {
+    crate::init().unwrap();
+
+    let output = run(ProcCommand::new("/bin/pwd").current_dir("/")).unwrap();
+    assert_eq!(b"/\n".to_vec(), output.stdout);
+}
+
+#[test]
+fn test_run_streaming() {
+    crate::init().unwrap();
+
+    let mut lines = Vec::new();
+    let output = run_streaming(sh("echo one; echo two >&2; printf three"), |which, line| {
+        lines.push((which, line.to_owned()))
+    })
+    .unwrap();
+
+    let stdout: Vec<&str> = lines
+        .iter()
+        .filter(|(w, _)| *w == OutputStream::Stdout)
+        .map(|(_, l)| l.as_str())
+        .collect();
+    assert_eq!(vec!["one", "three"], stdout);
+    let stderr: Vec<&str> = lines
+        .iter()
+        .filter(|(w, _)| *w == OutputStream::Stderr)
+        .map(|(_, l)| l.as_str())
+        .collect();
+    assert_eq!(vec!["two"], stderr);
+    assert_eq!(b"one\nthree".to_vec(), output.stdout);
+}
+
+#[test]
+fn test_command_display() {
+    crate::init().unwrap();
+
+    assert_eq!(
+        "git commit -m \"some message\"",
+        ProcCommand::new("git")
+            .args(["commit", "-m", "some message"])
+            .to_string()
+    );
+}
@@ -0,0 +1,70 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::testing::clock::*;
+use std::time::{Duration, SystemTime};
+
+#[test]
+fn test_mock_clock_advance() {
+    crate::init().unwrap();
+
+    let clock = MockClock::default();
+    let utc = clock.now_utc();
+    let instant = clock.now_instant();
+    assert_eq!(SystemTime::UNIX_EPOCH, utc);
+    // Time shouldn't pass on its own.
+    assert_eq!(utc, clock.now_utc());
+    assert_eq!(instant, clock.now_instant());
+
+    clock.advance(Duration::from_secs(10));
+    assert_eq!(utc + Duration::from_secs(10), clock.now_utc());
+    assert_eq!(instant + Duration::from_secs(10), clock.now_instant());
+
+    clock.sleep(Duration::from_millis(500));
+    assert_eq!(utc + Duration::from_millis(10500), clock.now_utc());
+    assert_eq!(instant + Duration::from_millis(10500), clock.now_instant());
+}
+
+#[test]
+fn test_mock_clock_set_is_monotonic() {
+    crate::init().unwrap();
+
+    let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+    let clock = MockClock::new(start);
+    clock.advance(Duration::from_secs(1));
+    let instant = clock.now_instant();
+
+    // Setting the wall-clock time backwards must not affect the monotonic time.
+    clock.set(SystemTime::UNIX_EPOCH);
+    assert_eq!(SystemTime::UNIX_EPOCH, clock.now_utc());
+    assert_eq!(instant, clock.now_instant());
+
+    clock.advance(Duration::from_secs(1));
+    assert_eq!(
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1),
+        clock.now_utc()
+    );
+    assert!(clock.now_instant() > instant);
+}
+
+#[test]
+fn test_system_clock() {
+    crate::init().unwrap();
+
+    let clock = SystemClock;
+    let before = clock.now_instant();
+    clock.sleep(Duration::from_millis(1));
+    assert!(clock.now_instant() > before);
+    assert!(clock.now_utc() > SystemTime::UNIX_EPOCH);
+}
@@ -0,0 +1,230 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::error::*;
+use crate::http::client::AbstractClient;
+use crate::testing::http::{FakeClient, TestStubClient};
+use reqwest::{Method, Request, Url};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
+struct Item {
+    id: u64,
+    name: String,
+}
+
+fn new_request(client: &FakeClient, method: Method, url: &str) -> Request {
+    let url = Url::parse(url).unwrap();
+    match method {
+        Method::GET => client.get(url),
+        Method::DELETE => client.delete(url),
+        _ => panic!("unsupported method {}", method),
+    }
+    .build()
+    .unwrap()
+}
+
+#[test]
+fn test_fake_client_unordered() {
+    crate::init().unwrap();
+
+    let mut fake = FakeClient::new();
+    fake.expect(Method::GET, "/v1/items")
+        .with_query("page", "2")
+        .respond(200)
+        .body("page two");
+    fake.expect(Method::DELETE, "/v1/items/1").respond(204);
+
+    // Satisfy the expectations in the opposite order they were declared.
+    let (metadata, _) = fake
+        .execute(new_request(
+            &fake,
+            Method::DELETE,
+            "https://example.com/v1/items/1",
+        ))
+        .unwrap();
+    assert_eq!(204, metadata.get_status().unwrap().as_u16());
+    let (metadata, body) = fake
+        .execute(new_request(
+            &fake,
+            Method::GET,
+            "https://example.com/v1/items?sort=asc&page=2",
+        ))
+        .unwrap();
+    assert_eq!(200, metadata.get_status().unwrap().as_u16());
+    assert_eq!(b"page two".to_vec(), body);
+
+    fake.verify().unwrap();
+}
+
+#[test]
+fn test_fake_client_ordered() {
+    crate::init().unwrap();
+
+    let mut fake = FakeClient::new_ordered();
+    fake.expect(Method::GET, "/first");
+    fake.expect(Method::GET, "/second");
+
+    assert!(fake
+        .execute(new_request(
+            &fake,
+            Method::GET,
+            "https://example.com/second"
+        ))
+        .is_err());
+    fake.execute(new_request(&fake, Method::GET, "https://example.com/first"))
+        .unwrap();
+    fake.execute(new_request(
+        &fake,
+        Method::GET,
+        "https://example.com/second",
+    ))
+    .unwrap();
+
+    // The out-of-order request should be reported.
+    match fake.verify() {
+        Err(Error::Precondition(msg)) => assert!(msg.contains("/second")),
+        r => panic!("expected Precondition error, got {:?}", r),
+    }
+}
+
+#[test]
+fn test_fake_client_times_violated() {
+    crate::init().unwrap();
+
+    let mut fake = FakeClient::new();
+    fake.expect(Method::GET, "/v1/items").times(2);
+    fake.expect(Method::GET, "/v1/health").any_times();
+
+    fake.execute(new_request(
+        &fake,
+        Method::GET,
+        "https://example.com/v1/items",
+    ))
+    .unwrap();
+    match fake.verify() {
+        Err(Error::Precondition(msg)) => assert!(msg.contains("/v1/items")),
+        r => panic!("expected Precondition error, got {:?}", r),
+    }
+
+    fake.execute(new_request(
+        &fake,
+        Method::GET,
+        "https://example.com/v1/items",
+    ))
+    .unwrap();
+    fake.verify().unwrap();
+
+    // A third call exceeds the expectation.
+    assert!(fake
+        .execute(new_request(
+            &fake,
+            Method::GET,
+            "https://example.com/v1/items"
+        ))
+        .is_err());
+    assert!(fake.verify().is_err());
+}
+
+#[test]
+fn test_fake_client_verify_diff() {
+    crate::init().unwrap();
+
+    let mut fake = FakeClient::new();
+    fake.expect(Method::GET, "/v1/items")
+        .with_query("page", "2")
+        .with_header("x-api-version", "3");
+
+    let mut request = new_request(&fake, Method::GET, "https://example.com/v1/items?page=3");
+    request
+        .headers_mut()
+        .insert("x-api-version", "2".parse().unwrap());
+    assert!(fake.execute(request).is_err());
+
+    // The mismatched fields are described individually.
+    let msg = match fake.verify() {
+        Err(Error::Precondition(msg)) => msg,
+        r => panic!("expected Precondition error, got {:?}", r),
+    };
+    assert!(msg.contains("unmet expectation GET /v1/items"));
+    assert!(msg.contains("unexpected request GET https://example.com/v1/items?page=3"));
+    assert!(msg.contains(
+        "url: expected https://example.com/v1/items?page=2, actual https://example.com/v1/items?page=3"
+    ));
+    assert!(msg.contains("header x-api-version: expected"));
+    assert!(!msg.contains("method:"));
+}
+
+#[test]
+fn test_fake_client_json_response() {
+    crate::init().unwrap();
+
+    let items = vec![
+        Item {
+            id: 1,
+            name: "foo".to_owned(),
+        },
+        Item {
+            id: 2,
+            name: "bar".to_owned(),
+        },
+    ];
+    let mut fake = FakeClient::new();
+    fake.expect(Method::GET, "/v1/items").json(&items);
+
+    let (metadata, body) = fake
+        .execute(new_request(
+            &fake,
+            Method::GET,
+            "https://example.com/v1/items",
+        ))
+        .unwrap();
+    assert_eq!(
+        "application/json",
+        metadata.get_headers()["content-type"][0]
+            .clone()
+            .try_into_string()
+            .unwrap()
+    );
+    assert_eq!(items, serde_json::from_slice::<Vec<Item>>(&body).unwrap());
+    fake.verify().unwrap();
+}
+
+#[test]
+fn test_fake_client_into_recording() {
+    crate::init().unwrap();
+
+    let mut fake = FakeClient::new();
+    fake.expect(Method::GET, "/v1/items").body("items");
+    fake.execute(new_request(
+        &fake,
+        Method::GET,
+        "https://example.com/v1/items",
+    ))
+    .unwrap();
+    fake.verify().unwrap();
+
+    // The session should be replayable with a TestStubClient.
+    let recording = serde_json::to_vec(&fake.into_recording()).unwrap();
+    let stub = TestStubClient::new();
+    stub.push_recording(recording.as_slice()).unwrap();
+    let (_, body) = stub
+        .execute(
+            stub.get(Url::parse("https://example.com/v1/items").unwrap())
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+    assert_eq!(b"items".to_vec(), body);
+}
@@ -0,0 +1,144 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::testing::logging::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use tracing::span::{Attributes, Id, Record as SpanRecord};
+use tracing::{Event, Level, Metadata, Subscriber};
+
+/// A stand-in for a logger installed by an application, which just counts the
+/// events it receives.
+struct CountingSubscriber {
+    events: Arc<AtomicUsize>,
+}
+
+impl Subscriber for CountingSubscriber {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        *metadata.level() <= Level::INFO
+    }
+
+    fn new_span(&self, _: &Attributes<'_>) -> Id {
+        Id::from_u64(1)
+    }
+
+    fn record(&self, _: &Id, _: &SpanRecord<'_>) {}
+
+    fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+    fn event(&self, _: &Event<'_>) {
+        self.events.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn enter(&self, _: &Id) {}
+
+    fn exit(&self, _: &Id) {}
+}
+
+#[test]
+fn test_capture_records() {
+    crate::init().unwrap();
+
+    let logs = capture(|| {
+        tracing::warn!("disk {} is almost full", "sda");
+        tracing::debug!(free = 12, "checked free space");
+    });
+    assert_eq!(2, logs.records().len());
+    let record = &logs.records()[0];
+    assert_eq!(Level::WARN, record.level);
+    assert_eq!("disk sda is almost full", record.message);
+    assert_eq!(module_path!(), record.target);
+    assert_eq!(Some(module_path!()), record.module.as_deref());
+    assert_eq!("checked free space free=12", logs.records()[1].message);
+
+    // Nothing logged outside of the capture is included.
+    tracing::error!("not captured");
+    assert_eq!(2, logs.records().len());
+}
+
+#[test]
+fn test_capture_assertions() {
+    crate::init().unwrap();
+
+    let logs = capture(|| {
+        tracing::info!("connected to example.com");
+        tracing::warn!("retrying request");
+    });
+    assert!(logs.contains(Level::INFO, "example.com"));
+    assert!(!logs.contains(Level::WARN, "example.com"));
+    logs.assert_contains(Level::WARN, "retrying");
+    logs.assert_none_at_or_above(Level::ERROR);
+    assert!(std::panic::catch_unwind(|| logs.assert_none_at_or_above(Level::WARN)).is_err());
+    assert!(std::panic::catch_unwind(|| logs.assert_contains(Level::ERROR, "retrying")).is_err());
+}
+
+#[test]
+fn test_capture_is_thread_scoped() {
+    crate::init().unwrap();
+
+    let logs = capture(|| {
+        tracing::info!("main thread");
+        thread::spawn(|| tracing::info!("other thread"))
+            .join()
+            .unwrap();
+    });
+    logs.assert_contains(Level::INFO, "main thread");
+    assert!(!logs.contains(Level::INFO, "other thread"));
+
+    // Concurrent captures on different threads only see their own events.
+    let handles: Vec<_> = (0..4)
+        .map(|i| {
+            thread::spawn(move || {
+                let logs = capture(|| {
+                    for _ in 0..10 {
+                        tracing::info!("thread {}", i);
+                    }
+                });
+                assert_eq!(10, logs.records().len());
+                assert!(logs
+                    .records()
+                    .iter()
+                    .all(|r| r.message == format!("thread {}", i)));
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn test_capture_passes_events_through() {
+    crate::init().unwrap();
+
+    let events = Arc::new(AtomicUsize::new(0));
+    let installed = CountingSubscriber {
+        events: events.clone(),
+    };
+    tracing::subscriber::with_default(installed, || {
+        let logs = capture(|| {
+            let span = tracing::info_span!("request");
+            let _guard = span.enter();
+            tracing::info!("handled");
+            tracing::debug!("details");
+        });
+        // We capture everything, but the installed subscriber still gets the
+        // events it was interested in.
+        assert_eq!(2, logs.records().len());
+        assert_eq!(1, events.load(Ordering::SeqCst));
+
+        tracing::info!("after");
+        assert_eq!(2, events.load(Ordering::SeqCst));
+    });
+}
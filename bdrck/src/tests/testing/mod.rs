@@ -12,7 +12,16 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+#[cfg(test)]
+mod clock;
 #[cfg(test)]
 mod fn_instrumentation;
+#[cfg(debug_assertions)]
+#[cfg(test)]
+mod http;
+#[cfg(test)]
+mod logging;
+#[cfg(test)]
+mod prop;
 #[cfg(test)]
 mod temp;
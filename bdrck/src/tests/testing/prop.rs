@@ -0,0 +1,147 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::testing::prop::*;
+use std::panic;
+
+const TEST_ITERATIONS: usize = 100;
+const TEST_SEED: u64 = 0x1234_5678;
+
+#[test]
+fn test_passing_property() {
+    crate::init().unwrap();
+
+    assert!(check(
+        TEST_ITERATIONS,
+        TEST_SEED,
+        gen::vec(gen::int(0..1000_u32), 0..16),
+        |v| match v.len() < 16 {
+            true => Ok(()),
+            false => Err(format!("vector too long: {}", v.len())),
+        }
+    )
+    .is_ok());
+}
+
+#[test]
+fn test_failing_property_shrinks() {
+    crate::init().unwrap();
+
+    let failure = check(
+        TEST_ITERATIONS,
+        TEST_SEED,
+        gen::vec(gen::int(0..1000_u32), 0..16),
+        |v| match v.iter().all(|&x| x < 500) {
+            true => Ok(()),
+            false => Err("found an element >= 500".to_string()),
+        },
+    )
+    .unwrap_err();
+    assert_eq!(TEST_SEED, failure.seed);
+    assert!(failure.original.iter().any(|&x| x >= 500));
+    // The minimal counterexample is a single element which is exactly 500.
+    assert_eq!(vec![500], failure.shrunk);
+    assert_ne!(failure.original, failure.shrunk);
+    assert!(failure.shrink_steps > 0);
+
+    // Running again with the same seed must reproduce the failure exactly.
+    let reproduced = check(
+        TEST_ITERATIONS,
+        failure.seed,
+        gen::vec(gen::int(0..1000_u32), 0..16),
+        |v| match v.iter().all(|&x| x < 500) {
+            true => Ok(()),
+            false => Err("found an element >= 500".to_string()),
+        },
+    )
+    .unwrap_err();
+    assert_eq!(failure, reproduced);
+}
+
+#[test]
+fn test_shrink_respects_bounds() {
+    crate::init().unwrap();
+
+    let failure = check(
+        TEST_ITERATIONS,
+        TEST_SEED,
+        gen::int(10..=100_i32),
+        |&x| match x < 50 {
+            true => Ok(()),
+            false => Err(format!("{} is too large", x)),
+        },
+    )
+    .unwrap_err();
+    assert_eq!(50, failure.shrunk);
+    assert_eq!("50 is too large", failure.message);
+
+    let failure = check(
+        TEST_ITERATIONS,
+        TEST_SEED,
+        gen::ascii_string(3..64),
+        |s| match s.contains('~') {
+            false => Ok(()),
+            true => Err("found a tilde".to_string()),
+        },
+    )
+    .unwrap_err();
+    assert!(failure.original.len() > failure.shrunk.len());
+    assert_eq!(3, failure.shrunk.len());
+    assert_eq!(1, failure.shrunk.matches('~').count());
+    assert_eq!(2, failure.shrunk.matches('a').count());
+}
+
+#[test]
+fn test_closure_generators_do_not_shrink() {
+    crate::init().unwrap();
+
+    let failure = check(
+        TEST_ITERATIONS,
+        TEST_SEED,
+        |rng: &mut Rng| rand::Rng::gen_range(rng, 0..1000_u32),
+        |&x| match x < 500 {
+            true => Ok(()),
+            false => Err("too large".to_string()),
+        },
+    )
+    .unwrap_err();
+    assert_eq!(failure.original, failure.shrunk);
+    assert_eq!(0, failure.shrink_steps);
+}
+
+#[test]
+fn test_run_reports_seed() {
+    crate::init().unwrap();
+
+    let prop = |v: &(u8, String)| match v.0 < 200 || v.1.len() < 10 {
+        true => Ok(()),
+        false => Err("both too large".to_string()),
+    };
+    let gen = || gen::pair(gen::int(..=u8::MAX), gen::ascii_string(0..32));
+
+    let result = panic::catch_unwind(|| run(TEST_ITERATIONS, false, gen(), prop));
+    let message = *result.unwrap_err().downcast::<String>().unwrap();
+    assert!(message.contains("(200, \"aaaaaaaaaa\")"), "{}", message);
+
+    let needle = format!("{}=", SEED_ENV_VAR);
+    let seed_start = message.find(&needle).unwrap() + needle.len();
+    let seed: u64 = message[seed_start..]
+        .split(')')
+        .next()
+        .unwrap()
+        .parse()
+        .unwrap();
+    let failure = check(TEST_ITERATIONS, seed, gen(), prop).unwrap_err();
+    assert_eq!(message, failure.to_string());
+}
@@ -12,9 +12,10 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::error::*;
 use crate::testing::temp::*;
 use std::fs;
-use std::io::{Read, Write};
+use std::io::{ErrorKind, Read, Write};
 
 #[test]
 fn test_read_write_temp_file() {
@@ -43,6 +44,12 @@ fn test_new_file_in_subdirectory() {
     let dir = Dir::new("bdrck").unwrap();
     let file = File::new_file_at(dir.sub_path("foo/bar/file.txt").unwrap()).unwrap();
     assert!(file.path().exists());
+
+    // Files are created exclusively, and removed when dropped.
+    assert!(File::new_file_at(file.path()).is_err());
+    let path = file.path().to_path_buf();
+    drop(file);
+    assert!(!path.exists());
 }
 
 #[test]
@@ -55,3 +62,72 @@ fn test_new_symlink_in_subdirectory() {
         File::new_symlink_at(file.path(), dir.sub_path("bar/baz/symlink.txt").unwrap()).unwrap();
     assert!(symlink.path().exists());
 }
+
+#[test]
+fn test_faulty_fs_fail_nth() {
+    crate::init().unwrap();
+
+    let ffs = FaultyFs::new("bdrck").unwrap();
+    ffs.fail_nth(Operation::Rename, 2, ErrorKind::PermissionDenied)
+        .fail_nth(Operation::Rename, 4, ErrorKind::Other);
+    let path = ffs.sub_path("data").unwrap();
+
+    let results: Vec<Option<ErrorCode>> = (0..5)
+        .map(|_| ffs.atomic_write(&path, b"data").err().map(|e| e.code()))
+        .collect();
+    assert_eq!(
+        vec![
+            None,
+            Some(ErrorCode::IoPermissionDenied),
+            None,
+            Some(ErrorCode::Io),
+            None
+        ],
+        results
+    );
+    // Every call is counted, failed or not.
+    assert_eq!(5, ffs.calls(Operation::Rename));
+    assert_eq!(5, ffs.calls(Operation::Open));
+    assert_eq!(5, ffs.calls(Operation::Fsync));
+    // The two temporary files which couldn't be renamed were removed.
+    assert_eq!(2, ffs.calls(Operation::Remove));
+    assert_eq!(20, ffs.bytes_written());
+}
+
+#[test]
+fn test_faulty_fs_fail_after_bytes() {
+    crate::init().unwrap();
+
+    let ffs = FaultyFs::new("bdrck").unwrap();
+    ffs.fail_after_bytes(Operation::Write, 10, ErrorKind::StorageFull);
+
+    let mut file = ffs.temp_file(ffs.path(), "test").unwrap();
+    file.write_all(b"0123").unwrap();
+    file.write_all(b"456").unwrap();
+    let err = file.write_all(b"789abc").unwrap_err();
+    match err {
+        Error::Io(e) => assert_eq!(ErrorKind::StorageFull, e.kind()),
+        _ => panic!("unexpected error {:?}", err),
+    }
+
+    // As much as fit was written, and every later write fails.
+    assert_eq!(10, ffs.bytes_written());
+    assert_eq!(b"0123456789".to_vec(), fs::read(file.path()).unwrap());
+    assert!(file.write_all(b"d").is_err());
+    assert!(file.write_all(b"").is_ok());
+    assert_eq!(5, ffs.calls(Operation::Write));
+}
+
+#[test]
+fn test_faulty_fs_passes_through() {
+    crate::init().unwrap();
+
+    let ffs = FaultyFs::new("bdrck").unwrap();
+    // Faults for other operations (or later calls) don't affect anything.
+    ffs.fail_nth(Operation::Rename, 100, ErrorKind::Other);
+    let path = ffs.sub_path("data").unwrap();
+    ffs.atomic_write(&path, b"hello").unwrap();
+    assert_eq!(b"hello".to_vec(), fs::read(&path).unwrap());
+    assert_eq!(1, ffs.calls(Operation::Write));
+    assert_eq!(0, ffs.calls(Operation::Remove));
+}
@@ -0,0 +1,55 @@
+// Copyright 2015 Axel Rasmussen
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Returns the number of terminal columns the given character occupies. This
+/// is a minimal approximation of Unicode's East Asian Width property: wide
+/// (e.g. CJK) characters occupy two columns, combining marks and control
+/// characters occupy none, and everything else occupies one.
+pub(crate) fn char_width(c: char) -> usize {
+    match c as u32 {
+        0..=0x1F | 0x7F..=0x9F => 0,
+        0x0300..=0x036F | 0x200B..=0x200F | 0xFE00..=0xFE0F => 0,
+        0x1100..=0x115F
+        | 0x2E80..=0x303E
+        | 0x3041..=0x33FF
+        | 0x3400..=0x4DBF
+        | 0x4E00..=0x9FFF
+        | 0xA000..=0xA4CF
+        | 0xAC00..=0xD7A3
+        | 0xF900..=0xFAFF
+        | 0xFE30..=0xFE4F
+        | 0xFF00..=0xFF60
+        | 0xFFE0..=0xFFE6
+        | 0x1F300..=0x1F64F
+        | 0x1F900..=0x1F9FF
+        | 0x20000..=0x3FFFD => 2,
+        _ => 1,
+    }
+}
+
+/// Returns the longest prefix of the given characters which fits in `width`
+/// columns.
+pub(crate) fn take_width<I: Iterator<Item = char>>(chars: I, width: usize) -> Vec<char> {
+    let mut taken = Vec::new();
+    let mut used = 0;
+    for c in chars {
+        let w = char_width(c);
+        if used + w > width {
+            break;
+        }
+        used += w;
+        taken.push(c);
+    }
+    taken
+}